@@ -0,0 +1,529 @@
+/// Peerの障害を素早く検知するための、最小限のBFD(RFC5880, RFC5881)の実装です。
+/// single hopのAsynchronous modeのみに対応し、認証, Echo, Demand modeには対応しない。
+/// `bfd=on`のPeer毎にセッションを作り、local_ip毎に1回だけbindしたUDPの3784番ポートで
+/// 受信したControl Packetを送信元のアドレスのセッションに渡す。
+/// セッションがUpからDownになると、PeerはHold Timerの満了を待たずにセッションを閉じる。
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use bytes::{BufMut, BytesMut};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+
+pub const BFD_CONTROL_PORT: u16 = 3784;
+// RFC5881 Section 4: 送信元ポートは49152から65535の範囲を使う。
+const SOURCE_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+// RFC5881 Section 5: single hopのBFDはTTLを255にして送信する。
+const BFD_TTL: u32 = 255;
+const CONTROL_PACKET_LENGTH: usize = 24;
+// セッションがUpでない間の送信間隔。(RFC5880 Section 6.8.3)
+const SLOW_TX_INTERVAL: Duration = Duration::from_secs(1);
+
+// 各セッションのMy Discriminator。プロセス内で一意にするために数え上げる。
+static NEXT_DISCRIMINATOR: AtomicU32 = AtomicU32::new(1);
+
+/// BFDのセッションの状態です。(RFC5880 Section 4.1)
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum BfdState {
+    AdminDown,
+    Down,
+    Init,
+    Up,
+}
+
+impl From<BfdState> for u8 {
+    fn from(state: BfdState) -> u8 {
+        match state {
+            BfdState::AdminDown => 0,
+            BfdState::Down => 1,
+            BfdState::Init => 2,
+            BfdState::Up => 3,
+        }
+    }
+}
+
+impl From<u8> for BfdState {
+    fn from(value: u8) -> Self {
+        match value & 0b11 {
+            0 => BfdState::AdminDown,
+            1 => BfdState::Down,
+            2 => BfdState::Init,
+            _ => BfdState::Up,
+        }
+    }
+}
+
+impl fmt::Display for BfdState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BfdState::AdminDown => write!(f, "admin-down"),
+            BfdState::Down => write!(f, "down"),
+            BfdState::Init => write!(f, "init"),
+            BfdState::Up => write!(f, "up"),
+        }
+    }
+}
+
+/// セッションがDownになった理由を表すDiagnostic Codeです。
+/// 本実装が使うものだけを定義している。(RFC5880 Section 4.1)
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub struct Diagnostic(pub u8);
+
+impl Diagnostic {
+    pub const NONE: Diagnostic = Diagnostic(0);
+    pub const CONTROL_DETECTION_TIME_EXPIRED: Diagnostic = Diagnostic(1);
+    pub const NEIGHBOR_SIGNALED_SESSION_DOWN: Diagnostic = Diagnostic(3);
+}
+
+/// BFD Control Packetです。認証のセクションには対応しない。
+/// 時間の値はパケットと同じくマイクロ秒で持つ。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub struct ControlPacket {
+    pub diagnostic: Diagnostic,
+    pub state: BfdState,
+    pub poll: bool,
+    pub final_: bool,
+    pub detect_mult: u8,
+    pub my_discriminator: u32,
+    pub your_discriminator: u32,
+    pub desired_min_tx_interval: u32,
+    pub required_min_rx_interval: u32,
+    pub required_min_echo_rx_interval: u32,
+}
+
+impl TryFrom<&[u8]> for ControlPacket {
+    type Error = anyhow::Error;
+
+    /// 受信したパケットを変換する。RFC5880 Section 6.8.6で
+    /// 破棄するように定められているパケットはエラーにする。
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < CONTROL_PACKET_LENGTH {
+            return Err(anyhow::anyhow!(
+                "BFD Control Packetの長さが足りません。"
+            ));
+        }
+        let version = bytes[0] >> 5;
+        let length = bytes[3] as usize;
+        if version != 1
+            || length < CONTROL_PACKET_LENGTH
+            || length > bytes.len()
+        {
+            return Err(anyhow::anyhow!(
+                "BFD Control Packetのversionか長さが不正です。"
+            ));
+        }
+        let u32_at = |i: usize| {
+            u32::from_be_bytes([
+                bytes[i],
+                bytes[i + 1],
+                bytes[i + 2],
+                bytes[i + 3],
+            ])
+        };
+        let packet = Self {
+            diagnostic: Diagnostic(bytes[0] & 0b1_1111),
+            state: BfdState::from(bytes[1] >> 6),
+            poll: bytes[1] & 0b10_0000 != 0,
+            final_: bytes[1] & 0b1_0000 != 0,
+            detect_mult: bytes[2],
+            my_discriminator: u32_at(4),
+            your_discriminator: u32_at(8),
+            desired_min_tx_interval: u32_at(12),
+            required_min_rx_interval: u32_at(16),
+            required_min_echo_rx_interval: u32_at(20),
+        };
+        // Authentication Presentのパケットは、認証に対応していないので破棄する。
+        if bytes[1] & 0b100 != 0 {
+            return Err(anyhow::anyhow!("BFDの認証には対応していません。"));
+        }
+        if packet.detect_mult == 0 || packet.my_discriminator == 0 {
+            return Err(anyhow::anyhow!(
+                "BFD Control PacketのDetect MultかMy Discriminatorが0です。"
+            ));
+        }
+        if packet.your_discriminator == 0
+            && !matches!(packet.state, BfdState::Down | BfdState::AdminDown)
+        {
+            return Err(anyhow::anyhow!(
+                "BFD Control PacketのYour Discriminatorが0です。"
+            ));
+        }
+        Ok(packet)
+    }
+}
+
+impl From<&ControlPacket> for BytesMut {
+    fn from(packet: &ControlPacket) -> BytesMut {
+        let mut bytes = BytesMut::with_capacity(CONTROL_PACKET_LENGTH);
+        bytes.put_u8(1 << 5 | packet.diagnostic.0 & 0b1_1111);
+        let mut flags = u8::from(packet.state) << 6;
+        if packet.poll {
+            flags |= 0b10_0000;
+        }
+        if packet.final_ {
+            flags |= 0b1_0000;
+        }
+        bytes.put_u8(flags);
+        bytes.put_u8(packet.detect_mult);
+        bytes.put_u8(CONTROL_PACKET_LENGTH as u8);
+        bytes.put_u32(packet.my_discriminator);
+        bytes.put_u32(packet.your_discriminator);
+        bytes.put_u32(packet.desired_min_tx_interval);
+        bytes.put_u32(packet.required_min_rx_interval);
+        bytes.put_u32(packet.required_min_echo_rx_interval);
+        bytes
+    }
+}
+
+/// 1つのPeerとのBFDのセッションの状態機械です。(RFC5880 Section 6.8)
+/// ToDo: セッションがUpになった後に送信間隔を変えるPoll Sequenceは送らない。
+/// Peerから受けたPollにはFinalを返す。
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct BfdSession {
+    pub state: BfdState,
+    pub diagnostic: Diagnostic,
+    local_discriminator: u32,
+    remote_discriminator: u32,
+    desired_min_tx_interval: Duration,
+    required_min_rx_interval: Duration,
+    detect_mult: u8,
+    remote_min_rx_interval: Duration,
+    remote_desired_min_tx_interval: Duration,
+    remote_detect_mult: u8,
+    // 次に送るパケットにFinalを立てるか。
+    pending_final: bool,
+    last_received: Option<Instant>,
+}
+
+impl BfdSession {
+    pub fn new(interval: Duration, detect_mult: u8) -> Self {
+        Self {
+            state: BfdState::Down,
+            diagnostic: Diagnostic::NONE,
+            local_discriminator: NEXT_DISCRIMINATOR
+                .fetch_add(1, Ordering::Relaxed),
+            remote_discriminator: 0,
+            desired_min_tx_interval: interval,
+            required_min_rx_interval: interval,
+            detect_mult,
+            // 初期値は1マイクロ秒。(RFC5880 Section 6.8.1)
+            remote_min_rx_interval: Duration::from_micros(1),
+            remote_desired_min_tx_interval: SLOW_TX_INTERVAL,
+            remote_detect_mult: 0,
+            pending_final: false,
+            last_received: None,
+        }
+    }
+
+    /// 受信したパケットによりセッションの状態を進める。(RFC5880 Section 6.8.6)
+    pub fn receive(&mut self, packet: &ControlPacket, now: Instant) {
+        if packet.your_discriminator != 0
+            && packet.your_discriminator != self.local_discriminator
+        {
+            return;
+        }
+        self.remote_discriminator = packet.my_discriminator;
+        self.remote_min_rx_interval =
+            Duration::from_micros(packet.required_min_rx_interval.into());
+        self.remote_desired_min_tx_interval =
+            Duration::from_micros(packet.desired_min_tx_interval.into());
+        self.remote_detect_mult = packet.detect_mult;
+        self.pending_final |= packet.poll;
+        self.last_received = Some(now);
+
+        match (self.state, packet.state) {
+            (state, BfdState::AdminDown) if state != BfdState::Down => {
+                self.down(Diagnostic::NEIGHBOR_SIGNALED_SESSION_DOWN);
+            }
+            (BfdState::Down, BfdState::Down) => self.state = BfdState::Init,
+            (BfdState::Down, BfdState::Init)
+            | (BfdState::Init, BfdState::Init | BfdState::Up) => {
+                self.state = BfdState::Up;
+                self.diagnostic = Diagnostic::NONE;
+            }
+            (BfdState::Up, BfdState::Down) => {
+                self.down(Diagnostic::NEIGHBOR_SIGNALED_SESSION_DOWN)
+            }
+            _ => {}
+        }
+    }
+
+    /// Detection Timeの間パケットを受信していなければDownにする。
+    pub fn check_detection_time(&mut self, now: Instant) {
+        if !matches!(self.state, BfdState::Init | BfdState::Up) {
+            return;
+        }
+        if self
+            .last_received
+            .is_some_and(|t| now.duration_since(t) > self.detection_time())
+        {
+            self.down(Diagnostic::CONTROL_DETECTION_TIME_EXPIRED);
+        }
+    }
+
+    /// Peerがパケットを送る間隔にDetect Multを掛けた時間。(RFC5880 Section 6.8.4)
+    pub fn detection_time(&self) -> Duration {
+        self.required_min_rx_interval
+            .max(self.remote_desired_min_tx_interval)
+            * self.remote_detect_mult.into()
+    }
+
+    /// 次のパケットを送るまでの間隔。Upでない間は1秒以上にする。
+    /// 送信がPeerと同期しないように、0から25%短くする。(RFC5880 Section 6.8.7)
+    pub fn tx_interval(&self) -> Duration {
+        let desired = match self.state {
+            BfdState::Up => self.desired_min_tx_interval,
+            _ => self.desired_min_tx_interval.max(SLOW_TX_INTERVAL),
+        };
+        let interval = desired.max(self.remote_min_rx_interval);
+        interval - interval * jitter_percent() / 100
+    }
+
+    /// 送信するパケットを作る。
+    pub fn packet(&mut self) -> ControlPacket {
+        let desired_min_tx_interval = match self.state {
+            BfdState::Up => self.desired_min_tx_interval,
+            _ => self.desired_min_tx_interval.max(SLOW_TX_INTERVAL),
+        };
+        ControlPacket {
+            diagnostic: self.diagnostic,
+            state: self.state,
+            poll: false,
+            final_: std::mem::take(&mut self.pending_final),
+            detect_mult: self.detect_mult,
+            my_discriminator: self.local_discriminator,
+            your_discriminator: self.remote_discriminator,
+            desired_min_tx_interval: desired_min_tx_interval.as_micros()
+                as u32,
+            required_min_rx_interval: self.required_min_rx_interval.as_micros()
+                as u32,
+            required_min_echo_rx_interval: 0,
+        }
+    }
+
+    fn down(&mut self, diagnostic: Diagnostic) {
+        self.state = BfdState::Down;
+        self.diagnostic = diagnostic;
+        self.remote_discriminator = 0;
+    }
+}
+
+/// 0から25の値を返す。乱数のcrateを使うほどではないので、時刻のナノ秒を使う。
+fn jitter_percent() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos() % 26)
+}
+
+/// `bfd=on`の全てのPeerで共有する、BFDのControl Packetを受信するlistenerです。
+#[derive(Debug, Default)]
+pub struct BfdListener {
+    sessions:
+        Arc<Mutex<HashMap<IpAddr, mpsc::UnboundedSender<ControlPacket>>>>,
+}
+
+impl BfdListener {
+    /// `bfd=on`のconfigのlocal_ip毎にbindし、受信を始める。
+    pub async fn bind(configs: &[Config]) -> Result<Arc<Self>> {
+        let listener = Arc::new(Self::default());
+        let local_ips: HashSet<IpAddr> = configs
+            .iter()
+            .filter(|c| c.bfd)
+            .map(|c| c.local_ip)
+            .collect();
+        for local_ip in local_ips {
+            let addr = SocketAddr::new(local_ip, BFD_CONTROL_PORT);
+            let socket = UdpSocket::bind(addr).await.context(format!(
+                "{0}にbindすることが出来ませんでした。",
+                addr
+            ))?;
+            info!("bfd is listening on {}.", addr);
+            tokio::spawn(receive_packets(
+                socket,
+                Arc::clone(&listener.sessions),
+            ));
+        }
+        Ok(listener)
+    }
+
+    /// configのPeerとのBFDのセッションを始め、その状態を受け取るreceiverを返す。
+    /// セッションはBGPのセッションやPeerのタスクと関係なく動き続ける。
+    pub async fn start_session(
+        &self,
+        config: &Config,
+    ) -> Result<watch::Receiver<BfdState>> {
+        let socket = bind_source_port(config.local_ip).await?;
+        socket.set_ttl(BFD_TTL)?;
+        socket
+            .connect(SocketAddr::new(config.remote_ip, BFD_CONTROL_PORT))
+            .await?;
+        let (packets, receiver) = mpsc::unbounded_channel();
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(config.remote_ip, packets);
+        let session =
+            BfdSession::new(config.bfd_interval, config.bfd_multiplier);
+        let (state, state_receiver) = watch::channel(session.state);
+        tokio::spawn(run_session(session, socket, receiver, state));
+        Ok(state_receiver)
+    }
+}
+
+/// 受信したパケットを、送信元のアドレスのセッションに渡し続ける。
+/// ToDo: 受信したパケットのTTLが255であることを確認していない。
+async fn receive_packets(
+    socket: UdpSocket,
+    sessions: Arc<
+        Mutex<HashMap<IpAddr, mpsc::UnboundedSender<ControlPacket>>>,
+    >,
+) {
+    let mut buf = [0u8; 512];
+    loop {
+        let (length, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("cannot receive bfd packet: {:?}.", e);
+                continue;
+            }
+        };
+        let packet = match ControlPacket::try_from(&buf[..length]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("bfd packet from {} is discarded: {:?}.", from, e);
+                continue;
+            }
+        };
+        if let Some(session) = sessions.lock().unwrap().get(&from.ip()) {
+            let _ = session.send(packet);
+        }
+    }
+}
+
+/// SOURCE_PORTSのうち、空いているポートにbindする。
+async fn bind_source_port(local_ip: IpAddr) -> Result<UdpSocket> {
+    for port in SOURCE_PORTS {
+        if let Ok(socket) =
+            UdpSocket::bind(SocketAddr::new(local_ip, port)).await
+        {
+            return Ok(socket);
+        }
+    }
+    Err(anyhow::anyhow!(
+        "BFDの送信元ポートにbindすることが出来ませんでした。"
+    ))
+}
+
+async fn run_session(
+    mut session: BfdSession,
+    socket: UdpSocket,
+    mut packets: mpsc::UnboundedReceiver<ControlPacket>,
+    state: watch::Sender<BfdState>,
+) {
+    let mut next_tx = Instant::now();
+    loop {
+        // Detection TimeはInitかUpの間だけ確認する。
+        let detection = session
+            .last_received
+            .filter(|_| matches!(session.state, BfdState::Init | BfdState::Up))
+            .map(|t| t + session.detection_time());
+        tokio::select! {
+            Some(packet) = packets.recv() => {
+                session.receive(&packet, Instant::now());
+                // Pollを受けたら、次の送信を待たずにFinalを返す。
+                if packet.poll {
+                    next_tx = Instant::now();
+                }
+            }
+            _ = tokio::time::sleep_until(next_tx) => {
+                let bytes = BytesMut::from(&session.packet());
+                // Peerが止まっている間はICMP port unreachableで失敗し続けるので、
+                // Upの間だけ警告する。
+                match socket.send(&bytes).await {
+                    Err(e) if session.state == BfdState::Up => {
+                        warn!("cannot send bfd packet: {:?}.", e)
+                    }
+                    Err(e) => debug!("cannot send bfd packet: {:?}.", e),
+                    Ok(_) => {}
+                }
+                next_tx = Instant::now() + session.tx_interval();
+            }
+            _ = tokio::time::sleep_until(detection.unwrap_or(next_tx)),
+                if detection.is_some() => {
+                session.check_detection_time(Instant::now());
+            }
+        }
+        if *state.borrow() != session.state {
+            info!(
+                "bfd session is {} (diagnostic {}).",
+                session.state, session.diagnostic.0
+            );
+            // 状態が変わったことを直ちにPeerに伝える。
+            next_tx = next_tx.min(Instant::now());
+            if state.send(session.state).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_bytes_to_control_packet_and_control_packet_to_bytes() {
+        let mut session = BfdSession::new(Duration::from_millis(300), 3);
+        let packet = session.packet();
+        let bytes = BytesMut::from(&packet);
+        assert_eq!(bytes.len(), CONTROL_PACKET_LENGTH);
+        assert_eq!(ControlPacket::try_from(&bytes[..]).unwrap(), packet);
+        // Upでない間は1秒以上の間隔を広報する。
+        assert_eq!(packet.desired_min_tx_interval, 1_000_000);
+        assert_eq!(packet.required_min_rx_interval, 300_000);
+    }
+
+    #[test]
+    fn bfd_session_goes_up_and_down_on_detection_time() {
+        let interval = Duration::from_millis(300);
+        let mut local = BfdSession::new(interval, 3);
+        let mut remote = BfdSession::new(interval, 3);
+        let now = Instant::now();
+
+        // 3-way handshake (RFC5880 Section 6.2)
+        local.receive(&remote.packet(), now);
+        assert_eq!(local.state, BfdState::Init);
+        remote.receive(&local.packet(), now);
+        assert_eq!(remote.state, BfdState::Up);
+        local.receive(&remote.packet(), now);
+        assert_eq!(local.state, BfdState::Up);
+        remote.receive(&local.packet(), now);
+        assert_eq!(local.detection_time(), Duration::from_millis(900));
+
+        local.check_detection_time(now + Duration::from_millis(800));
+        assert_eq!(local.state, BfdState::Up);
+        local.check_detection_time(now + Duration::from_millis(1000));
+        assert_eq!(local.state, BfdState::Down);
+        assert_eq!(
+            local.diagnostic,
+            Diagnostic::CONTROL_DETECTION_TIME_EXPIRED
+        );
+
+        // Down (Diag 1)を受け取ったPeerもDownになる。
+        remote.receive(&local.packet(), now);
+        assert_eq!(remote.state, BfdState::Down);
+        assert_eq!(
+            remote.diagnostic,
+            Diagnostic::NEIGHBOR_SIGNALED_SESSION_DOWN
+        );
+    }
+}
@@ -0,0 +1,616 @@
+/// BGP-LS (RFC7752)のLink-State NLRIとBGP-LS Attributeを扱うモジュールです。
+/// ルータがBGP-LSで広報するIGPのトポロジを収集し、グラフとして参照できるようにします。
+/// BGP-LSのルートは収集のためにだけ受信し、広報はしません。
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+use bytes::{BufMut, BytesMut};
+
+use crate::error::ConvertBytesToBgpMessageError;
+use crate::path_attribute::PathAttribute;
+use crate::routing::{Rib, RibKey};
+
+/// BGP-LSのNLRI, Attributeを構成するTLVです。
+/// 値の解釈はTLVを保持する側のメソッドで行い、
+/// 対応していないTLVもそのまま保持します。
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub struct Tlv {
+    pub type_: u16,
+    pub value: Vec<u8>,
+}
+
+impl Tlv {
+    fn bytes_len(&self) -> usize {
+        // Type(2) + Length(2) + Value
+        4 + self.value.len()
+    }
+
+    fn from_u8_slice(
+        bytes: &[u8],
+    ) -> Result<Vec<Self>, ConvertBytesToBgpMessageError> {
+        let mut tlvs = vec![];
+        let mut i = 0;
+        while bytes.len() > i {
+            if bytes.len() < i + 4 {
+                return Err(ConvertBytesToBgpMessageError::from(
+                    anyhow::anyhow!("BGP-LSのTLVの長さが足りません。"),
+                ));
+            }
+            let type_ = u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+            let length =
+                u16::from_be_bytes([bytes[i + 2], bytes[i + 3]]) as usize;
+            if bytes.len() < i + 4 + length {
+                return Err(ConvertBytesToBgpMessageError::from(
+                    anyhow::anyhow!(
+                        "BGP-LSのTLV(type {})の長さが足りません。",
+                        type_
+                    ),
+                ));
+            }
+            tlvs.push(Self {
+                type_,
+                value: bytes[i + 4..i + 4 + length].to_vec(),
+            });
+            i += 4 + length;
+        }
+        Ok(tlvs)
+    }
+
+    fn value_as_u32(&self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.value.get(..4)?.try_into().ok()?))
+    }
+}
+
+fn put_tlvs(bytes: &mut BytesMut, tlvs: &[Tlv]) {
+    for tlv in tlvs {
+        bytes.put_u16(tlv.type_);
+        bytes.put_u16(tlv.value.len() as u16);
+        bytes.put(&tlv.value[..]);
+    }
+}
+
+fn find_tlv(tlvs: &[Tlv], type_: u16) -> Option<&Tlv> {
+    tlvs.iter().find(|t| t.type_ == type_)
+}
+
+/// Local/Remote Node Descriptors (RFC7752 Section 3.2.1.4)
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub struct NodeDescriptors(pub Vec<Tlv>);
+
+impl NodeDescriptors {
+    pub fn asn(&self) -> Option<u32> {
+        find_tlv(&self.0, 512).and_then(|t| t.value_as_u32())
+    }
+
+    pub fn ospf_area_id(&self) -> Option<u32> {
+        find_tlv(&self.0, 514).and_then(|t| t.value_as_u32())
+    }
+
+    /// IGP Router-IDを文字列で返す。OSPFではRouter ID(IPv4アドレス形式)、
+    /// IS-ISではSystem ID(とPseudonode ID)の16進表記になる。
+    pub fn igp_router_id(&self) -> Option<String> {
+        let v = &find_tlv(&self.0, 515)?.value;
+        Some(match v.len() {
+            4 => Ipv4Addr::new(v[0], v[1], v[2], v[3]).to_string(),
+            6 | 7 => {
+                let system_id = format!(
+                    "{:02x}{:02x}.{:02x}{:02x}.{:02x}{:02x}",
+                    v[0], v[1], v[2], v[3], v[4], v[5]
+                );
+                match v.get(6) {
+                    Some(pseudonode) => {
+                        format!("{}.{:02x}", system_id, pseudonode)
+                    }
+                    None => system_id,
+                }
+            }
+            _ => v.iter().map(|b| format!("{:02x}", b)).collect(),
+        })
+    }
+}
+
+/// Link-State NLRI (RFC7752 Section 3.2)
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub enum LinkStateNlri {
+    Node {
+        protocol_id: u8,
+        identifier: u64,
+        local_node: NodeDescriptors,
+    },
+    Link {
+        protocol_id: u8,
+        identifier: u64,
+        local_node: NodeDescriptors,
+        remote_node: NodeDescriptors,
+        // Link Descriptors (RFC7752 Section 3.2.2)
+        link_descriptors: Vec<Tlv>,
+    },
+    Prefix {
+        // IPv4 Topology Prefixならfalse, IPv6 Topology Prefixならtrue
+        is_ipv6: bool,
+        protocol_id: u8,
+        identifier: u64,
+        local_node: NodeDescriptors,
+        // Prefix Descriptors (RFC7752 Section 3.2.3)
+        prefix_descriptors: Vec<Tlv>,
+    },
+    // 対応していないNLRI Type用
+    Unknown {
+        nlri_type: u16,
+        value: Vec<u8>,
+    },
+}
+
+impl LinkStateNlri {
+    fn nlri_type(&self) -> u16 {
+        match self {
+            LinkStateNlri::Node { .. } => 1,
+            LinkStateNlri::Link { .. } => 2,
+            LinkStateNlri::Prefix { is_ipv6: false, .. } => 3,
+            LinkStateNlri::Prefix { is_ipv6: true, .. } => 4,
+            LinkStateNlri::Unknown { nlri_type, .. } => *nlri_type,
+        }
+    }
+
+    pub fn bytes_len(&self) -> usize {
+        // NLRI Type(2) + Total NLRI Length(2) + 値
+        4 + self.value_bytes().len()
+    }
+
+    fn value_bytes(&self) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        let put_header =
+            |bytes: &mut BytesMut, protocol_id: u8, identifier: u64| {
+                bytes.put_u8(protocol_id);
+                bytes.put_u64(identifier);
+            };
+        let put_node =
+            |bytes: &mut BytesMut, type_: u16, n: &NodeDescriptors| {
+                bytes.put_u16(type_);
+                bytes
+                    .put_u16(n.0.iter().map(|t| t.bytes_len()).sum::<usize>()
+                        as u16);
+                put_tlvs(bytes, &n.0);
+            };
+        match self {
+            LinkStateNlri::Node {
+                protocol_id,
+                identifier,
+                local_node,
+            } => {
+                put_header(&mut bytes, *protocol_id, *identifier);
+                put_node(&mut bytes, 256, local_node);
+            }
+            LinkStateNlri::Link {
+                protocol_id,
+                identifier,
+                local_node,
+                remote_node,
+                link_descriptors,
+            } => {
+                put_header(&mut bytes, *protocol_id, *identifier);
+                put_node(&mut bytes, 256, local_node);
+                put_node(&mut bytes, 257, remote_node);
+                put_tlvs(&mut bytes, link_descriptors);
+            }
+            LinkStateNlri::Prefix {
+                protocol_id,
+                identifier,
+                local_node,
+                prefix_descriptors,
+                ..
+            } => {
+                put_header(&mut bytes, *protocol_id, *identifier);
+                put_node(&mut bytes, 256, local_node);
+                put_tlvs(&mut bytes, prefix_descriptors);
+            }
+            LinkStateNlri::Unknown { value, .. } => bytes.put(&value[..]),
+        }
+        bytes
+    }
+
+    pub fn from_u8_slice(
+        bytes: &[u8],
+    ) -> Result<Vec<Self>, ConvertBytesToBgpMessageError> {
+        let mut nlris = vec![];
+        let mut i = 0;
+        while bytes.len() > i {
+            if bytes.len() < i + 4 {
+                return Err(ConvertBytesToBgpMessageError::from(
+                    anyhow::anyhow!("Link-State NLRIの長さが足りません。"),
+                ));
+            }
+            let nlri_type = u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+            let length =
+                u16::from_be_bytes([bytes[i + 2], bytes[i + 3]]) as usize;
+            let start = i + 4;
+            let end = start + length;
+            if bytes.len() < end {
+                return Err(ConvertBytesToBgpMessageError::from(
+                    anyhow::anyhow!("Link-State NLRIの長さが足りません。"),
+                ));
+            }
+            nlris.push(Self::from_type_and_value(
+                nlri_type,
+                &bytes[start..end],
+            )?);
+            i = end;
+        }
+        Ok(nlris)
+    }
+
+    fn from_type_and_value(
+        nlri_type: u16,
+        b: &[u8],
+    ) -> Result<Self, ConvertBytesToBgpMessageError> {
+        if !(1..=4).contains(&nlri_type) {
+            return Ok(LinkStateNlri::Unknown {
+                nlri_type,
+                value: b.to_vec(),
+            });
+        }
+        // Protocol-ID(1) + Identifier(8)
+        if b.len() < 9 {
+            return Err(ConvertBytesToBgpMessageError::from(anyhow::anyhow!(
+                "Link-State NLRIの長さが足りません。"
+            )));
+        }
+        let protocol_id = b[0];
+        let identifier =
+            u64::from_be_bytes(b[1..9].try_into().expect("長さは確認済み"));
+        let mut tlvs = Tlv::from_u8_slice(&b[9..])?.into_iter();
+        let mut node_descriptors =
+            |type_: u16| -> Result<_, ConvertBytesToBgpMessageError> {
+                match tlvs.next() {
+                    Some(t) if t.type_ == type_ => {
+                        Ok(NodeDescriptors(Tlv::from_u8_slice(&t.value)?))
+                    }
+                    _ => Err(ConvertBytesToBgpMessageError::from(
+                        anyhow::anyhow!(
+                            "Link-State NLRIにNode Descriptors(type {})が\
+                             ありません。",
+                            type_
+                        ),
+                    )),
+                }
+            };
+        let local_node = node_descriptors(256)?;
+        Ok(match nlri_type {
+            1 => LinkStateNlri::Node {
+                protocol_id,
+                identifier,
+                local_node,
+            },
+            2 => {
+                let remote_node = node_descriptors(257)?;
+                LinkStateNlri::Link {
+                    protocol_id,
+                    identifier,
+                    local_node,
+                    remote_node,
+                    link_descriptors: tlvs.collect(),
+                }
+            }
+            _ => LinkStateNlri::Prefix {
+                is_ipv6: nlri_type == 4,
+                protocol_id,
+                identifier,
+                local_node,
+                prefix_descriptors: tlvs.collect(),
+            },
+        })
+    }
+
+    fn local_node(&self) -> Option<&NodeDescriptors> {
+        match self {
+            LinkStateNlri::Node { local_node, .. }
+            | LinkStateNlri::Link { local_node, .. }
+            | LinkStateNlri::Prefix { local_node, .. } => Some(local_node),
+            LinkStateNlri::Unknown { .. } => None,
+        }
+    }
+}
+
+impl From<&LinkStateNlri> for BytesMut {
+    fn from(nlri: &LinkStateNlri) -> BytesMut {
+        let value = nlri.value_bytes();
+        let mut bytes = BytesMut::new();
+        bytes.put_u16(nlri.nlri_type());
+        bytes.put_u16(value.len() as u16);
+        bytes.put(value);
+        bytes
+    }
+}
+
+/// IP Reachability Information TLV(265)の値をprefixの文字列にする。
+fn ip_reachability(is_ipv6: bool, v: &[u8]) -> Option<String> {
+    let (prefix_length, bytes) = v.split_first()?;
+    if is_ipv6 {
+        let mut octets = [0u8; 16];
+        octets[..bytes.len().min(16)]
+            .copy_from_slice(&bytes[..bytes.len().min(16)]);
+        Some(format!("{}/{}", Ipv6Addr::from(octets), prefix_length))
+    } else {
+        let mut octets = [0u8; 4];
+        octets[..bytes.len().min(4)]
+            .copy_from_slice(&bytes[..bytes.len().min(4)]);
+        Some(format!("{}/{}", Ipv4Addr::from(octets), prefix_length))
+    }
+}
+
+/// BGP-LS Attribute (RFC7752 Section 3.3)。Node, Link, Prefixの属性をTLVで運ぶ。
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub struct LinkStateAttribute(pub Vec<Tlv>);
+
+impl LinkStateAttribute {
+    pub fn bytes_len(&self) -> usize {
+        self.0.iter().map(|t| t.bytes_len()).sum()
+    }
+
+    pub fn node_name(&self) -> Option<String> {
+        find_tlv(&self.0, 1026)
+            .map(|t| String::from_utf8_lossy(&t.value).into_owned())
+    }
+
+    pub fn igp_metric(&self) -> Option<u32> {
+        // IGP Metricは1-3 octetsの可変長
+        find_tlv(&self.0, 1095).map(|t| {
+            t.value.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32)
+        })
+    }
+
+    pub fn te_default_metric(&self) -> Option<u32> {
+        find_tlv(&self.0, 1092).and_then(|t| t.value_as_u32())
+    }
+
+    /// Maximum Link Bandwidth (bytes/秒)
+    pub fn max_link_bandwidth(&self) -> Option<f32> {
+        find_tlv(&self.0, 1089)
+            .and_then(|t| t.value_as_u32())
+            .map(f32::from_bits)
+    }
+
+    pub fn prefix_metric(&self) -> Option<u32> {
+        find_tlv(&self.0, 1155).and_then(|t| t.value_as_u32())
+    }
+}
+
+impl TryFrom<&[u8]> for LinkStateAttribute {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        Ok(Self(Tlv::from_u8_slice(value)?))
+    }
+}
+
+impl From<&LinkStateAttribute> for BytesMut {
+    fn from(a: &LinkStateAttribute) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        put_tlvs(&mut bytes, &a.0);
+        bytes
+    }
+}
+
+/// BGP-LSのルートを保持するRibのエントリです。
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+pub struct LinkStateRibEntry {
+    pub nlri: LinkStateNlri,
+    pub path_attributes: Arc<Vec<PathAttribute>>,
+}
+
+impl RibKey for LinkStateRibEntry {
+    type Key = LinkStateNlri;
+
+    fn key(&self) -> &LinkStateNlri {
+        &self.nlri
+    }
+}
+
+impl LinkStateRibEntry {
+    fn attribute(&self) -> Option<&LinkStateAttribute> {
+        self.path_attributes.iter().find_map(|p| match p {
+            PathAttribute::LinkState(a) => Some(a),
+            _ => None,
+        })
+    }
+}
+
+/// BGP-LSで収集したIGPのトポロジです。ノードはIGP Router-IDで識別します。
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct TopologyGraph {
+    pub nodes: BTreeMap<String, TopologyNode>,
+    pub links: Vec<TopologyLink>,
+}
+
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct TopologyNode {
+    pub name: Option<String>,
+    pub asn: Option<u32>,
+    pub prefixes: Vec<String>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct TopologyLink {
+    pub local: String,
+    pub remote: String,
+    pub igp_metric: Option<u32>,
+    pub max_link_bandwidth: Option<f32>,
+}
+
+impl TopologyGraph {
+    /// BGP-LSのRibからトポロジを組み立てる。
+    pub fn from_rib(rib: &Rib<LinkStateRibEntry>) -> Self {
+        let mut graph = Self::default();
+        let mut entries: Vec<&Arc<LinkStateRibEntry>> = rib.routes().collect();
+        // 出力を安定させるためにNLRIの順に処理する。
+        entries.sort_by(|a, b| a.nlri.cmp(&b.nlri));
+        for entry in entries {
+            let local_node = match entry.nlri.local_node() {
+                Some(n) => n,
+                None => continue,
+            };
+            let local = match local_node.igp_router_id() {
+                Some(id) => id,
+                None => continue,
+            };
+            let attribute = entry.attribute();
+            let node = graph.nodes.entry(local.clone()).or_default();
+            if node.asn.is_none() {
+                node.asn = local_node.asn();
+            }
+            match &entry.nlri {
+                LinkStateNlri::Node { .. } => {
+                    node.name = attribute.and_then(|a| a.node_name());
+                }
+                LinkStateNlri::Link { remote_node, .. } => {
+                    let remote = match remote_node.igp_router_id() {
+                        Some(id) => id,
+                        None => continue,
+                    };
+                    graph.nodes.entry(remote.clone()).or_default();
+                    graph.links.push(TopologyLink {
+                        local,
+                        remote,
+                        igp_metric: attribute.and_then(|a| a.igp_metric()),
+                        max_link_bandwidth: attribute
+                            .and_then(|a| a.max_link_bandwidth()),
+                    });
+                }
+                LinkStateNlri::Prefix {
+                    is_ipv6,
+                    prefix_descriptors,
+                    ..
+                } => {
+                    if let Some(prefix) = find_tlv(prefix_descriptors, 265)
+                        .and_then(|t| ip_reachability(*is_ipv6, &t.value))
+                    {
+                        node.prefixes.push(prefix);
+                    }
+                }
+                LinkStateNlri::Unknown { .. } => {}
+            }
+        }
+        graph
+    }
+}
+
+impl fmt::Display for TopologyGraph {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (id, node) in &self.nodes {
+            write!(f, "node {}", id)?;
+            if let Some(name) = &node.name {
+                write!(f, " name {}", name)?;
+            }
+            if let Some(asn) = node.asn {
+                write!(f, " as {}", asn)?;
+            }
+            writeln!(f)?;
+            for prefix in &node.prefixes {
+                writeln!(f, "  prefix {}", prefix)?;
+            }
+        }
+        for link in &self.links {
+            write!(f, "link {} -> {}", link.local, link.remote)?;
+            if let Some(metric) = link.igp_metric {
+                write!(f, " metric {}", metric)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(router_id: [u8; 4]) -> NodeDescriptors {
+        NodeDescriptors(vec![
+            Tlv {
+                type_: 512,
+                value: 64512u32.to_be_bytes().to_vec(),
+            },
+            Tlv {
+                type_: 515,
+                value: router_id.to_vec(),
+            },
+        ])
+    }
+
+    #[test]
+    fn convert_bytes_to_link_state_nlri_and_link_state_nlri_to_bytes() {
+        let nlris = vec![
+            LinkStateNlri::Node {
+                protocol_id: 3,
+                identifier: 0,
+                local_node: node([10, 0, 0, 1]),
+            },
+            LinkStateNlri::Link {
+                protocol_id: 3,
+                identifier: 0,
+                local_node: node([10, 0, 0, 1]),
+                remote_node: node([10, 0, 0, 2]),
+                link_descriptors: vec![Tlv {
+                    type_: 259,
+                    value: vec![192, 168, 0, 1],
+                }],
+            },
+        ];
+        let mut bytes = BytesMut::new();
+        nlris.iter().for_each(|n| bytes.put::<BytesMut>(n.into()));
+        assert_eq!(
+            bytes.len(),
+            nlris.iter().map(|n| n.bytes_len()).sum::<usize>()
+        );
+
+        assert_eq!(LinkStateNlri::from_u8_slice(&bytes[..]).unwrap(), nlris);
+    }
+
+    #[test]
+    fn topology_graph_from_link_state_rib() {
+        let mut rib = Rib::new();
+        rib.insert(Arc::new(LinkStateRibEntry {
+            nlri: LinkStateNlri::Node {
+                protocol_id: 3,
+                identifier: 0,
+                local_node: node([10, 0, 0, 1]),
+            },
+            path_attributes: Arc::new(vec![PathAttribute::LinkState(
+                LinkStateAttribute(vec![Tlv {
+                    type_: 1026,
+                    value: b"r1".to_vec(),
+                }]),
+            )]),
+        }));
+        rib.insert(Arc::new(LinkStateRibEntry {
+            nlri: LinkStateNlri::Link {
+                protocol_id: 3,
+                identifier: 0,
+                local_node: node([10, 0, 0, 1]),
+                remote_node: node([10, 0, 0, 2]),
+                link_descriptors: vec![],
+            },
+            path_attributes: Arc::new(vec![PathAttribute::LinkState(
+                LinkStateAttribute(vec![Tlv {
+                    type_: 1095,
+                    value: vec![0, 0, 10],
+                }]),
+            )]),
+        }));
+
+        let graph = TopologyGraph::from_rib(&rib);
+        assert_eq!(graph.nodes.len(), 2);
+        assert_eq!(graph.nodes["10.0.0.1"].name, Some("r1".to_string()));
+        assert_eq!(
+            graph.links,
+            vec![TopologyLink {
+                local: "10.0.0.1".to_string(),
+                remote: "10.0.0.2".to_string(),
+                igp_metric: Some(10),
+                max_link_bandwidth: None,
+            }]
+        );
+    }
+}
@@ -1,6 +1,8 @@
 /// BGPに特有のデータ型のうち、primitiveに近く、
 /// わざわざ個別にモジュールを用意するほどでもないデータ型を定義するモジュールです。
-use crate::error::ConvertBytesToBgpMessageError;
+use std::str::FromStr;
+
+use crate::error::{ConfigParseError, ConvertBytesToBgpMessageError};
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
 pub struct AutonomousSystemNumber(u16);
@@ -39,9 +41,19 @@ impl Default for HoldTime {
 }
 
 impl HoldTime {
+    // 0以外のHold Timeとして受け入れられる最小の秒数。(RFC4271 Section 4.2)
+    const MINIMUM: u16 = 3;
+
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// PeerがOPEN Messageで提案したHold Timeを受け入れられるか。
+    /// 0は常に受け入れ、1, 2秒は常に拒否する。
+    /// それ以外はminimum(ローカルで設定した最小値)以上であれば受け入れる。
+    pub fn is_acceptable(self, minimum: HoldTime) -> bool {
+        self.0 == 0 || self.0 >= minimum.0.max(Self::MINIMUM)
+    }
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
@@ -79,3 +91,221 @@ impl Version {
         Default::default()
     }
 }
+
+/// Address Family Identifier。
+/// (https://www.iana.org/assignments/address-family-numbers)
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum Afi {
+    Ipv4,
+    Ipv6,
+    L2vpn,
+    LinkState,
+}
+
+impl TryFrom<u16> for Afi {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(v: u16) -> Result<Self, Self::Error> {
+        match v {
+            1 => Ok(Afi::Ipv4),
+            2 => Ok(Afi::Ipv6),
+            25 => Ok(Afi::L2vpn),
+            16388 => Ok(Afi::LinkState),
+            _ => Err(Self::Error::from(anyhow::anyhow!(
+                "AFI {}には対応していません。",
+                v
+            ))),
+        }
+    }
+}
+
+impl From<Afi> for u16 {
+    fn from(afi: Afi) -> u16 {
+        match afi {
+            Afi::Ipv4 => 1,
+            Afi::Ipv6 => 2,
+            Afi::L2vpn => 25,
+            Afi::LinkState => 16388,
+        }
+    }
+}
+
+/// Subsequent Address Family Identifier。
+/// (https://www.iana.org/assignments/safi-namespace)
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum Safi {
+    Unicast,
+    LabeledUnicast,
+    MplsVpn,
+    FlowSpec,
+    Evpn,
+    LinkState,
+    RouteTargetConstraint,
+}
+
+impl TryFrom<u8> for Safi {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            1 => Ok(Safi::Unicast),
+            4 => Ok(Safi::LabeledUnicast),
+            128 => Ok(Safi::MplsVpn),
+            133 => Ok(Safi::FlowSpec),
+            70 => Ok(Safi::Evpn),
+            71 => Ok(Safi::LinkState),
+            132 => Ok(Safi::RouteTargetConstraint),
+            _ => Err(Self::Error::from(anyhow::anyhow!(
+                "SAFI {}には対応していません。",
+                v
+            ))),
+        }
+    }
+}
+
+impl From<Safi> for u8 {
+    fn from(safi: Safi) -> u8 {
+        match safi {
+            Safi::Unicast => 1,
+            Safi::LabeledUnicast => 4,
+            Safi::MplsVpn => 128,
+            Safi::FlowSpec => 133,
+            Safi::Evpn => 70,
+            Safi::LinkState => 71,
+            Safi::RouteTargetConstraint => 132,
+        }
+    }
+}
+
+/// AFIとSAFIの組です。MP-BGP(RFC4760)ではこの組ごとにルートを交換します。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct AddressFamily {
+    pub afi: Afi,
+    pub safi: Safi,
+}
+
+impl AddressFamily {
+    pub const IPV4_UNICAST: AddressFamily = AddressFamily {
+        afi: Afi::Ipv4,
+        safi: Safi::Unicast,
+    };
+    pub const IPV6_UNICAST: AddressFamily = AddressFamily {
+        afi: Afi::Ipv6,
+        safi: Safi::Unicast,
+    };
+    pub const IPV4_LABELED_UNICAST: AddressFamily = AddressFamily {
+        afi: Afi::Ipv4,
+        safi: Safi::LabeledUnicast,
+    };
+    pub const IPV6_LABELED_UNICAST: AddressFamily = AddressFamily {
+        afi: Afi::Ipv6,
+        safi: Safi::LabeledUnicast,
+    };
+    pub const IPV4_MPLS_VPN: AddressFamily = AddressFamily {
+        afi: Afi::Ipv4,
+        safi: Safi::MplsVpn,
+    };
+    pub const IPV4_FLOWSPEC: AddressFamily = AddressFamily {
+        afi: Afi::Ipv4,
+        safi: Safi::FlowSpec,
+    };
+    pub const L2VPN_EVPN: AddressFamily = AddressFamily {
+        afi: Afi::L2vpn,
+        safi: Safi::Evpn,
+    };
+    pub const IPV4_RTC: AddressFamily = AddressFamily {
+        afi: Afi::Ipv4,
+        safi: Safi::RouteTargetConstraint,
+    };
+    pub const LINK_STATE: AddressFamily = AddressFamily {
+        afi: Afi::LinkState,
+        safi: Safi::LinkState,
+    };
+
+    pub fn new(afi: Afi, safi: Safi) -> Self {
+        Self { afi, safi }
+    }
+}
+
+/// MP_REACH_NLRI, MP_UNREACH_NLRIやMultiprotocol Capabilityの先頭にある
+/// AFI(2 octets), SAFI(1 octet)のbytes表現から変換します。
+impl TryFrom<&[u8]> for AddressFamily {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() < 3 {
+            return Err(Self::Error::from(anyhow::anyhow!(
+                "AFI/SAFIを表すbytesの長さが足りません。"
+            )));
+        }
+        let afi = Afi::try_from(u16::from_be_bytes([bytes[0], bytes[1]]))?;
+        let safi = Safi::try_from(bytes[2])?;
+        Ok(Self { afi, safi })
+    }
+}
+
+impl FromStr for AddressFamily {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ipv4-unicast" | "ipv4" => Ok(AddressFamily::IPV4_UNICAST),
+            "ipv6-unicast" | "ipv6" => Ok(AddressFamily::IPV6_UNICAST),
+            "ipv4-labeled-unicast" | "ipv4-lu" => {
+                Ok(AddressFamily::IPV4_LABELED_UNICAST)
+            }
+            "ipv6-labeled-unicast" | "ipv6-lu" => {
+                Ok(AddressFamily::IPV6_LABELED_UNICAST)
+            }
+            "ipv4-vpn" | "vpnv4" => Ok(AddressFamily::IPV4_MPLS_VPN),
+            "ipv4-flowspec" | "flowspec" => Ok(AddressFamily::IPV4_FLOWSPEC),
+            "l2vpn-evpn" | "evpn" => Ok(AddressFamily::L2VPN_EVPN),
+            "link-state" | "bgp-ls" => Ok(AddressFamily::LINK_STATE),
+            "ipv4-rtc" | "rtc" => Ok(AddressFamily::IPV4_RTC),
+            _ => Err(ConfigParseError::from(anyhow::anyhow!(
+                "cannot parse {s} as address family"
+            ))),
+        }
+    }
+}
+
+/// MPLSのラベル(20 bits)です。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct MplsLabel(u32);
+
+impl MplsLabel {
+    /// MP_UNREACH_NLRIでラベルの代わりに使われる値 (RFC8277 Section 2.4)
+    pub const WITHDRAWN: MplsLabel = MplsLabel(0x80000);
+    /// 受信側でラベルをpopしてIPパケットとして転送させるためのラベル (RFC3032)
+    pub const IMPLICIT_NULL: MplsLabel = MplsLabel(3);
+
+    pub fn new(label: u32) -> Result<Self, ConfigParseError> {
+        if label > 0xfffff {
+            return Err(ConfigParseError::from(anyhow::anyhow!(
+                "MPLS label {} is larger than 20 bits",
+                label
+            )));
+        }
+        Ok(Self(label))
+    }
+
+    /// NLRI中のラベル(3 octets)から変換する。
+    /// 戻り値の2つ目はBottom of Stackのbitが立っているかを表す。
+    pub fn from_nlri_bytes(bytes: [u8; 3]) -> (Self, bool) {
+        let v = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        (Self(v >> 4), v & 1 == 1)
+    }
+
+    /// NLRI中のラベル(3 octets)に変換する。TC(旧EXP)のbitsは0にする。
+    pub fn to_nlri_bytes(self, bottom_of_stack: bool) -> [u8; 3] {
+        let v = (self.0 << 4) | bottom_of_stack as u32;
+        let b = v.to_be_bytes();
+        [b[1], b[2], b[3]]
+    }
+}
+
+impl From<MplsLabel> for u32 {
+    fn from(label: MplsLabel) -> u32 {
+        label.0
+    }
+}
@@ -0,0 +1,222 @@
+//! BGP Message, MRTダンプを人が読める形式やJSONに変換して表示するコマンドです。
+//!
+//! ```text
+//! mrbgp-decode [--json] hex [HEX...]   16進数の文字列(省略時は標準入力)
+//! mrbgp-decode [--json] raw <FILE>     BGP Messageが連続したファイル
+//! mrbgp-decode [--json] mrt <FILE>     MRT(RFC6396)形式のファイル(非圧縮)
+//! ```
+use std::env;
+use std::fmt::Debug;
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+
+use mrbgpdv2::wire::{self, Message, MrtBody, MrtRecord};
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let json = match args.iter().position(|a| a == "--json") {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+    if let Err(e) = run(&args, json) {
+        eprintln!("mrbgp-decode: {:?}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[String], json: bool) -> anyhow::Result<()> {
+    let (mode, rest) = match args.split_first() {
+        Some((mode, rest)) => (mode.as_str(), rest),
+        None => {
+            anyhow::bail!(
+                "usage: mrbgp-decode [--json] hex [HEX...] | raw <FILE> | \
+                 mrt <FILE>"
+            )
+        }
+    };
+    match mode {
+        "hex" => {
+            let input = if rest.is_empty() {
+                let mut s = String::new();
+                io::stdin().read_to_string(&mut s)?;
+                s
+            } else {
+                rest.join(" ")
+            };
+            print_messages(&wire::parse_hex(&input)?, json);
+        }
+        "raw" => print_messages(&fs::read(file_path(rest)?)?, json),
+        "mrt" => {
+            for record in wire::decode_mrt(&fs::read(file_path(rest)?)?) {
+                match record {
+                    Ok(record) if json => {
+                        println!("{}", mrt_record_json(&record))
+                    }
+                    Ok(record) => println!("{:#?}", record),
+                    Err(e) => print_error(&e, json),
+                }
+            }
+        }
+        _ => anyhow::bail!("unknown mode {}", mode),
+    }
+    Ok(())
+}
+
+fn file_path(args: &[String]) -> anyhow::Result<&str> {
+    args.first()
+        .map(|s| s.as_str())
+        .ok_or_else(|| anyhow::anyhow!("ファイルを指定してください。"))
+}
+
+fn print_messages(bytes: &[u8], json: bool) {
+    for message in wire::decode_messages(bytes) {
+        match message {
+            Ok(message) if json => println!("{}", message_json(&message)),
+            Ok(message) => println!("{:#?}", message),
+            Err(e) => print_error(&e, json),
+        }
+    }
+}
+
+fn print_error(e: &wire::ConvertBytesToBgpMessageError, json: bool) {
+    if json {
+        println!("{{\"error\":{}}}", string(e));
+    } else {
+        println!("error: {}", e);
+    }
+}
+
+/// JSONの文字列にする。
+fn string(s: impl ToString) -> String {
+    let mut escaped = String::from("\"");
+    for c in s.to_string().chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                escaped.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// 各要素をJSONの文字列にした配列にする。
+fn array<T>(items: &[T], f: impl Fn(&T) -> String) -> String {
+    let items: Vec<String> = items.iter().map(|i| string(f(i))).collect();
+    format!("[{}]", items.join(","))
+}
+
+fn debug<T: Debug>(v: &T) -> String {
+    format!("{:?}", v)
+}
+
+fn message_json(message: &Message) -> String {
+    match message {
+        Message::Open(open) => format!(
+            "{{\"type\":\"open\",\"as\":{},\"bgp_identifier\":{},\
+             \"capabilities\":{}}}",
+            u16::from(open.my_as_number),
+            string(open.bgp_identifier),
+            array(&open.capabilities, debug),
+        ),
+        Message::Keepalive(_) => "{\"type\":\"keepalive\"}".to_string(),
+        Message::Update(update) => format!(
+            "{{\"type\":\"update\",\"withdrawn_routes\":{},\
+             \"path_attributes\":{},\"nlri\":{}}}",
+            array(&update.withdrawn_routes, |n| n.to_string()),
+            array(&update.path_attributes, debug),
+            array(&update.network_layer_reachability_information, |n| {
+                n.to_string()
+            }),
+        ),
+        Message::Notification(notification) => format!(
+            "{{\"type\":\"notification\",\"error_code\":{},\
+             \"error_subcode\":{}}}",
+            notification.error_code, notification.error_subcode,
+        ),
+        Message::RouteRefresh(route_refresh) => format!(
+            "{{\"type\":\"route_refresh\",\"address_family\":{}}}",
+            string(debug(&route_refresh.address_family)),
+        ),
+    }
+}
+
+fn mrt_record_json(record: &MrtRecord) -> String {
+    let body = match &record.body {
+        MrtBody::Bgp4mpMessage { peer, message } => format!(
+            "\"type\":\"bgp4mp_message\",\"peer_as\":{},\"peer_ip\":{},\
+             \"local_as\":{},\"local_ip\":{},\"message\":{}",
+            peer.peer_as,
+            string(peer.peer_ip),
+            peer.local_as,
+            string(peer.local_ip),
+            message_json(message),
+        ),
+        MrtBody::Bgp4mpStateChange {
+            peer,
+            old_state,
+            new_state,
+        } => format!(
+            "\"type\":\"bgp4mp_state_change\",\"peer_as\":{},\"peer_ip\":{},\
+             \"old_state\":{},\"new_state\":{}",
+            peer.peer_as,
+            string(peer.peer_ip),
+            old_state,
+            new_state,
+        ),
+        MrtBody::PeerIndexTable {
+            collector_bgp_id,
+            view_name,
+            peers,
+        } => format!(
+            "\"type\":\"peer_index_table\",\"collector_bgp_id\":{},\
+             \"view_name\":{},\"peers\":{}",
+            string(collector_bgp_id),
+            string(view_name),
+            array(peers, |(id, ip, asn)| format!("{} {} AS{}", id, ip, asn)),
+        ),
+        MrtBody::RibEntries {
+            sequence,
+            prefix,
+            entries,
+        } => {
+            let entries: Vec<String> = entries
+                .iter()
+                .map(|e| {
+                    format!(
+                        "{{\"peer_index\":{},\"originated_time\":{},\
+                         \"path_attributes\":{}}}",
+                        e.peer_index,
+                        e.originated_time,
+                        array(&e.path_attributes, debug),
+                    )
+                })
+                .collect();
+            format!(
+                "\"type\":\"rib\",\"sequence\":{},\"prefix\":{},\
+                 \"entries\":[{}]",
+                sequence,
+                string(prefix),
+                entries.join(","),
+            )
+        }
+        MrtBody::Unknown {
+            type_,
+            subtype,
+            length,
+        } => format!(
+            "\"type\":\"unknown\",\"mrt_type\":{},\"mrt_subtype\":{},\
+             \"length\":{}",
+            type_, subtype, length,
+        ),
+    };
+    format!("{{\"timestamp\":{},{}}}", record.timestamp, body)
+}
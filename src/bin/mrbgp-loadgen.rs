@@ -0,0 +1,87 @@
+//! 負荷試験用のBGPスピーカーです。Peerと接続し、合成ルートを指定したレートで広報します。
+//! 全てのルートをoriginateするまでの時間と、プロセスのメモリ使用量を表示します。
+//!
+//! ```text
+//! mrbgp-loadgen [--count N] [--rate ROUTES_PER_SEC] [--base NETWORK]
+//!               [--prefix-length LEN] [--next-hop ADDRESS] <config...>
+//! ```
+//! `<config...>`はmrbgpdv2と同じ形式です。
+use std::env;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use mrbgpdv2::config::Config;
+use mrbgpdv2::loadgen::{self, SyntheticRoutes};
+use mrbgpdv2::peer::Peer;
+use mrbgpdv2::routing::LocRib;
+use tokio::sync::Mutex;
+use tracing::info;
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+    if let Err(e) = run(env::args().skip(1).collect()).await {
+        eprintln!("mrbgp-loadgen: {:?}", e);
+        process::exit(1);
+    }
+}
+
+async fn run(mut args: Vec<String>) -> Result<()> {
+    let count: u32 = take_option(&mut args, "--count")?.unwrap_or(10000);
+    let rate: u32 = take_option(&mut args, "--rate")?.unwrap_or(1000);
+    let base = take_option(&mut args, "--base")?
+        .unwrap_or_else(|| "100.64.0.0/10".parse().unwrap());
+    let prefix_length: u8 =
+        take_option(&mut args, "--prefix-length")?.unwrap_or(24);
+    let next_hop = take_option(&mut args, "--next-hop")?;
+    let config: Config = args.join(" ").parse()?;
+
+    let routes =
+        SyntheticRoutes::new(base, prefix_length, count, config.local_as)?;
+    let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await?));
+    let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+    peer.start();
+    tokio::spawn(async move {
+        loop {
+            peer.next().await;
+        }
+    });
+
+    let elapsed =
+        loadgen::originate(&loc_rib, &routes, next_hop, rate).await?;
+    info!(
+        "{} routes are originated in {:?}, rss={:?}kB.",
+        routes.count(),
+        elapsed,
+        loadgen::resident_memory_kb()
+    );
+    // Peerとのセッションを維持し、メモリ使用量を表示し続ける。
+    loop {
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        info!("rss={:?}kB.", loadgen::resident_memory_kb());
+    }
+}
+
+/// argsから`name value`を取り除き、valueをparseして返す。
+fn take_option<T: std::str::FromStr>(
+    args: &mut Vec<String>,
+    name: &str,
+) -> Result<Option<T>>
+where
+    T::Err: std::fmt::Debug,
+{
+    let i = match args.iter().position(|a| a == name) {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+    if i + 1 >= args.len() {
+        anyhow::bail!("{} requires a value", name);
+    }
+    let value = args.remove(i + 1);
+    args.remove(i);
+    value.parse().map(Some).map_err(|e| {
+        anyhow::anyhow!("cannot parse {} {}: {:?}", name, value, e)
+    })
+}
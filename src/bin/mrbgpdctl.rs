@@ -0,0 +1,113 @@
+//! 動作中のmrbgpdv2にcontrol socketを通じてルートの広報, 取り消しや
+//! セッションのリセットを指示するコマンドです。
+//!
+//! ```text
+//! mrbgpdctl [--socket <PATH>] announce [vrf <name>] <network> [--next-hop <address>] [--community <asn>:<value>|blackhole]...
+//! mrbgpdctl [--socket <PATH>] withdraw [vrf <name>] <network>
+//! mrbgpdctl [--socket <PATH>] clear bgp neighbor <address> [soft [in|out]]
+//! mrbgpdctl [--socket <PATH>] show bgp [vrf <name>] <network>
+//! mrbgpdctl [--socket <PATH>] show bgp neighbor <address> [messages]
+//! mrbgpdctl [--socket <PATH>] show bgp [vrf <name>] statistics
+//! mrbgpdctl [--socket <PATH>] maintenance bgp [neighbor <address>] <on|off>
+//! mrbgpdctl [--socket <PATH>] set bgp neighbor <address> log-level <trace|debug|info|warn|error|off|default>
+//! mrbgpdctl [--socket <PATH>] dump bgp rib
+//! mrbgpdctl diff bgp rib <old> <new>
+//! ```
+//!
+//! `vrf <name>`を指定すると、そのlinux-vrfのLocRibを操作する。
+//!
+//! `diff bgp rib`はcontrol socketを使わず、`dump bgp rib`で書き出した
+//! 2つのファイルを比較して、追加(+), 削除(-), PathAttributeが変わった(~)
+//! ルートを表示する。差分があれば終了コード1で終了する。
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+use mrbgpdv2::control::{self, ControlCommand, DEFAULT_CONTROL_SOCKET};
+use mrbgpdv2::dump;
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("mrbgpdctl: control socket is only supported on unix.");
+    process::exit(1);
+}
+
+#[cfg(unix)]
+#[tokio::main]
+async fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let socket = match args.iter().position(|a| a == "--socket") {
+        Some(i) if i + 1 < args.len() => {
+            let path = args.remove(i + 1);
+            args.remove(i);
+            PathBuf::from(path)
+        }
+        _ => PathBuf::from(DEFAULT_CONTROL_SOCKET),
+    };
+    if let ["diff", "bgp", "rib", old, new] =
+        args.iter().map(|a| a.as_str()).collect::<Vec<_>>()[..]
+    {
+        diff_rib(old, new).await;
+    }
+    let command: ControlCommand = match args.join(" ").parse() {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("mrbgpdctl: {}", e);
+            eprintln!(
+                "usage: mrbgpdctl [--socket <PATH>] announce [vrf <name>] \
+                 <network> [--next-hop <address>] [--community <asn>:<value>]...\n       \
+                 mrbgpdctl [--socket <PATH>] withdraw [vrf <name>] \
+                 <network>\n       \
+                 mrbgpdctl [--socket <PATH>] clear bgp neighbor <address> \
+                 [soft [in|out]]\n       \
+                 mrbgpdctl [--socket <PATH>] show bgp [vrf <name>] \
+                 <network>\n       \
+                 mrbgpdctl [--socket <PATH>] show bgp neighbor <address> \
+                 [messages]\n       \
+                 mrbgpdctl [--socket <PATH>] show bgp [vrf <name>] \
+                 statistics\n       \
+                 mrbgpdctl [--socket <PATH>] maintenance bgp \
+                 [neighbor <address>] <on|off>\n       \
+                 mrbgpdctl [--socket <PATH>] set bgp neighbor <address> \
+                 log-level <trace|debug|info|warn|error|off|default>\n       \
+                 mrbgpdctl [--socket <PATH>] dump bgp rib\n       \
+                 mrbgpdctl diff bgp rib <old> <new>"
+            );
+            process::exit(2);
+        }
+    };
+    match control::request(&socket, &command).await {
+        Ok(response) if response == "ok" => {}
+        Ok(response) if !response.starts_with("error") => {
+            println!("{}", response)
+        }
+        Ok(response) => {
+            eprintln!("mrbgpdctl: {}", response);
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("mrbgpdctl: {:?}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// 2つのダンプの差分を表示して終了する。
+#[cfg(unix)]
+async fn diff_rib(old: &str, new: &str) -> ! {
+    let (old, new) = match tokio::try_join!(
+        dump::read_rib_dump(old.as_ref()),
+        dump::read_rib_dump(new.as_ref())
+    ) {
+        Ok(dumps) => dumps,
+        Err(e) => {
+            eprintln!("mrbgpdctl: {:?}", e);
+            process::exit(2);
+        }
+    };
+    let changes = dump::diff_rib_dumps(&old, &new);
+    for change in &changes {
+        println!("{}", change);
+    }
+    process::exit(if changes.is_empty() { 0 } else { 1 });
+}
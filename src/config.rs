@@ -1,18 +1,425 @@
-use crate::bgp_type::AutonomousSystemNumber;
+use crate::bgp_type::{
+    AddressFamily, AutonomousSystemNumber, HoldTime, MplsLabel,
+};
+use crate::control::DEFAULT_CONTROL_SOCKET;
+use crate::dump::DEFAULT_DUMP_DIR;
 use crate::error::ConfigParseError;
-use crate::routing::Ipv4Network;
+use crate::flowspec::{FlowSpecEnforcement, FlowSpecRoute};
+use crate::kernel::{RouteProtocol, MAIN_TABLE};
+use crate::packets::capability::{Capability, LlgrFamily};
+use crate::path_attribute::{Community, ExtendedCommunity, Origin};
+use crate::policy::Policy;
+use crate::routing::IpNetwork;
+use crate::vpn::{RouteTarget, VrfConfig};
 use anyhow::{Context, Result};
-use std::net::Ipv4Addr;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
+// Hostname CapabilityのValueは255 octetsまでなので、
+// 2つの長さのoctetを除いたホスト名とドメイン名の長さの合計。
+const MAX_FQDN_LENGTH: usize = 253;
+
+/// `local-pref`を省略した場合のLOCAL_PREF。
+/// LOCAL_PREFの無いルートもこの値として経路選択する。
+pub const DEFAULT_LOCAL_PREF: u32 = 100;
+
+/// Peer毎の設定です。以下の形式の文字列からparseします。
+/// `<local_as> <local_ip> <remote_as> <remote_ip> <mode> [network...] [key=value...]`
+///
+/// `network`はカーネルのルーティングテーブルに同じネットワークのルートが
+/// 存在する間だけ広報する。ルーティングテーブルの変化には起動後も追従し、
+/// ルートが消えれば広報をやめ、現れれば広報を始める。
+///
+/// keyとして指定できるものは以下の通り。
+/// - `router-id`: BGP Identifier。local_ipがIPv6の場合は必須。
+/// - `always-advertise`: カーネルのルーティングテーブルを確認せず、
+///   常に広報するネットワーク。(例: `always-advertise=10.1.0.0/24`)
+/// - `address-family`: 広報するaddress familyをカンマ区切りで指定する。
+///   (例: `address-family=ipv4-unicast,ipv6-unicast,vpnv4`)
+///   `evpn`(l2vpn-evpn)と`bgp-ls`(link-state)のルートは受信して保持するだけで、広報はしない。
+///   `rtc`(ipv4-rtc)を指定するとVRFのimport route targetをmembershipとして広報し、
+///   Peerのmembershipに一致するVPNv4ルートだけを広報する。
+/// - `vrf-rd`: VRFを作成し、Route Distinguisherを設定する。(例: `vrf-rd=blue:64512:1`)
+/// - `vrf-import`, `vrf-export`: VRFのimport/export route targetを
+///   カンマ区切りで指定する。(例: `vrf-import=blue:64512:100,64512:101`)
+/// - `vrf-label`: VRFのルートに付けるMPLSラベル。(例: `vrf-label=blue:100`)
+/// - `vrf-network`: VRF内でoriginateするネットワーク。(例: `vrf-network=blue:10.1.0.0/24`)
+///
+/// - `labeled-network`: Labeled unicastとしてoriginateするネットワークとラベル。
+///   (例: `labeled-network=10.1.0.0/24,100`)
+/// - `mpls-encap`: `on`の場合、受信したLabeled unicastのルートを
+///   MPLS encapのルートとしてカーネルに書き込む。
+/// - `kernel-metric`: カーネルのルーティングテーブルに書き込むルートのmetric。
+///   ルートはprotocolを`bgp`にして書き込み、削除する時はprotocolとmetricが
+///   一致するルートだけを削除するので、同じネットワークのstaticなどのルートは消さない。
+///   (省略時は20)
+/// - `linux-vrf`: `<vrfデバイス>:<テーブルのid>`の形式で、PeerをLinuxのvrfに
+///   所属させる。同じvrfのPeerだけで1つのLocRibを共有し、受信したルートはvrfの
+///   テーブルに書き込む。Peerとの接続はvrfデバイスにbindする。`network`, `redistribute`
+///   やnext hopの解決にもvrfのテーブルを使う。passiveのPeerからの接続を受けるには、
+///   `net.ipv4.tcp_l3mdev_accept=1`が必要。(例: `linux-vrf=red:10`)
+/// - `flowspec`: originateするFlowSpecのルール。
+///   (例: `flowspec=dst:203.0.113.0/24,proto:6,dport:80|443`)
+/// - `flowspec-rate`, `flowspec-mark`, `flowspec-redirect`: 直前の`flowspec`の
+///   ルールに付けるaction。rateはbytes/秒で0ならdiscard。
+///   (例: `flowspec-rate=0`, `flowspec-mark=10`, `flowspec-redirect=64512:100`)
+/// - `flowspec-enforcement`: 受信したFlowSpecのルールを反映する先。(例: `nftables`)
+/// - `update-rate`: Peerに送るUPDATE Messageの1秒あたりの最大数。
+///   省略時は制限しない。(例: `update-rate=1000`)
+/// - `hold-time`: OPEN Messageで提案するHold Timeの秒数。(省略時は240)
+///   Peerの提案と小さい方が使われ、0の場合はHold TimerとKEEPALIVEの送信を止める。
+///   1, 2秒はRFC4271で禁止されているので指定できない。
+/// - `min-hold-time`: Peerが提案するHold Timeとして受け入れる最小の秒数。
+///   これより短いHold Time(0を除く)を提案されると、NOTIFICATIONを送って拒否する。
+///   (省略時は3。1, 2秒は指定に関わらず常に拒否する)
+/// - `connect-retry-time`: TCPの接続に失敗してから、次に接続を試みるまでの秒数。
+///   RFC4271のConnectRetryTimer。0は指定できない。(省略時は120)
+/// - `delay-open-time`: TCPの接続が確立してから、PeerのOPEN Messageを待つ秒数。
+///   その間にPeerから受信しなければ自身のOPEN Messageを送る。
+///   RFC4271のDelayOpenTimer。省略時はすぐにOPEN Messageを送る。
+/// - `required-capabilities`: Peerが広報しなければならないCapabilityを
+///   カンマ区切りで指定する。`route-refresh`かaddress family(そのMultiprotocol
+///   Capability)を指定でき、足りない場合はUnsupported Capabilityの
+///   NOTIFICATIONを送ってセッションを拒否する。(例: `required-capabilities=route-refresh,vpnv4`)
+/// - `capability-fallback`: `on`の場合、PeerからUnsupported Capabilityか
+///   Unsupported Optional ParameterのNOTIFICATIONを受け取ったら、
+///   以降はCapabilityを付けずにOPEN Messageを送り直す。
+/// - `dscp`: sessionのパケットに付けるDSCP。`cs0`-`cs7`, `af11`-`af43`, `ef`か
+///   0-63の数値で指定する。IPv4のsessionのみ対応。(例: `dscp=cs6`)
+/// - `ttl`: sessionのパケットのTTL(IPv6ではHop Limit)。省略時はOSの既定値。
+///   GTSM(RFC5082)を使うPeerには255を指定する。
+/// - `route-count-warning`: LocRibのルートの数がこれを超えたら警告する。
+/// - `maximum-prefix`: Peerから受信するルートの数の上限。超えた場合は警告し、
+///   CeaseのNOTIFICATION(Maximum Number of Prefixes Reached)を送ってセッションを閉じる。
+/// - `maximum-prefix-action`: `warn`の場合、`maximum-prefix`を超えても警告するだけで
+///   セッションを閉じない。(省略時は`teardown`)
+/// - `maximum-prefix-restart`: `maximum-prefix`を超えてセッションを閉じてから、
+///   再び接続を試みるまでの秒数。省略時は他の理由でセッションを閉じた場合と同じ。
+/// - `damping`: `on`の場合、Peerから受信したルートのroute flap damping(RFC2439)を
+///   行う。`<half-life>,<reuse>,<suppress>,<max-suppress>`の形式でパラメータを
+///   指定することもできる。half-life, max-suppressは秒数。
+///   (省略時は`off`、`on`の場合は`900,750,2000,3600`)
+/// - `memory-warning`: プロセスのメモリ使用量(kB)がこれを超えたら警告する。
+/// - `advertise-if-exist`, `advertise-if-not-exist`: `<network>,<condition>`の
+///   形式で指定し、conditionのネットワークのルートがLocRibに存在する(しない)間だけ
+///   networkをこのPeerに広報する。LocRibが変わる度に評価する。
+///   (例: `advertise-if-not-exist=0.0.0.0/0,198.51.100.0/24`)
+/// - `network-origin`, `network-med`, `network-community`: `<network>,<値>`の形式で、
+///   自身がoriginateするネットワークのORIGIN(`igp`, `egp`, `incomplete`), MED,
+///   Communityを指定する。`network-community`は繰り返し指定できる。
+///   (例: `network-origin=10.1.0.0/24,incomplete network-med=10.1.0.0/24,100`)
+/// - `network-extended-community`: `<network>,<値>`の形式で、自身がoriginateする
+///   ネットワークに付けるExtended Communityを指定する。繰り返し指定できる。
+///   値は`rt:<administrator>:<値>`(Route Target), `soo:<administrator>:<値>`
+///   (Route Origin), `bandwidth:<AS番号>:<bytes/秒>`(Link Bandwidth)のいずれか。
+///   (例: `network-extended-community=10.1.0.0/24,bandwidth:64512:125000000`)
+/// - `redistribute`: `<protocol>[,<prefix>]`の形式で、カーネルのmainの
+///   ルーティングテーブルからprotocolが一致するルートを探して広報する。
+///   prefixを指定した場合は、それに含まれるルートだけを広報する。ORIGINは
+///   INCOMPLETEになる。protocolは`connected`, `kernel`(protocolを指定せずに
+///   `ip route add`したルート), `static`, `dhcp`, `isis`, `ospf`, `rip`か数値で
+///   指定する。ルーティングテーブルの変化には`network`と同様に追従する。
+///   繰り返し指定できる。(例: `redistribute=static,10.0.0.0/8`)
+/// - `allowed-origination`: 自身がoriginateしてよいネットワークをカンマ区切りで指定する。
+///   指定した場合、これに含まれない`network`, `always-advertise`, `labeled-network`と
+///   control socketからのannounceは拒否してログに残す。(例: `allowed-origination=203.0.113.0/24`)
+/// - `own-prefix-check`: eBGPのPeerから`allowed-origination`に含まれるルートを
+///   受信した場合に、`warn`なら警告し、`reject`なら警告してルートを受け入れない。
+///   (省略時は`off`)
+/// - `confederation-id`: confederation(RFC5065)のidentifier。`<local_as>`は
+///   自身のmember ASになり、confederationの外のPeerにはこのAS番号を使う。
+/// - `confederation-peers`: confederation内の他のmember ASをカンマ区切りで指定する。
+///   `<remote_as>`がこれに含まれるPeerとはconfederation内のeBGPとしてセッションを張る。
+///   (例: `confederation-id=64512 confederation-peers=65001,65002`)
+/// - `route-reflector-client`: `on`の場合、iBGPのPeerをroute reflector(RFC4456)の
+///   clientとして扱い、他のiBGPのPeerから受信したルートも反射する。
+///   cluster idには自身のBGP Identifierを使う。
+/// - `next-hop-self`: `off`の場合、Peerから受信したルートをNext Hopを
+///   書き換えずに広報する。Peerと受信元が同じセグメントにいて、直接転送できる
+///   場合に使う。(省略時は`on`)
+/// - `import-policy`, `export-policy`: Peerから受信したルートをLocRibに入れる時と、
+///   LocRibのルートをPeerに広報する時に適用するルール。`permit`か`deny`に続けて
+///   `,`区切りで条件(`prefix`, `as-path`, `as-path-regex`, `community`,
+///   `large-community`)と変更(`set-local-pref`, `set-med`, `set-community`,
+///   `delete-community`, `set-large-community`, `delete-large-community`,
+///   `prepend`)を`<kind>:<value>`の形式で並べる。`as-path-regex`は`^64513_`,
+///   `_174$`のような正規表現で、AS番号の区切りに一致する`_`を使える。
+///   `delete-community:all`, `delete-large-community:all`は全てのCommunityを取り除く。
+///   NO_EXPORT, NO_ADVERTISEはimport-policyで付けた場合も従い、export-policyより先に評価する。
+///   複数指定した場合は書いた順に評価し、最初に一致したルールを適用する。
+///   指定した場合、どのルールにも一致しないルートは受け入れない(広報しない)。
+///   `prepend`はimportではPeerのAS番号、exportでは自身のAS番号を追加する。
+///   他のASから受信したルートのMEDは、`set-med`しても他のASのPeerには広報しない。
+///   iBGPとconfederation内のPeerとの間では`prepend`は何もしない。
+/// - `as-path-prepend`: このPeerに広報する全てのルートのAS Pathの先頭に、
+///   export-policyを適用した後で自身のAS番号を指定した回数だけ追加する。
+///   iBGPとconfederation内のPeerには無視する。(省略時は0)
+/// - `default-originate`: `on`の場合、LocRibやカーネルのルーティングテーブルに
+///   デフォルトルートが無くても、このPeerに0.0.0.0/0を広報する。
+///   import-policyと同じ形式のルールを指定した場合は、ルールでpermitされる
+///   ルートがLocRibにある間だけ広報する。ルールは繰り返し指定できる。
+///   export-policyは適用しない。(省略時は`off`)
+///   (例: `default-originate=permit,prefix:198.51.100.0/24`)
+///   (例: `import-policy=deny,as-path:64600 import-policy=permit,prefix:10.0.0.0/8,set-local-pref:200`)
+/// - `local-pref`: 自身がoriginateするルートと、eBGPのPeerから受信したルートに
+///   付けるLOCAL_PREF。大きいほど優先する。(省略時は100)
+/// - `llgr-stale-time`: Long-Lived Graceful Restartで、セッションが切れた後に
+///   Peerのルートを`llgr-stale`を付けて優先度を下げて保持する秒数。(最大16777215)
+///   指定した場合にLLGR Capabilityを送信し、Peerの値と小さい方を使う。
+/// - `bfd`: `on`の場合、PeerとBFDのセッションを張り、BFDがDownになったら
+///   Hold Timerの満了を待たずにBGPのセッションを閉じる。(省略時は`off`)
+/// - `bfd-interval`: BFDのControl Packetの送受信の間隔(ミリ秒)。(省略時は300)
+/// - `bfd-multiplier`: BFDのDetect Mult。この回数受信できなければDownとする。(省略時は3)
+/// - `hostname`, `domain-name`: Hostname CapabilityでPeerに伝えるホスト名とドメイン名。
+///   `hostname`の省略時はシステムのホスト名を使い、`domain-name`の省略時は空にする。
+///   合わせて253文字まで。
+/// - `maintenance-delay`: maintenance modeにしてから、Peerへの広報を変更して
+///   トラフィックが他の経路に移るのを待ち、セッションを閉じるまでの秒数。(省略時は60)
+/// - `maintenance-graceful-shutdown`: `on`の場合、maintenance mode中は
+///   広報するルートにGRACEFUL_SHUTDOWN Community(RFC8326)を付ける。(省略時は`on`)
+/// - `maintenance-prepend`: maintenance mode中に、広報するルートのAS Pathに
+///   追加で自AS番号を付ける回数。(省略時は0)
+/// - `maintenance-med`: maintenance mode中に、広報するルートに付けるMED。
+///   大きい値にすると、Peerはこのルートを選ばなくなる。(省略時は変更しない)
+/// - `message-log-size`: Peerと送受信したメッセージを直近のいくつまで保持するか。
+///   `show bgp neighbor <address> messages`で確認できる。0の場合は保持しない。
+///   (省略時は100)
+/// - `accept-inbound`: `on`の場合、activeのPeerでも自身から接続を試みつつ、
+///   Peerからの接続も受け付ける。再起動直後に自身のbindが失敗し続けていても、
+///   先に確立できた方の接続でセッションを張れる。
+/// - `control-socket`: mrbgpdctlから操作するためのUnix domain socketのパス。
+///   (省略時は`/var/run/mrbgpdv2.sock`)
+/// - `startup-wait`: 起動後、全てのPeerがEstablishedになるまで、どのPeerにも
+///   ルートを広報せずに待つ最大の秒数。収束途中のLocRibを広報しないようにする。
+///   (省略時は待たない)
+/// - `worker-threads`: tokioのruntimeのworker threadの数。(省略時はCPUの数)
+/// - `peer-task-pool`: 指定した数のタスクに全てのPeerを割り当てて動かす。
+///   Peerが数百あるような場合に、タスクの切り替えを減らすために使う。
+///   (省略時はPeer毎に1つのタスクで動かす)
+/// - `maximum-paths`: カーネルのルーティングテーブルに書き込む、ネットワーク毎の
+///   ルートの最大数。best pathとnext hop以外で優劣の付かないルートがあれば、
+///   それらのnext hopへのECMPのルートとして書き込む。(省略時は1)
+/// - `fib-writer-task`: `on`の場合、カーネルのルーティングテーブルへの書き込みを
+///   専用のタスクで行い、LocRibのlockを持ったまま書き込みを待たないようにする。
+///   (省略時は`off`)
+/// - `fib-reconcile-interval`: カーネルのルーティングテーブルの自身が書き込んだルートを、
+///   LocRibが書き込んだはずのルートと比べる間隔の秒数。`ip route flush`などで
+///   無くなったルートは書き込み直し、LocRibに無いルートは削除する。
+///   0の場合は比べない。(省略時は60)
+/// - `dump-dir`: SIGUSR1か`dump bgp rib`でRIBのダンプを書き出すディレクトリ。
+///   (省略時は`/var/tmp`)
+///
+/// `vrf-*`は`vrf-rd`でVRFを作成した後に指定する。
 #[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
 pub struct Config {
     pub local_as: AutonomousSystemNumber,
-    pub local_ip: Ipv4Addr,
+    pub local_ip: IpAddr,
     pub remote_as: AutonomousSystemNumber,
-    pub remote_ip: Ipv4Addr,
+    pub remote_ip: IpAddr,
     pub mode: Mode,
-    pub networks: Vec<Ipv4Network>,
+    pub networks: Vec<IpNetwork>,
+    pub always_advertised_networks: Vec<IpNetwork>,
+    pub router_id: Option<Ipv4Addr>,
+    pub address_families: Vec<AddressFamily>,
+    pub vrfs: Vec<VrfConfig>,
+    pub labeled_networks: Vec<(IpNetwork, MplsLabel)>,
+    pub mpls_encap: bool,
+    pub kernel_metric: u32,
+    pub linux_vrf: Option<LinuxVrf>,
+    pub flowspec: Vec<FlowSpecRoute>,
+    pub flowspec_enforcement: Option<FlowSpecEnforcement>,
+    pub control_socket: PathBuf,
+    pub dump_dir: PathBuf,
+    pub startup_wait: Option<Duration>,
+    pub worker_threads: Option<usize>,
+    pub peer_task_pool: Option<usize>,
+    pub fib_writer_task: bool,
+    pub fib_reconcile_interval: Option<Duration>,
+    pub update_rate: Option<u32>,
+    pub accept_inbound: bool,
+    pub hold_time: HoldTime,
+    pub min_hold_time: HoldTime,
+    pub connect_retry_time: Duration,
+    pub delay_open_time: Option<Duration>,
+    pub required_capabilities: Vec<Capability>,
+    pub capability_fallback: bool,
+    pub dscp: Option<u8>,
+    pub ttl: Option<u8>,
+    pub route_count_warning: Option<u64>,
+    pub maximum_prefix: Option<MaximumPrefix>,
+    pub damping: Option<Damping>,
+    pub memory_warning: Option<u64>,
+    pub conditional_advertisements: Vec<ConditionalAdvertisement>,
+    pub redistributions: Vec<Redistribution>,
+    pub import_policy: Policy,
+    pub export_policy: Policy,
+    pub as_path_prepend: u8,
+    pub default_originate: Option<Policy>,
+    pub allowed_originations: Vec<IpNetwork>,
+    pub originated_attributes: BTreeMap<IpNetwork, OriginatedAttributes>,
+    pub own_prefix_check: Option<OwnPrefixCheck>,
+    pub llgr_stale_time: Option<u32>,
+    pub bfd: bool,
+    pub bfd_interval: Duration,
+    pub bfd_multiplier: u8,
+    pub hostname: Option<String>,
+    pub domain_name: Option<String>,
+    pub message_log_size: usize,
+    pub maintenance: MaintenancePolicy,
+    pub confederation: Option<Confederation>,
+    pub route_reflector_client: bool,
+    pub next_hop_self: bool,
+    pub local_pref: u32,
+    pub maximum_paths: usize,
+}
+
+/// confederation(RFC5065)のidentifierと、自身以外のmember ASです。
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub struct Confederation {
+    pub identifier: AutonomousSystemNumber,
+    pub peers: Vec<AutonomousSystemNumber>,
+}
+
+/// confederationを設定している場合の、Peerとのセッションの種類です。
+/// Peerに広報するAS_PATHの作り方が変わる。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum ConfederationSession {
+    // confederation内の他のmember ASのPeer。
+    Member,
+    // confederationの外のPeer。値はconfederation identifier。
+    External(AutonomousSystemNumber),
+}
+
+/// maintenance modeで、セッションを閉じる前にPeerへの広報に加える変更と、
+/// 変更してからセッションを閉じるまでの時間です。
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub struct MaintenancePolicy {
+    pub delay: Duration,
+    pub graceful_shutdown: bool,
+    pub prepend: u8,
+    pub med: Option<u32>,
+}
+
+impl Default for MaintenancePolicy {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_secs(60),
+            graceful_shutdown: true,
+            prepend: 0,
+            med: None,
+        }
+    }
+}
+
+/// 自身がoriginateするネットワーク毎に、既定値から変更するPathAttributeです。
+/// 指定しなければORIGINはIGPで、MED, COMMUNITIES, EXTENDED_COMMUNITIESは付けない。
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord, Default)]
+pub struct OriginatedAttributes {
+    pub origin: Option<Origin>,
+    pub med: Option<u32>,
+    pub communities: Vec<Community>,
+    pub extended_communities: Vec<ExtendedCommunity>,
+}
+
+/// eBGPのPeerから自身のネットワークを受信した場合の扱いです。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum OwnPrefixCheck {
+    Warn,
+    Reject,
+}
+
+/// conditionのネットワークのルートがLocRibに存在する間、
+/// existがfalseの場合は存在しない間だけnetworkを広報する条件です。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct ConditionalAdvertisement {
+    pub network: IpNetwork,
+    pub condition: IpNetwork,
+    pub exist: bool,
+}
+
+/// カーネルのルーティングテーブルから広報するルートの条件です。
+/// protocolが一致し、prefixを指定した場合はそれに含まれるルートを広報する。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct Redistribution {
+    pub protocol: RouteProtocol,
+    pub prefix: Option<IpNetwork>,
+}
+
+/// Linuxのvrfデバイスと、それに対応するカーネルのルーティングテーブルです。
+/// VPNv4のVRF(`vrf-*`)とは異なり、Route Distinguisherは使わずにLocRibごと分ける。
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub struct LinuxVrf {
+    pub device: String,
+    pub table: u32,
+}
+
+/// route flap damping(RFC2439)のパラメータです。
+/// ルートが変化する度にpenaltyを加え、penaltyはhalf_lifeで半分になるように減らす。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct Damping {
+    pub half_life: Duration,
+    // penaltyがこれを下回ると、抑制していたルートを再び使う。
+    pub reuse: u32,
+    // penaltyがこれを超えると、ルートを抑制する。
+    pub suppress: u32,
+    // ルートを抑制し続ける最大の時間。penaltyの上限はこれから決まる。
+    pub max_suppress: Duration,
+}
+
+impl Default for Damping {
+    fn default() -> Self {
+        Self {
+            half_life: Duration::from_secs(900),
+            reuse: 750,
+            suppress: 2000,
+            max_suppress: Duration::from_secs(3600),
+        }
+    }
+}
+
+impl FromStr for Damping {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|v| v.parse::<u32>().ok().filter(|n| *n != 0))
+            .collect::<Option<Vec<u32>>>()
+            .context(format!("damping parameters must be positive, {}", s))?;
+        let damping = match values[..] {
+            [half_life, reuse, suppress, max_suppress] => Self {
+                half_life: Duration::from_secs(half_life.into()),
+                reuse,
+                suppress,
+                max_suppress: Duration::from_secs(max_suppress.into()),
+            },
+            _ => anyhow::bail!("damping needs 4 parameters, {}", s),
+        };
+        if damping.reuse >= damping.suppress {
+            anyhow::bail!("reuse must be smaller than suppress, {}", s);
+        }
+        if damping.max_suppress < damping.half_life {
+            anyhow::bail!(
+                "max-suppress must not be less than half-life, {}",
+                s
+            );
+        }
+        Ok(damping)
+    }
+}
+
+/// Peerから受信するルートの数の上限と、超えた場合の動作です。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct MaximumPrefix {
+    pub limit: usize,
+    // trueの場合は警告するだけで、セッションを閉じない。
+    pub warning_only: bool,
+    // セッションを閉じてから再び接続を試みるまでの時間。
+    // Noneの場合は通常のIdleHoldTimeに従う。
+    pub restart: Option<Duration>,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
@@ -34,6 +441,72 @@ impl FromStr for Mode {
     }
 }
 
+impl Config {
+    /// LocRibのルートを書き込むカーネルのルーティングテーブルのid。
+    pub fn kernel_table(&self) -> u32 {
+        self.linux_vrf.as_ref().map_or(MAIN_TABLE, |vrf| vrf.table)
+    }
+
+    /// 同じASのPeerとのセッションか。confederationでは同じmember ASのPeer。
+    pub fn is_ibgp(&self) -> bool {
+        self.local_as == self.remote_as
+    }
+
+    /// confederationを設定していて、Peerが自身と異なるAS(member AS)の場合の
+    /// セッションの種類。
+    pub fn confederation_session(&self) -> Option<ConfederationSession> {
+        let confederation = self.confederation.as_ref()?;
+        if self.is_ibgp() {
+            None
+        } else if confederation.peers.contains(&self.remote_as) {
+            Some(ConfederationSession::Member)
+        } else {
+            Some(ConfederationSession::External(confederation.identifier))
+        }
+    }
+
+    /// OPEN Messageで送る自身のAS番号。
+    /// confederationの外のPeerにはconfederation identifierを使う。
+    pub fn as_number_for_peer(&self) -> AutonomousSystemNumber {
+        match self.confederation_session() {
+            Some(ConfederationSession::External(identifier)) => identifier,
+            _ => self.local_as,
+        }
+    }
+
+    /// OPEN Messageで送るBGP Identifier。
+    /// router-idが設定されていなければlocal_ip(IPv4)を使う。
+    pub fn bgp_identifier(&self) -> Ipv4Addr {
+        match (self.router_id, self.local_ip) {
+            (Some(router_id), _) => router_id,
+            (None, IpAddr::V4(local_ip)) => local_ip,
+            (None, IpAddr::V6(_)) => unreachable!(
+                "local_ipがIPv6の場合にrouter-idが無いConfigは作成できません。"
+            ),
+        }
+    }
+
+    /// Hostname Capabilityで送るホスト名とドメイン名。
+    /// hostnameが設定されていなければシステムのホスト名を使い、
+    /// それも取得できないか長すぎる場合はNone。
+    pub fn fqdn(&self) -> Option<(String, String)> {
+        let hostname = match &self.hostname {
+            Some(hostname) => hostname.clone(),
+            None => std::fs::read_to_string("/proc/sys/kernel/hostname")
+                .ok()?
+                .trim()
+                .to_string(),
+        };
+        let domain = self.domain_name.clone().unwrap_or_default();
+        if hostname.is_empty()
+            || hostname.len() + domain.len() > MAX_FQDN_LENGTH
+        {
+            return None;
+        }
+        Some((hostname, domain))
+    }
+}
+
 impl FromStr for Config {
     type Err = ConfigParseError;
 
@@ -46,7 +519,7 @@ impl FromStr for Config {
                 config[0], s
             ))?,
         );
-        let local_ip: Ipv4Addr = config[1].parse().context(format!(
+        let local_ip: IpAddr = config[1].parse().context(format!(
             "cannot parse 2nd part of config, `{0}`, \
             as as-number and config is {1}",
             config[1], s
@@ -58,7 +531,7 @@ impl FromStr for Config {
                 config[2], s
             ))?,
         );
-        let remote_ip: Ipv4Addr = config[3].parse().context(format!(
+        let remote_ip: IpAddr = config[3].parse().context(format!(
             "cannot parse 4th part of config, `{0}`, \
              as as-number and config is {1}",
             config[3], s
@@ -68,14 +541,819 @@ impl FromStr for Config {
              as as-number and config is {1}",
             config[4], s
         ))?;
-        let mut networks: Vec<Ipv4Network> = vec![];
-        for network in &config[5..] {
-            networks.push(network.parse().context(format!(
+        let mut networks: Vec<IpNetwork> = vec![];
+        let mut always_advertised_networks: Vec<IpNetwork> = vec![];
+        let mut router_id = None;
+        let mut address_families =
+            vec![AddressFamily::IPV4_UNICAST, AddressFamily::IPV6_UNICAST];
+        let mut vrfs: Vec<VrfConfig> = vec![];
+        let mut labeled_networks = vec![];
+        let mut mpls_encap = false;
+        let mut kernel_metric = 20;
+        let mut linux_vrf = None;
+        let mut flowspec: Vec<FlowSpecRoute> = vec![];
+        let mut flowspec_enforcement = None;
+        let mut control_socket = PathBuf::from(DEFAULT_CONTROL_SOCKET);
+        let mut dump_dir = PathBuf::from(DEFAULT_DUMP_DIR);
+        let mut update_rate = None;
+        let mut accept_inbound = false;
+        let mut hold_time = HoldTime::new();
+        let mut min_hold_time = HoldTime::from(3);
+        let mut connect_retry_time = Duration::from_secs(120);
+        let mut delay_open_time = None;
+        let mut required_capabilities = vec![];
+        let mut capability_fallback = false;
+        let mut dscp = None;
+        let mut ttl = None;
+        let mut route_count_warning = None;
+        let mut maximum_prefix = None;
+        let mut maximum_prefix_warning_only = false;
+        let mut maximum_prefix_restart = None;
+        let mut damping = None;
+        let mut memory_warning = None;
+        let mut conditional_advertisements = vec![];
+        let mut redistributions = vec![];
+        let mut import_policy = Policy::default();
+        let mut export_policy = Policy::default();
+        let mut as_path_prepend = 0;
+        let mut default_originate: Option<Policy> = None;
+        let mut allowed_originations: Vec<IpNetwork> = vec![];
+        let mut originated_attributes: BTreeMap<
+            IpNetwork,
+            OriginatedAttributes,
+        > = BTreeMap::new();
+        let mut own_prefix_check = None;
+        let mut llgr_stale_time = None;
+        let mut startup_wait = None;
+        let mut worker_threads = None;
+        let mut peer_task_pool = None;
+        let mut fib_writer_task = false;
+        let mut fib_reconcile_interval = Some(Duration::from_secs(60));
+        let mut bfd = false;
+        let mut bfd_interval = Duration::from_millis(300);
+        let mut bfd_multiplier = 3;
+        let mut hostname = None;
+        let mut domain_name = None;
+        let mut message_log_size = 100;
+        let mut maintenance = MaintenancePolicy::default();
+        let mut confederation_id = None;
+        let mut confederation_peers = vec![];
+        let mut route_reflector_client = false;
+        let mut next_hop_self = true;
+        let mut local_pref = DEFAULT_LOCAL_PREF;
+        let mut maximum_paths = 1;
+        for part in &config[5..] {
+            if let Some((key, value)) = part.split_once('=') {
+                match key {
+                    "router-id" => {
+                        router_id = Some(value.parse().context(format!(
+                            "cannot parse router-id, `{0}`, \
+                             as ipv4 address and config is {1}",
+                            value, s
+                        ))?)
+                    }
+                    "always-advertise" => always_advertised_networks.push(
+                        value.parse().context(format!(
+                            "cannot parse always-advertise, `{0}`, \
+                                 as IpNetwork and config is {1}",
+                            value, s
+                        ))?,
+                    ),
+                    "address-family" => {
+                        address_families = value
+                            .split(',')
+                            .map(|af| af.parse())
+                            .collect::<Result<_, _>>()
+                            .context(format!(
+                                "cannot parse address-family, `{0}`, \
+                                 and config is {1}",
+                                value, s
+                            ))?
+                    }
+                    "vrf-rd" => {
+                        let (name, rd) = split_vrf_value(value, s)?;
+                        vrfs.push(VrfConfig::new(
+                            name,
+                            rd.parse().context(format!(
+                                "cannot parse route distinguisher, `{0}`, \
+                                 and config is {1}",
+                                rd, s
+                            ))?,
+                        ))
+                    }
+                    "vrf-import" | "vrf-export" | "vrf-label"
+                    | "vrf-network" => {
+                        let (name, v) = split_vrf_value(value, s)?;
+                        let vrf = vrfs
+                            .iter_mut()
+                            .find(|vrf| vrf.name == name)
+                            .context(format!(
+                                "vrf `{0}` is not defined by vrf-rd \
+                                 and config is {1}",
+                                name, s
+                            ))?;
+                        let context = format!(
+                            "cannot parse {0}, `{1}`, and config is {2}",
+                            key, v, s
+                        );
+                        match key {
+                            "vrf-import" => vrf.import_route_targets.extend(
+                                v.split(',')
+                                    .map(|rt| rt.parse::<RouteTarget>())
+                                    .collect::<Result<Vec<_>, _>>()
+                                    .context(context)?,
+                            ),
+                            "vrf-export" => vrf.export_route_targets.extend(
+                                v.split(',')
+                                    .map(|rt| rt.parse::<RouteTarget>())
+                                    .collect::<Result<Vec<_>, _>>()
+                                    .context(context)?,
+                            ),
+                            "vrf-label" => {
+                                vrf.label = Some(MplsLabel::new(
+                                    v.parse().context(context.clone())?,
+                                )?)
+                            }
+                            _ => {
+                                vrf.networks.push(v.parse().context(context)?)
+                            }
+                        }
+                    }
+                    "labeled-network" => {
+                        let context = format!(
+                            "cannot parse labeled-network, `{0}`, \
+                             as `<network>,<label>` and config is {1}",
+                            value, s
+                        );
+                        let (network, label) =
+                            value.split_once(',').context(context.clone())?;
+                        labeled_networks.push((
+                            network.parse().context(context.clone())?,
+                            MplsLabel::new(label.parse().context(context)?)?,
+                        ))
+                    }
+                    "mpls-encap" => {
+                        mpls_encap = match value {
+                            "on" => true,
+                            "off" => false,
+                            _ => {
+                                return Err(ConfigParseError::from(
+                                    anyhow::anyhow!(
+                                        "mpls-encap must be on or off \
+                                         and config is {0}",
+                                        s
+                                    ),
+                                ))
+                            }
+                        }
+                    }
+                    "kernel-metric" => {
+                        kernel_metric = value.parse().context(format!(
+                            "cannot parse kernel-metric, `{0}`, \
+                             and config is {1}",
+                            value, s
+                        ))?;
+                    }
+                    "linux-vrf" => {
+                        let (device, table) = split_vrf_value(value, s)?;
+                        let table: u32 = table.parse().context(format!(
+                            "cannot parse linux-vrf table, `{0}`, \
+                             and config is {1}",
+                            table, s
+                        ))?;
+                        // unspec(0)と予約済みのdefault, main, localは使えない。
+                        if table == 0 || (253..=255).contains(&table) {
+                            return Err(ConfigParseError::from(
+                                anyhow::anyhow!(
+                                    "linux-vrf table must not be a reserved \
+                                     table, `{0}`, and config is {1}",
+                                    table,
+                                    s
+                                ),
+                            ));
+                        }
+                        linux_vrf = Some(LinuxVrf {
+                            device: device.to_owned(),
+                            table,
+                        });
+                    }
+                    "flowspec" => flowspec.push(FlowSpecRoute {
+                        rule: value.parse().context(format!(
+                            "cannot parse flowspec, `{0}`, and config is {1}",
+                            value, s
+                        ))?,
+                        actions: vec![],
+                    }),
+                    "flowspec-rate" | "flowspec-mark"
+                    | "flowspec-redirect" => {
+                        let route = flowspec.last_mut().context(format!(
+                            "{0} must follow flowspec and config is {1}",
+                            key, s
+                        ))?;
+                        let context = format!(
+                            "cannot parse {0}, `{1}`, and config is {2}",
+                            key, value, s
+                        );
+                        let action = match key {
+                            "flowspec-rate" => {
+                                ExtendedCommunity::TrafficRate {
+                                    asn: u16::from(local_as),
+                                    rate: value.parse().context(context)?,
+                                }
+                            }
+                            "flowspec-mark" => {
+                                ExtendedCommunity::TrafficMarking(
+                                    value.parse().context(context)?,
+                                )
+                            }
+                            _ => {
+                                let (asn, v) = value
+                                    .split_once(':')
+                                    .context(context.clone())?;
+                                ExtendedCommunity::Redirect {
+                                    asn: asn
+                                        .parse()
+                                        .context(context.clone())?,
+                                    value: v.parse().context(context)?,
+                                }
+                            }
+                        };
+                        route.actions.push(action);
+                    }
+                    "flowspec-enforcement" => {
+                        flowspec_enforcement = Some(value.parse()?)
+                    }
+                    "control-socket" => control_socket = PathBuf::from(value),
+                    "dump-dir" => dump_dir = PathBuf::from(value),
+                    "startup-wait" => {
+                        startup_wait = Some(Duration::from_secs(
+                            value.parse().context(format!(
+                                "cannot parse startup-wait, `{0}`, \
+                                 as seconds and config is {1}",
+                                value, s
+                            ))?,
+                        ))
+                    }
+                    "worker-threads" => {
+                        worker_threads = Some(
+                            value.parse().ok().filter(|n| *n != 0).context(
+                                format!(
+                                    "worker-threads must be positive number, \
+                                     `{0}`, and config is {1}",
+                                    value, s
+                                ),
+                            )?,
+                        )
+                    }
+                    "peer-task-pool" => {
+                        peer_task_pool = Some(
+                            value.parse().ok().filter(|n| *n != 0).context(
+                                format!(
+                                    "peer-task-pool must be positive number, \
+                                     `{0}`, and config is {1}",
+                                    value, s
+                                ),
+                            )?,
+                        )
+                    }
+                    "fib-writer-task" => {
+                        fib_writer_task = match value {
+                            "on" => true,
+                            "off" => false,
+                            _ => {
+                                return Err(ConfigParseError::from(
+                                    anyhow::anyhow!(
+                                        "fib-writer-task must be on or off \
+                                         and config is {0}",
+                                        s
+                                    ),
+                                ))
+                            }
+                        }
+                    }
+                    "fib-reconcile-interval" => {
+                        let seconds: u64 = value.parse().context(format!(
+                            "cannot parse fib-reconcile-interval, `{0}`, \
+                             as seconds and config is {1}",
+                            value, s
+                        ))?;
+                        fib_reconcile_interval = (seconds > 0)
+                            .then(|| Duration::from_secs(seconds));
+                    }
+                    "update-rate" => {
+                        update_rate = Some(value.parse().context(format!(
+                            "cannot parse update-rate, `{0}`, \
+                             as number and config is {1}",
+                            value, s
+                        ))?)
+                    }
+                    "hold-time" => {
+                        hold_time = HoldTime::from(
+                            value.parse::<u16>().context(format!(
+                                "cannot parse hold-time, `{0}`, \
+                                 as seconds and config is {1}",
+                                value, s
+                            ))?,
+                        )
+                    }
+                    "min-hold-time" => {
+                        min_hold_time = HoldTime::from(
+                            value.parse::<u16>().context(format!(
+                                "cannot parse min-hold-time, `{0}`, \
+                                 as seconds and config is {1}",
+                                value, s
+                            ))?,
+                        )
+                    }
+                    "connect-retry-time" => {
+                        connect_retry_time = Duration::from_secs(
+                            value.parse().ok().filter(|n| *n != 0).context(
+                                format!(
+                                    "connect-retry-time must be positive \
+                                     seconds, `{0}`, and config is {1}",
+                                    value, s
+                                ),
+                            )?,
+                        )
+                    }
+                    "delay-open-time" => {
+                        delay_open_time = Some(Duration::from_secs(
+                            value.parse().ok().filter(|n| *n != 0).context(
+                                format!(
+                                    "delay-open-time must be positive \
+                                     seconds, `{0}`, and config is {1}",
+                                    value, s
+                                ),
+                            )?,
+                        ))
+                    }
+                    "required-capabilities" => {
+                        required_capabilities = value
+                            .split(',')
+                            .map(|c| c.parse())
+                            .collect::<Result<_, _>>()
+                            .context(format!(
+                                "cannot parse required-capabilities, `{0}`, \
+                                 and config is {1}",
+                                value, s
+                            ))?
+                    }
+                    "capability-fallback" => {
+                        capability_fallback = match value {
+                            "on" => true,
+                            "off" => false,
+                            _ => {
+                                return Err(ConfigParseError::from(
+                                    anyhow::anyhow!(
+                                    "capability-fallback must be on or off \
+                                         and config is {0}",
+                                    s
+                                ),
+                                ))
+                            }
+                        }
+                    }
+                    "dscp" => dscp = Some(parse_dscp(value, s)?),
+                    "ttl" => {
+                        ttl = Some(
+                            value
+                                .parse()
+                                .ok()
+                                .filter(|ttl| *ttl != 0)
+                                .context(format!(
+                                "ttl must be 1-255, `{0}`, and config is {1}",
+                                value, s
+                            ))?,
+                        )
+                    }
+                    "route-count-warning" | "memory-warning" => {
+                        let threshold =
+                            Some(value.parse().context(format!(
+                                "cannot parse {0}, `{1}`, \
+                             as number and config is {2}",
+                                key, value, s
+                            ))?);
+                        match key {
+                            "route-count-warning" => {
+                                route_count_warning = threshold
+                            }
+                            _ => memory_warning = threshold,
+                        }
+                    }
+                    "maximum-prefix" => {
+                        maximum_prefix =
+                            Some(value.parse().context(format!(
+                                "cannot parse maximum-prefix, `{0}`, \
+                             as number and config is {1}",
+                                value, s
+                            ))?)
+                    }
+                    "maximum-prefix-action" => {
+                        maximum_prefix_warning_only = match value {
+                            "warn" => true,
+                            "teardown" => false,
+                            _ => {
+                                return Err(ConfigParseError::from(
+                                    anyhow::anyhow!(
+                                        "maximum-prefix-action must be warn \
+                                         or teardown and config is {0}",
+                                        s
+                                    ),
+                                ))
+                            }
+                        }
+                    }
+                    "maximum-prefix-restart" => {
+                        maximum_prefix_restart = Some(Duration::from_secs(
+                            value.parse().ok().filter(|n| *n != 0).context(
+                                format!(
+                                    "maximum-prefix-restart must be positive \
+                                     seconds, `{0}`, and config is {1}",
+                                    value, s
+                                ),
+                            )?,
+                        ))
+                    }
+                    "advertise-if-exist" | "advertise-if-not-exist" => {
+                        let context = format!(
+                            "cannot parse {0}, `{1}`, \
+                             as `<network>,<condition>` and config is {2}",
+                            key, value, s
+                        );
+                        let (network, condition) =
+                            value.split_once(',').context(context.clone())?;
+                        conditional_advertisements.push(
+                            ConditionalAdvertisement {
+                                network: network
+                                    .parse()
+                                    .context(context.clone())?,
+                                condition: condition
+                                    .parse()
+                                    .context(context)?,
+                                exist: key == "advertise-if-exist",
+                            },
+                        )
+                    }
+                    "damping" => {
+                        damping = match value {
+                            "on" => Some(Damping::default()),
+                            "off" => None,
+                            _ => Some(value.parse().context(format!(
+                                "cannot parse damping, `{0}`, \
+                                 and config is {1}",
+                                value, s
+                            ))?),
+                        }
+                    }
+                    "import-policy" | "export-policy" => {
+                        let rule = value.parse().context(format!(
+                            "cannot parse {0}, `{1}`, and config is {2}",
+                            key, value, s
+                        ))?;
+                        match key {
+                            "import-policy" => import_policy.0.push(rule),
+                            _ => export_policy.0.push(rule),
+                        }
+                    }
+                    "default-originate" => match value {
+                        "on" => {
+                            default_originate
+                                .get_or_insert_with(Policy::default);
+                        }
+                        "off" => default_originate = None,
+                        _ => {
+                            let rule = value.parse().context(format!(
+                                "cannot parse default-originate, `{0}`, \
+                                 and config is {1}",
+                                value, s
+                            ))?;
+                            default_originate
+                                .get_or_insert_with(Policy::default)
+                                .0
+                                .push(rule);
+                        }
+                    },
+                    "as-path-prepend" => {
+                        as_path_prepend = value.parse().context(format!(
+                            "cannot parse as-path-prepend, `{0}`, \
+                             and config is {1}",
+                            value, s
+                        ))?;
+                    }
+                    "redistribute" => {
+                        let context = format!(
+                            "cannot parse redistribute, `{0}`, \
+                             as `<protocol>[,<prefix>]` and config is {1}",
+                            value, s
+                        );
+                        let (protocol, prefix) = match value.split_once(',') {
+                            Some((protocol, prefix)) => (
+                                protocol,
+                                Some(prefix.parse().context(context.clone())?),
+                            ),
+                            None => (value, None),
+                        };
+                        redistributions.push(Redistribution {
+                            protocol: protocol.parse().context(context)?,
+                            prefix,
+                        })
+                    }
+                    "network-origin"
+                    | "network-med"
+                    | "network-community"
+                    | "network-extended-community" => {
+                        let context = format!(
+                            "cannot parse {0}, `{1}`, \
+                             as `<network>,<value>` and config is {2}",
+                            key, value, s
+                        );
+                        let (network, value) =
+                            value.split_once(',').context(context.clone())?;
+                        let attributes = originated_attributes
+                            .entry(network.parse().context(context.clone())?)
+                            .or_default();
+                        match key {
+                            "network-origin" => {
+                                attributes.origin =
+                                    Some(value.parse().context(context)?)
+                            }
+                            "network-med" => {
+                                attributes.med =
+                                    Some(value.parse().context(context)?)
+                            }
+                            "network-community" => attributes
+                                .communities
+                                .push(value.parse().context(context)?),
+                            _ => attributes
+                                .extended_communities
+                                .push(value.parse().context(context)?),
+                        }
+                    }
+                    "allowed-origination" => allowed_originations.extend(
+                        value
+                            .split(',')
+                            .map(|n| n.parse::<IpNetwork>())
+                            .collect::<Result<Vec<_>, _>>()
+                            .context(format!(
+                                "cannot parse allowed-origination, `{0}`, \
+                                 and config is {1}",
+                                value, s
+                            ))?,
+                    ),
+                    "own-prefix-check" => {
+                        own_prefix_check = match value {
+                            "warn" => Some(OwnPrefixCheck::Warn),
+                            "reject" => Some(OwnPrefixCheck::Reject),
+                            "off" => None,
+                            _ => {
+                                return Err(ConfigParseError::from(
+                                    anyhow::anyhow!(
+                                        "own-prefix-check must be warn, \
+                                         reject or off and config is {0}",
+                                        s
+                                    ),
+                                ))
+                            }
+                        }
+                    }
+                    "confederation-id" => {
+                        confederation_id = Some(AutonomousSystemNumber::from(
+                            value.parse::<u16>().context(format!(
+                                "cannot parse confederation-id, `{0}`, \
+                                 as as-number and config is {1}",
+                                value, s
+                            ))?,
+                        ))
+                    }
+                    "route-reflector-client" => {
+                        route_reflector_client = match value {
+                            "on" => true,
+                            "off" => false,
+                            _ => {
+                                return Err(ConfigParseError::from(
+                                    anyhow::anyhow!(
+                                        "route-reflector-client must be on \
+                                         or off and config is {0}",
+                                        s
+                                    ),
+                                ))
+                            }
+                        }
+                    }
+                    "confederation-peers" => {
+                        confederation_peers = value
+                            .split(',')
+                            .map(|a| a.parse::<u16>().map(Into::into))
+                            .collect::<Result<_, _>>()
+                            .context(format!(
+                                "cannot parse confederation-peers, `{0}`, \
+                                 as as-numbers and config is {1}",
+                                value, s
+                            ))?
+                    }
+                    "llgr-stale-time" => {
+                        llgr_stale_time = Some(
+                            value
+                                .parse::<u32>()
+                                .ok()
+                                .filter(|t| *t <= LlgrFamily::MAX_STALE_TIME)
+                                .context(format!(
+                                    "llgr-stale-time must be 0-16777215, \
+                                     `{0}`, and config is {1}",
+                                    value, s
+                                ))?,
+                        )
+                    }
+                    "bfd" => {
+                        bfd = match value {
+                            "on" => true,
+                            "off" => false,
+                            _ => {
+                                return Err(ConfigParseError::from(
+                                    anyhow::anyhow!(
+                                        "bfd must be on or off \
+                                         and config is {0}",
+                                        s
+                                    ),
+                                ))
+                            }
+                        }
+                    }
+                    "bfd-interval" => {
+                        bfd_interval = value
+                            .parse()
+                            .ok()
+                            .filter(|ms| *ms != 0)
+                            .map(Duration::from_millis)
+                            .context(format!(
+                                "bfd-interval must be positive milliseconds, \
+                                 `{0}`, and config is {1}",
+                                value, s
+                            ))?
+                    }
+                    "bfd-multiplier" => {
+                        bfd_multiplier =
+                            value.parse().ok().filter(|m| *m != 0).context(
+                                format!(
+                                    "bfd-multiplier must be 1-255, `{0}`, \
+                                 and config is {1}",
+                                    value, s
+                                ),
+                            )?
+                    }
+                    "hostname" => hostname = Some(value.to_string()),
+                    "domain-name" => domain_name = Some(value.to_string()),
+                    "maintenance-delay" => {
+                        maintenance.delay = Duration::from_secs(
+                            value.parse().context(format!(
+                                "cannot parse maintenance-delay, `{0}`, \
+                                     as seconds and config is {1}",
+                                value, s
+                            ))?,
+                        )
+                    }
+                    "maintenance-graceful-shutdown" => {
+                        maintenance.graceful_shutdown = match value {
+                            "on" => true,
+                            "off" => false,
+                            _ => {
+                                return Err(ConfigParseError::from(
+                                    anyhow::anyhow!(
+                                    "maintenance-graceful-shutdown must be \
+                                         on or off and config is {0}",
+                                    s
+                                ),
+                                ))
+                            }
+                        }
+                    }
+                    "maintenance-prepend" => {
+                        maintenance.prepend =
+                            value.parse().context(format!(
+                                "maintenance-prepend must be 0-255, `{0}`, \
+                             and config is {1}",
+                                value, s
+                            ))?
+                    }
+                    "next-hop-self" => {
+                        next_hop_self = match value {
+                            "on" => true,
+                            "off" => false,
+                            _ => {
+                                return Err(ConfigParseError::from(
+                                    anyhow::anyhow!(
+                                        "next-hop-self must be on or off \
+                                         and config is {0}",
+                                        s
+                                    ),
+                                ))
+                            }
+                        }
+                    }
+                    "local-pref" => {
+                        local_pref = value.parse().context(format!(
+                            "cannot parse local-pref, `{0}`, \
+                             as number and config is {1}",
+                            value, s
+                        ))?
+                    }
+                    "maximum-paths" => {
+                        maximum_paths =
+                            value.parse().ok().filter(|n| *n != 0).context(
+                                format!(
+                                    "maximum-paths must be positive number, \
+                                     `{0}`, and config is {1}",
+                                    value, s
+                                ),
+                            )?
+                    }
+                    "maintenance-med" => {
+                        maintenance.med =
+                            Some(value.parse().context(format!(
+                                "cannot parse maintenance-med, `{0}`, \
+                             as number and config is {1}",
+                                value, s
+                            ))?)
+                    }
+                    "message-log-size" => {
+                        message_log_size = value.parse().context(format!(
+                            "cannot parse message-log-size, `{0}`, \
+                             as number and config is {1}",
+                            value, s
+                        ))?
+                    }
+                    "accept-inbound" => {
+                        accept_inbound = match value {
+                            "on" => true,
+                            "off" => false,
+                            _ => {
+                                return Err(ConfigParseError::from(
+                                    anyhow::anyhow!(
+                                        "accept-inbound must be on or off \
+                                         and config is {0}",
+                                        s
+                                    ),
+                                ))
+                            }
+                        }
+                    }
+                    _ => {
+                        return Err(ConfigParseError::from(anyhow::anyhow!(
+                            "unknown config key `{0}` and config is {1}",
+                            key,
+                            s
+                        )))
+                    }
+                }
+                continue;
+            }
+            networks.push(part.parse().context(format!(
                 "cannot parse config[5..], `{0}` \
-                 as Ipv4Network and config is {1}",
-                network, s
+                 as IpNetwork and config is {1}",
+                part, s
             ))?)
         }
+        if !hold_time.is_acceptable(HoldTime::from(0)) {
+            return Err(ConfigParseError::from(anyhow::anyhow!(
+                "hold-time must be 0 or at least 3 seconds \
+                 and config is {0}",
+                s
+            )));
+        }
+        if remote_ip.is_ipv6() && dscp.is_some() {
+            return Err(ConfigParseError::from(anyhow::anyhow!(
+                "dscp is not supported for ipv6 session and config is {0}",
+                s
+            )));
+        }
+        let fqdn_length = hostname.as_ref().map_or(0, String::len)
+            + domain_name.as_ref().map_or(0, String::len);
+        if fqdn_length > MAX_FQDN_LENGTH {
+            return Err(ConfigParseError::from(anyhow::anyhow!(
+                "hostname and domain-name must be at most {0} characters \
+                 and config is {1}",
+                MAX_FQDN_LENGTH,
+                s
+            )));
+        }
+        if confederation_id.is_none() && !confederation_peers.is_empty() {
+            return Err(ConfigParseError::from(anyhow::anyhow!(
+                "confederation-peers requires confederation-id \
+                 and config is {0}",
+                s
+            )));
+        }
+        let confederation = confederation_id.map(|identifier| Confederation {
+            identifier,
+            peers: confederation_peers,
+        });
+        if local_ip.is_ipv6() && router_id.is_none() {
+            return Err(ConfigParseError::from(anyhow::anyhow!(
+                "router-id is required when local ip is ipv6 \
+                 and config is {0}",
+                s
+            )));
+        }
         Ok(Self {
             local_as,
             local_ip,
@@ -83,6 +1361,512 @@ impl FromStr for Config {
             remote_ip,
             mode,
             networks,
+            always_advertised_networks,
+            router_id,
+            address_families,
+            vrfs,
+            labeled_networks,
+            mpls_encap,
+            kernel_metric,
+            linux_vrf,
+            flowspec,
+            flowspec_enforcement,
+            control_socket,
+            dump_dir,
+            startup_wait,
+            worker_threads,
+            peer_task_pool,
+            fib_writer_task,
+            fib_reconcile_interval,
+            update_rate,
+            accept_inbound,
+            hold_time,
+            min_hold_time,
+            connect_retry_time,
+            delay_open_time,
+            required_capabilities,
+            capability_fallback,
+            dscp,
+            ttl,
+            route_count_warning,
+            maximum_prefix: maximum_prefix.map(|limit| MaximumPrefix {
+                limit,
+                warning_only: maximum_prefix_warning_only,
+                restart: maximum_prefix_restart,
+            }),
+            damping,
+            memory_warning,
+            conditional_advertisements,
+            redistributions,
+            import_policy,
+            export_policy,
+            as_path_prepend,
+            default_originate,
+            allowed_originations,
+            originated_attributes,
+            own_prefix_check,
+            llgr_stale_time,
+            bfd,
+            bfd_interval,
+            bfd_multiplier,
+            hostname,
+            domain_name,
+            message_log_size,
+            maintenance,
+            confederation,
+            route_reflector_client,
+            next_hop_self,
+            local_pref,
+            maximum_paths,
         })
     }
 }
+
+/// `vrf-*`, `linux-vrf`の値をVRF名とそれ以外に分ける。
+fn split_vrf_value<'a>(
+    value: &'a str,
+    config: &str,
+) -> Result<(&'a str, &'a str), ConfigParseError> {
+    Ok(value.split_once(':').context(format!(
+        "cannot parse `{0}` as `<vrf name>:<value>` and config is {1}",
+        value, config
+    ))?)
+}
+
+/// DSCPの名前か0-63の数値をparseする。
+fn parse_dscp(value: &str, config: &str) -> Result<u8, ConfigParseError> {
+    let dscp = match value {
+        "ef" => Some(46),
+        _ => match value.split_at(value.len().min(2)) {
+            // Class Selector (RFC2474)
+            ("cs", class) => {
+                class.parse::<u8>().ok().filter(|c| *c <= 7).map(|c| c << 3)
+            }
+            // Assured Forwarding (RFC2597)
+            ("af", class) => match class.as_bytes() {
+                [c @ b'1'..=b'4', p @ b'1'..=b'3'] => {
+                    Some((c - b'0') << 3 | (p - b'0') << 1)
+                }
+                _ => None,
+            },
+            _ => value.parse().ok().filter(|dscp| *dscp < 64),
+        },
+    };
+    Ok(dscp.context(format!(
+        "cannot parse dscp, `{0}`, and config is {1}",
+        value, config
+    ))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ipv6_only_config() {
+        let config: Config = "64512 fd00::2 64513 fd00::3 active \
+                              fd00:100::/64 router-id=10.0.0.2 \
+                              address-family=ipv6-unicast"
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            config.bgp_identifier(),
+            "10.0.0.2".parse::<Ipv4Addr>().unwrap()
+        );
+        assert_eq!(config.networks, vec!["fd00:100::/64".parse().unwrap()]);
+        assert_eq!(config.address_families, vec![AddressFamily::IPV6_UNICAST]);
+    }
+
+    #[test]
+    fn ipv6_config_without_router_id_is_error() {
+        let config = "64512 fd00::2 64513 fd00::3 active".parse::<Config>();
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn hold_time_of_1_or_2_seconds_is_error() {
+        for hold_time in [1, 2] {
+            let config = format!(
+                "64512 10.0.0.2 64513 10.0.0.3 active hold-time={}",
+                hold_time
+            );
+            assert!(config.parse::<Config>().is_err());
+        }
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active hold-time=0 min-hold-time=30"
+                .parse()
+                .unwrap();
+        assert_eq!(config.hold_time, HoldTime::from(0));
+        assert_eq!(config.min_hold_time, HoldTime::from(30));
+        assert!(HoldTime::from(0).is_acceptable(config.min_hold_time));
+        assert!(!HoldTime::from(10).is_acceptable(config.min_hold_time));
+        assert!(!HoldTime::from(2).is_acceptable(HoldTime::from(0)));
+    }
+
+    #[test]
+    fn parse_conditional_advertisement_config() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              advertise-if-exist=10.1.0.0/24,198.51.100.0/24 \
+                              advertise-if-not-exist=0.0.0.0/0,203.0.113.0/24"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            config.conditional_advertisements,
+            vec![
+                ConditionalAdvertisement {
+                    network: "10.1.0.0/24".parse().unwrap(),
+                    condition: "198.51.100.0/24".parse().unwrap(),
+                    exist: true,
+                },
+                ConditionalAdvertisement {
+                    network: "0.0.0.0/0".parse().unwrap(),
+                    condition: "203.0.113.0/24".parse().unwrap(),
+                    exist: false,
+                },
+            ]
+        );
+        assert!("64512 10.0.0.2 64513 10.0.0.3 active \
+                 advertise-if-exist=10.1.0.0/24"
+            .parse::<Config>()
+            .is_err());
+    }
+
+    #[test]
+    fn parse_redistribute_config() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              redistribute=static,10.0.0.0/8 \
+                              redistribute=connected redistribute=200"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            config.redistributions,
+            vec![
+                Redistribution {
+                    protocol: RouteProtocol::STATIC,
+                    prefix: Some("10.0.0.0/8".parse().unwrap()),
+                },
+                Redistribution {
+                    protocol: RouteProtocol::KERNEL,
+                    prefix: None,
+                },
+                Redistribution {
+                    protocol: RouteProtocol(200),
+                    prefix: None,
+                },
+            ]
+        );
+        assert!("64512 10.0.0.2 64513 10.0.0.3 active redistribute=unknown"
+            .parse::<Config>()
+            .is_err());
+    }
+
+    #[test]
+    fn parse_maximum_prefix_config() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              maximum-prefix=100 maximum-prefix-action=warn \
+                              maximum-prefix-restart=30"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            config.maximum_prefix,
+            Some(MaximumPrefix {
+                limit: 100,
+                warning_only: true,
+                restart: Some(Duration::from_secs(30)),
+            })
+        );
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        assert_eq!(config.maximum_prefix, None);
+        assert!("64512 10.0.0.2 64513 10.0.0.3 active maximum-prefix=many"
+            .parse::<Config>()
+            .is_err());
+    }
+
+    #[test]
+    fn parse_policy_config() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              import-policy=deny,as-path:64600 \
+                              import-policy=permit,community:65000:1,prepend:2 \
+                              export-policy=permit,prefix:10.0.0.0/8"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            config.import_policy,
+            Policy(vec![
+                "deny,as-path:64600".parse().unwrap(),
+                "permit,community:65000:1,prepend:2".parse().unwrap(),
+            ])
+        );
+        assert_eq!(
+            config.export_policy,
+            Policy(vec!["permit,prefix:10.0.0.0/8".parse().unwrap()])
+        );
+        assert!("64512 10.0.0.2 64513 10.0.0.3 active export-policy=allow"
+            .parse::<Config>()
+            .is_err());
+
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active as-path-prepend=3"
+                .parse()
+                .unwrap();
+        assert_eq!(config.as_path_prepend, 3);
+        assert!("64512 10.0.0.2 64513 10.0.0.3 active as-path-prepend=300"
+            .parse::<Config>()
+            .is_err());
+
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active default-originate=on"
+                .parse()
+                .unwrap();
+        assert_eq!(config.default_originate, Some(Policy::default()));
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              default-originate=permit,prefix:10.0.0.0/8"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            config.default_originate,
+            Some(Policy(vec!["permit,prefix:10.0.0.0/8".parse().unwrap()]))
+        );
+        assert!("64512 10.0.0.2 64513 10.0.0.3 active default-originate=yes"
+            .parse::<Config>()
+            .is_err());
+    }
+
+    #[test]
+    fn parse_damping_config() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active damping=on"
+            .parse()
+            .unwrap();
+        assert_eq!(config.damping, Some(Damping::default()));
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              damping=60,500,1500,300"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            config.damping,
+            Some(Damping {
+                half_life: Duration::from_secs(60),
+                reuse: 500,
+                suppress: 1500,
+                max_suppress: Duration::from_secs(300),
+            })
+        );
+        for damping in ["60,500,1500", "60,1500,500,300", "600,500,1500,300"] {
+            assert!(format!(
+                "64512 10.0.0.2 64513 10.0.0.3 active damping={}",
+                damping
+            )
+            .parse::<Config>()
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn parse_dscp_and_ttl_config() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active dscp=cs6 ttl=255"
+                .parse()
+                .unwrap();
+        assert_eq!(config.dscp, Some(48));
+        assert_eq!(config.ttl, Some(255));
+
+        assert_eq!(parse_dscp("af41", "").unwrap(), 34);
+        assert_eq!(parse_dscp("ef", "").unwrap(), 46);
+        assert_eq!(parse_dscp("10", "").unwrap(), 10);
+        assert!(parse_dscp("cs8", "").is_err());
+        assert!(parse_dscp("64", "").is_err());
+        assert!("64512 10.0.0.2 64513 10.0.0.3 active ttl=0"
+            .parse::<Config>()
+            .is_err());
+    }
+
+    #[test]
+    fn parse_hostname_config() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              hostname=router1 domain-name=example.com"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            config.fqdn(),
+            Some(("router1".to_string(), "example.com".to_string()))
+        );
+        let long = format!(
+            "64512 10.0.0.2 64513 10.0.0.3 active hostname={}",
+            "a".repeat(254)
+        );
+        assert!(long.parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn parse_originated_attributes_config() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              network-origin=10.1.0.0/24,egp \
+                              network-med=10.1.0.0/24,100 \
+                              network-community=10.1.0.0/24,64512:10 \
+                              network-extended-community=10.1.0.0/24,\
+                              soo:64512:1 \
+                              network-extended-community=10.1.0.0/24,\
+                              bandwidth:64512:125000000"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            config.originated_attributes[&"10.1.0.0/24".parse().unwrap()],
+            OriginatedAttributes {
+                origin: Some(Origin::Egp),
+                med: Some(100),
+                communities: vec![Community(64512 << 16 | 10)],
+                extended_communities: vec![
+                    ExtendedCommunity::RouteOrigin("64512:1".parse().unwrap()),
+                    ExtendedCommunity::LinkBandwidth {
+                        asn: 64512,
+                        bandwidth: 125_000_000,
+                    },
+                ],
+            }
+        );
+        assert!("64512 10.0.0.2 64513 10.0.0.3 active \
+                 network-origin=10.1.0.0/24,bgp"
+            .parse::<Config>()
+            .is_err());
+    }
+
+    #[test]
+    fn parse_runtime_config() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              worker-threads=4 peer-task-pool=2 \
+                              fib-writer-task=on kernel-metric=100"
+            .parse()
+            .unwrap();
+        assert_eq!(config.worker_threads, Some(4));
+        assert_eq!(config.peer_task_pool, Some(2));
+        assert!(config.fib_writer_task);
+        assert_eq!(config.kernel_metric, 100);
+        assert_eq!(
+            config.fib_reconcile_interval,
+            Some(Duration::from_secs(60))
+        );
+        assert!("64512 10.0.0.2 64513 10.0.0.3 active peer-task-pool=0"
+            .parse::<Config>()
+            .is_err());
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active fib-reconcile-interval=0"
+                .parse()
+                .unwrap();
+        assert_eq!(config.kernel_metric, 20);
+        assert_eq!(config.fib_reconcile_interval, None);
+        assert!("64512 10.0.0.2 64513 10.0.0.3 active kernel-metric=-1"
+            .parse::<Config>()
+            .is_err());
+    }
+
+    #[test]
+    fn parse_vrf_config() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              address-family=vpnv4 vrf-rd=blue:64512:1 \
+                              vrf-import=blue:64512:100,64512:101 \
+                              vrf-export=blue:64512:100 vrf-label=blue:100 \
+                              vrf-network=blue:10.1.0.0/24"
+            .parse()
+            .unwrap();
+
+        let mut vrf = VrfConfig::new("blue", "64512:1".parse().unwrap());
+        vrf.import_route_targets =
+            vec!["64512:100".parse().unwrap(), "64512:101".parse().unwrap()];
+        vrf.export_route_targets = vec!["64512:100".parse().unwrap()];
+        vrf.label = Some(MplsLabel::new(100).unwrap());
+        vrf.networks = vec!["10.1.0.0/24".parse().unwrap()];
+        assert_eq!(config.vrfs, vec![vrf]);
+    }
+
+    #[test]
+    fn parse_linux_vrf_config() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active linux-vrf=red:1000"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            config.linux_vrf,
+            Some(LinuxVrf {
+                device: "red".to_owned(),
+                table: 1000,
+            })
+        );
+        assert_eq!(config.kernel_table(), 1000);
+
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        assert_eq!(config.linux_vrf, None);
+        assert_eq!(config.kernel_table(), MAIN_TABLE);
+        for invalid in ["red", "red:main", "red:254"] {
+            assert!(format!(
+                "64512 10.0.0.2 64513 10.0.0.3 active linux-vrf={}",
+                invalid
+            )
+            .parse::<Config>()
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn parse_flowspec_config() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              address-family=flowspec \
+                              flowspec=dst:203.0.113.0/24,proto:17 \
+                              flowspec-rate=0 flowspec-enforcement=nftables"
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            config.flowspec,
+            vec![FlowSpecRoute {
+                rule: "dst:203.0.113.0/24,proto:17".parse().unwrap(),
+                actions: vec![ExtendedCommunity::TrafficRate {
+                    asn: 64512,
+                    rate: 0
+                }],
+            }]
+        );
+        assert_eq!(
+            config.flowspec_enforcement,
+            Some(FlowSpecEnforcement::Nftables)
+        );
+    }
+
+    #[test]
+    fn parse_confederation_config() {
+        let config = |remote_as: u16| -> Config {
+            format!(
+                "65001 10.0.0.2 {} 10.0.0.3 active confederation-id=64512 \
+                 confederation-peers=65002,65003",
+                remote_as
+            )
+            .parse()
+            .unwrap()
+        };
+        assert_eq!(
+            config(65001).confederation,
+            Some(Confederation {
+                identifier: 64512.into(),
+                peers: vec![65002.into(), 65003.into()],
+            })
+        );
+        assert_eq!(config(65001).confederation_session(), None);
+        assert_eq!(
+            config(65002).confederation_session(),
+            Some(ConfederationSession::Member)
+        );
+        assert_eq!(config(65002).as_number_for_peer(), 65001.into());
+        assert_eq!(
+            config(64513).confederation_session(),
+            Some(ConfederationSession::External(64512.into()))
+        );
+        assert_eq!(config(64513).as_number_for_peer(), 64512.into());
+
+        assert!("65001 10.0.0.2 65002 10.0.0.3 active \
+                 confederation-peers=65002"
+            .parse::<Config>()
+            .is_err());
+    }
+}
@@ -1,11 +1,24 @@
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
 use anyhow::{Context, Result};
-use bytes::{BufMut, BytesMut};
+use bytes::{Buf, BufMut, BytesMut};
+use socket2::SockRef;
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{TcpSocket, TcpStream};
 
 use crate::config::{Config, Mode};
-use crate::error::CreateConnectionError;
+use crate::error::{CreateConnectionError, MalformedMessageError};
+use crate::listener::{bind_with_retry, BgpListener, BGP_PORT};
 use crate::packets::message::Message;
+use crate::packets::notification::{
+    NotificationMessage, EXTENDED_MAX_MESSAGE_LENGTH, MAX_MESSAGE_LENGTH,
+    MIN_MESSAGE_LENGTH,
+};
+
+// accept-inboundの場合に、自身からの接続に失敗した後Peerからの接続を待つ時間。
+const INBOUND_WAIT: Duration = Duration::from_secs(30);
 
 /// 通信に関する処理を担当する構造体です。
 /// TcpConnectionを張ったり、
@@ -14,35 +27,243 @@ use crate::packets::message::Message;
 pub struct Connection {
     conn: TcpStream,
     buffer: BytesMut,
+    // 送信待ちのUPDATE Message。flush_queued_messagesで少しずつ送る。
+    send_queue: VecDeque<BytesMut>,
+    // 送信途中のMessageのうち、まだ書き込めていないbytes。
+    write_buffer: BytesMut,
+    pacer: Option<Pacer>,
+    // 受信するMessageの最大長。Extended Messageをネゴシエーションすると大きくなる。
+    max_message_length: usize,
+    // 自身から接続したか。Connection Collisionの解決に使う。
+    outbound: bool,
+}
+
+/// 送信するMessageの数を1秒あたりrate個に制限するtoken bucketです。
+/// 一度に送れるのは100ミリ秒分までとし、大量のUPDATEを一気に送らないようにする。
+#[derive(Debug)]
+struct Pacer {
+    rate: u32,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Pacer {
+    fn new(rate: u32) -> Self {
+        let mut pacer = Self {
+            rate,
+            tokens: 0.0,
+            last_refill: Instant::now(),
+        };
+        pacer.tokens = pacer.capacity();
+        pacer
+    }
+
+    fn capacity(&self) -> f64 {
+        (self.rate as f64 / 10.0).max(1.0)
+    }
+
+    /// 今送ってよいMessageの数を返す。
+    fn available(&mut self) -> usize {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * self.rate as f64).min(self.capacity());
+        self.last_refill = now;
+        self.tokens as usize
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.tokens -= n as f64;
+    }
 }
 
 impl Connection {
+    /// passiveの場合、listenerがあればそこで受け付けた接続を使い、
+    /// 無ければ自身でbindして接続を待つ。
     pub async fn connect(
         config: &Config,
+        listener: Option<&BgpListener>,
     ) -> Result<Self, CreateConnectionError> {
-        let conn = match config.mode {
-            Mode::Active => Self::connect_to_remote_peer(config).await,
-            Mode::Passive => {
-                Self::wait_connection_from_remote_peer(config).await
+        let (conn, outbound) = match (config.mode, listener) {
+            (Mode::Active, Some(listener)) if config.accept_inbound => {
+                Self::connect_or_accept(config, listener).await
+            }
+            (Mode::Active, _) => Self::connect_to_remote_peer(config)
+                .await
+                .map(|conn| (conn, true)),
+            (Mode::Passive, Some(listener)) => listener
+                .accept_from(config.remote_ip)
+                .await
+                .map(|conn| (conn, false)),
+            (Mode::Passive, None) => {
+                Self::wait_connection_from_remote_peer(config)
+                    .await
+                    .map(|conn| (conn, false))
             }
         }?;
-        let buffer = BytesMut::with_capacity(1500);
-        Ok(Self { conn, buffer })
+        // 受け付けた接続にはここで設定する。
+        set_socket_options(SockRef::from(&conn), config)?;
+        Ok(Self::new(conn, config.update_rate, outbound))
+    }
+
+    /// listenerで受け付けたPeerからの接続を使う。
+    pub fn accepted(conn: TcpStream, config: &Config) -> Result<Self> {
+        set_socket_options(SockRef::from(&conn), config)?;
+        Ok(Self::new(conn, config.update_rate, false))
+    }
+
+    fn new(conn: TcpStream, update_rate: Option<u32>, outbound: bool) -> Self {
+        Self {
+            conn,
+            buffer: BytesMut::with_capacity(1500),
+            send_queue: VecDeque::new(),
+            write_buffer: BytesMut::new(),
+            pacer: update_rate.map(Pacer::new),
+            max_message_length: MAX_MESSAGE_LENGTH,
+            outbound,
+        }
+    }
+
+    /// Extended Message(RFC8654)をネゴシエーションした場合に、
+    /// 4096 octetsより大きいMessageを受信できるようにする。
+    pub fn set_extended_message(&mut self, extended: bool) {
+        self.max_message_length = if extended {
+            EXTENDED_MAX_MESSAGE_LENGTH
+        } else {
+            MAX_MESSAGE_LENGTH
+        };
+    }
+
+    /// 自身から接続したか。falseの場合はPeerから接続された。
+    pub fn is_outbound(&self) -> bool {
+        self.outbound
     }
 
-    pub async fn send(&mut self, message: Message) {
+    /// messageをすぐに送信する。
+    /// 送信途中のMessageがある場合は、それを送り切ってから送る。
+    /// 送信に失敗した場合、書き込めなかったbytesはwrite_bufferに残るので、
+    /// Messageの途中から別のMessageを送ってしまうことはない。
+    pub async fn send(&mut self, message: Message) -> Result<()> {
         let bytes: BytesMut = message.into();
-        self.conn.write_all(&bytes[..]).await;
+        self.write_buffer.extend_from_slice(&bytes[..]);
+        while !self.write_buffer.is_empty() {
+            let n = self
+                .conn
+                .write(&self.write_buffer[..])
+                .await
+                .context("cannot write to tcp connection")?;
+            if n == 0 {
+                anyhow::bail!("tcp connection is closed while sending");
+            }
+            self.write_buffer.advance(n);
+        }
+        Ok(())
+    }
+
+    /// messageを送信キューに入れる。実際の送信はflush_queued_messagesで行う。
+    pub fn enqueue(&mut self, message: Message) {
+        self.send_queue.push_back(message.into());
+    }
+
+    /// 送信キューのMessageのうち、pacingで許された数だけを、
+    /// TCPのsocketにブロックせずに書き込める範囲で送信する。
+    /// 書き込めなかった分は次に呼ばれたときに続きから送る。
+    /// 戻り値は送信キューから取り出して送信を始めたMessageの数。
+    pub fn flush_queued_messages(&mut self) -> Result<usize> {
+        let mut available = match self.pacer.as_mut() {
+            Some(pacer) => pacer.available(),
+            None => usize::MAX,
+        };
+        let mut sent = 0;
+        loop {
+            if self.write_buffer.is_empty() {
+                if sent >= available {
+                    break;
+                }
+                match self.send_queue.pop_front() {
+                    Some(bytes) => {
+                        self.write_buffer = bytes;
+                        sent += 1;
+                    }
+                    None => break,
+                }
+            }
+            match self.conn.try_write(&self.write_buffer[..]) {
+                Ok(n) => self.write_buffer.advance(n),
+                // socketの送信バッファに空きがない。
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    return Err(e).context("cannot write to tcp connection")
+                }
+            }
+        }
+        if let Some(pacer) = self.pacer.as_mut() {
+            pacer.consume(sent);
+        }
+        Ok(sent)
+    }
+
+    /// まだ送信できていないbytes数。送信途中のMessageの残りと送信キューの合計。
+    pub fn pending_bytes(&self) -> usize {
+        self.write_buffer.len()
+            + self.send_queue.iter().map(|b| b.len()).sum::<usize>()
+    }
+
+    /// 送信キューに残っているMessageの数。
+    pub fn queued_messages(&self) -> usize {
+        self.send_queue.len()
     }
 
     /// bgp messageを1つ以上受信していれば
     /// 最古に受信したMessageをSome<Message>として返す。
     /// bgp messageのデータの受信中（半端に受信している）、
     /// ないしは何も受信していない場合はNoneを返す。
-    pub async fn get_message(&mut self) -> Option<Message> {
-        self.read_data_from_tcp_connection().await;
-        let buffer = self.split_buffer_at_message_separator()?;
-        Message::try_from(buffer).ok()
+    /// TCP Connectionが閉じられたか受信に失敗した場合は、
+    /// 受信済みのMessageを全て返した後にErrを返す。
+    /// 受信したMessageを解釈できなかった場合は、Peerに送るNOTIFICATIONを持った
+    /// MalformedMessageErrorを返す。
+    pub async fn get_message(&mut self) -> Result<Option<Message>> {
+        let read = self.read_data_from_tcp_connection().await;
+        if self.has_malformed_header() {
+            return Err(MalformedMessageError(
+                NotificationMessage::for_malformed_message(
+                    &self.buffer,
+                    self.max_message_length,
+                ),
+            )
+            .into());
+        }
+        if let Some(buffer) = self.split_buffer_at_message_separator() {
+            return match Message::try_from(buffer.clone()) {
+                Ok(message) => Ok(Some(message)),
+                Err(e) => {
+                    // 解釈できなかった箇所に応じたNOTIFICATIONがあればそれを送る。
+                    let notification =
+                        e.notification().cloned().unwrap_or_else(|| {
+                            NotificationMessage::for_malformed_message(
+                                &buffer,
+                                self.max_message_length,
+                            )
+                        });
+                    Err(anyhow::Error::from(e)
+                        .context(MalformedMessageError(notification)))
+                }
+            };
+        }
+        read.map(|_| None)
+    }
+
+    /// bufferの先頭のHeaderのMarkerかLengthが誤っているか。
+    /// Lengthが誤っていると、どこまでが1つのMessageか分からない。
+    fn has_malformed_header(&self) -> bool {
+        if self.buffer.len() < MIN_MESSAGE_LENGTH {
+            return false;
+        }
+        let length =
+            u16::from_be_bytes([self.buffer[16], self.buffer[17]]) as usize;
+        self.buffer[0..16].iter().any(|b| *b != 0xFF)
+            || !(MIN_MESSAGE_LENGTH..=self.max_message_length)
+                .contains(&length)
     }
 
     /// self.bufferから1つのbgp messageを表すbyteを切り出す。
@@ -68,27 +289,35 @@ impl Connection {
         Ok(u16::from_be_bytes([self.buffer[16], self.buffer[17]]) as usize)
     }
 
-    async fn read_data_from_tcp_connection(&mut self) {
+    async fn read_data_from_tcp_connection(&mut self) -> Result<()> {
         loop {
             let mut buf: Vec<u8> = vec![];
             match self.conn.try_read_buf(&mut buf) {
                 // TCP ConnectionがCloseされたことを意味している。
-                Ok(0) => (),
+                Ok(0) => anyhow::bail!("tcp connection is closed by peer"),
                 // n bytesのデータを受信
                 Ok(n) => self.buffer.put(&buf[..]),
                 // 今readできるデータがないことを意味する。
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
-                Err(e) => panic!(
-                    "read data from tcp connectionでエラー{:?}が発生しました",
-                    e
-                ),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(())
+                }
+                Err(e) => {
+                    return Err(e).context("cannot read from tcp connection")
+                }
             }
         }
     }
 
     async fn connect_to_remote_peer(config: &Config) -> Result<TcpStream> {
         let bgp_port = 179;
-        TcpStream::connect((config.remote_ip, bgp_port))
+        let socket = match config.remote_ip {
+            IpAddr::V4(_) => TcpSocket::new_v4()?,
+            IpAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        // SYNから設定したDSCP, TTLで送るため、接続する前に設定する。
+        set_socket_options(SockRef::from(&socket), config)?;
+        socket
+            .connect(SocketAddr::new(config.remote_ip, bgp_port))
             .await
             .context(format!(
                 "cannot connect to remote peer {0}:{1}",
@@ -96,16 +325,39 @@ impl Connection {
             ))
     }
 
-    async fn wait_connection_from_remote_peer(
+    /// 自身から接続を試みつつPeerからの接続も待ち、先に確立した方を使う。
+    /// 自身からの接続に失敗しても、INBOUND_WAITの間はPeerからの接続を待つ。
+    /// 自身から接続した場合はtrueを一緒に返す。
+    async fn connect_or_accept(
         config: &Config,
-    ) -> Result<TcpStream> {
-        let bgp_port = 179;
-        let listener = TcpListener::bind((config.local_ip, bgp_port))
+        listener: &BgpListener,
+    ) -> Result<(TcpStream, bool)> {
+        let inbound = listener.accept_from(config.remote_ip);
+        tokio::pin!(inbound);
+        tokio::select! {
+            outbound = Self::connect_to_remote_peer(config) => {
+                if let Ok(stream) = outbound {
+                    return Ok((stream, true));
+                }
+            }
+            stream = &mut inbound => return stream.map(|s| (s, false)),
+        }
+        tokio::time::timeout(INBOUND_WAIT, inbound)
             .await
             .context(format!(
-                "{0}:{1}にbindすることが出来ませんでした。",
-                config.local_ip, bgp_port
-            ))?;
+                "cannot connect to remote peer {0} \
+                 and no connection is accepted from it",
+                config.remote_ip
+            ))?
+            .map(|stream| (stream, false))
+    }
+
+    async fn wait_connection_from_remote_peer(
+        config: &Config,
+    ) -> Result<TcpStream> {
+        let bgp_port = BGP_PORT;
+        let listener =
+            bind_with_retry(SocketAddr::new(config.local_ip, bgp_port)).await;
         Ok(listener
             .accept()
             .await
@@ -118,3 +370,161 @@ impl Connection {
             .0)
     }
 }
+
+/// configのDSCPとTTLをsocketに設定する。
+/// linux-vrfのPeerとの接続は、vrfのテーブルで経路を引くようにvrfデバイスにbindする。
+fn set_socket_options(socket: SockRef, config: &Config) -> Result<()> {
+    let is_ipv6 = config.remote_ip.is_ipv6();
+    if let Some(dscp) = config.dscp {
+        // DSCPはToS(Traffic Class)の上位6 bits。
+        let tos = (dscp as u32) << 2;
+        // ToDo: IPv6のsessionにもIPV6_TCLASSで設定する。
+        if is_ipv6 {
+            anyhow::bail!("dscp is not supported for ipv6 session");
+        }
+        socket.set_tos(tos).context("cannot set dscp")?;
+    }
+    if let Some(ttl) = config.ttl {
+        if is_ipv6 {
+            socket.set_unicast_hops_v6(ttl.into())
+        } else {
+            socket.set_ttl(ttl.into())
+        }
+        .context("cannot set ttl")?;
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(vrf) = &config.linux_vrf {
+        socket
+            .bind_device(Some(vrf.device.as_bytes()))
+            .context(format!("cannot bind to vrf device {}", vrf.device))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::keepalive::KeepaliveMessage;
+    use tokio::net::TcpListener;
+
+    async fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) =
+            tokio::join!(TcpStream::connect(addr), listener.accept());
+        (client.unwrap(), server.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn dscp_and_ttl_are_set_to_socket() {
+        let config: Config =
+            "64512 127.0.0.1 64513 127.0.0.2 active dscp=cs6 ttl=255"
+                .parse()
+                .unwrap();
+        let (local, _remote) = connected_pair().await;
+        set_socket_options(SockRef::from(&local), &config).unwrap();
+
+        let socket = SockRef::from(&local);
+        assert_eq!(socket.tos().unwrap(), 48 << 2);
+        assert_eq!(socket.ttl().unwrap(), 255);
+    }
+
+    #[tokio::test]
+    async fn received_messages_are_returned_before_close_is_reported() {
+        let (local, mut remote) = connected_pair().await;
+        let mut conn = Connection::new(local, None, true);
+        let bytes: BytesMut = Message::new_keepalive().into();
+        remote.write_all(&bytes[..]).await.unwrap();
+        drop(remote);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert!(matches!(
+            conn.get_message().await,
+            Ok(Some(Message::Keepalive(_)))
+        ));
+        assert!(conn.get_message().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn large_message_is_accepted_only_with_extended_message() {
+        // ORIGINと、全体を5000 octetsにするための未知のPath Attributeを持つUPDATE。
+        let mut bytes = BytesMut::from(&[0xFF; 16][..]);
+        bytes.put_u16(5000);
+        bytes.put_u8(2);
+        bytes.put_u16(0);
+        bytes.put_u16(5000 - 23);
+        bytes.put(&[0x40, 1, 1, 0][..]);
+        bytes.put(&[0x90, 99, 0x13, 0x69][..]);
+        bytes.resize(5000, 0);
+
+        for extended in [false, true] {
+            let (local, mut remote) = connected_pair().await;
+            let mut conn = Connection::new(local, None, true);
+            conn.set_extended_message(extended);
+            remote.write_all(&bytes[..]).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let result = conn.get_message().await;
+            if extended {
+                assert!(matches!(result, Ok(Some(Message::Update(_)))));
+            } else {
+                let e = result.unwrap_err();
+                let notification =
+                    &e.downcast_ref::<MalformedMessageError>().unwrap().0;
+                assert_eq!(
+                    notification.error_subcode,
+                    NotificationMessage::BAD_MESSAGE_LENGTH
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn send_error_is_returned() {
+        let (local, remote) = connected_pair().await;
+        let mut conn = Connection::new(local, None, true);
+        conn.send(Message::new_keepalive()).await.unwrap();
+        drop(remote);
+
+        // 閉じられた接続への最初の書き込みは成功し、RSTを受けた後に失敗する。
+        let mut result = Ok(());
+        for _ in 0..10 {
+            result = conn.send(Message::new_keepalive()).await;
+            if result.is_err() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(result.is_err());
+        // 送れなかったMessageはbytesとして残っている。
+        assert_eq!(conn.pending_bytes(), 19);
+    }
+
+    #[tokio::test]
+    async fn queued_messages_are_sent_with_pacing() {
+        let (local, mut remote) = connected_pair().await;
+        // 1秒あたり10個なので、一度に送れるのは1個。
+        let mut conn = Connection::new(local, Some(10), true);
+        for _ in 0..5 {
+            conn.enqueue(Message::Keepalive(KeepaliveMessage::new()));
+        }
+        assert_eq!(conn.flush_queued_messages().unwrap(), 1);
+        assert_eq!(conn.queued_messages(), 4);
+
+        let mut buf = [0; 19];
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(
+            Message::try_from(BytesMut::from(&buf[..])).unwrap(),
+            Message::Keepalive(KeepaliveMessage::new())
+        );
+
+        let (local, mut remote) = connected_pair().await;
+        let mut conn = Connection::new(local, None, true);
+        for _ in 0..5 {
+            conn.enqueue(Message::Keepalive(KeepaliveMessage::new()));
+        }
+        assert_eq!(conn.flush_queued_messages().unwrap(), 5);
+        assert_eq!(conn.queued_messages(), 0);
+        let mut buf = [0; 19 * 5];
+        remote.read_exact(&mut buf).await.unwrap();
+    }
+}
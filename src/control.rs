@@ -0,0 +1,951 @@
+/// 動作中のmrbgpdv2をmrbgpdctlから操作するためのcontrol socketです。
+/// Unix domain socketに1行のコマンドを送ると、1行の結果が返ります。
+///
+/// ```text
+/// announce [vrf <name>] <network> [--next-hop <address>] [--community <asn>:<value>|no-export|no-advertise|blackhole]...
+/// withdraw [vrf <name>] <network>
+/// clear bgp neighbor <address> [soft [in|out]]
+/// show bgp [vrf <name>] <network>
+/// show bgp neighbor <address>
+/// show bgp neighbor <address> messages
+/// show bgp [vrf <name>] statistics
+/// maintenance bgp [neighbor <address>] <on|off>
+/// set bgp neighbor <address> log-level <trace|debug|info|warn|error|off|default>
+/// dump bgp rib
+/// ```
+///
+/// `vrf <name>`はlinux-vrfのdevice名で、そのlinux-vrfのLocRibを操作する。
+/// 指定しない場合はlinux-vrfを設定していないPeerのLocRibを、
+/// 全てのPeerがlinux-vrfを設定している場合は最初のlinux-vrfのLocRibを操作する。
+/// `show bgp statistics`はvrfを指定しない場合、全てのLocRibの合計を表示する。
+/// `dump bgp rib`は全てのLocRibを書き出す。
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use tokio::sync::{mpsc, watch, Mutex};
+#[cfg(unix)]
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+use tracing::level_filters::LevelFilter;
+use tracing::{info, warn};
+
+use crate::dump::{self, DumpRequest};
+use crate::error::ConfigParseError;
+use crate::logging::PeerLogLevels;
+use crate::message_log::MessageLog;
+use crate::path_attribute::Community;
+use crate::peer::{PeerStatus, ResetKind};
+use crate::routing::{IpNetwork, LocRib, LocRibs, RibStats};
+use crate::stats::resident_memory_kb;
+
+pub const DEFAULT_CONTROL_SOCKET: &str = "/var/run/mrbgpdv2.sock";
+
+/// control socketから操作するPeerです。
+#[derive(Debug)]
+pub struct Neighbor {
+    // Peerにリセットを指示するsender。
+    pub admin_sender: mpsc::UnboundedSender<ResetKind>,
+    pub status: watch::Receiver<PeerStatus>,
+    // PeerにAdj-RIBのダンプを求めるsender。
+    pub dump_sender: mpsc::UnboundedSender<DumpRequest>,
+    // Peerが直近に送受信したメッセージ。
+    pub messages: MessageLog,
+    // Peerにmaintenance modeの開始(true), 終了(false)を指示するsender。
+    pub maintenance_sender: mpsc::UnboundedSender<bool>,
+    // 全てのPeerで共有する、Peer毎のログのレベル。
+    pub log_levels: PeerLogLevels,
+}
+
+/// Peerのアドレスと、そのPeerの対応です。
+pub type Neighbors = HashMap<IpAddr, Neighbor>;
+
+// vrfは、操作するLocRibのlinux-vrfのdevice名。
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum ControlCommand {
+    Announce {
+        vrf: Option<String>,
+        network: IpNetwork,
+        next_hop: Option<IpAddr>,
+        communities: Vec<Community>,
+    },
+    Withdraw {
+        vrf: Option<String>,
+        network: IpNetwork,
+    },
+    Clear {
+        neighbor: IpAddr,
+        reset: ResetKind,
+    },
+    Show {
+        vrf: Option<String>,
+        network: IpNetwork,
+    },
+    ShowNeighbor {
+        neighbor: IpAddr,
+    },
+    ShowNeighborMessages {
+        neighbor: IpAddr,
+    },
+    ShowStatistics {
+        vrf: Option<String>,
+    },
+    // neighborがNoneの場合は全てのPeerをmaintenance modeにする。
+    Maintenance {
+        neighbor: Option<IpAddr>,
+        enabled: bool,
+    },
+    SetLogLevel {
+        neighbor: IpAddr,
+        // Noneの場合はRUST_LOGのレベルに戻す。
+        level: Option<LevelFilter>,
+    },
+    DumpRib,
+}
+
+impl FromStr for ControlCommand {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let (command, vrf, network) = match (words.next(), words.next()) {
+            (Some("clear"), _) => return parse_clear_command(s),
+            (Some("show"), _) => return parse_show_command(s),
+            (Some("set"), _) => return parse_set_command(s),
+            (Some("maintenance"), _) => return parse_maintenance_command(s),
+            (Some("dump"), Some("bgp"))
+                if s.split_whitespace().eq(["dump", "bgp", "rib"]) =>
+            {
+                return Ok(ControlCommand::DumpRib)
+            }
+            (Some(command), Some("vrf")) => {
+                match (words.next(), words.next()) {
+                    (Some(vrf), Some(network)) => {
+                        (command, Some(vrf.to_owned()), network)
+                    }
+                    _ => {
+                        return Err(ConfigParseError::from(anyhow::anyhow!(
+                            "cannot parse `{s}` as command"
+                        )))
+                    }
+                }
+            }
+            (Some(command), Some(network)) => (command, None, network),
+            _ => {
+                return Err(ConfigParseError::from(anyhow::anyhow!(
+                    "cannot parse `{s}` as command"
+                )))
+            }
+        };
+        let network: IpNetwork = network
+            .parse()
+            .context(format!("cannot parse {network} as network"))?;
+        match command {
+            "announce" => {
+                let mut next_hop = None;
+                let mut communities = vec![];
+                while let Some(option) = words.next() {
+                    let value = words.next().context(format!(
+                        "{option} requires a value and command is {s}"
+                    ))?;
+                    match option {
+                        "--next-hop" => {
+                            next_hop = Some(value.parse().context(format!(
+                                "cannot parse {value} as next hop"
+                            ))?)
+                        }
+                        "--community" => communities.push(value.parse()?),
+                        _ => {
+                            return Err(ConfigParseError::from(
+                                anyhow::anyhow!(
+                                "unknown option {option} and command is {s}"
+                            ),
+                            ))
+                        }
+                    }
+                }
+                Ok(ControlCommand::Announce {
+                    vrf,
+                    network,
+                    next_hop,
+                    communities,
+                })
+            }
+            "withdraw" => Ok(ControlCommand::Withdraw { vrf, network }),
+            _ => Err(ConfigParseError::from(anyhow::anyhow!(
+                "unknown command {command}"
+            ))),
+        }
+    }
+}
+
+/// `clear bgp neighbor <address> [soft [in|out]]`をparseする。
+fn parse_clear_command(s: &str) -> Result<ControlCommand, ConfigParseError> {
+    let words: Vec<&str> = s.split_whitespace().collect();
+    let neighbor = match words[..] {
+        ["clear", "bgp", "neighbor", neighbor, ..] => neighbor,
+        _ => {
+            return Err(ConfigParseError::from(anyhow::anyhow!(
+                "cannot parse `{s}` as clear command"
+            )))
+        }
+    };
+    let neighbor: IpAddr = neighbor
+        .parse()
+        .context(format!("cannot parse {neighbor} as neighbor address"))?;
+    let reset = match words[4..] {
+        [] => ResetKind::Hard,
+        ["soft"] => ResetKind::Soft,
+        ["soft", "in"] => ResetKind::SoftIn,
+        ["soft", "out"] => ResetKind::SoftOut,
+        _ => {
+            return Err(ConfigParseError::from(anyhow::anyhow!(
+                "unknown reset type `{}` and command is {s}",
+                words[4..].join(" ")
+            )))
+        }
+    };
+    Ok(ControlCommand::Clear { neighbor, reset })
+}
+
+/// `maintenance bgp [neighbor <address>] <on|off>`をparseする。
+fn parse_maintenance_command(
+    s: &str,
+) -> Result<ControlCommand, ConfigParseError> {
+    let words: Vec<&str> = s.split_whitespace().collect();
+    let (neighbor, enabled) = match words[..] {
+        ["maintenance", "bgp", enabled] => (None, enabled),
+        ["maintenance", "bgp", "neighbor", neighbor, enabled] => (
+            Some(neighbor.parse().context(format!(
+                "cannot parse {neighbor} as neighbor address"
+            ))?),
+            enabled,
+        ),
+        _ => {
+            return Err(ConfigParseError::from(anyhow::anyhow!(
+                "cannot parse `{s}` as maintenance command"
+            )))
+        }
+    };
+    let enabled = match enabled {
+        "on" => true,
+        "off" => false,
+        _ => {
+            return Err(ConfigParseError::from(anyhow::anyhow!(
+                "maintenance must be on or off and command is {s}"
+            )))
+        }
+    };
+    Ok(ControlCommand::Maintenance { neighbor, enabled })
+}
+
+/// `show bgp [vrf <name>] <network>`, `show bgp neighbor <address> [messages]`,
+/// `show bgp [vrf <name>] statistics`をparseする。
+fn parse_show_command(s: &str) -> Result<ControlCommand, ConfigParseError> {
+    let words: Vec<&str> = s.split_whitespace().collect();
+    let (vrf, words) = match words[..] {
+        ["show", "bgp", "vrf", vrf, ..] => (Some(vrf.to_owned()), &words[4..]),
+        ["show", "bgp", ..] => (None, &words[2..]),
+        _ => (None, &words[..0]),
+    };
+    match (words, vrf) {
+        (["statistics"], vrf) => Ok(ControlCommand::ShowStatistics { vrf }),
+        (["neighbor", neighbor], None) => Ok(ControlCommand::ShowNeighbor {
+            neighbor: neighbor.parse().context(format!(
+                "cannot parse {neighbor} as neighbor address"
+            ))?,
+        }),
+        (["neighbor", neighbor, "messages"], None) => {
+            Ok(ControlCommand::ShowNeighborMessages {
+                neighbor: neighbor.parse().context(format!(
+                    "cannot parse {neighbor} as neighbor address"
+                ))?,
+            })
+        }
+        ([network], vrf) => Ok(ControlCommand::Show {
+            vrf,
+            network: network
+                .parse()
+                .context(format!("cannot parse {network} as network"))?,
+        }),
+        _ => Err(ConfigParseError::from(anyhow::anyhow!(
+            "cannot parse `{s}` as show command"
+        ))),
+    }
+}
+
+/// `set bgp neighbor <address> log-level <level|default>`をparseする。
+fn parse_set_command(s: &str) -> Result<ControlCommand, ConfigParseError> {
+    let words: Vec<&str> = s.split_whitespace().collect();
+    let (neighbor, level) = match words[..] {
+        ["set", "bgp", "neighbor", neighbor, "log-level", level] => {
+            (neighbor, level)
+        }
+        _ => {
+            return Err(ConfigParseError::from(anyhow::anyhow!(
+                "cannot parse `{s}` as set command"
+            )))
+        }
+    };
+    let neighbor: IpAddr = neighbor
+        .parse()
+        .context(format!("cannot parse {neighbor} as neighbor address"))?;
+    let level = match level {
+        "default" => None,
+        level => Some(
+            level
+                .parse()
+                .context(format!("cannot parse {level} as log level"))?,
+        ),
+    };
+    Ok(ControlCommand::SetLogLevel { neighbor, level })
+}
+
+/// `vrf <name> `を書き出す。vrfがNoneの場合は何も書かない。
+fn write_vrf(f: &mut fmt::Formatter<'_>, vrf: &Option<String>) -> fmt::Result {
+    match vrf {
+        Some(vrf) => write!(f, "vrf {} ", vrf),
+        None => Ok(()),
+    }
+}
+
+impl fmt::Display for ControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlCommand::Announce {
+                vrf,
+                network,
+                next_hop,
+                communities,
+            } => {
+                write!(f, "announce ")?;
+                write_vrf(f, vrf)?;
+                write!(f, "{}", network)?;
+                if let Some(next_hop) = next_hop {
+                    write!(f, " --next-hop {}", next_hop)?;
+                }
+                for community in communities {
+                    write!(f, " --community {}", community)?;
+                }
+                Ok(())
+            }
+            ControlCommand::Withdraw { vrf, network } => {
+                write!(f, "withdraw ")?;
+                write_vrf(f, vrf)?;
+                write!(f, "{}", network)
+            }
+            ControlCommand::Clear { neighbor, reset } => {
+                write!(f, "clear bgp neighbor {}", neighbor)?;
+                match reset {
+                    ResetKind::Hard => Ok(()),
+                    ResetKind::Soft => write!(f, " soft"),
+                    ResetKind::SoftIn => write!(f, " soft in"),
+                    ResetKind::SoftOut => write!(f, " soft out"),
+                }
+            }
+            ControlCommand::Show { vrf, network } => {
+                write!(f, "show bgp ")?;
+                write_vrf(f, vrf)?;
+                write!(f, "{}", network)
+            }
+            ControlCommand::ShowNeighbor { neighbor } => {
+                write!(f, "show bgp neighbor {}", neighbor)
+            }
+            ControlCommand::ShowNeighborMessages { neighbor } => {
+                write!(f, "show bgp neighbor {} messages", neighbor)
+            }
+            ControlCommand::ShowStatistics { vrf } => {
+                write!(f, "show bgp ")?;
+                write_vrf(f, vrf)?;
+                write!(f, "statistics")
+            }
+            ControlCommand::Maintenance { neighbor, enabled } => {
+                write!(f, "maintenance bgp ")?;
+                if let Some(neighbor) = neighbor {
+                    write!(f, "neighbor {} ", neighbor)?;
+                }
+                write!(f, "{}", if *enabled { "on" } else { "off" })
+            }
+            ControlCommand::SetLogLevel { neighbor, level } => {
+                write!(f, "set bgp neighbor {} log-level ", neighbor)?;
+                match level {
+                    Some(level) => write!(f, "{}", level),
+                    None => write!(f, "default"),
+                }
+            }
+            ControlCommand::DumpRib => write!(f, "dump bgp rib"),
+        }
+    }
+}
+
+/// vrfのLocRibを返す。vrfがNoneの場合は、linux-vrfを設定していないPeerの
+/// LocRibか、それが無ければ最初のlinux-vrfのLocRibを返す。
+fn select_loc_rib<'a>(
+    loc_ribs: &'a LocRibs,
+    vrf: &Option<String>,
+) -> Result<&'a Mutex<LocRib>> {
+    let loc_rib = match vrf {
+        Some(vrf) => loc_ribs
+            .iter()
+            .find(|(linux_vrf, _)| {
+                linux_vrf.as_ref().is_some_and(|v| &v.device == vrf)
+            })
+            .map(|(_, loc_rib)| loc_rib)
+            .context(format!("{vrf} is not configured as linux-vrf"))?,
+        None => loc_ribs.values().next().context("no loc_rib exists")?,
+    };
+    Ok(loc_rib)
+}
+
+impl ControlCommand {
+    /// コマンドをLocRibに反映するか、Peerに指示する。
+    /// showの場合は表示する内容を、dumpの場合は書き出したファイルのパスを返す。
+    pub async fn execute(
+        &self,
+        loc_ribs: &LocRibs,
+        neighbors: &Neighbors,
+        dump_dir: &Path,
+    ) -> Result<Option<String>> {
+        let result = match self {
+            ControlCommand::Announce {
+                vrf,
+                network,
+                next_hop,
+                communities,
+            } => select_loc_rib(loc_ribs, vrf)?.lock().await.announce(
+                *network,
+                *next_hop,
+                communities.clone(),
+            ),
+            ControlCommand::Withdraw { vrf, network } => {
+                select_loc_rib(loc_ribs, vrf)?
+                    .lock()
+                    .await
+                    .withdraw(*network)
+            }
+            ControlCommand::Clear { neighbor, reset } => neighbors
+                .get(neighbor)
+                .context(format!("{neighbor} is not configured as neighbor"))?
+                .admin_sender
+                .send(*reset)
+                .context(format!("session with {neighbor} is stopped")),
+            ControlCommand::Show { vrf, network } => {
+                return select_loc_rib(loc_ribs, vrf)?
+                    .lock()
+                    .await
+                    .best_path(*network)
+                    .map(|best| Some(best.to_string()))
+                    .context(format!("{network} is not in loc_rib"));
+            }
+            ControlCommand::ShowNeighbor { neighbor } => {
+                let status = neighbors
+                    .get(neighbor)
+                    .context(format!(
+                        "{neighbor} is not configured as neighbor"
+                    ))?
+                    .status
+                    .borrow()
+                    .clone();
+                return Ok(Some(format!("neighbor {} {}", neighbor, status)));
+            }
+            ControlCommand::ShowNeighborMessages { neighbor } => {
+                // 応答は1行なので、改行を含まないJSONで返す。
+                let messages = neighbors
+                    .get(neighbor)
+                    .context(format!(
+                        "{neighbor} is not configured as neighbor"
+                    ))?
+                    .messages
+                    .to_json();
+                return Ok(Some(messages.to_string()));
+            }
+            ControlCommand::ShowStatistics { vrf } => {
+                let stats = match vrf {
+                    Some(_) => {
+                        select_loc_rib(loc_ribs, vrf)?.lock().await.stats()
+                    }
+                    None => {
+                        let mut stats = RibStats::default();
+                        for loc_rib in loc_ribs.values() {
+                            stats += loc_rib.lock().await.stats();
+                        }
+                        stats
+                    }
+                };
+                let mut output = stats.to_string();
+                if let Some(rss) = resident_memory_kb() {
+                    output += &format!(" rss={}kB", rss);
+                }
+                return Ok(Some(output));
+            }
+            ControlCommand::Maintenance { neighbor, enabled } => {
+                let targets: Vec<&Neighbor> = match neighbor {
+                    Some(neighbor) => vec![neighbors.get(neighbor).context(
+                        format!("{neighbor} is not configured as neighbor"),
+                    )?],
+                    None => neighbors.values().collect(),
+                };
+                for target in targets {
+                    let _ = target.maintenance_sender.send(*enabled);
+                }
+                Ok(())
+            }
+            ControlCommand::SetLogLevel { neighbor, level } => {
+                neighbors
+                    .get(neighbor)
+                    .context(format!(
+                        "{neighbor} is not configured as neighbor"
+                    ))?
+                    .log_levels
+                    .set(*neighbor, *level);
+                Ok(())
+            }
+            ControlCommand::DumpRib => {
+                let peers = neighbors.iter().map(|(remote_ip, neighbor)| {
+                    (*remote_ip, neighbor.dump_sender.clone())
+                });
+                let path =
+                    dump::write_rib_dump(dump_dir, loc_ribs, peers).await?;
+                return Ok(Some(format!(
+                    "rib is dumped to {}",
+                    path.display()
+                )));
+            }
+        };
+        result.map(|_| None)
+    }
+}
+
+#[cfg(unix)]
+/// control socketで接続を待ち受け、受信したコマンドを実行する。
+pub async fn serve(
+    path: &Path,
+    loc_ribs: Arc<LocRibs>,
+    neighbors: Arc<Neighbors>,
+    dump_dir: PathBuf,
+) -> Result<()> {
+    // 前回起動時のsocketが残っているとbindできない。
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)
+        .context(format!("cannot bind control socket {}", path.display()))?;
+    info!("control socket is listening on {}.", path.display());
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let loc_ribs = Arc::clone(&loc_ribs);
+        let neighbors = Arc::clone(&neighbors);
+        let dump_dir = dump_dir.clone();
+        tokio::spawn(async move {
+            if let Err(e) =
+                handle_connection(stream, loc_ribs, neighbors, dump_dir).await
+            {
+                warn!("control connection is closed with error: {:?}.", e);
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+async fn handle_connection(
+    stream: UnixStream,
+    loc_ribs: Arc<LocRibs>,
+    neighbors: Arc<Neighbors>,
+    dump_dir: PathBuf,
+) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let result = match line.parse::<ControlCommand>() {
+            Ok(command) => {
+                info!("control command is received, command={}.", command);
+                command
+                    .execute(&loc_ribs, &neighbors, &dump_dir)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+            Err(e) => Err(e.to_string()),
+        };
+        let response = match result {
+            Ok(Some(output)) => format!("{}\n", output),
+            Ok(None) => "ok\n".to_string(),
+            Err(e) => format!("error: {}\n", e),
+        };
+        writer.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+/// control socketにコマンドを送り、結果を返す。
+pub async fn request(path: &Path, command: &ControlCommand) -> Result<String> {
+    let stream = UnixStream::connect(path)
+        .await
+        .context(format!("cannot connect to {}", path.display()))?;
+    let (reader, mut writer) = stream.into_split();
+    writer
+        .write_all(format!("{}\n", command).as_bytes())
+        .await?;
+    let response = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .context("control socket is closed without response")?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn parse_control_command() {
+        let command: ControlCommand =
+            "announce 203.0.113.0/24 --next-hop 10.0.0.1 --community 64512:100"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            command,
+            ControlCommand::Announce {
+                network: "203.0.113.0/24".parse().unwrap(),
+                next_hop: Some("10.0.0.1".parse().unwrap()),
+                communities: vec![Community(64512 << 16 | 100)],
+                vrf: None,
+            }
+        );
+        assert_eq!(
+            command.to_string().parse::<ControlCommand>().unwrap(),
+            command
+        );
+        assert!("announce 203.0.113.0/24 --next-hop"
+            .parse::<ControlCommand>()
+            .is_err());
+
+        let show: ControlCommand = "show bgp 203.0.113.0/24".parse().unwrap();
+        assert_eq!(show.to_string().parse::<ControlCommand>().unwrap(), show);
+    }
+
+    /// configのLocRibだけを持つLocRibsを作る。
+    async fn loc_ribs(config: &Config) -> LocRibs {
+        let loc_rib = LocRib::new(config).await.unwrap();
+        LocRibs::from([(
+            config.linux_vrf.clone(),
+            Arc::new(Mutex::new(loc_rib)),
+        )])
+    }
+
+    /// Peerの代わりに、Neighborに送られた指示を受け取るものです。
+    struct NeighborHandles {
+        admin: mpsc::UnboundedReceiver<ResetKind>,
+        status: watch::Sender<PeerStatus>,
+        maintenance: mpsc::UnboundedReceiver<bool>,
+        log_levels: PeerLogLevels,
+    }
+
+    /// configのPeerだけを持つNeighborsと、その指示を受け取るものを作る。
+    fn neighbors(config: &Config) -> (Neighbors, NeighborHandles) {
+        let (admin_sender, admin) = mpsc::unbounded_channel();
+        let (status_sender, status) = watch::channel(PeerStatus::default());
+        let (dump_sender, _) = mpsc::unbounded_channel();
+        let (maintenance_sender, maintenance) = mpsc::unbounded_channel();
+        let log_levels = PeerLogLevels::default();
+        let neighbors = Neighbors::from([(
+            config.remote_ip,
+            Neighbor {
+                admin_sender,
+                status,
+                dump_sender,
+                messages: MessageLog::new(10),
+                maintenance_sender,
+                log_levels: log_levels.clone(),
+            },
+        )]);
+        let handles = NeighborHandles {
+            admin,
+            status: status_sender,
+            maintenance,
+            log_levels,
+        };
+        (neighbors, handles)
+    }
+
+    #[tokio::test]
+    async fn clear_command_is_sent_to_neighbor() {
+        let command: ControlCommand =
+            "clear bgp neighbor 10.0.0.3 soft in".parse().unwrap();
+        assert_eq!(
+            command,
+            ControlCommand::Clear {
+                neighbor: "10.0.0.3".parse().unwrap(),
+                reset: ResetKind::SoftIn,
+            }
+        );
+        assert_eq!(
+            command.to_string().parse::<ControlCommand>().unwrap(),
+            command
+        );
+        assert!("clear bgp neighbor 10.0.0.3 hard"
+            .parse::<ControlCommand>()
+            .is_err());
+
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let loc_ribs = loc_ribs(&config).await;
+        let (neighbors, mut handles) = neighbors(&config);
+        let dump_dir = std::env::temp_dir();
+        assert_eq!(
+            command
+                .execute(&loc_ribs, &neighbors, &dump_dir)
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(handles.admin.try_recv().unwrap(), ResetKind::SoftIn);
+
+        let unknown: ControlCommand =
+            "clear bgp neighbor 10.0.0.4".parse().unwrap();
+        assert!(unknown
+            .execute(&loc_ribs, &neighbors, &dump_dir)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn vrf_command_uses_loc_rib_of_linux_vrf() {
+        let command: ControlCommand =
+            "announce vrf red 203.0.113.0/24".parse().unwrap();
+        assert_eq!(
+            command,
+            ControlCommand::Announce {
+                network: "203.0.113.0/24".parse().unwrap(),
+                next_hop: None,
+                communities: vec![],
+                vrf: Some("red".to_owned()),
+            }
+        );
+        assert_eq!(
+            command.to_string().parse::<ControlCommand>().unwrap(),
+            command
+        );
+
+        let default: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let red: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active linux-vrf=red:1000"
+                .parse()
+                .unwrap();
+        let mut loc_ribs = loc_ribs(&default).await;
+        loc_ribs.extend(self::loc_ribs(&red).await);
+        let (neighbors, _handles) = neighbors(&default);
+        let dump_dir = std::env::temp_dir();
+        command
+            .execute(&loc_ribs, &neighbors, &dump_dir)
+            .await
+            .unwrap();
+
+        let network = "203.0.113.0/24".parse().unwrap();
+        assert!(loc_ribs[&red.linux_vrf]
+            .lock()
+            .await
+            .best_path(network)
+            .is_some());
+        assert!(loc_ribs[&None].lock().await.best_path(network).is_none());
+
+        let show: ControlCommand =
+            "show bgp vrf red 203.0.113.0/24".parse().unwrap();
+        assert_eq!(show.to_string().parse::<ControlCommand>().unwrap(), show);
+        assert!(show
+            .execute(&loc_ribs, &neighbors, &dump_dir)
+            .await
+            .unwrap()
+            .is_some());
+        let show: ControlCommand = "show bgp 203.0.113.0/24".parse().unwrap();
+        assert!(show
+            .execute(&loc_ribs, &neighbors, &dump_dir)
+            .await
+            .is_err());
+
+        let unknown: ControlCommand =
+            "withdraw vrf blue 203.0.113.0/24".parse().unwrap();
+        assert!(unknown
+            .execute(&loc_ribs, &neighbors, &dump_dir)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn show_neighbor_command_shows_status() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let loc_ribs = loc_ribs(&config).await;
+        let (neighbors, handles) = neighbors(&config);
+        let dump_dir = std::env::temp_dir();
+        let show: ControlCommand =
+            "show bgp neighbor 10.0.0.3".parse().unwrap();
+        assert_eq!(show.to_string().parse::<ControlCommand>().unwrap(), show);
+        assert_eq!(
+            show.execute(&loc_ribs, &neighbors, &dump_dir)
+                .await
+                .unwrap(),
+            Some("neighbor 10.0.0.3 state=Idle".to_string())
+        );
+        // Peerが最後にメッセージを受信してからの経過時間も表示する。
+        handles
+            .status
+            .send(PeerStatus {
+                last_message_received: Some(
+                    Instant::now() - Duration::from_secs(5),
+                ),
+                ..PeerStatus::default()
+            })
+            .unwrap();
+        let shown = show
+            .execute(&loc_ribs, &neighbors, &dump_dir)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(
+            shown.starts_with("neighbor 10.0.0.3 state=Idle last-received="),
+            "{shown}"
+        );
+        assert!(shown.ends_with("s-ago"), "{shown}");
+    }
+
+    #[tokio::test]
+    async fn show_neighbor_messages_command_shows_json() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let loc_ribs = loc_ribs(&config).await;
+        let (neighbors, _handles) = neighbors(&config);
+        let show_messages: ControlCommand =
+            "show bgp neighbor 10.0.0.3 messages".parse().unwrap();
+        assert_eq!(
+            show_messages.to_string().parse::<ControlCommand>().unwrap(),
+            show_messages
+        );
+        assert_eq!(
+            show_messages
+                .execute(&loc_ribs, &neighbors, &std::env::temp_dir())
+                .await
+                .unwrap(),
+            Some("[]".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn maintenance_command_is_sent_to_neighbors() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let loc_ribs = loc_ribs(&config).await;
+        let (neighbors, mut handles) = neighbors(&config);
+        for command in [
+            "maintenance bgp on",
+            "maintenance bgp neighbor 10.0.0.3 off",
+        ] {
+            let maintenance_command: ControlCommand = command.parse().unwrap();
+            assert_eq!(maintenance_command.to_string(), command);
+            maintenance_command
+                .execute(&loc_ribs, &neighbors, &std::env::temp_dir())
+                .await
+                .unwrap();
+        }
+        assert!(handles.maintenance.try_recv().unwrap());
+        assert!(!handles.maintenance.try_recv().unwrap());
+        assert!("maintenance bgp neighbor 10.0.0.3"
+            .parse::<ControlCommand>()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn set_log_level_command_changes_neighbor_level() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let loc_ribs = loc_ribs(&config).await;
+        let (neighbors, handles) = neighbors(&config);
+        let dump_dir = std::env::temp_dir();
+        let set: ControlCommand =
+            "set bgp neighbor 10.0.0.3 log-level debug".parse().unwrap();
+        assert_eq!(set.to_string().parse::<ControlCommand>().unwrap(), set);
+        assert_eq!(
+            set.execute(&loc_ribs, &neighbors, &dump_dir).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            handles.log_levels.get(config.remote_ip),
+            Some(LevelFilter::DEBUG)
+        );
+        let default: ControlCommand =
+            "set bgp neighbor 10.0.0.3 log-level default"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            default.to_string().parse::<ControlCommand>().unwrap(),
+            default
+        );
+        default
+            .execute(&loc_ribs, &neighbors, &dump_dir)
+            .await
+            .unwrap();
+        assert_eq!(handles.log_levels.get(config.remote_ip), None);
+        assert!("set bgp neighbor 10.0.0.3 log-level verbose"
+            .parse::<ControlCommand>()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn announce_and_withdraw_through_control_socket() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let loc_rib =
+            Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let path = std::env::temp_dir()
+            .join(format!("mrbgpdv2-test-{}.sock", std::process::id()));
+        let server = tokio::spawn({
+            let path = path.clone();
+            let loc_ribs = LocRibs::from([(None, Arc::clone(&loc_rib))]);
+            async move {
+                serve(
+                    &path,
+                    Arc::new(loc_ribs),
+                    Arc::default(),
+                    std::env::temp_dir(),
+                )
+                .await
+            }
+        });
+        while !path.exists() {
+            tokio::task::yield_now().await;
+        }
+
+        let announce: ControlCommand =
+            "announce 203.0.113.0/24 --community 64512:100"
+                .parse()
+                .unwrap();
+        assert_eq!(request(&path, &announce).await.unwrap(), "ok");
+        {
+            let loc_rib = loc_rib.lock().await;
+            let routes: Vec<_> = loc_rib.routes().collect();
+            assert_eq!(routes.len(), 1);
+            assert_eq!(routes[0].next_hop(), Some(config.local_ip));
+            assert_eq!(loc_rib.generation(), 1);
+        }
+
+        let withdraw: ControlCommand =
+            "withdraw 203.0.113.0/24".parse().unwrap();
+        assert_eq!(request(&path, &withdraw).await.unwrap(), "ok");
+        assert_eq!(loc_rib.lock().await.routes().count(), 0);
+        assert!(request(&path, &withdraw)
+            .await
+            .unwrap()
+            .starts_with("error"));
+
+        server.abort();
+        let _ = std::fs::remove_file(&path);
+    }
+}
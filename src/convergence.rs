@@ -0,0 +1,83 @@
+/// 起動直後の収束を待つモジュールです。
+/// 起動直後は一部のPeerからしかルートを受信しておらず、LocRibが収束していないので、
+/// 全てのPeerがEstablishedになるか`startup-wait`の時間が経つまで、
+/// どのPeerにもルートを広報しないようにする。
+use std::time::Duration;
+
+use futures::future::select_all;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::peer::PeerStatus;
+use crate::state::State;
+
+/// 全てのPeerの状態を監視し、収束したとみなしたらtrueになるreceiverを返す。
+pub fn wait_for_convergence(
+    statuses: Vec<watch::Receiver<PeerStatus>>,
+    wait: Duration,
+) -> watch::Receiver<bool> {
+    let (converged, receiver) = watch::channel(false);
+    tokio::spawn(async move {
+        let timer = tokio::time::sleep(wait);
+        tokio::pin!(timer);
+        let mut statuses = statuses;
+        loop {
+            if is_all_established(&statuses) {
+                info!("all peers are established, start advertisement.");
+                break;
+            }
+            let changed =
+                select_all(statuses.iter_mut().map(|s| Box::pin(s.changed())));
+            tokio::select! {
+                _ = &mut timer => {
+                    warn!(
+                        "startup wait {:?} is expired before all peers are \
+                         established, start advertisement.",
+                        wait
+                    );
+                    break;
+                }
+                _ = changed => {}
+            }
+        }
+        let _ = converged.send(true);
+    });
+    receiver
+}
+
+fn is_all_established(statuses: &[watch::Receiver<PeerStatus>]) -> bool {
+    statuses
+        .iter()
+        .all(|s| s.borrow().state == State::Established)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn converged_when_all_peers_are_established_or_timer_expires() {
+        let (peer1, status1) = watch::channel(PeerStatus::default());
+        let (peer2, status2) = watch::channel(PeerStatus::default());
+        let mut converged = wait_for_convergence(
+            vec![status1, status2],
+            Duration::from_secs(60),
+        );
+        let established = PeerStatus {
+            state: State::Established,
+            ..PeerStatus::default()
+        };
+        peer1.send(established.clone()).unwrap();
+        tokio::task::yield_now().await;
+        assert!(!*converged.borrow());
+        peer2.send(established).unwrap();
+        converged.changed().await.unwrap();
+        assert!(*converged.borrow());
+
+        let (_peer, status) = watch::channel(PeerStatus::default());
+        let mut converged =
+            wait_for_convergence(vec![status], Duration::from_millis(10));
+        converged.changed().await.unwrap();
+        assert!(*converged.borrow());
+    }
+}
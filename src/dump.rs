@@ -0,0 +1,455 @@
+/// LocRibと各PeerのAdj-RIBをJSONでファイルに書き出すモジュールです。
+/// SIGUSR1を受け取るか、control socketから`dump bgp rib`を指示されると、
+/// `dump-dir`に`mrbgpdv2-rib-<UNIX時刻(ミリ秒)>.json`を作成する。
+/// 一時ファイルに書き込んでからrenameするので、途中まで書かれたファイルが
+/// 読まれることはない。
+/// 2つのダンプを比較して、追加, 削除, PathAttributeが変わったルートを
+/// 求めることもできる(`mrbgpdctl diff bgp rib <old> <new>`)。
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time::Instant;
+
+use crate::bgp_ls::LinkStateRibEntry;
+use crate::evpn::EvpnRibEntry;
+use crate::flowspec::FlowSpecRibEntry;
+use crate::path_attribute::PathAttribute;
+use crate::routing::{LocRib, LocRibs, Rib, RibEntry, RibKey};
+use crate::vpn::{RtcRibEntry, VpnRibEntry};
+
+pub const DEFAULT_DUMP_DIR: &str = "/var/tmp";
+
+// Peerが接続の確立などで応答できない場合に、応答を諦めるまでの時間。
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// PeerにAdj-RIBのJSONを返すように指示するためのsenderです。
+pub type DumpRequest = oneshot::Sender<Value>;
+
+/// Ribのエントリを、NLRIを表す文字列とPathAttributeの組として書き出す。
+pub trait DumpEntry {
+    fn nlri(&self) -> String;
+    fn path_attributes(&self) -> &[PathAttribute];
+}
+
+impl DumpEntry for RibEntry {
+    fn nlri(&self) -> String {
+        if self.labels.is_empty() {
+            self.network_address.to_string()
+        } else {
+            format!("{} labels {:?}", self.network_address, self.labels)
+        }
+    }
+
+    fn path_attributes(&self) -> &[PathAttribute] {
+        &self.path_attributes
+    }
+}
+
+impl DumpEntry for VpnRibEntry {
+    fn nlri(&self) -> String {
+        self.prefix.to_string()
+    }
+
+    fn path_attributes(&self) -> &[PathAttribute] {
+        &self.path_attributes
+    }
+}
+
+impl DumpEntry for FlowSpecRibEntry {
+    fn nlri(&self) -> String {
+        self.rule.to_string()
+    }
+
+    fn path_attributes(&self) -> &[PathAttribute] {
+        &self.path_attributes
+    }
+}
+
+impl DumpEntry for EvpnRibEntry {
+    fn nlri(&self) -> String {
+        self.route.to_string()
+    }
+
+    fn path_attributes(&self) -> &[PathAttribute] {
+        &self.path_attributes
+    }
+}
+
+impl DumpEntry for LinkStateRibEntry {
+    fn nlri(&self) -> String {
+        format!("{:?}", self.nlri)
+    }
+
+    fn path_attributes(&self) -> &[PathAttribute] {
+        &self.path_attributes
+    }
+}
+
+impl DumpEntry for RtcRibEntry {
+    fn nlri(&self) -> String {
+        self.membership.to_string()
+    }
+
+    fn path_attributes(&self) -> &[PathAttribute] {
+        &self.path_attributes
+    }
+}
+
+impl<E: RibKey + DumpEntry> Rib<E> {
+    /// エントリの順に`{"nlri": .., "path_attributes": [..]}`の配列にする。
+    pub fn to_json(&self) -> Value {
+        self.routes()
+            .map(|entry| {
+                let path_attributes: Vec<String> = entry
+                    .path_attributes()
+                    .iter()
+                    .map(|p| format!("{:?}", p))
+                    .collect();
+                json!({
+                    "nlri": entry.nlri(),
+                    "path_attributes": path_attributes,
+                })
+            })
+            .collect()
+    }
+}
+
+/// 各PeerにAdj-RIBを問い合わせてから、LocRibと合わせてdirに書き出し、
+/// 作成したファイルのパスを返す。
+/// 応答しなかったPeerのAdj-RIBはnullとして書き出す。
+/// linux-vrfのLocRibは、`linux_vrfs`にdevice名をkeyにして書き出す。
+pub async fn write_rib_dump(
+    dir: &Path,
+    loc_ribs: &LocRibs,
+    peers: impl IntoIterator<Item = (IpAddr, mpsc::UnboundedSender<DumpRequest>)>,
+) -> Result<PathBuf> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("system time is before unix epoch")?;
+
+    // PeerはAdj-RIBを返す前にLocRibのlockを取ることがあるので、
+    // Peerの応答を全て待ってからLocRibのlockを取る。
+    let mut receivers = vec![];
+    for (remote_ip, sender) in peers {
+        let (reply, receiver) = oneshot::channel();
+        let receiver = match sender.send(reply) {
+            Ok(()) => Some(receiver),
+            Err(_) => None,
+        };
+        receivers.push((remote_ip, receiver));
+    }
+    let deadline = Instant::now() + PEER_TIMEOUT;
+    let mut neighbors = BTreeMap::new();
+    for (remote_ip, receiver) in receivers {
+        let adj_ribs = match receiver {
+            Some(receiver) => tokio::time::timeout_at(deadline, receiver)
+                .await
+                .ok()
+                .and_then(|r| r.ok())
+                .unwrap_or(Value::Null),
+            None => Value::Null,
+        };
+        neighbors.insert(remote_ip.to_string(), adj_ribs);
+    }
+    let mut dump = json!({
+        "timestamp": timestamp.as_secs(),
+        "neighbors": neighbors,
+    });
+    let mut linux_vrfs = serde_json::Map::new();
+    for (linux_vrf, loc_rib) in loc_ribs {
+        let loc_rib = loc_rib.lock().await.to_json();
+        match linux_vrf {
+            Some(linux_vrf) => {
+                linux_vrfs.insert(linux_vrf.device.clone(), loc_rib);
+            }
+            None => dump["loc_rib"] = loc_rib,
+        }
+    }
+    if !linux_vrfs.is_empty() {
+        dump["linux_vrfs"] = Value::Object(linux_vrfs);
+    }
+
+    let path =
+        dir.join(format!("mrbgpdv2-rib-{}.json", timestamp.as_millis()));
+    let temporary = path.with_extension("json.tmp");
+    tokio::fs::write(&temporary, serde_json::to_vec_pretty(&dump)?)
+        .await
+        .context(format!("cannot write {}", temporary.display()))?;
+    tokio::fs::rename(&temporary, &path)
+        .await
+        .context(format!("cannot rename to {}", path.display()))?;
+    Ok(path)
+}
+
+/// 2つのダンプの間でのルートの変化です。
+/// tableはダンプ内のRibの位置を`/`で繋いだもので、
+/// 例えば`loc_rib/unicast`や`neighbors/10.0.0.3/adj_rib_in/unicast`になる。
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum RibChange {
+    Added {
+        table: String,
+        nlri: String,
+        path_attributes: Vec<Value>,
+    },
+    Removed {
+        table: String,
+        nlri: String,
+        path_attributes: Vec<Value>,
+    },
+    Changed {
+        table: String,
+        nlri: String,
+        old: Vec<Value>,
+        new: Vec<Value>,
+    },
+}
+
+impl fmt::Display for RibChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let paths = |paths: &[Value]| {
+            paths
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        match self {
+            RibChange::Added {
+                table,
+                nlri,
+                path_attributes,
+            } => write!(f, "+ {} {} {}", table, nlri, paths(path_attributes)),
+            RibChange::Removed {
+                table,
+                nlri,
+                path_attributes,
+            } => write!(f, "- {} {} {}", table, nlri, paths(path_attributes)),
+            RibChange::Changed {
+                table,
+                nlri,
+                old,
+                new,
+            } => write!(
+                f,
+                "~ {} {} {} -> {}",
+                table,
+                nlri,
+                paths(old),
+                paths(new)
+            ),
+        }
+    }
+}
+
+// (table, nlri)毎の、PathAttributeの一覧。
+// Add-Pathなどで1つのNLRIに複数のエントリがあることがあるので、
+// エントリ毎のPathAttributeを順に並べて持つ。
+type DumpedRoutes = BTreeMap<(String, String), Vec<Value>>;
+
+/// ダンプを読み込む。
+pub async fn read_rib_dump(path: &Path) -> Result<Value> {
+    let bytes = tokio::fs::read(path)
+        .await
+        .context(format!("cannot read {}", path.display()))?;
+    let dump: Value = serde_json::from_slice(&bytes)
+        .context(format!("{} is not a rib dump", path.display()))?;
+    if !dump.is_object() {
+        bail!("{} is not a rib dump", path.display());
+    }
+    Ok(dump)
+}
+
+/// 2つのダンプを比較し、table, NLRIの順に並べたルートの変化を返す。
+/// 応答しなかったPeerなど、片方にしか無いRibは全て追加, 削除として扱う。
+pub fn diff_rib_dumps(old: &Value, new: &Value) -> Vec<RibChange> {
+    let mut old_routes = DumpedRoutes::new();
+    collect_routes(old, &mut vec![], &mut old_routes);
+    let mut new_routes = DumpedRoutes::new();
+    collect_routes(new, &mut vec![], &mut new_routes);
+
+    let mut changes = vec![];
+    let mut old_routes = old_routes.into_iter().peekable();
+    let mut new_routes = new_routes.into_iter().peekable();
+    loop {
+        let order = match (old_routes.peek(), new_routes.peek()) {
+            (Some((o, _)), Some((n, _))) => o.cmp(n),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => break,
+        };
+        match order {
+            Ordering::Less => {
+                let ((table, nlri), path_attributes) =
+                    old_routes.next().unwrap();
+                changes.push(RibChange::Removed {
+                    table,
+                    nlri,
+                    path_attributes,
+                });
+            }
+            Ordering::Greater => {
+                let ((table, nlri), path_attributes) =
+                    new_routes.next().unwrap();
+                changes.push(RibChange::Added {
+                    table,
+                    nlri,
+                    path_attributes,
+                });
+            }
+            Ordering::Equal => {
+                let (_, old) = old_routes.next().unwrap();
+                let ((table, nlri), new) = new_routes.next().unwrap();
+                if old != new {
+                    changes.push(RibChange::Changed {
+                        table,
+                        nlri,
+                        old,
+                        new,
+                    });
+                }
+            }
+        }
+    }
+    changes
+}
+
+// `{"nlri": .., "path_attributes": [..]}`の配列をRibとみなし、
+// そこに至るまでのkeyをtableの名前にしてroutesに集める。
+fn collect_routes(
+    value: &Value,
+    path: &mut Vec<String>,
+    routes: &mut DumpedRoutes,
+) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                path.push(key.clone());
+                collect_routes(value, path, routes);
+                path.pop();
+            }
+        }
+        Value::Array(entries) => {
+            let table = path.join("/");
+            for entry in entries {
+                let nlri = match entry.get("nlri").and_then(|n| n.as_str()) {
+                    Some(nlri) => nlri.to_string(),
+                    None => continue,
+                };
+                let path_attributes = entry
+                    .get("path_attributes")
+                    .cloned()
+                    .unwrap_or(Value::Null);
+                let paths =
+                    routes.entry((table.clone(), nlri)).or_insert(vec![]);
+                paths.push(path_attributes);
+                // ダンプ内でのエントリの順に依らず比較できるようにする。
+                paths.sort_by_key(|p| p.to_string());
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn loc_rib_and_adj_ribs_are_dumped_as_json() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let loc_rib =
+            Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        loc_rib
+            .lock()
+            .await
+            .announce("203.0.113.0/24".parse().unwrap(), None, vec![])
+            .unwrap();
+
+        let (established, mut requests) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let reply: DumpRequest = requests.recv().await.unwrap();
+            reply.send(json!({ "state": "Established" })).unwrap();
+        });
+        let (stopped, _) = mpsc::unbounded_channel();
+        let peers = vec![
+            ("10.0.0.3".parse().unwrap(), established),
+            ("10.0.0.4".parse().unwrap(), stopped),
+        ];
+        let dir = std::env::temp_dir();
+        let loc_ribs = LocRibs::from([(None, loc_rib)]);
+        let path = write_rib_dump(&dir, &loc_ribs, peers).await.unwrap();
+
+        let dump: Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(dump["loc_rib"]["unicast"][0]["nlri"], "203.0.113.0/24");
+        assert_eq!(
+            dump["neighbors"]["10.0.0.3"],
+            json!({ "state": "Established" })
+        );
+        assert_eq!(dump["neighbors"]["10.0.0.4"], Value::Null);
+        assert!(!path.with_extension("json.tmp").exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rib_dumps_are_diffed_by_table_and_nlri() {
+        let old = json!({
+            "timestamp": 1,
+            "loc_rib": {
+                "unicast": [
+                    { "nlri": "10.1.0.0/24", "path_attributes": ["Origin(Igp)"] },
+                    { "nlri": "10.2.0.0/24", "path_attributes": ["Origin(Igp)"] },
+                    { "nlri": "10.3.0.0/24", "path_attributes": ["Origin(Igp)"] },
+                ],
+                "vrfs": {},
+            },
+            "neighbors": { "10.0.0.3": null },
+        });
+        let new = json!({
+            "timestamp": 2,
+            "loc_rib": {
+                "unicast": [
+                    { "nlri": "10.1.0.0/24", "path_attributes": ["Origin(Igp)"] },
+                    { "nlri": "10.3.0.0/24", "path_attributes": ["Origin(Egp)"] },
+                    { "nlri": "10.4.0.0/24", "path_attributes": ["Origin(Igp)"] },
+                ],
+                "vrfs": {},
+            },
+            "neighbors": { "10.0.0.3": null },
+        });
+        let table = "loc_rib/unicast".to_string();
+        let igp = vec![json!(["Origin(Igp)"])];
+        assert_eq!(
+            diff_rib_dumps(&old, &new),
+            vec![
+                RibChange::Removed {
+                    table: table.clone(),
+                    nlri: "10.2.0.0/24".to_string(),
+                    path_attributes: igp.clone(),
+                },
+                RibChange::Changed {
+                    table: table.clone(),
+                    nlri: "10.3.0.0/24".to_string(),
+                    old: igp.clone(),
+                    new: vec![json!(["Origin(Egp)"])],
+                },
+                RibChange::Added {
+                    table,
+                    nlri: "10.4.0.0/24".to_string(),
+                    path_attributes: igp,
+                },
+            ]
+        );
+        assert!(diff_rib_dumps(&old, &old).is_empty());
+    }
+}
@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::packets::notification::NotificationMessage;
+
 #[derive(Error, Debug)]
 #[error(transparent)]
 pub struct ConfigParseError {
@@ -14,6 +16,23 @@ pub struct ConvertBytesToBgpMessageError {
     source: anyhow::Error,
 }
 
+impl ConvertBytesToBgpMessageError {
+    /// 解釈できなかった理由に応じて、Peerに送るNOTIFICATIONを付ける。
+    pub fn with_notification(self, notification: NotificationMessage) -> Self {
+        Self::from(
+            anyhow::Error::from(self)
+                .context(MalformedMessageError(notification)),
+        )
+    }
+
+    /// with_notificationで付けたNOTIFICATIONを返す。
+    pub fn notification(&self) -> Option<&NotificationMessage> {
+        self.source
+            .downcast_ref::<MalformedMessageError>()
+            .map(|e| &e.0)
+    }
+}
+
 #[derive(Error, Debug)]
 #[error(transparent)]
 pub struct ConvertBgpMessageToBytesError {
@@ -34,3 +53,20 @@ pub struct ConstructIpv4NetworkError {
     #[from]
     source: anyhow::Error,
 }
+
+#[derive(Error, Debug)]
+#[error(transparent)]
+pub struct ConstructIpv6NetworkError {
+    #[from]
+    source: anyhow::Error,
+}
+
+/// 受信したMessageを解釈できなかったことを表すエラーです。
+/// Peerに送るNOTIFICATIONを持つ。
+#[derive(Error, Debug)]
+#[error(
+    "received malformed message, error_code={}, error_subcode={}",
+    .0.error_code,
+    .0.error_subcode
+)]
+pub struct MalformedMessageError(pub NotificationMessage);
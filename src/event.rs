@@ -1,5 +1,7 @@
 use crate::packets::{
-    keepalive::KeepaliveMessage, open::OpenMessage, update::UpdateMessage,
+    keepalive::KeepaliveMessage, notification::NotificationMessage,
+    open::OpenMessage, route_refresh::RouteRefreshMessage,
+    update::UpdateMessage,
 };
 
 /// BGPのRFC内 8.1
@@ -8,14 +10,48 @@ use crate::packets::{
 #[derive(PartialEq, Eq, Debug, Clone, Hash)]
 pub enum Event {
     ManualStart,
+    // Peer::stopでセッションを閉じるように指示されたことを表す。
+    ManualStop,
+    // セッションが閉じた後などに、管理者の指示無しに接続を試みる(やめる)ことを表す。
+    // passiveの場合はPeerからの接続を待ち直す。
+    AutomaticStart,
+    AutomaticStop,
     // 正常系しか実装しない本実装では別のEventとして扱う意味がないため、
     // TcpConnectionConfirmedはTcpCrAckedも兼ねている。
     TcpConnectionConfirmed,
+    // TCP Connectionの確立か、確立していたTCP Connectionでの送信に
+    // 失敗したことを表す。
+    TcpConnectionFails,
     BgpOpen(OpenMessage),
     // MsgはMessageの省略形。BGPのRFC内での定義に従っている。
     KeepAliveMsg(KeepaliveMessage),
     // BGPのRFC内での定義に従っている。
     UpdateMsg(UpdateMessage),
+    NotifMsg(NotificationMessage),
+    // 受信したMessageを解釈できなかったことを表す。RFC4271のBGPHeaderErr,
+    // BGPOpenMsgErr, UpdateMsgErrをまとめたもので、Peerに送るNOTIFICATIONを持つ。
+    MessageErr(NotificationMessage),
+    // ROUTE-REFRESH Messageを受信したことを表す。(RFC2918)
+    RouteRefreshMsg(RouteRefreshMessage),
+    // Hold Timer, Keepalive Timerが満了したことを表す。
+    HoldTimerExpires,
+    KeepaliveTimerExpires,
+    // TCPの接続に失敗した後、接続をやり直す時刻になったことを表す。
+    ConnectRetryTimerExpires,
+    // TCPの接続が確立した後、PeerのOPEN Messageを待つ時間が経ったことを表す。
+    DelayOpenTimerExpires,
+    // セッションをリセットした後、再び接続を試みる時刻になったことを表す。
+    IdleHoldTimerExpires,
+    // LLGRで保持していたルートのstale timeが満了したことを表す。(RFC9494)
+    LlgrStaleTimerExpires,
+    // BFDのセッションがUpからDownになったことを表す。(RFC5882)
+    BfdSessionDown,
+    // 管理者がmaintenance modeを開始, 終了したことと、maintenance modeで
+    // 広報を変更してからセッションを閉じるまでの時間が経ったことを表す。
+    // 本実装オリジナルのイベント。
+    MaintenanceStart,
+    MaintenanceEnd,
+    MaintenanceTimerExpires,
     // StateがEstablishedに遷移したことを表す。
     // 存在するほうが実装が楽なので追加した本実装オリジナルのイベント
     Established,
@@ -24,4 +60,54 @@ pub enum Event {
     LocRibChanged,
     AdjRibOutChanged,
     AdjRibInChanged,
+    // 管理者がclear bgp neighborでセッションのリセットを指示したことを表す。
+    // 本実装オリジナルのイベント。
+    AdminReset(ResetKind),
+}
+
+impl Event {
+    /// セッションを閉じたり維持したりするためのイベントか。
+    /// これらはキューに溜まったRibの更新のイベントより先に処理する。
+    pub fn is_high_priority(&self) -> bool {
+        matches!(
+            self,
+            Event::AdminReset(_)
+                | Event::ManualStop
+                | Event::AutomaticStop
+                | Event::NotifMsg(_)
+                | Event::MessageErr(_)
+                | Event::TcpConnectionFails
+                | Event::HoldTimerExpires
+                | Event::KeepaliveTimerExpires
+                | Event::LlgrStaleTimerExpires
+                | Event::BfdSessionDown
+                | Event::MaintenanceStart
+                | Event::MaintenanceEnd
+                | Event::MaintenanceTimerExpires
+        )
+    }
+
+    /// LocRib, AdjRibIn, AdjRibOutが変わったことを表すイベントか。
+    /// これらは処理する時点のRibの内容を見るので、何度起きても1回処理すればよい。
+    pub fn is_rib_changed(&self) -> bool {
+        matches!(
+            self,
+            Event::LocRibChanged
+                | Event::AdjRibInChanged
+                | Event::AdjRibOutChanged
+        )
+    }
+}
+
+/// clear bgp neighborで指示されるリセットの種類です。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum ResetKind {
+    // Cease NOTIFICATIONを送ってセッションを張り直す。
+    Hard,
+    // PeerにROUTE-REFRESHを送り、受信したルートをLocRibへ反映し直す。
+    SoftIn,
+    // AdjRibOutを作り直し、全てのルートをPeerへ送り直す。
+    SoftOut,
+    // SoftInとSoftOutの両方。
+    Soft,
 }
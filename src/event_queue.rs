@@ -1,19 +1,168 @@
 use crate::event::Event;
-use std::collections::VecDeque;
+use std::collections::{HashSet, VecDeque};
+use std::mem::{self, Discriminant};
+use tracing::warn;
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
-pub struct EventQueue(VecDeque<Event>);
+// 優先度が通常のイベントの数がこれに達すると、Peerからの受信を止める。
+const CAPACITY: usize = 10_000;
+
+/// Peerのイベントのキューです。
+/// セッションの状態に関わるイベントは、溜まっているRibの更新などの
+/// イベントより先に処理するため、別のキューに入れて先に取り出す。
+///
+/// Ribが変わったことを表すイベントは、同じイベントが既にキューにあれば
+/// 1つにまとめる。それ以外のイベントは捨てると受信したUPDATEなどが失われるので、
+/// 捨てずに溜める。代わりに通常のキューがCAPACITYに達している間は、
+/// PeerはTCP Connectionからメッセージを読まず、Peerに送信を待たせる。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventQueue {
+    high_priority: VecDeque<Event>,
+    normal: VecDeque<Event>,
+    // 通常のキューに入っている、Ribが変わったことを表すイベントの種類。
+    pending_rib_changes: HashSet<Discriminant<Event>>,
+    // 通常のキューがCAPACITYに達し、Peerからの受信を止めた回数。
+    throttled: u64,
+}
 
 impl EventQueue {
     pub fn new() -> Self {
-        EventQueue(VecDeque::new())
+        EventQueue {
+            high_priority: VecDeque::new(),
+            normal: VecDeque::new(),
+            pending_rib_changes: HashSet::new(),
+            throttled: 0,
+        }
     }
 
     pub fn enqueue(&mut self, event: Event) {
-        self.0.push_front(event);
+        if event.is_high_priority() {
+            self.high_priority.push_front(event);
+            return;
+        }
+        if event.is_rib_changed()
+            && !self.pending_rib_changes.insert(mem::discriminant(&event))
+        {
+            return;
+        }
+        self.normal.push_front(event);
+        if self.normal.len() == CAPACITY {
+            if self.throttled == 0 {
+                warn!("event queue is full, receiving messages is paused.");
+            }
+            self.throttled += 1;
+        }
     }
 
     pub fn dequeue(&mut self) -> Option<Event> {
-        self.0.pop_back()
+        if let Some(event) = self.high_priority.pop_back() {
+            return Some(event);
+        }
+        let event = self.normal.pop_back()?;
+        if event.is_rib_changed() {
+            self.pending_rib_changes.remove(&mem::discriminant(&event));
+        }
+        Some(event)
+    }
+
+    /// キューに入っているイベントの数。
+    pub fn len(&self) -> usize {
+        self.high_priority.len() + self.normal.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 通常のキューがCAPACITYに達しているか。
+    /// 達している間は、Peerからメッセージを受信しない。
+    pub fn is_full(&self) -> bool {
+        self.normal.len() >= CAPACITY
+    }
+
+    /// 通常のキューがCAPACITYに達し、Peerからの受信を止めた回数。
+    pub fn throttled(&self) -> u64 {
+        self.throttled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::ResetKind;
+    use crate::packets::keepalive::KeepaliveMessage;
+    use crate::packets::update::UpdateMessage;
+    use std::sync::Arc;
+
+    #[test]
+    fn high_priority_events_preempt_queued_events() {
+        let mut queue = EventQueue::new();
+        for _ in 0..1000 {
+            queue.enqueue(Event::KeepAliveMsg(KeepaliveMessage::new()));
+        }
+        queue.enqueue(Event::LocRibChanged);
+        queue.enqueue(Event::HoldTimerExpires);
+        queue.enqueue(Event::AdminReset(ResetKind::Hard));
+
+        assert_eq!(queue.dequeue(), Some(Event::HoldTimerExpires));
+        assert_eq!(queue.dequeue(), Some(Event::AdminReset(ResetKind::Hard)));
+        // 同じ優先度のイベントは入れた順に取り出す。
+        assert_eq!(
+            queue.dequeue(),
+            Some(Event::KeepAliveMsg(KeepaliveMessage::new()))
+        );
+    }
+
+    #[test]
+    fn rib_changed_events_are_coalesced_and_full_queue_is_throttled() {
+        let mut queue = EventQueue::new();
+        for _ in 0..100 {
+            queue.enqueue(Event::LocRibChanged);
+            queue.enqueue(Event::AdjRibOutChanged);
+        }
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.dequeue(), Some(Event::LocRibChanged));
+        // 取り出した後は再びキューに入る。
+        queue.enqueue(Event::LocRibChanged);
+        assert_eq!(queue.len(), 2);
+
+        for _ in 0..CAPACITY {
+            queue.enqueue(Event::KeepAliveMsg(KeepaliveMessage::new()));
+        }
+        assert!(queue.is_full());
+        assert_eq!(queue.throttled(), 1);
+        queue.enqueue(Event::HoldTimerExpires);
+        assert_eq!(queue.dequeue(), Some(Event::HoldTimerExpires));
+        // Ribが変わったことを表す2つのイベントの分も取り出す。
+        for _ in 0..3 {
+            queue.dequeue();
+        }
+        assert!(!queue.is_full());
+    }
+
+    #[test]
+    fn update_messages_are_never_dropped() {
+        let mut queue = EventQueue::new();
+        let update = |n: u8| {
+            Event::UpdateMsg(UpdateMessage::new(
+                Arc::new(vec![]),
+                vec![],
+                vec![format!("10.{}.0.0/24", n).parse().unwrap()],
+            ))
+        };
+        for _ in 0..CAPACITY {
+            queue.enqueue(Event::KeepAliveMsg(KeepaliveMessage::new()));
+        }
+        assert!(queue.is_full());
+        for n in 0..3 {
+            queue.enqueue(update(n));
+        }
+        assert_eq!(queue.len(), CAPACITY + 3);
+        for _ in 0..CAPACITY {
+            queue.dequeue();
+        }
+        for n in 0..3 {
+            assert_eq!(queue.dequeue(), Some(update(n)));
+        }
+        assert!(queue.is_empty());
     }
 }
@@ -0,0 +1,572 @@
+/// BGP MPLS-Based Ethernet VPN (RFC7432, RFC9136)のEVPNルートを扱うモジュールです。
+/// EVPNのルートは収集(モニタリング)のためにだけ受信し、広報はしません。
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+use anyhow::Context;
+use bytes::{BufMut, BytesMut};
+
+use crate::error::ConvertBytesToBgpMessageError;
+use crate::path_attribute::PathAttribute;
+use crate::routing::RibKey;
+use crate::vpn::RouteDistinguisher;
+
+/// Ethernet Segment Identifier (10 octets)
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct EthernetSegmentIdentifier(pub [u8; 10]);
+
+impl fmt::Display for EthernetSegmentIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex: Vec<String> =
+            self.0.iter().map(|b| format!("{:02x}", b)).collect();
+        write!(f, "{}", hex.join(":"))
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex: Vec<String> =
+            self.0.iter().map(|b| format!("{:02x}", b)).collect();
+        write!(f, "{}", hex.join(":"))
+    }
+}
+
+/// EVPNのNLRIのラベル(3 octets)です。
+/// MPLSではラベル(上位20 bits)、VXLANではVNI(24 bits)として使われるため、
+/// 3 octetsの値をそのまま保持します。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct EvpnLabel(pub u32);
+
+impl EvpnLabel {
+    pub fn from_bytes(b: &[u8]) -> Self {
+        Self(u32::from_be_bytes([0, b[0], b[1], b[2]]))
+    }
+
+    pub fn to_bytes(self) -> [u8; 3] {
+        let b = self.0.to_be_bytes();
+        [b[1], b[2], b[3]]
+    }
+}
+
+/// EVPNのNLRI (RFC7432 Section 7, RFC9136 Section 3)
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub enum EvpnRoute {
+    // Route Type 1
+    EthernetAutoDiscovery {
+        route_distinguisher: RouteDistinguisher,
+        esi: EthernetSegmentIdentifier,
+        ethernet_tag: u32,
+        label: EvpnLabel,
+    },
+    // Route Type 2
+    MacIpAdvertisement {
+        route_distinguisher: RouteDistinguisher,
+        esi: EthernetSegmentIdentifier,
+        ethernet_tag: u32,
+        mac_address: MacAddress,
+        ip_address: Option<IpAddr>,
+        labels: Vec<EvpnLabel>,
+    },
+    // Route Type 3
+    InclusiveMulticastEthernetTag {
+        route_distinguisher: RouteDistinguisher,
+        ethernet_tag: u32,
+        originating_router: IpAddr,
+    },
+    // Route Type 4
+    EthernetSegment {
+        route_distinguisher: RouteDistinguisher,
+        esi: EthernetSegmentIdentifier,
+        originating_router: IpAddr,
+    },
+    // Route Type 5
+    IpPrefix {
+        route_distinguisher: RouteDistinguisher,
+        esi: EthernetSegmentIdentifier,
+        ethernet_tag: u32,
+        prefix: IpAddr,
+        prefix_length: u8,
+        gateway: IpAddr,
+        label: EvpnLabel,
+    },
+    // 対応していないRoute Type用
+    Unknown {
+        route_type: u8,
+        value: Vec<u8>,
+    },
+}
+
+/// IP Address Length(bits)に続くアドレスを読み込む。長さ0の場合はNone。
+fn ip_address_from_u8_slice(
+    length_bits: u8,
+    b: &[u8],
+) -> Result<Option<IpAddr>, ConvertBytesToBgpMessageError> {
+    Ok(match length_bits {
+        0 => None,
+        32 => {
+            let octets: [u8; 4] = b
+                .get(..4)
+                .context("EVPN NLRIのIPv4アドレスが不正です。")?
+                .try_into()
+                .context("EVPN NLRIのIPv4アドレスが不正です。")?;
+            Some(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        128 => {
+            let octets: [u8; 16] = b
+                .get(..16)
+                .context("EVPN NLRIのIPv6アドレスが不正です。")?
+                .try_into()
+                .context("EVPN NLRIのIPv6アドレスが不正です。")?;
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => {
+            return Err(ConvertBytesToBgpMessageError::from(anyhow::anyhow!(
+                "EVPN NLRIのIPアドレスの長さ{}には対応していません。",
+                length_bits
+            )))
+        }
+    })
+}
+
+fn put_ip_address(bytes: &mut BytesMut, addr: &IpAddr) {
+    match addr {
+        IpAddr::V4(a) => bytes.put(&a.octets()[..]),
+        IpAddr::V6(a) => bytes.put(&a.octets()[..]),
+    }
+}
+
+/// IP Address Length(bits)とアドレスを書き込む。
+fn put_ip_address_with_length(bytes: &mut BytesMut, addr: &Option<IpAddr>) {
+    match addr {
+        None => bytes.put_u8(0),
+        Some(a @ IpAddr::V4(_)) => {
+            bytes.put_u8(32);
+            put_ip_address(bytes, a);
+        }
+        Some(a @ IpAddr::V6(_)) => {
+            bytes.put_u8(128);
+            put_ip_address(bytes, a);
+        }
+    }
+}
+
+impl EvpnRoute {
+    pub fn route_type(&self) -> u8 {
+        match self {
+            EvpnRoute::EthernetAutoDiscovery { .. } => 1,
+            EvpnRoute::MacIpAdvertisement { .. } => 2,
+            EvpnRoute::InclusiveMulticastEthernetTag { .. } => 3,
+            EvpnRoute::EthernetSegment { .. } => 4,
+            EvpnRoute::IpPrefix { .. } => 5,
+            EvpnRoute::Unknown { route_type, .. } => *route_type,
+        }
+    }
+
+    /// 同じルートを表すか。withdrawと置き換えるルートの判断に使う。
+    /// ラベル, Route Type 2と5のESI, Route Type 5のGateway IP Addressは
+    /// ルートのキーに含まれないので比べない。(RFC7432 Section 7, RFC9136 Section 3.2)
+    pub fn is_same_route(&self, other: &EvpnRoute) -> bool {
+        self.route_key() == other.route_key()
+    }
+
+    /// ルートのキーに含まれないフィールドを0にしたルートを返す。
+    fn route_key(&self) -> EvpnRoute {
+        let mut key = self.clone();
+        match &mut key {
+            EvpnRoute::EthernetAutoDiscovery { label, .. } => {
+                *label = EvpnLabel(0)
+            }
+            EvpnRoute::MacIpAdvertisement { esi, labels, .. } => {
+                *esi = EthernetSegmentIdentifier([0; 10]);
+                labels.clear();
+            }
+            EvpnRoute::IpPrefix {
+                esi,
+                gateway,
+                label,
+                ..
+            } => {
+                *esi = EthernetSegmentIdentifier([0; 10]);
+                *gateway = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+                *label = EvpnLabel(0);
+            }
+            _ => {}
+        }
+        key
+    }
+
+    pub fn bytes_len(&self) -> usize {
+        // Route Type(1) + Length(1) + Route Type毎の値
+        2 + self.value_bytes().len()
+    }
+
+    /// Route TypeとLengthに続くRoute Type毎の値のbytes表現を返す。
+    fn value_bytes(&self) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        match self {
+            EvpnRoute::EthernetAutoDiscovery {
+                route_distinguisher,
+                esi,
+                ethernet_tag,
+                label,
+            } => {
+                bytes.put::<BytesMut>(route_distinguisher.into());
+                bytes.put(&esi.0[..]);
+                bytes.put_u32(*ethernet_tag);
+                bytes.put(&label.to_bytes()[..]);
+            }
+            EvpnRoute::MacIpAdvertisement {
+                route_distinguisher,
+                esi,
+                ethernet_tag,
+                mac_address,
+                ip_address,
+                labels,
+            } => {
+                bytes.put::<BytesMut>(route_distinguisher.into());
+                bytes.put(&esi.0[..]);
+                bytes.put_u32(*ethernet_tag);
+                bytes.put_u8(48);
+                bytes.put(&mac_address.0[..]);
+                put_ip_address_with_length(&mut bytes, ip_address);
+                labels.iter().for_each(|l| bytes.put(&l.to_bytes()[..]));
+            }
+            EvpnRoute::InclusiveMulticastEthernetTag {
+                route_distinguisher,
+                ethernet_tag,
+                originating_router,
+            } => {
+                bytes.put::<BytesMut>(route_distinguisher.into());
+                bytes.put_u32(*ethernet_tag);
+                put_ip_address_with_length(
+                    &mut bytes,
+                    &Some(*originating_router),
+                );
+            }
+            EvpnRoute::EthernetSegment {
+                route_distinguisher,
+                esi,
+                originating_router,
+            } => {
+                bytes.put::<BytesMut>(route_distinguisher.into());
+                bytes.put(&esi.0[..]);
+                put_ip_address_with_length(
+                    &mut bytes,
+                    &Some(*originating_router),
+                );
+            }
+            EvpnRoute::IpPrefix {
+                route_distinguisher,
+                esi,
+                ethernet_tag,
+                prefix,
+                prefix_length,
+                gateway,
+                label,
+            } => {
+                bytes.put::<BytesMut>(route_distinguisher.into());
+                bytes.put(&esi.0[..]);
+                bytes.put_u32(*ethernet_tag);
+                bytes.put_u8(*prefix_length);
+                put_ip_address(&mut bytes, prefix);
+                put_ip_address(&mut bytes, gateway);
+                bytes.put(&label.to_bytes()[..]);
+            }
+            EvpnRoute::Unknown { value, .. } => bytes.put(&value[..]),
+        }
+        bytes
+    }
+
+    pub fn from_u8_slice(
+        bytes: &[u8],
+    ) -> Result<Vec<Self>, ConvertBytesToBgpMessageError> {
+        let mut routes = vec![];
+        let mut i = 0;
+        while bytes.len() > i {
+            if bytes.len() < i + 2 {
+                return Err(ConvertBytesToBgpMessageError::from(
+                    anyhow::anyhow!("EVPN NLRIの長さが足りません。"),
+                ));
+            }
+            let route_type = bytes[i];
+            let length = bytes[i + 1] as usize;
+            let start = i + 2;
+            let end = start + length;
+            if bytes.len() < end {
+                return Err(ConvertBytesToBgpMessageError::from(
+                    anyhow::anyhow!("EVPN NLRIの長さが足りません。"),
+                ));
+            }
+            routes.push(Self::from_type_and_value(
+                route_type,
+                &bytes[start..end],
+            )?);
+            i = end;
+        }
+        Ok(routes)
+    }
+
+    fn from_type_and_value(
+        route_type: u8,
+        b: &[u8],
+    ) -> Result<Self, ConvertBytesToBgpMessageError> {
+        let too_short = || {
+            ConvertBytesToBgpMessageError::from(anyhow::anyhow!(
+                "Route Type {}のEVPN NLRIの長さ{}が足りません。",
+                route_type,
+                b.len()
+            ))
+        };
+        let esi = |from: usize| -> Result<_, ConvertBytesToBgpMessageError> {
+            let esi: [u8; 10] = b
+                .get(from..from + 10)
+                .ok_or_else(too_short)?
+                .try_into()
+                .context("ESIを取得できませんでした。")?;
+            Ok(EthernetSegmentIdentifier(esi))
+        };
+        let u32_at =
+            |from: usize| -> Result<_, ConvertBytesToBgpMessageError> {
+                let v: [u8; 4] = b
+                    .get(from..from + 4)
+                    .ok_or_else(too_short)?
+                    .try_into()
+                    .context("Ethernet Tag IDを取得できませんでした。")?;
+                Ok(u32::from_be_bytes(v))
+            };
+        let label = |from: usize| -> Result<_, ConvertBytesToBgpMessageError> {
+            Ok(EvpnLabel::from_bytes(
+                b.get(from..from + 3).ok_or_else(too_short)?,
+            ))
+        };
+        if (1..=5).contains(&route_type) && b.len() < 8 {
+            return Err(too_short());
+        }
+        let route_distinguisher = || RouteDistinguisher::try_from(&b[0..8]);
+
+        Ok(match route_type {
+            1 => EvpnRoute::EthernetAutoDiscovery {
+                route_distinguisher: route_distinguisher()?,
+                esi: esi(8)?,
+                ethernet_tag: u32_at(18)?,
+                label: label(22)?,
+            },
+            2 => {
+                // RD(8) + ESI(10) + Ethernet Tag(4) + MAC Length(1) + MAC(6)
+                let mac: [u8; 6] = b
+                    .get(23..29)
+                    .ok_or_else(too_short)?
+                    .try_into()
+                    .context("MACアドレスを取得できませんでした。")?;
+                let ip_length = *b.get(29).ok_or_else(too_short)?;
+                let ip_address =
+                    ip_address_from_u8_slice(ip_length, &b[30..])?;
+                let mut i = 30 + ip_length as usize / 8;
+                let mut labels = vec![label(i)?];
+                i += 3;
+                // MPLS Label2はoptional
+                if b.len() >= i + 3 {
+                    labels.push(label(i)?);
+                }
+                EvpnRoute::MacIpAdvertisement {
+                    route_distinguisher: route_distinguisher()?,
+                    esi: esi(8)?,
+                    ethernet_tag: u32_at(18)?,
+                    mac_address: MacAddress(mac),
+                    ip_address,
+                    labels,
+                }
+            }
+            3 => {
+                let ip_length = *b.get(12).ok_or_else(too_short)?;
+                EvpnRoute::InclusiveMulticastEthernetTag {
+                    route_distinguisher: route_distinguisher()?,
+                    ethernet_tag: u32_at(8)?,
+                    originating_router: ip_address_from_u8_slice(
+                        ip_length,
+                        &b[13..],
+                    )?
+                    .ok_or_else(too_short)?,
+                }
+            }
+            4 => {
+                let ip_length = *b.get(18).ok_or_else(too_short)?;
+                EvpnRoute::EthernetSegment {
+                    route_distinguisher: route_distinguisher()?,
+                    esi: esi(8)?,
+                    originating_router: ip_address_from_u8_slice(
+                        ip_length,
+                        &b[19..],
+                    )?
+                    .ok_or_else(too_short)?,
+                }
+            }
+            5 => {
+                // NLRIの長さがIPv4なら34 octets, IPv6なら58 octets。
+                let ip_length = match b.len() {
+                    34 => 32,
+                    58 => 128,
+                    _ => return Err(too_short()),
+                };
+                let address_length = ip_length as usize / 8;
+                let prefix = ip_address_from_u8_slice(ip_length, &b[23..])?
+                    .ok_or_else(too_short)?;
+                let gateway = ip_address_from_u8_slice(
+                    ip_length,
+                    &b[23 + address_length..],
+                )?
+                .ok_or_else(too_short)?;
+                EvpnRoute::IpPrefix {
+                    route_distinguisher: route_distinguisher()?,
+                    esi: esi(8)?,
+                    ethernet_tag: u32_at(18)?,
+                    prefix_length: b[22],
+                    prefix,
+                    gateway,
+                    label: label(23 + 2 * address_length)?,
+                }
+            }
+            _ => EvpnRoute::Unknown {
+                route_type,
+                value: b.to_vec(),
+            },
+        })
+    }
+}
+
+impl From<&EvpnRoute> for BytesMut {
+    fn from(route: &EvpnRoute) -> BytesMut {
+        let value = route.value_bytes();
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(route.route_type());
+        bytes.put_u8(value.len() as u8);
+        bytes.put(value);
+        bytes
+    }
+}
+
+impl fmt::Display for EvpnRoute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvpnRoute::EthernetAutoDiscovery {
+                route_distinguisher,
+                esi,
+                ethernet_tag,
+                ..
+            } => write!(
+                f,
+                "[1][rd:{}][esi:{}][etag:{}]",
+                route_distinguisher, esi, ethernet_tag
+            ),
+            EvpnRoute::MacIpAdvertisement {
+                route_distinguisher,
+                ethernet_tag,
+                mac_address,
+                ip_address,
+                ..
+            } => {
+                write!(
+                    f,
+                    "[2][rd:{}][etag:{}][mac:{}]",
+                    route_distinguisher, ethernet_tag, mac_address
+                )?;
+                if let Some(ip) = ip_address {
+                    write!(f, "[ip:{}]", ip)?;
+                }
+                Ok(())
+            }
+            EvpnRoute::InclusiveMulticastEthernetTag {
+                route_distinguisher,
+                ethernet_tag,
+                originating_router,
+            } => write!(
+                f,
+                "[3][rd:{}][etag:{}][orig:{}]",
+                route_distinguisher, ethernet_tag, originating_router
+            ),
+            EvpnRoute::EthernetSegment {
+                route_distinguisher,
+                esi,
+                originating_router,
+            } => write!(
+                f,
+                "[4][rd:{}][esi:{}][orig:{}]",
+                route_distinguisher, esi, originating_router
+            ),
+            EvpnRoute::IpPrefix {
+                route_distinguisher,
+                ethernet_tag,
+                prefix,
+                prefix_length,
+                ..
+            } => write!(
+                f,
+                "[5][rd:{}][etag:{}][prefix:{}/{}]",
+                route_distinguisher, ethernet_tag, prefix, prefix_length
+            ),
+            EvpnRoute::Unknown { route_type, .. } => {
+                write!(f, "[{}]", route_type)
+            }
+        }
+    }
+}
+
+/// EVPNルートを保持するRibのエントリです。
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+pub struct EvpnRibEntry {
+    pub route: EvpnRoute,
+    pub path_attributes: Arc<Vec<PathAttribute>>,
+}
+
+impl RibKey for EvpnRibEntry {
+    type Key = EvpnRoute;
+
+    fn key(&self) -> &EvpnRoute {
+        &self.route
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_bytes_to_evpn_routes_and_evpn_routes_to_bytes() {
+        let routes = vec![
+            EvpnRoute::MacIpAdvertisement {
+                route_distinguisher: "10.0.0.1:100".parse().unwrap(),
+                esi: EthernetSegmentIdentifier([0; 10]),
+                ethernet_tag: 0,
+                mac_address: MacAddress([0x02, 0, 0, 0, 0, 0x01]),
+                ip_address: Some("10.1.0.1".parse().unwrap()),
+                labels: vec![EvpnLabel(10100)],
+            },
+            EvpnRoute::InclusiveMulticastEthernetTag {
+                route_distinguisher: "10.0.0.1:100".parse().unwrap(),
+                ethernet_tag: 0,
+                originating_router: "10.0.0.1".parse().unwrap(),
+            },
+            EvpnRoute::IpPrefix {
+                route_distinguisher: "64512:1".parse().unwrap(),
+                esi: EthernetSegmentIdentifier([0; 10]),
+                ethernet_tag: 0,
+                prefix: "fd00:100::".parse().unwrap(),
+                prefix_length: 64,
+                gateway: "::".parse().unwrap(),
+                label: EvpnLabel(10200),
+            },
+        ];
+        let mut bytes = BytesMut::new();
+        routes.iter().for_each(|r| bytes.put::<BytesMut>(r.into()));
+        assert_eq!(
+            bytes.len(),
+            routes.iter().map(|r| r.bytes_len()).sum::<usize>()
+        );
+
+        assert_eq!(EvpnRoute::from_u8_slice(&bytes[..]).unwrap(), routes);
+    }
+}
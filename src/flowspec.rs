@@ -0,0 +1,889 @@
+/// Dissemination of Flow Specification Rules (RFC8955)のIPv4 FlowSpecを
+/// 扱うモジュールです。FlowSpecのNLRIと、受信したルールを
+/// パケットフィルタに反映するための仕組みを定義します。
+use std::collections::HashMap;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use anyhow::{Context, Result};
+use bytes::{BufMut, BytesMut};
+use tracing::{info, warn};
+
+use crate::error::{ConfigParseError, ConvertBytesToBgpMessageError};
+use crate::path_attribute::{ExtendedCommunity, PathAttribute};
+use crate::routing::{Ipv4Network, RibKey};
+
+/// 数値の比較を表すoperator (RFC8955 Section 4.2.1.1)
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct NumericOperator {
+    // 直前の条件とANDで結合するならtrue, ORならfalse
+    pub and: bool,
+    pub lt: bool,
+    pub gt: bool,
+    pub eq: bool,
+    pub value: u64,
+}
+
+impl NumericOperator {
+    pub fn equal(value: u64) -> Self {
+        Self {
+            and: false,
+            lt: false,
+            gt: false,
+            eq: true,
+            value,
+        }
+    }
+}
+
+/// bitの一致を表すoperator (RFC8955 Section 4.2.1.2)
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct BitmaskOperator {
+    pub and: bool,
+    pub not: bool,
+    // trueならvalueのbitがすべて立っていること、falseならいずれかが立っていること
+    pub match_: bool,
+    pub value: u64,
+}
+
+/// FlowSpecのNLRIを構成するcomponentです。(RFC8955 Section 4.2.2)
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub enum FlowSpecComponent {
+    DestinationPrefix(Ipv4Network),
+    SourcePrefix(Ipv4Network),
+    IpProtocol(Vec<NumericOperator>),
+    Port(Vec<NumericOperator>),
+    DestinationPort(Vec<NumericOperator>),
+    SourcePort(Vec<NumericOperator>),
+    IcmpType(Vec<NumericOperator>),
+    IcmpCode(Vec<NumericOperator>),
+    TcpFlags(Vec<BitmaskOperator>),
+    PacketLength(Vec<NumericOperator>),
+    Dscp(Vec<NumericOperator>),
+    Fragment(Vec<BitmaskOperator>),
+}
+
+impl FlowSpecComponent {
+    fn type_(&self) -> u8 {
+        match self {
+            FlowSpecComponent::DestinationPrefix(_) => 1,
+            FlowSpecComponent::SourcePrefix(_) => 2,
+            FlowSpecComponent::IpProtocol(_) => 3,
+            FlowSpecComponent::Port(_) => 4,
+            FlowSpecComponent::DestinationPort(_) => 5,
+            FlowSpecComponent::SourcePort(_) => 6,
+            FlowSpecComponent::IcmpType(_) => 7,
+            FlowSpecComponent::IcmpCode(_) => 8,
+            FlowSpecComponent::TcpFlags(_) => 9,
+            FlowSpecComponent::PacketLength(_) => 10,
+            FlowSpecComponent::Dscp(_) => 11,
+            FlowSpecComponent::Fragment(_) => 12,
+        }
+    }
+
+    fn numeric_operators(&self) -> Option<&Vec<NumericOperator>> {
+        match self {
+            FlowSpecComponent::IpProtocol(o)
+            | FlowSpecComponent::Port(o)
+            | FlowSpecComponent::DestinationPort(o)
+            | FlowSpecComponent::SourcePort(o)
+            | FlowSpecComponent::IcmpType(o)
+            | FlowSpecComponent::IcmpCode(o)
+            | FlowSpecComponent::PacketLength(o)
+            | FlowSpecComponent::Dscp(o) => Some(o),
+            _ => None,
+        }
+    }
+}
+
+/// operatorのvalueの長さ(octets)。operatorのlen bitsには
+/// 1, 2, 4, 8 octetsのいずれかを2の冪の指数として書き込む。
+fn value_length(value: u64) -> (u8, usize) {
+    if value <= u8::MAX as u64 {
+        (0, 1)
+    } else if value <= u16::MAX as u64 {
+        (1, 2)
+    } else if value <= u32::MAX as u64 {
+        (2, 4)
+    } else {
+        (3, 8)
+    }
+}
+
+/// operator毎の(and, 下位3 bits, value)。numericとbitmaskで下位3 bitsの意味が異なる。
+type RawOperators = Vec<(bool, u8, u64)>;
+
+/// operatorのリストを書き込む。
+fn put_operators(bytes: &mut BytesMut, operators: &RawOperators) {
+    for (i, (and, flags, value)) in operators.iter().enumerate() {
+        let end_of_list = i == operators.len() - 1;
+        let (length_bits, length) = value_length(*value);
+        let operator = (end_of_list as u8) << 7
+            | (*and as u8) << 6
+            | length_bits << 4
+            | flags;
+        bytes.put_u8(operator);
+        bytes.put(&value.to_be_bytes()[8 - length..]);
+    }
+}
+
+/// operatorのリストを読み込む。戻り値はoperatorのリストと、読み込んだoctet数。
+fn operators_from_u8_slice(
+    bytes: &[u8],
+) -> Result<(RawOperators, usize), ConvertBytesToBgpMessageError> {
+    let mut operators = vec![];
+    let mut i = 0;
+    loop {
+        let operator = *bytes.get(i).context(
+            "FlowSpecのoperatorを読み込む前にbytesが終了しました。",
+        )?;
+        let length = 1 << ((operator >> 4) & 0b11);
+        if bytes.len() < i + 1 + length {
+            return Err(ConvertBytesToBgpMessageError::from(anyhow::anyhow!(
+                "FlowSpecのoperatorのvalueの長さが足りません。"
+            )));
+        }
+        let mut value = [0u8; 8];
+        value[8 - length..].copy_from_slice(&bytes[i + 1..i + 1 + length]);
+        operators.push((
+            operator & 0b01000000 != 0,
+            operator & 0b00000111,
+            u64::from_be_bytes(value),
+        ));
+        i += 1 + length;
+        if operator & 0b10000000 != 0 {
+            break;
+        }
+    }
+    Ok((operators, i))
+}
+
+impl From<&FlowSpecComponent> for BytesMut {
+    fn from(component: &FlowSpecComponent) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(component.type_());
+        match component {
+            FlowSpecComponent::DestinationPrefix(p)
+            | FlowSpecComponent::SourcePrefix(p) => {
+                bytes.put::<BytesMut>(p.into());
+            }
+            FlowSpecComponent::TcpFlags(o)
+            | FlowSpecComponent::Fragment(o) => {
+                let operators: Vec<_> = o
+                    .iter()
+                    .map(|o| {
+                        (o.and, (o.not as u8) << 1 | o.match_ as u8, o.value)
+                    })
+                    .collect();
+                put_operators(&mut bytes, &operators);
+            }
+            c => {
+                let operators: Vec<_> = c
+                    .numeric_operators()
+                    .expect("prefix, bitmask以外のcomponentはnumeric")
+                    .iter()
+                    .map(|o| {
+                        (
+                            o.and,
+                            (o.lt as u8) << 2 | (o.gt as u8) << 1 | o.eq as u8,
+                            o.value,
+                        )
+                    })
+                    .collect();
+                put_operators(&mut bytes, &operators);
+            }
+        }
+        bytes
+    }
+}
+
+/// FlowSpecのルール(1つのNLRI)です。componentはtypeの昇順に並んでいる必要があります。
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub struct FlowSpecRule(pub Vec<FlowSpecComponent>);
+
+impl FlowSpecRule {
+    fn components_bytes(&self) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        self.0.iter().for_each(|c| bytes.put::<BytesMut>(c.into()));
+        bytes
+    }
+
+    pub fn bytes_len(&self) -> usize {
+        let length = self.components_bytes().len();
+        // 240 octets以上の場合はlengthが2 octetsになる。
+        if length < 240 {
+            1 + length
+        } else {
+            2 + length
+        }
+    }
+
+    pub fn from_u8_slice(
+        bytes: &[u8],
+    ) -> Result<Vec<Self>, ConvertBytesToBgpMessageError> {
+        let mut rules = vec![];
+        let mut i = 0;
+        while bytes.len() > i {
+            let (length, length_octets) = if bytes[i] & 0xf0 == 0xf0 {
+                let b = bytes
+                    .get(i + 1)
+                    .context("FlowSpec NLRIのlengthが不正です。")?;
+                ((((bytes[i] & 0x0f) as usize) << 8) | *b as usize, 2)
+            } else {
+                (bytes[i] as usize, 1)
+            };
+            let start = i + length_octets;
+            let end = start + length;
+            if bytes.len() < end {
+                return Err(ConvertBytesToBgpMessageError::from(
+                    anyhow::anyhow!("FlowSpec NLRIの長さが足りません。"),
+                ));
+            }
+            rules.push(Self::components_from_u8_slice(&bytes[start..end])?);
+            i = end;
+        }
+        Ok(rules)
+    }
+
+    fn components_from_u8_slice(
+        bytes: &[u8],
+    ) -> Result<Self, ConvertBytesToBgpMessageError> {
+        let mut components = vec![];
+        let mut i = 0;
+        while bytes.len() > i {
+            let type_ = bytes[i];
+            i += 1;
+            let component = match type_ {
+                1 | 2 => {
+                    let prefix_length = *bytes
+                        .get(i)
+                        .context("FlowSpecのprefixが不正です。")?;
+                    let length = 1 + (prefix_length as usize).div_ceil(8);
+                    if bytes.len() < i + length {
+                        return Err(ConvertBytesToBgpMessageError::from(
+                            anyhow::anyhow!("FlowSpecのprefixが不正です。"),
+                        ));
+                    }
+                    let prefix =
+                        Ipv4Network::from_u8_slice(&bytes[i..i + length])?
+                            .pop()
+                            .context("FlowSpecのprefixが不正です。")?;
+                    i += length;
+                    if type_ == 1 {
+                        FlowSpecComponent::DestinationPrefix(prefix)
+                    } else {
+                        FlowSpecComponent::SourcePrefix(prefix)
+                    }
+                }
+                9 | 12 => {
+                    let (operators, length) =
+                        operators_from_u8_slice(&bytes[i..])?;
+                    i += length;
+                    let operators = operators
+                        .into_iter()
+                        .map(|(and, flags, value)| BitmaskOperator {
+                            and,
+                            not: flags & 0b10 != 0,
+                            match_: flags & 0b01 != 0,
+                            value,
+                        })
+                        .collect();
+                    if type_ == 9 {
+                        FlowSpecComponent::TcpFlags(operators)
+                    } else {
+                        FlowSpecComponent::Fragment(operators)
+                    }
+                }
+                3..=11 => {
+                    let (operators, length) =
+                        operators_from_u8_slice(&bytes[i..])?;
+                    i += length;
+                    let operators = operators
+                        .into_iter()
+                        .map(|(and, flags, value)| NumericOperator {
+                            and,
+                            lt: flags & 0b100 != 0,
+                            gt: flags & 0b010 != 0,
+                            eq: flags & 0b001 != 0,
+                            value,
+                        })
+                        .collect();
+                    match type_ {
+                        3 => FlowSpecComponent::IpProtocol(operators),
+                        4 => FlowSpecComponent::Port(operators),
+                        5 => FlowSpecComponent::DestinationPort(operators),
+                        6 => FlowSpecComponent::SourcePort(operators),
+                        7 => FlowSpecComponent::IcmpType(operators),
+                        8 => FlowSpecComponent::IcmpCode(operators),
+                        10 => FlowSpecComponent::PacketLength(operators),
+                        _ => FlowSpecComponent::Dscp(operators),
+                    }
+                }
+                _ => {
+                    return Err(ConvertBytesToBgpMessageError::from(
+                        anyhow::anyhow!(
+                        "FlowSpecのcomponent type {}には対応していません。",
+                        type_
+                    ),
+                    ))
+                }
+            };
+            components.push(component);
+        }
+        Ok(Self(components))
+    }
+}
+
+impl From<&FlowSpecRule> for BytesMut {
+    fn from(rule: &FlowSpecRule) -> BytesMut {
+        let components = rule.components_bytes();
+        let mut bytes = BytesMut::new();
+        if components.len() < 240 {
+            bytes.put_u8(components.len() as u8);
+        } else {
+            bytes.put_u16(0xf000 | components.len() as u16);
+        }
+        bytes.put(components);
+        bytes
+    }
+}
+
+impl FromStr for FlowSpecRule {
+    type Err = ConfigParseError;
+
+    /// `dst:203.0.113.0/24,proto:6,dport:80|443,len:1000-1500`の形式からparseする。
+    /// 数値はN, または範囲N-Mで指定し、`|`で区切るといずれかに一致する条件になる。
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut components = vec![];
+        for part in s.split(',') {
+            let (key, value) = part.split_once(':').context(format!(
+                "cannot parse `{0}` as flowspec component in `{1}`",
+                part, s
+            ))?;
+            let numeric = || -> Result<Vec<NumericOperator>> {
+                let mut operators = vec![];
+                for v in value.split('|') {
+                    if let Some((from, to)) = v.split_once('-') {
+                        operators.push(NumericOperator {
+                            and: false,
+                            lt: false,
+                            gt: true,
+                            eq: true,
+                            value: from.parse()?,
+                        });
+                        operators.push(NumericOperator {
+                            and: true,
+                            lt: true,
+                            gt: false,
+                            eq: true,
+                            value: to.parse()?,
+                        });
+                    } else {
+                        operators.push(NumericOperator::equal(v.parse()?));
+                    }
+                }
+                Ok(operators)
+            };
+            let context =
+                format!("cannot parse flowspec component `{0}`", part);
+            let component = match key {
+                "dst" => FlowSpecComponent::DestinationPrefix(
+                    value.parse().context(context)?,
+                ),
+                "src" => FlowSpecComponent::SourcePrefix(
+                    value.parse().context(context)?,
+                ),
+                "proto" => {
+                    FlowSpecComponent::IpProtocol(numeric().context(context)?)
+                }
+                "port" => FlowSpecComponent::Port(numeric().context(context)?),
+                "dport" => FlowSpecComponent::DestinationPort(
+                    numeric().context(context)?,
+                ),
+                "sport" => {
+                    FlowSpecComponent::SourcePort(numeric().context(context)?)
+                }
+                "icmp-type" => {
+                    FlowSpecComponent::IcmpType(numeric().context(context)?)
+                }
+                "icmp-code" => {
+                    FlowSpecComponent::IcmpCode(numeric().context(context)?)
+                }
+                "len" => FlowSpecComponent::PacketLength(
+                    numeric().context(context)?,
+                ),
+                "dscp" => FlowSpecComponent::Dscp(numeric().context(context)?),
+                _ => {
+                    return Err(ConfigParseError::from(anyhow::anyhow!(
+                        "unknown flowspec component `{0}` in `{1}`",
+                        key,
+                        s
+                    )))
+                }
+            };
+            components.push(component);
+        }
+        components.sort_by_key(|c| c.type_());
+        Ok(Self(components))
+    }
+}
+
+impl fmt::Display for FlowSpecRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match to_nftables_match(self) {
+            Some(m) => write!(f, "{}", m),
+            None => write!(f, "{:?}", self.0),
+        }
+    }
+}
+
+/// FlowSpecのルールに対するtraffic filtering action (RFC8955 Section 7)です。
+/// Extended Communityとして運ばれます。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum FlowSpecAction {
+    // bytes/秒。0の場合はdiscard。
+    TrafficRate(u32),
+    TrafficMarking(u8),
+}
+
+impl FlowSpecAction {
+    pub fn from_extended_community(c: &ExtendedCommunity) -> Option<Self> {
+        match c {
+            ExtendedCommunity::TrafficRate { rate, .. } => {
+                Some(FlowSpecAction::TrafficRate(*rate))
+            }
+            ExtendedCommunity::TrafficMarking(dscp) => {
+                Some(FlowSpecAction::TrafficMarking(*dscp))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Configで設定する、originateするFlowSpecのルールとactionです。
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub struct FlowSpecRoute {
+    pub rule: FlowSpecRule,
+    pub actions: Vec<ExtendedCommunity>,
+}
+
+/// FlowSpecのルールを保持するRibのエントリです。
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+pub struct FlowSpecRibEntry {
+    pub rule: FlowSpecRule,
+    pub path_attributes: Arc<Vec<PathAttribute>>,
+}
+
+impl RibKey for FlowSpecRibEntry {
+    type Key = FlowSpecRule;
+
+    fn key(&self) -> &FlowSpecRule {
+        &self.rule
+    }
+}
+
+impl FlowSpecRibEntry {
+    pub fn actions(&self) -> Vec<FlowSpecAction> {
+        self.path_attributes
+            .iter()
+            .filter_map(|p| match p {
+                PathAttribute::ExtendedCommunities(c) => Some(c),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(FlowSpecAction::from_extended_community)
+            .collect()
+    }
+}
+
+/// 受信したFlowSpecのルールをパケットフィルタに反映する仕組みです。
+/// 反映先ごとにこのtraitを実装します。
+/// コマンドの実行などでブロックしうるので、asyncのタスクからは
+/// `synchronize`関数を通して呼び出してください。
+pub trait FlowSpecEnforcer: fmt::Debug + Send {
+    fn enforce(&mut self, entry: &Arc<FlowSpecRibEntry>) -> Result<()>;
+
+    /// enforceで反映したルールを取り除く。反映していないルールの場合は何もしない。
+    fn withdraw(&mut self, entry: &Arc<FlowSpecRibEntry>) -> Result<()>;
+
+    /// 反映しているルールを返す。
+    fn enforced(&self) -> Vec<Arc<FlowSpecRibEntry>>;
+
+    /// 反映しているルールをentriesに合わせる。entriesに無いルールは取り除き、
+    /// 反映していないルールは反映する。失敗したルールは警告して次に進む。
+    fn synchronize(&mut self, entries: &[Arc<FlowSpecRibEntry>]) {
+        for entry in self.enforced() {
+            if entries.contains(&entry) {
+                continue;
+            }
+            if let Err(e) = self.withdraw(&entry) {
+                warn!(
+                    "cannot withdraw flowspec rule {}: {:?}.",
+                    entry.rule, e
+                );
+            }
+        }
+        for entry in entries {
+            if let Err(e) = self.enforce(entry) {
+                warn!("cannot enforce flowspec rule {}: {:?}.", entry.rule, e);
+            }
+        }
+    }
+}
+
+/// PeerとPeerSupervisorで共有するFlowSpecEnforcerです。
+/// Peerのタスクが終了した後も、PeerSupervisorが反映したルールを取り除けるようにする。
+pub type SharedFlowSpecEnforcer = Arc<Mutex<Box<dyn FlowSpecEnforcer>>>;
+
+/// enforcerが反映しているルールをentriesに合わせる。
+/// ブロックする処理でruntimeのworkerを止めないように、blocking用のスレッドで行う。
+pub async fn synchronize(
+    enforcer: &SharedFlowSpecEnforcer,
+    entries: Vec<Arc<FlowSpecRibEntry>>,
+) {
+    let enforcer = Arc::clone(enforcer);
+    let result = tokio::task::spawn_blocking(move || {
+        enforcer
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .synchronize(&entries)
+    })
+    .await;
+    if let Err(e) = result {
+        warn!("cannot synchronize flowspec rules: {:?}.", e);
+    }
+}
+
+/// 反映先の種類です。Configの`flowspec-enforcement`で指定します。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum FlowSpecEnforcement {
+    Nftables,
+}
+
+impl FlowSpecEnforcement {
+    pub fn build(self) -> SharedFlowSpecEnforcer {
+        match self {
+            FlowSpecEnforcement::Nftables => {
+                Arc::new(Mutex::new(Box::new(NftablesEnforcer::new())))
+            }
+        }
+    }
+
+    /// 反映先に残っている全てのルールを取り除く。
+    /// 前回の起動時のルールが残らないように起動時に、
+    /// 終了後にルールが残らないように終了時に呼ぶ。
+    pub async fn flush(self) -> Result<()> {
+        tokio::task::spawn_blocking(move || match self {
+            FlowSpecEnforcement::Nftables => NftablesEnforcer::flush(),
+        })
+        .await?
+    }
+}
+
+impl FromStr for FlowSpecEnforcement {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nftables" => Ok(FlowSpecEnforcement::Nftables),
+            _ => Err(ConfigParseError::from(anyhow::anyhow!(
+                "cannot parse {s} as flowspec enforcement"
+            ))),
+        }
+    }
+}
+
+/// `nft`コマンドでinet mrbgpdv2 tableのflowspec chainにルールを追加します。
+/// 追加したルールは、nftablesが割り当てたhandleで削除します。
+#[derive(Debug)]
+pub struct NftablesEnforcer {
+    is_initialized: bool,
+    enforced: HashMap<Arc<FlowSpecRibEntry>, u64>,
+}
+
+impl NftablesEnforcer {
+    pub fn new() -> Self {
+        Self {
+            is_initialized: false,
+            enforced: HashMap::new(),
+        }
+    }
+
+    /// `nft`コマンドを実行し、標準出力を返す。
+    fn nft(args: &str) -> Result<String> {
+        let output = Command::new("nft")
+            .args(args.split(' '))
+            .output()
+            .context("nftコマンドを実行できませんでした。")?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "`nft {}`が失敗しました。{} {}",
+                args,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// inet mrbgpdv2 tableを、含まれるルールごと削除する。
+    fn flush() -> Result<()> {
+        // tableが無い場合にdeleteが失敗しないように、先にaddしておく。
+        Self::nft("add table inet mrbgpdv2")?;
+        Self::nft("delete table inet mrbgpdv2")?;
+        info!("flowspec rules enforced by nftables are flushed.");
+        Ok(())
+    }
+}
+
+/// `nft --echo --handle`の出力から、追加したルールのhandleを取り出す。
+/// (例: `add rule inet mrbgpdv2 flowspec ip daddr 203.0.113.0/24 drop # handle 4`)
+fn parse_nftables_handle(output: &str) -> Option<u64> {
+    output
+        .rsplit_once("# handle ")?
+        .1
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+impl Default for NftablesEnforcer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FlowSpecEnforcer for NftablesEnforcer {
+    fn enforce(&mut self, entry: &Arc<FlowSpecRibEntry>) -> Result<()> {
+        if self.enforced.contains_key(entry) {
+            return Ok(());
+        }
+        let rule = match to_nftables_rule(&entry.rule, &entry.actions()) {
+            Some(rule) => rule,
+            None => {
+                warn!(
+                    "flowspec rule {:?} cannot be translated to nftables.",
+                    entry.rule
+                );
+                return Ok(());
+            }
+        };
+        if !self.is_initialized {
+            Self::nft("add table inet mrbgpdv2")?;
+            Self::nft(
+                "add chain inet mrbgpdv2 flowspec { type filter hook \
+                 forward priority 0 ; }",
+            )?;
+            self.is_initialized = true;
+        }
+        let output = Self::nft(&format!(
+            "--echo --handle add rule inet mrbgpdv2 flowspec {}",
+            rule
+        ))?;
+        let handle = parse_nftables_handle(&output).context(format!(
+            "`nft`の出力からルールのhandleを取り出せませんでした。{}",
+            output
+        ))?;
+        info!("flowspec rule is enforced by nftables: {}.", rule);
+        self.enforced.insert(Arc::clone(entry), handle);
+        Ok(())
+    }
+
+    fn withdraw(&mut self, entry: &Arc<FlowSpecRibEntry>) -> Result<()> {
+        let handle = match self.enforced.get(entry) {
+            Some(handle) => *handle,
+            None => return Ok(()),
+        };
+        Self::nft(&format!(
+            "delete rule inet mrbgpdv2 flowspec handle {}",
+            handle
+        ))?;
+        info!("flowspec rule is withdrawn from nftables: {}.", entry.rule);
+        self.enforced.remove(entry);
+        Ok(())
+    }
+
+    fn enforced(&self) -> Vec<Arc<FlowSpecRibEntry>> {
+        self.enforced.keys().cloned().collect()
+    }
+}
+
+/// numeric operatorのリストをnftablesの条件に変換する。
+/// ORで結合された(範囲を含む)値の集合のみ対応する。
+fn numeric_to_nftables(operators: &[NumericOperator]) -> Option<String> {
+    let mut values = vec![];
+    let mut i = 0;
+    while i < operators.len() {
+        let o = operators[i];
+        match (o.lt, o.gt, o.eq) {
+            (false, false, true) => values.push(o.value.to_string()),
+            (false, true, true) => {
+                let to = operators.get(i + 1)?;
+                if !(to.and && to.lt && to.eq) {
+                    return None;
+                }
+                values.push(format!("{}-{}", o.value, to.value));
+                i += 1;
+            }
+            _ => return None,
+        }
+        i += 1;
+    }
+    Some(format!("{{ {} }}", values.join(", ")))
+}
+
+/// FlowSpecのcomponentをnftablesのmatch式に変換する。
+fn to_nftables_match(rule: &FlowSpecRule) -> Option<String> {
+    let mut matches = vec![];
+    for component in &rule.0 {
+        let m = match component {
+            FlowSpecComponent::DestinationPrefix(p) => {
+                format!("ip daddr {}", **p)
+            }
+            FlowSpecComponent::SourcePrefix(p) => format!("ip saddr {}", **p),
+            FlowSpecComponent::IpProtocol(o) => {
+                format!("ip protocol {}", numeric_to_nftables(o)?)
+            }
+            FlowSpecComponent::DestinationPort(o) => {
+                format!("th dport {}", numeric_to_nftables(o)?)
+            }
+            FlowSpecComponent::SourcePort(o) => {
+                format!("th sport {}", numeric_to_nftables(o)?)
+            }
+            FlowSpecComponent::IcmpType(o) => {
+                format!("icmp type {}", numeric_to_nftables(o)?)
+            }
+            FlowSpecComponent::IcmpCode(o) => {
+                format!("icmp code {}", numeric_to_nftables(o)?)
+            }
+            FlowSpecComponent::PacketLength(o) => {
+                format!("ip length {}", numeric_to_nftables(o)?)
+            }
+            FlowSpecComponent::Dscp(o) => {
+                format!("ip dscp {}", numeric_to_nftables(o)?)
+            }
+            _ => return None,
+        };
+        matches.push(m);
+    }
+    Some(matches.join(" "))
+}
+
+/// FlowSpecのルールとactionをnftablesのルールに変換する。
+/// actionが無い場合は何もしない(acceptする)ルールになる。
+pub fn to_nftables_rule(
+    rule: &FlowSpecRule,
+    actions: &[FlowSpecAction],
+) -> Option<String> {
+    let mut nftables_rule = to_nftables_match(rule)?;
+    for action in actions {
+        match action {
+            FlowSpecAction::TrafficRate(0) => nftables_rule += " drop",
+            FlowSpecAction::TrafficRate(rate) => {
+                nftables_rule +=
+                    &format!(" limit rate over {} bytes/second drop", rate)
+            }
+            FlowSpecAction::TrafficMarking(dscp) => {
+                nftables_rule += &format!(" ip dscp set {}", dscp)
+            }
+        }
+    }
+    Some(nftables_rule)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_bytes_to_flowspec_rule_and_flowspec_rule_to_bytes() {
+        let rule: FlowSpecRule =
+            "dst:203.0.113.0/24,proto:6,dport:80|1024-2048"
+                .parse()
+                .unwrap();
+        let bytes: BytesMut = (&rule).into();
+        assert_eq!(bytes.len(), rule.bytes_len());
+
+        let rules = FlowSpecRule::from_u8_slice(&bytes[..]).unwrap();
+        assert_eq!(rules, vec![rule]);
+    }
+
+    #[test]
+    fn flowspec_rule_to_nftables_rule() {
+        let rule: FlowSpecRule =
+            "proto:17,dst:203.0.113.0/24,dport:53".parse().unwrap();
+        assert_eq!(
+            to_nftables_rule(&rule, &[FlowSpecAction::TrafficRate(0)]),
+            Some(
+                "ip daddr 203.0.113.0/24 ip protocol { 17 } \
+                 th dport { 53 } drop"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn parse_handle_from_nft_echo_output() {
+        assert_eq!(
+            parse_nftables_handle(
+                "add rule inet mrbgpdv2 flowspec ip daddr 203.0.113.0/24 \
+                 drop # handle 4\n"
+            ),
+            Some(4)
+        );
+        assert_eq!(parse_nftables_handle(""), None);
+    }
+
+    /// 反映したルールを覚えておくだけのFlowSpecEnforcerです。
+    #[derive(Debug, Default)]
+    struct MockEnforcer {
+        enforced: Vec<Arc<FlowSpecRibEntry>>,
+    }
+
+    impl FlowSpecEnforcer for MockEnforcer {
+        fn enforce(&mut self, entry: &Arc<FlowSpecRibEntry>) -> Result<()> {
+            if !self.enforced.contains(entry) {
+                self.enforced.push(Arc::clone(entry));
+            }
+            Ok(())
+        }
+
+        fn withdraw(&mut self, entry: &Arc<FlowSpecRibEntry>) -> Result<()> {
+            self.enforced.retain(|e| e != entry);
+            Ok(())
+        }
+
+        fn enforced(&self) -> Vec<Arc<FlowSpecRibEntry>> {
+            self.enforced.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn synchronize_withdraws_rules_no_longer_installed() {
+        let entry = |rule: &str| {
+            Arc::new(FlowSpecRibEntry {
+                rule: rule.parse().unwrap(),
+                path_attributes: Arc::new(vec![]),
+            })
+        };
+        let enforcer: SharedFlowSpecEnforcer =
+            Arc::new(Mutex::new(Box::new(MockEnforcer::default())));
+        let first = entry("dst:203.0.113.0/24,proto:6");
+        let second = entry("dst:198.51.100.0/24,proto:17");
+
+        synchronize(&enforcer, vec![Arc::clone(&first), Arc::clone(&second)])
+            .await;
+        assert_eq!(enforcer.lock().unwrap().enforced().len(), 2);
+
+        synchronize(&enforcer, vec![Arc::clone(&second)]).await;
+        assert_eq!(enforcer.lock().unwrap().enforced(), vec![second]);
+
+        // セッションが切れた場合は空にする。
+        synchronize(&enforcer, vec![]).await;
+        assert!(enforcer.lock().unwrap().enforced().is_empty());
+    }
+}
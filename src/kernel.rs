@@ -0,0 +1,1050 @@
+/// カーネルのルーティングテーブルを読み書きするモジュールです。
+/// LinuxではNetlink(rtnetlink)を使う。
+/// それ以外のOSは開発機でcontrol plane, codecやテストを動かすためだけに対応しており、
+/// カーネルのルーティングテーブルには一切触れない。
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::future::{BoxFuture, FutureExt};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::bgp_type::{Afi, MplsLabel};
+use crate::error::ConfigParseError;
+use crate::routing::{IpNetwork, RibEntry};
+
+#[cfg(target_os = "linux")]
+pub use linux::{
+    add_routes, delete_routes, lookup_installed_routes, lookup_kernel_routes,
+    lookup_routes, resolvable_next_hops, watch_route_changes,
+};
+#[cfg(not(target_os = "linux"))]
+pub use other::{
+    add_routes, delete_routes, lookup_installed_routes, lookup_kernel_routes,
+    lookup_routes, resolvable_next_hops, watch_route_changes,
+};
+
+/// カーネルのルーティングテーブルにルートを書き込み、削除するものです。
+/// 書き込むルートのnext hopも、書き込む先のルーティングテーブルで解決する。
+/// LocRibはこれを通して書き込むので、テストでは書き込みを記録するものに差し替えられる。
+pub trait RouteWriter: fmt::Debug + Send + Sync {
+    fn add_routes(
+        &self,
+        routes: Vec<Arc<RibEntry>>,
+        mpls_encap: bool,
+        target: FibTarget,
+    ) -> BoxFuture<'_, Result<()>>;
+
+    fn delete_routes(
+        &self,
+        routes: Vec<Arc<RibEntry>>,
+        target: FibTarget,
+    ) -> BoxFuture<'_, Result<()>>;
+
+    fn resolvable_next_hops(
+        &self,
+        next_hops: HashSet<IpAddr>,
+        table: u32,
+    ) -> BoxFuture<'_, Result<HashSet<IpAddr>>>;
+
+    fn lookup_installed_routes(
+        &self,
+        target: FibTarget,
+    ) -> BoxFuture<'_, Result<InstalledRoutes>>;
+}
+
+/// カーネルのルーティングテーブルに自身が書き込んだルートの、
+/// ネットワークとnext hopです。
+pub type InstalledRoutes = Vec<(IpNetwork, Vec<IpAddr>)>;
+
+/// 同じネットワークのpathsをカーネルに書き込んだルートが持つnext hopを返す。
+/// next hopはネットワークと同じアドレスファミリーのものだけを使う。
+/// BLACKHOLEのルートはnext hopを持たず、ラベルを付けて転送するルートは
+/// 最初のnext hopだけを持つ。
+pub fn installed_next_hops(
+    paths: &[&RibEntry],
+    mpls_encap: bool,
+) -> Vec<IpAddr> {
+    let e = match paths.first() {
+        Some(e) if !e.is_blackhole() => e,
+        _ => return vec![],
+    };
+    let mut next_hops: Vec<IpAddr> = paths
+        .iter()
+        .filter_map(|p| p.next_hop())
+        .filter(|n| n.is_ipv4() == (e.network_address.afi() == Afi::Ipv4))
+        .collect();
+    if mpls_encap && e.labels.iter().any(|l| *l != MplsLabel::IMPLICIT_NULL) {
+        next_hops.truncate(1);
+    }
+    next_hops
+}
+
+/// Netlinkでカーネルのルーティングテーブルに書き込むRouteWriterです。
+#[derive(Debug)]
+pub struct NetlinkRouteWriter;
+
+impl RouteWriter for NetlinkRouteWriter {
+    fn add_routes(
+        &self,
+        routes: Vec<Arc<RibEntry>>,
+        mpls_encap: bool,
+        target: FibTarget,
+    ) -> BoxFuture<'_, Result<()>> {
+        async move {
+            add_routes(routes.iter().map(|e| e.as_ref()), mpls_encap, target)
+                .await
+        }
+        .boxed()
+    }
+
+    fn delete_routes(
+        &self,
+        routes: Vec<Arc<RibEntry>>,
+        target: FibTarget,
+    ) -> BoxFuture<'_, Result<()>> {
+        async move {
+            delete_routes(routes.iter().map(|e| e.as_ref()), target).await
+        }
+        .boxed()
+    }
+
+    fn resolvable_next_hops(
+        &self,
+        next_hops: HashSet<IpAddr>,
+        table: u32,
+    ) -> BoxFuture<'_, Result<HashSet<IpAddr>>> {
+        async move { resolvable_next_hops(&next_hops, table).await }.boxed()
+    }
+
+    fn lookup_installed_routes(
+        &self,
+        target: FibTarget,
+    ) -> BoxFuture<'_, Result<InstalledRoutes>> {
+        async move { lookup_installed_routes(target).await }.boxed()
+    }
+}
+
+/// LocRibとFibWriterのタスクで共有するRouteWriterです。
+#[derive(Debug, Clone)]
+pub struct SharedRouteWriter(Arc<dyn RouteWriter>);
+
+impl SharedRouteWriter {
+    pub fn new(writer: impl RouteWriter + 'static) -> Self {
+        Self(Arc::new(writer))
+    }
+
+    pub async fn add_routes(
+        &self,
+        routes: Vec<Arc<RibEntry>>,
+        mpls_encap: bool,
+        target: FibTarget,
+    ) -> Result<()> {
+        if routes.is_empty() {
+            return Ok(());
+        }
+        self.0.add_routes(routes, mpls_encap, target).await
+    }
+
+    pub async fn delete_routes(
+        &self,
+        routes: Vec<Arc<RibEntry>>,
+        target: FibTarget,
+    ) -> Result<()> {
+        if routes.is_empty() {
+            return Ok(());
+        }
+        self.0.delete_routes(routes, target).await
+    }
+
+    pub async fn resolvable_next_hops(
+        &self,
+        next_hops: HashSet<IpAddr>,
+        table: u32,
+    ) -> Result<HashSet<IpAddr>> {
+        self.0.resolvable_next_hops(next_hops, table).await
+    }
+
+    pub async fn lookup_installed_routes(
+        &self,
+        target: FibTarget,
+    ) -> Result<InstalledRoutes> {
+        self.0.lookup_installed_routes(target).await
+    }
+}
+
+impl Default for SharedRouteWriter {
+    fn default() -> Self {
+        Self::new(NetlinkRouteWriter)
+    }
+}
+
+// LocRibの比較で書き込み先は区別しない。
+impl PartialEq for SharedRouteWriter {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for SharedRouteWriter {}
+
+/// fib-writer-task=onの場合に、専用のタスクにルーティングテーブルへの
+/// 書き込みを指示するためのsenderです。
+/// 指示した順に書き込むので、追加と削除の順序は入れ替わらない。
+#[derive(Debug, Clone)]
+pub struct FibWriter(mpsc::UnboundedSender<FibUpdate>);
+
+#[derive(Debug)]
+enum FibUpdate {
+    Add {
+        routes: Vec<Arc<RibEntry>>,
+        mpls_encap: bool,
+        target: FibTarget,
+    },
+    Delete {
+        routes: Vec<Arc<RibEntry>>,
+        target: FibTarget,
+    },
+}
+
+/// mainのルーティングテーブル(RT_TABLE_MAIN)のidです。
+pub const MAIN_TABLE: u32 = 254;
+
+/// ルートを書き込むカーネルのルーティングテーブルと、書き込むルートのmetricです。
+/// linux-vrfのPeerのLocRibはvrfのテーブルに、それ以外はmainのテーブルに書き込む。
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FibTarget {
+    pub table: u32,
+    pub metric: u32,
+}
+
+// LocRibの比較で書き込み先は区別しない。
+impl PartialEq for FibWriter {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for FibWriter {}
+
+impl FibWriter {
+    /// writerで書き込みを行うタスクを起動する。
+    pub fn spawn(writer: SharedRouteWriter) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(update) = receiver.recv().await {
+                let result = match &update {
+                    FibUpdate::Add {
+                        routes,
+                        mpls_encap,
+                        target,
+                    } => {
+                        writer
+                            .add_routes(routes.clone(), *mpls_encap, *target)
+                            .await
+                    }
+                    FibUpdate::Delete { routes, target } => {
+                        writer.delete_routes(routes.clone(), *target).await
+                    }
+                };
+                if let Err(e) = result {
+                    warn!(
+                        "cannot write to kernel routing table: {:?}, {:?}.",
+                        e, update
+                    );
+                }
+            }
+        });
+        Self(sender)
+    }
+
+    /// ルートの追加を指示する。書き込みの完了は待たない。
+    pub fn add_routes(
+        &self,
+        routes: impl Iterator<Item = Arc<RibEntry>>,
+        mpls_encap: bool,
+        target: FibTarget,
+    ) -> Result<()> {
+        self.send(FibUpdate::Add {
+            routes: routes.collect(),
+            mpls_encap,
+            target,
+        })
+    }
+
+    /// ルートの削除を指示する。書き込みの完了は待たない。
+    pub fn delete_routes(
+        &self,
+        routes: impl Iterator<Item = Arc<RibEntry>>,
+        target: FibTarget,
+    ) -> Result<()> {
+        self.send(FibUpdate::Delete {
+            routes: routes.collect(),
+            target,
+        })
+    }
+
+    fn send(&self, update: FibUpdate) -> Result<()> {
+        match &update {
+            FibUpdate::Add { routes, .. }
+            | FibUpdate::Delete { routes, .. }
+                if routes.is_empty() =>
+            {
+                return Ok(())
+            }
+            _ => {}
+        }
+        self.0
+            .send(update)
+            .map_err(|_| anyhow::anyhow!("fib writer task is stopped"))
+    }
+}
+
+/// カーネルのルーティングテーブルの1つのテーブルにある、ルートのネットワークと、
+/// そのルートを追加したprotocolです。configの`network`と`redistribute`を
+/// 1回のdumpで確認するために使います。
+#[derive(Debug, Default)]
+pub struct KernelRoutes {
+    routes: HashMap<IpNetwork, Vec<RouteProtocol>>,
+    // ルーティングテーブルを参照できない場合に、全てのネットワークが
+    // 存在するものとして扱うか。
+    contains_all: bool,
+}
+
+impl KernelRoutes {
+    pub fn insert(&mut self, network: IpNetwork, protocol: RouteProtocol) {
+        self.routes.entry(network).or_default().push(protocol);
+    }
+
+    /// networkに一致するルートがあるか。
+    pub fn contains(&self, network: &IpNetwork) -> bool {
+        self.contains_all || self.routes.contains_key(network)
+    }
+
+    /// protocolが追加したルートのネットワークを返す。
+    pub fn networks_of(
+        &self,
+        protocol: RouteProtocol,
+    ) -> impl Iterator<Item = IpNetwork> + '_ {
+        self.routes
+            .iter()
+            .filter(move |(_, protocols)| protocols.contains(&protocol))
+            .map(|(network, _)| *network)
+    }
+}
+
+/// カーネルのルーティングテーブルのルートを追加したprotocol(rtm_protocol)です。
+/// Configの`redistribute`で広報するルートを選ぶのに使います。
+/// 値はinclude/uapi/linux/rtnetlink.hを参照。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct RouteProtocol(pub u8);
+
+impl RouteProtocol {
+    // インターフェースのアドレスからカーネルが作ったルート。(connected)
+    pub const KERNEL: Self = Self(2);
+    // protocolを指定せずに`ip route add`で追加したルート。
+    pub const BOOT: Self = Self(3);
+    pub const STATIC: Self = Self(4);
+    pub const DHCP: Self = Self(16);
+    // 自身が書き込むルート。redistributeで自身のルートを広報し直さないように区別する。
+    pub const BGP: Self = Self(186);
+    pub const ISIS: Self = Self(187);
+    pub const OSPF: Self = Self(188);
+    pub const RIP: Self = Self(189);
+}
+
+impl FromStr for RouteProtocol {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "connected" => Ok(RouteProtocol::KERNEL),
+            "kernel" | "boot" => Ok(RouteProtocol::BOOT),
+            "static" => Ok(RouteProtocol::STATIC),
+            "dhcp" => Ok(RouteProtocol::DHCP),
+            "isis" => Ok(RouteProtocol::ISIS),
+            "ospf" => Ok(RouteProtocol::OSPF),
+            "rip" => Ok(RouteProtocol::RIP),
+            _ => s.parse().map(RouteProtocol).map_err(|_| {
+                ConfigParseError::from(anyhow::anyhow!(
+                    "cannot parse {s} as route protocol"
+                ))
+            }),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::*;
+    use futures::stream::{StreamExt, TryStreamExt};
+    use rtnetlink::constants::{RTMGRP_IPV4_ROUTE, RTMGRP_IPV6_ROUTE};
+    use rtnetlink::packet::route::{NextHop, NextHopFlags, Nla};
+    use rtnetlink::packet::{
+        NetlinkPayload, RouteMessage, RtnlMessage, AF_INET, AF_INET6,
+        RTN_BLACKHOLE, RTN_UNICAST, RT_SCOPE_UNIVERSE,
+    };
+    use rtnetlink::sys::{AsyncSocket, SocketAddr};
+    use rtnetlink::{new_connection, IpVersion};
+    use std::collections::BTreeSet;
+
+    /// カーネルのtableのルーティングテーブルからnetwork_addressに一致するルートを返す。
+    pub async fn lookup_routes(
+        network_address: IpNetwork,
+        table: u32,
+    ) -> Result<Vec<IpNetwork>> {
+        let (connection, handle, _) = new_connection()?;
+        tokio::spawn(connection);
+        let ip_version = match network_address {
+            IpNetwork::V4(_) => IpVersion::V4,
+            IpNetwork::V6(_) => IpVersion::V6,
+        };
+        let mut routes = handle.route().get(ip_version).execute();
+        let mut results = vec![];
+        while let Some(route) = routes.try_next().await? {
+            if table_of(&route) != table {
+                continue;
+            }
+            let destination = match destination(&route)? {
+                Some(destination) => destination,
+                None => continue,
+            };
+
+            if destination != network_address {
+                continue;
+            }
+
+            results.push(destination);
+        }
+        Ok(results)
+    }
+
+    /// カーネルのtableのルーティングテーブルの全てのルートを、1回のdumpで返す。
+    pub async fn lookup_kernel_routes(table: u32) -> Result<KernelRoutes> {
+        let (connection, handle, _) = new_connection()?;
+        tokio::spawn(connection);
+        let mut results = KernelRoutes::default();
+        for ip_version in [IpVersion::V4, IpVersion::V6] {
+            let mut routes = handle.route().get(ip_version).execute();
+            while let Some(route) = routes.try_next().await? {
+                if table_of(&route) != table {
+                    continue;
+                }
+                if let Some(destination) = destination(&route)? {
+                    results.insert(
+                        destination,
+                        RouteProtocol(route.header.protocol),
+                    );
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    /// targetのテーブルから、自身が書き込んだルート(protocolがBGPで、
+    /// metricが一致するもの)のネットワークとnext hopを返す。
+    /// ECMPのルートはRTA_MULTIPATHの全てのnext hopを返す。
+    pub async fn lookup_installed_routes(
+        target: FibTarget,
+    ) -> Result<InstalledRoutes> {
+        let (connection, handle, _) = new_connection()?;
+        tokio::spawn(connection);
+        let mut results = vec![];
+        for ip_version in [IpVersion::V4, IpVersion::V6] {
+            let mut routes = handle.route().get(ip_version).execute();
+            while let Some(route) = routes.try_next().await? {
+                let metric = route
+                    .nlas
+                    .iter()
+                    .find_map(|n| match n {
+                        Nla::Priority(metric) => Some(*metric),
+                        _ => None,
+                    })
+                    .unwrap_or(0);
+                if table_of(&route) != target.table
+                    || route.header.protocol != RouteProtocol::BGP.0
+                    || metric != target.metric
+                {
+                    continue;
+                }
+                if let Some(destination) = destination(&route)? {
+                    results.push((destination, gateways(&route)));
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    /// ルートのRTA_GATEWAYか、RTA_MULTIPATHの各next hopのgatewayを返す。
+    fn gateways(route: &RouteMessage) -> Vec<IpAddr> {
+        route
+            .nlas
+            .iter()
+            .flat_map(|n| match n {
+                Nla::Gateway(address) => {
+                    ip_addr(address).into_iter().collect()
+                }
+                Nla::MultiPath(next_hops) => multipath_gateways(next_hops),
+                _ => vec![],
+            })
+            .collect()
+    }
+
+    /// RTA_MULTIPATHのNetlink Attributeの中身から、各next hopのgatewayを返す。
+    /// 形式はmultipath_nlaを参照。
+    fn multipath_gateways(mut next_hops: &[u8]) -> Vec<IpAddr> {
+        // include/uapi/linux/rtnetlink.h
+        const RTA_GATEWAY: u16 = 5;
+
+        let mut gateways = vec![];
+        while next_hops.len() >= 8 {
+            let length = u16::from_ne_bytes([next_hops[0], next_hops[1]]);
+            let length = (length as usize).clamp(8, next_hops.len());
+            let mut nlas = &next_hops[8..length];
+            while nlas.len() >= 4 {
+                let nla_length = u16::from_ne_bytes([nlas[0], nlas[1]]);
+                let nla_length = (nla_length as usize).clamp(4, nlas.len());
+                let kind = u16::from_ne_bytes([nlas[2], nlas[3]]);
+                if kind == RTA_GATEWAY {
+                    gateways.extend(ip_addr(&nlas[4..nla_length]));
+                }
+                // Netlink Attributeは4 octets単位にalignされる。
+                nlas = &nlas[((nla_length + 3) & !3).min(nlas.len())..];
+            }
+            next_hops = &next_hops[length..];
+        }
+        gateways
+    }
+
+    fn ip_addr(octets: &[u8]) -> Option<IpAddr> {
+        match octets.len() {
+            4 => Some(IpAddr::from(<[u8; 4]>::try_from(octets).ok()?)),
+            16 => Some(IpAddr::from(<[u8; 16]>::try_from(octets).ok()?)),
+            _ => None,
+        }
+    }
+
+    /// ルートのテーブルのid。256以上のidはRTA_TABLEにだけ入っている。
+    fn table_of(route: &RouteMessage) -> u32 {
+        route
+            .nlas
+            .iter()
+            .find_map(|n| match n {
+                Nla::Table(table) => Some(*table),
+                _ => None,
+            })
+            .unwrap_or(route.header.table as u32)
+    }
+
+    /// messageのテーブルを指定する。rtm_tableに入らない256以上のidは、
+    /// rtm_tableをRT_TABLE_COMPATにしてRTA_TABLEで指定する。
+    fn set_table(message: &mut RouteMessage, table: u32) {
+        // include/uapi/linux/rtnetlink.h
+        const RT_TABLE_COMPAT: u8 = 252;
+
+        message.header.table = u8::try_from(table).unwrap_or(RT_TABLE_COMPAT);
+        message.nlas.retain(|n| !matches!(n, Nla::Table(_)));
+        message.nlas.push(Nla::Table(table));
+    }
+
+    fn destination(route: &RouteMessage) -> Result<Option<IpNetwork>> {
+        Ok(match route.destination_prefix() {
+            Some((IpAddr::V4(addr), prefix)) => {
+                Some(ipnetwork::Ipv4Network::new(addr, prefix)?.into())
+            }
+            Some((IpAddr::V6(addr), prefix)) => Some(IpNetwork::V6(
+                ipnetwork::Ipv6Network::new(addr, prefix)?.into(),
+            )),
+            None => None,
+        })
+    }
+
+    /// ルートをカーネルのルーティングテーブルに書き込む。既にあるルートは置き換える。
+    /// 同じネットワークのルートが続く場合は、それらのnext hopへの
+    /// multipath(ECMP)のルートにする。
+    /// mpls_encapがtrueの場合はラベルを付けて転送するルートにする。
+    /// ラベルはnext hop毎には指定しないので、その場合は最初のルートのnext hopだけを使う。
+    /// 自身が書き込んだルートと区別できるように、protocolはBGPにし、metricを付ける。
+    /// ルートはtargetのテーブルに書き込む。
+    /// 書き込めないルートがあっても、残りのルートの書き込みは続ける。
+    pub async fn add_routes(
+        routes: impl Iterator<Item = &RibEntry>,
+        mpls_encap: bool,
+        target: FibTarget,
+    ) -> Result<()> {
+        let (connection, handle, _) = new_connection()?;
+        tokio::spawn(connection);
+        let routes: Vec<&RibEntry> = routes.collect();
+        let mut failed = 0;
+        for paths in
+            routes.chunk_by(|a, b| a.network_address == b.network_address)
+        {
+            let e = paths[0];
+            let gateways = installed_next_hops(paths, mpls_encap);
+            let message =
+                match add_route_message(e, &gateways, mpls_encap, target) {
+                    Some(message) => message,
+                    None => continue,
+                };
+            let mut request = handle.route().add().replace();
+            *request.message_mut() = message;
+            if let Err(error) = request.execute().await {
+                match e.is_blackhole() {
+                    true => warn!(
+                        "cannot add blackhole route {}: {:?}.",
+                        e.network_address, error
+                    ),
+                    false => warn!(
+                        "cannot add route {}: {:?}.",
+                        e.network_address, error
+                    ),
+                }
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            anyhow::bail!("{} routes are not written", failed);
+        }
+        Ok(())
+    }
+
+    /// eを書き込むためのRTM_NEWROUTEのメッセージを返す。
+    /// BLACKHOLE Community(RFC7999)の付いたルートは、next hopに関係なく
+    /// 宛先への通信を破棄するblackholeのルートにする。
+    /// それ以外でgatewaysが無い場合は書き込めないのでNoneを返す。
+    fn add_route_message(
+        e: &RibEntry,
+        gateways: &[IpAddr],
+        mpls_encap: bool,
+        target: FibTarget,
+    ) -> Option<RouteMessage> {
+        let mut message = route_message(e.network_address, target);
+        message.header.scope = RT_SCOPE_UNIVERSE;
+        if e.is_blackhole() {
+            message.header.kind = RTN_BLACKHOLE;
+            return Some(message);
+        }
+        message.header.kind = RTN_UNICAST;
+        let gateway = match gateways.first()? {
+            IpAddr::V4(gateway) => gateway.octets().to_vec(),
+            IpAddr::V6(gateway) => gateway.octets().to_vec(),
+        };
+        message.nlas.push(Nla::Gateway(gateway));
+        set_next_hops(&mut message, e, gateways, mpls_encap);
+        Some(message)
+    }
+
+    /// network_addressの、自身が書き込むルートを指すメッセージを返す。
+    /// 自身が書き込んだルートと区別できるように、protocolはBGPにし、
+    /// targetのテーブルとmetricを指定する。
+    fn route_message(
+        network_address: IpNetwork,
+        target: FibTarget,
+    ) -> RouteMessage {
+        let mut message = RouteMessage::default();
+        message.header.protocol = RouteProtocol::BGP.0;
+        let destination = match network_address {
+            IpNetwork::V4(dest) => {
+                message.header.address_family = AF_INET as u8;
+                message.header.destination_prefix_length = dest.prefix();
+                dest.ip().octets().to_vec()
+            }
+            IpNetwork::V6(dest) => {
+                message.header.address_family = AF_INET6 as u8;
+                message.header.destination_prefix_length = dest.prefix();
+                dest.ip().octets().to_vec()
+            }
+        };
+        message.nlas.push(Nla::Destination(destination));
+        set_table(&mut message, target.table);
+        message.nlas.push(Nla::Priority(target.metric));
+        message
+    }
+
+    /// MPLS encapする場合はeのラベルを付け、そうでなくgatewayが複数ある場合は
+    /// RTA_GATEWAYの代わりにRTA_MULTIPATHでgatewayを指定する。
+    fn set_next_hops(
+        message: &mut RouteMessage,
+        e: &RibEntry,
+        gateways: &[IpAddr],
+        mpls_encap: bool,
+    ) {
+        let encap = match mpls_encap {
+            true => mpls_encap_nlas(&e.labels),
+            false => vec![],
+        };
+        if !encap.is_empty() {
+            message.nlas.extend(encap);
+        } else if gateways.len() > 1 {
+            message.nlas.retain(|n| !matches!(n, Nla::Gateway(_)));
+            message.nlas.push(multipath_nla(gateways));
+        }
+    }
+
+    /// gatewaysに等しい重みで転送するためのRTA_MULTIPATHのNetlink Attributeを返す。
+    fn multipath_nla(gateways: &[IpAddr]) -> Nla {
+        // include/uapi/linux/rtnetlink.h
+        const RTA_GATEWAY: u16 = 5;
+
+        // next hop毎に、struct rtnexthop: [長さ(2)][flags(1)][hops(1)][ifindex(4)]
+        // と、それに続くRTA_GATEWAY: [長さ(2)][type(2)][アドレス]を並べる。
+        // アドレスは4 octetsか16 octetsなので、alignmentのpaddingは要らない。
+        let mut next_hops = vec![];
+        for gateway in gateways {
+            let address = match gateway {
+                IpAddr::V4(a) => a.octets().to_vec(),
+                IpAddr::V6(a) => a.octets().to_vec(),
+            };
+            next_hops
+                .extend_from_slice(&(12 + address.len() as u16).to_ne_bytes());
+            next_hops.extend_from_slice(&[0, 0]);
+            next_hops.extend_from_slice(&0u32.to_ne_bytes());
+            next_hops
+                .extend_from_slice(&(4 + address.len() as u16).to_ne_bytes());
+            next_hops.extend_from_slice(&RTA_GATEWAY.to_ne_bytes());
+            next_hops.extend_from_slice(&address);
+        }
+        Nla::MultiPath(next_hops)
+    }
+
+    /// ルートをカーネルのルーティングテーブルから削除する。
+    /// 自身が書き込んだルートだけを削除するように、protocolとmetricを指定する。
+    /// 既にルーティングテーブルに無いルートは無視する。
+    /// 削除できないルートがあっても、残りのルートの削除は続ける。
+    pub async fn delete_routes(
+        routes: impl Iterator<Item = &RibEntry>,
+        target: FibTarget,
+    ) -> Result<()> {
+        let (connection, handle, _) = new_connection()?;
+        tokio::spawn(connection);
+        let mut failed = 0;
+        for e in routes {
+            let message = route_message(e.network_address, target);
+            // include/uapi/asm-generic/errno-base.h
+            const ESRCH: i32 = 3;
+            match handle.route().del(message).execute().await {
+                Err(rtnetlink::Error::NetlinkError(e)) if e.code == -ESRCH => {
+                }
+                Err(error) => {
+                    warn!(
+                        "cannot delete route {}: {:?}.",
+                        e.network_address, error
+                    );
+                    failed += 1;
+                }
+                Ok(()) => {}
+            }
+        }
+        if failed > 0 {
+            anyhow::bail!("{} routes are not deleted", failed);
+        }
+        Ok(())
+    }
+
+    /// next_hopsのうち、カーネルのtableのルーティングテーブルで解決できるものを返す。
+    /// default routeで解決できるだけのnext hopは到達可能とみなさない。
+    /// 自身が書き込んだBGPのルートで解決できるだけのnext hopも、
+    /// 到達可能とみなさない。
+    pub async fn resolvable_next_hops(
+        next_hops: &HashSet<IpAddr>,
+        table: u32,
+    ) -> Result<HashSet<IpAddr>> {
+        let (connection, handle, _) = new_connection()?;
+        tokio::spawn(connection);
+        let mut destinations = vec![];
+        for ip_version in [IpVersion::V4, IpVersion::V6] {
+            let mut routes = handle.route().get(ip_version).execute();
+            while let Some(route) = routes.try_next().await? {
+                if table_of(&route) != table
+                    || route.header.protocol == RouteProtocol::BGP.0
+                {
+                    continue;
+                }
+                match route.destination_prefix() {
+                    Some((_, 0)) | None => continue,
+                    Some((addr, prefix)) => destinations
+                        .push(ipnetwork::IpNetwork::new(addr, prefix)?),
+                }
+            }
+        }
+        resolve_next_hops(next_hops, &destinations)
+    }
+
+    /// next_hopsのうち、destinationsのいずれかに含まれるものを返す。
+    /// destinationsをprefix長ごとのネットワークの集合にしておき、
+    /// next hopをprefix長ごとに切り詰めて探す。
+    fn resolve_next_hops(
+        next_hops: &HashSet<IpAddr>,
+        destinations: &[ipnetwork::IpNetwork],
+    ) -> Result<HashSet<IpAddr>> {
+        let mut networks = HashSet::new();
+        let mut prefixes = BTreeSet::new();
+        for d in destinations {
+            networks.insert((d.network(), d.prefix()));
+            prefixes.insert((d.is_ipv4(), d.prefix()));
+        }
+        let mut resolvable = HashSet::new();
+        for next_hop in next_hops {
+            for (is_ipv4, prefix) in &prefixes {
+                if *is_ipv4 != next_hop.is_ipv4() {
+                    continue;
+                }
+                let network =
+                    ipnetwork::IpNetwork::new(*next_hop, *prefix)?.network();
+                if networks.contains(&(network, *prefix)) {
+                    resolvable.insert(*next_hop);
+                    break;
+                }
+            }
+        }
+        Ok(resolvable)
+    }
+
+    /// カーネルのルーティングテーブルが変わる度に通知するchannelを返す。
+    /// 自身が書き込んだルートの変更は、next hopの解決をやり直す必要が無いので
+    /// 通知しない。
+    pub async fn watch_route_changes() -> Result<mpsc::UnboundedReceiver<()>> {
+        let (mut connection, _, mut messages) = new_connection()?;
+        let groups = RTMGRP_IPV4_ROUTE | RTMGRP_IPV6_ROUTE;
+        connection
+            .socket_mut()
+            .socket_mut()
+            .bind(&SocketAddr::new(0, groups))?;
+        tokio::spawn(connection);
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some((message, _)) = messages.next().await {
+                if let NetlinkPayload::InnerMessage(
+                    RtnlMessage::NewRoute(route)
+                    | RtnlMessage::DelRoute(route),
+                ) = &message.payload
+                {
+                    if route.header.protocol == RouteProtocol::BGP.0 {
+                        continue;
+                    }
+                }
+                if tx.send(()).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(rx)
+    }
+
+    /// ラベルを付けてnext hopに転送するためのMPLS encapのNetlink Attributeを返す。
+    /// ラベルが無い場合とimplicit nullの場合はIPパケットのまま転送するので空を返す。
+    fn mpls_encap_nlas(labels: &[MplsLabel]) -> Vec<Nla> {
+        // include/uapi/linux/lwtunnel.h, include/uapi/linux/mpls_iptunnel.h
+        const LWTUNNEL_ENCAP_MPLS: u16 = 1;
+        const MPLS_IPTUNNEL_DST: u16 = 1;
+
+        let labels: Vec<&MplsLabel> = labels
+            .iter()
+            .filter(|l| **l != MplsLabel::IMPLICIT_NULL)
+            .collect();
+        if labels.is_empty() {
+            return vec![];
+        }
+        // Netlink Attributeの入れ子: [長さ(2)][type(2)][label stack entry(4) * n]
+        // label stack entryは[ラベル(20 bits)][TC(3 bits)][S(1 bit)][TTL(8 bits)]。
+        let mut encap = vec![];
+        encap.extend_from_slice(&(4 + 4 * labels.len() as u16).to_ne_bytes());
+        encap.extend_from_slice(&MPLS_IPTUNNEL_DST.to_ne_bytes());
+        for (i, label) in labels.iter().enumerate() {
+            let bottom_of_stack = (i == labels.len() - 1) as u32;
+            let entry = (u32::from(**label) << 12) | (bottom_of_stack << 8);
+            encap.extend_from_slice(&entry.to_be_bytes());
+        }
+        vec![Nla::EncapType(LWTUNNEL_ENCAP_MPLS), Nla::Encap(encap)]
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::path_attribute::{Community, PathAttribute};
+
+        fn entry(
+            network: &str,
+            path_attributes: Vec<PathAttribute>,
+        ) -> RibEntry {
+            RibEntry {
+                network_address: network.parse().unwrap(),
+                labels: vec![],
+                path_attributes: Arc::new(path_attributes),
+            }
+        }
+
+        fn priority(message: &RouteMessage) -> Option<u32> {
+            message.nlas.iter().find_map(|n| match n {
+                Nla::Priority(metric) => Some(*metric),
+                _ => None,
+            })
+        }
+
+        #[test]
+        fn next_hops_are_resolved_by_destination_prefixes() {
+            let destinations: Vec<ipnetwork::IpNetwork> =
+                ["10.0.0.0/8", "192.168.1.0/24", "2001:db8::/32"]
+                    .iter()
+                    .map(|d| d.parse().unwrap())
+                    .collect();
+            let next_hops: HashSet<IpAddr> =
+                ["10.1.2.3", "192.168.2.1", "2001:db8::1", "2001:db9::1"]
+                    .iter()
+                    .map(|n| n.parse().unwrap())
+                    .collect();
+            let resolvable =
+                resolve_next_hops(&next_hops, &destinations).unwrap();
+            assert_eq!(
+                resolvable,
+                ["10.1.2.3", "2001:db8::1"]
+                    .iter()
+                    .map(|n| n.parse().unwrap())
+                    .collect()
+            );
+        }
+
+        #[test]
+        fn installed_routes_are_tagged_with_bgp_protocol_and_metric() {
+            let target = FibTarget {
+                table: 1000,
+                metric: 20,
+            };
+            let gateway: IpAddr = "10.0.0.3".parse().unwrap();
+            let e = entry("10.1.0.0/24", vec![]);
+
+            let message =
+                add_route_message(&e, &[gateway], false, target).unwrap();
+            assert_eq!(message.header.protocol, RouteProtocol::BGP.0);
+            assert_eq!(message.header.kind, RTN_UNICAST);
+            assert_eq!(message.header.address_family, AF_INET as u8);
+            assert_eq!(message.header.destination_prefix_length, 24);
+            assert_eq!(priority(&message), Some(20));
+            assert_eq!(table_of(&message), 1000);
+            assert!(message
+                .nlas
+                .contains(&Nla::Destination(vec![10, 1, 0, 0])));
+            assert!(message.nlas.contains(&Nla::Gateway(vec![10, 0, 0, 3])));
+
+            // 削除するときも、自身が書き込んだルートだけを指定する。
+            let message = route_message(e.network_address, target);
+            assert_eq!(message.header.protocol, RouteProtocol::BGP.0);
+            assert_eq!(priority(&message), Some(20));
+            assert_eq!(table_of(&message), 1000);
+        }
+
+        #[test]
+        fn blackhole_routes_are_tagged_with_bgp_protocol_and_metric() {
+            let target = FibTarget {
+                table: MAIN_TABLE,
+                metric: 20,
+            };
+            let e = entry(
+                "2001:db8::1/128",
+                vec![PathAttribute::Communities(vec![Community::BLACKHOLE])],
+            );
+
+            let message = add_route_message(&e, &[], false, target).unwrap();
+            assert_eq!(message.header.protocol, RouteProtocol::BGP.0);
+            assert_eq!(message.header.kind, RTN_BLACKHOLE);
+            assert_eq!(message.header.address_family, AF_INET6 as u8);
+            assert_eq!(message.header.table, MAIN_TABLE as u8);
+            assert_eq!(priority(&message), Some(20));
+            assert!(!message
+                .nlas
+                .iter()
+                .any(|n| matches!(n, Nla::Gateway(_))));
+        }
+
+        #[test]
+        fn gateways_of_multipath_route_are_parsed() {
+            let gateways: Vec<IpAddr> =
+                vec!["10.0.0.3".parse().unwrap(), "10.0.0.4".parse().unwrap()];
+            let mut message = RouteMessage::default();
+            message.nlas.push(multipath_nla(&gateways));
+            assert_eq!(super::gateways(&message), gateways);
+
+            let mut message = RouteMessage::default();
+            message.nlas.push(Nla::Gateway(vec![10, 0, 0, 3]));
+            assert_eq!(super::gateways(&message), gateways[..1]);
+        }
+
+        #[test]
+        fn route_without_gateway_is_not_installed() {
+            let target = FibTarget {
+                table: MAIN_TABLE,
+                metric: 20,
+            };
+            let e = entry("10.1.0.0/24", vec![]);
+            assert_eq!(add_route_message(&e, &[], false, target), None);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod other {
+    use super::*;
+    use tracing::debug;
+
+    /// ルーティングテーブルを参照できないので、
+    /// 設定されたネットワークは常に存在するものとして扱う。
+    pub async fn lookup_routes(
+        network_address: IpNetwork,
+        _table: u32,
+    ) -> Result<Vec<IpNetwork>> {
+        Ok(vec![network_address])
+    }
+
+    pub async fn add_routes(
+        routes: impl Iterator<Item = &RibEntry>,
+        _mpls_encap: bool,
+        _target: FibTarget,
+    ) -> Result<()> {
+        for e in routes {
+            debug!(
+                "kernel routing table is not supported on this os, \
+                 route is not written: {:?}.",
+                e.network_address
+            );
+        }
+        Ok(())
+    }
+
+    pub async fn delete_routes(
+        _routes: impl Iterator<Item = &RibEntry>,
+        _target: FibTarget,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// ルーティングテーブルを参照できないので、設定されたネットワークは
+    /// 常に存在するものとし、redistributeするルートは無いものとして扱う。
+    pub async fn lookup_kernel_routes(_table: u32) -> Result<KernelRoutes> {
+        Ok(KernelRoutes {
+            contains_all: true,
+            ..KernelRoutes::default()
+        })
+    }
+
+    pub async fn lookup_installed_routes(
+        _target: FibTarget,
+    ) -> Result<InstalledRoutes> {
+        anyhow::bail!("reading kernel routing table is not supported")
+    }
+
+    /// ルーティングテーブルを参照できないので、全てのnext hopを到達可能とみなす。
+    pub async fn resolvable_next_hops(
+        next_hops: &HashSet<IpAddr>,
+        _table: u32,
+    ) -> Result<HashSet<IpAddr>> {
+        Ok(next_hops.clone())
+    }
+
+    pub async fn watch_route_changes() -> Result<mpsc::UnboundedReceiver<()>> {
+        anyhow::bail!("watching kernel routing table is not supported")
+    }
+}
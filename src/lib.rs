@@ -1,14 +1,38 @@
 #![feature(backtrace, exclusive_range_pattern, arc_unwrap_or_clone)]
 #![allow(dead_code, unused)]
 
+pub mod bfd;
+mod bgp_ls;
 mod bgp_type;
 pub mod config;
 mod connection;
+#[cfg(unix)]
+pub mod control;
+pub mod convergence;
+pub mod dump;
 mod error;
 mod event;
 mod event_queue;
+mod evpn;
+pub mod flowspec;
+mod kernel;
+pub mod listener;
+pub mod loadgen;
+pub mod logging;
+pub mod message_log;
+pub mod nexthop;
 mod packets;
 mod path_attribute;
 pub mod peer;
+mod policy;
+mod prefix_sid;
+pub mod reconcile;
 pub mod routing;
 mod state;
+pub mod stats;
+pub mod supervisor;
+#[cfg(unix)]
+pub mod systemd;
+pub mod task_pool;
+mod vpn;
+pub mod wire;
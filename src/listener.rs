@@ -0,0 +1,214 @@
+/// passiveの全てのPeerで共有する、BGPのポートで接続を待ち受けるlistenerです。
+/// Peer毎にbindすると2つ目以降のPeerはbindできないので、local_ip毎に1回だけbindし、
+/// 受け付けた接続を接続元のアドレスのPeerに渡す。
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+use crate::config::{Config, Mode};
+
+pub const BGP_PORT: u16 = 179;
+// bindに失敗した場合に再試行するまでの時間。失敗する度に倍にする。
+const INITIAL_BIND_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BIND_BACKOFF: Duration = Duration::from_secs(8);
+const LISTEN_BACKLOG: u32 = 1024;
+
+#[derive(Debug)]
+pub struct BgpListener {
+    state: Arc<Mutex<ListenerState>>,
+}
+
+#[derive(Debug, Default)]
+struct ListenerState {
+    // 接続を受け付けるremote ip。これ以外からの接続は切る。
+    remote_ips: HashSet<IpAddr>,
+    // まだPeerに渡していない接続。
+    accepted: HashMap<IpAddr, TcpStream>,
+    // 接続を待っているPeer。
+    waiting: HashMap<IpAddr, oneshot::Sender<TcpStream>>,
+}
+
+impl BgpListener {
+    /// passiveまたはaccept-inboundのconfigのlocal_ip毎にbindし、
+    /// 接続の受け付けを始める。
+    /// 再起動直後でアドレスが使用中の場合は、bindできるまでバックグラウンドで
+    /// 再試行し、その間Peerはaccept_fromで待ち続ける。
+    pub async fn bind(configs: &[Config]) -> Result<Arc<Self>> {
+        let inbound: Vec<&Config> = configs
+            .iter()
+            .filter(|c| c.mode == Mode::Passive || c.accept_inbound)
+            .collect();
+        let state = Arc::new(Mutex::new(ListenerState {
+            remote_ips: inbound.iter().map(|c| c.remote_ip).collect(),
+            ..Default::default()
+        }));
+        let local_ips: HashSet<IpAddr> =
+            inbound.iter().map(|c| c.local_ip).collect();
+        for local_ip in local_ips {
+            let addr = SocketAddr::new(local_ip, BGP_PORT);
+            let listener = match bind_reusable(addr) {
+                Ok(listener) => listener,
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                    warn!("{} is in use, retry binding in background.", addr);
+                    let state = Arc::clone(&state);
+                    tokio::spawn(async move {
+                        let listener = bind_with_retry(addr).await;
+                        accept_connections(listener, state).await
+                    });
+                    continue;
+                }
+                Err(e) => {
+                    return Err(e).context(format!(
+                        "{0}にbindすることが出来ませんでした。",
+                        addr
+                    ))
+                }
+            };
+            info!("listening on {}.", addr);
+            tokio::spawn(accept_connections(listener, Arc::clone(&state)));
+        }
+        Ok(Arc::new(Self { state }))
+    }
+
+    /// remote_ipからの接続を受け付けるまで待ち、その接続を返す。
+    pub async fn accept_from(&self, remote_ip: IpAddr) -> Result<TcpStream> {
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(stream) = state.accepted.remove(&remote_ip) {
+                return Ok(stream);
+            }
+            let (sender, receiver) = oneshot::channel();
+            state.waiting.insert(remote_ip, sender);
+            receiver
+        };
+        receiver.await.context("listener is stopped")
+    }
+
+    /// remote_ipから受け付けていて、まだPeerに渡していない接続があれば返す。
+    /// 待たずにすぐに返る。
+    pub fn take_accepted(&self, remote_ip: IpAddr) -> Option<TcpStream> {
+        self.state.lock().unwrap().accepted.remove(&remote_ip)
+    }
+}
+
+/// SO_REUSEADDRを設定してbindする。
+/// 再起動前の接続がTIME_WAITで残っていてもbindできるようにするため。
+pub fn bind_reusable(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(LISTEN_BACKLOG)
+}
+
+/// bindできるまで、間隔を空けながらbind_reusableを再試行する。
+/// 前のプロセスがまだソケットを閉じきっていない場合に、それを待つため。
+pub async fn bind_with_retry(addr: SocketAddr) -> TcpListener {
+    let mut backoff = INITIAL_BIND_BACKOFF;
+    loop {
+        match bind_reusable(addr) {
+            Ok(listener) => {
+                info!("listening on {}.", addr);
+                return listener;
+            }
+            Err(e) => warn!(
+                "cannot bind {}: {:?}, retry after {:?}.",
+                addr, e, backoff
+            ),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BIND_BACKOFF);
+    }
+}
+
+async fn accept_connections(
+    listener: TcpListener,
+    state: Arc<Mutex<ListenerState>>,
+) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("cannot accept tcp connection: {:?}.", e);
+                continue;
+            }
+        };
+        let remote_ip = addr.ip();
+        let mut state = state.lock().unwrap();
+        if !state.remote_ips.contains(&remote_ip) {
+            warn!("connection from unknown peer {} is closed.", remote_ip);
+            continue;
+        }
+        let stream = match state.waiting.remove(&remote_ip) {
+            Some(sender) => match sender.send(stream) {
+                Ok(()) => continue,
+                // 待っていたPeerが既にいない。
+                Err(stream) => stream,
+            },
+            None => stream,
+        };
+        // Peerが接続を待ち始めたときに渡す。
+        state.accepted.insert(remote_ip, stream);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn connections_are_dispatched_by_remote_ip() {
+        let configs: Vec<Config> = [
+            "64512 127.0.0.10 64513 127.0.0.11 passive",
+            "64512 127.0.0.10 64514 127.0.0.12 passive",
+        ]
+        .iter()
+        .map(|c| c.parse().unwrap())
+        .collect();
+        let listener = BgpListener::bind(&configs).await.unwrap();
+
+        let connect = |remote: &str| {
+            let socket = tokio::net::TcpSocket::new_v4().unwrap();
+            socket
+                .bind(format!("{}:0", remote).parse().unwrap())
+                .unwrap();
+            socket.connect("127.0.0.10:179".parse().unwrap())
+        };
+        // Peerが待ち始める前に受け付けた接続も渡される。
+        let _early = connect("127.0.0.12").await.unwrap();
+        let waiting = tokio::spawn({
+            let listener = Arc::clone(&listener);
+            let remote_ip = configs[0].remote_ip;
+            async move { listener.accept_from(remote_ip).await }
+        });
+        let _late = connect("127.0.0.11").await.unwrap();
+
+        let stream = waiting.await.unwrap().unwrap();
+        assert_eq!(stream.peer_addr().unwrap().ip(), configs[0].remote_ip);
+        let stream = listener.accept_from(configs[1].remote_ip).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap().ip(), configs[1].remote_ip);
+    }
+
+    #[tokio::test]
+    async fn bind_succeeds_while_previous_connection_is_in_time_wait() {
+        let addr: SocketAddr = "127.0.0.20:10179".parse().unwrap();
+        let listener = bind_reusable(addr).unwrap();
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        // 待ち受け側から先に閉じると、その接続はTIME_WAITで残る。
+        drop(server);
+        drop(listener);
+        drop(client);
+
+        assert!(bind_reusable(addr).is_ok());
+    }
+}
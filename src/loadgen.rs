@@ -0,0 +1,202 @@
+/// 負荷試験用に大量の合成ルートを生成し、LocRibにoriginateするモジュールです。
+/// mrbgp-loadgenから使い、収束時間やメモリ使用量の計測に使う。
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::bgp_type::AutonomousSystemNumber;
+use crate::path_attribute::Community;
+use crate::routing::{IpNetwork, LocRib};
+pub use crate::stats::resident_memory_kb;
+
+// レートを守るためにルートをoriginateする間隔。
+const TICK: Duration = Duration::from_millis(100);
+
+/// baseから連続するprefix_lengthのネットワークをcount個生成する。
+/// Communityはルート毎に0から3個の`<local_as>:<1000 + n>`を付け、
+/// PathAttributeにばらつきを持たせる。
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SyntheticRoutes {
+    base: IpNetwork,
+    prefix_length: u8,
+    count: u32,
+    local_as: u16,
+}
+
+impl SyntheticRoutes {
+    pub fn new(
+        base: IpNetwork,
+        prefix_length: u8,
+        count: u32,
+        local_as: AutonomousSystemNumber,
+    ) -> Result<Self> {
+        let (base_prefix_length, max_prefix_length) = match base {
+            IpNetwork::V4(n) => (n.prefix(), 32),
+            IpNetwork::V6(n) => (n.prefix(), 128),
+        };
+        if prefix_length < base_prefix_length
+            || prefix_length > max_prefix_length
+        {
+            anyhow::bail!(
+                "prefix length {}は{}の範囲内である必要があります。",
+                prefix_length,
+                base
+            );
+        }
+        let capacity = 1u128
+            .checked_shl((prefix_length - base_prefix_length) as u32)
+            .unwrap_or(u128::MAX);
+        if count as u128 > capacity {
+            anyhow::bail!(
+                "{}には/{}のネットワークが{}個しかありません。",
+                base,
+                prefix_length,
+                capacity
+            );
+        }
+        Ok(Self {
+            base,
+            prefix_length,
+            count,
+            local_as: local_as.into(),
+        })
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn iter(
+        &self,
+    ) -> impl Iterator<Item = (IpNetwork, Vec<Community>)> + '_ {
+        (0..self.count).map(move |i| (self.network(i), self.communities(i)))
+    }
+
+    fn network(&self, index: u32) -> IpNetwork {
+        match self.base {
+            IpNetwork::V4(base) => {
+                let step = 1u64 << (32 - self.prefix_length as u32);
+                let addr =
+                    u32::from(base.network()) as u64 + index as u64 * step;
+                ipnetwork::Ipv4Network::new(
+                    Ipv4Addr::from(addr as u32),
+                    self.prefix_length,
+                )
+                .expect("prefix lengthは確認済み")
+                .into()
+            }
+            IpNetwork::V6(base) => {
+                let step = 1u128
+                    .checked_shl(128 - self.prefix_length as u32)
+                    .unwrap_or(0);
+                let addr = u128::from(base.network()) + index as u128 * step;
+                IpNetwork::V6(
+                    ipnetwork::Ipv6Network::new(
+                        Ipv6Addr::from(addr),
+                        self.prefix_length,
+                    )
+                    .expect("prefix lengthは確認済み")
+                    .into(),
+                )
+            }
+        }
+    }
+
+    fn communities(&self, index: u32) -> Vec<Community> {
+        let local_as = (self.local_as as u32) << 16;
+        (0..index % 4)
+            .map(|n| Community(local_as | (1000 + n)))
+            .collect()
+    }
+}
+
+/// routesを1秒あたりrate個の速さでLocRibにoriginateし、かかった時間を返す。
+pub async fn originate(
+    loc_rib: &Arc<Mutex<LocRib>>,
+    routes: &SyntheticRoutes,
+    next_hop: Option<IpAddr>,
+    rate: u32,
+) -> Result<Duration> {
+    let per_tick = (rate as u64 * TICK.as_millis() as u64 / 1000).max(1);
+    let started = Instant::now();
+    let mut interval = tokio::time::interval(TICK);
+    let mut routes = routes.iter().peekable();
+    let mut originated = 0;
+    while routes.peek().is_some() {
+        interval.tick().await;
+        let mut loc_rib = loc_rib.lock().await;
+        for (network, communities) in routes.by_ref().take(per_tick as usize) {
+            loc_rib
+                .announce(network, next_hop, communities)
+                .context(format!("cannot originate {}", network))?;
+            originated += 1;
+        }
+        info!("{} synthetic routes are originated.", originated);
+    }
+    Ok(started.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn generate_synthetic_routes() {
+        let routes = SyntheticRoutes::new(
+            "100.64.0.0/10".parse().unwrap(),
+            24,
+            1001,
+            64512.into(),
+        )
+        .unwrap();
+        let generated: Vec<_> = routes.iter().collect();
+        assert_eq!(generated.len(), 1001);
+        assert_eq!(generated[0].0, "100.64.0.0/24".parse().unwrap());
+        assert_eq!(generated[1].0, "100.64.1.0/24".parse().unwrap());
+        assert_eq!(generated[1000].0, "100.67.232.0/24".parse().unwrap());
+        assert_eq!(generated[0].1, vec![]);
+        assert_eq!(
+            generated[3].1,
+            (1000..1003)
+                .map(|n| Community(64512 << 16 | n))
+                .collect::<Vec<_>>()
+        );
+
+        assert!(SyntheticRoutes::new(
+            "100.64.0.0/24".parse().unwrap(),
+            25,
+            3,
+            64512.into()
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn originate_synthetic_routes_into_loc_rib() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let loc_rib =
+            Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let routes = SyntheticRoutes::new(
+            "2001:db8::/32".parse().unwrap(),
+            48,
+            20,
+            config.local_as,
+        )
+        .unwrap();
+        originate(
+            &loc_rib,
+            &routes,
+            Some("2001:db8::1".parse().unwrap()),
+            1000,
+        )
+        .await
+        .unwrap();
+        assert_eq!(loc_rib.lock().await.routes().count(), 20);
+    }
+}
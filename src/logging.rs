@@ -0,0 +1,211 @@
+/// ログの出力を設定するモジュールです。
+/// 各Peerのタスクは`peer`というspanの中で動くので、そのspanのremote_ipに
+/// control socketからレベルが設定されていればそのレベルでフィルタし、
+/// 設定されていないPeerやspanの外のログはRUST_LOGに従ってフィルタする。
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::span;
+use tracing::subscriber::{Interest, Subscriber};
+use tracing::Metadata;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::EnvFilter;
+
+/// Peerのタスクを動かすspanの名前。remote_ipのfieldを持つ。
+pub const PEER_SPAN: &str = "peer";
+
+/// Peer毎に設定したログのレベルです。
+/// 複製してもすべて同じ設定を共有するので、control socketから変更できる。
+#[derive(Debug, Clone, Default)]
+pub struct PeerLogLevels(Arc<RwLock<HashMap<IpAddr, LevelFilter>>>);
+
+impl PeerLogLevels {
+    /// remote_ipのPeerのログのレベルを設定する。
+    /// Noneの場合は設定を取り除き、RUST_LOGに従うようにする。
+    pub fn set(&self, remote_ip: IpAddr, level: Option<LevelFilter>) {
+        let mut levels = self.0.write().unwrap();
+        match level {
+            Some(level) => levels.insert(remote_ip, level),
+            None => levels.remove(&remote_ip),
+        };
+    }
+
+    pub fn get(&self, remote_ip: IpAddr) -> Option<LevelFilter> {
+        self.0.read().unwrap().get(&remote_ip).copied()
+    }
+}
+
+/// Peerのspanの拡張として、そのPeerのremote_ipを保存する。
+struct PeerSpan(IpAddr);
+
+struct RemoteIpVisitor(Option<IpAddr>);
+
+impl Visit for RemoteIpVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "remote_ip" {
+            self.0 = format!("{:?}", value).parse().ok();
+        }
+    }
+}
+
+/// Peer毎のレベルと、RUST_LOGによるフィルタを組み合わせたLayerです。
+#[derive(Debug)]
+pub struct PeerFilter {
+    env: EnvFilter,
+    levels: PeerLogLevels,
+}
+
+impl PeerFilter {
+    pub fn new(env: EnvFilter, levels: PeerLogLevels) -> Self {
+        Self { env, levels }
+    }
+
+    /// 現在のspanを含むPeerのspanを探し、そのPeerに設定されたレベルを返す。
+    fn peer_level<S>(&self, ctx: &Context<'_, S>) -> Option<LevelFilter>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let current = ctx.lookup_current()?;
+        for span in current.scope() {
+            if let Some(PeerSpan(remote_ip)) = span.extensions().get() {
+                return self.levels.get(*remote_ip);
+            }
+        }
+        None
+    }
+}
+
+impl<S> Layer<S> for PeerFilter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn register_callsite(
+        &self,
+        metadata: &'static Metadata<'static>,
+    ) -> Interest {
+        // Peer毎のレベルは実行中に変わるので、callsite毎の結果をcacheさせず、
+        // 毎回enabledで判定する。
+        Layer::<S>::register_callsite(&self.env, metadata);
+        Interest::sometimes()
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(LevelFilter::TRACE)
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, ctx: Context<'_, S>) -> bool {
+        // Peerのspanはレベルを後から変更できるように、常に作っておく。
+        if metadata.is_span() && metadata.name() == PEER_SPAN {
+            return true;
+        }
+        match self.peer_level(&ctx) {
+            Some(level) => level >= *metadata.level(),
+            None => self.env.enabled(metadata, ctx),
+        }
+    }
+
+    fn new_span(
+        &self,
+        attrs: &span::Attributes<'_>,
+        id: &span::Id,
+        ctx: Context<'_, S>,
+    ) {
+        if attrs.metadata().name() == PEER_SPAN {
+            let mut visitor = RemoteIpVisitor(None);
+            attrs.record(&mut visitor);
+            if let (Some(remote_ip), Some(span)) = (visitor.0, ctx.span(id)) {
+                span.extensions_mut().insert(PeerSpan(remote_ip));
+            }
+        }
+        self.env.new_span(attrs, id, ctx);
+    }
+
+    fn on_record(
+        &self,
+        id: &span::Id,
+        values: &span::Record<'_>,
+        ctx: Context<'_, S>,
+    ) {
+        self.env.on_record(id, values, ctx);
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        self.env.on_enter(id, ctx);
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+        self.env.on_exit(id, ctx);
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        self.env.on_close(id, ctx);
+    }
+}
+
+/// ログの出力を初期化し、Peer毎のレベルを変更するためのPeerLogLevelsを返す。
+pub fn init() -> PeerLogLevels {
+    let levels = PeerLogLevels::default();
+    tracing_subscriber::registry()
+        .with(PeerFilter::new(
+            EnvFilter::from_default_env(),
+            levels.clone(),
+        ))
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+    levels
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tracing::{debug, info_span, Event};
+
+    use super::*;
+
+    struct CountEvents(Arc<AtomicUsize>);
+
+    impl<S: Subscriber> Layer<S> for CountEvents {
+        fn on_event(&self, _: &Event<'_>, _: Context<'_, S>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn log_level_is_changed_only_for_configured_peer() {
+        let levels = PeerLogLevels::default();
+        let count = Arc::new(AtomicUsize::new(0));
+        let subscriber = tracing_subscriber::registry()
+            .with(PeerFilter::new(EnvFilter::new("info"), levels.clone()))
+            .with(CountEvents(Arc::clone(&count)));
+        let flapping: IpAddr = "10.0.0.3".parse().unwrap();
+        let stable: IpAddr = "10.0.0.4".parse().unwrap();
+        let log_debug = || {
+            info_span!(PEER_SPAN, remote_ip = %flapping).in_scope(|| {
+                info_span!("next").in_scope(|| debug!("flapping"))
+            });
+            info_span!(PEER_SPAN, remote_ip = %stable)
+                .in_scope(|| debug!("stable"));
+            debug!("outside of peers");
+        };
+
+        tracing::subscriber::with_default(subscriber, || {
+            log_debug();
+            assert_eq!(count.load(Ordering::SeqCst), 0);
+
+            levels.set(flapping, Some(LevelFilter::DEBUG));
+            log_debug();
+            assert_eq!(count.load(Ordering::SeqCst), 1);
+
+            levels.set(flapping, None);
+            log_debug();
+            assert_eq!(count.load(Ordering::SeqCst), 1);
+        });
+    }
+}
@@ -1,51 +1,326 @@
+use std::collections::BTreeSet;
 use std::env;
+#[cfg(unix)]
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
+use futures::future::join_all;
+use mrbgpdv2::bfd::BfdListener;
 use mrbgpdv2::config::Config;
-use mrbgpdv2::peer::Peer;
-use mrbgpdv2::routing::LocRib;
-use tokio::sync::Mutex;
-use tracing::info;
-
-#[tokio::main]
-async fn main() {
-    let config = env::args().skip(1).fold("".to_owned(), |mut acc, s| {
-        acc += &(s + " ");
-        acc
-    });
-    let config = config.trim_end();
-    let configs = vec![Config::from_str(config)
-        .expect("引数からConfig構造体の作成に失敗しました。")];
+#[cfg(unix)]
+use mrbgpdv2::control::{self, ControlCommand};
+use mrbgpdv2::convergence;
+use mrbgpdv2::flowspec::FlowSpecEnforcement;
+use mrbgpdv2::listener::BgpListener;
+use mrbgpdv2::logging::{self, PeerLogLevels};
+use mrbgpdv2::nexthop;
+use mrbgpdv2::reconcile;
+use mrbgpdv2::routing::{LocRib, LocRibs};
+use mrbgpdv2::stats;
+use mrbgpdv2::supervisor::PeerSupervisor;
+#[cfg(unix)]
+use mrbgpdv2::systemd;
+use mrbgpdv2::task_pool::PeerTaskPool;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, Mutex};
+use tracing::{error, info, warn};
+
+// systemdにREADY=1を送るまでPeerの接続の試行を待つ最大時間。
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn main() {
+    // 複数のPeerのconfigは`--`で区切って渡す。
+    // 例: `64512 10.0.0.1 64513 10.0.0.2 passive -- 64512 10.0.0.1 64514 10.0.0.3 passive`
+    let args: Vec<String> = env::args().skip(1).collect();
+    let configs: Vec<Config> = args
+        .split(|arg| arg == "--")
+        .map(|config| {
+            Config::from_str(&config.join(" "))
+                .expect("引数からConfig構造体の作成に失敗しました。")
+        })
+        .collect();
 
-    tracing_subscriber::fmt::init();
+    // worker threadの数はruntimeを作る前に決める必要があるので、
+    // configを読んでからruntimeを作る。
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if let Some(worker_threads) = configs[0].worker_threads {
+        runtime.worker_threads(worker_threads);
+    }
+    runtime
+        .enable_all()
+        .build()
+        .expect("tokioのruntimeの生成に失敗しました。")
+        .block_on(run(configs));
+}
+
+async fn run(configs: Vec<Config>) {
+    let log_levels = logging::init();
     info!("mrbgpdv2 started with configs {:?}.", configs);
+    // 前回の起動時に反映したFlowSpecのルールが残っていれば取り除く。
+    let flowspec_enforcements: BTreeSet<FlowSpecEnforcement> = configs
+        .iter()
+        .filter_map(|config| config.flowspec_enforcement)
+        .collect();
+    flush_flowspec_rules(&flowspec_enforcements).await;
 
-    // ToDo: configs[0]ではなく、アドバタイズするnetworkのvecを引数に取るようにする。
-    // Configはpeerごとなのに、loc_ribはすべてのpeerで共有する。Peer毎のコンフィグから
-    // 共有するものを生成することに違和感があるため。
-    let loc_rib = Arc::new(Mutex::new(
-        LocRib::new(&configs[0])
+    // LocRibはlinux-vrf毎に作り、同じlinux-vrfのPeerで共有する。
+    // ToDo: 各linux-vrfの最初のconfigではなく、アドバタイズするnetworkのvecを引数に
+    // 取るようにする。Configはpeerごとなのに、loc_ribは複数のpeerで共有する。
+    // Peer毎のコンフィグから共有するものを生成することに違和感があるため。
+    let mut loc_ribs = LocRibs::new();
+    for config in &configs {
+        if loc_ribs.contains_key(&config.linux_vrf) {
+            continue;
+        }
+        let mut loc_rib = LocRib::new(config)
             .await
-            .expect("LocRibの生成に失敗しました。"),
-    ));
-    let mut peers: Vec<Peer> = configs
+            .expect("LocRibの生成に失敗しました。");
+        if configs[0].fib_writer_task {
+            loc_rib.spawn_fib_writer();
+        }
+        let loc_rib = Arc::new(Mutex::new(loc_rib));
+        let nexthop_loc_rib = Arc::clone(&loc_rib);
+        tokio::spawn(async move {
+            if let Err(e) = nexthop::track(nexthop_loc_rib).await {
+                warn!("next hop tracking is stopped with error: {:?}.", e);
+            }
+        });
+        if let Some(interval) = configs[0].fib_reconcile_interval {
+            tokio::spawn(reconcile::run(Arc::clone(&loc_rib), interval));
+        }
+        loc_ribs.insert(config.linux_vrf.clone(), loc_rib);
+    }
+    let loc_ribs = Arc::new(loc_ribs);
+    let stats_loc_ribs = Arc::clone(&loc_ribs);
+    let stats_config = configs[0].clone();
+    tokio::spawn(async move {
+        stats::monitor(stats_loc_ribs, &stats_config).await;
+    });
+    // passiveのPeerはlocal_ip毎に1つのlistenerを共有する。
+    let listener = BgpListener::bind(&configs)
+        .await
+        .expect("listenerの生成に失敗しました。");
+    // bfd=onのPeerはlocal_ip毎に1つのBFDのlistenerを共有する。
+    let bfd_listener = BfdListener::bind(&configs)
+        .await
+        .expect("BFDのlistenerの生成に失敗しました。");
+    #[cfg(unix)]
+    let control_socket = configs[0].control_socket.clone();
+    #[cfg(unix)]
+    let dump_dir = configs[0].dump_dir.clone();
+    let startup_wait = configs[0].startup_wait;
+    let pool = configs[0].peer_task_pool.map(PeerTaskPool::new);
+    let mut supervisors: Vec<PeerSupervisor> = vec![];
+    for config in configs {
+        // BFDのセッションはPeerを作り直しても維持するので、ここで始める。
+        let bfd = match config.bfd {
+            true => Some(
+                bfd_listener
+                    .start_session(&config)
+                    .await
+                    .expect("BFDのセッションの開始に失敗しました。"),
+            ),
+            false => None,
+        };
+        let loc_rib = Arc::clone(&loc_ribs[&config.linux_vrf]);
+        supervisors.push(PeerSupervisor::new(
+            config,
+            loc_rib,
+            Arc::clone(&listener),
+            bfd,
+        ));
+    }
+    if let Some(pool) = pool {
+        for supervisor in supervisors.iter_mut() {
+            supervisor.run_on_pool(pool.clone());
+        }
+    }
+    if let Some(wait) = startup_wait {
+        let statuses = supervisors.iter().map(|s| s.status()).collect();
+        let converged = convergence::wait_for_convergence(statuses, wait);
+        for supervisor in supervisors.iter_mut() {
+            supervisor.wait_for_convergence(converged.clone());
+        }
+    }
+    #[cfg(unix)]
+    spawn_control_tasks(
+        control_socket,
+        dump_dir,
+        &supervisors,
+        Arc::clone(&loc_ribs),
+        log_levels,
+    );
+    // 各Peerは最初のイベント(ManualStart)で接続を試みた後に通知する。
+    let (attempted_tx, mut attempted_rx) = mpsc::channel(supervisors.len());
+    let peers: Vec<_> = supervisors
         .into_iter()
-        .map(|c| Peer::new(c, Arc::clone(&loc_rib)))
+        .map(|supervisor| {
+            let remote_ip = supervisor.remote_ip();
+            let handle = tokio::spawn(supervisor.run(attempted_tx.clone()));
+            async move { (remote_ip, handle.await) }
+        })
         .collect();
-    for peer in &mut peers {
-        peer.start();
-    }
-    let mut handles = vec![];
-    for mut peer in peers {
-        let handle = tokio::spawn(async move {
-            loop {
-                peer.next().await;
+    drop(attempted_tx);
+
+    // passiveのPeerは接続されるまで待ち続けるので、
+    // READY_TIMEOUTを過ぎたら全てのPeerの試行を待たずにREADY=1を送る。
+    let attempted = async { while attempted_rx.recv().await.is_some() {} };
+    if tokio::time::timeout(READY_TIMEOUT, attempted)
+        .await
+        .is_err()
+    {
+        warn!("some peers are not established yet, notify ready anyway.");
+    }
+    #[cfg(unix)]
+    systemd::notify_ready();
+
+    // 各Peerは別々のタスクで動くので、1つのPeerのタスクが終了しても
+    // 他のPeerはそのまま動かし続け、全てのPeerの終了か終了のシグナルを待つ。
+    // その間、systemdのwatchdogにも通知し続ける。
+    tokio::select! {
+        results = join_all(peers) => {
+            for (remote_ip, result) in results {
+                match result {
+                    Ok(()) => warn!("peer {} is stopped.", remote_ip),
+                    Err(e) => {
+                        error!("peer {} is aborted: {:?}.", remote_ip, e)
+                    }
+                }
             }
-        });
-        handles.push(handle);
+        }
+        _ = wait_for_termination() => {
+            info!("mrbgpdv2 is terminating.");
+        }
+        _ = notify_watchdog(loc_ribs.values().cloned().collect()) => {}
     }
-    for handle in handles {
-        handle.await;
+    // Peerから受信したルートがカーネルに残ると、終了後もトラフィックを
+    // 存在しない経路に転送し続けるので削除する。
+    for loc_rib in loc_ribs.values() {
+        let loc_rib = loc_rib.lock().await;
+        if let Err(e) = loc_rib.remove_learned_routes_from_kernel().await {
+            warn!("cannot remove routes from kernel routing table: {:?}.", e);
+        }
     }
+    // 同様に、Peerから受信したFlowSpecのルールもパケットフィルタから取り除く。
+    flush_flowspec_rules(&flowspec_enforcements).await;
+}
+
+async fn flush_flowspec_rules(enforcements: &BTreeSet<FlowSpecEnforcement>) {
+    for enforcement in enforcements {
+        if let Err(e) = enforcement.flush().await {
+            warn!("cannot flush flowspec rules: {:?}.", e);
+        }
+    }
+}
+
+/// SIGINT(Ctrl-C)かSIGTERMを受け取るまで待つ。
+async fn wait_for_termination() {
+    #[cfg(unix)]
+    {
+        let mut sigterm = match signal(SignalKind::terminate()) {
+            Ok(sigterm) => sigterm,
+            Err(e) => {
+                warn!("cannot handle SIGTERM: {:?}.", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// control socketと、SIGUSR1を受け取ったらRIBをダンプするタスクを起動する。
+#[cfg(unix)]
+fn spawn_control_tasks(
+    control_socket: PathBuf,
+    dump_dir: PathBuf,
+    supervisors: &[PeerSupervisor],
+    loc_ribs: Arc<LocRibs>,
+    log_levels: PeerLogLevels,
+) {
+    let neighbors: control::Neighbors = supervisors
+        .iter()
+        .map(|s| {
+            let neighbor = control::Neighbor {
+                admin_sender: s.admin_sender(),
+                status: s.status(),
+                dump_sender: s.dump_sender(),
+                messages: s.message_log(),
+                maintenance_sender: s.maintenance_sender(),
+                log_levels: log_levels.clone(),
+            };
+            (s.remote_ip(), neighbor)
+        })
+        .collect();
+    let neighbors = Arc::new(neighbors);
+
+    let dump_loc_ribs = Arc::clone(&loc_ribs);
+    let dump_neighbors = Arc::clone(&neighbors);
+    let dump_command_dir = dump_dir.clone();
+    tokio::spawn(async move {
+        let mut sigusr1 = match signal(SignalKind::user_defined1()) {
+            Ok(sigusr1) => sigusr1,
+            Err(e) => {
+                warn!("cannot handle SIGUSR1: {:?}.", e);
+                return;
+            }
+        };
+        while sigusr1.recv().await.is_some() {
+            match ControlCommand::DumpRib
+                .execute(&dump_loc_ribs, &dump_neighbors, &dump_command_dir)
+                .await
+            {
+                Ok(Some(output)) => info!("{}.", output),
+                Ok(None) => {}
+                Err(e) => warn!("cannot dump rib: {:?}.", e),
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        if let Err(e) =
+            control::serve(&control_socket, loc_ribs, neighbors, dump_dir)
+                .await
+        {
+            warn!("control socket is stopped with error: {:?}.", e);
+        }
+    });
+}
+
+/// WatchdogSecが設定されている場合、WATCHDOG=1を送り続ける。返らない。
+/// 別のタスクで送ると、Peerのタスクが止まっても送り続けてしまうので、
+/// mainのループで送る。送る前に全てのLocRibのlockを取れることを確かめ、
+/// interval内に取れなければ送らずにsystemdに再起動させる。
+#[cfg(unix)]
+async fn notify_watchdog(loc_ribs: Vec<Arc<Mutex<LocRib>>>) {
+    let interval = match systemd::watchdog_interval() {
+        Some(interval) => interval,
+        None => return std::future::pending().await,
+    };
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let probe = async {
+            for loc_rib in &loc_ribs {
+                drop(loc_rib.lock().await);
+            }
+        };
+        match tokio::time::timeout(interval, probe).await {
+            Ok(()) => systemd::notify_watchdog(),
+            Err(_) => warn!("loc rib is locked too long, skip watchdog."),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn notify_watchdog(_loc_ribs: Vec<Arc<Mutex<LocRib>>>) {
+    std::future::pending().await
 }
@@ -0,0 +1,114 @@
+/// Peer毎に、送受信したメッセージを直近のものだけ保持するモジュールです。
+/// セッションが落ちた後からでも、その前後にPeerと何を交換したかを
+/// control socketの`show bgp neighbor <address> messages`で確認できる。
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::packets::message::Message;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Sent => write!(f, "sent"),
+            Direction::Received => write!(f, "received"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LoggedMessage {
+    timestamp: SystemTime,
+    direction: Direction,
+    message: Message,
+}
+
+/// 直近のcapacity個のメッセージを保持するring bufferです。
+/// 複製しても同じbufferを共有するので、Peerを作り直しても残る。
+#[derive(Debug, Clone)]
+pub struct MessageLog {
+    capacity: usize,
+    messages: Arc<Mutex<VecDeque<LoggedMessage>>>,
+}
+
+impl MessageLog {
+    /// capacityが0の場合は何も保持しない。
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            messages: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    pub(crate) fn record(&self, direction: Direction, message: &Message) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() == self.capacity {
+            messages.pop_front();
+        }
+        messages.push_back(LoggedMessage {
+            timestamp: SystemTime::now(),
+            direction,
+            message: message.clone(),
+        });
+    }
+
+    /// 古い順に`{"timestamp": .., "direction": .., "message": ..}`の配列にする。
+    /// timestampはUNIX時刻(ミリ秒)。
+    pub fn to_json(&self) -> Value {
+        self.messages
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| {
+                let timestamp = m
+                    .timestamp
+                    .duration_since(UNIX_EPOCH)
+                    .map(|t| t.as_millis() as u64)
+                    .unwrap_or(0);
+                json!({
+                    "timestamp": timestamp,
+                    "direction": m.direction.to_string(),
+                    "message": format!("{:?}", m.message),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_latest_messages_are_kept() {
+        let log = MessageLog::new(2);
+        let shared = log.clone();
+        log.record(Direction::Sent, &Message::new_keepalive());
+        log.record(Direction::Received, &Message::new_keepalive());
+        log.record(Direction::Sent, &Message::new_administrative_reset());
+        let json = shared.to_json();
+        assert_eq!(json.as_array().unwrap().len(), 2);
+        assert_eq!(json[0]["direction"], "received");
+        assert_eq!(json[1]["direction"], "sent");
+        assert!(json[1]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Notification"));
+
+        let disabled = MessageLog::new(0);
+        disabled.record(Direction::Sent, &Message::new_keepalive());
+        assert_eq!(disabled.to_json(), json!([]));
+    }
+}
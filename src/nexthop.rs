@@ -0,0 +1,71 @@
+/// LocRibのルートのnext hopの到達性を追跡するモジュールです。
+/// カーネルのルーティングテーブルが変わる度にnext hopを解決し直し、
+/// 到達できなくなったnext hopのルートをLocRibとカーネルから外す。
+/// 到達できるようになれば元に戻す。
+/// 同じタイミングで、configの`network`を広報するかどうかも更新する。
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::kernel;
+use crate::routing::LocRib;
+
+// ルートの変更はまとめて通知されることが多いので、少し待ってからまとめて処理する。
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// カーネルのルーティングテーブルの変更を監視し、next hopの到達性を更新し続ける。
+pub async fn track(loc_rib: Arc<Mutex<LocRib>>) -> Result<()> {
+    let mut changes = kernel::watch_route_changes().await?;
+    loop {
+        if let Err(e) = update(&loc_rib).await {
+            warn!("cannot update next hop reachability: {:?}.", e);
+        }
+        changes
+            .recv()
+            .await
+            .context("kernel route monitor is stopped")?;
+        tokio::time::sleep(DEBOUNCE).await;
+        while changes.try_recv().is_ok() {}
+    }
+}
+
+/// カーネルのルーティングテーブルを参照し、書き込む間は、他のPeerがLocRibを
+/// 使えるように、LocRibのロックを外しておく。
+async fn update(loc_rib: &Arc<Mutex<LocRib>>) -> Result<()> {
+    let table = loc_rib.lock().await.kernel_table();
+    let kernel_routes = kernel::lookup_kernel_routes(table).await?;
+    let (next_hops, route_writer) = {
+        let mut loc_rib = loc_rib.lock().await;
+        loc_rib.refresh_originated_networks(&kernel_routes);
+        (loc_rib.next_hops(), loc_rib.route_writer())
+    };
+    let resolvable = route_writer
+        .resolvable_next_hops(next_hops.clone(), table)
+        .await?;
+    let (changes, kernel_route_changes) = {
+        let mut loc_rib = loc_rib.lock().await;
+        let changes =
+            loc_rib.update_resolved_next_hops(&next_hops, &resolvable);
+        let kernel_route_changes =
+            loc_rib.next_hop_kernel_route_changes(&changes);
+        (changes, kernel_route_changes)
+    };
+    for entry in &changes.unreachable {
+        info!(
+            "next hop {:?} of {} is unreachable.",
+            entry.next_hop(),
+            entry.network_address
+        );
+    }
+    for entry in &changes.reachable {
+        info!(
+            "next hop {:?} of {} is reachable again.",
+            entry.next_hop(),
+            entry.network_address
+        );
+    }
+    kernel_route_changes.program().await
+}
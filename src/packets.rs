@@ -1,7 +1,10 @@
 /// BGP Messageなど通信に使うデータ構造を定義するモジュールです。
 /// ここに定義されているデータ構造をBGP peer間でやり取りします。
+pub mod capability;
 mod header;
 pub mod keepalive;
 pub mod message;
+pub mod notification;
 pub mod open;
+pub mod route_refresh;
 pub mod update;
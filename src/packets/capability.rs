@@ -0,0 +1,335 @@
+use std::str::FromStr;
+
+use bytes::{BufMut, BytesMut};
+
+use crate::bgp_type::AddressFamily;
+use crate::error::{ConfigParseError, ConvertBytesToBgpMessageError};
+
+/// OPEN MessageのOptional Parameterで広報されるCapability (RFC5492)です。
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub enum Capability {
+    // Multiprotocol Extensions (RFC4760)
+    MultiProtocol(AddressFamily),
+    // Route Refresh (RFC2918)
+    RouteRefresh,
+    // Extended Message (RFC8654)
+    ExtendedMessage,
+    // Support for 4-octet AS number (RFC6793)
+    // 本実装は2 octetsのAS番号のみを扱うので、受信した場合に解釈するだけで送信しない。
+    FourOctetAsNumber(u32),
+    // Long-Lived Graceful Restart (RFC9494)
+    LongLivedGracefulRestart(Vec<LlgrFamily>),
+    // Hostname Capability (draft-walton-bgp-hostname-capability)
+    // 表示のためだけに交換し、ネゴシエーションには使わない。
+    Fqdn { hostname: String, domain: String },
+    // 対応していないCapability用
+    Unknown { code: u8, value: Vec<u8> },
+}
+
+/// Long-Lived Graceful Restart Capabilityのaddress family毎の値です。
+/// セッションが切れた後、stale_time秒の間ルートを保持するように求める。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct LlgrFamily {
+    pub address_family: AddressFamily,
+    pub flags: u8,
+    // 3 octetsで表現されるので、最大は16777215秒。
+    pub stale_time: u32,
+}
+
+impl LlgrFamily {
+    pub const MAX_STALE_TIME: u32 = 0xFF_FFFF;
+}
+
+impl Capability {
+    /// Capability Code。(https://www.iana.org/assignments/capability-codes)
+    pub fn code(&self) -> u8 {
+        match self {
+            Capability::MultiProtocol(_) => 1,
+            Capability::RouteRefresh => 2,
+            Capability::ExtendedMessage => 6,
+            Capability::FourOctetAsNumber(_) => 65,
+            Capability::LongLivedGracefulRestart(_) => 71,
+            Capability::Fqdn { .. } => 73,
+            Capability::Unknown { code, .. } => *code,
+        }
+    }
+
+    /// Capability Code(1 octet) + Capability Length(1 octet) + Value
+    pub fn bytes_len(&self) -> usize {
+        let value_length = match self {
+            Capability::MultiProtocol(_) => 4,
+            Capability::RouteRefresh => 0,
+            Capability::ExtendedMessage => 0,
+            Capability::FourOctetAsNumber(_) => 4,
+            // AFI(2), SAFI(1), Flags(1), Long-lived Stale Time(3)
+            Capability::LongLivedGracefulRestart(families) => {
+                7 * families.len()
+            }
+            // Hostname Length(1), Hostname, Domain Name Length(1), Domain Name
+            Capability::Fqdn { hostname, domain } => {
+                2 + hostname.len() + domain.len()
+            }
+            Capability::Unknown { value, .. } => value.len(),
+        };
+        2 + value_length
+    }
+
+    /// Optional Parametersのbytes表現からCapabilityを取り出す。
+    /// Capabilities Optional Parameter(Parameter Type 2)以外は無視する。
+    /// extendedの場合、各Parameter Lengthは2 octetsで表現される。(RFC9072)
+    pub fn from_optional_parameters(
+        bytes: &[u8],
+        extended: bool,
+    ) -> Result<Vec<Self>, ConvertBytesToBgpMessageError> {
+        let length_size = if extended { 2 } else { 1 };
+        let mut capabilities = vec![];
+        let mut i = 0;
+        while bytes.len() > i {
+            if bytes.len() < i + 1 + length_size {
+                return Err(ConvertBytesToBgpMessageError::from(
+                    anyhow::anyhow!("Optional Parameterの長さが不正です。"),
+                ));
+            }
+            let parameter_type = bytes[i];
+            let parameter_length = if extended {
+                u16::from_be_bytes([bytes[i + 1], bytes[i + 2]]) as usize
+            } else {
+                bytes[i + 1] as usize
+            };
+            let parameter_start_index = i + 1 + length_size;
+            let parameter_end_index = parameter_start_index + parameter_length;
+            if bytes.len() < parameter_end_index {
+                return Err(ConvertBytesToBgpMessageError::from(
+                    anyhow::anyhow!("Optional Parameterの長さが不正です。"),
+                ));
+            }
+            if parameter_type == 2 {
+                capabilities.extend(Self::from_u8_slice(
+                    &bytes[parameter_start_index..parameter_end_index],
+                )?);
+            }
+            i = parameter_end_index;
+        }
+        Ok(capabilities)
+    }
+
+    /// Capabilitiesを1つのCapabilities Optional Parameterにまとめたbytes表現を返す。
+    /// extendedの場合、Parameter Lengthを2 octetsで表現する。(RFC9072)
+    pub fn to_optional_parameters(
+        capabilities: &[Self],
+        extended: bool,
+    ) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        if capabilities.is_empty() {
+            return bytes;
+        }
+        let parameter_type = 2;
+        let parameter_length: usize =
+            capabilities.iter().map(|c| c.bytes_len()).sum();
+        bytes.put_u8(parameter_type);
+        if extended {
+            bytes.put_u16(parameter_length as u16);
+        } else {
+            bytes.put_u8(parameter_length as u8);
+        }
+        capabilities
+            .iter()
+            .for_each(|c| bytes.put::<BytesMut>(c.into()));
+        bytes
+    }
+
+    /// Capabilityを並べたbytes表現からCapabilityを取り出す。
+    /// Unsupported Capability NOTIFICATIONのDataもこの形式。(RFC5492 Section 5)
+    pub fn from_u8_slice(
+        bytes: &[u8],
+    ) -> Result<Vec<Self>, ConvertBytesToBgpMessageError> {
+        let mut capabilities = vec![];
+        let mut i = 0;
+        while bytes.len() > i {
+            if bytes.len() < i + 2 {
+                return Err(ConvertBytesToBgpMessageError::from(
+                    anyhow::anyhow!("Capabilityの長さが不正です。"),
+                ));
+            }
+            let code = bytes[i];
+            let length = bytes[i + 1] as usize;
+            let value_start_index = i + 2;
+            let value_end_index = value_start_index + length;
+            if bytes.len() < value_end_index {
+                return Err(ConvertBytesToBgpMessageError::from(
+                    anyhow::anyhow!("Capabilityの長さが不正です。"),
+                ));
+            }
+            let value = &bytes[value_start_index..value_end_index];
+            let capability = match code {
+                // MultiProtocolのValueはAFI(2), Reserved(1), SAFI(1)。
+                1 if length == 4 => {
+                    match AddressFamily::try_from(
+                        &[value[0], value[1], value[3]][..],
+                    ) {
+                        Ok(af) => Capability::MultiProtocol(af),
+                        Err(_) => Capability::Unknown {
+                            code,
+                            value: value.to_owned(),
+                        },
+                    }
+                }
+                2 if length == 0 => Capability::RouteRefresh,
+                6 if length == 0 => Capability::ExtendedMessage,
+                65 if length == 4 => {
+                    Capability::FourOctetAsNumber(u32::from_be_bytes([
+                        value[0], value[1], value[2], value[3],
+                    ]))
+                }
+                71 if length.is_multiple_of(7) => value
+                    .chunks(7)
+                    .map(|v| {
+                        AddressFamily::try_from(&[v[0], v[1], v[2]][..]).map(
+                            |address_family| LlgrFamily {
+                                address_family,
+                                flags: v[3],
+                                stale_time: u32::from_be_bytes([
+                                    0, v[4], v[5], v[6],
+                                ]),
+                            },
+                        )
+                    })
+                    .collect::<Result<_, _>>()
+                    .map(Capability::LongLivedGracefulRestart)
+                    .unwrap_or_else(|_| Capability::Unknown {
+                        code,
+                        value: value.to_owned(),
+                    }),
+                73 => {
+                    parse_fqdn(value).unwrap_or_else(|| Capability::Unknown {
+                        code,
+                        value: value.to_owned(),
+                    })
+                }
+                _ => Capability::Unknown {
+                    code,
+                    value: value.to_owned(),
+                },
+            };
+            capabilities.push(capability);
+            i = value_end_index;
+        }
+        Ok(capabilities)
+    }
+}
+
+/// configで指定する`route-refresh`またはaddress familyをparseする。
+/// address familyはそのMultiprotocol Capabilityを表す。
+impl FromStr for Capability {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "route-refresh" => Ok(Capability::RouteRefresh),
+            _ => Ok(Capability::MultiProtocol(s.parse()?)),
+        }
+    }
+}
+
+/// Hostname CapabilityのValueを変換する。長さが不正かUTF-8でなければNone。
+fn parse_fqdn(value: &[u8]) -> Option<Capability> {
+    let hostname_length = *value.first()? as usize;
+    let hostname = value.get(1..1 + hostname_length)?;
+    let domain_length = *value.get(1 + hostname_length)? as usize;
+    let domain_start = 2 + hostname_length;
+    let domain = value.get(domain_start..domain_start + domain_length)?;
+    Some(Capability::Fqdn {
+        hostname: String::from_utf8(hostname.to_vec()).ok()?,
+        domain: String::from_utf8(domain.to_vec()).ok()?,
+    })
+}
+
+impl From<&Capability> for BytesMut {
+    fn from(capability: &Capability) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        match capability {
+            Capability::MultiProtocol(af) => {
+                bytes.put_u8(1);
+                bytes.put_u8(4);
+                bytes.put_u16(af.afi.into());
+                bytes.put_u8(0);
+                bytes.put_u8(af.safi.into());
+            }
+            Capability::RouteRefresh => {
+                bytes.put_u8(2);
+                bytes.put_u8(0);
+            }
+            Capability::ExtendedMessage => {
+                bytes.put_u8(6);
+                bytes.put_u8(0);
+            }
+            Capability::FourOctetAsNumber(as_number) => {
+                bytes.put_u8(65);
+                bytes.put_u8(4);
+                bytes.put_u32(*as_number);
+            }
+            Capability::LongLivedGracefulRestart(families) => {
+                bytes.put_u8(71);
+                bytes.put_u8((7 * families.len()) as u8);
+                for family in families {
+                    bytes.put_u16(family.address_family.afi.into());
+                    bytes.put_u8(family.address_family.safi.into());
+                    bytes.put_u8(family.flags);
+                    bytes.put(&family.stale_time.to_be_bytes()[1..]);
+                }
+            }
+            Capability::Fqdn { hostname, domain } => {
+                bytes.put_u8(73);
+                bytes.put_u8((2 + hostname.len() + domain.len()) as u8);
+                bytes.put_u8(hostname.len() as u8);
+                bytes.put(hostname.as_bytes());
+                bytes.put_u8(domain.len() as u8);
+                bytes.put(domain.as_bytes());
+            }
+            Capability::Unknown { code, value } => {
+                bytes.put_u8(*code);
+                bytes.put_u8(value.len() as u8);
+                bytes.put(&value[..]);
+            }
+        }
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_capabilities_to_optional_parameters_and_back() {
+        let capabilities = vec![
+            Capability::MultiProtocol(AddressFamily::IPV4_UNICAST),
+            Capability::MultiProtocol(AddressFamily::IPV6_UNICAST),
+            Capability::RouteRefresh,
+            Capability::ExtendedMessage,
+            Capability::FourOctetAsNumber(4200000000),
+            Capability::LongLivedGracefulRestart(vec![LlgrFamily {
+                address_family: AddressFamily::IPV4_UNICAST,
+                flags: 0,
+                stale_time: 86400,
+            }]),
+            Capability::Fqdn {
+                hostname: "router1".to_string(),
+                domain: "example.com".to_string(),
+            },
+            Capability::Unknown {
+                code: 64,
+                value: vec![0, 120],
+            },
+        ];
+        for extended in [false, true] {
+            let bytes =
+                Capability::to_optional_parameters(&capabilities, extended);
+            let capabilities2 =
+                Capability::from_optional_parameters(&bytes[..], extended)
+                    .unwrap();
+
+            assert_eq!(capabilities, capabilities2);
+        }
+    }
+}
@@ -13,13 +13,33 @@ impl Header {
     pub fn new(length: u16, type_: MessageType) -> Self {
         Self { length, type_ }
     }
+
+    /// Headerを含むMessage全体の長さ。
+    pub fn length(&self) -> usize {
+        self.length as usize
+    }
 }
 
 impl TryFrom<BytesMut> for Header {
     type Error = ConvertBytesToBgpMessageError;
 
+    /// Markerが全て1でない場合は、Peerとの同期が取れていないのでErrを返す。
+    /// この場合はConnection Not SynchronizedのNOTIFICATIONを送る。
+    /// (RFC4271 Section 6.1)
     fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
+        if bytes.len() < 19 {
+            return Err(Self::Error::from(anyhow::anyhow!(
+                "BytesからHeaderに変換できませんでした。\
+                 Bytesの長さが19 octetsより短いです。"
+            )));
+        }
         let marker = &bytes[0..16];
+        if marker.iter().any(|b| *b != 0xFF) {
+            return Err(Self::Error::from(anyhow::anyhow!(
+                "Headerのmarkerが全て1ではありません。marker: {:?}",
+                marker
+            )));
+        }
         let length = u16::from_be_bytes([bytes[16], bytes[17]]);
         let type_ = bytes[18].try_into()?;
         Ok(Header { length, type_ })
@@ -44,6 +64,8 @@ pub enum MessageType {
     Open,
     Keepalive,
     Update,
+    Notification,
+    RouteRefresh,
 }
 
 impl TryFrom<u8> for MessageType {
@@ -53,22 +75,41 @@ impl TryFrom<u8> for MessageType {
         match num {
             1 => Ok(MessageType::Open),
             2 => Ok(MessageType::Update),
+            3 => Ok(MessageType::Notification),
             4 => Ok(MessageType::Keepalive),
+            5 => Ok(MessageType::RouteRefresh),
             _ => {
                 Err(Self::Error::from(anyhow::anyhow!(
                 "Num {0}をBGP Message Typeに変換することが出来ませんでした。\
-                 numは1-4が期待されています。", num)))
+                 numは1-5が期待されています。", num)))
             }
         }
     }
 }
 
+impl MessageType {
+    /// Messageの種類毎に、長さが取りうる範囲に入っているかを返す。
+    /// 全体の最大長はExtended Messageをネゴシエーションしたかで変わるので、
+    /// ここでは確認しない。(RFC4271 Section 6.1)
+    pub fn is_valid_length(&self, length: usize) -> bool {
+        match self {
+            MessageType::Open => length >= 29,
+            MessageType::Keepalive => length == 19,
+            MessageType::Update => length >= 23,
+            MessageType::Notification => length >= 21,
+            MessageType::RouteRefresh => length >= 23,
+        }
+    }
+}
+
 impl From<MessageType> for u8 {
     fn from(type_: MessageType) -> Self {
         match type_ {
             MessageType::Open => 1,
             MessageType::Update => 2,
+            MessageType::Notification => 3,
             MessageType::Keepalive => 4,
+            MessageType::RouteRefresh => 5,
         }
     }
 }
@@ -76,6 +117,9 @@ impl From<MessageType> for u8 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::packets::notification::{
+        NotificationMessage, MAX_MESSAGE_LENGTH,
+    };
 
     #[test]
     fn convert_bytes_to_header_and_header_to_bytes() {
@@ -85,4 +129,21 @@ mod tests {
 
         assert_eq!(header, header2);
     }
+
+    #[test]
+    fn header_with_invalid_marker_is_rejected() {
+        let mut header_bytes: BytesMut =
+            Header::new(19, MessageType::Keepalive).into();
+        header_bytes[3] = 0;
+        assert!(Header::try_from(header_bytes.clone()).is_err());
+
+        let notification = NotificationMessage::for_malformed_message(
+            &header_bytes,
+            MAX_MESSAGE_LENGTH,
+        );
+        assert_eq!(
+            notification.error_subcode,
+            NotificationMessage::CONNECTION_NOT_SYNCHRONIZED
+        );
+    }
 }
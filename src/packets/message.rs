@@ -2,13 +2,16 @@ use std::net::Ipv4Addr;
 
 use bytes::BytesMut;
 
-use crate::bgp_type::AutonomousSystemNumber;
+use crate::bgp_type::{AddressFamily, AutonomousSystemNumber, HoldTime};
 use crate::error::{
     ConvertBgpMessageToBytesError, ConvertBytesToBgpMessageError,
 };
+use crate::packets::capability::Capability;
 use crate::packets::header::{Header, MessageType};
 use crate::packets::keepalive::KeepaliveMessage;
+use crate::packets::notification::NotificationMessage;
 use crate::packets::open::OpenMessage;
+use crate::packets::route_refresh::RouteRefreshMessage;
 use crate::packets::update::UpdateMessage;
 
 #[derive(PartialEq, Eq, Debug, Clone, Hash)]
@@ -16,11 +19,15 @@ pub enum Message {
     Open(OpenMessage),
     Keepalive(KeepaliveMessage),
     Update(UpdateMessage),
+    Notification(NotificationMessage),
+    RouteRefresh(RouteRefreshMessage),
 }
 
 impl TryFrom<BytesMut> for Message {
     type Error = ConvertBytesToBgpMessageError;
 
+    /// HeaderのLengthがbytesの長さと一致しないか、Messageの種類毎の
+    /// 長さの範囲に入らない場合はErrを返す。
     fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
         let header_bytes_length = 19;
 
@@ -33,6 +40,16 @@ impl TryFrom<BytesMut> for Message {
 
         let header =
             Header::try_from(BytesMut::from(&bytes[0..header_bytes_length]))?;
+        if header.length() != bytes.len()
+            || !header.type_.is_valid_length(bytes.len())
+        {
+            return Err(Self::Error::from(anyhow::anyhow!(
+                "BytesからMessageに変換できませんでした。\
+                 {:?} Messageの長さ{}が不正です。",
+                header.type_,
+                bytes.len()
+            )));
+        }
         match header.type_ {
             MessageType::Open => {
                 Ok(Message::Open(OpenMessage::try_from(bytes)?))
@@ -43,6 +60,12 @@ impl TryFrom<BytesMut> for Message {
             MessageType::Update => {
                 Ok(Message::Update(UpdateMessage::try_from(bytes)?))
             }
+            MessageType::Notification => Ok(Message::Notification(
+                NotificationMessage::try_from(bytes)?,
+            )),
+            MessageType::RouteRefresh => Ok(Message::RouteRefresh(
+                RouteRefreshMessage::try_from(bytes)?,
+            )),
         }
     }
 }
@@ -53,6 +76,8 @@ impl From<Message> for BytesMut {
             Message::Open(open) => open.into(),
             Message::Keepalive(keepalive) => keepalive.into(),
             Message::Update(update) => update.into(),
+            Message::Notification(notification) => notification.into(),
+            Message::RouteRefresh(route_refresh) => route_refresh.into(),
         }
     }
 }
@@ -60,12 +85,120 @@ impl From<Message> for BytesMut {
 impl Message {
     pub fn new_open(
         my_as_number: AutonomousSystemNumber,
-        my_ip_addr: Ipv4Addr,
+        hold_time: HoldTime,
+        bgp_identifier: Ipv4Addr,
+        capabilities: Vec<Capability>,
     ) -> Self {
-        Self::Open(OpenMessage::new(my_as_number, my_ip_addr))
+        Self::Open(OpenMessage::new(
+            my_as_number,
+            hold_time,
+            bgp_identifier,
+            capabilities,
+        ))
     }
 
     pub fn new_keepalive() -> Self {
         Self::Keepalive(KeepaliveMessage::new())
     }
+
+    /// OPEN Messageで提案されたHold Timeを拒否するNOTIFICATION。
+    pub fn new_unacceptable_hold_time() -> Self {
+        Self::Notification(NotificationMessage::new(
+            NotificationMessage::OPEN_MESSAGE_ERROR,
+            NotificationMessage::UNACCEPTABLE_HOLD_TIME,
+            vec![],
+        ))
+    }
+
+    /// Peerが広報しなかった必須のCapabilityをDataに入れたNOTIFICATION。
+    pub fn new_unsupported_capability(capabilities: &[Capability]) -> Self {
+        let data = capabilities
+            .iter()
+            .flat_map(|c| BytesMut::from(c).to_vec())
+            .collect();
+        Self::Notification(NotificationMessage::new(
+            NotificationMessage::OPEN_MESSAGE_ERROR,
+            NotificationMessage::UNSUPPORTED_CAPABILITY,
+            data,
+        ))
+    }
+
+    /// Hold Timerが満了したことを知らせるNOTIFICATION。
+    pub fn new_hold_timer_expired() -> Self {
+        Self::Notification(NotificationMessage::new(
+            NotificationMessage::HOLD_TIMER_EXPIRED,
+            0,
+            vec![],
+        ))
+    }
+
+    /// Administrative Resetを理由とするCease NOTIFICATION。
+    pub fn new_administrative_reset() -> Self {
+        Self::Notification(NotificationMessage::new(
+            NotificationMessage::CEASE,
+            NotificationMessage::ADMINISTRATIVE_RESET,
+            vec![],
+        ))
+    }
+
+    /// Administrative Shutdownを理由とするCease NOTIFICATION。
+    pub fn new_administrative_shutdown() -> Self {
+        Self::Notification(NotificationMessage::new(
+            NotificationMessage::CEASE,
+            NotificationMessage::ADMINISTRATIVE_SHUTDOWN,
+            vec![],
+        ))
+    }
+
+    /// Connection Collisionで閉じる側の接続に送るCease NOTIFICATION。
+    pub fn new_connection_collision_resolution() -> Self {
+        Self::Notification(NotificationMessage::new(
+            NotificationMessage::CEASE,
+            NotificationMessage::CONNECTION_COLLISION_RESOLUTION,
+            vec![],
+        ))
+    }
+
+    pub fn new_route_refresh(address_family: AddressFamily) -> Self {
+        Self::RouteRefresh(RouteRefreshMessage::new(address_family))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::notification::MAX_MESSAGE_LENGTH;
+
+    #[test]
+    fn message_with_invalid_length_is_rejected() {
+        let message = |length: u16, type_: u8, body: &[u8]| {
+            let mut bytes = BytesMut::from(&[0xFF; 16][..]);
+            bytes.extend_from_slice(&length.to_be_bytes());
+            bytes.extend_from_slice(&[type_]);
+            bytes.extend_from_slice(body);
+            bytes
+        };
+        let keepalive = message(20, 4, &[0]);
+        let open = message(28, 1, &[4, 0xFC, 0, 0, 180, 10, 0, 0]);
+        // withdrawn_routes_lengthがMessageの長さを超えているUPDATE。
+        let update = message(23, 2, &[0, 10, 0, 0]);
+        for bytes in [&keepalive, &open] {
+            assert!(Message::try_from(bytes.clone()).is_err());
+            let notification = NotificationMessage::for_malformed_message(
+                bytes,
+                MAX_MESSAGE_LENGTH,
+            );
+            assert_eq!(
+                (notification.error_subcode, notification.data),
+                (
+                    NotificationMessage::BAD_MESSAGE_LENGTH,
+                    bytes[16..18].to_vec()
+                )
+            );
+        }
+        assert!(Message::try_from(update).is_err());
+
+        // Headerの長さと実際の長さが異なる場合も拒否する。
+        assert!(Message::try_from(message(19, 4, &[0])).is_err());
+    }
 }
@@ -0,0 +1,303 @@
+use std::fmt;
+
+use bytes::{BufMut, BytesMut};
+
+use crate::error::ConvertBytesToBgpMessageError;
+
+use super::header::{Header, MessageType};
+
+/// NOTIFICATION Message (RFC4271 Section 4.5)です。
+/// 送受信した後はTCP Connectionを閉じる。
+#[derive(PartialEq, Eq, Debug, Clone, Hash)]
+pub struct NotificationMessage {
+    header: Header,
+    pub error_code: u8,
+    pub error_subcode: u8,
+    pub data: Vec<u8>,
+}
+
+impl NotificationMessage {
+    pub const MESSAGE_HEADER_ERROR: u8 = 1;
+    pub const OPEN_MESSAGE_ERROR: u8 = 2;
+    pub const UPDATE_MESSAGE_ERROR: u8 = 3;
+    pub const HOLD_TIMER_EXPIRED: u8 = 4;
+    pub const FINITE_STATE_MACHINE_ERROR: u8 = 5;
+    pub const CEASE: u8 = 6;
+    // Message Header ErrorのSubcode
+    pub const CONNECTION_NOT_SYNCHRONIZED: u8 = 1;
+    pub const BAD_MESSAGE_LENGTH: u8 = 2;
+    pub const BAD_MESSAGE_TYPE: u8 = 3;
+    // Subcodeを特定しない場合のSubcode (RFC4271 Section 6.2)
+    pub const UNSPECIFIC: u8 = 0;
+    // Finite State Machine ErrorのSubcode (RFC6608)
+    pub const UNEXPECTED_MESSAGE_IN_OPEN_SENT: u8 = 1;
+    pub const UNEXPECTED_MESSAGE_IN_OPEN_CONFIRM: u8 = 2;
+    pub const UNEXPECTED_MESSAGE_IN_ESTABLISHED: u8 = 3;
+    // UPDATE Message ErrorのSubcode
+    pub const MALFORMED_ATTRIBUTE_LIST: u8 = 1;
+    pub const ATTRIBUTE_LENGTH_ERROR: u8 = 5;
+    pub const INVALID_NETWORK_FIELD: u8 = 10;
+    // OPEN Message ErrorのSubcode
+    pub const UNSUPPORTED_VERSION_NUMBER: u8 = 1;
+    pub const BAD_PEER_AS: u8 = 2;
+    pub const BAD_BGP_IDENTIFIER: u8 = 3;
+    pub const UNSUPPORTED_OPTIONAL_PARAMETER: u8 = 4;
+    pub const UNACCEPTABLE_HOLD_TIME: u8 = 6;
+    // RFC5492 Section 5
+    pub const UNSUPPORTED_CAPABILITY: u8 = 7;
+    // Cease NOTIFICATIONのsubcode (RFC4486)
+    pub const MAXIMUM_NUMBER_OF_PREFIXES_REACHED: u8 = 1;
+    pub const ADMINISTRATIVE_SHUTDOWN: u8 = 2;
+    pub const ADMINISTRATIVE_RESET: u8 = 4;
+    pub const CONNECTION_COLLISION_RESOLUTION: u8 = 7;
+
+    pub fn new(error_code: u8, error_subcode: u8, data: Vec<u8>) -> Self {
+        let header =
+            Header::new(21 + data.len() as u16, MessageType::Notification);
+        Self {
+            header,
+            error_code,
+            error_subcode,
+            data,
+        }
+    }
+
+    /// 受信したMessageのbytesを解釈できなかった場合にPeerへ送るNOTIFICATION。
+    /// Headerの誤りはMessage Header Errorとし、それ以外はMessageの種類毎の
+    /// Errorにする。(RFC4271 Section 6.1, 6.2, 6.3)
+    /// max_lengthはExtended Messageをネゴシエーションしたかで変わるMessageの最大長。
+    pub fn for_malformed_message(bytes: &[u8], max_length: usize) -> Self {
+        if bytes.len() < MIN_MESSAGE_LENGTH {
+            return Self::new(
+                Self::MESSAGE_HEADER_ERROR,
+                Self::BAD_MESSAGE_LENGTH,
+                (bytes.len() as u16).to_be_bytes().to_vec(),
+            );
+        }
+        if bytes[0..16].iter().any(|b| *b != 0xFF) {
+            return Self::new(
+                Self::MESSAGE_HEADER_ERROR,
+                Self::CONNECTION_NOT_SYNCHRONIZED,
+                vec![],
+            );
+        }
+        let length = u16::from_be_bytes([bytes[16], bytes[17]]);
+        let bad_message_length = Self::new(
+            Self::MESSAGE_HEADER_ERROR,
+            Self::BAD_MESSAGE_LENGTH,
+            length.to_be_bytes().to_vec(),
+        );
+        if !(MIN_MESSAGE_LENGTH..=max_length).contains(&(length as usize)) {
+            return bad_message_length;
+        }
+        match MessageType::try_from(bytes[18]) {
+            Ok(type_) if !type_.is_valid_length(length as usize) => {
+                bad_message_length
+            }
+            Ok(MessageType::Open) => {
+                Self::new(Self::OPEN_MESSAGE_ERROR, Self::UNSPECIFIC, vec![])
+            }
+            Ok(MessageType::Update) => Self::new(
+                Self::UPDATE_MESSAGE_ERROR,
+                Self::MALFORMED_ATTRIBUTE_LIST,
+                vec![],
+            ),
+            // KEEPALIVE, NOTIFICATION, ROUTE-REFRESHは長さが誤っている場合にだけ
+            // 解釈に失敗する。
+            Ok(_) => bad_message_length,
+            Err(_) => Self::new(
+                Self::MESSAGE_HEADER_ERROR,
+                Self::BAD_MESSAGE_TYPE,
+                vec![bytes[18]],
+            ),
+        }
+    }
+}
+
+/// Messageの最小, 最大の長さ。(RFC4271 Section 4.1)
+pub const MIN_MESSAGE_LENGTH: usize = 19;
+pub const MAX_MESSAGE_LENGTH: usize = 4096;
+/// Extended Messageをネゴシエーションした場合のMessageの最大長。(RFC8654)
+pub const EXTENDED_MAX_MESSAGE_LENGTH: usize = 65535;
+
+/// NOTIFICATIONのError Code (RFC4271 Section 4.5)です。ログに名前を出すために使う。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum ErrorCode {
+    MessageHeaderError,
+    OpenMessageError,
+    UpdateMessageError,
+    HoldTimerExpired,
+    FiniteStateMachineError,
+    Cease,
+    Unknown(u8),
+}
+
+impl From<u8> for ErrorCode {
+    fn from(code: u8) -> Self {
+        match code {
+            NotificationMessage::MESSAGE_HEADER_ERROR => {
+                Self::MessageHeaderError
+            }
+            NotificationMessage::OPEN_MESSAGE_ERROR => Self::OpenMessageError,
+            NotificationMessage::UPDATE_MESSAGE_ERROR => {
+                Self::UpdateMessageError
+            }
+            NotificationMessage::HOLD_TIMER_EXPIRED => Self::HoldTimerExpired,
+            NotificationMessage::FINITE_STATE_MACHINE_ERROR => {
+                Self::FiniteStateMachineError
+            }
+            NotificationMessage::CEASE => Self::Cease,
+            code => Self::Unknown(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for u8 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::MessageHeaderError => {
+                NotificationMessage::MESSAGE_HEADER_ERROR
+            }
+            ErrorCode::OpenMessageError => {
+                NotificationMessage::OPEN_MESSAGE_ERROR
+            }
+            ErrorCode::UpdateMessageError => {
+                NotificationMessage::UPDATE_MESSAGE_ERROR
+            }
+            ErrorCode::HoldTimerExpired => {
+                NotificationMessage::HOLD_TIMER_EXPIRED
+            }
+            ErrorCode::FiniteStateMachineError => {
+                NotificationMessage::FINITE_STATE_MACHINE_ERROR
+            }
+            ErrorCode::Cease => NotificationMessage::CEASE,
+            ErrorCode::Unknown(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCode::MessageHeaderError => write!(f, "message header error"),
+            ErrorCode::OpenMessageError => write!(f, "open message error"),
+            ErrorCode::UpdateMessageError => write!(f, "update message error"),
+            ErrorCode::HoldTimerExpired => write!(f, "hold timer expired"),
+            ErrorCode::FiniteStateMachineError => {
+                write!(f, "finite state machine error")
+            }
+            ErrorCode::Cease => write!(f, "cease"),
+            ErrorCode::Unknown(code) => {
+                write!(f, "unknown error code {}", code)
+            }
+        }
+    }
+}
+
+impl TryFrom<BytesMut> for NotificationMessage {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
+        if bytes.len() < 21 {
+            return Err(anyhow::anyhow!(
+                "NOTIFICATION Messageの長さが最小の長さより短いです。"
+            )
+            .into());
+        }
+        let header = Header::try_from(BytesMut::from(&bytes[0..19]))?;
+        if header.type_ != MessageType::Notification {
+            return Err(anyhow::anyhow!(
+                "bytes列のtypeがnotificationではありません。"
+            )
+            .into());
+        }
+        Ok(Self {
+            header,
+            error_code: bytes[19],
+            error_subcode: bytes[20],
+            data: bytes[21..].to_vec(),
+        })
+    }
+}
+
+impl From<NotificationMessage> for BytesMut {
+    fn from(notification: NotificationMessage) -> Self {
+        let mut bytes: BytesMut = notification.header.into();
+        bytes.put_u8(notification.error_code);
+        bytes.put_u8(notification.error_subcode);
+        bytes.put(&notification.data[..]);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_bytes_to_notification_message_and_back() {
+        let notification = NotificationMessage::new(
+            NotificationMessage::CEASE,
+            NotificationMessage::ADMINISTRATIVE_RESET,
+            vec![],
+        );
+        let bytes: BytesMut = notification.clone().into();
+        assert_eq!(bytes.len(), 21);
+        let notification2: NotificationMessage = bytes.try_into().unwrap();
+
+        assert_eq!(notification, notification2);
+    }
+
+    #[test]
+    fn notification_for_malformed_message() {
+        let mut bytes = vec![0xFF; 16];
+        bytes.extend_from_slice(&[0, 19, 9]);
+        let bad_type = NotificationMessage::for_malformed_message(
+            &bytes,
+            MAX_MESSAGE_LENGTH,
+        );
+        assert_eq!(
+            (bad_type.error_code, bad_type.error_subcode, bad_type.data),
+            (
+                NotificationMessage::MESSAGE_HEADER_ERROR,
+                NotificationMessage::BAD_MESSAGE_TYPE,
+                vec![9]
+            )
+        );
+
+        bytes[17] = 18;
+        let bad_length = NotificationMessage::for_malformed_message(
+            &bytes,
+            MAX_MESSAGE_LENGTH,
+        );
+        assert_eq!(
+            (bad_length.error_subcode, bad_length.data),
+            (NotificationMessage::BAD_MESSAGE_LENGTH, vec![0, 18])
+        );
+
+        bytes[0] = 0;
+        let not_synchronized = NotificationMessage::for_malformed_message(
+            &bytes,
+            MAX_MESSAGE_LENGTH,
+        );
+        assert_eq!(
+            not_synchronized.error_subcode,
+            NotificationMessage::CONNECTION_NOT_SYNCHRONIZED
+        );
+
+        let mut update = vec![0xFF; 16];
+        update.extend_from_slice(&[0, 23, 2, 0, 0, 0, 1]);
+        let malformed = NotificationMessage::for_malformed_message(
+            &update,
+            MAX_MESSAGE_LENGTH,
+        );
+        assert_eq!(
+            ErrorCode::from(malformed.error_code),
+            ErrorCode::UpdateMessageError
+        );
+        assert_eq!(
+            ErrorCode::from(malformed.error_code).to_string(),
+            "update message error"
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use std::net::Ipv4Addr;
 
+use super::capability::Capability;
 use super::header::{self, Header, MessageType};
 use crate::bgp_type::{AutonomousSystemNumber, HoldTime, Version};
 use crate::error::ConvertBytesToBgpMessageError;
@@ -10,31 +11,59 @@ use bytes::{BufMut, BytesMut};
 pub struct OpenMessage {
     header: Header,
     version: Version,
-    my_as_number: AutonomousSystemNumber,
-    hold_time: HoldTime, // 正常系のみ実装するので一旦実質的に使用しない。
-    bgp_identifier: Ipv4Addr,
+    pub my_as_number: AutonomousSystemNumber,
+    pub hold_time: HoldTime,
+    pub bgp_identifier: Ipv4Addr,
 
-    // 使用しないが、相手から受信したときに一応保存しておくためにプロパティとして用意
-    optional_parameter_length: u8,
-    optional_parameters: BytesMut,
+    // Optional ParametersのうちCapabilitiesのみを解釈して保持する。
+    // 255 octetsを超える場合はExtended Optional Parameters Length(RFC9072)で
+    // 表現し、extended_optional_parametersをtrueにする。
+    optional_parameter_length: u16,
+    extended_optional_parameters: bool,
+    pub capabilities: Vec<Capability>,
 }
 
+// Extended Optional Parameters Lengthを使うことを表す、
+// Non-Ext OP Len, Non-Ext OP Typeの値。(RFC9072 Section 2)
+const EXTENDED_OPTIONAL_PARAMETERS: u8 = 255;
+
 impl OpenMessage {
     pub fn new(
         my_as_number: AutonomousSystemNumber,
-        my_ip_addr: Ipv4Addr,
+        hold_time: HoldTime,
+        bgp_identifier: Ipv4Addr,
+        capabilities: Vec<Capability>,
     ) -> Self {
-        let header = Header::new(29, MessageType::Open);
+        let non_extended_length =
+            Capability::to_optional_parameters(&capabilities, false).len();
+        let extended_optional_parameters = non_extended_length > 255;
+        let optional_parameter_length = Capability::to_optional_parameters(
+            &capabilities,
+            extended_optional_parameters,
+        )
+        .len() as u16;
+        // Extendedの場合、Non-Ext OP Len, Non-Ext OP Typeの後に
+        // Extended Opt. Parm. Length(2 octets)が続く。
+        let length_field = if extended_optional_parameters { 4 } else { 1 };
+        let header = Header::new(
+            28 + length_field + optional_parameter_length,
+            MessageType::Open,
+        );
         Self {
             header,
             version: Version::new(),
             my_as_number,
-            hold_time: HoldTime::new(),
-            bgp_identifier: my_ip_addr,
-            optional_parameter_length: 0,
-            optional_parameters: BytesMut::new(),
+            hold_time,
+            bgp_identifier,
+            optional_parameter_length,
+            extended_optional_parameters,
+            capabilities,
         }
     }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
 }
 
 impl TryFrom<BytesMut> for OpenMessage {
@@ -60,8 +89,19 @@ impl TryFrom<BytesMut> for OpenMessage {
             .try_into()
             .context("Ip Addressのoctetsを取得できませんでした。")?;
         let bgp_identifier = Ipv4Addr::from(b);
-        let optional_parameter_length = bytes[28];
-        let optional_parameters = BytesMut::from(&bytes[29..]);
+        let extended_optional_parameters = bytes.len() >= 32
+            && bytes[28] == EXTENDED_OPTIONAL_PARAMETERS
+            && bytes[29] == EXTENDED_OPTIONAL_PARAMETERS;
+        let (optional_parameter_length, optional_parameters) =
+            if extended_optional_parameters {
+                (u16::from_be_bytes([bytes[30], bytes[31]]), &bytes[32..])
+            } else {
+                (bytes[28].into(), &bytes[29..])
+            };
+        let capabilities = Capability::from_optional_parameters(
+            optional_parameters,
+            extended_optional_parameters,
+        )?;
 
         Ok(OpenMessage {
             header,
@@ -70,7 +110,8 @@ impl TryFrom<BytesMut> for OpenMessage {
             hold_time,
             bgp_identifier,
             optional_parameter_length,
-            optional_parameters,
+            extended_optional_parameters,
+            capabilities,
         })
     }
 }
@@ -84,8 +125,17 @@ impl From<OpenMessage> for BytesMut {
         bytes.put_u16(message.my_as_number.into());
         bytes.put_u16(message.hold_time.into());
         bytes.put(&message.bgp_identifier.octets()[..]);
-        bytes.put_u8(message.optional_parameter_length);
-        bytes.put(&message.optional_parameters[..]);
+        if message.extended_optional_parameters {
+            bytes.put_u8(EXTENDED_OPTIONAL_PARAMETERS);
+            bytes.put_u8(EXTENDED_OPTIONAL_PARAMETERS);
+            bytes.put_u16(message.optional_parameter_length);
+        } else {
+            bytes.put_u8(message.optional_parameter_length as u8);
+        }
+        bytes.put(Capability::to_optional_parameters(
+            &message.capabilities,
+            message.extended_optional_parameters,
+        ));
 
         bytes
     }
@@ -94,12 +144,60 @@ impl From<OpenMessage> for BytesMut {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bgp_type::AddressFamily;
 
     #[test]
     fn convert_bytes_to_open_message_and_open_message_to_bytes() {
-        let open_message =
-            OpenMessage::new(64512.into(), "127.0.0.1".parse().unwrap());
+        let open_message = OpenMessage::new(
+            64512.into(),
+            HoldTime::new(),
+            "127.0.0.1".parse().unwrap(),
+            vec![],
+        );
+        let open_message_bytes: BytesMut = open_message.clone().into();
+        let open_message2: OpenMessage =
+            open_message_bytes.try_into().unwrap();
+
+        assert_eq!(open_message, open_message2);
+    }
+
+    #[test]
+    fn convert_open_message_with_capabilities() {
+        let open_message = OpenMessage::new(
+            64512.into(),
+            HoldTime::new(),
+            "127.0.0.1".parse().unwrap(),
+            vec![
+                Capability::MultiProtocol(AddressFamily::IPV4_UNICAST),
+                Capability::MultiProtocol(AddressFamily::IPV6_UNICAST),
+            ],
+        );
+        let open_message_bytes: BytesMut = open_message.clone().into();
+        assert_eq!(open_message_bytes.len(), 29 + 2 + 6 * 2);
+        let open_message2: OpenMessage =
+            open_message_bytes.try_into().unwrap();
+
+        assert_eq!(open_message, open_message2);
+    }
+
+    #[test]
+    fn convert_open_message_with_extended_optional_parameters() {
+        let capabilities: Vec<Capability> = (0..30)
+            .map(|code| Capability::Unknown {
+                code: 128 + code,
+                value: vec![0; 8],
+            })
+            .collect();
+        let open_message = OpenMessage::new(
+            64512.into(),
+            HoldTime::new(),
+            "127.0.0.1".parse().unwrap(),
+            capabilities,
+        );
         let open_message_bytes: BytesMut = open_message.clone().into();
+        // Capabilityは30 * 10 octetsなので、255 octetsを超える。
+        assert_eq!(open_message_bytes.len(), 29 + 3 + 3 + 30 * 10);
+        assert_eq!(&open_message_bytes[28..32], &[255, 255, 1, 47]);
         let open_message2: OpenMessage =
             open_message_bytes.try_into().unwrap();
 
@@ -0,0 +1,77 @@
+use bytes::{BufMut, BytesMut};
+
+use crate::bgp_type::AddressFamily;
+use crate::error::ConvertBytesToBgpMessageError;
+
+use super::header::{Header, MessageType};
+
+/// ROUTE-REFRESH Message (RFC2918)です。
+/// 受信したPeerはaddress_familyのAdjRibOutを全て送り直す。
+#[derive(PartialEq, Eq, Debug, Clone, Hash)]
+pub struct RouteRefreshMessage {
+    header: Header,
+    pub address_family: AddressFamily,
+}
+
+impl RouteRefreshMessage {
+    pub fn new(address_family: AddressFamily) -> Self {
+        let header = Header::new(23, MessageType::RouteRefresh);
+        Self {
+            header,
+            address_family,
+        }
+    }
+}
+
+impl TryFrom<BytesMut> for RouteRefreshMessage {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
+        if bytes.len() < 23 {
+            return Err(anyhow::anyhow!(
+                "ROUTE-REFRESH Messageの長さが足りません。"
+            )
+            .into());
+        }
+        let header = Header::try_from(BytesMut::from(&bytes[0..19]))?;
+        if header.type_ != MessageType::RouteRefresh {
+            return Err(anyhow::anyhow!(
+                "bytes列のtypeがroute refreshではありません。"
+            )
+            .into());
+        }
+        // AFI(2), Reserved(1), SAFI(1)
+        let address_family =
+            AddressFamily::try_from(&[bytes[19], bytes[20], bytes[22]][..])?;
+        Ok(Self {
+            header,
+            address_family,
+        })
+    }
+}
+
+impl From<RouteRefreshMessage> for BytesMut {
+    fn from(route_refresh: RouteRefreshMessage) -> Self {
+        let mut bytes: BytesMut = route_refresh.header.into();
+        bytes.put_u16(route_refresh.address_family.afi.into());
+        bytes.put_u8(0);
+        bytes.put_u8(route_refresh.address_family.safi.into());
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_bytes_to_route_refresh_message_and_back() {
+        let route_refresh =
+            RouteRefreshMessage::new(AddressFamily::IPV6_UNICAST);
+        let bytes: BytesMut = route_refresh.clone().into();
+        assert_eq!(bytes.len(), 23);
+        let route_refresh2: RouteRefreshMessage = bytes.try_into().unwrap();
+
+        assert_eq!(route_refresh, route_refresh2);
+    }
+}
@@ -1,16 +1,24 @@
 use std::collections::HashMap;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
 
 use crate::routing::Ipv4Network;
 use anyhow::Context;
 use bytes::{BufMut, BytesMut};
 
-use crate::bgp_type::AutonomousSystemNumber;
+use crate::bgp_ls::{LinkStateAttribute, LinkStateNlri, NodeDescriptors, Tlv};
+use crate::bgp_type::{AddressFamily, AutonomousSystemNumber, MplsLabel};
 use crate::error::ConvertBytesToBgpMessageError;
+use crate::evpn::{EvpnLabel, EvpnRoute};
 use crate::packets::header::Header;
-use crate::path_attribute::{AsPath, Origin, PathAttribute};
-use crate::routing::{AdjRibOut, RibEntry};
+use crate::packets::notification::NotificationMessage;
+use crate::path_attribute::{
+    AsPath, ExtendedCommunity, MpNlri, MpReachNlri, MpUnreachNlri, Origin,
+    PathAttribute, PmsiTunnel,
+};
+use crate::prefix_sid::{PrefixSid, PrefixSidTlv};
+use crate::routing::{AdjRibOut, LabeledPrefix, RibEntry};
+use crate::vpn::Vpnv4Prefix;
 
 use super::header::MessageType;
 
@@ -28,34 +36,32 @@ pub struct UpdateMessage {
 }
 
 impl UpdateMessage {
+    /// Messageの長さが65535 octetsを超える場合はpanicする。
+    /// 広報するルートが多い場合は、message_lengthで長さを確かめて
+    /// 複数のUpdateMessageに分けること。
     pub fn new(
         path_attributes: Arc<Vec<PathAttribute>>,
         network_layer_reachability_information: Vec<Ipv4Network>,
         withdrawn_routes: Vec<Ipv4Network>,
     ) -> Self {
-        let path_attributes_length =
-            path_attributes.iter().map(|p| p.bytes_len()).sum::<usize>()
-                as u16;
-        let network_layer_reachability_information_length =
-            network_layer_reachability_information
+        let path_attributes_length = u16::try_from(
+            path_attributes.iter().map(|p| p.bytes_len()).sum::<usize>(),
+        )
+        .expect("path attributesが65535 octetsを超えています。");
+        let withdrawn_routes_length = u16::try_from(
+            withdrawn_routes
                 .iter()
-                .map(|r| r.bytes_len())
-                .sum::<usize>() as u16;
-        let withdrawn_routes_length = withdrawn_routes
-            .iter()
-            .map(|w| w.bytes_len())
-            .sum::<usize>() as u16;
-        let header_minimum_length: u16 = 19;
-        let header = Header::new(
-            header_minimum_length
-                + path_attributes_length
-                + network_layer_reachability_information_length
-                + withdrawn_routes_length
-                // +4はpath_attributes_length(u16)と
-                // withdrawn_routes_length(u16)のbytes表現分,
-                + 4,
-            MessageType::Update,
-        );
+                .map(|w| w.bytes_len())
+                .sum::<usize>(),
+        )
+        .expect("withdrawn routesが65535 octetsを超えています。");
+        let length = u16::try_from(Self::message_length(
+            &path_attributes,
+            &network_layer_reachability_information,
+            &withdrawn_routes,
+        ))
+        .expect("UpdateMessageが65535 octetsを超えています。");
+        let header = Header::new(length, MessageType::Update);
         Self {
             header,
             withdrawn_routes,
@@ -65,6 +71,25 @@ impl UpdateMessage {
             network_layer_reachability_information,
         }
     }
+
+    /// UpdateMessageにした時の、Headerを含めたオクテット数。
+    pub fn message_length(
+        path_attributes: &[PathAttribute],
+        network_layer_reachability_information: &[Ipv4Network],
+        withdrawn_routes: &[Ipv4Network],
+    ) -> usize {
+        let header_minimum_length = 19;
+        header_minimum_length
+            + path_attributes.iter().map(|p| p.bytes_len()).sum::<usize>()
+            + network_layer_reachability_information
+                .iter()
+                .map(|r| r.bytes_len())
+                .sum::<usize>()
+            + withdrawn_routes.iter().map(|w| w.bytes_len()).sum::<usize>()
+            // +4はpath_attributes_length(u16)と
+            // withdrawn_routes_length(u16)のbytes表現分,
+            + 4
+    }
 }
 
 impl From<UpdateMessage> for BytesMut {
@@ -101,9 +126,17 @@ impl TryFrom<BytesMut> for UpdateMessage {
                 &bytes
             ))?);
         let withdrawn_routes_end_index = 21 + withdrawn_routes_length as usize;
+        if withdrawn_routes_end_index + 2 > bytes.len() {
+            return Err(anyhow::anyhow!(
+                "withdrawn_routes_length {}がMessageの長さを超えています。",
+                withdrawn_routes_length
+            )
+            .into());
+        }
         let withdrawn_routes_bytes = &bytes[21..withdrawn_routes_end_index];
         let withdrawn_routes =
-            Ipv4Network::from_u8_slice(withdrawn_routes_bytes)?;
+            Ipv4Network::from_u8_slice(withdrawn_routes_bytes)
+                .map_err(invalid_network_field)?;
 
         let path_attributes_start_index = withdrawn_routes_end_index + 2;
         let total_path_attribute_length = u16::from_be_bytes(
@@ -115,6 +148,15 @@ impl TryFrom<BytesMut> for UpdateMessage {
                 ))?,
         );
 
+        if path_attributes_start_index + total_path_attribute_length as usize
+            > bytes.len()
+        {
+            return Err(anyhow::anyhow!(
+                "total_path_attribute_length {}がMessageの長さを超えています。",
+                total_path_attribute_length
+            )
+            .into());
+        }
         let path_attributes_bytes = &bytes[path_attributes_start_index
             ..path_attributes_start_index
                 + total_path_attribute_length as usize];
@@ -123,7 +165,8 @@ impl TryFrom<BytesMut> for UpdateMessage {
         let nlri_start_index =
             path_attributes_start_index + total_path_attribute_length as usize;
         let network_layer_reachability_information =
-            Ipv4Network::from_u8_slice(&bytes[nlri_start_index..])?;
+            Ipv4Network::from_u8_slice(&bytes[nlri_start_index..])
+                .map_err(invalid_network_field)?;
 
         Ok(Self {
             header,
@@ -136,9 +179,22 @@ impl TryFrom<BytesMut> for UpdateMessage {
     }
 }
 
+/// NLRIかWithdrawn Routesを解釈できなかった場合は、
+/// Invalid Network FieldのUPDATE Message Errorにする。(RFC4271 Section 6.3)
+fn invalid_network_field(
+    e: ConvertBytesToBgpMessageError,
+) -> ConvertBytesToBgpMessageError {
+    e.with_notification(NotificationMessage::new(
+        NotificationMessage::UPDATE_MESSAGE_ERROR,
+        NotificationMessage::INVALID_NETWORK_FIELD,
+        vec![],
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::path_attribute::AsPathSegment;
 
     #[tokio::test]
     async fn update_message_from_adj_rib_out() {
@@ -154,19 +210,22 @@ mod tests {
 
         let rib_path_attributes = Arc::new(vec![
             PathAttribute::Origin(Origin::Igp),
-            PathAttribute::AsPath(AsPath::AsSequence(vec![some_as])),
+            PathAttribute::AsPath(AsPath::from_sequence(vec![some_as])),
             PathAttribute::NextHop(some_ip),
         ]);
 
         let update_message_path_attributes = Arc::new(vec![
             PathAttribute::Origin(Origin::Igp),
-            PathAttribute::AsPath(AsPath::AsSequence(vec![some_as, local_as])),
+            PathAttribute::AsPath(AsPath::from_sequence(vec![
+                local_as, some_as,
+            ])),
             PathAttribute::NextHop(local_ip),
         ]);
         let mut adj_rib_out = AdjRibOut::new();
 
         adj_rib_out.insert(Arc::new(RibEntry {
             network_address: "10.100.220.0/24".parse().unwrap(),
+            labels: vec![],
             path_attributes: rib_path_attributes,
         }));
 
@@ -176,11 +235,74 @@ mod tests {
             vec![],
         );
         assert_eq!(
-            adj_rib_out.create_update_messages(local_ip, local_as),
+            adj_rib_out.create_update_messages(local_ip.into(), local_as),
             vec![expected_update_message]
         );
     }
 
+    #[test]
+    fn multi_segment_as_path_survives_encoding_and_prepend() {
+        let ases = |v: &[u16]| -> Vec<AutonomousSystemNumber> {
+            v.iter().map(|a| (*a).into()).collect()
+        };
+        let mut as_path = AsPath(vec![
+            AsPathSegment::AsSequence(ases(&[64513, 64514])),
+            AsPathSegment::AsSet(ases(&[64515, 64516]).into_iter().collect()),
+            AsPathSegment::AsSequence(ases(&[64517])),
+        ]);
+        assert_eq!(as_path.path_length(), 4);
+        assert!(as_path.does_contain(64516.into()));
+        assert!(as_path.does_contain(64517.into()));
+        assert!(!as_path.does_contain(64512.into()));
+
+        as_path.prepend(64512.into());
+        assert_eq!(
+            as_path.0[0],
+            AsPathSegment::AsSequence(ases(&[64512, 64513, 64514]))
+        );
+
+        let update_message = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(as_path.clone()),
+                PathAttribute::NextHop("10.200.100.3".parse().unwrap()),
+            ]),
+            vec!["10.100.220.0/24".parse().unwrap()],
+            vec![],
+        );
+        let bytes: BytesMut = update_message.clone().into();
+        assert_eq!(UpdateMessage::try_from(bytes).unwrap(), update_message);
+
+        // 255個を超えるAS_SEQUENCEはwire上で複数のsegmentに分割される。
+        let long = AsPath::from_sequence(vec![64513.into(); 300]);
+        let bytes: BytesMut = (&long).into();
+        assert_eq!(AsPath::try_from(&bytes[..]).unwrap().path_length(), 300);
+    }
+
+    #[test]
+    fn confederation_segments_are_encoded_and_removed_for_external_peer() {
+        let ases = |v: &[u16]| -> Vec<AutonomousSystemNumber> {
+            v.iter().map(|a| (*a).into()).collect()
+        };
+        let mut as_path = AsPath::from_sequence(ases(&[64513]));
+        as_path.prepend_confederation(65002.into());
+        as_path.prepend_confederation(65001.into());
+        assert_eq!(
+            as_path.0[0],
+            AsPathSegment::ConfedSequence(ases(&[65001, 65002]))
+        );
+        // confederation内のsegmentはpath lengthに数えない。
+        assert_eq!(as_path.path_length(), 1);
+        assert!(as_path.does_contain(65002.into()));
+
+        let bytes: BytesMut = (&as_path).into();
+        assert_eq!(AsPath::try_from(&bytes[..]).unwrap(), as_path);
+
+        as_path.remove_confederation_segments();
+        as_path.prepend(64512.into());
+        assert_eq!(as_path, AsPath::from_sequence(ases(&[64512, 64513])));
+    }
+
     #[test]
     fn convert_bytes_to_update_message_and_update_message_to_bytes() {
         let some_as: AutonomousSystemNumber = 64513.into();
@@ -191,8 +313,11 @@ mod tests {
 
         let update_message_path_attributes = Arc::new(vec![
             PathAttribute::Origin(Origin::Igp),
-            PathAttribute::AsPath(AsPath::AsSequence(vec![some_as, local_as])),
+            PathAttribute::AsPath(AsPath::from_sequence(vec![
+                some_as, local_as,
+            ])),
             PathAttribute::NextHop(local_ip),
+            PathAttribute::MultiExitDisc(100),
         ]);
 
         let update_message = UpdateMessage::new(
@@ -202,7 +327,471 @@ mod tests {
         );
 
         let update_message_bytes: BytesMut = update_message.clone().into();
-        let update_message2: UpdateMessage = update_message_bytes.try_into().unwrap();
+        let update_message2: UpdateMessage =
+            update_message_bytes.try_into().unwrap();
+        assert_eq!(update_message, update_message2);
+    }
+
+    #[test]
+    fn aggregate_attributes_are_encoded_with_their_flags() {
+        let update_message = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![
+                    64513.into()
+                ])),
+                PathAttribute::NextHop("10.0.100.3".parse().unwrap()),
+                PathAttribute::AtomicAggregate,
+                PathAttribute::Aggregator(
+                    64513.into(),
+                    "10.0.100.3".parse().unwrap(),
+                ),
+            ]),
+            vec!["10.100.0.0/16".parse().unwrap()],
+            vec![],
+        );
+
+        let bytes: BytesMut = update_message.clone().into();
+        // ATOMIC_AGGREGATEはWell-known, AGGREGATORはOptional Transitive。
+        let atomic_aggregate = [0b01000000, 6, 0];
+        let aggregator = [0b11000000, 7, 6, 0xfc, 0x01, 10, 0, 100, 3];
+        assert!(bytes
+            .windows(atomic_aggregate.len())
+            .any(|w| w == atomic_aggregate));
+        assert!(bytes.windows(aggregator.len()).any(|w| w == aggregator));
+        let update_message2: UpdateMessage = bytes.try_into().unwrap();
+        assert_eq!(update_message, update_message2);
+    }
+
+    #[test]
+    fn extended_communities_are_converted_to_bytes_and_back() {
+        let update_message = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![
+                    64513.into()
+                ])),
+                PathAttribute::NextHop("10.0.100.3".parse().unwrap()),
+                PathAttribute::ExtendedCommunities(vec![
+                    "rt:64513:100".parse().unwrap(),
+                    "soo:10.0.100.3:1".parse().unwrap(),
+                    "bandwidth:64513:125000000".parse().unwrap(),
+                ]),
+            ]),
+            vec!["10.100.0.0/16".parse().unwrap()],
+            vec![],
+        );
+
+        let bytes: BytesMut = update_message.clone().into();
+        let route_origin = [0x01, 0x03, 10, 0, 100, 3, 0, 1];
+        assert!(bytes.windows(route_origin.len()).any(|w| w == route_origin));
+        let update_message2: UpdateMessage = bytes.try_into().unwrap();
+        assert_eq!(update_message, update_message2);
+    }
+
+    #[test]
+    fn large_communities_are_converted_to_bytes_and_back() {
+        let update_message = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![
+                    64513.into()
+                ])),
+                PathAttribute::NextHop("10.0.100.3".parse().unwrap()),
+                PathAttribute::LargeCommunities(vec![
+                    "4200000000:1:2".parse().unwrap(),
+                    "64513:100:200".parse().unwrap(),
+                ]),
+            ]),
+            vec!["10.100.0.0/16".parse().unwrap()],
+            vec![],
+        );
+
+        let bytes: BytesMut = update_message.clone().into();
+        let header = [0b11000000, 32, 24];
+        assert!(bytes.windows(header.len()).any(|w| w == header));
+        let update_message2: UpdateMessage = bytes.try_into().unwrap();
+        assert_eq!(update_message, update_message2);
+    }
+
+    #[test]
+    fn convert_bytes_to_ipv6_update_message_and_update_message_to_bytes() {
+        let mut mp_reach_nlri = MpReachNlri::new(
+            AddressFamily::IPV6_UNICAST,
+            "fd00::3".parse().unwrap(),
+            MpNlri::Unicast(vec![
+                "fd00:100::/64".parse().unwrap(),
+                "2001:db8::/33".parse().unwrap(),
+            ]),
+        );
+        mp_reach_nlri.link_local_next_hop = Some("fe80::3".parse().unwrap());
+        let update_message = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![
+                    64513.into()
+                ])),
+                PathAttribute::MpReachNlri(mp_reach_nlri),
+                PathAttribute::MpUnreachNlri(MpUnreachNlri::new(
+                    AddressFamily::IPV6_UNICAST,
+                    MpNlri::Unicast(vec!["fd00:200::/48".parse().unwrap()]),
+                )),
+            ]),
+            vec![],
+            vec![],
+        );
+
+        let update_message_bytes: BytesMut = update_message.clone().into();
+        let update_message2: UpdateMessage =
+            update_message_bytes.try_into().unwrap();
+        assert_eq!(update_message, update_message2);
+    }
+
+    #[test]
+    fn ipv6_update_message_from_adj_rib_out() {
+        let local_as: AutonomousSystemNumber = 64514.into();
+        let local_ip: IpAddr = "fd00::2".parse().unwrap();
+
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.insert(Arc::new(RibEntry {
+            network_address: "fd00:100::/64".parse().unwrap(),
+            labels: vec![],
+            path_attributes: Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![])),
+                PathAttribute::MpReachNlri(MpReachNlri::new(
+                    AddressFamily::IPV6_UNICAST,
+                    "fd00::3".parse().unwrap(),
+                    MpNlri::Unicast(vec![]),
+                )),
+            ]),
+        }));
+
+        let expected_update_message = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![local_as])),
+                PathAttribute::MpReachNlri(MpReachNlri::new(
+                    AddressFamily::IPV6_UNICAST,
+                    local_ip,
+                    MpNlri::Unicast(vec!["fd00:100::/64".parse().unwrap()]),
+                )),
+            ]),
+            vec![],
+            vec![],
+        );
+        assert_eq!(
+            adj_rib_out.create_update_messages(local_ip, local_as),
+            vec![expected_update_message]
+        );
+    }
+
+    #[test]
+    fn convert_bytes_to_vpnv4_update_message_and_update_message_to_bytes() {
+        let update_message = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![
+                    64513.into()
+                ])),
+                PathAttribute::ExtendedCommunities(vec![
+                    ExtendedCommunity::RouteTarget(
+                        "64512:100".parse().unwrap(),
+                    ),
+                ]),
+                PathAttribute::MpReachNlri(MpReachNlri::new(
+                    AddressFamily::IPV4_MPLS_VPN,
+                    "10.200.100.3".parse().unwrap(),
+                    MpNlri::Vpnv4(vec![Vpnv4Prefix {
+                        labels: vec![MplsLabel::new(100).unwrap()],
+                        route_distinguisher: "64513:1".parse().unwrap(),
+                        prefix: "10.1.0.0/16".parse().unwrap(),
+                    }]),
+                )),
+            ]),
+            vec![],
+            vec![],
+        );
+
+        let update_message_bytes: BytesMut = update_message.clone().into();
+        let update_message2: UpdateMessage =
+            update_message_bytes.try_into().unwrap();
         assert_eq!(update_message, update_message2);
     }
+
+    #[test]
+    fn convert_bytes_to_flowspec_update_message_and_update_message_to_bytes() {
+        let update_message = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![
+                    64513.into()
+                ])),
+                PathAttribute::ExtendedCommunities(vec![
+                    ExtendedCommunity::TrafficRate {
+                        asn: 64513,
+                        rate: 0,
+                    },
+                    ExtendedCommunity::TrafficMarking(10),
+                ]),
+                PathAttribute::MpReachNlri(MpReachNlri::new(
+                    AddressFamily::IPV4_FLOWSPEC,
+                    IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    MpNlri::FlowSpec(vec![
+                        "dst:203.0.113.0/24,proto:6,dport:80|443"
+                            .parse()
+                            .unwrap(),
+                    ]),
+                )),
+            ]),
+            vec![],
+            vec![],
+        );
+
+        let update_message_bytes: BytesMut = update_message.clone().into();
+        let update_message2: UpdateMessage =
+            update_message_bytes.try_into().unwrap();
+        assert_eq!(update_message, update_message2);
+    }
+
+    #[test]
+    fn convert_bytes_to_evpn_update_message_and_update_message_to_bytes() {
+        let update_message = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![
+                    64513.into()
+                ])),
+                PathAttribute::ExtendedCommunities(vec![
+                    ExtendedCommunity::RouteTarget(
+                        "64513:10100".parse().unwrap(),
+                    ),
+                    ExtendedCommunity::MacMobility {
+                        sticky: false,
+                        sequence: 1,
+                    },
+                ]),
+                PathAttribute::PmsiTunnel(PmsiTunnel {
+                    flags: 0,
+                    tunnel_type: 6,
+                    label: EvpnLabel(10100),
+                    tunnel_identifier: vec![10, 0, 0, 1],
+                }),
+                PathAttribute::MpReachNlri(MpReachNlri::new(
+                    AddressFamily::L2VPN_EVPN,
+                    "10.0.0.1".parse().unwrap(),
+                    MpNlri::Evpn(vec![
+                        EvpnRoute::InclusiveMulticastEthernetTag {
+                            route_distinguisher: "10.0.0.1:100"
+                                .parse()
+                                .unwrap(),
+                            ethernet_tag: 0,
+                            originating_router: "10.0.0.1".parse().unwrap(),
+                        },
+                    ]),
+                )),
+            ]),
+            vec![],
+            vec![],
+        );
+
+        let update_message_bytes: BytesMut = update_message.clone().into();
+        let update_message2: UpdateMessage =
+            update_message_bytes.try_into().unwrap();
+        assert_eq!(update_message, update_message2);
+    }
+
+    #[test]
+    fn convert_bytes_to_labeled_unicast_update_message_and_update_message_to_bytes(
+    ) {
+        let update_message = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![
+                    64513.into()
+                ])),
+                PathAttribute::MpReachNlri(MpReachNlri::new(
+                    AddressFamily::IPV4_LABELED_UNICAST,
+                    "10.200.100.3".parse().unwrap(),
+                    MpNlri::LabeledUnicast(vec![LabeledPrefix {
+                        labels: vec![MplsLabel::new(100).unwrap()],
+                        prefix: "10.100.220.0/24".parse().unwrap(),
+                    }]),
+                )),
+            ]),
+            vec![],
+            vec![],
+        );
+
+        let update_message_bytes: BytesMut = update_message.clone().into();
+        let update_message2: UpdateMessage =
+            update_message_bytes.try_into().unwrap();
+        assert_eq!(update_message, update_message2);
+    }
+
+    #[test]
+    fn convert_bytes_to_link_state_update_message_and_update_message_to_bytes()
+    {
+        let update_message = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![
+                    64513.into()
+                ])),
+                PathAttribute::LinkState(LinkStateAttribute(vec![Tlv {
+                    type_: 1026,
+                    value: b"host1".to_vec(),
+                }])),
+                PathAttribute::MpReachNlri(MpReachNlri::new(
+                    AddressFamily::LINK_STATE,
+                    "10.200.100.3".parse().unwrap(),
+                    MpNlri::LinkState(vec![LinkStateNlri::Node {
+                        protocol_id: 3,
+                        identifier: 0,
+                        local_node: NodeDescriptors(vec![Tlv {
+                            type_: 515,
+                            value: vec![10, 0, 0, 1],
+                        }]),
+                    }]),
+                )),
+            ]),
+            vec![],
+            vec![],
+        );
+
+        let update_message_bytes: BytesMut = update_message.clone().into();
+        let update_message2: UpdateMessage =
+            update_message_bytes.try_into().unwrap();
+        assert_eq!(update_message, update_message2);
+    }
+
+    #[test]
+    fn prefix_sid_survives_re_advertisement() {
+        let local_as: AutonomousSystemNumber = 64514.into();
+        let local_ip: IpAddr = "10.200.100.3".parse().unwrap();
+        let prefix_sid = PrefixSid(vec![
+            PrefixSidTlv::LabelIndex {
+                flags: 0,
+                label_index: 100,
+            },
+            PrefixSidTlv::Unknown {
+                type_: 200,
+                value: vec![1, 2, 3],
+            },
+        ]);
+
+        let received = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![
+                    64513.into()
+                ])),
+                PathAttribute::NextHop("10.0.100.3".parse().unwrap()),
+                PathAttribute::PrefixSid(prefix_sid.clone()),
+            ]),
+            vec!["10.100.220.0/24".parse().unwrap()],
+            vec![],
+        );
+        let bytes: BytesMut = received.clone().into();
+        let received: UpdateMessage = bytes.try_into().unwrap();
+
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.insert(Arc::new(RibEntry {
+            network_address: "10.100.220.0/24".parse().unwrap(),
+            labels: vec![],
+            path_attributes: Arc::clone(&received.path_attributes),
+        }));
+        let updates = adj_rib_out.create_update_messages(local_ip, local_as);
+        assert_eq!(updates.len(), 1);
+        assert!(updates[0]
+            .path_attributes
+            .contains(&PathAttribute::PrefixSid(prefix_sid)));
+    }
+
+    #[test]
+    fn malformed_update_is_rejected_with_update_message_error() {
+        use crate::packets::message::Message;
+
+        let update = |withdrawn: &[u8], attributes: &[u8], nlri: &[u8]| {
+            let length = 23 + withdrawn.len() + attributes.len() + nlri.len();
+            let mut bytes = BytesMut::from(&[0xFF; 16][..]);
+            bytes.put_u16(length as u16);
+            bytes.put_u8(2);
+            bytes.put_u16(withdrawn.len() as u16);
+            bytes.extend_from_slice(withdrawn);
+            bytes.put_u16(attributes.len() as u16);
+            bytes.extend_from_slice(attributes);
+            bytes.extend_from_slice(nlri);
+            bytes
+        };
+        let origin = [0x40, 1, 1, 0];
+        let cases = [
+            // Path Attributeのheaderが途中で終わっている。
+            (
+                update(&[], &[0x40, 1], &[]),
+                NotificationMessage::MALFORMED_ATTRIBUTE_LIST,
+                vec![],
+            ),
+            // Extended Lengthのheaderが途中で終わっている。
+            (
+                update(&[], &[0x50, 2, 0], &[]),
+                NotificationMessage::MALFORMED_ATTRIBUTE_LIST,
+                vec![],
+            ),
+            // Attribute LengthがPath Attributesの長さを超えている。
+            (
+                update(&[], &[0x40, 2, 10, 2, 1], &[]),
+                NotificationMessage::MALFORMED_ATTRIBUTE_LIST,
+                vec![],
+            ),
+            // ORIGINの長さが1ではない。
+            (
+                update(&[], &[0x40, 1, 0], &[]),
+                NotificationMessage::ATTRIBUTE_LENGTH_ERROR,
+                vec![0x40, 1, 0],
+            ),
+            // NEXT_HOPの長さが4ではない。
+            (
+                update(
+                    &[],
+                    &[&origin[..], &[0x40, 3, 2, 10, 1]].concat(),
+                    &[],
+                ),
+                NotificationMessage::ATTRIBUTE_LENGTH_ERROR,
+                vec![0x40, 3, 2, 10, 1],
+            ),
+            // NLRIのprefixのbytesが足りない。
+            (
+                update(&[], &origin, &[24, 10, 1]),
+                NotificationMessage::INVALID_NETWORK_FIELD,
+                vec![],
+            ),
+            // Withdrawn Routesのprefixのbytesが足りない。
+            (
+                update(&[32, 10, 1, 2], &[], &[]),
+                NotificationMessage::INVALID_NETWORK_FIELD,
+                vec![],
+            ),
+        ];
+        for (bytes, error_subcode, data) in cases {
+            let error = Message::try_from(bytes.clone()).unwrap_err();
+            let notification = error.notification().unwrap_or_else(|| {
+                panic!("no notification for {:?}: {:?}", bytes, error)
+            });
+            assert_eq!(
+                (
+                    notification.error_code,
+                    notification.error_subcode,
+                    &notification.data
+                ),
+                (
+                    NotificationMessage::UPDATE_MESSAGE_ERROR,
+                    error_subcode,
+                    &data
+                ),
+                "{:?}",
+                bytes
+            );
+        }
+    }
 }
@@ -2,15 +2,50 @@ use anyhow::Context;
 use bytes::{BufMut, BytesMut};
 
 use crate::{
-    bgp_type::AutonomousSystemNumber, error::ConvertBytesToBgpMessageError,
+    bgp_ls::{LinkStateAttribute, LinkStateNlri},
+    bgp_type::{AddressFamily, Afi, AutonomousSystemNumber, Safi},
+    error::{ConfigParseError, ConvertBytesToBgpMessageError},
+    evpn::{EvpnLabel, EvpnRoute, MacAddress},
+    flowspec::FlowSpecRule,
+    packets::notification::NotificationMessage,
+    prefix_sid::PrefixSid,
+    routing::{IpNetwork, Ipv4Network, Ipv6Network, LabeledPrefix},
+    vpn::{
+        RouteDistinguisher, RouteOrigin, RouteTarget, RouteTargetMembership,
+        Vpnv4Prefix,
+    },
+};
+use std::{
+    collections::BTreeSet,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
 };
-use std::{collections::BTreeSet, net::Ipv4Addr};
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
 pub enum PathAttribute {
     Origin(Origin),
     AsPath(AsPath),
     NextHop(Ipv4Addr),
+    MultiExitDisc(u32),
+    // iBGPのPeerの間でだけ交換する、AS内でのルートの優先度。大きいほど優先する。
+    LocalPref(u32),
+    // 集約により、AS_PATHに含まれていたASの情報が失われたルートであること。
+    AtomicAggregate,
+    // ルートを集約したスピーカーのAS番号とBGP Identifier。
+    Aggregator(AutonomousSystemNumber, Ipv4Addr),
+    MpReachNlri(MpReachNlri),
+    MpUnreachNlri(MpUnreachNlri),
+    Communities(Vec<Community>),
+    // route reflectorがiBGPのPeerに反射したルートの、最初の送信元のBGP Identifier。
+    OriginatorId(Ipv4Addr),
+    // ルートを反射したroute reflectorのcluster id。先頭が最も新しい。
+    ClusterList(Vec<Ipv4Addr>),
+    ExtendedCommunities(Vec<ExtendedCommunity>),
+    PmsiTunnel(PmsiTunnel),
+    LargeCommunities(Vec<LargeCommunity>),
+    LinkState(LinkStateAttribute),
+    PrefixSid(PrefixSid),
     DontKnow(Vec<u8>), // 対応してないPathAttribute用
 }
 
@@ -20,6 +55,20 @@ impl PathAttribute {
             PathAttribute::Origin(o) => 1,
             PathAttribute::AsPath(a) => a.bytes_len(),
             PathAttribute::NextHop(_) => 4,
+            PathAttribute::MultiExitDisc(_) => 4,
+            PathAttribute::LocalPref(_) => 4,
+            PathAttribute::AtomicAggregate => 0,
+            PathAttribute::Aggregator(_, _) => 6,
+            PathAttribute::MpReachNlri(m) => m.bytes_len(),
+            PathAttribute::MpUnreachNlri(m) => m.bytes_len(),
+            PathAttribute::Communities(c) => 4 * c.len(),
+            PathAttribute::OriginatorId(_) => 4,
+            PathAttribute::ClusterList(c) => 4 * c.len(),
+            PathAttribute::ExtendedCommunities(c) => 8 * c.len(),
+            PathAttribute::PmsiTunnel(p) => p.bytes_len(),
+            PathAttribute::LargeCommunities(c) => 12 * c.len(),
+            PathAttribute::LinkState(a) => a.bytes_len(),
+            PathAttribute::PrefixSid(p) => p.bytes_len(),
             PathAttribute::DontKnow(v) => v.len(),
         };
         // flagを表すoctet, typeを表すoctet分を追加。
@@ -42,18 +91,55 @@ impl PathAttribute {
             let attribute_flag = bytes[i];
             let attribute_length_octets =
                 ((attribute_flag & 0b00010000) >> 4) + 1;
+            let attribute_start_index =
+                i + 1 + attribute_length_octets as usize + 1;
+            if attribute_start_index > bytes.len() {
+                return Err(malformed_attribute(
+                    NotificationMessage::MALFORMED_ATTRIBUTE_LIST,
+                    vec![],
+                    format!(
+                        "offset {}: Path Attributeのheaderの長さが足りません。",
+                        i
+                    ),
+                ));
+            }
             let attribute_type_code = bytes[i + 1];
             let attribute_length = if attribute_length_octets == 1 {
                 bytes[i + 2] as usize
             } else {
-                u16::from_be_bytes(
-                    bytes[i + 2..i + 4].try_into().context("aaa")?,
-                ) as usize
+                u16::from_be_bytes([bytes[i + 2], bytes[i + 3]]) as usize
             };
-
-            let attribute_start_index =
-                i + 1 + attribute_length_octets as usize + 1;
             let attribute_end_index = attribute_start_index + attribute_length;
+            if attribute_end_index > bytes.len() {
+                return Err(malformed_attribute(
+                    NotificationMessage::MALFORMED_ATTRIBUTE_LIST,
+                    vec![],
+                    format!(
+                        "type {}のPath Attributeの長さ{}が\
+                         Path Attributesの長さを超えています。",
+                        attribute_type_code, attribute_length
+                    ),
+                ));
+            }
+            // 長さの決まっているPath Attributeの長さが誤っている場合は、
+            // そのPath AttributeをdataにしたAttribute Length Errorにする。
+            // (RFC4271 Section 6.3)
+            let expected_length = match attribute_type_code {
+                1 => Some(1),
+                3..=5 => Some(4),
+                6 => Some(0),
+                _ => None,
+            };
+            if expected_length.is_some_and(|l| l != attribute_length) {
+                return Err(malformed_attribute(
+                    NotificationMessage::ATTRIBUTE_LENGTH_ERROR,
+                    bytes[i..attribute_end_index].to_vec(),
+                    format!(
+                        "type {}のPath Attributeの長さ{}が不正です。",
+                        attribute_type_code, attribute_length
+                    ),
+                ));
+            }
             let path_attribute = match attribute_type_code {
                 1 => PathAttribute::Origin(Origin::try_from(
                     bytes[attribute_start_index],
@@ -61,15 +147,86 @@ impl PathAttribute {
                 2 => PathAttribute::AsPath(AsPath::try_from(
                     &bytes[attribute_start_index..attribute_end_index],
                 )?),
-                3 => {
-                    let addr = Ipv4Addr::new(
-                        bytes[attribute_start_index],
-                        bytes[attribute_start_index + 1],
-                        bytes[attribute_start_index + 2],
-                        bytes[attribute_start_index + 3],
-                    );
-                    PathAttribute::NextHop(addr)
+                3 => PathAttribute::NextHop(Ipv4Addr::from(
+                    <[u8; 4]>::try_from(
+                        &bytes[attribute_start_index..attribute_end_index],
+                    )
+                    .context("NEXT_HOPのbytes表現が不正です。")?,
+                )),
+                4 => PathAttribute::MultiExitDisc(u32::from_be_bytes(
+                    bytes[attribute_start_index..attribute_end_index]
+                        .try_into()
+                        .context("MEDのbytes表現が不正です。")?,
+                )),
+                5 => PathAttribute::LocalPref(u32::from_be_bytes(
+                    bytes[attribute_start_index..attribute_end_index]
+                        .try_into()
+                        .context("LOCAL_PREFのbytes表現が不正です。")?,
+                )),
+                6 => PathAttribute::AtomicAggregate,
+                7 if attribute_length == 6 => {
+                    let value =
+                        &bytes[attribute_start_index..attribute_end_index];
+                    PathAttribute::Aggregator(
+                        u16::from_be_bytes([value[0], value[1]]).into(),
+                        Ipv4Addr::new(value[2], value[3], value[4], value[5]),
+                    )
                 }
+                // 対応していないaddress familyのものはDontKnowとして扱う。
+                14 if AddressFamily::try_from(
+                    &bytes[attribute_start_index..attribute_end_index],
+                )
+                .is_ok() =>
+                {
+                    PathAttribute::MpReachNlri(MpReachNlri::try_from(
+                        &bytes[attribute_start_index..attribute_end_index],
+                    )?)
+                }
+                15 if AddressFamily::try_from(
+                    &bytes[attribute_start_index..attribute_end_index],
+                )
+                .is_ok() =>
+                {
+                    PathAttribute::MpUnreachNlri(MpUnreachNlri::try_from(
+                        &bytes[attribute_start_index..attribute_end_index],
+                    )?)
+                }
+                8 => PathAttribute::Communities(Community::from_u8_slice(
+                    &bytes[attribute_start_index..attribute_end_index],
+                )?),
+                9 if attribute_length == 4 => {
+                    PathAttribute::OriginatorId(Ipv4Addr::from(
+                        <[u8; 4]>::try_from(
+                            &bytes[attribute_start_index..attribute_end_index],
+                        )
+                        .context("ORIGINATOR_IDのbytes表現が不正です。")?,
+                    ))
+                }
+                10 if attribute_length % 4 == 0 => PathAttribute::ClusterList(
+                    bytes[attribute_start_index..attribute_end_index]
+                        .chunks(4)
+                        .map(|c| Ipv4Addr::new(c[0], c[1], c[2], c[3]))
+                        .collect(),
+                ),
+                16 => PathAttribute::ExtendedCommunities(
+                    ExtendedCommunity::from_u8_slice(
+                        &bytes[attribute_start_index..attribute_end_index],
+                    )?,
+                ),
+                22 => PathAttribute::PmsiTunnel(PmsiTunnel::try_from(
+                    &bytes[attribute_start_index..attribute_end_index],
+                )?),
+                29 => PathAttribute::LinkState(LinkStateAttribute::try_from(
+                    &bytes[attribute_start_index..attribute_end_index],
+                )?),
+                32 => PathAttribute::LargeCommunities(
+                    LargeCommunity::from_u8_slice(
+                        &bytes[attribute_start_index..attribute_end_index],
+                    )?,
+                ),
+                40 => PathAttribute::PrefixSid(PrefixSid::try_from(
+                    &bytes[attribute_start_index..attribute_end_index],
+                )?),
                 _ => PathAttribute::DontKnow(
                     bytes[i..attribute_end_index].to_owned(),
                 ),
@@ -81,6 +238,20 @@ impl PathAttribute {
     }
 }
 
+/// Path Attributesを解釈できなかった場合の、UPDATE Message Errorのsubcodeを持つエラー。
+fn malformed_attribute(
+    error_subcode: u8,
+    data: Vec<u8>,
+    message: String,
+) -> ConvertBytesToBgpMessageError {
+    ConvertBytesToBgpMessageError::from(anyhow::anyhow!(message))
+        .with_notification(NotificationMessage::new(
+            NotificationMessage::UPDATE_MESSAGE_ERROR,
+            error_subcode,
+            data,
+        ))
+}
+
 impl From<&PathAttribute> for BytesMut {
     fn from(p: &PathAttribute) -> BytesMut {
         let mut bytes = BytesMut::new();
@@ -145,19 +316,964 @@ impl From<&PathAttribute> for BytesMut {
                 bytes.put_u8(attribute_length);
                 bytes.put(&attribute[..]);
             }
+            PathAttribute::MultiExitDisc(med) => {
+                let attribute_flag = 0b10000000;
+                let attribute_type_code = 4;
+                put_attribute_header(
+                    &mut bytes,
+                    attribute_flag,
+                    attribute_type_code,
+                    4,
+                );
+                bytes.put_u32(*med);
+            }
+            PathAttribute::LocalPref(local_pref) => {
+                let attribute_flag = 0b01000000;
+                let attribute_type_code = 5;
+                put_attribute_header(
+                    &mut bytes,
+                    attribute_flag,
+                    attribute_type_code,
+                    4,
+                );
+                bytes.put_u32(*local_pref);
+            }
+            PathAttribute::AtomicAggregate => {
+                let attribute_flag = 0b01000000;
+                let attribute_type_code = 6;
+                put_attribute_header(
+                    &mut bytes,
+                    attribute_flag,
+                    attribute_type_code,
+                    0,
+                );
+            }
+            PathAttribute::Aggregator(as_number, address) => {
+                let attribute_flag = 0b11000000;
+                let attribute_type_code = 7;
+                put_attribute_header(
+                    &mut bytes,
+                    attribute_flag,
+                    attribute_type_code,
+                    6,
+                );
+                bytes.put_u16(u16::from(*as_number));
+                bytes.put(&address.octets()[..]);
+            }
+            PathAttribute::MpReachNlri(m) => {
+                let attribute_flag = 0b10000000;
+                let attribute_type_code = 14;
+                put_attribute_header(
+                    &mut bytes,
+                    attribute_flag,
+                    attribute_type_code,
+                    m.bytes_len(),
+                );
+                bytes.put(BytesMut::from(m));
+            }
+            PathAttribute::MpUnreachNlri(m) => {
+                let attribute_flag = 0b10000000;
+                let attribute_type_code = 15;
+                put_attribute_header(
+                    &mut bytes,
+                    attribute_flag,
+                    attribute_type_code,
+                    m.bytes_len(),
+                );
+                bytes.put(BytesMut::from(m));
+            }
+            PathAttribute::Communities(c) => {
+                let attribute_flag = 0b11000000;
+                let attribute_type_code = 8;
+                put_attribute_header(
+                    &mut bytes,
+                    attribute_flag,
+                    attribute_type_code,
+                    4 * c.len(),
+                );
+                c.iter().for_each(|c| bytes.put_u32(c.0));
+            }
+            PathAttribute::OriginatorId(o) => {
+                let attribute_flag = 0b10000000;
+                let attribute_type_code = 9;
+                put_attribute_header(
+                    &mut bytes,
+                    attribute_flag,
+                    attribute_type_code,
+                    4,
+                );
+                bytes.put(&o.octets()[..]);
+            }
+            PathAttribute::ClusterList(c) => {
+                let attribute_flag = 0b10000000;
+                let attribute_type_code = 10;
+                put_attribute_header(
+                    &mut bytes,
+                    attribute_flag,
+                    attribute_type_code,
+                    4 * c.len(),
+                );
+                c.iter().for_each(|c| bytes.put(&c.octets()[..]));
+            }
+            PathAttribute::ExtendedCommunities(c) => {
+                let attribute_flag = 0b11000000;
+                let attribute_type_code = 16;
+                put_attribute_header(
+                    &mut bytes,
+                    attribute_flag,
+                    attribute_type_code,
+                    8 * c.len(),
+                );
+                c.iter().for_each(|c| bytes.put(&<[u8; 8]>::from(c)[..]));
+            }
+            PathAttribute::PmsiTunnel(p) => {
+                let attribute_flag = 0b11000000;
+                let attribute_type_code = 22;
+                put_attribute_header(
+                    &mut bytes,
+                    attribute_flag,
+                    attribute_type_code,
+                    p.bytes_len(),
+                );
+                bytes.put_u8(p.flags);
+                bytes.put_u8(p.tunnel_type);
+                bytes.put(&p.label.to_bytes()[..]);
+                bytes.put(&p.tunnel_identifier[..]);
+            }
+            PathAttribute::LinkState(a) => {
+                let attribute_flag = 0b10000000;
+                let attribute_type_code = 29;
+                put_attribute_header(
+                    &mut bytes,
+                    attribute_flag,
+                    attribute_type_code,
+                    a.bytes_len(),
+                );
+                bytes.put::<BytesMut>(a.into());
+            }
+            PathAttribute::LargeCommunities(c) => {
+                let attribute_flag = 0b11000000;
+                let attribute_type_code = 32;
+                put_attribute_header(
+                    &mut bytes,
+                    attribute_flag,
+                    attribute_type_code,
+                    12 * c.len(),
+                );
+                c.iter().for_each(|c| {
+                    bytes.put_u32(c.global_administrator);
+                    bytes.put_u32(c.local_data1);
+                    bytes.put_u32(c.local_data2);
+                });
+            }
+            PathAttribute::PrefixSid(p) => {
+                let attribute_flag = 0b11000000;
+                let attribute_type_code = 40;
+                put_attribute_header(
+                    &mut bytes,
+                    attribute_flag,
+                    attribute_type_code,
+                    p.bytes_len(),
+                );
+                bytes.put::<BytesMut>(p.into());
+            }
             PathAttribute::DontKnow(v) => bytes.put(&v[..]),
         }
         bytes
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+/// Attribute Flag, Attribute Type Code, Attribute Lengthを書き込む。
+/// Attribute Lengthが255を超える場合はExtended Lengthのbitを立てて2 octetsで表す。
+fn put_attribute_header(
+    bytes: &mut BytesMut,
+    attribute_flag: u8,
+    attribute_type_code: u8,
+    attribute_length: usize,
+) {
+    if attribute_length > 255 {
+        bytes.put_u8(attribute_flag | 0b00010000);
+        bytes.put_u8(attribute_type_code);
+        bytes.put_u16(attribute_length as u16);
+    } else {
+        bytes.put_u8(attribute_flag);
+        bytes.put_u8(attribute_type_code);
+        bytes.put_u8(attribute_length as u8);
+    }
+}
+
+/// MP_REACH_NLRI, MP_UNREACH_NLRIに含まれるNLRIです。
+/// address family毎にNLRIの形式が異なります。
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+pub enum MpNlri {
+    Unicast(Vec<IpNetwork>),
+    LabeledUnicast(Vec<LabeledPrefix>),
+    Vpnv4(Vec<Vpnv4Prefix>),
+    FlowSpec(Vec<FlowSpecRule>),
+    Evpn(Vec<EvpnRoute>),
+    LinkState(Vec<LinkStateNlri>),
+    RouteTargetConstraint(Vec<RouteTargetMembership>),
+}
+
+impl MpNlri {
+    /// address familyに対応するNLRIが空のMpNlriを返す。
+    pub fn empty(address_family: AddressFamily) -> Self {
+        match address_family.safi {
+            Safi::Unicast => MpNlri::Unicast(vec![]),
+            Safi::LabeledUnicast => MpNlri::LabeledUnicast(vec![]),
+            Safi::MplsVpn => MpNlri::Vpnv4(vec![]),
+            Safi::FlowSpec => MpNlri::FlowSpec(vec![]),
+            Safi::Evpn => MpNlri::Evpn(vec![]),
+            Safi::LinkState => MpNlri::LinkState(vec![]),
+            Safi::RouteTargetConstraint => {
+                MpNlri::RouteTargetConstraint(vec![])
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            MpNlri::Unicast(v) => v.is_empty(),
+            MpNlri::LabeledUnicast(v) => v.is_empty(),
+            MpNlri::Vpnv4(v) => v.is_empty(),
+            MpNlri::FlowSpec(v) => v.is_empty(),
+            MpNlri::Evpn(v) => v.is_empty(),
+            MpNlri::LinkState(v) => v.is_empty(),
+            MpNlri::RouteTargetConstraint(v) => v.is_empty(),
+        }
+    }
+
+    fn bytes_len(&self) -> usize {
+        match self {
+            MpNlri::Unicast(v) => v.iter().map(|n| n.bytes_len()).sum(),
+            MpNlri::LabeledUnicast(v) => v.iter().map(|n| n.bytes_len()).sum(),
+            MpNlri::Vpnv4(v) => v.iter().map(|n| n.bytes_len()).sum(),
+            MpNlri::FlowSpec(v) => v.iter().map(|r| r.bytes_len()).sum(),
+            MpNlri::Evpn(v) => v.iter().map(|r| r.bytes_len()).sum(),
+            MpNlri::LinkState(v) => v.iter().map(|n| n.bytes_len()).sum(),
+            MpNlri::RouteTargetConstraint(v) => {
+                v.iter().map(|m| m.bytes_len()).sum()
+            }
+        }
+    }
+
+    /// address familyに応じてNLRIのbytes表現を変換する。
+    fn from_u8_slice(
+        address_family: AddressFamily,
+        bytes: &[u8],
+    ) -> Result<Self, ConvertBytesToBgpMessageError> {
+        Ok(match (address_family.afi, address_family.safi) {
+            (Afi::Ipv4, Safi::Unicast) => MpNlri::Unicast(
+                Ipv4Network::from_u8_slice(bytes)?
+                    .into_iter()
+                    .map(IpNetwork::from)
+                    .collect(),
+            ),
+            (Afi::Ipv6, Safi::Unicast) => MpNlri::Unicast(
+                Ipv6Network::from_u8_slice(bytes)?
+                    .into_iter()
+                    .map(IpNetwork::from)
+                    .collect(),
+            ),
+            (afi @ (Afi::Ipv4 | Afi::Ipv6), Safi::LabeledUnicast) => {
+                MpNlri::LabeledUnicast(LabeledPrefix::from_u8_slice(
+                    afi, bytes,
+                )?)
+            }
+            (Afi::Ipv4, Safi::MplsVpn) => {
+                MpNlri::Vpnv4(Vpnv4Prefix::from_u8_slice(bytes)?)
+            }
+            (Afi::Ipv4, Safi::FlowSpec) => {
+                MpNlri::FlowSpec(FlowSpecRule::from_u8_slice(bytes)?)
+            }
+            (Afi::Ipv6, Safi::MplsVpn) => {
+                return Err(ConvertBytesToBgpMessageError::from(
+                    anyhow::anyhow!("VPNv6には対応していません。"),
+                ))
+            }
+            (Afi::L2vpn, Safi::Evpn) => {
+                MpNlri::Evpn(EvpnRoute::from_u8_slice(bytes)?)
+            }
+            (Afi::LinkState, Safi::LinkState) => {
+                MpNlri::LinkState(LinkStateNlri::from_u8_slice(bytes)?)
+            }
+            (Afi::Ipv4, Safi::RouteTargetConstraint) => {
+                MpNlri::RouteTargetConstraint(
+                    RouteTargetMembership::from_u8_slice(bytes)?,
+                )
+            }
+            (afi, safi) => {
+                return Err(ConvertBytesToBgpMessageError::from(
+                    anyhow::anyhow!(
+                        "AFI {:?}, SAFI {:?}の組には対応していません。",
+                        afi,
+                        safi
+                    ),
+                ))
+            }
+        })
+    }
+}
+
+impl From<&MpNlri> for BytesMut {
+    fn from(nlri: &MpNlri) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        match nlri {
+            MpNlri::Unicast(v) => {
+                v.iter().for_each(|n| bytes.put::<BytesMut>(n.into()))
+            }
+            MpNlri::LabeledUnicast(v) => {
+                v.iter().for_each(|n| bytes.put::<BytesMut>(n.into()))
+            }
+            MpNlri::Vpnv4(v) => {
+                v.iter().for_each(|n| bytes.put::<BytesMut>(n.into()))
+            }
+            MpNlri::FlowSpec(v) => {
+                v.iter().for_each(|r| bytes.put::<BytesMut>(r.into()))
+            }
+            MpNlri::Evpn(v) => {
+                v.iter().for_each(|r| bytes.put::<BytesMut>(r.into()))
+            }
+            MpNlri::LinkState(v) => {
+                v.iter().for_each(|n| bytes.put::<BytesMut>(n.into()))
+            }
+            MpNlri::RouteTargetConstraint(v) => {
+                v.iter().for_each(|m| bytes.put::<BytesMut>(m.into()))
+            }
+        }
+        bytes
+    }
+}
+
+/// MP_REACH_NLRI (RFC4760)。
+/// IPv4 unicast以外のaddress familyのルートはこのPathAttributeで広報される。
+/// RibEntryに保持するときはnlriを空にして、next hopを運ぶためにだけ使う。
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+pub struct MpReachNlri {
+    pub address_family: AddressFamily,
+    pub next_hop: IpAddr,
+    // IPv6ではglobal addressに加えてlink-local addressが含まれることがある。
+    pub link_local_next_hop: Option<Ipv6Addr>,
+    pub nlri: MpNlri,
+}
+
+impl MpReachNlri {
+    pub fn new(
+        address_family: AddressFamily,
+        next_hop: IpAddr,
+        nlri: MpNlri,
+    ) -> Self {
+        Self {
+            address_family,
+            next_hop,
+            link_local_next_hop: None,
+            nlri,
+        }
+    }
+
+    fn next_hop_bytes_len(&self) -> usize {
+        // FlowSpecはnext hopを持たない。(RFC8955 Section 4)
+        if self.address_family.safi == Safi::FlowSpec {
+            return 0;
+        }
+        let global = match self.next_hop {
+            IpAddr::V4(_) => 4,
+            IpAddr::V6(_) => 16,
+        };
+        let link_local = if self.link_local_next_hop.is_some() {
+            16
+        } else {
+            0
+        };
+        // VPNのnext hopの前にはRoute Distinguisher(8 octets)が付く。
+        let route_distinguisher = if self.address_family.safi == Safi::MplsVpn
+        {
+            8
+        } else {
+            0
+        };
+        route_distinguisher + global + link_local
+    }
+
+    fn bytes_len(&self) -> usize {
+        // AFI(2) + SAFI(1) + next hopの長さ(1) + next hop + Reserved(1) + NLRI
+        2 + 1 + 1 + self.next_hop_bytes_len() + 1 + self.nlri.bytes_len()
+    }
+}
+
+impl From<&MpReachNlri> for BytesMut {
+    fn from(m: &MpReachNlri) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        bytes.put_u16(m.address_family.afi.into());
+        bytes.put_u8(m.address_family.safi.into());
+        bytes.put_u8(m.next_hop_bytes_len() as u8);
+        if m.address_family.safi == Safi::MplsVpn {
+            bytes.put::<BytesMut>((&RouteDistinguisher::ZERO).into());
+        }
+        match m.next_hop {
+            _ if m.address_family.safi == Safi::FlowSpec => {}
+            IpAddr::V4(a) => bytes.put(&a.octets()[..]),
+            IpAddr::V6(a) => bytes.put(&a.octets()[..]),
+        }
+        if let Some(a) = m.link_local_next_hop {
+            bytes.put(&a.octets()[..]);
+        }
+        bytes.put_u8(0); // Reserved
+        bytes.put::<BytesMut>((&m.nlri).into());
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for MpReachNlri {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let address_family = AddressFamily::try_from(value)?;
+        if value.len() < 4 {
+            return Err(Self::Error::from(anyhow::anyhow!(
+                "MP_REACH_NLRIの長さが足りません。"
+            )));
+        }
+        let next_hop_length = value[3] as usize;
+        let next_hop_end_index = 4 + next_hop_length;
+        // next hopの後ろにReserved(1 octet)がある。
+        if value.len() < next_hop_end_index + 1 {
+            return Err(Self::Error::from(anyhow::anyhow!(
+                "MP_REACH_NLRIのnext hopの長さが不正です。"
+            )));
+        }
+        let next_hop_bytes = &value[4..next_hop_end_index];
+        // VPNのnext hopの前に付くRoute Distinguisher(8 octets)は読み飛ばす。
+        let next_hop_bytes = if address_family.safi == Safi::MplsVpn
+            && next_hop_bytes.len() >= 8
+        {
+            &next_hop_bytes[8..]
+        } else {
+            next_hop_bytes
+        };
+        let (next_hop, link_local_next_hop) = match next_hop_bytes.len() {
+            // FlowSpecではnext hopの長さが0になる。
+            0 if address_family.safi == Safi::FlowSpec => {
+                (IpAddr::V4(Ipv4Addr::UNSPECIFIED), None)
+            }
+            4 => {
+                let b: [u8; 4] = next_hop_bytes
+                    .try_into()
+                    .context("next hopを取得できませんでした。")?;
+                (IpAddr::V4(Ipv4Addr::from(b)), None)
+            }
+            16 => {
+                let b: [u8; 16] = next_hop_bytes
+                    .try_into()
+                    .context("next hopを取得できませんでした。")?;
+                (IpAddr::V6(Ipv6Addr::from(b)), None)
+            }
+            32 => {
+                let global: [u8; 16] = next_hop_bytes[0..16]
+                    .try_into()
+                    .context("next hopを取得できませんでした。")?;
+                let link_local: [u8; 16] =
+                    next_hop_bytes[16..32]
+                        .try_into()
+                        .context("next hopを取得できませんでした。")?;
+                (
+                    IpAddr::V6(Ipv6Addr::from(global)),
+                    Some(Ipv6Addr::from(link_local)),
+                )
+            }
+            _ => {
+                return Err(Self::Error::from(anyhow::anyhow!(
+                    "MP_REACH_NLRIのnext hopの長さ{}には対応していません。",
+                    next_hop_length
+                )))
+            }
+        };
+        let nlri = MpNlri::from_u8_slice(
+            address_family,
+            &value[next_hop_end_index + 1..],
+        )?;
+        Ok(Self {
+            address_family,
+            next_hop,
+            link_local_next_hop,
+            nlri,
+        })
+    }
+}
+
+/// MP_UNREACH_NLRI (RFC4760)。
+/// IPv4 unicast以外のaddress familyのルートの取り消しに使われる。
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+pub struct MpUnreachNlri {
+    pub address_family: AddressFamily,
+    pub withdrawn_routes: MpNlri,
+}
+
+impl MpUnreachNlri {
+    pub fn new(
+        address_family: AddressFamily,
+        withdrawn_routes: MpNlri,
+    ) -> Self {
+        Self {
+            address_family,
+            withdrawn_routes,
+        }
+    }
+
+    fn bytes_len(&self) -> usize {
+        // AFI(2) + SAFI(1) + Withdrawn Routes
+        2 + 1 + self.withdrawn_routes.bytes_len()
+    }
+}
+
+impl From<&MpUnreachNlri> for BytesMut {
+    fn from(m: &MpUnreachNlri) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        bytes.put_u16(m.address_family.afi.into());
+        bytes.put_u8(m.address_family.safi.into());
+        bytes.put::<BytesMut>((&m.withdrawn_routes).into());
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for MpUnreachNlri {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let address_family = AddressFamily::try_from(value)?;
+        let withdrawn_routes =
+            MpNlri::from_u8_slice(address_family, &value[3..])?;
+        Ok(Self {
+            address_family,
+            withdrawn_routes,
+        })
+    }
+}
+
+/// Community (RFC1997)。1つ4 octetsで、上位2 octetsがAS番号、下位2 octetsが値。
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct Community(pub u32);
+
+impl Community {
+    // Well-known Communities (RFC1997)
+    pub const NO_EXPORT: Community = Community(0xFFFFFF01);
+    pub const NO_ADVERTISE: Community = Community(0xFFFFFF02);
+    pub const NO_EXPORT_SUBCONFED: Community = Community(0xFFFFFF03);
+    // Graceful BGP Session Shutdown (RFC8326)
+    pub const GRACEFUL_SHUTDOWN: Community = Community(0xFFFF0000);
+    // Long-Lived Graceful Restart (RFC9494)
+    pub const LLGR_STALE: Community = Community(0xFFFF0006);
+    pub const NO_LLGR: Community = Community(0xFFFF0007);
+    // BLACKHOLE (RFC7999)
+    pub const BLACKHOLE: Community = Community(0xFFFF029A);
+
+    fn from_u8_slice(
+        bytes: &[u8],
+    ) -> Result<Vec<Self>, ConvertBytesToBgpMessageError> {
+        if !bytes.len().is_multiple_of(4) {
+            return Err(ConvertBytesToBgpMessageError::from(anyhow::anyhow!(
+                "Communitiesの長さ{}が4の倍数ではありません。",
+                bytes.len()
+            )));
+        }
+        Ok(bytes
+            .chunks(4)
+            .map(|c| Self(u32::from_be_bytes([c[0], c[1], c[2], c[3]])))
+            .collect())
+    }
+}
+
+impl FromStr for Community {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "no-export" => return Ok(Self::NO_EXPORT),
+            "no-advertise" => return Ok(Self::NO_ADVERTISE),
+            "no-export-subconfed" => return Ok(Self::NO_EXPORT_SUBCONFED),
+            "graceful-shutdown" => return Ok(Self::GRACEFUL_SHUTDOWN),
+            "llgr-stale" => return Ok(Self::LLGR_STALE),
+            "no-llgr" => return Ok(Self::NO_LLGR),
+            "blackhole" => return Ok(Self::BLACKHOLE),
+            _ => {}
+        }
+        let (asn, value) = s.split_once(':').ok_or_else(|| {
+            ConfigParseError::from(anyhow::anyhow!(
+                "communityは<AS番号>:<値>の形式で指定してください: {s}"
+            ))
+        })?;
+        let asn: u16 = asn.parse().context(format!("cannot parse {s}"))?;
+        let value: u16 = value.parse().context(format!("cannot parse {s}"))?;
+        Ok(Self((asn as u32) << 16 | value as u32))
+    }
+}
+
+impl fmt::Display for Community {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::NO_EXPORT => write!(f, "no-export"),
+            Self::NO_ADVERTISE => write!(f, "no-advertise"),
+            Self::NO_EXPORT_SUBCONFED => write!(f, "no-export-subconfed"),
+            Self::GRACEFUL_SHUTDOWN => write!(f, "graceful-shutdown"),
+            Self::LLGR_STALE => write!(f, "llgr-stale"),
+            Self::NO_LLGR => write!(f, "no-llgr"),
+            Self::BLACKHOLE => write!(f, "blackhole"),
+            _ => write!(f, "{}:{}", self.0 >> 16, self.0 & 0xffff),
+        }
+    }
+}
+
+/// Large Community (RFC8092)。1つ12 octetsで、4 octetsのAS番号と2つの値からなる。
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct LargeCommunity {
+    pub global_administrator: u32,
+    pub local_data1: u32,
+    pub local_data2: u32,
+}
+
+impl LargeCommunity {
+    fn from_u8_slice(
+        bytes: &[u8],
+    ) -> Result<Vec<Self>, ConvertBytesToBgpMessageError> {
+        if !bytes.len().is_multiple_of(12) {
+            return Err(ConvertBytesToBgpMessageError::from(anyhow::anyhow!(
+                "Large Communitiesの長さ{}が12の倍数ではありません。",
+                bytes.len()
+            )));
+        }
+        let u32_at = |c: &[u8], i: usize| {
+            u32::from_be_bytes([c[i], c[i + 1], c[i + 2], c[i + 3]])
+        };
+        Ok(bytes
+            .chunks(12)
+            .map(|c| Self {
+                global_administrator: u32_at(c, 0),
+                local_data1: u32_at(c, 4),
+                local_data2: u32_at(c, 8),
+            })
+            .collect())
+    }
+}
+
+impl FromStr for LargeCommunity {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values: Vec<u32> = s
+            .split(':')
+            .map(|v| v.parse())
+            .collect::<Result<_, _>>()
+            .context(format!("cannot parse {s}"))?;
+        match values[..] {
+            [global_administrator, local_data1, local_data2] => Ok(Self {
+                global_administrator,
+                local_data1,
+                local_data2,
+            }),
+            _ => Err(ConfigParseError::from(anyhow::anyhow!(
+                "large communityは<AS番号>:<値>:<値>の形式で指定してください: {s}"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for LargeCommunity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.global_administrator, self.local_data1, self.local_data2
+        )
+    }
+}
+
+/// Extended Community (RFC4360)。1つ8 octets。
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum ExtendedCommunity {
+    RouteTarget(RouteTarget),
+    RouteOrigin(RouteOrigin),
+    // Peerへのリンクの帯域(bytes/秒)。wire上はIEEE floatで表現される。
+    // 複数のeBGPのPeerに負荷を分散する割合を決めるのに使われる。
+    // (draft-ietf-idr-link-bandwidth)
+    LinkBandwidth {
+        asn: u16,
+        bandwidth: u32,
+    },
+    // 以下はFlowSpecのtraffic filtering action (RFC8955 Section 7)
+    // rateはbytes/秒。wire上はIEEE floatで表現される。
+    TrafficRate {
+        asn: u16,
+        rate: u32,
+    },
+    TrafficAction {
+        sample: bool,
+        terminal: bool,
+    },
+    Redirect {
+        asn: u16,
+        value: u32,
+    },
+    TrafficMarking(u8),
+    // 以下はEVPNのExtended Community (RFC7432 Section 7, RFC9135)
+    MacMobility {
+        sticky: bool,
+        sequence: u32,
+    },
+    EsiLabel {
+        single_active: bool,
+        label: EvpnLabel,
+    },
+    EsImportRouteTarget(MacAddress),
+    RouterMac(MacAddress),
+    // 対応していないExtended Community用
+    Unknown([u8; 8]),
+}
+
+impl ExtendedCommunity {
+    pub fn route_target(&self) -> Option<RouteTarget> {
+        match self {
+            ExtendedCommunity::RouteTarget(rt) => Some(*rt),
+            _ => None,
+        }
+    }
+
+    fn from_u8_slice(
+        bytes: &[u8],
+    ) -> Result<Vec<Self>, ConvertBytesToBgpMessageError> {
+        if !bytes.len().is_multiple_of(8) {
+            return Err(ConvertBytesToBgpMessageError::from(anyhow::anyhow!(
+                "Extended Communitiesの長さ{}が8の倍数ではありません。",
+                bytes.len()
+            )));
+        }
+        Ok(bytes
+            .chunks(8)
+            .map(|c| {
+                let b: [u8; 8] = c.try_into().expect("chunkは8 octets");
+                let asn = u16::from_be_bytes([b[2], b[3]]);
+                let value = u32::from_be_bytes([b[4], b[5], b[6], b[7]]);
+                match (b[0], b[1]) {
+                    (0x80, 0x06) => ExtendedCommunity::TrafficRate {
+                        asn,
+                        rate: f32::from_bits(value) as u32,
+                    },
+                    (0x80, 0x07) => ExtendedCommunity::TrafficAction {
+                        sample: b[7] & 0b10 != 0,
+                        terminal: b[7] & 0b01 != 0,
+                    },
+                    (0x80, 0x08) => ExtendedCommunity::Redirect { asn, value },
+                    (0x40, 0x04) => ExtendedCommunity::LinkBandwidth {
+                        asn,
+                        bandwidth: f32::from_bits(value) as u32,
+                    },
+                    (0x80, 0x09) => {
+                        ExtendedCommunity::TrafficMarking(b[7] & 0b00111111)
+                    }
+                    (0x06, 0x00) => ExtendedCommunity::MacMobility {
+                        sticky: b[2] & 0b1 != 0,
+                        sequence: value,
+                    },
+                    (0x06, 0x01) => ExtendedCommunity::EsiLabel {
+                        single_active: b[2] & 0b1 != 0,
+                        label: EvpnLabel(u32::from_be_bytes([
+                            0, b[5], b[6], b[7],
+                        ])),
+                    },
+                    (0x06, 0x02) => {
+                        ExtendedCommunity::EsImportRouteTarget(MacAddress([
+                            b[2], b[3], b[4], b[5], b[6], b[7],
+                        ]))
+                    }
+                    (0x06, 0x03) => {
+                        ExtendedCommunity::RouterMac(MacAddress([
+                            b[2], b[3], b[4], b[5], b[6], b[7],
+                        ]))
+                    }
+                    _ => RouteTarget::from_extended_community_bytes(&b)
+                        .map(ExtendedCommunity::RouteTarget)
+                        .or_else(|| {
+                            RouteOrigin::from_extended_community_bytes(&b)
+                                .map(ExtendedCommunity::RouteOrigin)
+                        })
+                        .unwrap_or(ExtendedCommunity::Unknown(b)),
+                }
+            })
+            .collect())
+    }
+}
+
+impl From<&ExtendedCommunity> for [u8; 8] {
+    fn from(c: &ExtendedCommunity) -> [u8; 8] {
+        match c {
+            ExtendedCommunity::RouteTarget(rt) => {
+                rt.to_extended_community_bytes()
+            }
+            ExtendedCommunity::RouteOrigin(ro) => {
+                ro.to_extended_community_bytes()
+            }
+            ExtendedCommunity::LinkBandwidth { asn, bandwidth } => {
+                let mut b = [0x40, 0x04, 0, 0, 0, 0, 0, 0];
+                b[2..4].copy_from_slice(&asn.to_be_bytes());
+                b[4..8].copy_from_slice(
+                    &(*bandwidth as f32).to_bits().to_be_bytes(),
+                );
+                b
+            }
+            ExtendedCommunity::TrafficRate { asn, rate } => {
+                let mut b = [0x80, 0x06, 0, 0, 0, 0, 0, 0];
+                b[2..4].copy_from_slice(&asn.to_be_bytes());
+                b[4..8]
+                    .copy_from_slice(&(*rate as f32).to_bits().to_be_bytes());
+                b
+            }
+            ExtendedCommunity::TrafficAction { sample, terminal } => [
+                0x80,
+                0x07,
+                0,
+                0,
+                0,
+                0,
+                0,
+                (*sample as u8) << 1 | *terminal as u8,
+            ],
+            ExtendedCommunity::Redirect { asn, value } => {
+                let mut b = [0x80, 0x08, 0, 0, 0, 0, 0, 0];
+                b[2..4].copy_from_slice(&asn.to_be_bytes());
+                b[4..8].copy_from_slice(&value.to_be_bytes());
+                b
+            }
+            ExtendedCommunity::TrafficMarking(dscp) => {
+                [0x80, 0x09, 0, 0, 0, 0, 0, dscp & 0b00111111]
+            }
+            ExtendedCommunity::MacMobility { sticky, sequence } => {
+                let mut b = [0x06, 0x00, *sticky as u8, 0, 0, 0, 0, 0];
+                b[4..8].copy_from_slice(&sequence.to_be_bytes());
+                b
+            }
+            ExtendedCommunity::EsiLabel {
+                single_active,
+                label,
+            } => {
+                let mut b = [0x06, 0x01, *single_active as u8, 0, 0, 0, 0, 0];
+                b[5..8].copy_from_slice(&label.0.to_be_bytes()[1..]);
+                b
+            }
+            ExtendedCommunity::EsImportRouteTarget(mac) => {
+                let mut b = [0x06, 0x02, 0, 0, 0, 0, 0, 0];
+                b[2..8].copy_from_slice(&mac.0);
+                b
+            }
+            ExtendedCommunity::RouterMac(mac) => {
+                let mut b = [0x06, 0x03, 0, 0, 0, 0, 0, 0];
+                b[2..8].copy_from_slice(&mac.0);
+                b
+            }
+            ExtendedCommunity::Unknown(b) => *b,
+        }
+    }
+}
+
+impl FromStr for ExtendedCommunity {
+    type Err = ConfigParseError;
+
+    /// `rt:<administrator>:<値>`, `soo:<administrator>:<値>`,
+    /// `bandwidth:<AS番号>:<bytes/秒>`の形式からparseする。
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = s.split_once(':').ok_or_else(|| {
+            ConfigParseError::from(anyhow::anyhow!(
+                "extended communityは<種類>:<値>の形式で指定してください: {s}"
+            ))
+        })?;
+        match kind {
+            "rt" => Ok(ExtendedCommunity::RouteTarget(value.parse()?)),
+            "soo" => Ok(ExtendedCommunity::RouteOrigin(value.parse()?)),
+            "bandwidth" => {
+                let (asn, bandwidth) = value
+                    .split_once(':')
+                    .context(format!("cannot parse {s}"))?;
+                Ok(ExtendedCommunity::LinkBandwidth {
+                    asn: asn.parse().context(format!("cannot parse {s}"))?,
+                    bandwidth: bandwidth
+                        .parse()
+                        .context(format!("cannot parse {s}"))?,
+                })
+            }
+            _ => Err(ConfigParseError::from(anyhow::anyhow!(
+                "extended communityの種類はrt, soo, bandwidthのいずれかです: {s}"
+            ))),
+        }
+    }
+}
+
+/// P-Multicast Service Interface Tunnel (RFC6514 Section 5)。
+/// EVPNのInclusive Multicast Ethernet Tag Routeに付けられ、
+/// BUMトラフィックの転送方法(例: tunnel type 6はIngress Replication)を表す。
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+pub struct PmsiTunnel {
+    pub flags: u8,
+    pub tunnel_type: u8,
+    pub label: EvpnLabel,
+    pub tunnel_identifier: Vec<u8>,
+}
+
+impl PmsiTunnel {
+    fn bytes_len(&self) -> usize {
+        // Flags(1) + Tunnel Type(1) + MPLS Label(3) + Tunnel Identifier
+        1 + 1 + 3 + self.tunnel_identifier.len()
+    }
+}
+
+impl TryFrom<&[u8]> for PmsiTunnel {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        if value.len() < 5 {
+            return Err(Self::Error::from(anyhow::anyhow!(
+                "PMSI_TUNNELの長さが足りません。"
+            )));
+        }
+        Ok(Self {
+            flags: value[0],
+            tunnel_type: value[1],
+            label: EvpnLabel::from_bytes(&value[2..5]),
+            tunnel_identifier: value[5..].to_vec(),
+        })
+    }
+}
+
+// 経路選択ではIGP, EGP, INCOMPLETEの順に優先されるので、その順に定義している。
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
 pub enum Origin {
     Igp,
     Egp,
     Incomplete,
 }
 
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Igp => write!(f, "igp"),
+            Origin::Egp => write!(f, "egp"),
+            Origin::Incomplete => write!(f, "incomplete"),
+        }
+    }
+}
+
+impl FromStr for Origin {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "igp" => Ok(Origin::Igp),
+            "egp" => Ok(Origin::Egp),
+            "incomplete" => Ok(Origin::Incomplete),
+            _ => Err(ConfigParseError::from(anyhow::anyhow!(
+                "originはigp, egp, incompleteのいずれかで指定してください: {s}"
+            ))),
+        }
+    }
+}
+
 impl TryFrom<u8> for Origin {
     type Error = anyhow::Error;
 
@@ -174,116 +1290,231 @@ impl TryFrom<u8> for Origin {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash)]
-pub enum AsPath {
-    AsSequence(Vec<AutonomousSystemNumber>),
+/// AS_PATH Attribute。AS_SEQUENCEとAS_SETのsegmentを順番に並べたもの。
+/// 先頭のsegmentが最も新しく(自身に近い)ASを表す。
+/// confederation(RFC5065)内では、通ったmember ASをAS_CONFED_SEQUENCEと
+/// AS_CONFED_SETで表す。
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Default, PartialOrd, Ord)]
+pub struct AsPath(pub Vec<AsPathSegment>);
+
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+pub enum AsPathSegment {
     AsSet(BTreeSet<AutonomousSystemNumber>),
+    AsSequence(Vec<AutonomousSystemNumber>),
+    ConfedSequence(Vec<AutonomousSystemNumber>),
+    ConfedSet(BTreeSet<AutonomousSystemNumber>),
+}
+
+// 1つのsegmentに含められるASの最大数。segmentのASの数は1 octetで表現される。
+const MAX_SEGMENT_LENGTH: usize = 255;
+
+/// AS_SEQUENCEは空白区切り、AS_SETは`{64512,64513}`のように表示する。
+/// AS_CONFED_SEQUENCEは`(65001 65002)`、AS_CONFED_SETは`[65001,65002]`のように表示する。
+impl fmt::Display for AsPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |ases: Vec<AutonomousSystemNumber>, separator| {
+            ases.iter()
+                .map(|a| u16::from(*a).to_string())
+                .collect::<Vec<_>>()
+                .join(separator)
+        };
+        let segments: Vec<String> = self
+            .0
+            .iter()
+            .map(|s| match s {
+                AsPathSegment::AsSequence(_) => join(s.ases(), " "),
+                AsPathSegment::AsSet(_) => {
+                    format!("{{{}}}", join(s.ases(), ","))
+                }
+                AsPathSegment::ConfedSequence(_) => {
+                    format!("({})", join(s.ases(), " "))
+                }
+                AsPathSegment::ConfedSet(_) => {
+                    format!("[{}]", join(s.ases(), ","))
+                }
+            })
+            .collect();
+        write!(f, "{}", segments.join(" "))
+    }
+}
+
+impl AsPathSegment {
+    fn type_(&self) -> u8 {
+        match self {
+            AsPathSegment::AsSet(_) => 1,
+            AsPathSegment::AsSequence(_) => 2,
+            AsPathSegment::ConfedSequence(_) => 3,
+            AsPathSegment::ConfedSet(_) => 4,
+        }
+    }
+
+    fn ases(&self) -> Vec<AutonomousSystemNumber> {
+        match self {
+            AsPathSegment::AsSet(s) | AsPathSegment::ConfedSet(s) => {
+                s.iter().copied().collect()
+            }
+            AsPathSegment::AsSequence(s)
+            | AsPathSegment::ConfedSequence(s) => s.clone(),
+        }
+    }
+
+    fn is_confederation(&self) -> bool {
+        matches!(
+            self,
+            AsPathSegment::ConfedSequence(_) | AsPathSegment::ConfedSet(_)
+        )
+    }
 }
 
 impl From<&AsPath> for BytesMut {
     fn from(as_path: &AsPath) -> BytesMut {
-        match as_path {
-            AsPath::AsSet(s) => {
-                let mut bytes = BytesMut::new();
-
-                let path_segment_type = 1;
-                let number_of_ases = s.len();
-                bytes.put_u8(path_segment_type);
-                bytes.put_u8(number_of_ases as u8);
-                bytes.put(
-                    &s.iter()
-                        .flat_map(|a| u16::from(*a).to_be_bytes())
-                        .collect::<Vec<u8>>()[..],
-                );
-                bytes
-            }
-            AsPath::AsSequence(s) => {
-                let mut bytes = BytesMut::new();
-
-                let path_segment_type = 2;
-                let number_of_ases = s.len();
-                bytes.put_u8(path_segment_type);
-                bytes.put_u8(number_of_ases as u8);
-                bytes.put(
-                    &s.iter()
-                        .flat_map(|a| u16::from(*a).to_be_bytes())
-                        .collect::<Vec<u8>>()[..],
-                );
-                bytes
+        let mut bytes = BytesMut::new();
+        for segment in &as_path.0 {
+            // 255個を超えるASはsegmentを分けて表現する。
+            for ases in segment.ases().chunks(MAX_SEGMENT_LENGTH) {
+                bytes.put_u8(segment.type_());
+                bytes.put_u8(ases.len() as u8);
+                for a in ases {
+                    bytes.put_u16(u16::from(*a));
+                }
             }
         }
+        bytes
     }
 }
 
 impl AsPath {
+    /// 1つのAS_SEQUENCEだけからなるAS_PATHを作る。ASが無い場合は空のAS_PATHになる。
+    pub fn from_sequence(ases: Vec<AutonomousSystemNumber>) -> Self {
+        if ases.is_empty() {
+            return Self::default();
+        }
+        Self(vec![AsPathSegment::AsSequence(ases)])
+    }
+
     fn bytes_len(&self) -> usize {
-        let as_bytes_length = match self {
-            AsPath::AsSequence(v) => 2 * v.len(),
-            AsPath::AsSet(s) => 2 * s.len(),
-        };
-        // AsSetかAsSequenceかを表すoctet + asの数を表すoctet + asのbytesの値
-        1 + 1 + as_bytes_length
+        self.0
+            .iter()
+            .map(|s| {
+                let number_of_ases = s.ases().len();
+                let number_of_segments =
+                    number_of_ases.div_ceil(MAX_SEGMENT_LENGTH).max(1);
+                // segmentの種類を表すoctet + asの数を表すoctet + asのbytesの値
+                2 * number_of_segments + 2 * number_of_ases
+            })
+            .sum()
     }
 
-    pub fn does_contain(&self, as_path: AutonomousSystemNumber) -> bool {
-        match self {
-            AsPath::AsSequence(seq) => seq.contains(&as_path),
-            AsPath::AsSet(set) => set.contains(&as_path),
+    /// 経路選択に使うAS_PATHの長さ。AS_SETはASの数によらず1として数える。
+    /// AS_CONFED_SEQUENCE, AS_CONFED_SETは数えない。(RFC5065 Section 5.3)
+    /// 参考: 9.1.2.2. Breaking Ties (Phase 2) in RFC4271.
+    pub fn path_length(&self) -> usize {
+        self.0
+            .iter()
+            .map(|s| match s {
+                AsPathSegment::AsSet(_) => 1,
+                AsPathSegment::AsSequence(seq) => seq.len(),
+                AsPathSegment::ConfedSequence(_)
+                | AsPathSegment::ConfedSet(_) => 0,
+            })
+            .sum()
+    }
+
+    /// ルートを広報してきた隣のAS。先頭のAS_SEQUENCEの最初のASで、
+    /// AS_CONFED_SEQUENCE, AS_CONFED_SETは飛ばす。(RFC5065 Section 5.3)
+    /// 自AS内で生成されたルートや、先頭がAS_SETの場合はNoneを返す。
+    /// 参考: 9.1.2.2. Breaking Ties (Phase 2) in RFC4271.
+    pub fn neighbor_as(&self) -> Option<AutonomousSystemNumber> {
+        match self.0.iter().find(|s| !s.is_confederation()) {
+            Some(AsPathSegment::AsSequence(seq)) => seq.first().copied(),
+            _ => None,
         }
     }
 
-    pub fn push(&mut self, as_path: AutonomousSystemNumber) {
-        match self {
-            AsPath::AsSequence(seq) => seq.push(as_path),
-            AsPath::AsSet(set) => {
-                set.insert(as_path);
+    /// 全てのsegmentのうち、いずれかにas_numberが含まれているかを返す。
+    /// AS_CONFED_SEQUENCE, AS_CONFED_SETに含まれるmember ASも対象にする。
+    pub fn does_contain(&self, as_number: AutonomousSystemNumber) -> bool {
+        self.0.iter().any(|s| s.ases().contains(&as_number))
+    }
+
+    /// confederation内のPeerに広報する場合に、AS_PATHの先頭に
+    /// 自身のmember ASを追加する。(RFC5065 Section 4)
+    pub fn prepend_confederation(
+        &mut self,
+        as_number: AutonomousSystemNumber,
+    ) {
+        match self.0.first_mut() {
+            Some(AsPathSegment::ConfedSequence(seq))
+                if seq.len() < MAX_SEGMENT_LENGTH =>
+            {
+                seq.insert(0, as_number)
             }
+            _ => self
+                .0
+                .insert(0, AsPathSegment::ConfedSequence(vec![as_number])),
         }
     }
-}
 
-impl TryFrom<&[u8]> for AsPath {
-    type Error = anyhow::Error;
+    /// confederationの外のPeerに広報する前に、AS_CONFED_SEQUENCEと
+    /// AS_CONFED_SETを取り除く。(RFC5065 Section 4)
+    pub fn remove_confederation_segments(&mut self) {
+        self.0.retain(|s| !s.is_confederation());
+    }
 
-    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        match value[0] {
-            1 => {
-                let mut ases = BTreeSet::new();
-                let mut i = 2;
-                while i < value.len() {
-                    ases.insert(
-                        u16::from_be_bytes(value[i..i + 2].try_into()?).into(),
-                    );
-                    i += 2;
-                }
-                Ok(AsPath::AsSet(ases))
-            }
-            2 => {
-                let mut ases = vec![];
-                let mut i = 2;
-                while i < value.len() {
-                    ases.push(
-                        u16::from_be_bytes(value[i..i + 2].try_into()?).into(),
-                    );
-                    i += 2;
-                }
-                Ok(AsPath::AsSequence(ases))
+    /// AS_PATHの先頭にas_numberを追加する。
+    /// 先頭のsegmentがAS_SEQUENCEで空きがあればその先頭に、
+    /// そうでなければ新しいAS_SEQUENCEを先頭に追加する。
+    /// 参考: 5.1.2. AS_PATH in RFC4271.
+    pub fn prepend(&mut self, as_number: AutonomousSystemNumber) {
+        match self.0.first_mut() {
+            Some(AsPathSegment::AsSequence(seq))
+                if seq.len() < MAX_SEGMENT_LENGTH =>
+            {
+                seq.insert(0, as_number)
             }
-            _ => Err(anyhow::anyhow!(format!(
-                "value: {:?}をAsPathに変換出来ませんでした。",
-                &value
-            ))),
+            _ => self.0.insert(0, AsPathSegment::AsSequence(vec![as_number])),
         }
     }
 }
 
-impl AsPath {
-    pub fn add(&mut self, as_number: AutonomousSystemNumber) {
-        match self {
-            AsPath::AsSequence(seq) => seq.push(as_number),
-            AsPath::AsSet(set) => {
-                set.insert(as_number);
+impl TryFrom<&[u8]> for AsPath {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let mut segments = vec![];
+        let mut i = 0;
+        while i < value.len() {
+            if value.len() < i + 2 {
+                anyhow::bail!(
+                    "value: {:?}のAS_PATH segmentの長さが足りません。",
+                    &value
+                );
             }
-        };
+            let type_ = value[i];
+            let number_of_ases = value[i + 1] as usize;
+            let ases = value
+                .get(i + 2..i + 2 + 2 * number_of_ases)
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "value: {:?}のAS_PATH segmentの長さが足りません。",
+                        &value
+                    )
+                })?
+                .chunks(2)
+                .map(|a| u16::from_be_bytes([a[0], a[1]]).into());
+            let segment = match type_ {
+                1 => AsPathSegment::AsSet(ases.collect()),
+                2 => AsPathSegment::AsSequence(ases.collect()),
+                3 => AsPathSegment::ConfedSequence(ases.collect()),
+                4 => AsPathSegment::ConfedSet(ases.collect()),
+                _ => anyhow::bail!(
+                    "value: {:?}をAsPathに変換出来ませんでした。",
+                    &value
+                ),
+            };
+            segments.push(segment);
+            i += 2 + 2 * number_of_ases;
+        }
+        Ok(AsPath(segments))
     }
 }
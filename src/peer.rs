@@ -1,20 +1,51 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
+use serde_json::json;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
-use tracing::{debug, info, instrument};
+use tokio::sync::{mpsc, watch, Mutex};
+use tracing::{debug, info, instrument, warn};
 
+use crate::bfd::BfdState;
+use crate::bgp_type::{AddressFamily, HoldTime, Version};
 use crate::config::{Config, Mode};
 use crate::connection::Connection;
+use crate::dump::DumpRequest;
+use crate::error::MalformedMessageError;
 use crate::event::Event;
+pub use crate::event::ResetKind;
 use crate::event_queue::EventQueue;
+use crate::flowspec::{self, SharedFlowSpecEnforcer};
+use crate::listener::BgpListener;
+use crate::message_log::{Direction, MessageLog};
+use crate::packets::capability::{Capability, LlgrFamily};
 use crate::packets::keepalive;
 use crate::packets::message::Message;
+use crate::packets::notification::{
+    ErrorCode, NotificationMessage, EXTENDED_MAX_MESSAGE_LENGTH,
+    MAX_MESSAGE_LENGTH,
+};
+use crate::packets::open::OpenMessage;
 use crate::packets::update::UpdateMessage;
-use crate::routing::{AdjRibIn, AdjRibOut, LocRib};
+use crate::routing::{
+    AdjRibIn, AdjRibOut, ImportPolicy, InternalPeer, LocRib, Rib,
+};
 use crate::state::State;
 
+// RFC4271 8.1.1のIdleHoldTime。セッションをリセットした後、
+// 再び接続を試みるまでに待つ時間。
+// セッションが安定しないうちにリセットを繰り返す度に、MAX_IDLE_HOLD_TIMEまで倍にする。
+const IDLE_HOLD_TIME: Duration = Duration::from_secs(1);
+const MAX_IDLE_HOLD_TIME: Duration = Duration::from_secs(120);
+// RFC4271 8.2.2で推奨される、OPEN Messageを送ってからPeerのOPEN Messageを
+// 待つ間のHold Time。Peerが応答しない場合にOpenSentに留まり続けないようにする。
+const LARGE_HOLD_TIME: Duration = Duration::from_secs(240);
+// route flap dampingで抑制したルートを、再び使えるか確認する間隔。
+const DAMPING_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
 /// BGPのRFCで示されている実装方針
 /// (https://datatracker.ietf.org/doc/html/rfc4271#section-8)では、
 /// 1つのPeerを1つのイベント駆動ステートマシンとして実装しています。
@@ -28,6 +59,165 @@ pub struct Peer {
     loc_rib: Arc<Mutex<LocRib>>,
     adj_rib_out: AdjRibOut,
     adj_rib_in: AdjRibIn,
+    // OPEN Messageの交換でPeerとネゴシエーションしたaddress family。
+    negotiated_address_families: Vec<AddressFamily>,
+    // PeerがRoute Refresh Capabilityを広報したかどうか。
+    route_refresh_supported: bool,
+    // capability-fallbackにより、OPEN MessageにCapabilityを付けないかどうか。
+    // セッションをリセットしても維持する。
+    capabilities_disabled: bool,
+    // Peerから受信したFlowSpecのルールを反映する先。
+    flowspec_enforcer: Option<SharedFlowSpecEnforcer>,
+    // 最後にAdjRibOutへ反映したLocRibのgeneration。
+    // control socketからのannounce, withdrawや、他のPeerが受信したルートによる
+    // LocRibの変化を検知するために使う。
+    loc_rib_generation: u64,
+    // Peerから最後にKEEPALIVEかUPDATEを受信した時刻。
+    last_message_received: Option<Instant>,
+    // OPEN Messageの交換でネゴシエーションしたHold Time。
+    negotiated_hold_time: Option<HoldTime>,
+    // Hold Timer, Keepalive Timerが満了する時刻。Hold Timeが0の場合は常にNone。
+    hold_timer: Option<Instant>,
+    keepalive_timer: Option<Instant>,
+    // TCPの接続に失敗した後、次に接続を試みる時刻。
+    connect_retry_timer: Option<Instant>,
+    // delay-open-timeを指定した場合に、自身のOPEN Messageを送る時刻。
+    delay_open_timer: Option<Instant>,
+    // 次にセッションをリセットした場合のIdleHoldTimeと、再び接続を試みる時刻。
+    idle_hold_time: Duration,
+    idle_hold_timer: Option<Instant>,
+    // Establishedに遷移した時刻。MAX_IDLE_HOLD_TIMEより長く続いたら
+    // セッションが安定したとみなしてidle_hold_timeを戻す。
+    established_at: Option<Instant>,
+    // OPEN Messageの交換でネゴシエーションしたLLGRのstale time。
+    llgr_stale_time: Option<Duration>,
+    // LLGRで保持しているルートを取り除く時刻。セッションをリセットしても維持する。
+    llgr_stale_timer: Option<Instant>,
+    // route flap dampingで抑制したルートを次に確認する時刻。
+    damping_timer: Option<Instant>,
+    // startup-waitで起動直後の収束を待つ場合の、収束したかどうか。
+    converged: Option<watch::Receiver<bool>>,
+    // 収束を待つために、AdjRibOutへの反映を見送ったか。
+    advertisement_deferred: bool,
+    // PeerがOPEN MessageのHostname Capabilityで伝えたホスト名。
+    remote_hostname: Option<String>,
+    // 自身とPeerの両方がOPEN Messageで広報したCapability。Peerが送った値を持つ。
+    negotiated_capabilities: Vec<Capability>,
+    // bfd=onの場合の、PeerとのBFDのセッションの状態と、最後に確認した状態。
+    bfd: Option<watch::Receiver<BfdState>>,
+    bfd_state: Option<BfdState>,
+    // 管理者が指示したmaintenance modeの状態と、最後に確認した状態。
+    // maintenance mode中はセッションを閉じた後に再び接続しない。
+    maintenance: Option<watch::Receiver<bool>>,
+    in_maintenance: bool,
+    // stopで止められたか。startされるまで再び接続しない。
+    stopped: bool,
+    // Peerから受信したルートの数がmaximum-prefixを超えていることを警告したか。
+    maximum_prefix_exceeded: bool,
+    // maintenance modeで広報を変更した後、セッションを閉じる時刻。
+    maintenance_timer: Option<Instant>,
+    // 他のPeerと共有するlistener。無ければ自身でbindする。
+    listener: Option<Arc<BgpListener>>,
+    // 自身から接続したセッションがOpenSentかOpenConfirmの間に、Peerからも
+    // 接続された場合の、Peerからの接続と、そこで受信したOPEN Message。
+    // (RFC4271 6.8 Connection Collision Detection)
+    collision: Option<Connection>,
+    collision_open: Option<OpenMessage>,
+    // PeerがOPEN Messageで伝えたBGP Identifier。
+    remote_bgp_identifier: Option<Ipv4Addr>,
+    // control socketなどPeerの外から指示されるリセット。
+    admin_events: mpsc::UnboundedReceiver<ResetKind>,
+    admin_sender: mpsc::UnboundedSender<ResetKind>,
+    // RIBのダンプのために、Adj-RIBのJSONを返すように求める指示。
+    dump_requests: mpsc::UnboundedReceiver<DumpRequest>,
+    dump_sender: mpsc::UnboundedSender<DumpRequest>,
+    // 直近に送受信したメッセージ。
+    message_log: MessageLog,
+    status: watch::Sender<PeerStatus>,
+    status_receiver: watch::Receiver<PeerStatus>,
+}
+
+/// show bgp neighborで表示するPeerの状態です。
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct PeerStatus {
+    pub state: State,
+    // OPEN Messageの交換でネゴシエーションしたHold Time。
+    pub hold_time: Option<HoldTime>,
+    // AdjRibIn, AdjRibOutのunicastのルートの数。
+    pub routes_received: usize,
+    pub routes_advertised: usize,
+    // イベントのキューに溜まっているイベントの数と、
+    // キューが一杯になりPeerからの受信を止めた回数。
+    pub queued_events: usize,
+    pub throttled_events: u64,
+    // bfd=onの場合の、BFDのセッションの状態。
+    pub bfd: Option<BfdState>,
+    // PeerがHostname Capabilityで伝えたホスト名。ドメイン名があれば繋げる。
+    pub hostname: Option<String>,
+    // maintenance mode中かどうか。
+    pub maintenance: bool,
+    // Peerから最後にKEEPALIVEかUPDATEを受信した時刻。
+    // 経過時間にすると状態が常に変わってしまうので、時刻で持つ。
+    pub last_message_received: Option<Instant>,
+}
+
+impl Default for PeerStatus {
+    fn default() -> Self {
+        Self {
+            state: State::Idle,
+            hold_time: None,
+            routes_received: 0,
+            routes_advertised: 0,
+            queued_events: 0,
+            throttled_events: 0,
+            bfd: None,
+            hostname: None,
+            maintenance: false,
+            last_message_received: None,
+        }
+    }
+}
+
+impl fmt::Display for PeerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "state={:?}", self.state)?;
+        if let Some(hostname) = &self.hostname {
+            write!(f, " hostname={}", hostname)?;
+        }
+        if self.state == State::Established {
+            write!(
+                f,
+                " received={} advertised={} queued-events={} \
+                 throttled-events={}",
+                self.routes_received,
+                self.routes_advertised,
+                self.queued_events,
+                self.throttled_events
+            )?;
+        }
+        if let Some(received) = self.last_message_received {
+            write!(f, " last-received={}s-ago", received.elapsed().as_secs())?;
+        }
+        if let Some(bfd) = self.bfd {
+            write!(f, " bfd={}", bfd)?;
+        }
+        if self.maintenance {
+            write!(f, " maintenance")?;
+        }
+        match self.hold_time.map(u16::from) {
+            None => Ok(()),
+            Some(0) => write!(
+                f,
+                " hold-time=0 (hold timer and keepalive are disabled)"
+            ),
+            Some(hold_time) => write!(
+                f,
+                " hold-time={}s keepalive={}s",
+                hold_time,
+                hold_time / 3
+            ),
+        }
+    }
 }
 
 impl Peer {
@@ -36,6 +226,11 @@ impl Peer {
         let event_queue = EventQueue::new();
         let adj_rib_out = AdjRibOut::new();
         let adj_rib_in = AdjRibIn::new();
+        let flowspec_enforcer = config.flowspec_enforcement.map(|e| e.build());
+        let (admin_sender, admin_events) = mpsc::unbounded_channel();
+        let (dump_sender, dump_requests) = mpsc::unbounded_channel();
+        let (status, status_receiver) = watch::channel(PeerStatus::default());
+        let message_log = MessageLog::new(config.message_log_size);
         Self {
             state,
             event_queue,
@@ -44,28 +239,283 @@ impl Peer {
             loc_rib,
             adj_rib_out,
             adj_rib_in,
+            negotiated_address_families: vec![],
+            route_refresh_supported: false,
+            capabilities_disabled: false,
+            flowspec_enforcer,
+            loc_rib_generation: 0,
+            last_message_received: None,
+            negotiated_hold_time: None,
+            hold_timer: None,
+            keepalive_timer: None,
+            connect_retry_timer: None,
+            delay_open_timer: None,
+            idle_hold_time: IDLE_HOLD_TIME,
+            idle_hold_timer: None,
+            established_at: None,
+            llgr_stale_time: None,
+            llgr_stale_timer: None,
+            damping_timer: None,
+            converged: None,
+            advertisement_deferred: false,
+            remote_hostname: None,
+            negotiated_capabilities: vec![],
+            bfd: None,
+            bfd_state: None,
+            maintenance: None,
+            in_maintenance: false,
+            stopped: false,
+            maximum_prefix_exceeded: false,
+            maintenance_timer: None,
+            listener: None,
+            collision: None,
+            collision_open: None,
+            remote_bgp_identifier: None,
+            admin_events,
+            admin_sender,
+            dump_requests,
+            dump_sender,
+            message_log,
+            status,
+            status_receiver,
         }
     }
 
+    /// 自身とPeerの両方がOPEN Messageで広報したCapability。
+    /// OPEN Messageを受信するまでは空。
+    pub fn negotiated_capabilities(&self) -> &[Capability] {
+        &self.negotiated_capabilities
+    }
+
     #[instrument]
     pub fn start(&mut self) {
         info!("peer is started.");
+        self.stopped = false;
+        self.idle_hold_time = IDLE_HOLD_TIME;
         self.event_queue.enqueue(Event::ManualStart);
     }
 
+    /// Cease NOTIFICATIONを送ってセッションを閉じ、Peerから受信したルートを
+    /// LocRibから取り除いてIdleに戻る。startされるまで再び接続しない。
+    #[instrument]
+    pub fn stop(&mut self) {
+        info!("peer is stopped.");
+        self.event_queue.enqueue(Event::ManualStop);
+    }
+
+    /// passiveの場合に、他のPeerと共有するlistenerで接続を待つPeerを作る。
+    pub fn with_listener(
+        config: Config,
+        loc_rib: Arc<Mutex<LocRib>>,
+        listener: Arc<BgpListener>,
+    ) -> Self {
+        let mut peer = Self::new(config, loc_rib);
+        peer.listener = Some(listener);
+        peer
+    }
+
+    pub fn remote_ip(&self) -> IpAddr {
+        self.config.remote_ip
+    }
+
+    /// 起動直後の収束を待ち、convergedがtrueになるまでルートを広報しない。
+    pub fn wait_for_convergence(&mut self, converged: watch::Receiver<bool>) {
+        self.converged = Some(converged);
+    }
+
+    fn is_converged(&self) -> bool {
+        self.converged.as_ref().is_none_or(|c| *c.borrow())
+    }
+
+    /// BFDのセッションの状態を監視し、UpからDownになったらBGPのセッションを閉じる。
+    pub fn watch_bfd(&mut self, bfd: watch::Receiver<BfdState>) {
+        self.bfd_state = Some(*bfd.borrow());
+        self.bfd = Some(bfd);
+    }
+
+    /// maintenance modeの指示を監視し、trueになったらPeerへの広報を変更して
+    /// maintenance-delay後にセッションを閉じる。falseになったら元に戻す。
+    pub fn watch_maintenance(&mut self, maintenance: watch::Receiver<bool>) {
+        self.maintenance = Some(maintenance);
+    }
+
+    /// Peerのセッションのリセットを指示するためのsenderを返す。
+    /// 指示は次のnext()の呼び出しでイベントとして処理される。
+    pub fn admin_sender(&self) -> mpsc::UnboundedSender<ResetKind> {
+        self.admin_sender.clone()
+    }
+
+    /// Adj-RIBのダンプを求めるためのsenderを返す。
+    /// 指示は次のnext()の呼び出しで処理される。
+    pub fn dump_sender(&self) -> mpsc::UnboundedSender<DumpRequest> {
+        self.dump_sender.clone()
+    }
+
+    /// 送受信したメッセージを、Peerを作り直しても残るlogに記録する。
+    pub fn record_messages(&mut self, message_log: MessageLog) {
+        self.message_log = message_log;
+    }
+
+    /// Peerの状態を受け取るためのreceiverを返す。状態はnext()の度に更新される。
+    pub fn status(&self) -> watch::Receiver<PeerStatus> {
+        self.status_receiver.clone()
+    }
+
+    /// Peerから受信したFlowSpecのルールを反映する先を返す。
+    /// Peerのタスクが終了した後に、反映したルールを取り除くために使う。
+    pub fn flowspec_enforcer(&self) -> Option<SharedFlowSpecEnforcer> {
+        self.flowspec_enforcer.clone()
+    }
+
     #[instrument]
     pub async fn next(&mut self) {
+        while let Ok(kind) = self.admin_events.try_recv() {
+            self.event_queue.enqueue(Event::AdminReset(kind));
+        }
+        while let Ok(reply) = self.dump_requests.try_recv() {
+            let _ = reply.send(json!({
+                "state": format!("{:?}", self.state),
+                "adj_rib_in": self.adj_rib_in.to_json(),
+                "adj_rib_out": self.adj_rib_out.to_json(),
+            }));
+        }
+
+        let now = Instant::now();
+        if self.hold_timer.is_some_and(|t| t <= now) {
+            self.hold_timer = None;
+            self.event_queue.enqueue(Event::HoldTimerExpires);
+        }
+        if self.keepalive_timer.is_some_and(|t| t <= now) {
+            self.keepalive_timer = None;
+            self.event_queue.enqueue(Event::KeepaliveTimerExpires);
+        }
+        if self.connect_retry_timer.is_some_and(|t| t <= now) {
+            self.connect_retry_timer = None;
+            self.event_queue.enqueue(Event::ConnectRetryTimerExpires);
+        }
+        if self.idle_hold_timer.is_some_and(|t| t <= now) {
+            self.idle_hold_timer = None;
+            self.event_queue.enqueue(Event::IdleHoldTimerExpires);
+        }
+        if self.delay_open_timer.is_some_and(|t| t <= now) {
+            self.delay_open_timer = None;
+            self.event_queue.enqueue(Event::DelayOpenTimerExpires);
+        }
+        if let Some(bfd) = &self.bfd {
+            let state = *bfd.borrow();
+            if self.bfd_state == Some(BfdState::Up)
+                && state != BfdState::Up
+                && self.state == State::Established
+            {
+                self.event_queue.enqueue(Event::BfdSessionDown);
+            }
+            self.bfd_state = Some(state);
+        }
+        if let Some(maintenance) = &self.maintenance {
+            let maintenance = *maintenance.borrow();
+            if maintenance != self.in_maintenance {
+                self.in_maintenance = maintenance;
+                self.event_queue.enqueue(match maintenance {
+                    true => Event::MaintenanceStart,
+                    false => Event::MaintenanceEnd,
+                });
+            }
+        }
+        if self.maintenance_timer.is_some_and(|t| t <= now) {
+            self.maintenance_timer = None;
+            self.event_queue.enqueue(Event::MaintenanceTimerExpires);
+        }
+        if self.llgr_stale_timer.is_some_and(|t| t <= now) {
+            self.llgr_stale_timer = None;
+            self.event_queue.enqueue(Event::LlgrStaleTimerExpires);
+        }
+        if let Some(damping) = self.config.damping {
+            if self.damping_timer.is_none_or(|t| t <= now) {
+                self.damping_timer = Some(now + DAMPING_CHECK_INTERVAL);
+                if self.state == State::Established
+                    && self.adj_rib_in.reuse_damped_routes(&damping, now)
+                {
+                    self.event_queue.enqueue(Event::AdjRibInChanged);
+                }
+            }
+        }
+
+        if self.advertisement_deferred && self.is_converged() {
+            self.advertisement_deferred = false;
+            self.event_queue.enqueue(Event::LocRibChanged);
+        }
+
+        if self.state == State::Established {
+            let generation = self.loc_rib.lock().await.generation();
+            if generation != self.loc_rib_generation {
+                self.loc_rib_generation = generation;
+                self.event_queue.enqueue(Event::LocRibChanged);
+            }
+        }
+
         if let Some(event) = self.event_queue.dequeue() {
             info!("event is occured, event={:?}.", event);
             self.handle_event(event).await;
         }
+        self.detect_collision().await;
 
         if let Some(conn) = &mut self.tcp_connection {
-            if let Some(message) = conn.get_message().await {
-                info!("message is recieved, message={:?}.", message);
-                self.handle_message(message);
+            match conn.flush_queued_messages() {
+                // UPDATEを送信した場合はKEEPALIVEを送る必要がないので、
+                // RFC4271 4.4の通りKeepaliveTimerをやり直す。
+                Ok(sent) if sent > 0 && self.keepalive_timer.is_some() => {
+                    self.start_keepalive_timer();
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("cannot send queued messages: {:?}.", e);
+                    self.event_queue.enqueue(Event::TcpConnectionFails);
+                }
+            }
+            // キューが一杯の間は受信せず、溜まったイベントの処理を優先する。
+            // 受信しなければTCPのwindowが閉じ、Peerは送信を待つ。
+            let conn = self.tcp_connection.as_mut().unwrap();
+            let received = if self.event_queue.is_full() {
+                Ok(None)
+            } else {
+                conn.get_message().await
+            };
+            match received {
+                Ok(Some(message)) => {
+                    info!("message is recieved, message={:?}.", message);
+                    self.message_log.record(Direction::Received, &message);
+                    self.handle_message(message);
+                }
+                Ok(None) => {}
+                Err(e) => match e.downcast_ref::<MalformedMessageError>() {
+                    Some(MalformedMessageError(notification)) => {
+                        warn!("cannot parse received message: {:?}.", e);
+                        self.event_queue
+                            .enqueue(Event::MessageErr(notification.clone()));
+                    }
+                    None => {
+                        warn!("cannot receive message: {:?}.", e);
+                        self.event_queue.enqueue(Event::TcpConnectionFails);
+                    }
+                },
             }
         }
+
+        let status = PeerStatus {
+            state: self.state,
+            hold_time: self.negotiated_hold_time,
+            routes_received: self.adj_rib_in.len(),
+            routes_advertised: self.adj_rib_out.len(),
+            queued_events: self.event_queue.len(),
+            throttled_events: self.event_queue.throttled(),
+            bfd: self.bfd_state,
+            hostname: self.remote_hostname.clone(),
+            maintenance: self.in_maintenance,
+            last_message_received: self.last_message_received,
+        };
+        if *self.status_receiver.borrow() != status {
+            let _ = self.status.send(status);
+        }
     }
 
     fn handle_message(&mut self, message: Message) {
@@ -79,62 +529,356 @@ impl Peer {
             Message::Update(update) => {
                 self.event_queue.enqueue(Event::UpdateMsg(update))
             }
+            Message::Notification(notification) => {
+                self.event_queue.enqueue(Event::NotifMsg(notification))
+            }
+            Message::RouteRefresh(route_refresh) => self
+                .event_queue
+                .enqueue(Event::RouteRefreshMsg(route_refresh)),
         }
     }
 
     #[instrument]
     async fn handle_event(&mut self, event: Event) {
+        // どのStateでもセッションを閉じてIdleに戻るイベント。
+        match &event {
+            Event::AdminReset(ResetKind::Hard) => {
+                self.send(Message::new_administrative_reset()).await;
+                self.restart_session().await;
+                return;
+            }
+            Event::NotifMsg(notification) => {
+                warn!(
+                    "session is closed by notification from peer, \
+                     error_code={} ({}), error_subcode={}.",
+                    notification.error_code,
+                    ErrorCode::from(notification.error_code),
+                    notification.error_subcode
+                );
+                if is_capability_error(notification) {
+                    warn!(
+                        "peer does not support capabilities {:?}.",
+                        Capability::from_u8_slice(&notification.data)
+                    );
+                    if self.config.capability_fallback
+                        && !self.capabilities_disabled
+                    {
+                        info!("retry open message without capabilities.");
+                        self.capabilities_disabled = true;
+                    }
+                }
+                self.restart_session().await;
+                return;
+            }
+            Event::ManualStop => {
+                self.stopped = true;
+                self.maintenance_timer = None;
+                self.send(Message::new_administrative_shutdown()).await;
+                self.restart_session().await;
+                return;
+            }
+            Event::MessageErr(notification) => {
+                self.send(Message::Notification(notification.clone())).await;
+                self.restart_session().await;
+                return;
+            }
+            // 接続できなかった場合はState::Connectで扱う。
+            Event::TcpConnectionFails if self.state != State::Connect => {
+                warn!("tcp connection is failed.");
+                self.retain_stale_routes().await;
+                self.restart_session().await;
+                return;
+            }
+            // Hold Timeが0の場合、これらのTimerは始動しないので起きない。
+            Event::HoldTimerExpires => {
+                warn!("hold timer is expired.");
+                self.send(Message::new_hold_timer_expired()).await;
+                self.retain_stale_routes().await;
+                self.restart_session().await;
+                return;
+            }
+            Event::BfdSessionDown => {
+                warn!("bfd session is down.");
+                self.retain_stale_routes().await;
+                self.restart_session().await;
+                return;
+            }
+            Event::LlgrStaleTimerExpires => {
+                info!("llgr stale timer is expired.");
+                if let Err(e) = self
+                    .loc_rib
+                    .lock()
+                    .await
+                    .remove_stale_routes_from(self.config.remote_ip)
+                    .await
+                {
+                    warn!("cannot remove stale routes: {:?}.", e);
+                }
+                return;
+            }
+            Event::MaintenanceStart if self.state == State::Established => {
+                let maintenance = self.config.maintenance.clone();
+                info!(
+                    "maintenance is started, session will be closed in {:?}.",
+                    maintenance.delay
+                );
+                self.maintenance_timer =
+                    Some(Instant::now() + maintenance.delay);
+                self.adj_rib_out.maintenance = Some(maintenance);
+                self.soft_reset_out();
+                return;
+            }
+            Event::MaintenanceStart | Event::MaintenanceTimerExpires => {
+                info!("session is closed for maintenance.");
+                self.maintenance_timer = None;
+                self.event_queue.enqueue(Event::AutomaticStop);
+                return;
+            }
+            // maintenance mode中はAutomaticStartしないので、終わるまでIdleに留まる。
+            Event::AutomaticStop => {
+                self.send(Message::new_administrative_shutdown()).await;
+                self.restart_session().await;
+                return;
+            }
+            Event::MaintenanceEnd => {
+                info!("maintenance is ended.");
+                self.maintenance_timer = None;
+                match self.state {
+                    State::Established => {
+                        self.adj_rib_out.maintenance = None;
+                        self.soft_reset_out();
+                    }
+                    State::Idle if !self.stopped => {
+                        self.event_queue.enqueue(Event::AutomaticStart)
+                    }
+                    _ => {}
+                }
+                return;
+            }
+            Event::KeepaliveTimerExpires => {
+                self.send(Message::new_keepalive()).await;
+                self.start_keepalive_timer();
+                return;
+            }
+            _ => {}
+        }
+
         match &self.state {
             State::Idle => match event {
-                Event::ManualStart => {
-                    self.tcp_connection =
-                        Connection::connect(&self.config).await.ok();
-                    if self.tcp_connection.is_some() {
-                        self.event_queue
-                            .enqueue(Event::TcpConnectionConfirmed);
-                    } else {
-                        panic!(
-                            "TCP Connectionの確立が出来ませんでした。{:?}",
-                            self.config
-                        )
-                    }
+                Event::ManualStart | Event::AutomaticStart
+                    if self.in_maintenance =>
+                {
+                    info!("peer is in maintenance, connection is not tried.");
+                }
+                Event::ManualStart | Event::AutomaticStart => {
+                    self.idle_hold_timer = None;
                     self.state = State::Connect;
+                    self.connect().await;
+                }
+                Event::IdleHoldTimerExpires => {
+                    self.event_queue.enqueue(Event::AutomaticStart)
                 }
                 _ => {}
             },
             State::Connect => match event {
+                // DelayOpenTimerの動作中に確立していた接続が切れた場合もここで扱う。
+                Event::TcpConnectionFails => {
+                    self.tcp_connection = None;
+                    self.delay_open_timer = None;
+                    self.connect_retry_timer =
+                        Some(Instant::now() + self.config.connect_retry_time);
+                    self.state = State::Active;
+                }
                 Event::TcpConnectionConfirmed => {
-                    self.tcp_connection
-                        .as_mut()
-                        .expect("TCP Connectionが確立できていません。")
-                        .send(Message::new_open(
-                            self.config.local_as,
-                            self.config.local_ip,
-                        ))
-                        .await;
-                    self.state = State::OpenSent
+                    match self.config.delay_open_time {
+                        Some(delay) => {
+                            self.delay_open_timer =
+                                Some(Instant::now() + delay)
+                        }
+                        None => self.send_open_message().await,
+                    }
+                }
+                Event::DelayOpenTimerExpires => {
+                    self.send_open_message().await;
+                }
+                // DelayOpenTimerの満了前にPeerのOPEN Messageを受信したら、
+                // 自身のOPEN Messageを送り、OpenSentで受信したものとして扱う。
+                Event::BgpOpen(open) if self.delay_open_timer.is_some() => {
+                    self.delay_open_timer = None;
+                    self.send_open_message().await;
+                    self.event_queue.enqueue(Event::BgpOpen(open));
+                }
+                // OPEN Messageを交換する前に受信するはずのないMessage。
+                Event::BgpOpen(_)
+                | Event::KeepAliveMsg(_)
+                | Event::UpdateMsg(_)
+                | Event::RouteRefreshMsg(_) => {
+                    self.reject_unexpected_message(
+                        NotificationMessage::UNSPECIFIC,
+                    )
+                    .await;
                 }
                 _ => {}
             },
+            State::Active => {
+                if let Event::ConnectRetryTimerExpires = event {
+                    self.state = State::Connect;
+                    self.connect().await;
+                }
+            }
             State::OpenSent => match event {
-                Event::BgpOpen(open) => {
-                    self.tcp_connection
-                        .as_mut()
-                        .expect("TCP Connectionが確立できていません。")
-                        .send(Message::new_keepalive())
+                Event::BgpOpen(open)
+                    if open_message_error(&open, &self.config).is_some() =>
+                {
+                    let notification =
+                        open_message_error(&open, &self.config).unwrap();
+                    warn!(
+                        "open message from peer is invalid, version={}, \
+                         as={}, bgp identifier={}, error_subcode={}.",
+                        u8::from(open.version()),
+                        u16::from(open.my_as_number),
+                        open.bgp_identifier,
+                        notification.error_subcode
+                    );
+                    self.send(Message::Notification(notification)).await;
+                    self.restart_session().await;
+                }
+                Event::BgpOpen(open)
+                    if !open
+                        .hold_time
+                        .is_acceptable(self.config.min_hold_time) =>
+                {
+                    warn!(
+                        "hold time {}s proposed by peer is unacceptable.",
+                        u16::from(open.hold_time)
+                    );
+                    self.send(Message::new_unacceptable_hold_time()).await;
+                    self.restart_session().await;
+                }
+                Event::BgpOpen(open)
+                    if !missing_capabilities(
+                        &self.config.required_capabilities,
+                        &open.capabilities,
+                    )
+                    .is_empty() =>
+                {
+                    let missing = missing_capabilities(
+                        &self.config.required_capabilities,
+                        &open.capabilities,
+                    );
+                    warn!(
+                        "peer does not advertise capabilities {:?}.",
+                        missing
+                    );
+                    self.send(Message::new_unsupported_capability(&missing))
                         .await;
+                    self.restart_session().await;
+                }
+                Event::BgpOpen(open) => {
+                    self.remote_bgp_identifier = Some(open.bgp_identifier);
+                    self.negotiated_capabilities = negotiate_capabilities(
+                        &self.open_capabilities(),
+                        &open.capabilities,
+                    );
+                    info!(
+                        "negotiated capabilities: {:?}.",
+                        self.negotiated_capabilities
+                    );
+                    let extended_message = self
+                        .negotiated_capabilities
+                        .contains(&Capability::ExtendedMessage);
+                    if let Some(conn) = self.tcp_connection.as_mut() {
+                        conn.set_extended_message(extended_message);
+                    }
+                    self.adj_rib_out.max_message_length = if extended_message {
+                        EXTENDED_MAX_MESSAGE_LENGTH
+                    } else {
+                        MAX_MESSAGE_LENGTH
+                    };
+                    self.negotiated_address_families =
+                        negotiate_address_families(
+                            &self.config.address_families,
+                            &open.capabilities,
+                        );
+                    info!(
+                        "negotiated address families: {:?}.",
+                        self.negotiated_address_families
+                    );
+                    self.route_refresh_supported =
+                        open.capabilities.contains(&Capability::RouteRefresh);
+                    self.remote_hostname = remote_hostname(&open.capabilities);
+                    if let Some(hostname) = &self.remote_hostname {
+                        info!("peer hostname is {}.", hostname);
+                    }
+                    self.llgr_stale_time = negotiate_llgr_stale_time(
+                        self.config.llgr_stale_time,
+                        &open.capabilities,
+                    );
+                    self.adj_rib_out.llgr_supported =
+                        open.capabilities.iter().any(|c| {
+                            matches!(
+                                c,
+                                Capability::LongLivedGracefulRestart(_)
+                            )
+                        });
+                    self.adj_rib_out.confederation =
+                        self.config.confederation_session();
+                    self.adj_rib_out.internal = self.config.is_ibgp();
+                    self.adj_rib_out.next_hop_self = self.config.next_hop_self;
+                    self.adj_rib_in.internal_peer =
+                        InternalPeer::new(&self.config, open.bgp_identifier);
+                    self.adj_rib_in.bgp_identifier = Some(open.bgp_identifier);
+                    self.adj_rib_in.import_policy =
+                        ImportPolicy::new(&self.config);
+                    let hold_time = self.config.hold_time.min(open.hold_time);
+                    if u16::from(hold_time) == 0 {
+                        info!(
+                            "negotiated hold time is 0, hold timer \
+                             and keepalive are disabled."
+                        );
+                    }
+                    self.negotiated_hold_time = Some(hold_time);
+                    self.send(Message::new_keepalive()).await;
+                    self.restart_hold_timer();
+                    self.start_keepalive_timer();
                     self.state = State::OpenConfirm;
                 }
+                Event::KeepAliveMsg(_)
+                | Event::UpdateMsg(_)
+                | Event::RouteRefreshMsg(_) => {
+                    self.reject_unexpected_message(
+                        NotificationMessage::UNEXPECTED_MESSAGE_IN_OPEN_SENT,
+                    )
+                    .await;
+                }
                 _ => {}
             },
             State::OpenConfirm => match event {
                 Event::KeepAliveMsg(keepalive) => {
+                    self.restart_hold_timer();
+                    self.established_at = Some(Instant::now());
                     self.state = State::Established;
                     self.event_queue.enqueue(Event::Established);
                 }
+                // 接続の衝突はdetect_collisionで扱うので、同じ接続でOPEN Messageを
+                // 再び受信することはない。
+                Event::BgpOpen(_)
+                | Event::UpdateMsg(_)
+                | Event::RouteRefreshMsg(_) => {
+                    self.reject_unexpected_message(
+                        NotificationMessage::UNEXPECTED_MESSAGE_IN_OPEN_CONFIRM,
+                    )
+                    .await;
+                }
                 _ => {}
             },
             State::Established => match event {
+                Event::Established | Event::LocRibChanged
+                    if !self.is_converged() =>
+                {
+                    debug!("advertisement is deferred until convergence.");
+                    self.advertisement_deferred = true;
+                }
                 Event::Established | Event::LocRibChanged => {
                     debug!(
                         "before install routes from loc_rib \
@@ -142,17 +886,23 @@ impl Peer {
                         self.adj_rib_out
                     );
                     let loc_rib = self.loc_rib.lock().await;
-                    self.adj_rib_out
-                        .install_from_loc_rib(&loc_rib, &self.config);
+                    let generation = self.adj_rib_out.generation();
+                    self.adj_rib_out.install_from_loc_rib(
+                        &loc_rib,
+                        &self.config,
+                        &self.negotiated_address_families,
+                        &self.adj_rib_in.rtc,
+                    );
                     debug!(
                         "after install routes from loc_rib \
                          to adj_rib_out: {:?}.",
                         self.adj_rib_out
                     );
-                    if self.adj_rib_out.does_contain_new_route() {
+                    if self.adj_rib_out.generation() != generation
+                        || self.adj_rib_out.has_withdrawn_routes()
+                    {
                         debug!("adj_rib_out is updated.");
                         self.event_queue.enqueue(Event::AdjRibOutChanged);
-                        self.adj_rib_out.update_to_all_unchanged();
                     }
                 }
                 Event::AdjRibOutChanged => {
@@ -161,30 +911,62 @@ impl Peer {
                             self.config.local_ip,
                             self.config.local_as,
                         );
+                    let conn = self
+                        .tcp_connection
+                        .as_mut()
+                        .expect("TCP Connectionが確立できていません。");
                     for update in updates {
-                        self.tcp_connection
-                            .as_mut()
-                            .expect("TCP Connectionが確立できていません。")
-                            .send(Message::Update(update))
-                            .await;
+                        let message = Message::Update(update);
+                        self.message_log.record(Direction::Sent, &message);
+                        conn.enqueue(message);
+                    }
+                    self.adj_rib_out.clear_withdrawn_routes();
+                }
+                Event::KeepAliveMsg(_) => self.restart_hold_timer(),
+                Event::AdminReset(kind) => {
+                    if matches!(kind, ResetKind::SoftIn | ResetKind::Soft) {
+                        self.soft_reset_in().await;
+                    }
+                    if matches!(kind, ResetKind::SoftOut | ResetKind::Soft) {
+                        self.soft_reset_out();
                     }
                 }
+                Event::RouteRefreshMsg(route_refresh) => {
+                    // ToDo: route_refresh.address_familyのルートだけを送り直す。
+                    info!(
+                        "route refresh is requested for {:?}.",
+                        route_refresh.address_family
+                    );
+                    self.soft_reset_out();
+                }
                 Event::UpdateMsg(update) => {
+                    self.restart_hold_timer();
                     debug!(
                         "before install routes in \
                          update message to adj_rib_in: {:?}.",
                         self.adj_rib_in
                     );
-                    self.adj_rib_in.install_from_update(update, &self.config);
+                    let generation = self.adj_rib_in.generation();
+                    let rtc_generation = self.adj_rib_in.rtc.generation();
+                    let withdrawn = self
+                        .adj_rib_in
+                        .install_from_update(update, &self.config);
+                    if self.check_maximum_prefix().await {
+                        return;
+                    }
                     debug!(
                         "after install routes in update message \
                          to adj_rib_in: {:?}.",
                         self.adj_rib_in
                     );
-                    if self.adj_rib_in.does_contain_new_route() {
+                    // Route Target Membershipが変わると広報するVPNv4ルートが変わる。
+                    if self.adj_rib_in.rtc.generation() != rtc_generation {
+                        self.event_queue.enqueue(Event::LocRibChanged);
+                    }
+                    if withdrawn || self.adj_rib_in.generation() != generation
+                    {
                         debug!("adj_rib in is updated.");
                         self.event_queue.enqueue(Event::AdjRibInChanged);
-                        self.adj_rib_in.update_to_all_unchanged();
                     }
                 }
                 Event::AdjRibInChanged => {
@@ -193,34 +975,569 @@ impl Peer {
                          to loc_rib: {:?}.",
                         self.loc_rib.lock().await
                     );
-                    self.loc_rib
-                        .lock()
-                        .await
-                        .install_from_adj_rib_in(&self.adj_rib_in);
+                    self.loc_rib.lock().await.install_from_adj_rib_in(
+                        self.config.remote_ip,
+                        &self.adj_rib_in,
+                    );
                     debug!(
                         "after install routes from adj_rib to loc_rib: {:?}.",
                         self.loc_rib.lock().await
                     );
-                    if self.loc_rib.lock().await.does_contain_new_route() {
+                    // 他のPeerと共有しているLocRibは書き換えずに、
+                    // 前回見たgenerationと比べて変化を検知する。
+                    let is_changed = {
+                        let loc_rib = self.loc_rib.lock().await;
+                        let generation = loc_rib.generation();
+                        let is_changed = generation != self.loc_rib_generation
+                            || loc_rib.does_contain_withdrawn_route();
+                        self.loc_rib_generation = generation;
+                        is_changed
+                    };
+                    if is_changed {
                         info!("loc_rib is updated.");
-                        self.loc_rib
+                        // カーネルへの書き込みを待つ間、他のPeerがLocRibを使えるように、
+                        // 変更だけを集めてロックを外してから書き込む。
+                        let changes = self
+                            .loc_rib
                             .lock()
                             .await
-                            .write_to_kernel_routing_table()
+                            .take_kernel_route_changes()
                             .await;
+                        if let Err(e) = changes.program().await {
+                            warn!(
+                                "cannot write routes to kernel routing table: \
+                                 {:?}.",
+                                e
+                            );
+                        }
+                        self.enforce_flowspec_rules().await;
                         self.event_queue.enqueue(Event::LocRibChanged);
-                        self.loc_rib.lock().await.update_to_all_unchanged();
                     }
                 }
+                Event::BgpOpen(_) => {
+                    self.reject_unexpected_message(
+                        NotificationMessage::UNEXPECTED_MESSAGE_IN_ESTABLISHED,
+                    )
+                    .await;
+                }
                 _ => {}
             },
         }
     }
 }
 
+impl Peer {
+    /// セッションを閉じてIdleに戻り、IdleHoldTime後に再び接続を試みる。
+    /// リセットを繰り返すPeerに接続し続けないよう、IdleHoldTimeは
+    /// セッションが安定するまでリセットの度に延ばす。(RFC4271 8.1.1 DampPeerOscillations)
+    /// maintenance mode中は、maintenance modeが終わるまで接続を試みない。
+    /// stopで止められた場合は、startされるまで接続を試みない。
+    async fn restart_session(&mut self) {
+        info!("session is reset.");
+        if let Err(e) = self
+            .loc_rib
+            .lock()
+            .await
+            .remove_routes_learned_from(self.config.remote_ip)
+            .await
+        {
+            warn!("cannot remove routes learned from peer: {:?}.", e);
+        }
+        if let Some(enforcer) = &self.flowspec_enforcer {
+            flowspec::synchronize(enforcer, vec![]).await;
+        }
+        self.tcp_connection = None;
+        self.collision = None;
+        self.collision_open = None;
+        self.remote_bgp_identifier = None;
+        self.event_queue = EventQueue::new();
+        self.adj_rib_in = AdjRibIn::new();
+        self.adj_rib_out = AdjRibOut::new();
+        self.negotiated_address_families = vec![];
+        self.route_refresh_supported = false;
+        self.last_message_received = None;
+        self.negotiated_hold_time = None;
+        self.hold_timer = None;
+        self.keepalive_timer = None;
+        self.connect_retry_timer = None;
+        self.delay_open_timer = None;
+        self.llgr_stale_time = None;
+        self.remote_hostname = None;
+        self.negotiated_capabilities = vec![];
+        self.advertisement_deferred = false;
+        self.maximum_prefix_exceeded = false;
+        self.idle_hold_timer = None;
+        if self
+            .established_at
+            .take()
+            .is_some_and(|t| t.elapsed() >= MAX_IDLE_HOLD_TIME)
+        {
+            self.idle_hold_time = IDLE_HOLD_TIME;
+        }
+        self.state = State::Idle;
+        if self.in_maintenance || self.stopped {
+            return;
+        }
+        info!("connection will be tried after {:?}.", self.idle_hold_time);
+        self.idle_hold_timer = Some(Instant::now() + self.idle_hold_time);
+        self.idle_hold_time =
+            (self.idle_hold_time * 2).min(MAX_IDLE_HOLD_TIME);
+    }
+
+    /// OPEN Messageを送り、PeerのOPEN Messageを待つOpenSentに遷移する。
+    async fn send_open_message(&mut self) {
+        self.send(self.open_message()).await;
+        self.hold_timer = Some(Instant::now() + LARGE_HOLD_TIME);
+        self.state = State::OpenSent
+    }
+
+    fn open_message(&self) -> Message {
+        Message::new_open(
+            self.config.as_number_for_peer(),
+            self.config.hold_time,
+            self.config.bgp_identifier(),
+            self.open_capabilities(),
+        )
+    }
+
+    /// 自身から接続したセッションがOpenSentかOpenConfirmの間に、Peerからの接続を
+    /// 受け付けていれば、そちらでもOPEN Messageを交換する。PeerのBGP Identifierが
+    /// 分かったら、BGP Identifierが大きい方が接続したものを残し、もう一方を閉じる。
+    /// (RFC4271 6.8)
+    async fn detect_collision(&mut self) {
+        if !matches!(self.state, State::OpenSent | State::OpenConfirm) {
+            return;
+        }
+        if self.collision.is_none() {
+            let outbound = self
+                .tcp_connection
+                .as_ref()
+                .is_some_and(|conn| conn.is_outbound());
+            let stream = match &self.listener {
+                Some(listener) if outbound => {
+                    listener.take_accepted(self.config.remote_ip)
+                }
+                _ => None,
+            };
+            let stream = match stream {
+                Some(stream) => stream,
+                None => return,
+            };
+            info!("connection collision is detected.");
+            let mut conn = match Connection::accepted(stream, &self.config) {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("cannot use connection from peer: {:?}.", e);
+                    return;
+                }
+            };
+            let open = self.open_message();
+            self.message_log.record(Direction::Sent, &open);
+            if let Err(e) = conn.send(open).await {
+                warn!("cannot send open message: {:?}.", e);
+                return;
+            }
+            self.collision = Some(conn);
+        }
+
+        let conn = self.collision.as_mut().unwrap();
+        match conn.get_message().await {
+            Ok(Some(message)) => {
+                self.message_log.record(Direction::Received, &message);
+                if let Message::Open(open) = message {
+                    self.collision_open = Some(open);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("connection from peer is closed: {:?}.", e);
+                self.collision = None;
+                self.collision_open = None;
+                return;
+            }
+        }
+        let remote_bgp_identifier = self
+            .collision_open
+            .as_ref()
+            .map(|open| open.bgp_identifier)
+            .or(self.remote_bgp_identifier);
+        if let Some(remote_bgp_identifier) = remote_bgp_identifier {
+            self.resolve_collision(remote_bgp_identifier).await;
+        }
+    }
+
+    async fn resolve_collision(&mut self, remote_bgp_identifier: Ipv4Addr) {
+        let mut conn = match self.collision.take() {
+            Some(conn) => conn,
+            None => return,
+        };
+        let notification = Message::new_connection_collision_resolution();
+        if self.config.bgp_identifier() < remote_bgp_identifier {
+            info!(
+                "connection collision is resolved, use connection from peer."
+            );
+            // 自身からの接続を閉じ、Peerからの接続でOPEN Messageを待つ所からやり直す。
+            std::mem::swap(&mut conn, self.tcp_connection.as_mut().unwrap());
+            self.state = State::OpenSent;
+            self.hold_timer = Some(Instant::now() + LARGE_HOLD_TIME);
+            self.keepalive_timer = None;
+            self.negotiated_hold_time = None;
+            if let Some(open) = self.collision_open.take() {
+                self.event_queue.enqueue(Event::BgpOpen(open));
+            }
+        } else {
+            info!("connection collision is resolved, use own connection.");
+            self.collision_open = None;
+        }
+        self.message_log.record(Direction::Sent, &notification);
+        if let Err(e) = conn.send(notification).await {
+            warn!("cannot send notification: {:?}.", e);
+        }
+    }
+
+    /// PeerとのTCP Connectionを確立する。
+    /// 失敗した場合はTcpConnectionFailsを発生させ、ConnectRetryTimerの満了後にやり直す。
+    async fn connect(&mut self) {
+        match Connection::connect(&self.config, self.listener.as_deref()).await
+        {
+            Ok(conn) => {
+                self.tcp_connection = Some(conn);
+                self.event_queue.enqueue(Event::TcpConnectionConfirmed);
+            }
+            Err(e) => {
+                warn!(
+                    "cannot establish tcp connection, retry in {:?}: {:?}.",
+                    self.config.connect_retry_time, e
+                );
+                self.event_queue.enqueue(Event::TcpConnectionFails);
+            }
+        }
+    }
+
+    /// LLGRをネゴシエーションしていれば、セッションが切れたPeerのルートを
+    /// LocRibにstaleとして保持し、stale timeが満了したら取り除くようにする。
+    /// restart_sessionより先に呼ぶ。
+    async fn retain_stale_routes(&mut self) {
+        let stale_time = match self.llgr_stale_time {
+            Some(stale_time) if self.state == State::Established => stale_time,
+            _ => return,
+        };
+        info!("routes are retained as llgr stale for {:?}.", stale_time);
+        if let Err(e) = self
+            .loc_rib
+            .lock()
+            .await
+            .mark_routes_stale(self.config.remote_ip)
+            .await
+        {
+            warn!("cannot retain routes as llgr stale: {:?}.", e);
+        }
+        self.llgr_stale_timer = Some(Instant::now() + stale_time);
+    }
+
+    /// Peerから受信したルートの数がmaximum-prefixを超えていれば警告し、
+    /// warnでなければCeaseのNOTIFICATIONを送ってセッションを閉じる。(RFC4486)
+    /// セッションを閉じた場合はtrueを返す。
+    async fn check_maximum_prefix(&mut self) -> bool {
+        let maximum_prefix = match self.config.maximum_prefix {
+            Some(maximum_prefix) => maximum_prefix,
+            None => return false,
+        };
+        let count = self.adj_rib_in.prefix_count();
+        if count <= maximum_prefix.limit {
+            self.maximum_prefix_exceeded = false;
+            return false;
+        }
+        if !self.maximum_prefix_exceeded {
+            warn!(
+                "{} prefixes are received, which exceeds maximum-prefix {}.",
+                count, maximum_prefix.limit
+            );
+            self.maximum_prefix_exceeded = true;
+        }
+        if maximum_prefix.warning_only {
+            return false;
+        }
+        self.send(Message::Notification(NotificationMessage::new(
+            NotificationMessage::CEASE,
+            NotificationMessage::MAXIMUM_NUMBER_OF_PREFIXES_REACHED,
+            vec![],
+        )))
+        .await;
+        self.restart_session().await;
+        if let (Some(restart), Some(_)) =
+            (maximum_prefix.restart, self.idle_hold_timer)
+        {
+            info!("connection will be tried after {:?} instead.", restart);
+            self.idle_hold_timer = Some(Instant::now() + restart);
+        }
+        true
+    }
+
+    /// そのStateで受信するはずのないMessageを受信したので、
+    /// Finite State Machine ErrorのNOTIFICATIONを送ってセッションを閉じる。
+    /// (RFC4271 Section 6.6, RFC6608)
+    async fn reject_unexpected_message(&mut self, error_subcode: u8) {
+        warn!("unexpected message is received in {:?}.", self.state);
+        self.send(Message::Notification(NotificationMessage::new(
+            NotificationMessage::FINITE_STATE_MACHINE_ERROR,
+            error_subcode,
+            vec![],
+        )))
+        .await;
+        self.restart_session().await;
+    }
+
+    /// messageを送信する。送信に失敗した場合はTcpConnectionFailsを発生させる。
+    async fn send(&mut self, message: Message) {
+        let conn = match self.tcp_connection.as_mut() {
+            Some(conn) => conn,
+            None => return,
+        };
+        self.message_log.record(Direction::Sent, &message);
+        if let Err(e) = conn.send(message).await {
+            warn!("cannot send message: {:?}.", e);
+            self.event_queue.enqueue(Event::TcpConnectionFails);
+        }
+    }
+
+    /// PeerにROUTE-REFRESHを送ってルートを送り直してもらい、
+    /// 受信済みのルートをLocRibへ反映し直す。
+    async fn soft_reset_in(&mut self) {
+        if self.route_refresh_supported {
+            for af in self.negotiated_address_families.clone() {
+                self.send(Message::new_route_refresh(af)).await;
+            }
+        } else {
+            warn!(
+                "peer does not support route refresh, \
+                 only routes in adj_rib_in are installed again."
+            );
+        }
+        self.event_queue.enqueue(Event::AdjRibInChanged);
+    }
+
+    /// 全てのルートをPeerへ送り直す。
+    /// 送り直さなくなったルートはwithdrawする。
+    fn soft_reset_out(&mut self) {
+        self.adj_rib_out.readvertise_all();
+        self.event_queue.enqueue(Event::LocRibChanged);
+    }
+
+    /// OPEN Messageで広報するCapability。
+    fn open_capabilities(&self) -> Vec<Capability> {
+        if self.capabilities_disabled {
+            return vec![];
+        }
+        self.config
+            .address_families
+            .iter()
+            .map(|af| Capability::MultiProtocol(*af))
+            .chain([Capability::RouteRefresh, Capability::ExtendedMessage])
+            .chain(self.config.fqdn().map(|(hostname, domain)| {
+                Capability::Fqdn { hostname, domain }
+            }))
+            .chain(self.config.llgr_stale_time.map(|stale_time| {
+                Capability::LongLivedGracefulRestart(
+                    self.config
+                        .address_families
+                        .iter()
+                        .map(|af| LlgrFamily {
+                            address_family: *af,
+                            flags: 0,
+                            stale_time,
+                        })
+                        .collect(),
+                )
+            }))
+            .collect()
+    }
+
+    /// ネゴシエーションしたHold Time。0の場合はTimerを使わないのでNone。
+    fn hold_time(&self) -> Option<Duration> {
+        self.negotiated_hold_time
+            .map(u16::from)
+            .filter(|hold_time| *hold_time != 0)
+            .map(|hold_time| Duration::from_secs(hold_time.into()))
+    }
+
+    /// KEEPALIVEかUPDATEを受信したときに呼ぶ。
+    fn restart_hold_timer(&mut self) {
+        let now = Instant::now();
+        self.last_message_received = Some(now);
+        self.hold_timer = self.hold_time().map(|hold_time| now + hold_time);
+    }
+
+    /// Hold Timeの1/3後にKEEPALIVEを送るようにする。(RFC4271 Section 10)
+    fn start_keepalive_timer(&mut self) {
+        self.keepalive_timer = self
+            .hold_time()
+            .map(|hold_time| Instant::now() + hold_time / 3);
+    }
+
+    /// Peerから最後にKEEPALIVEかUPDATEを受信してからの経過時間。
+    pub fn last_message_received(&self) -> Option<Duration> {
+        self.last_message_received.map(|t| t.elapsed())
+    }
+
+    /// Peerから受信したFlowSpecのルールのうち、LocRibにインストールされたものを
+    /// パケットフィルタに反映する。withdrawされたり、LocRibから無くなったりした
+    /// ルールはパケットフィルタから取り除く。
+    async fn enforce_flowspec_rules(&mut self) {
+        let enforcer = match &self.flowspec_enforcer {
+            Some(enforcer) => enforcer,
+            None => return,
+        };
+        let entries = {
+            let loc_rib = self.loc_rib.lock().await;
+            self.adj_rib_in
+                .flowspec
+                .routes()
+                .filter(|entry| loc_rib.flowspec.contains(entry))
+                .cloned()
+                .collect()
+        };
+        flowspec::synchronize(enforcer, entries).await;
+    }
+}
+
+/// PeerのOPEN MessageのVersion, AS番号, BGP Identifierが正しくなければ、
+/// Peerに送るOPEN Message ErrorのNOTIFICATIONを返す。(RFC4271 Section 6.2)
+/// BGP IdentifierはunicastのIPv4アドレスで、自身のものと異なる必要がある。
+fn open_message_error(
+    open: &OpenMessage,
+    config: &Config,
+) -> Option<NotificationMessage> {
+    let bgp_identifier = open.bgp_identifier;
+    let (subcode, data) = if open.version() != Version::new() {
+        // Dataには自身が対応している最大のVersionを2 octetsで入れる。
+        (
+            NotificationMessage::UNSUPPORTED_VERSION_NUMBER,
+            u16::from(u8::from(Version::new())).to_be_bytes().to_vec(),
+        )
+    } else if open.my_as_number != config.remote_as {
+        (NotificationMessage::BAD_PEER_AS, vec![])
+    } else if bgp_identifier.is_unspecified()
+        || bgp_identifier.is_multicast()
+        || bgp_identifier.is_broadcast()
+        || bgp_identifier == config.bgp_identifier()
+    {
+        (NotificationMessage::BAD_BGP_IDENTIFIER, vec![])
+    } else {
+        return None;
+    };
+    Some(NotificationMessage::new(
+        NotificationMessage::OPEN_MESSAGE_ERROR,
+        subcode,
+        data,
+    ))
+}
+
+/// Peerが広報したCapabilityのうち、自身も広報したものを返す。
+/// Multiprotocol Capabilityはaddress family毎に、それ以外はCapability Code毎に比べる。
+fn negotiate_capabilities(
+    local: &[Capability],
+    remote: &[Capability],
+) -> Vec<Capability> {
+    remote
+        .iter()
+        .filter(|r| match r {
+            Capability::MultiProtocol(_) => local.contains(r),
+            _ => local.iter().any(|l| l.code() == r.code()),
+        })
+        .cloned()
+        .collect()
+}
+
+/// PeerのOPEN Messageに含まれないrequiredのCapabilityを返す。
+fn missing_capabilities(
+    required: &[Capability],
+    remote_capabilities: &[Capability],
+) -> Vec<Capability> {
+    required
+        .iter()
+        .filter(|c| !remote_capabilities.contains(c))
+        .cloned()
+        .collect()
+}
+
+/// Capabilityに対応していないことを表すOPEN Message ErrorのNOTIFICATIONか。
+/// Capabilities Optional Parameter自体に対応していないPeerは
+/// Unsupported Optional Parameterを送ってくる。(RFC5492 Section 5)
+fn is_capability_error(notification: &NotificationMessage) -> bool {
+    notification.error_code == NotificationMessage::OPEN_MESSAGE_ERROR
+        && matches!(
+            notification.error_subcode,
+            NotificationMessage::UNSUPPORTED_CAPABILITY
+                | NotificationMessage::UNSUPPORTED_OPTIONAL_PARAMETER
+        )
+}
+
+/// 自身が設定しているaddress familyと、PeerのOPEN Messageに含まれる
+/// Multiprotocol Capabilityから、ルートを交換するaddress familyを決める。
+/// PeerがMultiprotocol Capabilityを1つも広報していない場合は、
+/// IPv4 unicastのみに対応しているとみなす。(RFC4760 Section 8)
+fn negotiate_address_families(
+    local: &[AddressFamily],
+    remote_capabilities: &[Capability],
+) -> Vec<AddressFamily> {
+    let remote: Vec<AddressFamily> = remote_capabilities
+        .iter()
+        .filter_map(|c| match c {
+            Capability::MultiProtocol(af) => Some(*af),
+            _ => None,
+        })
+        .collect();
+    let remote = if remote.is_empty() {
+        vec![AddressFamily::IPV4_UNICAST]
+    } else {
+        remote
+    };
+    local
+        .iter()
+        .filter(|af| remote.contains(af))
+        .copied()
+        .collect()
+}
+
+/// PeerのHostname Capabilityから、ドメイン名があれば繋げたホスト名を返す。
+fn remote_hostname(remote_capabilities: &[Capability]) -> Option<String> {
+    remote_capabilities.iter().find_map(|c| match c {
+        Capability::Fqdn { hostname, domain } if domain.is_empty() => {
+            Some(hostname.clone())
+        }
+        Capability::Fqdn { hostname, domain } => {
+            Some(format!("{}.{}", hostname, domain))
+        }
+        _ => None,
+    })
+}
+
+/// 自身のllgr-stale-timeと、PeerのLLGR Capabilityのstale timeの小さい方を返す。
+/// どちらかがLLGRに対応していないか、stale timeが0の場合はNone。
+fn negotiate_llgr_stale_time(
+    local: Option<u32>,
+    remote_capabilities: &[Capability],
+) -> Option<Duration> {
+    let remote = remote_capabilities
+        .iter()
+        .filter_map(|c| match c {
+            Capability::LongLivedGracefulRestart(families) => {
+                families.iter().map(|f| f.stale_time).min()
+            }
+            _ => None,
+        })
+        .min()?;
+    Some(local?.min(remote))
+        .filter(|stale_time| *stale_time != 0)
+        .map(|stale_time| Duration::from_secs(stale_time.into()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use bytes::BytesMut;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::time::{sleep, Duration};
 
     #[tokio::test]
@@ -253,24 +1570,98 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn peer_can_transition_to_open_sent_state() {
+    async fn peer_retries_connection_after_connect_retry_time() {
+        // 127.0.0.250:179では誰も接続を待っていないので、接続は失敗する。
         let config: Config =
-            "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+            "64512 127.0.0.1 64513 127.0.0.250 active connect-retry-time=1"
+                .parse()
+                .unwrap();
         let loc_rib =
             Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
         let mut peer = Peer::new(config, Arc::clone(&loc_rib));
         peer.start();
+        peer.next().await;
+        assert_eq!(peer.state, State::Connect);
+        peer.next().await;
+        assert_eq!(peer.state, State::Active);
+        assert!(peer.connect_retry_timer.is_some());
 
-        // 別スレッドでPeer構造体を実行しています。
-        // これはネットワーク上で離れた別のマシンを模擬しています。
-        tokio::spawn(async move {
-            let remote_config =
-                "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
-            let remote_loc_rib = Arc::new(Mutex::new(
-                LocRib::new(&remote_config).await.unwrap(),
-            ));
-            let mut remote_peer =
-                Peer::new(remote_config, Arc::clone(&remote_loc_rib));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        peer.next().await;
+        assert_eq!(peer.state, State::Connect);
+        assert!(peer.connect_retry_timer.is_none());
+        peer.next().await;
+        assert_eq!(peer.state, State::Active);
+    }
+
+    #[tokio::test]
+    async fn peer_reconnects_automatically_after_session_is_lost() {
+        let config: Config =
+            "64512 127.0.0.1 64513 127.0.0.250 active".parse().unwrap();
+        let loc_rib =
+            Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.state = State::OpenConfirm;
+        peer.event_queue.enqueue(Event::TcpConnectionFails);
+        peer.next().await;
+        assert_eq!(peer.state, State::Idle);
+        assert!(peer.idle_hold_timer.is_some());
+
+        tokio::time::sleep(IDLE_HOLD_TIME).await;
+        peer.next().await;
+        assert_eq!(peer.event_queue.dequeue(), Some(Event::AutomaticStart));
+
+        peer.event_queue.enqueue(Event::AutomaticStart);
+        peer.next().await;
+        assert_eq!(peer.state, State::Connect);
+    }
+
+    #[tokio::test]
+    async fn idle_hold_time_is_increased_while_session_flaps() {
+        let config: Config =
+            "64512 127.0.0.1 64513 127.0.0.250 active".parse().unwrap();
+        let loc_rib =
+            Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        let mut idle_hold_times = vec![];
+        for _ in 0..9 {
+            peer.state = State::OpenConfirm;
+            peer.event_queue.enqueue(Event::TcpConnectionFails);
+            peer.next().await;
+            let idle_hold_time =
+                peer.idle_hold_timer.unwrap() - Instant::now();
+            idle_hold_times.push(idle_hold_time.as_secs_f32().round() as u64);
+        }
+        assert_eq!(idle_hold_times, vec![1, 2, 4, 8, 16, 32, 64, 120, 120]);
+
+        // セッションが十分長くEstablishedだった場合は最初の値に戻す。
+        peer.state = State::Established;
+        peer.established_at = Some(Instant::now() - MAX_IDLE_HOLD_TIME);
+        peer.event_queue.enqueue(Event::TcpConnectionFails);
+        peer.next().await;
+        let idle_hold_time = peer.idle_hold_timer.unwrap() - Instant::now();
+        assert!(idle_hold_time <= IDLE_HOLD_TIME);
+    }
+
+    #[tokio::test]
+    async fn peer_can_transition_to_open_sent_state() {
+        let config: Config =
+            "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let loc_rib =
+            Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.start();
+
+        // 別スレッドでPeer構造体を実行しています。
+        // これはネットワーク上で離れた別のマシンを模擬しています。
+        tokio::spawn(async move {
+            let remote_config =
+                "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
+            let remote_loc_rib = Arc::new(Mutex::new(
+                LocRib::new(&remote_config).await.unwrap(),
+            ));
+            let mut remote_peer =
+                Peer::new(remote_config, Arc::clone(&remote_loc_rib));
             remote_peer.start();
             remote_peer.next().await;
             remote_peer.next().await;
@@ -281,6 +1672,107 @@ mod tests {
         peer.next().await;
         peer.next().await;
         assert_eq!(peer.state, State::OpenSent);
+        // PeerのOPEN Messageを受信するまではLARGE_HOLD_TIMEで待つ。
+        let hold_timer = peer.hold_timer.unwrap() - Instant::now();
+        assert!(hold_timer > LARGE_HOLD_TIME - Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn large_hold_timer_is_replaced_after_open_negotiation() {
+        let config: Config =
+            "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let loc_rib =
+            Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.start();
+
+        tokio::spawn(async move {
+            let remote_config =
+                "64513 127.0.0.2 64512 127.0.0.1 passive hold-time=30"
+                    .parse()
+                    .unwrap();
+            let remote_loc_rib = Arc::new(Mutex::new(
+                LocRib::new(&remote_config).await.unwrap(),
+            ));
+            let mut remote_peer =
+                Peer::new(remote_config, Arc::clone(&remote_loc_rib));
+            remote_peer.start();
+            let max_step = 50;
+            for _ in 0..max_step {
+                remote_peer.next().await;
+                tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+            }
+        });
+
+        // 先にremote_peer側の処理が進むことを保証するためのwait
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        peer.next().await;
+        peer.next().await;
+        assert_eq!(peer.state, State::OpenSent);
+        let hold_timer = peer.hold_timer.unwrap() - Instant::now();
+        assert!(hold_timer > LARGE_HOLD_TIME - Duration::from_secs(10));
+
+        // OPEN Messageを受信したら、ネゴシエーションしたHold Timeに置き換える。
+        let max_step = 10;
+        for _ in 0..max_step {
+            peer.next().await;
+            if peer.state == State::OpenConfirm {
+                break;
+            };
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert_eq!(peer.state, State::OpenConfirm);
+        assert_eq!(peer.negotiated_hold_time, Some(HoldTime::from(30)));
+        let hold_timer = peer.hold_timer.unwrap() - Instant::now();
+        assert!(hold_timer <= Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn open_message_is_delayed_until_peer_sends_open() {
+        let config: Config =
+            "64512 127.0.0.1 64513 127.0.0.2 active delay-open-time=60"
+                .parse()
+                .unwrap();
+        let loc_rib =
+            Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.start();
+
+        tokio::spawn(async move {
+            let remote_config =
+                "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
+            let remote_loc_rib = Arc::new(Mutex::new(
+                LocRib::new(&remote_config).await.unwrap(),
+            ));
+            let mut remote_peer =
+                Peer::new(remote_config, Arc::clone(&remote_loc_rib));
+            remote_peer.start();
+            let max_step = 50;
+            for _ in 0..max_step {
+                remote_peer.next().await;
+                tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+            }
+        });
+
+        // 先にremote_peer側の処理が進むことを保証するためのwait
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        peer.next().await;
+        peer.next().await;
+        // 接続が確立しても、DelayOpenTimerの間はOPEN Messageを送らない。
+        assert_eq!(peer.state, State::Connect);
+        assert!(peer.delay_open_timer.is_some());
+
+        // PeerのOPEN Messageを受信したら、満了を待たずにOPEN Messageを送る。
+        let max_step = 10;
+        for _ in 0..max_step {
+            peer.next().await;
+            if peer.state == State::OpenConfirm {
+                break;
+            };
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert_eq!(peer.state, State::OpenConfirm);
+        assert!(peer.delay_open_timer.is_none());
     }
 
     #[tokio::test]
@@ -367,5 +1859,511 @@ mod tests {
             tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
         }
         assert_eq!(peer.state, State::Established);
+        assert!(peer.last_message_received().is_some());
+        // show bgp neighborで表示できるように、statusでも公開する。
+        let status = peer.status().borrow().clone();
+        assert_eq!(status.last_message_received, peer.last_message_received);
+        assert!(status.to_string().contains(" last-received="));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stopped_peer_does_not_reconnect_until_started() {
+        let config: Config =
+            "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let loc_rib =
+            Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.start();
+
+        // remote_peerがEstablishedになった後もstopを確認し終えるまで
+        // TCP Connectionを閉じないよう、テストの終わりまで保持させる。
+        let (finished_tx, finished_rx) = tokio::sync::oneshot::channel();
+        // 時刻を止めていてもpeerの接続がremote_peerのbindより先にならない
+        // よう、spawnする前にbindしておく。
+        let remote_config: Config =
+            "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
+        let listener = BgpListener::bind(std::slice::from_ref(&remote_config))
+            .await
+            .unwrap();
+        tokio::spawn(async move {
+            let remote_loc_rib = Arc::new(Mutex::new(
+                LocRib::new(&remote_config).await.unwrap(),
+            ));
+            let mut remote_peer = Peer::with_listener(
+                remote_config,
+                Arc::clone(&remote_loc_rib),
+                listener,
+            );
+            remote_peer.start();
+            let max_step = 50;
+            for _ in 0..max_step {
+                remote_peer.next().await;
+                if remote_peer.state == State::Established {
+                    break;
+                };
+                tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+            }
+            let _ = finished_rx.await;
+        });
+
+        let max_step = 50;
+        for _ in 0..max_step {
+            peer.next().await;
+            if peer.state == State::Established {
+                break;
+            };
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert_eq!(peer.state, State::Established);
+
+        peer.stop();
+        peer.next().await;
+        assert_eq!(peer.state, State::Idle);
+        assert!(peer.tcp_connection.is_none());
+        assert_eq!(peer.event_queue.dequeue(), None);
+        let messages = peer.message_log.to_json();
+        let last = messages.as_array().unwrap().last().unwrap();
+        assert_eq!(last["direction"], "sent");
+        assert!(last["message"].as_str().unwrap().contains("error_code: 6"));
+
+        peer.start();
+        assert_eq!(peer.event_queue.dequeue(), Some(Event::ManualStart));
+        let _ = finished_tx.send(());
+    }
+
+    #[tokio::test]
+    async fn smaller_hold_time_is_negotiated() {
+        let config: Config =
+            "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let loc_rib =
+            Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.start();
+
+        tokio::spawn(async move {
+            let remote_config =
+                "64513 127.0.0.2 64512 127.0.0.1 passive hold-time=30"
+                    .parse()
+                    .unwrap();
+            let remote_loc_rib = Arc::new(Mutex::new(
+                LocRib::new(&remote_config).await.unwrap(),
+            ));
+            let mut remote_peer =
+                Peer::new(remote_config, Arc::clone(&remote_loc_rib));
+            remote_peer.start();
+            let max_step = 50;
+            for _ in 0..max_step {
+                remote_peer.next().await;
+                if remote_peer.state == State::Established {
+                    break;
+                };
+                tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+            }
+        });
+
+        // 先にremote_peer側の処理が進むことを保証するためのwait
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let max_step = 50;
+        for _ in 0..max_step {
+            peer.next().await;
+            if peer.state == State::Established {
+                break;
+            };
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert_eq!(peer.state, State::Established);
+        assert_eq!(peer.negotiated_hold_time, Some(HoldTime::from(30)));
+        // KEEPALIVEはネゴシエーションしたHold Timeの1/3の間隔で送る。
+        let keepalive = peer.keepalive_timer.unwrap() - Instant::now();
+        assert!(keepalive <= Duration::from_secs(10));
+        let hold_timer = peer.hold_timer.unwrap() - Instant::now();
+        assert!(hold_timer <= Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn keepalive_timer_is_restarted_when_update_is_sent() {
+        let config: Config =
+            "64512 127.0.0.1 64513 127.0.0.2 active hold-time=30"
+                .parse()
+                .unwrap();
+        let loc_rib =
+            Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.start();
+
+        tokio::spawn(async move {
+            let remote_config =
+                "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
+            let remote_loc_rib = Arc::new(Mutex::new(
+                LocRib::new(&remote_config).await.unwrap(),
+            ));
+            let mut remote_peer =
+                Peer::new(remote_config, Arc::clone(&remote_loc_rib));
+            remote_peer.start();
+            let max_step = 50;
+            for _ in 0..max_step {
+                remote_peer.next().await;
+                tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+            }
+        });
+
+        // 先にremote_peer側の処理が進むことを保証するためのwait
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let max_step = 50;
+        for _ in 0..max_step {
+            peer.next().await;
+            if peer.state == State::Established {
+                break;
+            };
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert_eq!(peer.state, State::Established);
+
+        // UPDATEを送信したら、KeepaliveTimerはHold Timeの1/3からやり直す。
+        peer.keepalive_timer = Some(Instant::now() + Duration::from_secs(1));
+        peer.tcp_connection
+            .as_mut()
+            .unwrap()
+            .enqueue(Message::Update(UpdateMessage::new(
+                Arc::new(vec![]),
+                vec![],
+                vec![],
+            )));
+        peer.next().await;
+        let keepalive = peer.keepalive_timer.unwrap() - Instant::now();
+        assert!(keepalive > Duration::from_secs(5));
+        assert!(keepalive <= Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn session_with_hold_time_0_has_no_timers() {
+        let config: Config =
+            "64512 127.0.0.1 64513 127.0.0.2 active hold-time=0"
+                .parse()
+                .unwrap();
+        let loc_rib =
+            Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.start();
+
+        // 別スレッドでPeer構造体を実行しています。
+        // これはネットワーク上で離れた別のマシンを模擬しています。
+        tokio::spawn(async move {
+            let remote_config =
+                "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
+            let remote_loc_rib = Arc::new(Mutex::new(
+                LocRib::new(&remote_config).await.unwrap(),
+            ));
+            let mut remote_peer =
+                Peer::new(remote_config, Arc::clone(&remote_loc_rib));
+            remote_peer.start();
+            let max_step = 50;
+            for _ in 0..max_step {
+                remote_peer.next().await;
+                if remote_peer.state == State::Established {
+                    break;
+                };
+                tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+            }
+        });
+
+        // 先にremote_peer側の処理が進むことを保証するためのwait
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let max_step = 50;
+        for _ in 0..max_step {
+            peer.next().await;
+            if peer.state == State::Established {
+                break;
+            };
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert_eq!(peer.state, State::Established);
+        assert_eq!(peer.hold_timer, None);
+        assert_eq!(peer.keepalive_timer, None);
+        let status = peer.status().borrow().clone();
+        assert_eq!(status.hold_time, Some(HoldTime::from(0)));
+        assert!(status
+            .to_string()
+            .ends_with("hold-time=0 (hold timer and keepalive are disabled)"));
+    }
+
+    #[tokio::test]
+    async fn connection_from_larger_bgp_identifier_is_kept_on_collision() {
+        async fn connected_pair() -> (TcpStream, TcpStream) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let remote =
+                TcpStream::connect(listener.local_addr().unwrap()).await;
+            let (local, _) = listener.accept().await.unwrap();
+            (local, remote.unwrap())
+        }
+        async fn receive(stream: &mut TcpStream, length: usize) -> Message {
+            let mut buf = vec![0; length];
+            stream.read_exact(&mut buf).await.unwrap();
+            Message::try_from(BytesMut::from(&buf[..])).unwrap()
+        }
+
+        // (自身のrouter-id, 閉じられる接続がPeerからの接続か)
+        for (router_id, close_inbound) in
+            [("127.0.0.1", false), ("127.0.0.3", true)]
+        {
+            let config: Config = format!(
+                "64512 127.0.0.1 64513 127.0.0.2 active router-id={}",
+                router_id
+            )
+            .parse()
+            .unwrap();
+            let loc_rib =
+                Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+            let mut peer = Peer::new(config.clone(), Arc::clone(&loc_rib));
+            let (local, outbound) = connected_pair().await;
+            let (collision, mut inbound) = connected_pair().await;
+            peer.tcp_connection =
+                Some(Connection::accepted(local, &config).unwrap());
+            peer.collision =
+                Some(Connection::accepted(collision, &config).unwrap());
+            peer.state = State::OpenSent;
+
+            let open = OpenMessage::new(
+                64513.into(),
+                HoldTime::new(),
+                "127.0.0.2".parse().unwrap(),
+                vec![],
+            );
+            inbound
+                .write_all(&BytesMut::from(open.clone()))
+                .await
+                .unwrap();
+            let max_step = 50;
+            for _ in 0..max_step {
+                peer.detect_collision().await;
+                if peer.collision.is_none() {
+                    break;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+
+            let mut closed = if close_inbound { inbound } else { outbound };
+            assert_eq!(
+                receive(&mut closed, 21).await,
+                Message::new_connection_collision_resolution()
+            );
+            assert_eq!(peer.state, State::OpenSent);
+            assert_eq!(
+                peer.event_queue.dequeue(),
+                (!close_inbound).then_some(Event::BgpOpen(open))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unexpected_message_resets_session() {
+        let config: Config =
+            "64512 127.0.0.1 64513 127.0.0.250 active".parse().unwrap();
+        let loc_rib =
+            Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        let open = OpenMessage::new(
+            64513.into(),
+            HoldTime::new(),
+            "127.0.0.250".parse().unwrap(),
+            vec![],
+        );
+        let keepalive = keepalive::KeepaliveMessage::new();
+        let unexpected = [
+            (State::Connect, Event::KeepAliveMsg(keepalive.clone())),
+            (State::OpenSent, Event::KeepAliveMsg(keepalive)),
+            (State::OpenConfirm, Event::BgpOpen(open.clone())),
+            (State::Established, Event::BgpOpen(open)),
+        ];
+        for (state, event) in unexpected {
+            peer.state = state;
+            peer.delay_open_timer = Some(Instant::now() + LARGE_HOLD_TIME);
+            peer.event_queue.enqueue(event);
+            peer.next().await;
+            assert_eq!(peer.state, State::Idle);
+        }
+    }
+
+    #[tokio::test]
+    async fn session_is_closed_when_maximum_prefix_is_exceeded() {
+        use crate::path_attribute::{AsPath, Origin, PathAttribute};
+        let update = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![
+                    64513.into()
+                ])),
+                PathAttribute::NextHop("127.0.0.250".parse().unwrap()),
+            ]),
+            vec![
+                "10.1.0.0/24".parse().unwrap(),
+                "10.2.0.0/24".parse().unwrap(),
+                "10.3.0.0/24".parse().unwrap(),
+            ],
+            vec![],
+        );
+
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.250 active \
+                              maximum-prefix=2 maximum-prefix-action=warn"
+            .parse()
+            .unwrap();
+        let loc_rib =
+            Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.state = State::Established;
+        peer.event_queue.enqueue(Event::UpdateMsg(update.clone()));
+        peer.next().await;
+        assert_eq!(peer.state, State::Established);
+        assert!(peer.maximum_prefix_exceeded);
+
+        let config: Config = "64512 127.0.0.1 64513 127.0.0.250 active \
+                              maximum-prefix=2 maximum-prefix-restart=600"
+            .parse()
+            .unwrap();
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.state = State::Established;
+        peer.event_queue.enqueue(Event::UpdateMsg(update));
+        peer.next().await;
+        assert_eq!(peer.state, State::Idle);
+        assert_eq!(peer.adj_rib_in.prefix_count(), 0);
+        assert!(
+            peer.idle_hold_timer.unwrap()
+                > Instant::now() + Duration::from_secs(500)
+        );
+    }
+
+    #[test]
+    fn invalid_open_message_is_rejected() {
+        let config: Config =
+            "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
+        let open = |as_number: u16, bgp_identifier: &str| {
+            OpenMessage::new(
+                as_number.into(),
+                HoldTime::new(),
+                bgp_identifier.parse().unwrap(),
+                vec![],
+            )
+        };
+        let subcode = |open: &OpenMessage| {
+            open_message_error(open, &config).map(|n| n.error_subcode)
+        };
+        assert_eq!(subcode(&open(64513, "127.0.0.2")), None);
+        assert_eq!(
+            subcode(&open(64514, "127.0.0.2")),
+            Some(NotificationMessage::BAD_PEER_AS)
+        );
+        for bgp_identifier in ["0.0.0.0", "224.0.0.1", "127.0.0.1"] {
+            assert_eq!(
+                subcode(&open(64513, bgp_identifier)),
+                Some(NotificationMessage::BAD_BGP_IDENTIFIER)
+            );
+        }
+
+        let mut bytes = BytesMut::from(open(64513, "127.0.0.2"));
+        bytes[19] = 3;
+        let notification =
+            open_message_error(&bytes.try_into().unwrap(), &config).unwrap();
+        assert_eq!(
+            notification.error_subcode,
+            NotificationMessage::UNSUPPORTED_VERSION_NUMBER
+        );
+        assert_eq!(notification.data, vec![0, 4]);
+    }
+
+    #[test]
+    fn unsupported_capabilities_are_sent_in_notification() {
+        let required = vec![
+            Capability::RouteRefresh,
+            Capability::MultiProtocol(AddressFamily::IPV4_MPLS_VPN),
+        ];
+        let remote = vec![
+            Capability::MultiProtocol(AddressFamily::IPV4_UNICAST),
+            Capability::RouteRefresh,
+        ];
+        let missing = missing_capabilities(&required, &remote);
+        assert_eq!(
+            missing,
+            vec![Capability::MultiProtocol(AddressFamily::IPV4_MPLS_VPN)]
+        );
+
+        let notification = match Message::new_unsupported_capability(&missing)
+        {
+            Message::Notification(notification) => notification,
+            _ => unreachable!(),
+        };
+        assert!(is_capability_error(&notification));
+        assert_eq!(
+            Capability::from_u8_slice(&notification.data).unwrap(),
+            missing
+        );
+    }
+
+    #[test]
+    fn negotiate_capabilities_with_peer() {
+        let local = vec![
+            Capability::MultiProtocol(AddressFamily::IPV4_UNICAST),
+            Capability::RouteRefresh,
+            Capability::Fqdn {
+                hostname: "router1".to_string(),
+                domain: "".to_string(),
+            },
+        ];
+        let remote = vec![
+            Capability::MultiProtocol(AddressFamily::IPV4_UNICAST),
+            Capability::MultiProtocol(AddressFamily::IPV6_UNICAST),
+            Capability::FourOctetAsNumber(64513),
+            Capability::Fqdn {
+                hostname: "router2".to_string(),
+                domain: "".to_string(),
+            },
+        ];
+        assert_eq!(
+            negotiate_capabilities(&local, &remote),
+            vec![
+                Capability::MultiProtocol(AddressFamily::IPV4_UNICAST),
+                Capability::Fqdn {
+                    hostname: "router2".to_string(),
+                    domain: "".to_string(),
+                },
+            ]
+        );
+        assert_eq!(negotiate_capabilities(&[], &remote), vec![]);
+    }
+
+    #[test]
+    fn negotiate_address_families_with_peer() {
+        let local =
+            vec![AddressFamily::IPV4_UNICAST, AddressFamily::IPV6_UNICAST];
+
+        let remote =
+            vec![Capability::MultiProtocol(AddressFamily::IPV6_UNICAST)];
+        assert_eq!(
+            negotiate_address_families(&local, &remote),
+            vec![AddressFamily::IPV6_UNICAST]
+        );
+
+        // Multiprotocol Capabilityが無い場合はIPv4 unicastのみ。
+        assert_eq!(
+            negotiate_address_families(&local, &[]),
+            vec![AddressFamily::IPV4_UNICAST]
+        );
+    }
+
+    #[test]
+    fn negotiate_llgr_stale_time_with_peer() {
+        let remote = |stale_time| {
+            vec![Capability::LongLivedGracefulRestart(vec![LlgrFamily {
+                address_family: AddressFamily::IPV4_UNICAST,
+                flags: 0,
+                stale_time,
+            }])]
+        };
+        assert_eq!(
+            negotiate_llgr_stale_time(Some(3600), &remote(86400)),
+            Some(Duration::from_secs(3600))
+        );
+        assert_eq!(negotiate_llgr_stale_time(None, &remote(86400)), None);
+        assert_eq!(negotiate_llgr_stale_time(Some(3600), &[]), None);
+        assert_eq!(negotiate_llgr_stale_time(Some(3600), &remote(0)), None);
     }
 }
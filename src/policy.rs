@@ -0,0 +1,610 @@
+/// Peerから受信したルートと、Peerに広報するルートに適用するpolicy(route-map)の
+/// モジュールです。configの`import-policy`, `export-policy`で書いた順にルールを
+/// 評価し、最初に一致したルールに従ってルートをpermit/denyし、PathAttributeを変える。
+use std::iter::Peekable;
+use std::str::{Chars, FromStr};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+
+use crate::bgp_type::AutonomousSystemNumber;
+use crate::path_attribute::{Community, LargeCommunity, PathAttribute};
+use crate::routing::{
+    add_community, communities, prepend_as_path, IpNetwork, RibEntry,
+};
+
+/// ルールを順に評価し、最初に一致したルールを適用する。
+/// ルールを1つ以上設定した場合、どのルールにも一致しないルートはdenyする。
+/// ルールが無い場合は全てのルートをそのままpermitする。
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord, Default)]
+pub struct Policy(pub Vec<PolicyRule>);
+
+/// `permit`か`deny`に続けて、`,`区切りで条件と変更を並べたルールです。
+/// 全ての条件に一致したルートにだけ適用する。
+/// (例: `permit,prefix:10.0.0.0/8,community:65000:100,set-local-pref:200`)
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub struct PolicyRule {
+    pub permit: bool,
+    pub matches: Vec<PolicyMatch>,
+    pub sets: Vec<PolicySet>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub enum PolicyMatch {
+    // `prefix:<network>`。networkに含まれるルートに一致する。
+    Prefix(IpNetwork),
+    // `as-path:<AS番号>`。AS PathにそのAS番号を含むルートに一致する。
+    AsPath(AutonomousSystemNumber),
+    // `as-path-regex:<正規表現>`。AS Pathを表示した文字列に一致するルートに一致する。
+    AsPathRegex(AsPathRegex),
+    // `community:<community>`。そのCommunityを持つルートに一致する。
+    Community(Community),
+    // `large-community:<large community>`。そのLarge Communityを持つルートに一致する。
+    LargeCommunity(LargeCommunity),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum PolicySet {
+    // `set-local-pref:<値>`
+    LocalPref(u32),
+    // `set-med:<値>`
+    Med(u32),
+    // `set-community:<community>`。COMMUNITIESに追加する。
+    Community(Community),
+    // `delete-community:<community>`。COMMUNITIESから取り除く。
+    // `delete-community:all`の場合(None)はCOMMUNITIESごと取り除く。
+    DeleteCommunity(Option<Community>),
+    // `set-large-community:<large community>`。LARGE_COMMUNITYに追加する。
+    LargeCommunity(LargeCommunity),
+    // `delete-large-community:<large community>`。`all`の場合(None)は全て取り除く。
+    DeleteLargeCommunity(Option<LargeCommunity>),
+    // `prepend:<回数>`。import時はPeerのAS番号を、export時は自身のAS番号を
+    // AS Pathの先頭に追加する。iBGPとconfederation内のPeerとの間では何もしない。
+    Prepend(u8),
+}
+
+impl Policy {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// entryにpolicyを適用し、permitした場合は変更後のルートを返す。
+    /// PathAttributeを変えない場合は同じArcを返す。
+    /// prepend_asはprependで追加するAS番号で、Noneの場合はprependしない。
+    pub fn apply(
+        &self,
+        entry: &Arc<RibEntry>,
+        prepend_as: Option<AutonomousSystemNumber>,
+    ) -> Option<Arc<RibEntry>> {
+        if self.is_empty() {
+            return Some(Arc::clone(entry));
+        }
+        let rule = self.0.iter().find(|r| r.does_match(entry))?;
+        if !rule.permit {
+            return None;
+        }
+        if rule.sets.is_empty() {
+            return Some(Arc::clone(entry));
+        }
+        let mut path_attributes = (*entry.path_attributes).clone();
+        for set in rule.sets.iter() {
+            set.apply(&mut path_attributes, prepend_as);
+        }
+        Some(Arc::new(RibEntry {
+            path_attributes: Arc::new(path_attributes),
+            ..(**entry).clone()
+        }))
+    }
+}
+
+impl PolicyRule {
+    fn does_match(&self, entry: &RibEntry) -> bool {
+        self.matches.iter().all(|m| match m {
+            PolicyMatch::Prefix(network) => {
+                entry.network_address.is_subnet_of(network)
+            }
+            PolicyMatch::AsPath(as_number) => entry
+                .as_path()
+                .is_some_and(|as_path| as_path.does_contain(*as_number)),
+            PolicyMatch::AsPathRegex(regex) => regex.is_match(
+                &entry.as_path().map(|a| a.to_string()).unwrap_or_default(),
+            ),
+            PolicyMatch::Community(community) => {
+                communities(&entry.path_attributes).contains(community)
+            }
+            PolicyMatch::LargeCommunity(community) => {
+                large_communities(&entry.path_attributes).contains(community)
+            }
+        })
+    }
+}
+
+impl PolicySet {
+    fn apply(
+        &self,
+        path_attributes: &mut Vec<PathAttribute>,
+        prepend_as: Option<AutonomousSystemNumber>,
+    ) {
+        match *self {
+            PolicySet::LocalPref(local_pref) => {
+                path_attributes
+                    .retain(|p| !matches!(p, PathAttribute::LocalPref(_)));
+                path_attributes.push(PathAttribute::LocalPref(local_pref));
+            }
+            PolicySet::Med(med) => {
+                path_attributes
+                    .retain(|p| !matches!(p, PathAttribute::MultiExitDisc(_)));
+                path_attributes.push(PathAttribute::MultiExitDisc(med));
+            }
+            PolicySet::Community(community) => {
+                if !communities(path_attributes).contains(&community) {
+                    add_community(path_attributes, community);
+                }
+            }
+            PolicySet::DeleteCommunity(community) => {
+                for p in path_attributes.iter_mut() {
+                    if let PathAttribute::Communities(c) = p {
+                        c.retain(|c| community.is_some_and(|d| *c != d));
+                    }
+                }
+                path_attributes.retain(
+                    |p| !matches!(p, PathAttribute::Communities(c) if c.is_empty()),
+                );
+            }
+            PolicySet::LargeCommunity(community) => {
+                match path_attributes.iter_mut().find_map(|p| match p {
+                    PathAttribute::LargeCommunities(c) => Some(c),
+                    _ => None,
+                }) {
+                    Some(c) if c.contains(&community) => {}
+                    Some(c) => c.push(community),
+                    None => path_attributes.push(
+                        PathAttribute::LargeCommunities(vec![community]),
+                    ),
+                }
+            }
+            PolicySet::DeleteLargeCommunity(community) => {
+                for p in path_attributes.iter_mut() {
+                    if let PathAttribute::LargeCommunities(c) = p {
+                        c.retain(|c| community.is_some_and(|d| *c != d));
+                    }
+                }
+                path_attributes.retain(|p| {
+                    !matches!(p, PathAttribute::LargeCommunities(c) if c.is_empty())
+                });
+            }
+            PolicySet::Prepend(count) => {
+                if let Some(as_number) = prepend_as {
+                    prepend_as_path(path_attributes, as_number, count);
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for PolicyRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut items = s.split(',');
+        let permit = match items.next() {
+            Some("permit") => true,
+            Some("deny") => false,
+            _ => anyhow::bail!("policy rule must start with permit or deny"),
+        };
+        let mut rule = Self {
+            permit,
+            matches: vec![],
+            sets: vec![],
+        };
+        for item in items {
+            let (kind, value) = item
+                .split_once(':')
+                .context(format!("`{}` must be `<kind>:<value>`", item))?;
+            let context = format!("cannot parse `{}`", item);
+            match kind {
+                "prefix" => rule.matches.push(PolicyMatch::Prefix(
+                    value.parse().context(context)?,
+                )),
+                "as-path" => rule.matches.push(PolicyMatch::AsPath(
+                    value.parse::<u16>().context(context)?.into(),
+                )),
+                "as-path-regex" => rule.matches.push(
+                    PolicyMatch::AsPathRegex(value.parse().context(context)?),
+                ),
+                "community" => rule.matches.push(PolicyMatch::Community(
+                    value.parse().context(context)?,
+                )),
+                "large-community" => {
+                    rule.matches.push(PolicyMatch::LargeCommunity(
+                        value.parse().context(context)?,
+                    ))
+                }
+                "delete-community" => {
+                    rule.sets.push(PolicySet::DeleteCommunity(match value {
+                        "all" => None,
+                        _ => Some(value.parse().context(context)?),
+                    }))
+                }
+                "set-large-community" => rule.sets.push(
+                    PolicySet::LargeCommunity(value.parse().context(context)?),
+                ),
+                "delete-large-community" => rule.sets.push(
+                    PolicySet::DeleteLargeCommunity(match value {
+                        "all" => None,
+                        _ => Some(value.parse().context(context)?),
+                    }),
+                ),
+                "set-local-pref" => rule.sets.push(PolicySet::LocalPref(
+                    value.parse().context(context)?,
+                )),
+                "set-med" => rule
+                    .sets
+                    .push(PolicySet::Med(value.parse().context(context)?)),
+                "set-community" => rule.sets.push(PolicySet::Community(
+                    value.parse().context(context)?,
+                )),
+                "prepend" => rule
+                    .sets
+                    .push(PolicySet::Prepend(value.parse().context(context)?)),
+                _ => anyhow::bail!("unknown policy item, `{}`", item),
+            }
+        }
+        if !rule.permit && !rule.sets.is_empty() {
+            anyhow::bail!("deny rule cannot change path attributes");
+        }
+        Ok(rule)
+    }
+}
+
+/// PathAttributesのLARGE_COMMUNITYを返す。無ければ空。
+fn large_communities(path_attributes: &[PathAttribute]) -> &[LargeCommunity] {
+    path_attributes
+        .iter()
+        .find_map(|p| match p {
+            PathAttribute::LargeCommunities(c) => Some(&c[..]),
+            _ => None,
+        })
+        .unwrap_or(&[])
+}
+
+/// AS Pathのフィルタに使う、小さな正規表現です。
+/// AS Pathを`64513 64514 {64515,64516}`のように表示した文字列と照合する。
+/// `^`, `$`, `.`, `[0-9]`, `[^0-9]`, `*`, `+`, `?`, `(a|b)`, `a|b`と、
+/// 文字列の先頭、末尾、AS番号の区切り(空白, `,`, 括弧)に一致する`_`を使える。
+/// (例: `^64513_`はPeerのASが64513のルート、`_174$`はAS174がoriginateしたルート)
+/// configを読んだ時に一度だけparseし、ルート毎にはparseしない。
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub struct AsPathRegex(Vec<Vec<Piece>>);
+
+/// 正規表現の1文字分と、その繰り返しの回数です。maxがNoneの場合は上限が無い。
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+struct Piece {
+    atom: Atom,
+    min: usize,
+    max: Option<usize>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+enum Atom {
+    Start,
+    End,
+    Boundary,
+    Any,
+    Char(char),
+    Class {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Group(Vec<Vec<Piece>>),
+}
+
+impl AsPathRegex {
+    /// 文字列のどこかに一致すればtrueを返す。
+    pub fn is_match(&self, s: &str) -> bool {
+        let chars: Vec<char> = s.chars().collect();
+        let atom = Atom::Group(self.0.clone());
+        (0..=chars.len())
+            .any(|start| match_atom(&atom, &chars, start, &|_| true))
+    }
+}
+
+/// piecesがsのposから一致し、続きの照合kも一致すればtrueを返す。
+fn match_pieces(
+    pieces: &[Piece],
+    s: &[char],
+    pos: usize,
+    k: &dyn Fn(usize) -> bool,
+) -> bool {
+    match pieces.split_first() {
+        None => k(pos),
+        Some((piece, rest)) => {
+            match_repeat(piece, 0, s, pos, &|p| match_pieces(rest, s, p, k))
+        }
+    }
+}
+
+/// できるだけ多く繰り返し、一致しなければ繰り返しを減らして試す。
+fn match_repeat(
+    piece: &Piece,
+    count: usize,
+    s: &[char],
+    pos: usize,
+    k: &dyn Fn(usize) -> bool,
+) -> bool {
+    // 幅が0の一致を無限に繰り返さないように、minを超えたら進まない一致は認めない。
+    if piece.max.is_none_or(|max| count < max)
+        && match_atom(&piece.atom, s, pos, &|p| {
+            (p != pos || count < piece.min)
+                && match_repeat(piece, count + 1, s, p, k)
+        })
+    {
+        return true;
+    }
+    count >= piece.min && k(pos)
+}
+
+fn match_atom(
+    atom: &Atom,
+    s: &[char],
+    pos: usize,
+    k: &dyn Fn(usize) -> bool,
+) -> bool {
+    let next = s.get(pos);
+    match atom {
+        Atom::Start => pos == 0 && k(pos),
+        Atom::End => pos == s.len() && k(pos),
+        Atom::Boundary => {
+            ((pos == 0 || pos == s.len()) && k(pos))
+                || next.is_some_and(|c| " ,{}()[]".contains(*c)) && k(pos + 1)
+        }
+        Atom::Any => next.is_some() && k(pos + 1),
+        Atom::Char(c) => next == Some(c) && k(pos + 1),
+        Atom::Class { ranges, negated } => {
+            next.is_some_and(|c| {
+                ranges.iter().any(|(from, to)| (from..=to).contains(&c))
+                    != *negated
+            }) && k(pos + 1)
+        }
+        Atom::Group(alternatives) => alternatives
+            .iter()
+            .any(|pieces| match_pieces(pieces, s, pos, k)),
+    }
+}
+
+/// `|`で区切られた選択肢を、`)`か文字列の終わりまでparseする。
+fn parse_alternatives(chars: &mut Peekable<Chars>) -> Result<Vec<Vec<Piece>>> {
+    let mut alternatives = vec![];
+    loop {
+        let mut pieces = vec![];
+        while let Some(c) = chars.next_if(|c| *c != '|' && *c != ')') {
+            let atom = match c {
+                '^' => Atom::Start,
+                '$' => Atom::End,
+                '_' => Atom::Boundary,
+                '.' => Atom::Any,
+                '[' => parse_class(chars)?,
+                '(' => {
+                    let group = parse_alternatives(chars)?;
+                    if chars.next() != Some(')') {
+                        anyhow::bail!("`(` is not closed");
+                    }
+                    Atom::Group(group)
+                }
+                '\\' => Atom::Char(chars.next().context("`\\` at the end")?),
+                '*' | '+' | '?' => anyhow::bail!("`{}` follows nothing", c),
+                ']' => anyhow::bail!("`]` is not opened"),
+                c => Atom::Char(c),
+            };
+            let (min, max) = match chars.next_if(|c| "*+?".contains(*c)) {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                _ => (1, Some(1)),
+            };
+            pieces.push(Piece { atom, min, max });
+        }
+        alternatives.push(pieces);
+        if chars.next_if_eq(&'|').is_none() {
+            return Ok(alternatives);
+        }
+    }
+}
+
+/// `[`の後から`]`までの文字クラスをparseする。
+fn parse_class(chars: &mut Peekable<Chars>) -> Result<Atom> {
+    let negated = chars.next_if_eq(&'^').is_some();
+    let mut ranges = vec![];
+    loop {
+        let from = match chars.next() {
+            Some(']') if !ranges.is_empty() => break,
+            Some(c) => c,
+            None => anyhow::bail!("`[` is not closed"),
+        };
+        let to = match chars.next_if_eq(&'-') {
+            Some(_) => chars.next().context("`[` is not closed")?,
+            None => from,
+        };
+        if from > to {
+            anyhow::bail!("invalid range {}-{}", from, to);
+        }
+        ranges.push((from, to));
+    }
+    Ok(Atom::Class { ranges, negated })
+}
+
+impl FromStr for AsPathRegex {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars().peekable();
+        let alternatives = parse_alternatives(&mut chars)?;
+        if chars.next().is_some() {
+            anyhow::bail!("`)` is not opened in {}", s);
+        }
+        Ok(Self(alternatives))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path_attribute::{AsPath, Origin};
+
+    fn entry(network: &str, as_path: Vec<u16>) -> Arc<RibEntry> {
+        Arc::new(RibEntry {
+            network_address: network.parse().unwrap(),
+            labels: vec![],
+            path_attributes: Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(
+                    as_path.into_iter().map(|a| a.into()).collect(),
+                )),
+                PathAttribute::NextHop("10.0.0.3".parse().unwrap()),
+                PathAttribute::Communities(vec![Community(65000 << 16 | 1)]),
+            ]),
+        })
+    }
+
+    #[test]
+    fn first_matching_rule_is_applied() {
+        let policy = Policy(
+            [
+                "deny,as-path:64600",
+                "permit,prefix:10.0.0.0/8,community:65000:1,\
+                 set-local-pref:200,set-med:10,set-community:65000:2,prepend:2",
+                "permit,prefix:192.168.0.0/16",
+            ]
+            .iter()
+            .map(|r| r.parse().unwrap())
+            .collect(),
+        );
+        let local_as = Some(64512.into());
+
+        assert_eq!(
+            policy.apply(&entry("10.1.0.0/24", vec![64513, 64600]), local_as),
+            None
+        );
+        assert_eq!(
+            policy.apply(&entry("172.16.0.0/16", vec![64513]), local_as),
+            None
+        );
+        let permitted = entry("192.168.1.0/24", vec![64513]);
+        assert!(Arc::ptr_eq(
+            &policy.apply(&permitted, local_as).unwrap(),
+            &permitted
+        ));
+
+        let changed = policy
+            .apply(&entry("10.1.0.0/24", vec![64513]), local_as)
+            .unwrap();
+        assert_eq!(changed.local_pref(), Some(200));
+        assert_eq!(changed.med(), Some(10));
+        assert_eq!(
+            communities(&changed.path_attributes),
+            &[Community(65000 << 16 | 1), Community(65000 << 16 | 2)]
+        );
+        assert_eq!(changed.as_path().unwrap().path_length(), 3);
+        assert!(changed.as_path().unwrap().does_contain(64512.into()));
+    }
+
+    #[test]
+    fn as_path_regex_matches_as_path() {
+        let cases = [
+            ("^64513_", "64513 174", true),
+            ("^64513_", "64513", true),
+            ("^64513_", "645130 174", false),
+            ("^64513_", "174 64513", false),
+            ("_174$", "64513 174", true),
+            ("_174$", "64513 1174", false),
+            ("_174_", "64513 174 3356", true),
+            ("^$", "", true),
+            ("^$", "64513", false),
+            ("^[0-9]+$", "64513", true),
+            ("^[0-9]+$", "64513 174", false),
+            ("^(64513|64514)_", "64514 174", true),
+            ("^6451[^3]_", "64513 174", false),
+            ("_64516[,}]", "64513 {64515,64516}", true),
+            ("^64513_.*_3356$", "64513 174 3356", true),
+            ("^64513_.*_3356$", "64513 3356 174", false),
+            ("^64513_?174?$", "64513 17", true),
+            (".*", "", true),
+        ];
+        for (regex, as_path, expected) in cases {
+            let compiled: AsPathRegex = regex.parse().unwrap();
+            assert_eq!(
+                compiled.is_match(as_path),
+                expected,
+                "{} {}",
+                regex,
+                as_path
+            );
+        }
+        for regex in ["(64513", "64513)", "*", "[9-0]", "[", "a|+"] {
+            assert!(regex.parse::<AsPathRegex>().is_err(), "{}", regex);
+        }
+
+        let policy =
+            Policy(vec!["permit,as-path-regex:_64600$".parse().unwrap()]);
+        let local_as = Some(64512.into());
+        assert!(policy
+            .apply(&entry("10.1.0.0/24", vec![64513, 64600]), local_as)
+            .is_some());
+        assert!(policy
+            .apply(&entry("10.1.0.0/24", vec![64600, 64513]), local_as)
+            .is_none());
+    }
+
+    #[test]
+    fn communities_are_added_deleted_and_matched() {
+        let local_as = Some(64512.into());
+        let large: LargeCommunity = "64512:1:1".parse().unwrap();
+        let policy = Policy(vec!["permit,community:65000:1,\
+                                  delete-community:65000:1,\
+                                  set-community:65000:2,\
+                                  set-large-community:64512:1:1"
+            .parse()
+            .unwrap()]);
+        let changed = policy
+            .apply(&entry("10.1.0.0/24", vec![64513]), local_as)
+            .unwrap();
+        assert_eq!(
+            communities(&changed.path_attributes),
+            &[Community(65000 << 16 | 2)]
+        );
+        assert_eq!(large_communities(&changed.path_attributes), &[large]);
+
+        let strip = Policy(vec!["permit,large-community:64512:1:1,\
+                                 delete-community:all,\
+                                 delete-large-community:all"
+            .parse()
+            .unwrap()]);
+        let stripped = strip.apply(&changed, local_as).unwrap();
+        assert!(!stripped.path_attributes.iter().any(|p| matches!(
+            p,
+            PathAttribute::Communities(_) | PathAttribute::LargeCommunities(_)
+        )));
+        assert_eq!(strip.apply(&stripped, local_as), None);
+    }
+
+    #[test]
+    fn empty_policy_permits_all_routes() {
+        let permitted = entry("10.1.0.0/24", vec![64513]);
+        assert_eq!(
+            Policy::default().apply(&permitted, Some(64512.into())),
+            Some(permitted)
+        );
+    }
+
+    #[test]
+    fn invalid_policy_rule_is_rejected() {
+        for rule in [
+            "accept,prefix:10.0.0.0/8",
+            "permit,prefix:10.0.0.0/33",
+            "permit,unknown:1",
+            "deny,set-med:10",
+        ] {
+            assert!(rule.parse::<PolicyRule>().is_err(), "{}", rule);
+        }
+    }
+}
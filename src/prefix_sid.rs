@@ -0,0 +1,296 @@
+/// BGP Prefix-SID Attribute (RFC8669, RFC9252)を扱うモジュールです。
+/// Segment RoutingのLabel-IndexやSRv6のService SIDを運ぶ。
+/// 自身はSegment Routingの転送を行わないので、受信した値を表示できるように解釈し、
+/// 再広報するときはそのまま送り出す。
+use std::fmt;
+use std::net::Ipv6Addr;
+
+use bytes::{BufMut, BytesMut};
+
+use crate::error::ConvertBytesToBgpMessageError;
+
+/// Prefix-SID AttributeのTLVを分割する。TLVはType(1) + Length(2) + Valueで構成される。
+fn split_tlvs(
+    bytes: &[u8],
+) -> Result<Vec<(u8, &[u8])>, ConvertBytesToBgpMessageError> {
+    let mut tlvs = vec![];
+    let mut i = 0;
+    while bytes.len() > i {
+        if bytes.len() < i + 3 {
+            return Err(ConvertBytesToBgpMessageError::from(anyhow::anyhow!(
+                "Prefix-SIDのTLVの長さが足りません。"
+            )));
+        }
+        let type_ = bytes[i];
+        let length = u16::from_be_bytes([bytes[i + 1], bytes[i + 2]]) as usize;
+        let value = bytes.get(i + 3..i + 3 + length).ok_or_else(|| {
+            ConvertBytesToBgpMessageError::from(anyhow::anyhow!(
+                "Prefix-SIDのTLV(type {})の長さが足りません。",
+                type_
+            ))
+        })?;
+        tlvs.push((type_, value));
+        i += 3 + length;
+    }
+    Ok(tlvs)
+}
+
+fn put_tlv(bytes: &mut BytesMut, type_: u8, value: &[u8]) {
+    bytes.put_u8(type_);
+    bytes.put_u16(value.len() as u16);
+    bytes.put(value);
+}
+
+/// BGP Prefix-SID Attribute (path attribute type 40)
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+pub struct PrefixSid(pub Vec<PrefixSidTlv>);
+
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+pub enum PrefixSidTlv {
+    // Type 1 (RFC8669 Section 3.1)
+    LabelIndex { flags: u16, label_index: u32 },
+    // Type 3 (RFC8669 Section 3.2)。SRGBの(base, range)の組。
+    OriginatorSrgb { flags: u16, srgbs: Vec<(u32, u32)> },
+    // Type 5, 6 (RFC9252 Section 2)
+    Srv6Service(Srv6Service),
+    // 対応していないTLV用
+    Unknown { type_: u8, value: Vec<u8> },
+}
+
+/// SRv6 L3 Service TLV, SRv6 L2 Service TLVです。
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+pub struct Srv6Service {
+    // L3 Serviceならfalse, L2 Serviceならtrue
+    pub is_l2: bool,
+    pub sids: Vec<Srv6SidInformation>,
+}
+
+/// SRv6 SID Information Sub-TLV (RFC9252 Section 3.1)
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+pub struct Srv6SidInformation {
+    pub sid: Ipv6Addr,
+    pub flags: u8,
+    pub endpoint_behavior: u16,
+    // SRv6 SID Structure Sub-Sub-TLVなど。再広報のためにbytesのまま保持する。
+    pub sub_sub_tlvs: Vec<u8>,
+}
+
+impl PrefixSid {
+    pub fn bytes_len(&self) -> usize {
+        BytesMut::from(self).len()
+    }
+
+    pub fn label_index(&self) -> Option<u32> {
+        self.0.iter().find_map(|t| match t {
+            PrefixSidTlv::LabelIndex { label_index, .. } => Some(*label_index),
+            _ => None,
+        })
+    }
+
+    pub fn srv6_sids(&self) -> Vec<Ipv6Addr> {
+        self.0
+            .iter()
+            .filter_map(|t| match t {
+                PrefixSidTlv::Srv6Service(s) => Some(s),
+                _ => None,
+            })
+            .flat_map(|s| s.sids.iter().map(|i| i.sid))
+            .collect()
+    }
+}
+
+impl TryFrom<&[u8]> for PrefixSid {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let mut tlvs = vec![];
+        for (type_, v) in split_tlvs(value)? {
+            let tlv = match type_ {
+                // Reserved(1) + Flags(2) + Label Index(4)
+                1 if v.len() == 7 => PrefixSidTlv::LabelIndex {
+                    flags: u16::from_be_bytes([v[1], v[2]]),
+                    label_index: u32::from_be_bytes([v[3], v[4], v[5], v[6]]),
+                },
+                // Flags(2) + SRGB(Base 3 octets + Range 3 octets)の繰り返し
+                3 if v.len() >= 2 && (v.len() - 2) % 6 == 0 => {
+                    PrefixSidTlv::OriginatorSrgb {
+                        flags: u16::from_be_bytes([v[0], v[1]]),
+                        srgbs: v[2..]
+                            .chunks(6)
+                            .map(|c| {
+                                (
+                                    u32::from_be_bytes([0, c[0], c[1], c[2]]),
+                                    u32::from_be_bytes([0, c[3], c[4], c[5]]),
+                                )
+                            })
+                            .collect(),
+                    }
+                }
+                5 | 6 if !v.is_empty() => PrefixSidTlv::Srv6Service(
+                    Srv6Service::from_u8_slice(type_ == 6, &v[1..])?,
+                ),
+                _ => PrefixSidTlv::Unknown {
+                    type_,
+                    value: v.to_vec(),
+                },
+            };
+            tlvs.push(tlv);
+        }
+        Ok(Self(tlvs))
+    }
+}
+
+impl From<&PrefixSid> for BytesMut {
+    fn from(p: &PrefixSid) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        for tlv in &p.0 {
+            match tlv {
+                PrefixSidTlv::LabelIndex { flags, label_index } => {
+                    let mut v = BytesMut::new();
+                    v.put_u8(0);
+                    v.put_u16(*flags);
+                    v.put_u32(*label_index);
+                    put_tlv(&mut bytes, 1, &v);
+                }
+                PrefixSidTlv::OriginatorSrgb { flags, srgbs } => {
+                    let mut v = BytesMut::new();
+                    v.put_u16(*flags);
+                    for (base, range) in srgbs {
+                        v.put(&base.to_be_bytes()[1..]);
+                        v.put(&range.to_be_bytes()[1..]);
+                    }
+                    put_tlv(&mut bytes, 3, &v);
+                }
+                PrefixSidTlv::Srv6Service(s) => {
+                    let mut v = BytesMut::new();
+                    v.put_u8(0);
+                    for sid in &s.sids {
+                        let mut sub = BytesMut::new();
+                        sub.put_u8(0);
+                        sub.put(&sid.sid.octets()[..]);
+                        sub.put_u8(sid.flags);
+                        sub.put_u16(sid.endpoint_behavior);
+                        sub.put_u8(0);
+                        sub.put(&sid.sub_sub_tlvs[..]);
+                        put_tlv(&mut v, 1, &sub);
+                    }
+                    put_tlv(&mut bytes, if s.is_l2 { 6 } else { 5 }, &v);
+                }
+                PrefixSidTlv::Unknown { type_, value } => {
+                    put_tlv(&mut bytes, *type_, value)
+                }
+            }
+        }
+        bytes
+    }
+}
+
+impl Srv6Service {
+    fn from_u8_slice(
+        is_l2: bool,
+        bytes: &[u8],
+    ) -> Result<Self, ConvertBytesToBgpMessageError> {
+        let mut sids = vec![];
+        // SRv6 SID Information Sub-TLV(type 1)以外のSub-TLVは定義されていないので無視する。
+        for (_, v) in split_tlvs(bytes)?.into_iter().filter(|(t, _)| *t == 1) {
+            // Reserved(1) + SID(16) + Flags(1) + Endpoint Behavior(2) + Reserved(1)
+            if v.len() < 21 {
+                return Err(ConvertBytesToBgpMessageError::from(
+                    anyhow::anyhow!(
+                        "SRv6 SID Information Sub-TLVの長さが足りません。"
+                    ),
+                ));
+            }
+            let sid: [u8; 16] = v[1..17].try_into().expect("長さは確認済み");
+            sids.push(Srv6SidInformation {
+                sid: Ipv6Addr::from(sid),
+                flags: v[17],
+                endpoint_behavior: u16::from_be_bytes([v[18], v[19]]),
+                sub_sub_tlvs: v[21..].to_vec(),
+            });
+        }
+        Ok(Self { is_l2, sids })
+    }
+}
+
+impl fmt::Display for PrefixSid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tlvs: Vec<String> = self
+            .0
+            .iter()
+            .map(|t| match t {
+                PrefixSidTlv::LabelIndex { label_index, .. } => {
+                    format!("label-index {}", label_index)
+                }
+                PrefixSidTlv::OriginatorSrgb { srgbs, .. } => {
+                    let srgbs: Vec<String> = srgbs
+                        .iter()
+                        .map(|(base, range)| format!("{}/{}", base, range))
+                        .collect();
+                    format!("srgb {}", srgbs.join(","))
+                }
+                PrefixSidTlv::Srv6Service(s) => {
+                    let sids: Vec<String> = s
+                        .sids
+                        .iter()
+                        .map(|i| {
+                            format!(
+                                "{} behavior {}",
+                                i.sid, i.endpoint_behavior
+                            )
+                        })
+                        .collect();
+                    format!(
+                        "srv6-{}-service {}",
+                        if s.is_l2 { "l2" } else { "l3" },
+                        sids.join(",")
+                    )
+                }
+                PrefixSidTlv::Unknown { type_, .. } => {
+                    format!("unknown-tlv {}", type_)
+                }
+            })
+            .collect();
+        write!(f, "{}", tlvs.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_bytes_to_prefix_sid_and_prefix_sid_to_bytes() {
+        let prefix_sid = PrefixSid(vec![
+            PrefixSidTlv::LabelIndex {
+                flags: 0,
+                label_index: 100,
+            },
+            PrefixSidTlv::OriginatorSrgb {
+                flags: 0,
+                srgbs: vec![(16000, 8000)],
+            },
+            PrefixSidTlv::Srv6Service(Srv6Service {
+                is_l2: false,
+                sids: vec![Srv6SidInformation {
+                    sid: "fc00:0:1:e000::".parse().unwrap(),
+                    flags: 0,
+                    endpoint_behavior: 0x0013,
+                    // SRv6 SID Structure Sub-Sub-TLV
+                    sub_sub_tlvs: vec![1, 0, 6, 32, 16, 16, 0, 16, 64],
+                }],
+            }),
+            PrefixSidTlv::Unknown {
+                type_: 200,
+                value: vec![1, 2, 3],
+            },
+        ]);
+        let bytes: BytesMut = (&prefix_sid).into();
+        assert_eq!(PrefixSid::try_from(&bytes[..]).unwrap(), prefix_sid);
+        assert_eq!(prefix_sid.label_index(), Some(100));
+        assert_eq!(
+            prefix_sid.srv6_sids(),
+            vec!["fc00:0:1:e000::".parse::<Ipv6Addr>().unwrap()]
+        );
+    }
+}
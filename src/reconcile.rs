@@ -0,0 +1,47 @@
+/// カーネルのルーティングテーブルを、LocRibが書き込んだはずのルートに定期的に合わせるモジュールです。
+/// 他のプロセスや`ip route flush`で自身が書き込んだルートが消されたり、
+/// 削除し損ねたルートが残ったりしても、次の確認で元に戻す。
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::routing::{FibDriftStats, LocRib};
+
+/// intervalごとにLocRibとカーネルのルーティングテーブルを比べ続ける。
+pub async fn run(loc_rib: Arc<Mutex<LocRib>>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    // 起動直後はLocRibが書き込みを終えていないので、最初のtickは飛ばす。
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match reconcile(&loc_rib).await {
+            Ok(drift) if drift.missing > 0 || drift.orphaned > 0 => info!(
+                "kernel routing table is reconciled, \
+                 missing={} orphaned={}.",
+                drift.missing, drift.orphaned
+            ),
+            Ok(_) => {}
+            Err(e) => {
+                warn!("cannot reconcile kernel routing table: {:?}.", e)
+            }
+        }
+    }
+}
+
+/// LocRibとカーネルのルーティングテーブルを一度比べ、ずれを直す。
+/// カーネルの参照と書き込みを待つ間、他のPeerがLocRibを使えるように、
+/// LocRibのロックは比べる間だけ取る。
+pub async fn reconcile(loc_rib: &Mutex<LocRib>) -> Result<FibDriftStats> {
+    let (route_writer, fib_target) = {
+        let loc_rib = loc_rib.lock().await;
+        (loc_rib.route_writer(), loc_rib.fib_target())
+    };
+    let installed = route_writer.lookup_installed_routes(fib_target).await?;
+    let (drift, changes) =
+        loc_rib.lock().await.compare_kernel_routing_table(installed);
+    changes.program().await?;
+    Ok(drift)
+}
@@ -1,21 +1,47 @@
-use std::collections::hash_map::Keys;
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
+use std::cmp::Ordering;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::hash::Hash;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::ops::{Deref, DerefMut};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Instant;
 
-use crate::bgp_type::AutonomousSystemNumber;
-use crate::config::Config;
+use crate::bgp_ls::{LinkStateNlri, LinkStateRibEntry, TopologyGraph};
+use crate::bgp_type::{
+    AddressFamily, Afi, AutonomousSystemNumber, MplsLabel, Safi,
+};
+use crate::config::{
+    ConditionalAdvertisement, ConfederationSession, Config, Damping, LinuxVrf,
+    MaintenancePolicy, OriginatedAttributes, OwnPrefixCheck, Redistribution,
+    DEFAULT_LOCAL_PREF,
+};
 use crate::error::{
-    ConfigParseError, ConstructIpv4NetworkError, ConvertBytesToBgpMessageError,
+    ConfigParseError, ConstructIpv4NetworkError, ConstructIpv6NetworkError,
+    ConvertBytesToBgpMessageError,
 };
+use crate::evpn::{EvpnRibEntry, EvpnRoute};
+use crate::flowspec::{FlowSpecRibEntry, FlowSpecRule};
+use crate::kernel::{
+    self, FibTarget, FibWriter, KernelRoutes, SharedRouteWriter,
+};
+use crate::packets::notification::MAX_MESSAGE_LENGTH;
 use crate::packets::update::UpdateMessage;
-use crate::path_attribute::{AsPath, Origin, PathAttribute};
+use crate::path_attribute::{
+    AsPath, Community, ExtendedCommunity, MpNlri, MpReachNlri, MpUnreachNlri,
+    Origin, PathAttribute,
+};
+use crate::policy::Policy;
+use crate::vpn::{
+    RouteTargetMembership, RtcRibEntry, VpnRibEntry, Vpnv4Prefix, Vrf,
+};
 use anyhow::{Context, Result};
 use bytes::{BufMut, BytesMut};
-use futures::stream::{Next, TryStreamExt};
-use rtnetlink::{new_connection, Handle, IpVersion};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
 pub struct Ipv4Network(ipnetwork::Ipv4Network);
@@ -40,6 +66,12 @@ impl From<ipnetwork::Ipv4Network> for Ipv4Network {
     }
 }
 
+impl From<ipnetwork::Ipv4Network> for IpNetwork {
+    fn from(ip_network: ipnetwork::Ipv4Network) -> Self {
+        IpNetwork::V4(ip_network.into())
+    }
+}
+
 impl From<&Ipv4Network> for BytesMut {
     fn from(network: &Ipv4Network) -> BytesMut {
         let prefix = network.prefix();
@@ -106,377 +138,7003 @@ impl Ipv4Network {
         while bytes.len() > i {
             let prefix = bytes[i];
             i += 1;
-            if prefix == 0 {
-                networks.push(
-                    Ipv4Network::new(Ipv4Addr::new(0, 0, 0, 0), prefix)
-                        .context("")?,
-                );
-            } else if (1..=8).contains(&prefix) {
-                networks.push(
-                    Ipv4Network::new(Ipv4Addr::new(bytes[i], 0, 0, 0), prefix)
-                        .context("")?,
-                );
-                i += 1;
-            } else if (9..=16).contains(&prefix) {
-                networks.push(
-                    Ipv4Network::new(
-                        Ipv4Addr::new(bytes[i], bytes[i + 1], 0, 0),
-                        prefix,
-                    )
-                    .context("bytes -> Ipv4に変換出来ませんでした。")?,
-                );
-                i += 2;
-            } else if (17..=24).contains(&prefix) {
-                networks.push(
-                    Ipv4Network::new(
-                        Ipv4Addr::new(bytes[i], bytes[i + 1], bytes[i + 2], 0),
-                        prefix,
-                    )
-                    .context("bytes -> Ipv4に変換出来ませんでした。")?,
-                );
-                i += 3;
-            } else if (24..=32).contains(&prefix) {
-                networks.push(
-                    Ipv4Network::new(
-                        Ipv4Addr::new(
-                            bytes[i],
-                            bytes[i + 1],
-                            bytes[i + 2],
-                            bytes[i + 3],
-                        ),
-                        prefix,
-                    )
-                    .context("bytes -> Ipv4に変換出来ませんでした。")?,
-                );
-                i += 4;
-            } else {
+            if prefix > 32 {
                 return Err(ConvertBytesToBgpMessageError::from(anyhow::anyhow!(
                     "bytes -> Ipv4Networkに変換が出来ませんでした。Prefixが0-32の間ではありません。"
                 )));
-            };
+            }
+            let length = (prefix as usize).div_ceil(8);
+            if bytes.len() < i + length {
+                return Err(ConvertBytesToBgpMessageError::from(anyhow::anyhow!(
+                    "bytes -> Ipv4Networkに変換が出来ませんでした。bytesの長さが足りません。"
+                )));
+            }
+            let mut octets = [0u8; 4];
+            octets[..length].copy_from_slice(&bytes[i..i + length]);
+            networks.push(
+                Ipv4Network::new(Ipv4Addr::from(octets), prefix)
+                    .context("bytes -> Ipv4に変換出来ませんでした。")?,
+            );
+            i += length;
         }
         Ok(networks)
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
-pub enum RibEntryStatus {
-    New,
-    UnChanged,
-}
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct Ipv6Network(ipnetwork::Ipv6Network);
 
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub struct Rib(HashMap<Arc<RibEntry>, RibEntryStatus>);
-impl Rib {
-    pub fn new() -> Self {
-        Self(HashMap::new())
-    }
-    pub fn insert(&mut self, entry: Arc<RibEntry>) {
-        self.0.entry(entry).or_insert(RibEntryStatus::New);
-    }
+impl Deref for Ipv6Network {
+    type Target = ipnetwork::Ipv6Network;
 
-    pub fn update_to_all_unchanged(&mut self) {
-        self.0
-            .iter_mut()
-            .for_each(|(_, v)| *v = RibEntryStatus::UnChanged);
+    fn deref(&self) -> &Self::Target {
+        &self.0
     }
+}
 
-    pub fn routes(&self) -> Keys<'_, Arc<RibEntry>, RibEntryStatus> {
-        self.0.keys()
+impl DerefMut for Ipv6Network {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
     }
+}
 
-    pub fn does_contain_new_route(&self) -> bool {
-        self.0
-            .values()
-            .map(|v| &RibEntryStatus::New == v)
-            .any(|v| v)
+impl From<ipnetwork::Ipv6Network> for Ipv6Network {
+    fn from(ip_network: ipnetwork::Ipv6Network) -> Self {
+        Self(ip_network)
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub struct LocRib {
-    rib: Rib,
-    local_as_number: AutonomousSystemNumber,
+impl From<&Ipv6Network> for BytesMut {
+    fn from(network: &Ipv6Network) -> BytesMut {
+        let prefix = network.prefix();
+        let n = network.network().octets();
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(prefix);
+        bytes.put(&n[0..network.bytes_len() - 1]);
+        bytes
+    }
 }
 
-impl Deref for LocRib {
-    type Target = Rib;
+impl FromStr for Ipv6Network {
+    type Err = ConfigParseError;
 
-    fn deref(&self) -> &Self::Target {
-        &self.rib
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let network = s.parse::<ipnetwork::Ipv6Network>().context(format!(
+            "s: {:?}を、Ipv6Networkにparse出来ませんでした",
+            s
+        ))?;
+        Ok(Self(network))
     }
 }
 
-impl DerefMut for LocRib {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.rib
+impl Ipv6Network {
+    /// prefix長を表す1 octet + prefixを表すのに必要なoctet数
+    pub fn bytes_len(&self) -> usize {
+        1 + (self.prefix() as usize).div_ceil(8)
     }
-}
 
-impl LocRib {
-    pub async fn new(config: &Config) -> Result<Self> {
-        let path_attributes = Arc::new(vec![
-            PathAttribute::Origin(Origin::Igp),
-            // AS Pathは、ほかのピアから受信したルートと統一的に扱うために、
-            // LocRib -> AdjRibOutにルートを送るときに、自分のAS番号を
-            // 追加するので、ここでは空にしておく。
-            PathAttribute::AsPath(AsPath::AsSequence(vec![])),
-            PathAttribute::NextHop(config.local_ip),
-        ]);
+    pub fn new(
+        addr: Ipv6Addr,
+        prefix: u8,
+    ) -> Result<Self, ConstructIpv6NetworkError> {
+        let net =
+            ipnetwork::Ipv6Network::new(addr, prefix).context(format!(
+                "Ipv6NetworkをConstruct出来ませんでした。addr: {}, prefix: {}",
+                addr, prefix
+            ))?;
+        Ok(Self(net))
+    }
 
-        let mut rib = Rib::new();
-        for network in &config.networks {
-            let routes = Self::lookup_kernel_routing_table(*network).await?;
-            for route in routes {
-                rib.insert(Arc::new(RibEntry {
-                    network_address: route,
-                    path_attributes: Arc::clone(&path_attributes),
-                }))
+    pub fn from_u8_slice(
+        bytes: &[u8],
+    ) -> Result<Vec<Self>, ConvertBytesToBgpMessageError> {
+        let mut networks = vec![];
+        let mut i = 0;
+        while bytes.len() > i {
+            let prefix = bytes[i];
+            i += 1;
+            if prefix > 128 {
+                return Err(ConvertBytesToBgpMessageError::from(anyhow::anyhow!(
+                    "bytes -> Ipv6Networkに変換が出来ませんでした。Prefixが0-128の間ではありません。"
+                )));
+            }
+            let length = (prefix as usize).div_ceil(8);
+            if bytes.len() < i + length {
+                return Err(ConvertBytesToBgpMessageError::from(anyhow::anyhow!(
+                    "bytes -> Ipv6Networkに変換が出来ませんでした。bytesの長さが足りません。"
+                )));
             }
+            let mut octets = [0u8; 16];
+            octets[..length].copy_from_slice(&bytes[i..i + length]);
+            networks.push(
+                Ipv6Network::new(Ipv6Addr::from(octets), prefix)
+                    .context("bytes -> Ipv6に変換出来ませんでした。")?,
+            );
+            i += length;
         }
-        Ok(Self {
-            rib,
-            local_as_number: config.local_as,
-        })
+        Ok(networks)
     }
+}
 
-    async fn lookup_kernel_routing_table(
-        network_address: Ipv4Network,
-    ) -> Result<(Vec<Ipv4Network>)> {
-        let (connection, handle, _) = new_connection()?;
-        tokio::spawn(connection);
-        let mut routes = handle.route().get(IpVersion::V4).execute();
-        let mut results = vec![];
-        while let Some(route) = routes.try_next().await? {
-            let destination = if let Some((IpAddr::V4(addr), prefix)) =
-                route.destination_prefix()
-            {
-                ipnetwork::Ipv4Network::new(addr, prefix)?.into()
-            } else {
-                continue;
-            };
-
-            if destination != network_address {
-                continue;
-            }
+/// IPv4, IPv6のどちらのprefixも表せるNLRIの型です。
+/// RibEntryはaddress familyに依らずこの型でprefixを保持します。
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum IpNetwork {
+    V4(Ipv4Network),
+    V6(Ipv6Network),
+}
 
-            results.push(destination);
-        }
-        Ok(results)
+impl From<Ipv4Network> for IpNetwork {
+    fn from(network: Ipv4Network) -> Self {
+        IpNetwork::V4(network)
     }
+}
 
-    /// AdjRibInから必要なルートをインストールする。
-    /// この時、自ASが含まれているルートはインストールしない。
-    /// 参考: 9.1.2.  Phase 2: Route Selection in RFC4271.
-    pub fn install_from_adj_rib_in(&mut self, adj_rib_in: &AdjRibIn) {
-        // closure内にselfを2回captureされて、借用チェックによるエラーを避けるため。
-        let local_as = self.local_as_number;
+impl From<Ipv6Network> for IpNetwork {
+    fn from(network: Ipv6Network) -> Self {
+        IpNetwork::V6(network)
+    }
+}
 
-        adj_rib_in
-            .routes()
-            .filter(|entry| !entry.does_contain_as(local_as))
-            .for_each(|entry| self.insert(Arc::clone(&entry)));
-    }
-
-    pub async fn write_to_kernel_routing_table(&self) -> Result<()> {
-        let (connection, handle, _) = new_connection()?;
-        tokio::spawn(connection);
-        for e in self.routes() {
-            for p in e.path_attributes.iter() {
-                if let PathAttribute::NextHop(gateway) = p {
-                    let dest = e.network_address;
-                    handle
-                        .route()
-                        .add()
-                        .v4()
-                        .destination_prefix(dest.ip(), dest.prefix())
-                        .gateway(*gateway)
-                        .execute()
-                        .await?;
-                    break;
-                }
-            }
+impl From<&IpNetwork> for BytesMut {
+    fn from(network: &IpNetwork) -> BytesMut {
+        match network {
+            IpNetwork::V4(n) => n.into(),
+            IpNetwork::V6(n) => n.into(),
         }
-        Ok(())
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub struct AdjRibOut(Rib);
-
-impl Deref for AdjRibOut {
-    type Target = Rib;
+impl FromStr for IpNetwork {
+    type Err = ConfigParseError;
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains(':') {
+            Ok(IpNetwork::V6(s.parse()?))
+        } else {
+            Ok(IpNetwork::V4(s.parse()?))
+        }
     }
 }
 
-impl DerefMut for AdjRibOut {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpNetwork::V4(n) => write!(f, "{}", n.0),
+            IpNetwork::V6(n) => write!(f, "{}", n.0),
+        }
     }
 }
 
-impl AdjRibOut {
-    pub fn new() -> Self {
-        Self(Rib::new())
+impl IpNetwork {
+    pub fn afi(&self) -> Afi {
+        match self {
+            IpNetwork::V4(_) => Afi::Ipv4,
+            IpNetwork::V6(_) => Afi::Ipv6,
+        }
     }
 
-    /// LocRibから必要なルートをインストールする。
-    /// この時、Remote AS番号が含まれているルートはインストールしない。
-    pub fn install_from_loc_rib(&mut self, loc_rib: &LocRib, config: &Config) {
-        loc_rib
-            .routes()
-            .filter(|entry| !entry.does_contain_as(config.remote_as))
-            .for_each(|r| self.insert(Arc::clone(r)));
+    pub fn bytes_len(&self) -> usize {
+        match self {
+            IpNetwork::V4(n) => n.bytes_len(),
+            IpNetwork::V6(n) => n.bytes_len(),
+        }
     }
 
-    /// AdjRibOutからUpdateMessageに変換する。
-    /// PathAttributeごとにUpdateMessageが分かれるためVec<UpdateMessage>の戻り値にしている。
-    pub fn create_update_messages(
-        &self,
-        local_ip: Ipv4Addr,
-        local_as: AutonomousSystemNumber,
-    ) -> Vec<UpdateMessage> {
-        let mut hash_map: HashMap<Arc<Vec<PathAttribute>>, Vec<Ipv4Network>> =
-            HashMap::new();
-        for entry in self.routes() {
-            if let Some(routes) = hash_map.get_mut(&entry.path_attributes) {
-                routes.push(entry.network_address);
-            } else {
-                hash_map.insert(
-                    Arc::clone(&entry.path_attributes),
-                    vec![entry.network_address],
-                );
-            }
+    /// otherと同じか、otherに含まれるネットワークであるかを返す。
+    pub fn is_subnet_of(&self, other: &IpNetwork) -> bool {
+        match (self, other) {
+            (IpNetwork::V4(n), IpNetwork::V4(o)) => n.0.is_subnet_of(o.0),
+            (IpNetwork::V6(n), IpNetwork::V6(o)) => n.0.is_subnet_of(o.0),
+            _ => false,
         }
+    }
 
-        let mut updates = vec![];
-        for (path_attributes, routes) in hash_map.into_iter() {
-            let mut path_attributes =
-                Arc::<Vec<PathAttribute>>::unwrap_or_clone(path_attributes);
-            // PathAttributeを二つ変更する。local ip, as_path add;
-            for p in path_attributes.iter_mut() {
-                if let PathAttribute::NextHop(n) = p {
-                    *n = local_ip
+    /// 1つのアドレスだけを表す/32, /128のネットワークであるかを返す。
+    pub fn is_host(&self) -> bool {
+        match self {
+            IpNetwork::V4(n) => n.0.prefix() == 32,
+            IpNetwork::V6(n) => n.0.prefix() == 128,
+        }
+    }
+}
+
+/// networkがallowed-originationのいずれかに含まれるかを返す。
+/// allowed-originationが指定されていなければ全てのネットワークを許可する。
+fn is_allowed_origination(allowed: &[IpNetwork], network: &IpNetwork) -> bool {
+    allowed.is_empty() || allowed.iter().any(|a| network.is_subnet_of(a))
+}
+
+/// Labeled unicastのNLRI (RFC8277)。ラベルスタック + prefixで構成される。
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+pub struct LabeledPrefix {
+    pub labels: Vec<MplsLabel>,
+    pub prefix: IpNetwork,
+}
+
+impl LabeledPrefix {
+    pub fn bytes_len(&self) -> usize {
+        // ラベル(3 octets * ラベル数) + prefix(先頭のLengthを含む)
+        3 * self.labels.len() + self.prefix.bytes_len()
+    }
+
+    pub fn from_u8_slice(
+        afi: Afi,
+        bytes: &[u8],
+    ) -> Result<Vec<Self>, ConvertBytesToBgpMessageError> {
+        let mut prefixes = vec![];
+        let mut i = 0;
+        while bytes.len() > i {
+            let mut length_bits = bytes[i] as usize;
+            i += 1;
+            let mut labels = vec![];
+            loop {
+                if bytes.len() < i + 3 || length_bits < 24 {
+                    return Err(ConvertBytesToBgpMessageError::from(
+                        anyhow::anyhow!(
+                            "Labeled unicast NLRIのラベルが不正です。"
+                        ),
+                    ));
                 }
-                if let PathAttribute::AsPath(ases) = p {
-                    ases.push(local_as)
+                let (label, bottom_of_stack) = MplsLabel::from_nlri_bytes([
+                    bytes[i],
+                    bytes[i + 1],
+                    bytes[i + 2],
+                ]);
+                labels.push(label);
+                i += 3;
+                length_bits -= 24;
+                if bottom_of_stack || label == MplsLabel::WITHDRAWN {
+                    break;
                 }
             }
+            // ラベルを除いた残りは通常のprefixのbytes表現と同じ。
+            let prefix_bytes_length = length_bits.div_ceil(8);
+            if bytes.len() < i + prefix_bytes_length {
+                return Err(ConvertBytesToBgpMessageError::from(
+                    anyhow::anyhow!(
+                        "Labeled unicast NLRIのprefixが不正です。"
+                    ),
+                ));
+            }
+            let mut prefix_bytes = vec![length_bits as u8];
+            prefix_bytes.extend_from_slice(&bytes[i..i + prefix_bytes_length]);
+            let prefix = match afi {
+                Afi::Ipv4 => Ipv4Network::from_u8_slice(&prefix_bytes)?
+                    .pop()
+                    .map(IpNetwork::V4),
+                _ => Ipv6Network::from_u8_slice(&prefix_bytes)?
+                    .pop()
+                    .map(IpNetwork::V6),
+            }
+            .context("Labeled unicast NLRIのprefixが不正です。")?;
+            i += prefix_bytes_length;
 
-            updates.push(UpdateMessage::new(
-                Arc::new(path_attributes),
-                routes,
-                vec![],
-            ));
+            prefixes.push(Self { labels, prefix });
         }
-        updates
+        Ok(prefixes)
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub struct AdjRibIn(Rib);
+impl From<&LabeledPrefix> for BytesMut {
+    fn from(p: &LabeledPrefix) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        let prefix_bytes: BytesMut = (&p.prefix).into();
+        let length_bits = 24 * p.labels.len() + prefix_bytes[0] as usize;
+        bytes.put_u8(length_bits as u8);
+        for (i, label) in p.labels.iter().enumerate() {
+            let bottom_of_stack =
+                i == p.labels.len() - 1 && *label != MplsLabel::WITHDRAWN;
+            bytes.put(&label.to_nlri_bytes(bottom_of_stack)[..]);
+        }
+        // 先頭のprefix長を表すoctetは上で書き込んだLengthに含まれている。
+        bytes.put(&prefix_bytes[1..]);
+        bytes
+    }
+}
 
-impl Deref for AdjRibIn {
-    type Target = Rib;
+/// ルートを保持するテーブルです。
+/// 型引数はエントリの型で、IPv4/IPv6 unicastのルートはRibEntry,
+/// それ以外のaddress familyはaddress family毎のエントリの型を使います。
+/// エントリはNLRI(RibKey)毎に、そのNLRIの候補のpathの組として保持するので、
+/// best pathの選択や置き換えなどのNLRI毎の操作はそのNLRIのpathだけを見ればよい。
+/// 生成するUpdateMessageの順序や表示を実行毎に変えないために、
+/// NLRIとpathはそれぞれの順序で並べて保持します。
+/// generationはエントリを追加するか取り除く度に進みます。テーブルの変化を
+/// 知りたい側は、前回見たgenerationを覚えておいて比べるので、
+/// テーブルを書き換えずに済みます。
+#[derive(Clone)]
+pub struct Rib<E: RibKey = RibEntry> {
+    paths: BTreeMap<E::Key, BTreeSet<Arc<E>>>,
+    len: usize,
+    generation: u64,
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+/// Ribのエントリです。keyが同じエントリは同じNLRIの候補のpathとして扱います。
+/// エントリの順序は、keyの順序と一致するようにします。(keyを最初のfieldにする)
+pub trait RibKey: Ord {
+    type Key: Ord + Clone + fmt::Debug;
+
+    fn key(&self) -> &Self::Key;
+}
+
+impl RibKey for RibEntry {
+    type Key = IpNetwork;
+
+    fn key(&self) -> &IpNetwork {
+        &self.network_address
     }
 }
 
-impl DerefMut for AdjRibIn {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+// generationは変化を検出するためだけのものなので、比較や表示ではエントリだけを見る。
+// 追加した順序によって結果が変わらないようにするため。
+impl<E: RibKey> PartialEq for Rib<E> {
+    fn eq(&self, other: &Self) -> bool {
+        self.routes().eq(other.routes())
     }
 }
 
-impl AdjRibIn {
-    pub fn new() -> Self {
-        Self(Rib::new())
+impl<E: RibKey> Eq for Rib<E> {}
+
+impl<E: RibKey + fmt::Debug> fmt::Debug for Rib<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.routes()).finish()
     }
-    pub fn install_from_update(
-        &mut self,
-        update: UpdateMessage,
-        config: &Config,
-    ) {
-        // ToDo: withdrawnに対応する。
-        let path_attributes = update.path_attributes;
-        for network in update.network_layer_reachability_information {
-            let rib_entry = Arc::new(RibEntry {
-                network_address: network,
-                path_attributes: Arc::clone(&path_attributes),
-            });
-            // PathAttributesが変わってたらインストールする必要がある。
-            self.insert(rib_entry);
+}
+
+impl<E: RibKey> Rib<E> {
+    pub fn new() -> Self {
+        Self {
+            paths: BTreeMap::new(),
+            len: 0,
+            generation: 0,
         }
     }
-}
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash)]
-pub struct RibEntry {
-    pub network_address: Ipv4Network,
-    pub path_attributes: Arc<Vec<PathAttribute>>,
-}
+    /// エントリを追加する。既にある場合は何もせず、generationも進めない。
+    pub fn insert(&mut self, entry: Arc<E>) {
+        let paths = self.paths.entry(entry.key().clone()).or_default();
+        if paths.insert(entry) {
+            self.generation += 1;
+            self.len += 1;
+        }
+    }
 
-impl RibEntry {
-    fn does_contain_as(&self, as_number: AutonomousSystemNumber) -> bool {
-        for path_attribute in self.path_attributes.iter() {
-            if let PathAttribute::AsPath(as_path) = path_attribute {
-                return as_path.does_contain(as_number);
-            }
+    /// entryと同じNLRIの他のpathを取り除いてから、entryを追加する。
+    /// 1つのPeerからは1つのNLRIに1つのpathしか受信しないので、
+    /// AdjRibInで新しく受信したpathで置き換えるのに使う。(implicit withdraw)
+    pub fn replace(&mut self, entry: Arc<E>) {
+        let old: Vec<Arc<E>> = self
+            .paths_of(entry.key())
+            .filter(|e| **e != entry)
+            .cloned()
+            .collect();
+        for e in old.iter() {
+            self.remove(e);
         }
-        false
+        self.insert(entry);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tokio::time::{sleep, Duration};
+    /// 全てのエントリを、NLRIの順に返す。
+    pub fn routes(&self) -> impl Iterator<Item = &Arc<E>> {
+        self.paths.values().flatten()
+    }
 
-    #[tokio::test]
-    async fn loclib_can_lookup_routing_table() {
-        // 本テストの値は環境によって異なる。
-        // 本実装では開発機, テスト実施機に10.200.100.0/24に属するIPが付与されていることを仮定している。
-        let network =
-            ipnetwork::Ipv4Network::new("10.200.100.0".parse().unwrap(), 24)
-                .unwrap()
-                .into();
-        let routes =
-            LocRib::lookup_kernel_routing_table(network).await.unwrap();
-        let expected = vec![network];
-        assert_eq!(routes, expected);
+    /// keyのNLRIのpathを返す。
+    pub fn paths_of(&self, key: &E::Key) -> impl Iterator<Item = &Arc<E>> {
+        self.paths.get(key).into_iter().flatten()
     }
 
-    #[tokio::test]
-    async fn loc_rib_to_adj_rib_out() {
+    pub fn contains(&self, entry: &Arc<E>) -> bool {
+        self.paths
+            .get(entry.key())
+            .is_some_and(|paths| paths.contains(entry))
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn remove(&mut self, entry: &Arc<E>) -> bool {
+        let Some(paths) = self.paths.get_mut(entry.key()) else {
+            return false;
+        };
+        let removed = paths.remove(entry);
+        if paths.is_empty() {
+            self.paths.remove(entry.key());
+        }
+        if removed {
+            self.generation += 1;
+            self.len -= 1;
+        }
+        removed
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+impl Rib {
+    /// address family毎のRibのviewを返す。
+    pub fn routes_of(
+        &self,
+        address_family: AddressFamily,
+    ) -> impl Iterator<Item = &Arc<RibEntry>> {
+        self.routes()
+            .filter(move |e| e.address_family() == address_family)
+    }
+
+    /// networkのルートのうち、address familyが一致するものを返す。
+    fn entries_of(
+        &self,
+        network: IpNetwork,
+        address_family: AddressFamily,
+    ) -> Vec<Arc<RibEntry>> {
+        self.paths_of(&network)
+            .filter(|e| e.address_family() == address_family)
+            .cloned()
+            .collect()
+    }
+
+    /// networkのエントリ以降を、エントリの順に返す。
+    fn entries_from(
+        &self,
+        network: IpNetwork,
+    ) -> impl Iterator<Item = &Arc<RibEntry>> {
+        self.paths.range(network..).flat_map(|(_, paths)| paths)
+    }
+
+    /// addrを含むネットワークのうち、prefix長が最も長いネットワークのルートを返す。
+    /// prefix長の長い方から順に、そのネットワークのpathだけを見る。
+    /// ルート数をnとすると、IPv4では最大33回、IPv6では最大129回のO(log n)の探索になる。
+    /// 専用のprefix trieを持たず、insert, removeの度に別の索引を更新しなくて済むようにしている。
+    pub fn lookup_longest_match(&self, addr: IpAddr) -> Vec<Arc<RibEntry>> {
+        let networks: Vec<IpNetwork> = match addr {
+            IpAddr::V4(a) => (0..=32)
+                .rev()
+                .map(|p| {
+                    let n = ipnetwork::Ipv4Network::new(a, p).unwrap();
+                    ipnetwork::Ipv4Network::new(n.network(), p).unwrap().into()
+                })
+                .collect(),
+            IpAddr::V6(a) => (0..=128)
+                .rev()
+                .map(|p| {
+                    let n = ipnetwork::Ipv6Network::new(a, p).unwrap();
+                    IpNetwork::V6(
+                        ipnetwork::Ipv6Network::new(n.network(), p)
+                            .unwrap()
+                            .into(),
+                    )
+                })
+                .collect(),
+        };
+        networks
+            .into_iter()
+            .map(|network| {
+                self.paths_of(&network).cloned().collect::<Vec<_>>()
+            })
+            .find(|entries| !entries.is_empty())
+            .unwrap_or_default()
+    }
+
+    /// prefixと同じか、prefixに含まれるネットワークのルートを返す。
+    /// prefixに含まれるネットワークのアドレスはprefixのアドレスの範囲にあるので、
+    /// その範囲だけを見る。返すルート数をkとするとO(log n + k)で、範囲には
+    /// prefixより短いprefix長のネットワークも先頭に最大でprefix長の数だけ含まれる。
+    pub fn covered_routes(&self, prefix: IpNetwork) -> Vec<Arc<RibEntry>> {
+        // prefix長0のネットワークは、同じアドレスのネットワークの中で最も前に並ぶ。
+        let (first, last) = match prefix {
+            IpNetwork::V4(p) => (
+                ipnetwork::Ipv4Network::new(p.network(), 0).unwrap().into(),
+                IpAddr::V4(p.broadcast()),
+            ),
+            IpNetwork::V6(p) => (
+                IpNetwork::V6(
+                    ipnetwork::Ipv6Network::new(p.network(), 0)
+                        .unwrap()
+                        .into(),
+                ),
+                IpAddr::V6(p.broadcast()),
+            ),
+        };
+        self.entries_from(first)
+            .take_while(|e| match (e.network_address, last) {
+                (IpNetwork::V4(n), IpAddr::V4(last)) => n.ip() <= last,
+                (IpNetwork::V6(n), IpAddr::V6(last)) => n.ip() <= last,
+                _ => false,
+            })
+            .filter(|e| e.network_address.is_subnet_of(&prefix))
+            .cloned()
+            .collect()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct LocRib {
+    rib: Rib,
+    // すべてのPeerから受信したVPNv4ルートと、VRFから広報するVPNv4ルート。
+    pub vpnv4: Rib<VpnRibEntry>,
+    // すべてのPeerから受信したFlowSpecルールと、Configで設定したFlowSpecルール。
+    pub flowspec: Rib<FlowSpecRibEntry>,
+    // すべてのPeerから受信したEVPNルート。収集のためだけに保持し、広報はしない。
+    pub evpn: Rib<EvpnRibEntry>,
+    // すべてのPeerから受信したBGP-LSのルート。IGPのトポロジを収集するために保持する。
+    pub link_state: Rib<LinkStateRibEntry>,
+    // VRFのimport route targetから作成した、自身が広報するRoute Target Membership。
+    pub rtc: Rib<RtcRibEntry>,
+    pub vrfs: Vec<Vrf>,
+    // control socketから広報を指示されたルート。
+    announced: HashMap<IpNetwork, Arc<RibEntry>>,
+    // Peer毎の、そのPeerから受信してribに入れたルート。
+    // Peerとのセッションが無くなったときに取り除くために使う。
+    learned: HashMap<IpAddr, HashSet<Arc<RibEntry>>>,
+    // Peer毎の、そのPeerから受信してvpnv4に入れたルート。
+    learned_vpnv4: LearnedRoutes<VpnRibEntry>,
+    // 同様に、Peer毎のflowspec, evpn, link_stateに入れたルート。
+    learned_flowspec: LearnedRoutes<FlowSpecRibEntry>,
+    learned_evpn: LearnedRoutes<EvpnRibEntry>,
+    learned_link_state: LearnedRoutes<LinkStateRibEntry>,
+    // Peerがwithdrawしてribから取り除き、まだカーネルのルーティングテーブルから
+    // 削除していないルート。
+    withdrawn: Vec<Arc<RibEntry>>,
+    // Long-Lived Graceful Restartにより、セッションが切れた後もLLGR_STALEを
+    // 付けて保持しているPeer毎のルート。
+    stale: HashMap<IpAddr, HashSet<Arc<RibEntry>>>,
+    // カーネルのルーティングテーブルに存在する間だけ広報するルート。
+    // ribに入っているかどうかはrefresh_originated_networksで更新する。
+    kernel_checked: Vec<Arc<RibEntry>>,
+    // カーネルのルーティングテーブルから広報するルートの条件。
+    redistributions: Vec<Redistribution>,
+    // redistributionsに一致し、ribに入れているルート。
+    redistributed: Vec<Arc<RibEntry>>,
+    // control socketからの指示やカーネルのルーティングテーブルの変化で
+    // LocRibが変わる度に増える。
+    // Peerはこれを見てLocRibChangedイベントを発生させる。
+    generation: u64,
+    local_as_number: AutonomousSystemNumber,
+    // confederationを設定している場合のidentifier。
+    // これをAS Pathに含むルートはconfederationの外から戻ってきたルート。
+    confederation_identifier: Option<AutonomousSystemNumber>,
+    // ルートを受信したiBGPのPeer。route reflectionで反射先を決めるのに使う。
+    internal_peers: HashMap<IpAddr, InternalPeer>,
+    // ルートを受信したPeerのBGP Identifier。best pathの選択で比べるのに使う。
+    bgp_identifiers: HashMap<IpAddr, Ipv4Addr>,
+    // Peer毎の、AdjRibInのroute flap dampingの状態。statsで表示するために使う。
+    damping: HashMap<IpAddr, DampingStats>,
+    // reconcile_kernel_routing_tableで検出した、カーネルのルーティングテーブルとの
+    // ずれの累計。statsで表示するために使う。
+    fib_drift: FibDriftStats,
+    local_ip: IpAddr,
+    // 自身がoriginateするルートに付けるLOCAL_PREF。
+    local_pref: u32,
+    // カーネルのルーティングテーブルに書き込む、ネットワーク毎のルートの最大数。
+    maximum_paths: usize,
+    // Labeled unicastのルートをカーネルにMPLS encapのルートとして書き込むか。
+    mpls_encap: bool,
+    // ルートを書き込むカーネルのルーティングテーブルとmetric。
+    // linux-vrfのLocRibでは、カーネルのルートの参照にもこのテーブルを使う。
+    fib_target: FibTarget,
+    // カーネルのルーティングテーブルで解決できないnext hop。
+    unreachable_next_hops: HashSet<IpAddr>,
+    // 到達性を確認したnext hop。これ以外のnext hopのルートは、
+    // カーネルに書き込む前に到達性を確認する。
+    checked_next_hops: HashSet<IpAddr>,
+    // next hopに到達できないため、ribから外しているルート。
+    unresolved: Rib,
+    // 自身がoriginateしてよいネットワーク。空の場合は制限しない。
+    allowed_originations: Vec<IpNetwork>,
+    // fib-writer-task=onの場合の、カーネルのルーティングテーブルに書き込むタスク。
+    // Noneの場合はその場で書き込み、完了を待つ。
+    fib_writer: Option<FibWriter>,
+    // カーネルのルーティングテーブルへの書き込みに使うもの。
+    route_writer: SharedRouteWriter,
+}
+
+/// linux-vrf毎のLocRibです。
+/// linux-vrfを設定していないPeerのLocRibは、Noneをkeyにして先頭に並ぶ。
+pub type LocRibs = BTreeMap<Option<LinuxVrf>, Arc<Mutex<LocRib>>>;
+
+/// unicast以外のaddress familyで、Peer毎に受信したルートです。
+/// 同じルートを複数のPeerから受信した場合は、全てのPeerがwithdrawするまでLocRibに残す。
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct LearnedRoutes<E: Eq + Hash>(HashMap<IpAddr, HashSet<Arc<E>>>);
+
+impl<E: RibKey + Hash> LearnedRoutes<E> {
+    fn new() -> Self {
+        Self(HashMap::new())
+    }
+
+    /// peerから受信しているルートをroutesに置き換え、ribに反映する。
+    /// peerが広報しなくなり、他のPeerからも受信していないルートはribから取り除いて返す。
+    fn replace(
+        &mut self,
+        peer: IpAddr,
+        routes: HashSet<Arc<E>>,
+        rib: &mut Rib<E>,
+    ) -> Vec<Arc<E>> {
+        let old = self.0.remove(&peer).unwrap_or_default();
+        let removed: Vec<Arc<E>> = old
+            .difference(&routes)
+            .filter(|e| !self.0.values().any(|l| l.contains(*e)))
+            .cloned()
+            .collect();
+        for entry in &removed {
+            rib.remove(entry);
+        }
+        for entry in &routes {
+            rib.insert(Arc::clone(entry));
+        }
+        if !routes.is_empty() {
+            self.0.insert(peer, routes);
+        }
+        removed
+    }
+}
+
+/// next hopの到達性が変わったことにより、ribから外したルートと戻したルートです。
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct NextHopChanges {
+    pub unreachable: Vec<Arc<RibEntry>>,
+    pub reachable: Vec<Arc<RibEntry>>,
+}
+
+/// 同じネットワークのルートのうちbest pathに選ばれたものと、選ばれた理由です。
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BestPath {
+    pub entry: Arc<RibEntry>,
+    pub reason: BestPathReason,
+    // best pathを含む、同じネットワークのルートの数。
+    pub candidates: usize,
+}
+
+/// best pathが2番目に良いルートより優先された理由です。
+/// 参考: 9.1.2.2. Breaking Ties (Phase 2) in RFC4271.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum BestPathReason {
+    OnlyPath,
+    // LLGR_STALEの付いていないルートを優先する。(RFC9494 Section 4.3)
+    NotLlgrStale,
+    HigherLocalPref,
+    ShorterAsPath,
+    LowerOrigin,
+    // 同じ隣のASから受信したルートの間でだけ比べる。MEDが無い場合は0とみなす。
+    LowerMed,
+    // ルートを受信したPeerのBGP Identifierが小さい方を選ぶ。
+    // 自身がoriginateしたルートはどのPeerのルートよりも優先する。
+    LowerRouterId,
+    // Peerのアドレスの代わりにnext hopの小さい方を選ぶ。
+    LowerNextHop,
+}
+
+impl fmt::Display for BestPathReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BestPathReason::OnlyPath => write!(f, "only path"),
+            BestPathReason::NotLlgrStale => write!(f, "not llgr stale"),
+            BestPathReason::HigherLocalPref => {
+                write!(f, "higher local pref")
+            }
+            BestPathReason::ShorterAsPath => write!(f, "shorter as path"),
+            BestPathReason::LowerOrigin => write!(f, "lower origin"),
+            BestPathReason::LowerMed => write!(f, "lower med"),
+            BestPathReason::LowerRouterId => write!(f, "lower router id"),
+            BestPathReason::LowerNextHop => write!(f, "lower next hop"),
+        }
+    }
+}
+
+impl fmt::Display for BestPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.entry.network_address)?;
+        if let Some(next_hop) = self.entry.next_hop() {
+            write!(f, " next-hop {}", next_hop)?;
+        }
+        if let Some(as_path) = self.entry.as_path() {
+            write!(f, " as-path [{}]", as_path)?;
+        }
+        if let Some(origin) = self.entry.origin() {
+            write!(f, " origin {}", origin)?;
+        }
+        if let Some(local_pref) = self.entry.local_pref() {
+            write!(f, " local-pref {}", local_pref)?;
+        }
+        if let Some(med) = self.entry.med() {
+            write!(f, " med {}", med)?;
+        }
+        write!(f, " best: {} ({} paths)", self.reason, self.candidates)
+    }
+}
+
+/// LocRibのRib毎のルートの数と、メモリ使用量の見積もりです。
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct RibStats {
+    pub unicast: usize,
+    pub vpnv4: usize,
+    pub flowspec: usize,
+    pub evpn: usize,
+    pub link_state: usize,
+    pub rtc: usize,
+    pub vrf: usize,
+    // next hopに到達できないため、ribから外しているルート。
+    pub unresolved: usize,
+    // ルートの間で共有しているPathAttributeの組の数。
+    pub attribute_sets: usize,
+    // ルートとPathAttributeの組が使うメモリの見積もり(bytes)。
+    // PathAttributeの中でさらに確保しているメモリ(AS_PATHなど)は含まない。
+    pub estimated_memory: usize,
+    // 全てのPeerのAdjRibInの、route flap dampingの状態の合計。
+    pub damping: DampingStats,
+    // カーネルのルーティングテーブルとのずれの累計。
+    pub fib_drift: FibDriftStats,
+}
+
+impl RibStats {
+    pub fn routes(&self) -> usize {
+        self.unicast
+            + self.vpnv4
+            + self.flowspec
+            + self.evpn
+            + self.link_state
+            + self.rtc
+            + self.vrf
+            + self.unresolved
+    }
+}
+
+/// 複数のLocRibの統計を合計する。
+/// 別のLocRibで共有しているPathAttributeの組は重複して数える。
+impl std::ops::AddAssign for RibStats {
+    fn add_assign(&mut self, other: Self) {
+        self.unicast += other.unicast;
+        self.vpnv4 += other.vpnv4;
+        self.flowspec += other.flowspec;
+        self.evpn += other.evpn;
+        self.link_state += other.link_state;
+        self.rtc += other.rtc;
+        self.vrf += other.vrf;
+        self.unresolved += other.unresolved;
+        self.attribute_sets += other.attribute_sets;
+        self.estimated_memory += other.estimated_memory;
+        self.damping = [self.damping, other.damping].into_iter().sum();
+        self.fib_drift += other.fib_drift;
+    }
+}
+
+impl fmt::Display for RibStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "routes={} (unicast={} vpnv4={} flowspec={} evpn={} \
+             link-state={} rtc={} vrf={} unresolved={}) \
+             attribute-sets={} estimated-memory={}kB",
+            self.routes(),
+            self.unicast,
+            self.vpnv4,
+            self.flowspec,
+            self.evpn,
+            self.link_state,
+            self.rtc,
+            self.vrf,
+            self.unresolved,
+            self.attribute_sets,
+            self.estimated_memory / 1024
+        )?;
+        if self.damping != DampingStats::default() {
+            write!(
+                f,
+                " damping=(histories={} suppressed={} flaps={})",
+                self.damping.histories,
+                self.damping.suppressed,
+                self.damping.flaps
+            )?;
+        }
+        if self.fib_drift != FibDriftStats::default() {
+            write!(
+                f,
+                " fib-drift=(checks={} missing={} orphaned={})",
+                self.fib_drift.checks,
+                self.fib_drift.missing,
+                self.fib_drift.orphaned
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Rib毎にルートを数え、PathAttributeの組はArcのアドレスで重複を除いて数える。
+#[derive(Default)]
+struct RibStatsCollector {
+    attribute_sets: HashMap<*const Vec<PathAttribute>, usize>,
+    entries_memory: usize,
+}
+
+impl RibStatsCollector {
+    // Arcの参照カウントの分。
+    const ARC_OVERHEAD: usize = 2 * std::mem::size_of::<usize>();
+
+    fn count<E: RibKey>(
+        &mut self,
+        rib: &Rib<E>,
+        path_attributes: impl Fn(&E) -> &Arc<Vec<PathAttribute>>,
+    ) -> usize {
+        for entry in rib.routes() {
+            self.entries_memory += std::mem::size_of::<E>()
+                + std::mem::size_of::<u64>()
+                + Self::ARC_OVERHEAD;
+            let attributes = path_attributes(entry);
+            self.attribute_sets
+                .entry(Arc::as_ptr(attributes))
+                .or_insert_with(|| {
+                    std::mem::size_of::<Vec<PathAttribute>>()
+                        + Self::ARC_OVERHEAD
+                        + attributes.capacity()
+                            * std::mem::size_of::<PathAttribute>()
+                });
+        }
+        rib.len()
+    }
+
+    fn estimated_memory(&self) -> usize {
+        self.entries_memory + self.attribute_sets.values().sum::<usize>()
+    }
+}
+
+impl Deref for LocRib {
+    type Target = Rib;
+
+    fn deref(&self) -> &Self::Target {
+        &self.rib
+    }
+}
+
+impl DerefMut for LocRib {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.rib
+    }
+}
+
+impl LocRib {
+    pub async fn new(config: &Config) -> Result<Self> {
+        // Next Hopは、LocRib -> AdjRibOutにルートを送るときに
+        // 自身のアドレスに書き換えるので、ここではlocal_ipの
+        // address familyが異なる場合は未指定のアドレスにしておく。
+        let ipv4_next_hop = match config.local_ip {
+            IpAddr::V4(addr) => addr,
+            IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+        };
+        let ipv6_next_hop = match config.local_ip {
+            IpAddr::V4(_) => Ipv6Addr::UNSPECIFIED,
+            IpAddr::V6(addr) => addr,
+        };
+        let ipv4_path_attributes = Arc::new(vec![
+            PathAttribute::Origin(Origin::Igp),
+            // AS Pathは、ほかのピアから受信したルートと統一的に扱うために、
+            // LocRib -> AdjRibOutにルートを送るときに、自分のAS番号を
+            // 追加するので、ここでは空にしておく。
+            PathAttribute::AsPath(AsPath::from_sequence(vec![])),
+            PathAttribute::NextHop(ipv4_next_hop),
+            PathAttribute::LocalPref(config.local_pref),
+        ]);
+        let ipv6_path_attributes = Arc::new(vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::from_sequence(vec![])),
+            PathAttribute::LocalPref(config.local_pref),
+            PathAttribute::MpReachNlri(MpReachNlri::new(
+                AddressFamily::IPV6_UNICAST,
+                IpAddr::V6(ipv6_next_hop),
+                MpNlri::Unicast(vec![]),
+            )),
+        ]);
+
+        let originated_entry = |network: &IpNetwork| {
+            let path_attributes = match network {
+                IpNetwork::V4(_) => &ipv4_path_attributes,
+                IpNetwork::V6(_) => &ipv6_path_attributes,
+            };
+            Arc::new(RibEntry {
+                network_address: *network,
+                labels: vec![],
+                path_attributes: override_originated_attributes(
+                    path_attributes,
+                    config.originated_attributes.get(network),
+                ),
+            })
+        };
+        // allowed-originationの外のネットワークは設定の誤りとみなし、広報しない。
+        let is_allowed = |network: &IpNetwork| {
+            let allowed =
+                is_allowed_origination(&config.allowed_originations, network);
+            if !allowed {
+                error!(
+                    "{} is not in allowed-origination, so it is not originated.",
+                    network
+                );
+            }
+            allowed
+        };
+        let mut rib = Rib::new();
+        for network in config
+            .always_advertised_networks
+            .iter()
+            .filter(|n| is_allowed(n))
+        {
+            rib.insert(originated_entry(network));
+        }
+        let kernel_checked: Vec<Arc<RibEntry>> = config
+            .networks
+            .iter()
+            .filter(|n| is_allowed(n))
+            .map(originated_entry)
+            .collect();
+
+        // ToDo: Labeled unicastのネットワークも起動後のルーティングテーブルの変化に追従する。
+        for (network, label) in config
+            .labeled_networks
+            .iter()
+            .filter(|(n, _)| is_allowed(n))
+        {
+            let address_family = match network {
+                IpNetwork::V4(_) => AddressFamily::IPV4_LABELED_UNICAST,
+                IpNetwork::V6(_) => AddressFamily::IPV6_LABELED_UNICAST,
+            };
+            let next_hop = match network {
+                IpNetwork::V4(_) => IpAddr::V4(ipv4_next_hop),
+                IpNetwork::V6(_) => IpAddr::V6(ipv6_next_hop),
+            };
+            let path_attributes = Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![])),
+                PathAttribute::LocalPref(config.local_pref),
+                PathAttribute::MpReachNlri(MpReachNlri::new(
+                    address_family,
+                    next_hop,
+                    MpNlri::LabeledUnicast(vec![]),
+                )),
+            ]);
+            let path_attributes = override_originated_attributes(
+                &path_attributes,
+                config.originated_attributes.get(network),
+            );
+            for route in Self::lookup_kernel_routing_table(
+                *network,
+                config.kernel_table(),
+            )
+            .await?
+            {
+                rib.insert(Arc::new(RibEntry {
+                    network_address: route,
+                    labels: vec![*label],
+                    path_attributes: Arc::clone(&path_attributes),
+                }))
+            }
+        }
+
+        // VRFのネットワークはカーネルのルーティングテーブルを確認せずに
+        // export route targetを付けたVPNv4ルートとして広報する。
+        let mut vpnv4 = Rib::new();
+        let mut vrfs = vec![];
+        for (i, vrf_config) in config.vrfs.iter().enumerate() {
+            let mut vrf = Vrf::new(vrf_config.clone(), i);
+            let path_attributes = Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![])),
+                PathAttribute::LocalPref(config.local_pref),
+                PathAttribute::ExtendedCommunities(
+                    vrf.config
+                        .export_route_targets
+                        .iter()
+                        .map(|rt| ExtendedCommunity::RouteTarget(*rt))
+                        .collect(),
+                ),
+                PathAttribute::MpReachNlri(MpReachNlri::new(
+                    AddressFamily::IPV4_MPLS_VPN,
+                    IpAddr::V4(ipv4_next_hop),
+                    MpNlri::Vpnv4(vec![]),
+                )),
+            ]);
+            for network in &vrf.config.networks {
+                vpnv4.insert(Arc::new(VpnRibEntry {
+                    prefix: Vpnv4Prefix {
+                        labels: vec![vrf.label],
+                        route_distinguisher: vrf.config.route_distinguisher,
+                        prefix: *network,
+                    },
+                    path_attributes: Arc::clone(&path_attributes),
+                }));
+                vrf.rib.insert(Arc::new(RibEntry {
+                    network_address: IpNetwork::V4(*network),
+                    labels: vec![],
+                    path_attributes: Arc::clone(&ipv4_path_attributes),
+                }));
+            }
+            vrfs.push(vrf);
+        }
+
+        // VRFでimportするRoute TargetのVPNv4ルートだけを受信するために、
+        // Route Target Constraint(RFC4684)のmembershipとして広報する。
+        let mut rtc = Rib::new();
+        let rtc_path_attributes = Arc::new(vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::from_sequence(vec![])),
+            PathAttribute::LocalPref(config.local_pref),
+            PathAttribute::MpReachNlri(MpReachNlri::new(
+                AddressFamily::IPV4_RTC,
+                IpAddr::V4(ipv4_next_hop),
+                MpNlri::RouteTargetConstraint(vec![]),
+            )),
+        ]);
+        for rt in vrfs.iter().flat_map(|v| &v.config.import_route_targets) {
+            rtc.insert(Arc::new(RtcRibEntry {
+                membership: RouteTargetMembership::new(
+                    u16::from(config.local_as) as u32,
+                    *rt,
+                ),
+                path_attributes: Arc::clone(&rtc_path_attributes),
+            }));
+        }
+
+        // FlowSpecはnext hopを持たないので、MP_REACH_NLRIには
+        // address familyを示すためだけに未指定のアドレスを入れておく。
+        let mut flowspec = Rib::new();
+        for route in &config.flowspec {
+            flowspec.insert(Arc::new(FlowSpecRibEntry {
+                rule: route.rule.clone(),
+                path_attributes: Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(vec![])),
+                    PathAttribute::LocalPref(config.local_pref),
+                    PathAttribute::ExtendedCommunities(route.actions.clone()),
+                    PathAttribute::MpReachNlri(MpReachNlri::new(
+                        AddressFamily::IPV4_FLOWSPEC,
+                        IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                        MpNlri::FlowSpec(vec![]),
+                    )),
+                ]),
+            }));
+        }
+
+        let mut loc_rib = Self {
+            rib,
+            vpnv4,
+            flowspec,
+            evpn: Rib::new(),
+            link_state: Rib::new(),
+            rtc,
+            vrfs,
+            announced: HashMap::new(),
+            learned: HashMap::new(),
+            learned_vpnv4: LearnedRoutes::new(),
+            learned_flowspec: LearnedRoutes::new(),
+            learned_evpn: LearnedRoutes::new(),
+            learned_link_state: LearnedRoutes::new(),
+            withdrawn: vec![],
+            stale: HashMap::new(),
+            kernel_checked,
+            redistributions: config.redistributions.clone(),
+            redistributed: vec![],
+            generation: 0,
+            local_as_number: config.local_as,
+            confederation_identifier: config
+                .confederation
+                .as_ref()
+                .map(|c| c.identifier),
+            internal_peers: HashMap::new(),
+            bgp_identifiers: HashMap::new(),
+            damping: HashMap::new(),
+            fib_drift: FibDriftStats::default(),
+            local_ip: config.local_ip,
+            local_pref: config.local_pref,
+            maximum_paths: config.maximum_paths,
+            mpls_encap: config.mpls_encap,
+            fib_target: FibTarget {
+                table: config.kernel_table(),
+                metric: config.kernel_metric,
+            },
+            unreachable_next_hops: HashSet::new(),
+            checked_next_hops: HashSet::new(),
+            unresolved: Rib::new(),
+            allowed_originations: config.allowed_originations.clone(),
+            fib_writer: None,
+            route_writer: SharedRouteWriter::default(),
+        };
+        let kernel_routes =
+            kernel::lookup_kernel_routes(config.kernel_table()).await?;
+        loc_rib.sync_originated_networks(&kernel_routes);
+        Ok(loc_rib)
+    }
+
+    /// entryをiBGPのPeerから受信していれば、そのPeerを返す。
+    fn internal_source(
+        &self,
+        entry: &RibEntry,
+    ) -> Option<(IpAddr, InternalPeer)> {
+        self.learned
+            .iter()
+            .filter(|(_, learned)| learned.contains(entry))
+            .find_map(|(peer, _)| {
+                self.internal_peers.get(peer).map(|p| (*peer, *p))
+            })
+    }
+
+    /// entryを受信したPeerのBGP Identifierのうち、最も小さいもの。
+    /// 自身がoriginateしたルートではNoneを返す。
+    fn bgp_identifier_of(&self, entry: &RibEntry) -> Option<Ipv4Addr> {
+        self.learned
+            .iter()
+            .chain(self.stale.iter())
+            .filter(|(_, routes)| routes.contains(entry))
+            .filter_map(|(peer, _)| self.bgp_identifiers.get(peer))
+            .min()
+            .copied()
+    }
+
+    /// routesのうち最も優先されるルートを返す。
+    fn select_best_path<'a>(
+        &self,
+        routes: impl Iterator<Item = &'a Arc<RibEntry>>,
+    ) -> Option<&'a Arc<RibEntry>> {
+        select_best_path(routes, |e| self.bgp_identifier_of(e))
+    }
+
+    /// 2つのルートを比較し、どちらが優先されるかと、その決め手を返す。
+    fn compare_paths(
+        &self,
+        a: &RibEntry,
+        b: &RibEntry,
+    ) -> (Ordering, BestPathReason) {
+        compare_paths(a, b, |e| self.bgp_identifier_of(e))
+    }
+
+    /// entryをpeerから受信したかどうか。LLGR_STALEを付けて保持しているルートも含む。
+    fn is_learned_from(&self, entry: &RibEntry, peer: IpAddr) -> bool {
+        self.learned.get(&peer).is_some_and(|l| l.contains(entry))
+            || self.stale.get(&peer).is_some_and(|s| s.contains(entry))
+    }
+
+    /// peerから受信したルートをribとカーネルのルーティングテーブルから取り除く。
+    /// 他のPeerからも同じルートを受信している場合は残す。
+    /// 同じネットワークに他のPeerのルートが残っていれば、カーネルのルートはそちらに切り替える。
+    /// VPNv4など、unicast以外のルートも同様に取り除く。
+    pub async fn remove_routes_learned_from(
+        &mut self,
+        peer: IpAddr,
+    ) -> Result<()> {
+        self.damping.remove(&peer);
+        if !self.stale.contains_key(&peer) {
+            self.bgp_identifiers.remove(&peer);
+        }
+        if self.remove_multiprotocol_routes_learned_from(peer) {
+            self.generation += 1;
+        }
+        let learned = match self.learned.remove(&peer) {
+            Some(learned) => learned,
+            None => return Ok(()),
+        };
+        let installed = self.paths_to_install();
+        let mut changed = false;
+        for entry in learned {
+            if self.learned.values().any(|l| l.contains(&entry)) {
+                continue;
+            }
+            changed |= self.unresolved.remove(&entry);
+            changed |= self.rib.remove(&entry);
+        }
+        if !changed {
+            return Ok(());
+        }
+        self.generation += 1;
+        self.apply_installed_changes(installed).await
+    }
+
+    /// Long-Lived Graceful Restartのため、peerから受信したルートを取り除く代わりに
+    /// LLGR_STALEを付けて保持する。保持したルートはbest pathに選ばれにくくなる。
+    /// NO_LLGRの付いたルートと、他のPeerからも受信しているルートは保持しない。
+    /// VPNv4など、unicast以外のルートは保持せずに取り除く。
+    pub async fn mark_routes_stale(&mut self, peer: IpAddr) -> Result<()> {
+        self.damping.remove(&peer);
+        if self.remove_multiprotocol_routes_learned_from(peer) {
+            self.generation += 1;
+        }
+        let learned = match self.learned.remove(&peer) {
+            Some(learned) => learned,
+            None => return Ok(()),
+        };
+        let installed = self.paths_to_install();
+        for entry in learned {
+            if self.learned.values().any(|l| l.contains(&entry)) {
+                continue;
+            }
+            let rib = if self.unresolved.remove(&entry) {
+                &mut self.unresolved
+            } else if self.rib.remove(&entry) {
+                &mut self.rib
+            } else {
+                continue;
+            };
+            if communities(&entry.path_attributes)
+                .contains(&Community::NO_LLGR)
+            {
+                continue;
+            }
+            let stale = Arc::new(entry.with_community(Community::LLGR_STALE));
+            rib.insert(Arc::clone(&stale));
+            self.stale.entry(peer).or_default().insert(stale);
+        }
+        self.generation += 1;
+        self.apply_installed_changes(installed).await
+    }
+
+    /// peerから受信したunicast以外のルートを取り除く。取り除いた場合はtrueを返す。
+    fn remove_multiprotocol_routes_learned_from(
+        &mut self,
+        peer: IpAddr,
+    ) -> bool {
+        let removed =
+            self.learned_vpnv4
+                .replace(peer, HashSet::new(), &mut self.vpnv4);
+        for entry in removed.iter() {
+            self.remove_from_vrfs(entry);
+        }
+        let removed_flowspec = self.learned_flowspec.replace(
+            peer,
+            HashSet::new(),
+            &mut self.flowspec,
+        );
+        let removed_evpn =
+            self.learned_evpn
+                .replace(peer, HashSet::new(), &mut self.evpn);
+        let removed_link_state = self.learned_link_state.replace(
+            peer,
+            HashSet::new(),
+            &mut self.link_state,
+        );
+        !removed.is_empty()
+            || !removed_flowspec.is_empty()
+            || !removed_evpn.is_empty()
+            || !removed_link_state.is_empty()
+    }
+
+    /// VPNv4ルートからVRFにインポートしたルートを取り除く。
+    fn remove_from_vrfs(&mut self, entry: &VpnRibEntry) {
+        let imported = Arc::new(entry.to_ipv4_rib_entry());
+        for vrf in self.vrfs.iter_mut() {
+            vrf.rib.remove(&imported);
+        }
+    }
+
+    /// mark_routes_staleで保持したpeerのルートを取り除く。
+    /// LLGRのstale timeが満了したときと、Peerを止めたときに呼ぶ。
+    pub async fn remove_stale_routes_from(
+        &mut self,
+        peer: IpAddr,
+    ) -> Result<()> {
+        let stale = match self.stale.remove(&peer) {
+            Some(stale) => stale,
+            None => return Ok(()),
+        };
+        if !self.learned.contains_key(&peer) {
+            self.bgp_identifiers.remove(&peer);
+        }
+        let installed = self.paths_to_install();
+        for entry in stale {
+            self.unresolved.remove(&entry);
+            self.rib.remove(&entry);
+        }
+        self.generation += 1;
+        self.apply_installed_changes(installed).await
+    }
+
+    /// カーネルのルーティングテーブルのルートkernel_routesを確認し、
+    /// configの`network`のうち存在するものを広報し、存在しないものの広報をやめる。
+    /// kernel_routesはLocRibのロックを外してから、kernel_tableのテーブルを
+    /// kernel::lookup_kernel_routesで1回だけdumpしたものを使う。
+    pub fn refresh_originated_networks(
+        &mut self,
+        kernel_routes: &KernelRoutes,
+    ) {
+        if self.sync_originated_networks(kernel_routes) {
+            self.generation += 1;
+        }
+    }
+
+    /// ribが変わった場合はtrueを返す。
+    fn sync_originated_networks(
+        &mut self,
+        kernel_routes: &KernelRoutes,
+    ) -> bool {
+        let mut changed = false;
+        for entry in self.kernel_checked.clone() {
+            let present = kernel_routes.contains(&entry.network_address);
+            match (present, self.rib.contains(&entry)) {
+                (true, false) => {
+                    self.rib.insert(entry);
+                    changed = true;
+                }
+                (false, true) => {
+                    self.rib.remove(&entry);
+                    changed = true;
+                }
+                _ => {}
+            }
+        }
+        changed |= self.sync_redistributed_routes(kernel_routes);
+        changed
+    }
+
+    /// カーネルのルーティングテーブルからredistributionsに一致するルートを探し、
+    /// 新しく現れたルートをribに入れ、無くなったルートをribから取り除く。
+    /// ribが変わった場合はtrueを返す。
+    fn sync_redistributed_routes(
+        &mut self,
+        kernel_routes: &KernelRoutes,
+    ) -> bool {
+        if self.redistributions.is_empty() {
+            return false;
+        }
+        let mut networks = HashSet::new();
+        for r in &self.redistributions {
+            networks.extend(
+                kernel_routes
+                    .networks_of(r.protocol)
+                    .filter(|n| r.prefix.is_none_or(|p| n.is_subnet_of(&p)))
+                    .filter(|n| {
+                        is_allowed_origination(&self.allowed_originations, n)
+                    }),
+            );
+        }
+        let mut changed = false;
+        let mut redistributed = vec![];
+        for entry in std::mem::take(&mut self.redistributed) {
+            if networks.remove(&entry.network_address) {
+                redistributed.push(entry);
+            } else {
+                changed |= self.rib.remove(&entry);
+            }
+        }
+        for network in networks {
+            let entry = Arc::new(self.redistributed_entry(network));
+            self.rib.insert(Arc::clone(&entry));
+            redistributed.push(entry);
+            changed = true;
+        }
+        self.redistributed = redistributed;
+        changed
+    }
+
+    /// カーネルのルーティングテーブルから広報するルート。
+    /// BGP以外から得たルートなので、ORIGINはINCOMPLETEにする。
+    fn redistributed_entry(&self, network: IpNetwork) -> RibEntry {
+        let mut path_attributes = vec![
+            PathAttribute::Origin(Origin::Incomplete),
+            PathAttribute::AsPath(AsPath::from_sequence(vec![])),
+            PathAttribute::LocalPref(self.local_pref),
+        ];
+        match (network, self.local_ip) {
+            (IpNetwork::V4(_), IpAddr::V4(n)) => {
+                path_attributes.push(PathAttribute::NextHop(n))
+            }
+            (IpNetwork::V4(_), IpAddr::V6(_)) => path_attributes
+                .push(PathAttribute::NextHop(Ipv4Addr::UNSPECIFIED)),
+            (IpNetwork::V6(_), local_ip) => {
+                let next_hop = match local_ip {
+                    IpAddr::V4(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                    IpAddr::V6(_) => local_ip,
+                };
+                path_attributes.push(PathAttribute::MpReachNlri(
+                    MpReachNlri::new(
+                        AddressFamily::IPV6_UNICAST,
+                        next_hop,
+                        MpNlri::Unicast(vec![]),
+                    ),
+                ))
+            }
+        }
+        RibEntry {
+            network_address: network,
+            labels: vec![],
+            path_attributes: Arc::new(path_attributes),
+        }
+    }
+
+    async fn lookup_kernel_routing_table(
+        network_address: IpNetwork,
+        table: u32,
+    ) -> Result<(Vec<IpNetwork>)> {
+        kernel::lookup_routes(network_address, table).await
+    }
+
+    /// AdjRibInから必要なルートをインストールする。
+    /// この時、自ASが含まれているルートはインストールしない。
+    /// import-policyを設定している場合は、policyを適用したルートをインストールする。
+    /// 以前peerから受信し、AdjRibInから無くなったルートは取り除く。
+    /// VPNv4ルートはimport route targetが一致するVRFにもインストールする。
+    /// 参考: 9.1.2.  Phase 2: Route Selection in RFC4271.
+    pub fn install_from_adj_rib_in(
+        &mut self,
+        peer: IpAddr,
+        adj_rib_in: &AdjRibIn,
+    ) {
+        // closure内にselfを2回captureされて、借用チェックによるエラーを避けるため。
+        let local_as = self.local_as_number;
+        let confederation_identifier = self.confederation_identifier;
+        let tables_generation = self.tables_generation();
+        match adj_rib_in.internal_peer {
+            Some(internal_peer) => {
+                self.internal_peers.insert(peer, internal_peer)
+            }
+            None => self.internal_peers.remove(&peer),
+        };
+        match adj_rib_in.bgp_identifier {
+            Some(bgp_identifier) => {
+                self.bgp_identifiers.insert(peer, bgp_identifier)
+            }
+            None => self.bgp_identifiers.remove(&peer),
+        };
+        let accepted: HashSet<Arc<RibEntry>> = match &adj_rib_in.import_policy
+        {
+            Some(import) => adj_rib_in
+                .routes()
+                .filter_map(|e| import.policy.apply(e, import.prepend_as))
+                .collect(),
+            None => adj_rib_in.routes().cloned().collect(),
+        };
+        match adj_rib_in.damping_stats() {
+            stats if stats == DampingStats::default() => {
+                self.damping.remove(&peer)
+            }
+            stats => self.damping.insert(peer, stats),
+        };
+
+        // Peerがwithdrawしたルートを取り除く。
+        // 他のPeerからも同じルートを受信している場合は残す。
+        let withdrawn: Vec<Arc<RibEntry>> = self
+            .learned
+            .get(&peer)
+            .map(|learned| {
+                learned
+                    .iter()
+                    .filter(|e| !accepted.contains(*e))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        for entry in withdrawn {
+            if let Some(learned) = self.learned.get_mut(&peer) {
+                learned.remove(&entry);
+            }
+            if self.learned.values().any(|l| l.contains(&entry)) {
+                continue;
+            }
+            self.unresolved.remove(&entry);
+            if self.rib.remove(&entry) {
+                self.withdrawn.push(entry);
+            }
+        }
+
+        // セッションが再確立してPeerが広報し直したルートは、保持していた
+        // LLGR_STALEのルートを置き換える。
+        // ToDo: End-of-RIBに対応したら、広報し直されなかったルートもそこで取り除く。
+        if let Some(stale) = self.stale.get_mut(&peer) {
+            let readvertised: Vec<Arc<RibEntry>> = stale
+                .iter()
+                .filter(|s| {
+                    accepted
+                        .iter()
+                        .any(|e| e.network_address == s.network_address)
+                })
+                .cloned()
+                .collect();
+            for entry in readvertised {
+                stale.remove(&entry);
+                self.unresolved.remove(&entry);
+                self.rib.remove(&entry);
+            }
+        }
+
+        for entry in accepted
+            .iter()
+            .filter(|entry| !entry.does_contain_as(local_as))
+            .filter(|entry| {
+                confederation_identifier
+                    .is_none_or(|id| !entry.does_contain_as(id))
+            })
+        {
+            self.learned
+                .entry(peer)
+                .or_default()
+                .insert(Arc::clone(entry));
+            match entry.next_hop() {
+                Some(n) if self.unreachable_next_hops.contains(&n) => {
+                    self.unresolved.insert(Arc::clone(entry))
+                }
+                _ => self.insert(Arc::clone(entry)),
+            }
+        }
+
+        // Peerがwithdrawしたルートは、他のPeerからも受信していなければ取り除く。
+        let vpnv4: HashSet<Arc<VpnRibEntry>> = adj_rib_in
+            .vpnv4
+            .routes()
+            .filter(|entry| !does_contain_as(&entry.path_attributes, local_as))
+            .cloned()
+            .collect();
+        for entry in
+            self.learned_vpnv4
+                .replace(peer, vpnv4.clone(), &mut self.vpnv4)
+        {
+            self.remove_from_vrfs(&entry);
+        }
+        for entry in vpnv4.iter() {
+            let route_targets = entry.route_targets();
+            for vrf in self.vrfs.iter_mut() {
+                if vrf.does_import(&route_targets) {
+                    vrf.rib.insert(Arc::new(entry.to_ipv4_rib_entry()));
+                }
+            }
+        }
+
+        let flowspec = adj_rib_in
+            .flowspec
+            .routes()
+            .filter(|entry| !does_contain_as(&entry.path_attributes, local_as))
+            .cloned()
+            .collect();
+        self.learned_flowspec
+            .replace(peer, flowspec, &mut self.flowspec);
+
+        let evpn = adj_rib_in
+            .evpn
+            .routes()
+            .filter(|entry| !does_contain_as(&entry.path_attributes, local_as))
+            .cloned()
+            .collect();
+        self.learned_evpn.replace(peer, evpn, &mut self.evpn);
+
+        let link_state = adj_rib_in
+            .link_state
+            .routes()
+            .filter(|entry| !does_contain_as(&entry.path_attributes, local_as))
+            .cloned()
+            .collect();
+        self.learned_link_state.replace(
+            peer,
+            link_state,
+            &mut self.link_state,
+        );
+
+        // 他のPeerも受信したルートの広報やconditional advertisementの条件を
+        // 評価し直せるように、いずれかのRibが変わった場合はgenerationを進める。
+        if self.tables_generation() != tables_generation {
+            self.generation += 1;
+        }
+    }
+
+    /// ルートを自身がoriginateするルートとしてLocRibに追加する。
+    /// next hopを指定しない場合は自身のアドレスになる。
+    /// 同じネットワークを既に広報している場合は置き換える。
+    /// allowed-originationの外のネットワークは広報せずにエラーを返す。
+    /// BLACKHOLE Community(RFC7999)を付けられるのは/32, /128のネットワークだけで、
+    /// 自身のASの外に広まらないようにNO_EXPORTも付ける。
+    pub fn announce(
+        &mut self,
+        network: IpNetwork,
+        next_hop: Option<IpAddr>,
+        mut communities: Vec<Community>,
+    ) -> Result<()> {
+        if !is_allowed_origination(&self.allowed_originations, &network) {
+            warn!("announce {} is rejected by allowed-origination.", network);
+            return Err(anyhow::anyhow!(
+                "{}はallowed-originationに含まれないため広報できません。",
+                network
+            ));
+        }
+        if communities.contains(&Community::BLACKHOLE) {
+            if !network.is_host() {
+                return Err(anyhow::anyhow!(
+                    "BLACKHOLEのルートとして広報できるのは/32, /128のネットワークだけですが、{}が指定されました。",
+                    network
+                ));
+            }
+            if !communities.contains(&Community::NO_EXPORT) {
+                communities.push(Community::NO_EXPORT);
+            }
+        }
+        let next_hop = next_hop.unwrap_or(self.local_ip);
+        let mut path_attributes = vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::from_sequence(vec![])),
+            PathAttribute::LocalPref(self.local_pref),
+        ];
+        match (network, next_hop) {
+            (IpNetwork::V4(_), IpAddr::V4(n)) => {
+                path_attributes.push(PathAttribute::NextHop(n))
+            }
+            (IpNetwork::V6(_), IpAddr::V6(_)) => path_attributes.push(
+                PathAttribute::MpReachNlri(MpReachNlri::new(
+                    AddressFamily::IPV6_UNICAST,
+                    next_hop,
+                    MpNlri::Unicast(vec![]),
+                )),
+            ),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "{}とnext hop {}のaddress familyが異なります。",
+                    network,
+                    next_hop
+                ))
+            }
+        }
+        if !communities.is_empty() {
+            path_attributes.push(PathAttribute::Communities(communities));
+        }
+        let entry = Arc::new(RibEntry {
+            network_address: network,
+            labels: vec![],
+            path_attributes: Arc::new(path_attributes),
+        });
+        if let Some(old) = self.announced.insert(network, Arc::clone(&entry)) {
+            self.rib.remove(&old);
+        }
+        self.rib.insert(entry);
+        self.generation += 1;
+        Ok(())
+    }
+
+    /// announceで追加したルートをLocRibから取り除く。
+    pub fn withdraw(&mut self, network: IpNetwork) -> Result<()> {
+        let entry = self.announced.remove(&network).ok_or_else(|| {
+            anyhow::anyhow!("{}は広報していません。", network)
+        })?;
+        self.rib.remove(&entry);
+        self.generation += 1;
+        Ok(())
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// ルートを書き込み、next hopの解決に使うカーネルのルーティングテーブルのid。
+    pub fn kernel_table(&self) -> u32 {
+        self.fib_target.table
+    }
+
+    /// Rib毎のルートの数と、メモリ使用量の見積もりを返す。
+    pub fn stats(&self) -> RibStats {
+        let mut collector = RibStatsCollector::default();
+        let unicast = collector.count(&self.rib, |e| &e.path_attributes);
+        let unresolved =
+            collector.count(&self.unresolved, |e| &e.path_attributes);
+        let vpnv4 = collector.count(&self.vpnv4, |e| &e.path_attributes);
+        let flowspec = collector.count(&self.flowspec, |e| &e.path_attributes);
+        let evpn = collector.count(&self.evpn, |e| &e.path_attributes);
+        let link_state =
+            collector.count(&self.link_state, |e| &e.path_attributes);
+        let rtc = collector.count(&self.rtc, |e| &e.path_attributes);
+        let vrf = self
+            .vrfs
+            .iter()
+            .map(|vrf| collector.count(&vrf.rib, |e| &e.path_attributes))
+            .sum();
+        RibStats {
+            unicast,
+            vpnv4,
+            flowspec,
+            evpn,
+            link_state,
+            rtc,
+            vrf,
+            unresolved,
+            attribute_sets: collector.attribute_sets.len(),
+            estimated_memory: collector.estimated_memory(),
+            damping: self.damping.values().copied().sum(),
+            fib_drift: self.fib_drift,
+        }
+    }
+
+    /// Rib毎のルートをJSONにする。VRFのルートはVRF名毎にまとめる。
+    pub fn to_json(&self) -> Value {
+        let vrfs: BTreeMap<&str, Value> = self
+            .vrfs
+            .iter()
+            .map(|vrf| (vrf.config.name.as_str(), vrf.rib.to_json()))
+            .collect();
+        json!({
+            "unicast": self.rib.to_json(),
+            "vpnv4": self.vpnv4.to_json(),
+            "flowspec": self.flowspec.to_json(),
+            "evpn": self.evpn.to_json(),
+            "link_state": self.link_state.to_json(),
+            "rtc": self.rtc.to_json(),
+            "vrfs": vrfs,
+            "unresolved": self.unresolved.to_json(),
+        })
+    }
+
+    /// conditional advertisementの条件を全て満たし、
+    /// networkを広報してよいかを返す。networkに条件が無ければ常にtrue。
+    fn satisfies_conditions(
+        &self,
+        network: IpNetwork,
+        conditions: &[ConditionalAdvertisement],
+    ) -> bool {
+        conditions.iter().filter(|c| c.network == network).all(|c| {
+            let exists = self.rib.paths_of(&c.condition).next().is_some();
+            exists == c.exist
+        })
+    }
+
+    /// networkのルートのうちbest pathを、選ばれた理由と共に返す。
+    pub fn best_path(&self, network: IpNetwork) -> Option<BestPath> {
+        let candidates: Vec<&Arc<RibEntry>> =
+            self.rib.paths_of(&network).collect();
+        let best = self.select_best_path(candidates.iter().copied())?;
+        let others =
+            candidates.iter().copied().filter(|e| !Arc::ptr_eq(e, best));
+        let reason = match self.select_best_path(others) {
+            None => BestPathReason::OnlyPath,
+            Some(second) => self.compare_paths(best, second).1,
+        };
+        Some(BestPath {
+            entry: Arc::clone(best),
+            reason,
+            candidates: candidates.len(),
+        })
+    }
+
+    /// ネットワークとaddress family毎のbest pathを返す。
+    /// AdjRibOutにはこれだけをインストールし、他のルートは広報しない。
+    pub fn best_paths(&self) -> Vec<&Arc<RibEntry>> {
+        let routes: Vec<&Arc<RibEntry>> = self.routes().collect();
+        routes
+            .chunk_by(|a, b| {
+                a.network_address == b.network_address
+                    && a.address_family() == b.address_family()
+            })
+            .filter_map(|candidates| {
+                self.select_best_path(candidates.iter().copied())
+            })
+            .collect()
+    }
+
+    /// addrへの転送に使うルート(longest prefix match)を返す。
+    pub fn lookup_longest_match(&self, addr: IpAddr) -> Vec<Arc<RibEntry>> {
+        self.rib.lookup_longest_match(addr)
+    }
+
+    /// prefixと同じか、より長いprefixのルートを返す。
+    pub fn covered_routes(&self, prefix: IpNetwork) -> Vec<Arc<RibEntry>> {
+        self.rib.covered_routes(prefix)
+    }
+
+    /// ribのルートと、next hopに到達できずribから外しているルートのnext hop。
+    /// 自身のアドレスがnext hopのルートと、next hopに転送しないBLACKHOLEのルートは含まない。
+    pub fn next_hops(&self) -> HashSet<IpAddr> {
+        self.routes()
+            .chain(self.unresolved.routes())
+            .filter(|e| !e.is_blackhole())
+            .filter_map(|e| e.next_hop())
+            .filter(|n| *n != self.local_ip)
+            .collect()
+    }
+
+    /// next hopの到達性を更新する。unreachableなnext hopのルートはribから外し、
+    /// 到達できるようになったnext hopのルートはribに戻す。
+    /// 同じネットワークで別のnext hopのルートがあれば、そちらが使われるようになる。
+    pub fn update_next_hop_reachability(
+        &mut self,
+        unreachable: HashSet<IpAddr>,
+    ) -> NextHopChanges {
+        let is_unreachable = |e: &Arc<RibEntry>| {
+            !e.is_blackhole()
+                && e.next_hop().is_some_and(|n| unreachable.contains(&n))
+        };
+        let changes = NextHopChanges {
+            unreachable: self
+                .routes()
+                .filter(|e| is_unreachable(e))
+                .cloned()
+                .collect(),
+            reachable: self
+                .unresolved
+                .routes()
+                .filter(|e| !is_unreachable(e))
+                .cloned()
+                .collect(),
+        };
+        for entry in &changes.unreachable {
+            self.remove(entry);
+            self.unresolved.insert(Arc::clone(entry));
+        }
+        for entry in &changes.reachable {
+            self.unresolved.remove(entry);
+            self.insert(Arc::clone(entry));
+        }
+        self.unreachable_next_hops = unreachable;
+        self.checked_next_hops = self.next_hops();
+        if changes != NextHopChanges::default() {
+            self.generation += 1;
+        }
+        changes
+    }
+
+    /// カーネルのルーティングテーブルでnext_hopsを解決した結果、resolvableだけが
+    /// 到達できるとして、next hopの到達性を更新する。
+    /// 解決している間にribに加わり、next_hopsに無いnext hopは、確認済みにしない。
+    pub fn update_resolved_next_hops(
+        &mut self,
+        next_hops: &HashSet<IpAddr>,
+        resolvable: &HashSet<IpAddr>,
+    ) -> NextHopChanges {
+        let checked: HashSet<IpAddr> =
+            self.checked_next_hops.union(next_hops).copied().collect();
+        let mut unreachable: HashSet<IpAddr> = self
+            .unreachable_next_hops
+            .difference(next_hops)
+            .copied()
+            .collect();
+        unreachable.extend(next_hops.difference(resolvable));
+        let changes = self.update_next_hop_reachability(unreachable);
+        self.checked_next_hops.retain(|n| checked.contains(n));
+        changes
+    }
+
+    /// BGP-LSで収集したIGPのトポロジをグラフとして返す。
+    pub fn topology(&self) -> TopologyGraph {
+        TopologyGraph::from_rib(&self.link_state)
+    }
+
+    /// address family毎のRibのgenerationの和。いずれかのRibが変わる度に進む。
+    fn tables_generation(&self) -> u64 {
+        self.rib.generation()
+            + self.vpnv4.generation()
+            + self.flowspec.generation()
+            + self.evpn.generation()
+            + self.link_state.generation()
+            + self.rtc.generation()
+    }
+
+    /// next hopの到達性の変化をカーネルのルーティングテーブルに反映する変更を集める。
+    /// 変化したネットワークは書き込むルートを選び直し、書き込むルートが無くなった
+    /// ネットワークは削除する。LocRibのロックを外してから書き込めるように、
+    /// KernelRouteChangesとして返す。
+    pub fn next_hop_kernel_route_changes(
+        &self,
+        changes: &NextHopChanges,
+    ) -> KernelRouteChanges {
+        self.kernel_network_changes(
+            changes.unreachable.iter().chain(&changes.reachable),
+        )
+    }
+
+    /// ribを変更する前にカーネルに書き込むルートとして選んでいたinstalledと比べ、
+    /// next hopかラベルが変わったネットワークだけをカーネルに反映する。
+    async fn apply_installed_changes(
+        &self,
+        installed: Vec<Arc<RibEntry>>,
+    ) -> Result<()> {
+        let key = |e: &Arc<RibEntry>| {
+            (e.network_address, e.next_hop(), e.labels.clone())
+        };
+        let before: HashSet<_> = installed.iter().map(key).collect();
+        let after = self.paths_to_install();
+        let after_keys: HashSet<_> = after.iter().map(key).collect();
+        let changed = installed
+            .iter()
+            .filter(|e| !after_keys.contains(&key(e)))
+            .chain(after.iter().filter(|e| !before.contains(&key(e))));
+        self.update_kernel_networks(changed).await
+    }
+
+    /// changedのルートのネットワークについて、カーネルに書き込むルートを選び直す。
+    async fn update_kernel_networks(
+        &self,
+        changed: impl Iterator<Item = &Arc<RibEntry>>,
+    ) -> Result<()> {
+        self.kernel_network_changes(changed).program().await
+    }
+
+    /// changedのルートのネットワークについて、カーネルに書き込むルートを選び直す。
+    /// ribにルートが残っていないネットワークは削除し、残っていれば書き込み直す。
+    /// 取り除いたルートのネットワークは、ribに残った他のルートに切り替わる。
+    fn kernel_network_changes<'a>(
+        &self,
+        changed: impl Iterator<Item = &'a Arc<RibEntry>>,
+    ) -> KernelRouteChanges {
+        let mut removed = vec![];
+        let mut networks = HashSet::new();
+        for e in changed {
+            if !networks.insert(e.network_address) {
+                continue;
+            }
+            if self.rib.paths_of(&e.network_address).next().is_none() {
+                removed.push(Arc::clone(e));
+            }
+        }
+        let installed: Vec<Arc<RibEntry>> = self
+            .paths_to_install()
+            .into_iter()
+            .filter(|e| networks.contains(&e.network_address))
+            .collect();
+        self.kernel_route_changes(removed, installed)
+    }
+
+    /// まだ到達性を確認していないnext hopをカーネルのルーティングテーブルで解決し、
+    /// 解決できないnext hopのルートをribから外す。
+    /// 解決できなかった場合は、到達できるものとしてそのまま書き込む。
+    async fn resolve_unchecked_next_hops(&mut self) {
+        let unchecked: HashSet<IpAddr> = self
+            .next_hops()
+            .difference(&self.checked_next_hops)
+            .copied()
+            .collect();
+        if unchecked.is_empty() {
+            return;
+        }
+        let resolvable = match self
+            .route_writer
+            .resolvable_next_hops(unchecked.clone(), self.fib_target.table)
+            .await
+        {
+            Ok(resolvable) => resolvable,
+            Err(e) => {
+                warn!("cannot resolve next hops {:?}: {:?}.", unchecked, e);
+                return;
+            }
+        };
+        for entry in self
+            .update_resolved_next_hops(&unchecked, &resolvable)
+            .unreachable
+        {
+            warn!(
+                "next hop {:?} of {} is unreachable, so it is not installed.",
+                entry.next_hop(),
+                entry.network_address
+            );
+        }
+    }
+
+    /// Peerがwithdrawしたルートがあるか。
+    pub fn does_contain_withdrawn_route(&self) -> bool {
+        !self.withdrawn.is_empty()
+    }
+
+    /// カーネルのルーティングテーブルに反映する変更を集める。
+    /// withdrawされたルートは削除し、ribのルートのうちpaths_to_installで選んだものを
+    /// 書き込む。withdrawされたネットワークでも、他のルートがribに残っていれば削除しない。
+    /// next hopに到達できないルートは書き込まずにribから外す。
+    /// 他のPeerを待たせないように、LocRibのロックを外してから
+    /// KernelRouteChanges::programで書き込む。
+    pub async fn take_kernel_route_changes(&mut self) -> KernelRouteChanges {
+        self.resolve_unchecked_next_hops().await;
+        let withdrawn: Vec<Arc<RibEntry>> =
+            std::mem::take(&mut self.withdrawn)
+                .into_iter()
+                .filter(|w| {
+                    self.rib.paths_of(&w.network_address).next().is_none()
+                })
+                .collect();
+        self.kernel_route_changes(withdrawn, self.paths_to_install())
+    }
+
+    fn kernel_route_changes(
+        &self,
+        withdrawn: Vec<Arc<RibEntry>>,
+        installed: Vec<Arc<RibEntry>>,
+    ) -> KernelRouteChanges {
+        KernelRouteChanges {
+            withdrawn,
+            installed,
+            mpls_encap: self.mpls_encap,
+            fib_target: self.fib_target,
+            fib_writer: self.fib_writer.clone(),
+            route_writer: self.route_writer.clone(),
+        }
+    }
+
+    /// カーネルのルーティングテーブルに書き込むルートを、ネットワーク順に返す。
+    /// ネットワーク毎に、best pathと、best pathとnext hop以外で優劣の付かない
+    /// ルートをmaximum_pathsまで選ぶ。同じネットワークのルートが複数あれば、
+    /// カーネルにはそれらのnext hopへのECMPのルートとして書き込む。
+    /// best pathのnext hopが自身の場合は、自身がoriginateするルートなので書き込まない。
+    /// ただしBLACKHOLEのルートは、自身がoriginateしたものも破棄するルートとして書き込む。
+    fn paths_to_install(&self) -> Vec<Arc<RibEntry>> {
+        let routes: Vec<&Arc<RibEntry>> = self.routes().collect();
+        let mut paths = vec![];
+        for candidates in
+            routes.chunk_by(|a, b| a.network_address == b.network_address)
+        {
+            let best = match self.select_best_path(candidates.iter().copied())
+            {
+                Some(best) => best,
+                None => continue,
+            };
+            if !best.is_blackhole()
+                && best
+                    .next_hop()
+                    .is_none_or(|n| n == self.local_ip || n.is_unspecified())
+            {
+                continue;
+            }
+            let mut selected = vec![best];
+            for e in candidates.iter().copied() {
+                if selected.len() >= self.maximum_paths {
+                    break;
+                }
+                // BGP Identifierが異なるだけのルートもECMPにする。
+                let is_equal_cost = matches!(
+                    self.compare_paths(e, best).1,
+                    BestPathReason::LowerRouterId
+                        | BestPathReason::LowerNextHop
+                );
+                if is_equal_cost
+                    && !selected.iter().any(|s| s.next_hop() == e.next_hop())
+                {
+                    selected.push(e);
+                }
+            }
+            paths.extend(selected.into_iter().cloned());
+        }
+        paths
+    }
+
+    /// カーネルのルーティングテーブルの自身が書き込んだルートinstalledを、
+    /// paths_to_installと比べ、無くなっていたり、next hop(ECMPのnext hopの組)が
+    /// 異なっていたりするネットワークのルートを書き込み直し、LocRibに無い
+    /// ネットワークのルートを削除する変更を集める。
+    /// `ip route flush`などで書き換えられても元に戻すために、定期的に呼ぶ。
+    /// 検出したずれは累計してstatsに表示する。
+    /// カーネルの参照と書き込みはLocRibのロックを外して行えるように、
+    /// installedを受け取り、KernelRouteChangesを返す。
+    pub fn compare_kernel_routing_table(
+        &mut self,
+        installed: kernel::InstalledRoutes,
+    ) -> (FibDriftStats, KernelRouteChanges) {
+        let installed: HashMap<IpNetwork, BTreeSet<IpAddr>> = installed
+            .into_iter()
+            .map(|(network, next_hops)| {
+                (network, next_hops.into_iter().collect())
+            })
+            .collect();
+        let (missing, orphaned) = fib_differences(
+            &self.paths_to_install(),
+            &installed,
+            self.mpls_encap,
+        );
+        let drift = FibDriftStats {
+            checks: 1,
+            missing: missing
+                .chunk_by(|a, b| a.network_address == b.network_address)
+                .count() as u64,
+            orphaned: orphaned.len() as u64,
+        };
+        self.fib_drift += drift;
+        let orphaned: Vec<Arc<RibEntry>> = orphaned
+            .into_iter()
+            .map(|network| {
+                Arc::new(RibEntry {
+                    network_address: network,
+                    labels: vec![],
+                    path_attributes: Arc::new(vec![]),
+                })
+            })
+            .collect();
+        (drift, self.kernel_route_changes(orphaned, missing))
+    }
+
+    /// Peerから受信してカーネルのルーティングテーブルに書き込んだルートを削除する。
+    /// デーモンの終了後に古いBGPのルートが残り続けないように、終了時に呼ぶ。
+    /// 自身がoriginateするルートと同じネットワークのルートは削除しない。
+    /// fib-writer-task=onでも、終了する前に削除を終えるためにその場で削除する。
+    pub async fn remove_learned_routes_from_kernel(&self) -> Result<()> {
+        let learned: HashSet<&Arc<RibEntry>> = self
+            .learned
+            .values()
+            .chain(self.stale.values())
+            .flatten()
+            .collect();
+        let removed: Vec<&Arc<RibEntry>> = learned
+            .iter()
+            .copied()
+            .filter(|e| self.rib.contains(e))
+            .filter(|e| {
+                self.rib
+                    .paths_of(&e.network_address)
+                    .all(|o| learned.contains(o))
+            })
+            .collect();
+        self.route_writer
+            .delete_routes(
+                removed.into_iter().cloned().collect(),
+                self.fib_target,
+            )
+            .await
+    }
+
+    /// ルートを書き込むカーネルのルーティングテーブルとmetric。
+    pub fn fib_target(&self) -> FibTarget {
+        self.fib_target
+    }
+
+    /// カーネルのルーティングテーブルの読み書きに使うもの。
+    /// LocRibのロックを外してからカーネルを参照するために使う。
+    pub fn route_writer(&self) -> SharedRouteWriter {
+        self.route_writer.clone()
+    }
+
+    /// 以降のカーネルのルーティングテーブルへの書き込みを専用のタスクで行う。
+    pub fn spawn_fib_writer(&mut self) {
+        self.fib_writer = Some(FibWriter::spawn(self.route_writer.clone()));
+    }
+
+    /// カーネルのルーティングテーブルの代わりにwriterに書き込む。
+    #[cfg(test)]
+    fn set_route_writer(&mut self, writer: SharedRouteWriter) {
+        self.route_writer = writer;
+    }
+
+    async fn add_to_kernel(
+        &self,
+        routes: impl Iterator<Item = &Arc<RibEntry>>,
+    ) -> Result<()> {
+        match &self.fib_writer {
+            Some(writer) => writer.add_routes(
+                routes.map(Arc::clone),
+                self.mpls_encap,
+                self.fib_target,
+            ),
+            None => {
+                self.route_writer
+                    .add_routes(
+                        routes.cloned().collect(),
+                        self.mpls_encap,
+                        self.fib_target,
+                    )
+                    .await
+            }
+        }
+    }
+
+    async fn delete_from_kernel(
+        &self,
+        routes: impl Iterator<Item = &Arc<RibEntry>>,
+    ) -> Result<()> {
+        match &self.fib_writer {
+            Some(writer) => {
+                writer.delete_routes(routes.map(Arc::clone), self.fib_target)
+            }
+            None => {
+                self.route_writer
+                    .delete_routes(routes.cloned().collect(), self.fib_target)
+                    .await
+            }
+        }
+    }
+}
+
+/// LocRibから集めた、カーネルのルーティングテーブルに反映する変更です。
+/// LocRibのロックを外した後で書き込めるように、書き込み先も一緒に持つ。
+#[derive(Debug)]
+pub struct KernelRouteChanges {
+    withdrawn: Vec<Arc<RibEntry>>,
+    installed: Vec<Arc<RibEntry>>,
+    mpls_encap: bool,
+    fib_target: FibTarget,
+    fib_writer: Option<FibWriter>,
+    route_writer: SharedRouteWriter,
+}
+
+impl KernelRouteChanges {
+    /// withdrawされたルートを削除してから、書き込むルートを書き込む。
+    pub async fn program(self) -> Result<()> {
+        match &self.fib_writer {
+            Some(writer) => {
+                writer.delete_routes(
+                    self.withdrawn.into_iter(),
+                    self.fib_target,
+                )?;
+                writer.add_routes(
+                    self.installed.into_iter(),
+                    self.mpls_encap,
+                    self.fib_target,
+                )
+            }
+            None => {
+                self.route_writer
+                    .delete_routes(self.withdrawn, self.fib_target)
+                    .await?;
+                self.route_writer
+                    .add_routes(
+                        self.installed,
+                        self.mpls_encap,
+                        self.fib_target,
+                    )
+                    .await
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AdjRibOut {
+    rib: Rib,
+    pub vpnv4: Rib<VpnRibEntry>,
+    pub flowspec: Rib<FlowSpecRibEntry>,
+    pub rtc: Rib<RtcRibEntry>,
+    // Well-known Communityにより広報しなかったルートの数。
+    pub suppressed: SuppressedRoutes,
+    // PeerがLLGR Capabilityを広報し、LLGR_STALEのルートを受け入れるか。
+    pub llgr_supported: bool,
+    // maintenance mode中の場合の、広報するルートに加える変更。
+    pub maintenance: Option<MaintenancePolicy>,
+    // confederationのmember ASまたは外のPeerの場合の、AS Pathの作り方。
+    pub confederation: Option<ConfederationSession>,
+    // iBGPのPeerか。iBGPのPeerにはAS Pathに自身のAS番号を追加しない。
+    pub internal: bool,
+    // 広報するルートのNext Hopを自身のアドレスに書き換えるか。
+    // falseの場合は受信したルートのNext Hopをそのまま広報する。
+    pub next_hop_self: bool,
+    // 広報をやめ、次のUpdateMessageでwithdrawを送るルート。
+    pub withdrawn: Rib,
+    // 同様に、withdrawを送るVPNv4ルート, FlowSpecのルール, Route Target Membership。
+    pub withdrawn_vpnv4: Rib<VpnRibEntry>,
+    pub withdrawn_flowspec: Rib<FlowSpecRibEntry>,
+    pub withdrawn_rtc: Rib<RtcRibEntry>,
+    // 送信するUpdateMessageの最大のオクテット数。
+    // Extended Messageをネゴシエーションした場合は65535 octetsになる。
+    pub max_message_length: usize,
+}
+
+/// AdjRibOutのribのルートをadvertisedに置き換える。広報しなくなったルートのうち、
+/// 同じNLRIの別のルートで置き換えないものはwithdrawnに加える。
+/// nlriは、同じルートとして置き換えるかを比べるためのkeyを返す。
+fn replace_advertised<E: RibKey + Hash, K: Eq + Hash>(
+    rib: &mut Rib<E>,
+    withdrawn: &mut Rib<E>,
+    advertised: HashSet<Arc<E>>,
+    nlri: impl Fn(&E) -> K,
+) {
+    let advertised_nlri: HashSet<K> =
+        advertised.iter().map(|a| nlri(a)).collect();
+    let removed: Vec<Arc<E>> = rib
+        .routes()
+        .filter(|e| !advertised.contains(*e))
+        .cloned()
+        .collect();
+    for entry in removed {
+        rib.remove(&entry);
+        if !advertised_nlri.contains(&nlri(&entry)) {
+            withdrawn.insert(entry);
+        }
+    }
+    // 広報し直すNLRIのwithdrawは送らない。
+    let readvertised: Vec<Arc<E>> = withdrawn
+        .routes()
+        .filter(|w| advertised_nlri.contains(&nlri(w)))
+        .cloned()
+        .collect();
+    for w in readvertised {
+        withdrawn.remove(&w);
+    }
+    for entry in advertised {
+        rib.insert(entry);
+    }
+}
+
+/// NO_ADVERTISE, NO_EXPORT, LLGR_STALEにより広報を抑制したルートの数です。
+/// install_from_loc_ribの度に数え直す。
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct SuppressedRoutes {
+    pub no_advertise: usize,
+    pub no_export: usize,
+    pub llgr_stale: usize,
+}
+
+impl Deref for AdjRibOut {
+    type Target = Rib;
+
+    fn deref(&self) -> &Self::Target {
+        &self.rib
+    }
+}
+
+impl DerefMut for AdjRibOut {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.rib
+    }
+}
+
+/// UpdateMessageにするPathAttribute, NLRI, withdrawn routes。
+type UpdateParts = (Vec<PathAttribute>, Vec<Ipv4Network>, Vec<Ipv4Network>);
+
+/// itemsのルートをmax_message_length以下のUpdateMessageにしてupdatesに加える。
+/// buildでitems全てのUpdateMessageを作り、長すぎる場合はitemsを半分ずつに分けて
+/// 作り直す。1つだけでも長すぎるルートは広報できないので送らない。
+fn push_update_messages<T: fmt::Debug>(
+    updates: &mut Vec<UpdateMessage>,
+    items: &[T],
+    max_message_length: usize,
+    build: &impl Fn(&[T]) -> UpdateParts,
+) {
+    if items.is_empty() {
+        return;
+    }
+    let (path_attributes, nlri, withdrawn_routes) = build(items);
+    let length = UpdateMessage::message_length(
+        &path_attributes,
+        &nlri,
+        &withdrawn_routes,
+    );
+    if length <= max_message_length {
+        updates.push(UpdateMessage::new(
+            Arc::new(path_attributes),
+            nlri,
+            withdrawn_routes,
+        ));
+        return;
+    }
+    if let [item] = items {
+        warn!(
+            "{:?} is not sent, it does not fit in {} octets.",
+            item, max_message_length
+        );
+        return;
+    }
+    let (first, second) = items.split_at(items.len() / 2);
+    push_update_messages(updates, first, max_message_length, build);
+    push_update_messages(updates, second, max_message_length, build);
+}
+
+/// IPv4 unicastのルートのネットワーク。
+fn ipv4_networks(entries: &[&Arc<RibEntry>]) -> Vec<Ipv4Network> {
+    entries
+        .iter()
+        .filter_map(|e| match e.network_address {
+            IpNetwork::V4(n) => Some(n),
+            _ => None,
+        })
+        .collect()
+}
+
+/// MP_REACH_NLRIとMP_UNREACH_NLRIに含めるルート。
+fn mp_nlri(
+    address_family: AddressFamily,
+    entries: &[&Arc<RibEntry>],
+) -> MpNlri {
+    match address_family.safi {
+        Safi::LabeledUnicast => MpNlri::LabeledUnicast(
+            entries
+                .iter()
+                .map(|e| LabeledPrefix {
+                    labels: e.labels.clone(),
+                    prefix: e.network_address,
+                })
+                .collect(),
+        ),
+        _ => MpNlri::Unicast(
+            entries.iter().map(|e| e.network_address).collect(),
+        ),
+    }
+}
+
+impl AdjRibOut {
+    pub fn new() -> Self {
+        Self {
+            rib: Rib::new(),
+            vpnv4: Rib::new(),
+            flowspec: Rib::new(),
+            rtc: Rib::new(),
+            suppressed: SuppressedRoutes::default(),
+            llgr_supported: false,
+            maintenance: None,
+            confederation: None,
+            internal: false,
+            next_hop_self: true,
+            withdrawn: Rib::new(),
+            withdrawn_vpnv4: Rib::new(),
+            withdrawn_flowspec: Rib::new(),
+            withdrawn_rtc: Rib::new(),
+            max_message_length: MAX_MESSAGE_LENGTH,
+        }
+    }
+
+    /// 次のUpdateMessageでwithdrawを送るルートがあるか。
+    pub fn has_withdrawn_routes(&self) -> bool {
+        !self.withdrawn.is_empty()
+            || !self.withdrawn_vpnv4.is_empty()
+            || !self.withdrawn_flowspec.is_empty()
+            || !self.withdrawn_rtc.is_empty()
+    }
+
+    /// withdrawを送ったルートを忘れる。
+    pub fn clear_withdrawn_routes(&mut self) {
+        self.withdrawn = Rib::new();
+        self.withdrawn_vpnv4 = Rib::new();
+        self.withdrawn_flowspec = Rib::new();
+        self.withdrawn_rtc = Rib::new();
+    }
+
+    /// 次のinstall_from_loc_ribで全てのルートを広報し直すために、
+    /// 広報したルートをwithdrawnに移す。install_from_loc_ribで広報し直すルートは
+    /// withdrawnから取り除かれ、広報しなくなったルートだけがwithdrawされる。
+    pub fn readvertise_all(&mut self) {
+        for entry in std::mem::replace(&mut self.rib, Rib::new()).routes() {
+            self.withdrawn.insert(Arc::clone(entry));
+        }
+        for entry in std::mem::replace(&mut self.vpnv4, Rib::new()).routes() {
+            self.withdrawn_vpnv4.insert(Arc::clone(entry));
+        }
+        for entry in std::mem::replace(&mut self.flowspec, Rib::new()).routes()
+        {
+            self.withdrawn_flowspec.insert(Arc::clone(entry));
+        }
+        for entry in std::mem::replace(&mut self.rtc, Rib::new()).routes() {
+            self.withdrawn_rtc.insert(Arc::clone(entry));
+        }
+    }
+
+    /// LocRibから必要なルートをインストールする。
+    /// ネットワークとaddress family毎に、best pathだけをインストールする。
+    /// この時、Remote AS番号が含まれているルートと、
+    /// Peerとネゴシエーションしていないaddress familyのルートはインストールしない。
+    /// Peer自身から受信したルートは、そのPeerに送り返さない。(split horizon)
+    /// best pathがPeer自身から受信したルートの場合は、他のルートを代わりに
+    /// 広報せず、インストール済みのルートをwithdrawする。
+    /// Peerから受信したLabeled unicastのルートはNext Hopを自身に書き換えて広報するので、
+    /// ラベルをimplicit nullにして、自身がIPパケットとして受け取りFIBで転送する。
+    /// PeerとRoute Target Constraintをネゴシエーションしている場合は、
+    /// Peerから受信したRoute Target Membershipに一致するVPNv4ルートだけをインストールする。
+    /// NO_ADVERTISEのルートはどのPeerにも、NO_EXPORTのルートはiBGP以外のPeerには広報しない。
+    /// LLGR_STALEのルートはLLGR Capabilityを広報していないPeerには広報しない。
+    /// iBGPのPeerから受信したルートは、route reflectorとして反射する場合
+    /// (RFC4456 Section 6)だけiBGPのPeerにインストールする。
+    /// conditional advertisementの条件を満たさないルートはインストールしない。
+    /// export-policyを設定している場合は、policyを適用したルートをインストールする。
+    /// default-originateを設定している場合は、条件を満たす間、LocRibのルートの
+    /// 代わりに自身がoriginateする0.0.0.0/0をインストールする。
+    /// インストール済みで広報しなくなったルートは取り除き、同じネットワークの
+    /// 他のルートを広報しない場合はwithdrawnに加える。VPNv4, FlowSpec,
+    /// Route Target Membershipも、同じNLRIで広報し直さない場合はwithdrawnに加える。
+    pub fn install_from_loc_rib(
+        &mut self,
+        loc_rib: &LocRib,
+        config: &Config,
+        address_families: &[AddressFamily],
+        route_target_memberships: &Rib<RtcRibEntry>,
+    ) {
+        let mut suppressed = SuppressedRoutes::default();
+        let llgr_supported = self.llgr_supported;
+        let conditions = &config.conditional_advertisements;
+        // prependで追加する自身のAS番号。AS Pathに自身のAS番号を加えない
+        // iBGPとconfederation内のPeerにはprependしない。
+        let prepend_as =
+            (!is_same_as_peer(config)).then(|| config.as_number_for_peer());
+        let mut advertised: HashSet<Arc<RibEntry>> = loc_rib
+            .best_paths()
+            .into_iter()
+            .filter(|entry| !entry.does_contain_as(config.remote_as))
+            .filter(|entry| !loc_rib.is_learned_from(entry, config.remote_ip))
+            .filter(|entry| {
+                loc_rib.satisfies_conditions(entry.network_address, conditions)
+            })
+            .filter(|entry| {
+                suppressed.is_advertisable(
+                    &entry.path_attributes,
+                    config,
+                    llgr_supported,
+                )
+            })
+            .filter(|entry| address_families.contains(&entry.address_family()))
+            .filter_map(|entry| reflect(entry, loc_rib, config))
+            .map(|r| {
+                if self.next_hop_self
+                    && !r.labels.is_empty()
+                    && r.next_hop() != Some(config.local_ip)
+                {
+                    Arc::new(RibEntry {
+                        labels: vec![MplsLabel::IMPLICIT_NULL],
+                        ..(*r).clone()
+                    })
+                } else {
+                    r
+                }
+            })
+            .filter_map(|r| config.export_policy.apply(&r, prepend_as))
+            .map(
+                |r| match prepend_as.filter(|_| config.as_path_prepend > 0) {
+                    Some(as_number) => Arc::new(
+                        r.with_prepended_as(as_number, config.as_path_prepend),
+                    ),
+                    None => r,
+                },
+            )
+            .collect();
+        if address_families.contains(&AddressFamily::IPV4_UNICAST)
+            && config.default_originate.as_ref().is_some_and(|policy| {
+                policy.is_empty()
+                    || loc_rib
+                        .routes()
+                        .any(|r| policy.apply(r, None).is_some())
+            })
+        {
+            let default_route = default_route(config);
+            advertised.retain(|r| {
+                r.network_address != default_route.network_address
+                    || r.address_family() != AddressFamily::IPV4_UNICAST
+            });
+            advertised.insert(match prepend_as {
+                Some(as_number) if config.as_path_prepend > 0 => Arc::new(
+                    default_route
+                        .with_prepended_as(as_number, config.as_path_prepend),
+                ),
+                _ => Arc::new(default_route),
+            });
+        }
+        let removed: Vec<Arc<RibEntry>> = self
+            .routes()
+            .filter(|entry| !advertised.contains(*entry))
+            .cloned()
+            .collect();
+        let advertised_networks: HashSet<(IpNetwork, AddressFamily)> =
+            advertised
+                .iter()
+                .map(|a| (a.network_address, a.address_family()))
+                .collect();
+        for entry in removed {
+            self.remove(&entry);
+            let is_replaced = advertised_networks
+                .contains(&(entry.network_address, entry.address_family()));
+            if !is_replaced {
+                self.withdrawn.insert(entry);
+            }
+        }
+        for entry in advertised {
+            for withdrawn in self
+                .withdrawn
+                .entries_of(entry.network_address, entry.address_family())
+            {
+                self.withdrawn.remove(&withdrawn);
+            }
+            self.insert(entry);
+        }
+
+        if address_families.contains(&AddressFamily::IPV4_MPLS_VPN) {
+            let advertised = loc_rib
+                .vpnv4
+                .routes()
+                .filter(|entry| {
+                    !does_contain_as(&entry.path_attributes, config.remote_as)
+                })
+                .filter(|entry| {
+                    suppressed.is_advertisable(
+                        &entry.path_attributes,
+                        config,
+                        llgr_supported,
+                    )
+                })
+                .filter(|entry| {
+                    !address_families.contains(&AddressFamily::IPV4_RTC) || {
+                        let route_targets = entry.route_targets();
+                        route_target_memberships
+                            .routes()
+                            .any(|m| m.membership.does_match(&route_targets))
+                    }
+                })
+                .cloned()
+                .collect();
+            replace_advertised(
+                &mut self.vpnv4,
+                &mut self.withdrawn_vpnv4,
+                advertised,
+                |e| (e.prefix.route_distinguisher, e.prefix.prefix),
+            );
+        }
+
+        if address_families.contains(&AddressFamily::IPV4_RTC) {
+            replace_advertised(
+                &mut self.rtc,
+                &mut self.withdrawn_rtc,
+                loc_rib.rtc.routes().cloned().collect(),
+                |e| e.membership,
+            );
+        }
+
+        if address_families.contains(&AddressFamily::IPV4_FLOWSPEC) {
+            let advertised = loc_rib
+                .flowspec
+                .routes()
+                .filter(|entry| {
+                    !does_contain_as(&entry.path_attributes, config.remote_as)
+                })
+                .filter(|entry| {
+                    suppressed.is_advertisable(
+                        &entry.path_attributes,
+                        config,
+                        llgr_supported,
+                    )
+                })
+                .cloned()
+                .collect();
+            replace_advertised(
+                &mut self.flowspec,
+                &mut self.withdrawn_flowspec,
+                advertised,
+                |e| e.rule.clone(),
+            );
+        }
+        self.suppressed = suppressed;
+    }
+
+    /// address family毎のRibのgenerationの和。ルートを追加するか取り除く度に進む。
+    /// withdrawnは広報する度に作り直すので含めない。
+    pub fn generation(&self) -> u64 {
+        self.rib.generation()
+            + self.vpnv4.generation()
+            + self.flowspec.generation()
+            + self.rtc.generation()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "unicast": self.rib.to_json(),
+            "vpnv4": self.vpnv4.to_json(),
+            "flowspec": self.flowspec.to_json(),
+            "rtc": self.rtc.to_json(),
+        })
+    }
+
+    /// AdjRibOutからUpdateMessageに変換する。
+    /// PathAttributeごとにUpdateMessageが分かれるためVec<UpdateMessage>の戻り値にしている。
+    /// 1つのUpdateMessageがmax_message_lengthを超える場合は、ルートを分けて
+    /// 複数のUpdateMessageにする。
+    /// IPv4 unicast以外のルートはMP_REACH_NLRIに含めて広報する。
+    /// Next Hopに使う自身のアドレスが無いaddress familyのルートは広報しない。
+    /// withdrawnのルートは先にwithdrawするUpdateMessageにする。
+    /// IPv4 unicast以外のルートはMP_UNREACH_NLRIに含めてwithdrawする。
+    /// VPNv4, FlowSpec, Route Target Membershipも同様にwithdrawする。
+    /// EVPNとBGP-LSのルートは収集するだけで広報しないので、withdrawも送らない。
+    pub fn create_update_messages(
+        &self,
+        local_ip: IpAddr,
+        local_as: AutonomousSystemNumber,
+    ) -> Vec<UpdateMessage> {
+        let mut updates = self.create_withdraw_messages(local_ip);
+
+        // address familyとPathAttributeが同じルートを1つのUpdateMessageにまとめる。
+        type Key = (AddressFamily, Arc<Vec<PathAttribute>>);
+        let mut groups: BTreeMap<Key, Vec<&Arc<RibEntry>>> = BTreeMap::new();
+        for entry in self.routes() {
+            groups
+                .entry((
+                    entry.address_family(),
+                    Arc::clone(&entry.path_attributes),
+                ))
+                .or_default()
+                .push(entry);
+        }
+
+        for ((address_family, path_attributes), entries) in groups.into_iter()
+        {
+            let is_local_ip_same_family = match local_ip {
+                IpAddr::V4(_) => address_family.afi == Afi::Ipv4,
+                IpAddr::V6(_) => address_family.afi == Afi::Ipv6,
+            };
+            if !is_local_ip_same_family {
+                continue;
+            }
+            push_update_messages(
+                &mut updates,
+                &entries,
+                self.max_message_length,
+                &|entries| {
+                    let nlri = if address_family == AddressFamily::IPV4_UNICAST
+                    {
+                        ipv4_networks(entries)
+                    } else {
+                        vec![]
+                    };
+                    let path_attributes = self
+                        .change_path_attributes_for_advertisement(
+                            &path_attributes,
+                            local_ip,
+                            local_as,
+                            mp_nlri(address_family, entries),
+                        );
+                    (path_attributes, nlri, vec![])
+                },
+            );
+        }
+
+        // VPNv4ルートとRoute Target MembershipはNext Hopに自身のIPv4アドレスが必要。
+        if local_ip.is_ipv4() {
+            let mut groups: BTreeMap<
+                Arc<Vec<PathAttribute>>,
+                Vec<Vpnv4Prefix>,
+            > = BTreeMap::new();
+            for entry in self.vpnv4.routes() {
+                groups
+                    .entry(Arc::clone(&entry.path_attributes))
+                    .or_default()
+                    .push(entry.prefix.clone());
+            }
+            for (path_attributes, routes) in groups.into_iter() {
+                push_update_messages(
+                    &mut updates,
+                    &routes,
+                    self.max_message_length,
+                    &|routes| {
+                        let path_attributes = self
+                            .change_path_attributes_for_advertisement(
+                                &path_attributes,
+                                local_ip,
+                                local_as,
+                                MpNlri::Vpnv4(routes.to_vec()),
+                            );
+                        (path_attributes, vec![], vec![])
+                    },
+                );
+            }
+
+            let memberships: Vec<RouteTargetMembership> =
+                self.rtc.routes().map(|e| e.membership).collect();
+            if let Some(entry) = self.rtc.routes().next() {
+                push_update_messages(
+                    &mut updates,
+                    &memberships,
+                    self.max_message_length,
+                    &|memberships| {
+                        let path_attributes = self
+                            .change_path_attributes_for_advertisement(
+                                &entry.path_attributes,
+                                local_ip,
+                                local_as,
+                                MpNlri::RouteTargetConstraint(
+                                    memberships.to_vec(),
+                                ),
+                            );
+                        (path_attributes, vec![], vec![])
+                    },
+                );
+            }
+        }
+
+        // FlowSpecルールはnext hopを持たないので、local_ipに関わらず広報する。
+        let mut groups: BTreeMap<Arc<Vec<PathAttribute>>, Vec<FlowSpecRule>> =
+            BTreeMap::new();
+        for entry in self.flowspec.routes() {
+            groups
+                .entry(Arc::clone(&entry.path_attributes))
+                .or_default()
+                .push(entry.rule.clone());
+        }
+        for (path_attributes, rules) in groups.into_iter() {
+            push_update_messages(
+                &mut updates,
+                &rules,
+                self.max_message_length,
+                &|rules| {
+                    let path_attributes = self
+                        .change_path_attributes_for_advertisement(
+                            &path_attributes,
+                            local_ip,
+                            local_as,
+                            MpNlri::FlowSpec(rules.to_vec()),
+                        );
+                    (path_attributes, vec![], vec![])
+                },
+            );
+        }
+        updates
+    }
+
+    /// withdrawnのルートをaddress family毎にUpdateMessageにする。
+    /// max_message_lengthを超える場合は複数のUpdateMessageに分ける。
+    fn create_withdraw_messages(
+        &self,
+        local_ip: IpAddr,
+    ) -> Vec<UpdateMessage> {
+        let mut groups: BTreeMap<AddressFamily, Vec<&Arc<RibEntry>>> =
+            BTreeMap::new();
+        for entry in self.withdrawn.routes() {
+            groups
+                .entry(entry.address_family())
+                .or_default()
+                .push(entry);
+        }
+
+        let mut updates = vec![];
+        for (address_family, entries) in groups.into_iter() {
+            let is_local_ip_same_family = match local_ip {
+                IpAddr::V4(_) => address_family.afi == Afi::Ipv4,
+                IpAddr::V6(_) => address_family.afi == Afi::Ipv6,
+            };
+            if !is_local_ip_same_family {
+                continue;
+            }
+            if address_family == AddressFamily::IPV4_UNICAST {
+                push_update_messages(
+                    &mut updates,
+                    &entries,
+                    self.max_message_length,
+                    &|entries| (vec![], vec![], ipv4_networks(entries)),
+                );
+                continue;
+            }
+            push_update_messages(
+                &mut updates,
+                &entries,
+                self.max_message_length,
+                &|entries| {
+                    let withdrawn_routes = MpUnreachNlri::new(
+                        address_family,
+                        mp_nlri(address_family, entries),
+                    );
+                    (
+                        vec![PathAttribute::MpUnreachNlri(withdrawn_routes)],
+                        vec![],
+                        vec![],
+                    )
+                },
+            );
+        }
+        let withdrawn_vpnv4: Vec<Vpnv4Prefix> = self
+            .withdrawn_vpnv4
+            .routes()
+            .map(|e| e.prefix.clone())
+            .collect();
+        push_update_messages(
+            &mut updates,
+            &withdrawn_vpnv4,
+            self.max_message_length,
+            &|routes| {
+                let withdrawn_routes = MpUnreachNlri::new(
+                    AddressFamily::IPV4_MPLS_VPN,
+                    MpNlri::Vpnv4(routes.to_vec()),
+                );
+                (
+                    vec![PathAttribute::MpUnreachNlri(withdrawn_routes)],
+                    vec![],
+                    vec![],
+                )
+            },
+        );
+        let withdrawn_flowspec: Vec<FlowSpecRule> = self
+            .withdrawn_flowspec
+            .routes()
+            .map(|e| e.rule.clone())
+            .collect();
+        push_update_messages(
+            &mut updates,
+            &withdrawn_flowspec,
+            self.max_message_length,
+            &|rules| {
+                let withdrawn_routes = MpUnreachNlri::new(
+                    AddressFamily::IPV4_FLOWSPEC,
+                    MpNlri::FlowSpec(rules.to_vec()),
+                );
+                (
+                    vec![PathAttribute::MpUnreachNlri(withdrawn_routes)],
+                    vec![],
+                    vec![],
+                )
+            },
+        );
+        let withdrawn_rtc: Vec<RouteTargetMembership> =
+            self.withdrawn_rtc.routes().map(|e| e.membership).collect();
+        push_update_messages(
+            &mut updates,
+            &withdrawn_rtc,
+            self.max_message_length,
+            &|memberships| {
+                let withdrawn_routes = MpUnreachNlri::new(
+                    AddressFamily::IPV4_RTC,
+                    MpNlri::RouteTargetConstraint(memberships.to_vec()),
+                );
+                (
+                    vec![PathAttribute::MpUnreachNlri(withdrawn_routes)],
+                    vec![],
+                    vec![],
+                )
+            },
+        );
+        updates
+    }
+
+    /// 広報するためにPathAttributeを変更する。
+    /// Next Hopを自身のアドレスにし、AS Pathに自身のAS番号を追加する。
+    /// MP_REACH_NLRIがある場合はnlriを広報するルートにする。
+    /// maintenance mode中はGRACEFUL_SHUTDOWNを付け、AS Pathを延ばし、
+    /// MEDを変えて、Peerがこのルートを選ばないようにする。
+    /// confederationのmember ASのPeerには自身のAS番号をAS_CONFED_SEQUENCEに追加し、
+    /// 外のPeerにはconfederationのsegmentを取り除いてidentifierを追加する。
+    /// iBGPのPeerにはAS Pathを変えず、反射するルートはNext Hopも変えない。
+    /// next-hop-self=offの場合は、どのPeerにも受信したNext Hopのまま広報する。
+    /// 他のASのPeerには、LOCAL_PREFと隣のASから受信したMEDを取り除く。
+    /// ORIGINATOR_IDとCLUSTER_LISTはiBGPのPeerにだけ広報する。
+    fn change_path_attributes_for_advertisement(
+        &self,
+        path_attributes: &[PathAttribute],
+        local_ip: IpAddr,
+        local_as: AutonomousSystemNumber,
+        nlri: MpNlri,
+    ) -> Vec<PathAttribute> {
+        let mut path_attributes = path_attributes.to_vec();
+        let is_reflected = path_attributes
+            .iter()
+            .any(|p| matches!(p, PathAttribute::OriginatorId(_)));
+        if !self.internal {
+            path_attributes.retain(|p| {
+                !matches!(
+                    p,
+                    PathAttribute::OriginatorId(_)
+                        | PathAttribute::ClusterList(_)
+                )
+            });
+        }
+        // 隣のASから受信したMEDは他のASに広報しない。自AS内で生成したルートの
+        // MEDだけを広報する。(RFC4271 Section 5.1.4)
+        // LOCAL_PREFは他のASのPeerには広報しない。(RFC4271 Section 5.1.5)
+        let is_external = !self.internal
+            && !matches!(
+                self.confederation,
+                Some(ConfederationSession::Member)
+            );
+        let is_from_other_as = path_attributes.iter().any(
+            |p| matches!(p, PathAttribute::AsPath(a) if a.path_length() > 0),
+        );
+        if is_external {
+            path_attributes.retain(|p| match p {
+                PathAttribute::LocalPref(_) => false,
+                PathAttribute::MultiExitDisc(_) => !is_from_other_as,
+                _ => true,
+            });
+        }
+        // next-hop-self=offの場合も、自身がoriginateしたnext hopが未指定の
+        // ルートは自身のアドレスにする。
+        let is_next_hop_kept =
+            (self.internal && is_reflected) || !self.next_hop_self;
+        for p in path_attributes.iter_mut() {
+            if let (PathAttribute::NextHop(n), IpAddr::V4(local_ip)) =
+                (&mut *p, local_ip)
+            {
+                if !is_next_hop_kept || n.is_unspecified() {
+                    *n = local_ip
+                }
+            }
+            if let PathAttribute::MpReachNlri(m) = p {
+                if !is_next_hop_kept || m.next_hop.is_unspecified() {
+                    m.next_hop = local_ip;
+                    m.link_local_next_hop = None;
+                }
+                m.nlri = nlri.clone();
+            }
+            if let PathAttribute::AsPath(ases) = p {
+                if self.internal {
+                    continue;
+                }
+                if let Some(ConfederationSession::External(_)) =
+                    self.confederation
+                {
+                    ases.remove_confederation_segments();
+                }
+                let prepend = self
+                    .maintenance
+                    .as_ref()
+                    .map_or(0, |maintenance| maintenance.prepend);
+                for _ in 0..=prepend {
+                    match self.confederation {
+                        Some(ConfederationSession::Member) => {
+                            ases.prepend_confederation(local_as)
+                        }
+                        Some(ConfederationSession::External(identifier)) => {
+                            ases.prepend(identifier)
+                        }
+                        None => ases.prepend(local_as),
+                    }
+                }
+            }
+        }
+        if let Some(maintenance) = &self.maintenance {
+            if let Some(med) = maintenance.med {
+                path_attributes
+                    .retain(|p| !matches!(p, PathAttribute::MultiExitDisc(_)));
+                path_attributes.push(PathAttribute::MultiExitDisc(med));
+            }
+            if maintenance.graceful_shutdown
+                && !communities(&path_attributes)
+                    .contains(&Community::GRACEFUL_SHUTDOWN)
+            {
+                add_community(
+                    &mut path_attributes,
+                    Community::GRACEFUL_SHUTDOWN,
+                );
+            }
+        }
+        path_attributes
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AdjRibIn {
+    rib: Rib,
+    pub vpnv4: Rib<VpnRibEntry>,
+    pub flowspec: Rib<FlowSpecRibEntry>,
+    pub evpn: Rib<EvpnRibEntry>,
+    pub link_state: Rib<LinkStateRibEntry>,
+    pub rtc: Rib<RtcRibEntry>,
+    // iBGPのPeerの場合の、route reflectionに使うPeerの情報。
+    pub internal_peer: Option<InternalPeer>,
+    // PeerのBGP Identifier。LocRibでbest pathを選ぶ時に比べる。
+    pub bgp_identifier: Option<Ipv4Addr>,
+    // import-policyを設定した場合の、LocRibに入れる時に適用するpolicy。
+    pub import_policy: Option<ImportPolicy>,
+    // route flap dampingのための、ルートが変化したネットワーク毎の履歴。
+    flap_histories: HashMap<IpNetwork, FlapHistory>,
+    // penaltyがsuppressを超えたため、ribから外しているルート。
+    suppressed: Rib,
+}
+
+/// route flap damping(RFC2439)のための、ネットワーク毎のpenaltyです。
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct FlapHistory {
+    penalty: u32,
+    // penaltyを計算した時刻。以降はhalf-lifeに従って減っていく。
+    updated: Instant,
+    flaps: u32,
+    suppressed: bool,
+}
+
+impl FlapHistory {
+    fn penalty_at(&self, now: Instant, damping: &Damping) -> u32 {
+        let half_lives =
+            now.saturating_duration_since(self.updated).as_secs_f64()
+                / damping.half_life.as_secs_f64();
+        (f64::from(self.penalty) * 0.5_f64.powf(half_lives)) as u32
+    }
+}
+
+/// AdjRibInのroute flap dampingの状態を数えたものです。
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct DampingStats {
+    // penaltyを持っているネットワークの数。
+    pub histories: usize,
+    // 抑制しているルートの数。
+    pub suppressed: usize,
+    // 履歴を持っているネットワークの、これまでに変化した回数の合計。
+    pub flaps: u64,
+}
+
+impl std::iter::Sum for DampingStats {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), |sum, stats| Self {
+            histories: sum.histories + stats.histories,
+            suppressed: sum.suppressed + stats.suppressed,
+            flaps: sum.flaps + stats.flaps,
+        })
+    }
+}
+
+/// カーネルのルーティングテーブルと、LocRibが書き込んだはずのルートのずれを数えたものです。
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct FibDriftStats {
+    // 比べた回数。
+    pub checks: u64,
+    // カーネルから無くなっていたか、next hopが異なっていたため、
+    // 書き込み直したネットワークの数。
+    pub missing: u64,
+    // LocRibに無いのにカーネルに残っていたため、削除したネットワークの数。
+    pub orphaned: u64,
+}
+
+impl std::ops::AddAssign for FibDriftStats {
+    fn add_assign(&mut self, other: Self) {
+        self.checks += other.checks;
+        self.missing += other.missing;
+        self.orphaned += other.orphaned;
+    }
+}
+
+/// 書き込むはずのpathsのうち、installedに無いか、installedのnext hopの組が
+/// 書き込むはずのものと異なるネットワークのルートと、
+/// installedのうちpathsに無いネットワークを返す。
+fn fib_differences(
+    paths: &[Arc<RibEntry>],
+    installed: &HashMap<IpNetwork, BTreeSet<IpAddr>>,
+    mpls_encap: bool,
+) -> (Vec<Arc<RibEntry>>, Vec<IpNetwork>) {
+    let mut missing = vec![];
+    for same_network in
+        paths.chunk_by(|a, b| a.network_address == b.network_address)
+    {
+        let entries: Vec<&RibEntry> =
+            same_network.iter().map(|p| p.as_ref()).collect();
+        let expected: BTreeSet<IpAddr> =
+            kernel::installed_next_hops(&entries, mpls_encap)
+                .into_iter()
+                .collect();
+        if installed.get(&same_network[0].network_address) != Some(&expected) {
+            missing.extend(same_network.iter().cloned());
+        }
+    }
+    let expected: HashSet<IpNetwork> =
+        paths.iter().map(|p| p.network_address).collect();
+    let mut orphaned: Vec<IpNetwork> = installed
+        .keys()
+        .filter(|n| !expected.contains(n))
+        .copied()
+        .collect();
+    orphaned.sort();
+    (missing, orphaned)
+}
+
+/// Peerから受信したルートをLocRibに入れる時に適用するpolicyです。
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ImportPolicy {
+    pub policy: Policy,
+    // prependで追加する、PeerのAS番号。iBGPとconfederation内のPeerではNone。
+    pub prepend_as: Option<AutonomousSystemNumber>,
+}
+
+impl ImportPolicy {
+    /// import-policyを設定した場合だけ作成する。
+    pub fn new(config: &Config) -> Option<Self> {
+        (!config.import_policy.is_empty()).then(|| Self {
+            policy: config.import_policy.clone(),
+            prepend_as: (!is_same_as_peer(config)).then_some(config.remote_as),
+        })
+    }
+}
+
+/// ルートを受信したiBGPのPeerです。
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct InternalPeer {
+    // 反射する場合にORIGINATOR_IDにする、PeerのBGP Identifier。
+    pub bgp_identifier: Ipv4Addr,
+    pub reflector_client: bool,
+}
+
+impl InternalPeer {
+    /// iBGPのPeerの場合だけ作成する。
+    pub fn new(config: &Config, bgp_identifier: Ipv4Addr) -> Option<Self> {
+        config.is_ibgp().then_some(Self {
+            bgp_identifier,
+            reflector_client: config.route_reflector_client,
+        })
+    }
+}
+
+impl Deref for AdjRibIn {
+    type Target = Rib;
+
+    fn deref(&self) -> &Self::Target {
+        &self.rib
+    }
+}
+
+impl DerefMut for AdjRibIn {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.rib
+    }
+}
+
+impl AdjRibIn {
+    pub fn new() -> Self {
+        Self {
+            rib: Rib::new(),
+            vpnv4: Rib::new(),
+            flowspec: Rib::new(),
+            evpn: Rib::new(),
+            link_state: Rib::new(),
+            rtc: Rib::new(),
+            internal_peer: None,
+            bgp_identifier: None,
+            import_policy: None,
+            flap_histories: HashMap::new(),
+            suppressed: Rib::new(),
+        }
+    }
+
+    // route flap dampingで、withdrawとPathAttributeの変化毎に加えるpenalty。
+    const WITHDRAWAL_PENALTY: u32 = 1000;
+    const ATTRIBUTE_CHANGE_PENALTY: u32 = 500;
+
+    pub fn damping_stats(&self) -> DampingStats {
+        DampingStats {
+            histories: self.flap_histories.len(),
+            suppressed: self.suppressed.len(),
+            flaps: self
+                .flap_histories
+                .values()
+                .map(|h| u64::from(h.flaps))
+                .sum(),
+        }
+    }
+
+    /// penaltyがreuseを下回ったルートの抑制をやめてribに戻し、
+    /// 十分にpenaltyが減ったネットワークの履歴を忘れる。
+    /// ribに戻したルートがあればtrueを返す。
+    pub fn reuse_damped_routes(
+        &mut self,
+        damping: &Damping,
+        now: Instant,
+    ) -> bool {
+        let mut reused = vec![];
+        self.flap_histories.retain(|network, history| {
+            let penalty = history.penalty_at(now, damping);
+            if history.suppressed && penalty < damping.reuse {
+                info!("{} is reused after route flap damping.", network);
+                history.suppressed = false;
+                reused.push(*network);
+            }
+            history.suppressed || penalty >= damping.reuse / 2
+        });
+        let entries: Vec<Arc<RibEntry>> = self
+            .suppressed
+            .routes()
+            .filter(|e| reused.contains(&e.network_address))
+            .cloned()
+            .collect();
+        for entry in entries.iter() {
+            self.suppressed.remove(entry);
+            self.rib.insert(Arc::clone(entry));
+        }
+        !entries.is_empty()
+    }
+
+    /// networkのpenaltyを加え、suppressを超えたらnetworkのルートを抑制する。
+    fn add_penalty(
+        &mut self,
+        network: IpNetwork,
+        address_family: AddressFamily,
+        penalty: u32,
+        damping: &Damping,
+    ) {
+        let now = Instant::now();
+        // penaltyがmax-suppressの間にreuseまで減るように上限を設ける。
+        let max_penalty = f64::from(damping.reuse)
+            * 2_f64.powf(
+                damping.max_suppress.as_secs_f64()
+                    / damping.half_life.as_secs_f64(),
+            );
+        let history =
+            self.flap_histories.entry(network).or_insert(FlapHistory {
+                penalty: 0,
+                updated: now,
+                flaps: 0,
+                suppressed: false,
+            });
+        history.penalty = (history.penalty_at(now, damping) + penalty)
+            .min(max_penalty.min(f64::from(u32::MAX)) as u32);
+        history.updated = now;
+        history.flaps += 1;
+        if history.suppressed || history.penalty <= damping.suppress {
+            return;
+        }
+        info!(
+            "{} is suppressed by route flap damping, penalty={}.",
+            network, history.penalty
+        );
+        history.suppressed = true;
+        for entry in self.rib.entries_of(network, address_family) {
+            self.rib.remove(&entry);
+            self.suppressed.insert(entry);
+        }
+    }
+
+    fn is_suppressed(&self, network: &IpNetwork) -> bool {
+        self.flap_histories
+            .get(network)
+            .is_some_and(|h| h.suppressed)
+    }
+
+    /// 全てのaddress familyの、Peerから受信したルートの数。
+    pub fn prefix_count(&self) -> usize {
+        self.rib.len()
+            + self.vpnv4.len()
+            + self.flowspec.len()
+            + self.evpn.len()
+            + self.link_state.len()
+            + self.rtc.len()
+    }
+
+    /// address family毎のRibのgenerationの和。ルートを追加するか取り除く度に進む。
+    pub fn generation(&self) -> u64 {
+        self.rib.generation()
+            + self.vpnv4.generation()
+            + self.flowspec.generation()
+            + self.evpn.generation()
+            + self.link_state.generation()
+            + self.rtc.generation()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "unicast": self.rib.to_json(),
+            "vpnv4": self.vpnv4.to_json(),
+            "flowspec": self.flowspec.to_json(),
+            "evpn": self.evpn.to_json(),
+            "link_state": self.link_state.to_json(),
+            "rtc": self.rtc.to_json(),
+        })
+    }
+
+    /// UPDATE Messageで広報されたルートをインストールし、withdrawされたルートを
+    /// 取り除く。ルートを取り除いた場合はtrueを返す。
+    /// VPNv4のルートは、Route DistinguisherとprefixでwithdrawするルートとMP_REACH_NLRIで
+    /// 置き換えるルートを判断する。ラベルはwithdrawでは意味を持たない。(RFC8277 Section 2.4)
+    /// FlowSpecのルール, Route Target Membership, BGP-LSのルートは、
+    /// NLRIそのものでwithdrawと置き換えを判断する。
+    /// EVPNのルートは、ルートのキーに含まれるフィールドで判断する。
+    pub fn install_from_update(
+        &mut self,
+        update: UpdateMessage,
+        config: &Config,
+    ) -> bool {
+        let mut withdrawn = false;
+        for network in update.withdrawn_routes.iter() {
+            withdrawn |= self.withdraw(
+                (*network).into(),
+                AddressFamily::IPV4_UNICAST,
+                config,
+            );
+        }
+        for p in update.path_attributes.iter() {
+            let mp_unreach = match p {
+                PathAttribute::MpUnreachNlri(m) => m,
+                _ => continue,
+            };
+            let networks: Vec<IpNetwork> = match &mp_unreach.withdrawn_routes {
+                MpNlri::Unicast(networks) => networks.clone(),
+                MpNlri::LabeledUnicast(prefixes) => {
+                    prefixes.iter().map(|p| p.prefix).collect()
+                }
+                MpNlri::Vpnv4(prefixes) => {
+                    for prefix in prefixes {
+                        self.remove_vpnv4(prefix);
+                    }
+                    vec![]
+                }
+                MpNlri::FlowSpec(rules) => {
+                    for rule in rules {
+                        self.remove_flowspec(rule);
+                    }
+                    vec![]
+                }
+                MpNlri::RouteTargetConstraint(memberships) => {
+                    for membership in memberships {
+                        self.remove_rtc(membership);
+                    }
+                    vec![]
+                }
+                MpNlri::Evpn(routes) => {
+                    for route in routes {
+                        self.remove_evpn(route);
+                    }
+                    vec![]
+                }
+                MpNlri::LinkState(nlris) => {
+                    for nlri in nlris {
+                        self.remove_link_state(nlri);
+                    }
+                    vec![]
+                }
+                _ => vec![],
+            };
+            for network in networks {
+                withdrawn |=
+                    self.withdraw(network, mp_unreach.address_family, config);
+            }
+        }
+        if config.is_ibgp()
+            && is_reflection_loop(&update.path_attributes, config)
+        {
+            return withdrawn;
+        }
+        // MP_REACH_NLRI, MP_UNREACH_NLRIはルート毎の情報なので、
+        // RibEntryには含めずに残りのPathAttributeを共有する。
+        let mut base_path_attributes: Vec<PathAttribute> = update
+            .path_attributes
+            .iter()
+            .filter(|p| {
+                !matches!(
+                    p,
+                    PathAttribute::MpReachNlri(_)
+                        | PathAttribute::MpUnreachNlri(_)
+                )
+            })
+            .cloned()
+            .collect();
+        // 他のASのPeerから受信したLOCAL_PREFは無視し、自身の値を付ける。
+        // iBGPのPeerのルートにLOCAL_PREFが無い場合も同様にする。
+        // (RFC4271 Section 5.1.5)
+        if !is_same_as_peer(config) {
+            base_path_attributes
+                .retain(|p| !matches!(p, PathAttribute::LocalPref(_)));
+        }
+        if !base_path_attributes
+            .iter()
+            .any(|p| matches!(p, PathAttribute::LocalPref(_)))
+        {
+            base_path_attributes
+                .push(PathAttribute::LocalPref(config.local_pref));
+        }
+        let path_attributes = Arc::new(base_path_attributes.clone());
+        for network in update.network_layer_reachability_information {
+            if !is_acceptable_from_peer(&network.into(), config) {
+                continue;
+            }
+            let rib_entry = Arc::new(RibEntry {
+                network_address: network.into(),
+                labels: vec![],
+                path_attributes: Arc::clone(&path_attributes),
+            });
+            // PathAttributesが変わってたらインストールする必要がある。
+            withdrawn |= self.replace(rib_entry, config);
+        }
+
+        for p in update.path_attributes.iter() {
+            let mp_reach = if let PathAttribute::MpReachNlri(m) = p {
+                m
+            } else {
+                continue;
+            };
+            // IPv4 unicast以外のルートではNEXT_HOPは使われず、
+            // MP_REACH_NLRI内のnext hopを使う。
+            let mut path_attributes: Vec<PathAttribute> = base_path_attributes
+                .iter()
+                .filter(|p| !matches!(p, PathAttribute::NextHop(_)))
+                .cloned()
+                .collect();
+            match (mp_reach.address_family, mp_reach.next_hop) {
+                (AddressFamily::IPV4_UNICAST, IpAddr::V4(n)) => {
+                    path_attributes.push(PathAttribute::NextHop(n))
+                }
+                _ => path_attributes.push(PathAttribute::MpReachNlri(
+                    MpReachNlri {
+                        nlri: MpNlri::empty(mp_reach.address_family),
+                        ..mp_reach.clone()
+                    },
+                )),
+            }
+            let path_attributes = Arc::new(path_attributes);
+            match &mp_reach.nlri {
+                MpNlri::Unicast(networks) => {
+                    for network in networks
+                        .iter()
+                        .filter(|n| is_acceptable_from_peer(n, config))
+                    {
+                        withdrawn |= self.replace(
+                            Arc::new(RibEntry {
+                                network_address: *network,
+                                labels: vec![],
+                                path_attributes: Arc::clone(&path_attributes),
+                            }),
+                            config,
+                        );
+                    }
+                }
+                MpNlri::LabeledUnicast(prefixes) => {
+                    for prefix in prefixes
+                        .iter()
+                        .filter(|p| is_acceptable_from_peer(&p.prefix, config))
+                    {
+                        withdrawn |= self.replace(
+                            Arc::new(RibEntry {
+                                network_address: prefix.prefix,
+                                labels: prefix.labels.clone(),
+                                path_attributes: Arc::clone(&path_attributes),
+                            }),
+                            config,
+                        );
+                    }
+                }
+                MpNlri::Vpnv4(prefixes) => {
+                    for prefix in prefixes {
+                        self.remove_vpnv4(prefix);
+                        self.vpnv4.insert(Arc::new(VpnRibEntry {
+                            prefix: prefix.clone(),
+                            path_attributes: Arc::clone(&path_attributes),
+                        }));
+                    }
+                }
+                MpNlri::FlowSpec(rules) => {
+                    for rule in rules {
+                        self.flowspec.replace(Arc::new(FlowSpecRibEntry {
+                            rule: rule.clone(),
+                            path_attributes: Arc::clone(&path_attributes),
+                        }));
+                    }
+                }
+                MpNlri::Evpn(routes) => {
+                    for route in routes {
+                        self.remove_evpn(route);
+                        self.evpn.insert(Arc::new(EvpnRibEntry {
+                            route: route.clone(),
+                            path_attributes: Arc::clone(&path_attributes),
+                        }));
+                    }
+                }
+                MpNlri::LinkState(nlris) => {
+                    for nlri in nlris {
+                        self.link_state.replace(Arc::new(LinkStateRibEntry {
+                            nlri: nlri.clone(),
+                            path_attributes: Arc::clone(&path_attributes),
+                        }));
+                    }
+                }
+                MpNlri::RouteTargetConstraint(memberships) => {
+                    for membership in memberships {
+                        self.rtc.replace(Arc::new(RtcRibEntry {
+                            membership: *membership,
+                            path_attributes: Arc::clone(&path_attributes),
+                        }));
+                    }
+                }
+            }
+        }
+        withdrawn
+    }
+
+    /// prefixとRoute Distinguisher, IPv4 prefixが同じVPNv4ルートを取り除く。
+    fn remove_vpnv4(&mut self, prefix: &Vpnv4Prefix) {
+        let removed: Vec<Arc<VpnRibEntry>> = self
+            .vpnv4
+            .routes()
+            .filter(|e| e.prefix.is_same_route(prefix))
+            .cloned()
+            .collect();
+        for entry in removed {
+            self.vpnv4.remove(&entry);
+        }
+    }
+
+    /// ruleが同じFlowSpecのルールを取り除く。
+    fn remove_flowspec(&mut self, rule: &FlowSpecRule) {
+        let removed: Vec<Arc<FlowSpecRibEntry>> =
+            self.flowspec.paths_of(rule).cloned().collect();
+        for entry in removed {
+            self.flowspec.remove(&entry);
+        }
+    }
+
+    /// routeと同じルートを表すEVPNルートを取り除く。
+    fn remove_evpn(&mut self, route: &EvpnRoute) {
+        let removed: Vec<Arc<EvpnRibEntry>> = self
+            .evpn
+            .routes()
+            .filter(|e| e.route.is_same_route(route))
+            .cloned()
+            .collect();
+        for entry in removed {
+            self.evpn.remove(&entry);
+        }
+    }
+
+    /// nlriが同じBGP-LSのルートを取り除く。
+    fn remove_link_state(&mut self, nlri: &LinkStateNlri) {
+        let removed: Vec<Arc<LinkStateRibEntry>> =
+            self.link_state.paths_of(nlri).cloned().collect();
+        for entry in removed {
+            self.link_state.remove(&entry);
+        }
+    }
+
+    /// Route Target Membershipを取り除く。
+    /// 取り除いたRoute Targetにだけ一致するVPNv4ルートは、
+    /// 次のinstall_from_loc_ribでAdjRibOutからwithdrawされる。
+    fn remove_rtc(&mut self, membership: &RouteTargetMembership) {
+        let removed: Vec<Arc<RtcRibEntry>> =
+            self.rtc.paths_of(membership).cloned().collect();
+        for entry in removed {
+            self.rtc.remove(&entry);
+        }
+    }
+
+    /// 同じネットワークのルートを別のPathAttributeで受信していれば置き換える。
+    /// (RFC4271 Section 3.1のimplicit withdraw)
+    /// route flap dampingで抑制されてribからルートが無くなった場合はtrueを返す。
+    fn replace(&mut self, entry: Arc<RibEntry>, config: &Config) -> bool {
+        let network = entry.network_address;
+        let address_family = entry.address_family();
+        let mut removed = false;
+        let mut changed = false;
+        for old in self.rib.entries_of(network, address_family) {
+            if old != entry {
+                removed |= self.rib.remove(&old);
+                changed = true;
+            }
+        }
+        for old in self.suppressed.entries_of(network, address_family) {
+            if old != entry {
+                self.suppressed.remove(&old);
+                changed = true;
+            }
+        }
+        if let Some(damping) = config.damping.filter(|_| changed) {
+            self.add_penalty(
+                network,
+                address_family,
+                Self::ATTRIBUTE_CHANGE_PENALTY,
+                &damping,
+            );
+        }
+        if self.is_suppressed(&network) {
+            self.suppressed.insert(entry);
+            removed
+        } else {
+            self.rib.insert(entry);
+            false
+        }
+    }
+
+    /// withdrawされたネットワークのルートを取り除く。取り除いた場合はtrueを返す。
+    fn withdraw(
+        &mut self,
+        network: IpNetwork,
+        address_family: AddressFamily,
+        config: &Config,
+    ) -> bool {
+        let entries = self.rib.entries_of(network, address_family);
+        for entry in entries.iter() {
+            self.rib.remove(entry);
+        }
+        let suppressed = self.suppressed.entries_of(network, address_family);
+        for entry in suppressed.iter() {
+            self.suppressed.remove(entry);
+        }
+        if let Some(damping) = config
+            .damping
+            .filter(|_| !entries.is_empty() || !suppressed.is_empty())
+        {
+            self.add_penalty(
+                network,
+                address_family,
+                Self::WITHDRAWAL_PENALTY,
+                &damping,
+            );
+        }
+        !entries.is_empty()
+    }
+}
+
+/// default-originateで広報する、自身がoriginateする0.0.0.0/0のルート。
+/// Next HopはLocRibの自身のルートと同じく、AdjRibOut -> Peerに送る時に書き換える。
+fn default_route(config: &Config) -> RibEntry {
+    let next_hop = match config.local_ip {
+        IpAddr::V4(addr) => addr,
+        IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+    };
+    RibEntry {
+        network_address: IpNetwork::V4(
+            Ipv4Network::new(Ipv4Addr::UNSPECIFIED, 0).unwrap(),
+        ),
+        labels: vec![],
+        path_attributes: Arc::new(vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::from_sequence(vec![])),
+            PathAttribute::NextHop(next_hop),
+            PathAttribute::LocalPref(config.local_pref),
+        ]),
+    }
+}
+
+/// iBGPかconfederation内のPeerか。LOCAL_PREFはこれらのPeerとだけ交換する。
+fn is_same_as_peer(config: &Config) -> bool {
+    config.is_ibgp()
+        || config.confederation_session() == Some(ConfederationSession::Member)
+}
+
+/// eBGPのPeerから自身のallowed-originationに含まれるネットワークを受信した場合は、
+/// 経路のハイジャックの可能性があるので、own-prefix-checkに従い警告するか拒否する。
+fn is_acceptable_from_peer(network: &IpNetwork, config: &Config) -> bool {
+    let is_own_prefix = config
+        .allowed_originations
+        .iter()
+        .any(|a| network.is_subnet_of(a));
+    if !is_own_prefix
+        || config.is_ibgp()
+        || config.confederation_session() == Some(ConfederationSession::Member)
+    {
+        return true;
+    }
+    match config.own_prefix_check {
+        None => true,
+        Some(OwnPrefixCheck::Warn) => {
+            warn!(
+                "{} is received from {}, but it is our own prefix.",
+                network, config.remote_ip
+            );
+            true
+        }
+        Some(OwnPrefixCheck::Reject) => {
+            warn!(
+                "{} is received from {}, but it is rejected as our own prefix.",
+                network, config.remote_ip
+            );
+            false
+        }
+    }
+}
+
+/// iBGPのPeerから受信したルートを、iBGPのPeerに広報してよいかを返す。
+/// clientから受信したルートは全てのiBGPのPeerに、それ以外のiBGPのPeerから
+/// 受信したルートはclientにだけ反射する。反射する場合はORIGINATOR_IDと
+/// CLUSTER_LISTを付ける。
+/// ToDo: VPNv4, FlowSpecなどunicast以外のルートは反射の対象にしていない。
+fn reflect(
+    entry: &Arc<RibEntry>,
+    loc_rib: &LocRib,
+    config: &Config,
+) -> Option<Arc<RibEntry>> {
+    if !config.is_ibgp() {
+        return Some(Arc::clone(entry));
+    }
+    let (peer, source) = match loc_rib.internal_source(entry) {
+        Some(source) => source,
+        None => return Some(Arc::clone(entry)),
+    };
+    if peer == config.remote_ip
+        || !(source.reflector_client || config.route_reflector_client)
+    {
+        return None;
+    }
+    let cluster_id = config.bgp_identifier();
+    let mut path_attributes = entry.path_attributes.to_vec();
+    if !path_attributes
+        .iter()
+        .any(|p| matches!(p, PathAttribute::OriginatorId(_)))
+    {
+        path_attributes
+            .push(PathAttribute::OriginatorId(source.bgp_identifier));
+    }
+    match path_attributes.iter_mut().find_map(|p| match p {
+        PathAttribute::ClusterList(c) => Some(c),
+        _ => None,
+    }) {
+        Some(cluster_list) => cluster_list.insert(0, cluster_id),
+        None => {
+            path_attributes.push(PathAttribute::ClusterList(vec![cluster_id]))
+        }
+    }
+    Some(Arc::new(RibEntry {
+        path_attributes: Arc::new(path_attributes),
+        ..(**entry).clone()
+    }))
+}
+
+/// route reflectorが反射したルートが自身に戻ってきたかを返す。(RFC4456 Section 8)
+fn is_reflection_loop(
+    path_attributes: &[PathAttribute],
+    config: &Config,
+) -> bool {
+    let bgp_identifier = config.bgp_identifier();
+    path_attributes.iter().any(|p| match p {
+        PathAttribute::OriginatorId(o) => *o == bgp_identifier,
+        PathAttribute::ClusterList(c) => c.contains(&bgp_identifier),
+        _ => false,
+    })
+}
+
+/// PathAttributesのAS Pathに指定されたAS番号が含まれているかを返す。
+fn does_contain_as(
+    path_attributes: &[PathAttribute],
+    as_number: AutonomousSystemNumber,
+) -> bool {
+    for path_attribute in path_attributes.iter() {
+        if let PathAttribute::AsPath(as_path) = path_attribute {
+            return as_path.does_contain(as_number);
+        }
+    }
+    false
+}
+
+/// 自身がoriginateするルートのPathAttributesを、configの指定で上書きする。
+/// 指定が無ければ他のルートと共有できるように、同じArcを返す。
+fn override_originated_attributes(
+    path_attributes: &Arc<Vec<PathAttribute>>,
+    attributes: Option<&OriginatedAttributes>,
+) -> Arc<Vec<PathAttribute>> {
+    let attributes = match attributes {
+        Some(attributes) => attributes,
+        None => return Arc::clone(path_attributes),
+    };
+    let mut path_attributes: Vec<PathAttribute> = path_attributes
+        .iter()
+        .map(|p| match (p, attributes.origin) {
+            (PathAttribute::Origin(_), Some(origin)) => {
+                PathAttribute::Origin(origin)
+            }
+            _ => p.clone(),
+        })
+        .collect();
+    if let Some(med) = attributes.med {
+        path_attributes.push(PathAttribute::MultiExitDisc(med));
+    }
+    if !attributes.communities.is_empty() {
+        path_attributes
+            .push(PathAttribute::Communities(attributes.communities.clone()));
+    }
+    if !attributes.extended_communities.is_empty() {
+        path_attributes.push(PathAttribute::ExtendedCommunities(
+            attributes.extended_communities.clone(),
+        ));
+    }
+    Arc::new(path_attributes)
+}
+
+/// COMMUNITIESにcommunityを追加する。COMMUNITIESが無ければ作る。
+pub fn add_community(
+    path_attributes: &mut Vec<PathAttribute>,
+    community: Community,
+) {
+    match path_attributes.iter_mut().find_map(|p| match p {
+        PathAttribute::Communities(c) => Some(c),
+        _ => None,
+    }) {
+        Some(communities) => communities.push(community),
+        None => {
+            path_attributes.push(PathAttribute::Communities(vec![community]))
+        }
+    }
+}
+
+/// AS_PATHの先頭のAS_SEQUENCEにas_numberをcount回加える。
+pub fn prepend_as_path(
+    path_attributes: &mut [PathAttribute],
+    as_number: AutonomousSystemNumber,
+    count: u8,
+) {
+    for p in path_attributes.iter_mut() {
+        if let PathAttribute::AsPath(as_path) = p {
+            for _ in 0..count {
+                as_path.prepend(as_number);
+            }
+        }
+    }
+}
+
+/// PathAttributesのCOMMUNITIESを返す。無ければ空。
+pub fn communities(path_attributes: &[PathAttribute]) -> &[Community] {
+    path_attributes
+        .iter()
+        .find_map(|p| match p {
+            PathAttribute::Communities(c) => Some(&c[..]),
+            _ => None,
+        })
+        .unwrap_or(&[])
+}
+
+impl SuppressedRoutes {
+    /// Well-known Communityに従い、ルートをPeerに広報してよいかを返す。
+    /// 広報しない場合はその理由ごとに数える。
+    /// LLGR_STALEのルートはLLGR Capabilityを広報したPeerにだけ広報する。
+    fn is_advertisable(
+        &mut self,
+        path_attributes: &[PathAttribute],
+        config: &Config,
+        llgr_supported: bool,
+    ) -> bool {
+        let communities = communities(path_attributes);
+        if communities.contains(&Community::NO_ADVERTISE) {
+            self.no_advertise += 1;
+            return false;
+        }
+        // NO_EXPORTはconfederation内のPeerには広報するが、
+        // NO_EXPORT_SUBCONFEDは同じ(member)ASのPeerにしか広報しない。
+        let is_ibgp = config.is_ibgp();
+        let is_confederation_member = config.confederation_session()
+            == Some(ConfederationSession::Member);
+        if (!is_ibgp
+            && !is_confederation_member
+            && communities.contains(&Community::NO_EXPORT))
+            || (!is_ibgp
+                && communities.contains(&Community::NO_EXPORT_SUBCONFED))
+        {
+            self.no_export += 1;
+            return false;
+        }
+        if !llgr_supported && communities.contains(&Community::LLGR_STALE) {
+            self.llgr_stale += 1;
+            return false;
+        }
+        true
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+pub struct RibEntry {
+    pub network_address: IpNetwork,
+    // Labeled unicast (RFC8277)のルートのラベルスタック。unicastのルートでは空。
+    pub labels: Vec<MplsLabel>,
+    pub path_attributes: Arc<Vec<PathAttribute>>,
+}
+
+impl RibEntry {
+    fn does_contain_as(&self, as_number: AutonomousSystemNumber) -> bool {
+        does_contain_as(&self.path_attributes, as_number)
+    }
+
+    pub fn address_family(&self) -> AddressFamily {
+        let safi = if self.labels.is_empty() {
+            Safi::Unicast
+        } else {
+            Safi::LabeledUnicast
+        };
+        AddressFamily::new(self.network_address.afi(), safi)
+    }
+
+    pub fn as_path(&self) -> Option<&AsPath> {
+        self.path_attributes.iter().find_map(|p| match p {
+            PathAttribute::AsPath(a) => Some(a),
+            _ => None,
+        })
+    }
+
+    pub fn origin(&self) -> Option<Origin> {
+        self.path_attributes.iter().find_map(|p| match p {
+            PathAttribute::Origin(o) => Some(*o),
+            _ => None,
+        })
+    }
+
+    pub fn local_pref(&self) -> Option<u32> {
+        self.path_attributes.iter().find_map(|p| match p {
+            PathAttribute::LocalPref(local_pref) => Some(*local_pref),
+            _ => None,
+        })
+    }
+
+    pub fn med(&self) -> Option<u32> {
+        self.path_attributes.iter().find_map(|p| match p {
+            PathAttribute::MultiExitDisc(med) => Some(*med),
+            _ => None,
+        })
+    }
+
+    /// AS Pathの先頭にas_numberをcount回加えたルートを返す。
+    fn with_prepended_as(
+        &self,
+        as_number: AutonomousSystemNumber,
+        count: u8,
+    ) -> Self {
+        let mut path_attributes = (*self.path_attributes).clone();
+        prepend_as_path(&mut path_attributes, as_number, count);
+        Self {
+            path_attributes: Arc::new(path_attributes),
+            ..self.clone()
+        }
+    }
+
+    /// COMMUNITIESにcommunityを加えたルートを返す。
+    fn with_community(&self, community: Community) -> Self {
+        let mut path_attributes = (*self.path_attributes).clone();
+        add_community(&mut path_attributes, community);
+        Self {
+            path_attributes: Arc::new(path_attributes),
+            ..self.clone()
+        }
+    }
+
+    /// IPv4のルートはNEXT_HOP, それ以外はMP_REACH_NLRIからnext hopを返す。
+    pub fn next_hop(&self) -> Option<IpAddr> {
+        self.path_attributes.iter().find_map(|p| match p {
+            PathAttribute::NextHop(n) => Some(IpAddr::V4(*n)),
+            PathAttribute::MpReachNlri(m) => Some(m.next_hop),
+            _ => None,
+        })
+    }
+
+    /// BLACKHOLE Community(RFC7999)の付いた、宛先への通信を破棄するルートか。
+    pub fn is_blackhole(&self) -> bool {
+        communities(&self.path_attributes).contains(&Community::BLACKHOLE)
+    }
+}
+
+/// routesのうち最も優先されるルートを返す。
+/// MEDは同じ隣のASのルートの間でしか比べず、比較が推移的にならないので、
+/// sortせずに順に比べて選ぶ。
+/// bgp_identifierは、ルートを受信したPeerのBGP Identifierを返す。
+fn select_best_path<'a>(
+    routes: impl Iterator<Item = &'a Arc<RibEntry>>,
+    bgp_identifier: impl Fn(&RibEntry) -> Option<Ipv4Addr>,
+) -> Option<&'a Arc<RibEntry>> {
+    routes.reduce(|best, e| {
+        if compare_paths(e, best, &bgp_identifier).0.is_lt() {
+            e
+        } else {
+            best
+        }
+    })
+}
+
+/// 2つのルートを比較し、どちらが優先されるかと、その決め手を返す。
+/// aが優先される場合はOrdering::Lessを返す。
+fn compare_paths(
+    a: &RibEntry,
+    b: &RibEntry,
+    bgp_identifier: impl Fn(&RibEntry) -> Option<Ipv4Addr>,
+) -> (Ordering, BestPathReason) {
+    let as_path_length =
+        |e: &RibEntry| e.as_path().map_or(0, |a| a.path_length());
+    let is_stale = |e: &RibEntry| {
+        communities(&e.path_attributes).contains(&Community::LLGR_STALE)
+    };
+    let local_pref =
+        |e: &RibEntry| e.local_pref().unwrap_or(DEFAULT_LOCAL_PREF);
+    let neighbor_as = |e: &RibEntry| e.as_path().and_then(|a| a.neighbor_as());
+    let med = |e: &RibEntry| e.med().unwrap_or(0);
+    let med_ordering = if neighbor_as(a) == neighbor_as(b) {
+        med(a).cmp(&med(b))
+    } else {
+        Ordering::Equal
+    };
+    [
+        (is_stale(a).cmp(&is_stale(b)), BestPathReason::NotLlgrStale),
+        (
+            local_pref(b).cmp(&local_pref(a)),
+            BestPathReason::HigherLocalPref,
+        ),
+        (
+            as_path_length(a).cmp(&as_path_length(b)),
+            BestPathReason::ShorterAsPath,
+        ),
+        (a.origin().cmp(&b.origin()), BestPathReason::LowerOrigin),
+        (med_ordering, BestPathReason::LowerMed),
+        // 自身がoriginateしたルート(None)はどのPeerのルートよりも小さい。
+        (
+            bgp_identifier(a).cmp(&bgp_identifier(b)),
+            BestPathReason::LowerRouterId,
+        ),
+        (
+            a.next_hop().cmp(&b.next_hop()),
+            BestPathReason::LowerNextHop,
+        ),
+    ]
+    .into_iter()
+    .find(|(ordering, _)| ordering.is_ne())
+    .unwrap_or((Ordering::Equal, BestPathReason::LowerNextHop))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::RouteProtocol;
+    use crate::packets::notification::EXTENDED_MAX_MESSAGE_LENGTH;
+    use crate::reconcile;
+    use tokio::time::{sleep, Duration};
+
+    /// カーネルのルーティングテーブルの代わりに、書き込んだルートを記録するRouteWriterです。
+    /// next hopはreachableに含まれるものだけを解決できる。
+    #[derive(Debug, Clone, Default)]
+    struct MockRouteWriter {
+        reachable: HashSet<IpAddr>,
+        // ネットワーク毎の、書き込んだルートのnext hop。
+        installed: Arc<std::sync::Mutex<BTreeMap<IpNetwork, Vec<IpAddr>>>>,
+        // 削除したネットワークを、削除した順に記録する。
+        deleted: Arc<std::sync::Mutex<Vec<IpNetwork>>>,
+    }
+
+    impl MockRouteWriter {
+        fn installed(&self) -> BTreeMap<IpNetwork, Vec<IpAddr>> {
+            self.installed.lock().unwrap().clone()
+        }
+
+        fn deleted(&self) -> Vec<IpNetwork> {
+            self.deleted.lock().unwrap().clone()
+        }
+    }
+
+    impl kernel::RouteWriter for MockRouteWriter {
+        fn add_routes(
+            &self,
+            routes: Vec<Arc<RibEntry>>,
+            _mpls_encap: bool,
+            _target: FibTarget,
+        ) -> futures::future::BoxFuture<'_, Result<()>> {
+            let mut installed = self.installed.lock().unwrap();
+            for paths in
+                routes.chunk_by(|a, b| a.network_address == b.network_address)
+            {
+                installed.insert(
+                    paths[0].network_address,
+                    paths.iter().filter_map(|p| p.next_hop()).collect(),
+                );
+            }
+            Box::pin(async { Ok(()) })
+        }
+
+        fn delete_routes(
+            &self,
+            routes: Vec<Arc<RibEntry>>,
+            _target: FibTarget,
+        ) -> futures::future::BoxFuture<'_, Result<()>> {
+            let mut installed = self.installed.lock().unwrap();
+            let mut deleted = self.deleted.lock().unwrap();
+            for e in routes {
+                installed.remove(&e.network_address);
+                deleted.push(e.network_address);
+            }
+            Box::pin(async { Ok(()) })
+        }
+
+        fn resolvable_next_hops(
+            &self,
+            next_hops: HashSet<IpAddr>,
+            _table: u32,
+        ) -> futures::future::BoxFuture<'_, Result<HashSet<IpAddr>>> {
+            let resolvable =
+                next_hops.intersection(&self.reachable).copied().collect();
+            Box::pin(async { Ok(resolvable) })
+        }
+
+        fn lookup_installed_routes(
+            &self,
+            _target: FibTarget,
+        ) -> futures::future::BoxFuture<'_, Result<kernel::InstalledRoutes>>
+        {
+            let installed = self.installed().into_iter().collect();
+            Box::pin(async { Ok(installed) })
+        }
+    }
+
+    #[test]
+    fn fib_differences_find_missing_and_orphaned_routes() {
+        let entry = |network: &str, next_hop: &str| {
+            Arc::new(RibEntry {
+                network_address: network.parse().unwrap(),
+                labels: vec![],
+                path_attributes: Arc::new(vec![PathAttribute::NextHop(
+                    next_hop.parse().unwrap(),
+                )]),
+            })
+        };
+        let installed = |routes: &[(&str, &[&str])]| {
+            routes
+                .iter()
+                .map(|(network, next_hops)| {
+                    (
+                        network.parse().unwrap(),
+                        next_hops.iter().map(|n| n.parse().unwrap()).collect(),
+                    )
+                })
+                .collect::<HashMap<IpNetwork, BTreeSet<IpAddr>>>()
+        };
+        let paths = vec![
+            entry("10.1.0.0/24", "10.0.0.3"),
+            entry("10.2.0.0/24", "10.0.0.3"),
+            entry("10.2.0.0/24", "10.0.0.4"),
+            entry("10.5.0.0/24", "10.0.0.3"),
+        ];
+        let (missing, orphaned) = fib_differences(
+            &paths,
+            &installed(&[
+                // ECMPのnext hopが1つ欠けている。
+                ("10.2.0.0/24", &["10.0.0.3"]),
+                ("10.4.0.0/24", &["10.0.0.3"]),
+                ("10.3.0.0/24", &["10.0.0.3"]),
+                // next hopが異なる。
+                ("10.5.0.0/24", &["10.0.0.9"]),
+            ]),
+            false,
+        );
+        assert_eq!(
+            missing,
+            vec![
+                entry("10.1.0.0/24", "10.0.0.3"),
+                entry("10.2.0.0/24", "10.0.0.3"),
+                entry("10.2.0.0/24", "10.0.0.4"),
+                entry("10.5.0.0/24", "10.0.0.3"),
+            ]
+        );
+        assert_eq!(
+            orphaned,
+            vec![
+                "10.3.0.0/24".parse().unwrap(),
+                "10.4.0.0/24".parse().unwrap()
+            ]
+        );
+
+        let installed = installed(&[
+            ("10.1.0.0/24", &["10.0.0.3"]),
+            ("10.2.0.0/24", &["10.0.0.4", "10.0.0.3"]),
+            ("10.5.0.0/24", &["10.0.0.3"]),
+        ]);
+        assert_eq!(
+            fib_differences(&paths, &installed, false),
+            (vec![], vec![])
+        );
+    }
+
+    #[test]
+    fn rib_tracks_changes_by_generation() {
+        let entry = |network: &str| {
+            Arc::new(RibEntry {
+                network_address: network.parse().unwrap(),
+                labels: vec![],
+                path_attributes: Arc::new(vec![]),
+            })
+        };
+        let mut rib = Rib::new();
+        rib.insert(entry("10.1.0.0/24"));
+        let generation = rib.generation();
+
+        // 既にあるエントリを追加してもgenerationは進まない。
+        rib.insert(entry("10.1.0.0/24"));
+        assert_eq!(rib.generation(), generation);
+
+        rib.insert(entry("10.2.0.0/24"));
+        assert!(rib.generation() > generation);
+
+        let generation = rib.generation();
+        assert!(rib.remove(&entry("10.1.0.0/24")));
+        assert!(rib.generation() > generation);
+        let generation = rib.generation();
+        assert!(!rib.remove(&entry("10.1.0.0/24")));
+        assert_eq!(rib.generation(), generation);
+    }
+
+    #[tokio::test]
+    async fn loclib_can_lookup_routing_table() {
+        // 本テストの値は環境によって異なる。
+        // 本実装では開発機, テスト実施機に10.200.100.0/24に属するIPが付与されていることを仮定している。
+        let network =
+            ipnetwork::Ipv4Network::new("10.200.100.0".parse().unwrap(), 24)
+                .unwrap()
+                .into();
+        let routes =
+            LocRib::lookup_kernel_routing_table(network, kernel::MAIN_TABLE)
+                .await
+                .unwrap();
+        let expected = vec![network];
+        assert_eq!(routes, expected);
+    }
+
+    #[tokio::test]
+    async fn loc_rib_to_adj_rib_out() {
         // 本テストの値は環境によって異なる。
         // 本実装では開発機, テスト実施機に10.200.100.0/24に属するIPが付与されていることを仮定している。
         // docker-composeした環境のhost2で実行することを仮定している。
         let config: Config =
-            "64513 10.200.100.3 64512 10.200.100.2 passive 10.100.220.0/24"
-                .parse()
-                .unwrap();
+            "64513 10.200.100.3 64512 10.200.100.2 passive 10.100.220.0/24"
+                .parse()
+                .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &config,
+            &config.address_families,
+            &Rib::new(),
+        );
+
+        println!("adj_rib_out is created!");
+        println!("expected_adj_rib_out is creating!");
+        let mut rib = Rib::new();
+        rib.insert(Arc::new(RibEntry {
+            network_address: "10.100.220.0/24".parse().unwrap(),
+            labels: vec![],
+            path_attributes: Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![])),
+                PathAttribute::NextHop("10.200.100.3".parse().unwrap()),
+                PathAttribute::LocalPref(100),
+            ]),
+        }));
+        let expected_adj_rib_out = AdjRibOut {
+            rib,
+            vpnv4: Rib::new(),
+            flowspec: Rib::new(),
+            rtc: Rib::new(),
+            suppressed: SuppressedRoutes::default(),
+            llgr_supported: false,
+            maintenance: None,
+            confederation: None,
+            internal: false,
+            next_hop_self: true,
+            withdrawn: Rib::new(),
+            withdrawn_vpnv4: Rib::new(),
+            withdrawn_flowspec: Rib::new(),
+            withdrawn_rtc: Rib::new(),
+            max_message_length: MAX_MESSAGE_LENGTH,
+        };
+
+        assert_eq!(adj_rib_out, expected_adj_rib_out);
+    }
+
+    #[tokio::test]
+    async fn vpnv4_route_is_imported_into_vrf_by_route_target() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              address-family=vpnv4 vrf-rd=blue:64512:1 \
+                              vrf-import=blue:64512:100"
+            .parse()
+            .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let mut adj_rib_in = AdjRibIn::new();
+
+        let vpnv4_prefix = |route_target: &str| {
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(vec![
+                        64513.into()
+                    ])),
+                    PathAttribute::ExtendedCommunities(vec![
+                        ExtendedCommunity::RouteTarget(
+                            route_target.parse().unwrap(),
+                        ),
+                    ]),
+                    PathAttribute::MpReachNlri(MpReachNlri::new(
+                        AddressFamily::IPV4_MPLS_VPN,
+                        "10.0.0.3".parse().unwrap(),
+                        MpNlri::Vpnv4(vec![Vpnv4Prefix {
+                            labels: vec![],
+                            route_distinguisher: "64513:1".parse().unwrap(),
+                            prefix: if route_target == "64512:100" {
+                                "10.1.0.0/16".parse().unwrap()
+                            } else {
+                                "10.2.0.0/16".parse().unwrap()
+                            },
+                        }]),
+                    )),
+                ]),
+                vec![],
+                vec![],
+            )
+        };
+        adj_rib_in.install_from_update(vpnv4_prefix("64512:100"), &config);
+        adj_rib_in.install_from_update(vpnv4_prefix("64512:200"), &config);
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+
+        assert_eq!(loc_rib.vpnv4.routes().count(), 2);
+        let vrf_routes: Vec<_> = loc_rib.vrfs[0].rib.routes().collect();
+        assert_eq!(vrf_routes.len(), 1);
+        assert_eq!(
+            vrf_routes[0].network_address,
+            "10.1.0.0/16".parse().unwrap()
+        );
+        assert_eq!(
+            vrf_routes[0].next_hop(),
+            Some("10.0.0.3".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn vpnv4_route_is_advertised_by_route_target_membership() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              address-family=vpnv4,rtc \
+                              vrf-rd=blue:64512:1 vrf-import=blue:64512:100 \
+                              vrf-export=blue:64512:100 \
+                              vrf-network=blue:10.1.0.0/24 \
+                              vrf-rd=red:64512:2 vrf-export=red:64512:200 \
+                              vrf-network=red:10.2.0.0/24"
+            .parse()
+            .unwrap();
+        let loc_rib = LocRib::new(&config).await.unwrap();
+        assert_eq!(loc_rib.rtc.routes().count(), 1);
+
+        let mut route_target_memberships = Rib::new();
+        route_target_memberships.insert(Arc::new(RtcRibEntry {
+            membership: RouteTargetMembership::new(
+                64513,
+                "64512:100".parse().unwrap(),
+            ),
+            path_attributes: Arc::new(vec![]),
+        }));
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &config,
+            &config.address_families,
+            &route_target_memberships,
+        );
+
+        let routes: Vec<_> = adj_rib_out.vpnv4.routes().collect();
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].prefix.prefix, "10.1.0.0/24".parse().unwrap());
+        assert_eq!(adj_rib_out.rtc.routes().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn vpnv4_route_is_withdrawn_from_loc_rib_and_adj_rib_out() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              address-family=vpnv4 vrf-rd=blue:64512:1 \
+                              vrf-import=blue:64512:100"
+            .parse()
+            .unwrap();
+        let other_config: Config = "64512 10.0.0.2 64514 10.0.0.4 active \
+                                    address-family=vpnv4"
+            .parse()
+            .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let mut adj_rib_in = AdjRibIn::new();
+        let mut adj_rib_out = AdjRibOut::new();
+
+        let vpnv4_prefix = || Vpnv4Prefix {
+            labels: vec![],
+            route_distinguisher: "64513:1".parse().unwrap(),
+            prefix: "10.1.0.0/16".parse().unwrap(),
+        };
+        let reach = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![
+                    64513.into()
+                ])),
+                PathAttribute::ExtendedCommunities(vec![
+                    ExtendedCommunity::RouteTarget(
+                        "64512:100".parse().unwrap(),
+                    ),
+                ]),
+                PathAttribute::MpReachNlri(MpReachNlri::new(
+                    AddressFamily::IPV4_MPLS_VPN,
+                    "10.0.0.3".parse().unwrap(),
+                    MpNlri::Vpnv4(vec![vpnv4_prefix()]),
+                )),
+            ]),
+            vec![],
+            vec![],
+        );
+        adj_rib_in.install_from_update(reach.clone(), &config);
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &other_config,
+            &other_config.address_families,
+            &Rib::new(),
+        );
+        assert_eq!(loc_rib.vpnv4.routes().count(), 1);
+        assert_eq!(loc_rib.vrfs[0].rib.routes().count(), 1);
+        assert_eq!(adj_rib_out.vpnv4.routes().count(), 1);
+
+        let unreach = UpdateMessage::new(
+            Arc::new(vec![PathAttribute::MpUnreachNlri(MpUnreachNlri::new(
+                AddressFamily::IPV4_MPLS_VPN,
+                MpNlri::Vpnv4(vec![vpnv4_prefix()]),
+            ))]),
+            vec![],
+            vec![],
+        );
+        adj_rib_in.install_from_update(unreach, &config);
+        assert_eq!(adj_rib_in.vpnv4.routes().count(), 0);
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        assert_eq!(loc_rib.vpnv4.routes().count(), 0);
+        assert_eq!(loc_rib.vrfs[0].rib.routes().count(), 0);
+
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &other_config,
+            &other_config.address_families,
+            &Rib::new(),
+        );
+        assert_eq!(adj_rib_out.vpnv4.routes().count(), 0);
+        assert_eq!(adj_rib_out.withdrawn_vpnv4.routes().count(), 1);
+        let updates = adj_rib_out
+            .create_update_messages(other_config.local_ip, 64512.into());
+        assert!(updates.iter().any(|update| {
+            update.path_attributes.iter().any(|attribute| {
+                matches!(
+                    attribute,
+                    PathAttribute::MpUnreachNlri(m)
+                        if m.address_family == AddressFamily::IPV4_MPLS_VPN
+                )
+            })
+        }));
+
+        // セッションが切れた場合も、peerから受信したVPNv4のルートを取り除く。
+        adj_rib_in.install_from_update(reach, &config);
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        assert_eq!(loc_rib.vpnv4.routes().count(), 1);
+        loc_rib
+            .remove_routes_learned_from(config.remote_ip)
+            .await
+            .unwrap();
+        assert_eq!(loc_rib.vpnv4.routes().count(), 0);
+        assert_eq!(loc_rib.vrfs[0].rib.routes().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn flowspec_rule_is_withdrawn_from_loc_rib_and_adj_rib_out() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              address-family=flowspec"
+            .parse()
+            .unwrap();
+        let other_config: Config = "64512 10.0.0.2 64514 10.0.0.4 active \
+                                    address-family=flowspec"
+            .parse()
+            .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let mut adj_rib_in = AdjRibIn::new();
+        let mut adj_rib_out = AdjRibOut::new();
+
+        let rule: FlowSpecRule = "dst:203.0.113.0/24,proto:6".parse().unwrap();
+        let reach = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![
+                    64513.into()
+                ])),
+                PathAttribute::MpReachNlri(MpReachNlri::new(
+                    AddressFamily::IPV4_FLOWSPEC,
+                    IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    MpNlri::FlowSpec(vec![rule.clone()]),
+                )),
+            ]),
+            vec![],
+            vec![],
+        );
+        adj_rib_in.install_from_update(reach.clone(), &config);
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &other_config,
+            &other_config.address_families,
+            &Rib::new(),
+        );
+        assert_eq!(loc_rib.flowspec.routes().count(), 1);
+        assert_eq!(adj_rib_out.flowspec.routes().count(), 1);
+
+        let unreach = UpdateMessage::new(
+            Arc::new(vec![PathAttribute::MpUnreachNlri(MpUnreachNlri::new(
+                AddressFamily::IPV4_FLOWSPEC,
+                MpNlri::FlowSpec(vec![rule]),
+            ))]),
+            vec![],
+            vec![],
+        );
+        adj_rib_in.install_from_update(unreach, &config);
+        assert_eq!(adj_rib_in.flowspec.routes().count(), 0);
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        assert_eq!(loc_rib.flowspec.routes().count(), 0);
+
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &other_config,
+            &other_config.address_families,
+            &Rib::new(),
+        );
+        assert_eq!(adj_rib_out.flowspec.routes().count(), 0);
+        assert_eq!(adj_rib_out.withdrawn_flowspec.routes().count(), 1);
+        let updates = adj_rib_out
+            .create_update_messages(other_config.local_ip, 64512.into());
+        assert!(updates.iter().any(|update| {
+            update.path_attributes.iter().any(|attribute| {
+                matches!(
+                    attribute,
+                    PathAttribute::MpUnreachNlri(m)
+                        if m.address_family == AddressFamily::IPV4_FLOWSPEC
+                )
+            })
+        }));
+
+        // セッションが切れた場合も、peerから受信したルールを取り除く。
+        adj_rib_in.install_from_update(reach, &config);
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        assert_eq!(loc_rib.flowspec.routes().count(), 1);
+        loc_rib
+            .remove_routes_learned_from(config.remote_ip)
+            .await
+            .unwrap();
+        assert_eq!(loc_rib.flowspec.routes().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn vpnv4_route_is_withdrawn_when_route_target_membership_is_withdrawn(
+    ) {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              address-family=vpnv4,rtc \
+                              vrf-rd=blue:64512:1 vrf-import=blue:64512:100 \
+                              vrf-export=blue:64512:100 \
+                              vrf-network=blue:10.1.0.0/24 \
+                              vrf-rd=red:64512:2 vrf-export=red:64512:200 \
+                              vrf-network=red:10.2.0.0/24"
+            .parse()
+            .unwrap();
+        let loc_rib = LocRib::new(&config).await.unwrap();
+        let mut adj_rib_in = AdjRibIn::new();
+        let mut adj_rib_out = AdjRibOut::new();
+        let membership = |route_target: &str| {
+            RouteTargetMembership::new(64513, route_target.parse().unwrap())
+        };
+        let rtc_update = |memberships: Vec<RouteTargetMembership>| {
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(vec![
+                        64513.into()
+                    ])),
+                    PathAttribute::MpReachNlri(MpReachNlri::new(
+                        AddressFamily::IPV4_RTC,
+                        "10.0.0.3".parse().unwrap(),
+                        MpNlri::RouteTargetConstraint(memberships),
+                    )),
+                ]),
+                vec![],
+                vec![],
+            )
+        };
+        let advertised_prefixes = |adj_rib_out: &AdjRibOut| {
+            let mut prefixes: Vec<Ipv4Network> = adj_rib_out
+                .vpnv4
+                .routes()
+                .map(|e| e.prefix.prefix)
+                .collect();
+            prefixes.sort();
+            prefixes
+        };
+
+        adj_rib_in.install_from_update(
+            rtc_update(vec![membership("64512:100"), membership("64512:200")]),
+            &config,
+        );
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &config,
+            &config.address_families,
+            &adj_rib_in.rtc,
+        );
+        assert_eq!(
+            advertised_prefixes(&adj_rib_out),
+            vec![
+                "10.1.0.0/24".parse().unwrap(),
+                "10.2.0.0/24".parse().unwrap()
+            ]
+        );
+
+        // 64512:200のmembershipがwithdrawされると、redのルートはwithdrawする。
+        let generation = adj_rib_in.rtc.generation();
+        adj_rib_in.install_from_update(
+            UpdateMessage::new(
+                Arc::new(vec![PathAttribute::MpUnreachNlri(
+                    MpUnreachNlri::new(
+                        AddressFamily::IPV4_RTC,
+                        MpNlri::RouteTargetConstraint(vec![membership(
+                            "64512:200",
+                        )]),
+                    ),
+                )]),
+                vec![],
+                vec![],
+            ),
+            &config,
+        );
+        assert_ne!(adj_rib_in.rtc.generation(), generation);
+        assert_eq!(adj_rib_in.rtc.routes().count(), 1);
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &config,
+            &config.address_families,
+            &adj_rib_in.rtc,
+        );
+        assert_eq!(
+            advertised_prefixes(&adj_rib_out),
+            vec!["10.1.0.0/24".parse().unwrap()]
+        );
+        let withdrawn: Vec<_> = adj_rib_out
+            .withdrawn_vpnv4
+            .routes()
+            .map(|e| e.prefix.prefix)
+            .collect();
+        assert_eq!(withdrawn, vec!["10.2.0.0/24".parse().unwrap()]);
+
+        // membershipが再び広報されると、redのルートも再び広報する。
+        adj_rib_in.install_from_update(
+            rtc_update(vec![membership("64512:200")]),
+            &config,
+        );
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &config,
+            &config.address_families,
+            &adj_rib_in.rtc,
+        );
+        assert_eq!(advertised_prefixes(&adj_rib_out).len(), 2);
+        assert!(adj_rib_out.withdrawn_vpnv4.is_empty());
+    }
+
+    #[tokio::test]
+    async fn evpn_route_is_withdrawn_from_loc_rib() {
+        use crate::evpn::{EthernetSegmentIdentifier, EvpnLabel, MacAddress};
+
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              address-family=evpn"
+            .parse()
+            .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let mut adj_rib_in = AdjRibIn::new();
+        let route = |label: u32| EvpnRoute::MacIpAdvertisement {
+            route_distinguisher: "10.0.0.3:100".parse().unwrap(),
+            esi: EthernetSegmentIdentifier([0; 10]),
+            ethernet_tag: 0,
+            mac_address: MacAddress([0x02, 0, 0, 0, 0, 1]),
+            ip_address: None,
+            labels: vec![EvpnLabel(label)],
+        };
+        let reach = |label: u32| {
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(vec![
+                        64513.into()
+                    ])),
+                    PathAttribute::MpReachNlri(MpReachNlri::new(
+                        AddressFamily::L2VPN_EVPN,
+                        "10.0.0.3".parse().unwrap(),
+                        MpNlri::Evpn(vec![route(label)]),
+                    )),
+                ]),
+                vec![],
+                vec![],
+            )
+        };
+
+        // ラベルだけが異なるルートは同じルートとして置き換える。
+        adj_rib_in.install_from_update(reach(10100), &config);
+        adj_rib_in.install_from_update(reach(10200), &config);
+        assert_eq!(adj_rib_in.evpn.routes().count(), 1);
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        assert_eq!(loc_rib.evpn.routes().count(), 1);
+
+        // withdrawのラベルは意味を持たない。
+        adj_rib_in.install_from_update(
+            UpdateMessage::new(
+                Arc::new(vec![PathAttribute::MpUnreachNlri(
+                    MpUnreachNlri::new(
+                        AddressFamily::L2VPN_EVPN,
+                        MpNlri::Evpn(vec![route(0)]),
+                    ),
+                )]),
+                vec![],
+                vec![],
+            ),
+            &config,
+        );
+        assert_eq!(adj_rib_in.evpn.routes().count(), 0);
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        assert_eq!(loc_rib.evpn.routes().count(), 0);
+
+        // セッションが切れた場合も、peerから受信したEVPNのルートを取り除く。
+        adj_rib_in.install_from_update(reach(10100), &config);
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        assert_eq!(loc_rib.evpn.routes().count(), 1);
+        loc_rib
+            .remove_routes_learned_from(config.remote_ip)
+            .await
+            .unwrap();
+        assert_eq!(loc_rib.evpn.routes().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn link_state_route_is_withdrawn_from_loc_rib() {
+        use crate::bgp_ls::{LinkStateAttribute, NodeDescriptors, Tlv};
+
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              address-family=link-state"
+            .parse()
+            .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let mut adj_rib_in = AdjRibIn::new();
+        let nlri = || LinkStateNlri::Node {
+            protocol_id: 3,
+            identifier: 0,
+            local_node: NodeDescriptors(vec![Tlv {
+                type_: 515,
+                value: vec![10, 0, 0, 1],
+            }]),
+        };
+        let reach = |name: &str| {
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(vec![
+                        64513.into()
+                    ])),
+                    PathAttribute::LinkState(LinkStateAttribute(vec![Tlv {
+                        type_: 1026,
+                        value: name.as_bytes().to_vec(),
+                    }])),
+                    PathAttribute::MpReachNlri(MpReachNlri::new(
+                        AddressFamily::LINK_STATE,
+                        "10.0.0.3".parse().unwrap(),
+                        MpNlri::LinkState(vec![nlri()]),
+                    )),
+                ]),
+                vec![],
+                vec![],
+            )
+        };
+
+        // 同じNLRIを異なる属性で受信した場合は置き換える。
+        adj_rib_in.install_from_update(reach("r1"), &config);
+        adj_rib_in.install_from_update(reach("r1-renamed"), &config);
+        assert_eq!(adj_rib_in.link_state.routes().count(), 1);
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        assert_eq!(loc_rib.link_state.routes().count(), 1);
+        assert_eq!(
+            loc_rib.topology().nodes["10.0.0.1"].name,
+            Some("r1-renamed".to_string())
+        );
+
+        adj_rib_in.install_from_update(
+            UpdateMessage::new(
+                Arc::new(vec![PathAttribute::MpUnreachNlri(
+                    MpUnreachNlri::new(
+                        AddressFamily::LINK_STATE,
+                        MpNlri::LinkState(vec![nlri()]),
+                    ),
+                )]),
+                vec![],
+                vec![],
+            ),
+            &config,
+        );
+        assert_eq!(adj_rib_in.link_state.routes().count(), 0);
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        assert_eq!(loc_rib.link_state.routes().count(), 0);
+        assert!(loc_rib.topology().nodes.is_empty());
+
+        // セッションが切れた場合も、peerから受信したBGP-LSのルートを取り除く。
+        adj_rib_in.install_from_update(reach("r1"), &config);
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        assert_eq!(loc_rib.link_state.routes().count(), 1);
+        loc_rib
+            .remove_routes_learned_from(config.remote_ip)
+            .await
+            .unwrap();
+        assert_eq!(loc_rib.link_state.routes().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn route_target_membership_is_withdrawn_from_adj_rib_out() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              address-family=vpnv4,rtc \
+                              vrf-rd=blue:64512:1 vrf-import=blue:64512:100"
+            .parse()
+            .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &config,
+            &config.address_families,
+            &Rib::new(),
+        );
+        assert_eq!(adj_rib_out.rtc.routes().count(), 1);
+
+        loc_rib.rtc = Rib::new();
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &config,
+            &config.address_families,
+            &Rib::new(),
+        );
+        assert!(adj_rib_out.rtc.is_empty());
+        assert_eq!(adj_rib_out.withdrawn_rtc.routes().count(), 1);
+        let updates = adj_rib_out
+            .create_update_messages(config.local_ip, config.local_as);
+        assert!(updates.iter().any(|update| {
+            update.path_attributes.iter().any(|attribute| {
+                matches!(
+                    attribute,
+                    PathAttribute::MpUnreachNlri(m)
+                        if m.address_family == AddressFamily::IPV4_RTC
+                )
+            })
+        }));
+        adj_rib_out.clear_withdrawn_routes();
+        assert!(!adj_rib_out.has_withdrawn_routes());
+    }
+
+    #[test]
+    fn update_messages_are_split_within_max_message_length() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let entry = |i: u32| {
+            Arc::new(RibEntry {
+                network_address: format!("10.{}.{}.0/24", i / 256, i % 256)
+                    .parse()
+                    .unwrap(),
+                labels: vec![],
+                path_attributes: Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(vec![])),
+                    PathAttribute::NextHop("10.0.0.3".parse().unwrap()),
+                ]),
+            })
+        };
+        let mut adj_rib_out = AdjRibOut::new();
+        for i in 0..5000 {
+            adj_rib_out.withdrawn.insert(entry(i));
+            adj_rib_out.insert(entry(i + 5000));
+        }
+
+        let updates = adj_rib_out
+            .create_update_messages(config.local_ip, config.local_as);
+        assert!(updates.len() > 2);
+        for update in &updates {
+            let bytes: BytesMut = update.clone().into();
+            assert!(bytes.len() <= MAX_MESSAGE_LENGTH);
+        }
+        let withdrawn: usize =
+            updates.iter().map(|u| u.withdrawn_routes.len()).sum();
+        let nlri: usize = updates
+            .iter()
+            .map(|u| u.network_layer_reachability_information.len())
+            .sum();
+        assert_eq!((withdrawn, nlri), (5000, 5000));
+
+        // Extended Messageをネゴシエーションした場合は大きいUpdateMessageで送る。
+        adj_rib_out.max_message_length = EXTENDED_MAX_MESSAGE_LENGTH;
+        let extended = adj_rib_out
+            .create_update_messages(config.local_ip, config.local_as);
+        assert!(extended.len() < updates.len());
+    }
+
+    #[tokio::test]
+    async fn readvertise_all_withdraws_routes_no_longer_advertised() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        for network in ["10.1.0.0/24", "10.2.0.0/24"] {
+            loc_rib
+                .announce(network.parse().unwrap(), None, vec![])
+                .unwrap();
+        }
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &config,
+            &config.address_families,
+            &Rib::new(),
+        );
+        assert_eq!(adj_rib_out.routes().count(), 2);
+
+        loc_rib.withdraw("10.2.0.0/24".parse().unwrap()).unwrap();
+        adj_rib_out.readvertise_all();
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &config,
+            &config.address_families,
+            &Rib::new(),
+        );
+        let updates = adj_rib_out
+            .create_update_messages(config.local_ip, config.local_as);
+        let withdrawn: Vec<Ipv4Network> = updates
+            .iter()
+            .flat_map(|u| u.withdrawn_routes.clone())
+            .collect();
+        let advertised: Vec<Ipv4Network> = updates
+            .iter()
+            .flat_map(|u| u.network_layer_reachability_information.clone())
+            .collect();
+        assert_eq!(withdrawn, vec!["10.2.0.0/24".parse().unwrap()]);
+        assert_eq!(advertised, vec!["10.1.0.0/24".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn update_messages_do_not_depend_on_insertion_order() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        // 同じネットワークでPathAttributeが異なるルートを混ぜる。
+        let routes: Vec<(&str, Community)> = (0..20u32)
+            .map(|i| {
+                let network = ["10.1.0.0/24", "10.2.0.0/24"][i as usize % 2];
+                (network, Community(i / 3))
+            })
+            .collect();
+        let mut dumps = vec![];
+        for reverse in [false, true] {
+            let mut loc_rib = LocRib::new(&config).await.unwrap();
+            let mut routes = routes.clone();
+            if reverse {
+                routes.reverse();
+            }
+            for (network, community) in routes {
+                loc_rib.insert(Arc::new(RibEntry {
+                    network_address: network.parse().unwrap(),
+                    labels: vec![],
+                    path_attributes: Arc::new(vec![
+                        PathAttribute::Origin(Origin::Igp),
+                        PathAttribute::AsPath(AsPath::from_sequence(vec![])),
+                        PathAttribute::NextHop("10.0.0.3".parse().unwrap()),
+                        PathAttribute::Communities(vec![community]),
+                    ]),
+                }));
+            }
+            let mut adj_rib_out = AdjRibOut::new();
+            adj_rib_out.install_from_loc_rib(
+                &loc_rib,
+                &config,
+                &config.address_families,
+                &Rib::new(),
+            );
+            dumps.push((
+                format!("{:?}", adj_rib_out.rib),
+                adj_rib_out
+                    .create_update_messages(config.local_ip, config.local_as),
+            ));
+        }
+        assert_eq!(dumps[0], dumps[1]);
+    }
+
+    #[tokio::test]
+    async fn routes_are_advertised_as_less_preferred_in_maintenance() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              maintenance-prepend=2 maintenance-med=1000"
+            .parse()
+            .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        loc_rib
+            .announce("10.1.0.0/24".parse().unwrap(), None, vec![])
+            .unwrap();
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.maintenance = Some(config.maintenance.clone());
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &config,
+            &config.address_families,
+            &Rib::new(),
+        );
+        let updates = adj_rib_out
+            .create_update_messages(config.local_ip, config.local_as);
+        let local_as = config.local_as;
+        assert_eq!(
+            *updates[0].path_attributes,
+            vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![
+                    local_as, local_as, local_as
+                ])),
+                PathAttribute::NextHop("10.0.0.2".parse().unwrap()),
+                PathAttribute::MultiExitDisc(1000),
+                PathAttribute::Communities(vec![Community::GRACEFUL_SHUTDOWN]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn routes_from_ibgp_peers_are_reflected() {
+        let config = |remote_ip: &str, client: &str| -> Config {
+            format!(
+                "64512 10.0.0.2 64512 {} active route-reflector-client={}",
+                remote_ip, client
+            )
+            .parse()
+            .unwrap()
+        };
+        let update = |network: &str, next_hop: &str| {
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(vec![
+                        64513.into()
+                    ])),
+                    PathAttribute::NextHop(next_hop.parse().unwrap()),
+                ]),
+                vec![network.parse().unwrap()],
+                vec![],
+            )
+        };
+        let client = config("10.0.0.3", "on");
+        let non_client = config("10.0.0.4", "off");
+        let mut loc_rib = LocRib::new(&client).await.unwrap();
+        for (config, network) in
+            [(&client, "10.1.0.0/24"), (&non_client, "10.2.0.0/24")]
+        {
+            let remote_ip = config.remote_ip.to_string();
+            let mut adj_rib_in = AdjRibIn::new();
+            adj_rib_in.internal_peer =
+                InternalPeer::new(config, remote_ip.parse().unwrap());
+            adj_rib_in
+                .install_from_update(update(network, &remote_ip), config);
+            loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        }
+
+        // 自身が反射したルートが戻ってきた場合は受け入れない。
+        let mut adj_rib_in = AdjRibIn::new();
+        let mut looped = update("10.3.0.0/24", "10.0.0.3");
+        Arc::make_mut(&mut looped.path_attributes).push(
+            PathAttribute::ClusterList(vec!["10.0.0.2".parse().unwrap()]),
+        );
+        adj_rib_in.install_from_update(looped, &client);
+        assert_eq!(adj_rib_in.routes().count(), 0);
+
+        let advertised = |config: &Config| {
+            let mut adj_rib_out = AdjRibOut::new();
+            adj_rib_out.internal = config.is_ibgp();
+            adj_rib_out.install_from_loc_rib(
+                &loc_rib,
+                config,
+                &config.address_families,
+                &Rib::new(),
+            );
+            adj_rib_out
+                .create_update_messages(config.local_ip, config.local_as)
+        };
+        let reflected = |network: &str, originator_id: &str| {
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(vec![
+                        64513.into()
+                    ])),
+                    PathAttribute::NextHop(originator_id.parse().unwrap()),
+                    PathAttribute::LocalPref(100),
+                    PathAttribute::OriginatorId(
+                        originator_id.parse().unwrap(),
+                    ),
+                    PathAttribute::ClusterList(vec!["10.0.0.2"
+                        .parse()
+                        .unwrap()]),
+                ]),
+                vec![network.parse().unwrap()],
+                vec![],
+            )
+        };
+        // clientから受信したルートは他のiBGPのPeerに、
+        // clientでないPeerから受信したルートはclientにだけ反射する。
+        assert_eq!(
+            advertised(&config("10.0.0.5", "off")),
+            vec![reflected("10.1.0.0/24", "10.0.0.3")]
+        );
+        assert_eq!(advertised(&config("10.0.0.5", "on")).len(), 2);
+        assert_eq!(
+            advertised(&client),
+            vec![reflected("10.2.0.0/24", "10.0.0.4")]
+        );
+        let update = reflected("10.2.0.0/24", "10.0.0.4");
+        let bytes: BytesMut = update.clone().into();
+        assert_eq!(UpdateMessage::try_from(bytes).unwrap(), update);
+        assert_eq!(
+            advertised(&non_client),
+            vec![reflected("10.1.0.0/24", "10.0.0.3")]
+        );
+
+        // eBGPのPeerにはORIGINATOR_IDとCLUSTER_LISTを付けない。
+        let ebgp: Config =
+            "64512 10.0.0.2 64514 10.0.0.6 active".parse().unwrap();
+        for update in advertised(&ebgp) {
+            assert!(!update.path_attributes.iter().any(|p| matches!(
+                p,
+                PathAttribute::OriginatorId(_) | PathAttribute::ClusterList(_)
+            )));
+        }
+    }
+
+    #[tokio::test]
+    async fn well_known_communities_suppress_advertisement() {
+        let ebgp: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let ibgp: Config =
+            "64512 10.0.0.2 64512 10.0.0.3 active".parse().unwrap();
+        let mut loc_rib = LocRib::new(&ebgp).await.unwrap();
+        let announce = |loc_rib: &mut LocRib, network: &str, c: Community| {
+            loc_rib.announce(network.parse().unwrap(), None, vec![c])
+        };
+        announce(&mut loc_rib, "10.1.0.0/24", Community::NO_ADVERTISE)
+            .unwrap();
+        announce(&mut loc_rib, "10.2.0.0/24", Community::NO_EXPORT).unwrap();
+        announce(&mut loc_rib, "10.3.0.0/24", Community(64512 << 16 | 100))
+            .unwrap();
+
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &ebgp,
+            &ebgp.address_families,
+            &Rib::new(),
+        );
+        assert_eq!(adj_rib_out.routes().count(), 1);
+        assert_eq!(
+            adj_rib_out.suppressed,
+            SuppressedRoutes {
+                no_advertise: 1,
+                no_export: 1,
+                llgr_stale: 0,
+            }
+        );
+
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &ibgp,
+            &ibgp.address_families,
+            &Rib::new(),
+        );
+        assert_eq!(adj_rib_out.routes().count(), 2);
+        assert_eq!(adj_rib_out.suppressed.no_advertise, 1);
+    }
+
+    #[tokio::test]
+    async fn blackhole_routes_are_installed_regardless_of_next_hop() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        assert!(loc_rib
+            .announce(
+                "203.0.113.0/24".parse().unwrap(),
+                None,
+                vec![Community::BLACKHOLE]
+            )
+            .is_err());
+        loc_rib
+            .announce(
+                "203.0.113.1/32".parse().unwrap(),
+                None,
+                vec![Community::BLACKHOLE],
+            )
+            .unwrap();
+        let originated = loc_rib.routes().next().unwrap().clone();
+        assert!(originated.is_blackhole());
+        assert!(communities(&originated.path_attributes)
+            .contains(&Community::NO_EXPORT));
+        // 自身がoriginateしたルートでも、BLACKHOLEのルートは書き込む。
+        assert_eq!(loc_rib.paths_to_install(), vec![originated]);
+
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.install_from_update(
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(vec![
+                        64513.into()
+                    ])),
+                    PathAttribute::NextHop("192.0.2.1".parse().unwrap()),
+                    PathAttribute::Communities(vec![Community::BLACKHOLE]),
+                ]),
+                vec!["198.51.100.1/32".parse().unwrap()],
+                vec![],
+            ),
+            &config,
+        );
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        // BLACKHOLEのルートのnext hopには転送しないので、到達性を確認しない。
+        assert!(loc_rib.next_hops().is_empty());
+        let changes = loc_rib.update_next_hop_reachability(
+            ["192.0.2.1".parse().unwrap()].into_iter().collect(),
+        );
+        assert_eq!(changes, NextHopChanges::default());
+        assert_eq!(loc_rib.paths_to_install().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn routes_are_advertised_only_while_conditions_are_satisfied() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              advertise-if-exist=10.1.0.0/24,198.51.100.0/24 \
+                              advertise-if-not-exist=10.2.0.0/24,198.51.100.0/24"
+            .parse()
+            .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        for network in ["10.1.0.0/24", "10.2.0.0/24", "10.3.0.0/24"] {
+            loc_rib
+                .announce(network.parse().unwrap(), None, vec![])
+                .unwrap();
+        }
+        let advertised = |loc_rib: &LocRib, adj_rib_out: &mut AdjRibOut| {
+            adj_rib_out.install_from_loc_rib(
+                loc_rib,
+                &config,
+                &config.address_families,
+                &Rib::new(),
+            );
+            adj_rib_out
+                .routes()
+                .map(|e| e.network_address.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let mut adj_rib_out = AdjRibOut::new();
+        assert_eq!(
+            advertised(&loc_rib, &mut adj_rib_out),
+            vec!["10.2.0.0/24", "10.3.0.0/24"]
+        );
+
+        loc_rib
+            .announce("198.51.100.0/24".parse().unwrap(), None, vec![])
+            .unwrap();
+        assert_eq!(
+            advertised(&loc_rib, &mut adj_rib_out),
+            vec!["10.1.0.0/24", "10.3.0.0/24", "198.51.100.0/24"]
+        );
+    }
+
+    #[tokio::test]
+    async fn routes_via_unreachable_next_hop_are_withdrawn_from_loc_rib() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let unreachable: IpAddr = "192.168.0.1".parse().unwrap();
+        loc_rib
+            .announce(
+                "10.1.0.0/24".parse().unwrap(),
+                Some(unreachable),
+                vec![],
+            )
+            .unwrap();
+        loc_rib
+            .announce(
+                "10.2.0.0/24".parse().unwrap(),
+                Some("192.168.0.2".parse().unwrap()),
+                vec![],
+            )
+            .unwrap();
+        assert_eq!(loc_rib.next_hops().len(), 2);
+        let generation = loc_rib.generation();
+
+        let changes =
+            loc_rib.update_next_hop_reachability(HashSet::from([unreachable]));
+        assert_eq!(changes.unreachable.len(), 1);
+        assert!(changes.reachable.is_empty());
+        assert_eq!(loc_rib.routes().count(), 1);
+        assert_eq!(
+            loc_rib.routes().next().unwrap().network_address,
+            "10.2.0.0/24".parse().unwrap()
+        );
+        assert!(loc_rib.generation() > generation);
+
+        let changes = loc_rib.update_next_hop_reachability(HashSet::new());
+        assert_eq!(changes.reachable.len(), 1);
+        assert_eq!(loc_rib.routes().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn next_hops_added_while_resolving_are_not_checked() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let unreachable: IpAddr = "192.168.0.1".parse().unwrap();
+        loc_rib
+            .announce(
+                "10.1.0.0/24".parse().unwrap(),
+                Some(unreachable),
+                vec![],
+            )
+            .unwrap();
+        let next_hops = loc_rib.next_hops();
+
+        // LocRibのロックを外して解決している間に、別のnext hopのルートが加わる。
+        let added: IpAddr = "192.168.0.2".parse().unwrap();
+        loc_rib
+            .announce("10.2.0.0/24".parse().unwrap(), Some(added), vec![])
+            .unwrap();
+        let changes =
+            loc_rib.update_resolved_next_hops(&next_hops, &HashSet::new());
+        assert_eq!(changes.unreachable.len(), 1);
+        assert_eq!(loc_rib.routes().count(), 1);
+        assert_eq!(loc_rib.checked_next_hops, HashSet::from([unreachable]));
+
+        let kernel_route_changes =
+            loc_rib.next_hop_kernel_route_changes(&changes);
+        let withdrawn: Vec<IpNetwork> = kernel_route_changes
+            .withdrawn
+            .iter()
+            .map(|e| e.network_address)
+            .collect();
+        assert_eq!(withdrawn, vec!["10.1.0.0/24".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn only_networks_in_kernel_routing_table_are_originated() {
+        // 本テストの値は環境によって異なる。
+        // 198.51.100.0/24(TEST-NET-2)がカーネルのルーティングテーブルに無いことを仮定している。
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              198.51.100.0/24 \
+                              always-advertise=203.0.113.0/24"
+            .parse()
+            .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let networks: Vec<IpNetwork> =
+            loc_rib.routes().map(|e| e.network_address).collect();
+        assert_eq!(networks, vec!["203.0.113.0/24".parse().unwrap()]);
+
+        let kernel_routes =
+            kernel::lookup_kernel_routes(loc_rib.kernel_table())
+                .await
+                .unwrap();
+        loc_rib.refresh_originated_networks(&kernel_routes);
+        assert_eq!(loc_rib.routes().count(), 1);
+        assert_eq!(loc_rib.generation(), 0);
+    }
+
+    #[tokio::test]
+    async fn originated_networks_are_checked_against_one_kernel_dump() {
+        // 198.51.100.0/24(TEST-NET-2)と10.0.0.0/8のstaticのルートが、
+        // カーネルのルーティングテーブルに無いことを仮定している。
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              198.51.100.0/24 \
+                              redistribute=static,10.0.0.0/8"
+            .parse()
+            .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        assert_eq!(loc_rib.routes().count(), 0);
+
+        let mut kernel_routes = KernelRoutes::default();
+        for (network, protocol) in [
+            ("198.51.100.0/24", RouteProtocol::BOOT),
+            ("10.1.0.0/24", RouteProtocol::STATIC),
+            ("10.2.0.0/24", RouteProtocol::BGP),
+            ("172.16.0.0/16", RouteProtocol::STATIC),
+        ] {
+            kernel_routes.insert(network.parse().unwrap(), protocol);
+        }
+        loc_rib.refresh_originated_networks(&kernel_routes);
+        let networks: Vec<IpNetwork> =
+            loc_rib.routes().map(|e| e.network_address).collect();
+        assert_eq!(
+            networks,
+            vec![
+                "10.1.0.0/24".parse().unwrap(),
+                "198.51.100.0/24".parse().unwrap()
+            ]
+        );
+
+        loc_rib.refresh_originated_networks(&KernelRoutes::default());
+        assert_eq!(loc_rib.routes().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn stats_count_routes_and_shared_attribute_sets() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let update = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![
+                    64513.into()
+                ])),
+                PathAttribute::NextHop("10.0.0.3".parse().unwrap()),
+            ]),
+            vec![
+                "10.1.0.0/24".parse().unwrap(),
+                "10.2.0.0/24".parse().unwrap(),
+            ],
+            vec![],
+        );
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.install_from_update(update, &config);
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        loc_rib
+            .announce("10.3.0.0/24".parse().unwrap(), None, vec![])
+            .unwrap();
+
+        let stats = loc_rib.stats();
+        assert_eq!(stats.unicast, 3);
+        assert_eq!(stats.routes(), 3);
+        // 同じUPDATEで受信したルートはPathAttributeを共有する。
+        assert_eq!(stats.attribute_sets, 2);
+        assert!(stats.estimated_memory > 0);
+    }
+
+    #[tokio::test]
+    async fn routes_learned_from_peer_are_removed() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let update = |networks: &[&str]| {
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(vec![
+                        64513.into()
+                    ])),
+                    PathAttribute::NextHop("10.0.0.3".parse().unwrap()),
+                ]),
+                networks.iter().map(|n| n.parse().unwrap()).collect(),
+                vec![],
+            )
+        };
+        let peer1: IpAddr = "10.0.0.3".parse().unwrap();
+        let peer2: IpAddr = "10.0.0.4".parse().unwrap();
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.install_from_update(
+            update(&["10.1.0.0/24", "10.2.0.0/24"]),
+            &config,
+        );
+        loc_rib.install_from_adj_rib_in(peer1, &adj_rib_in);
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.install_from_update(update(&["10.2.0.0/24"]), &config);
+        loc_rib.install_from_adj_rib_in(peer2, &adj_rib_in);
+        assert_eq!(loc_rib.routes().count(), 2);
+        // peer2からのルートは既にribにあるので、generationは1度だけ進む。
+        assert_eq!(loc_rib.generation(), 1);
+
+        // 10.2.0.0/24はpeer2からも受信しているので残る。
+        loc_rib.remove_routes_learned_from(peer1).await.unwrap();
+        let networks: Vec<IpNetwork> =
+            loc_rib.routes().map(|e| e.network_address).collect();
+        assert_eq!(networks, vec!["10.2.0.0/24".parse().unwrap()]);
+        assert_eq!(loc_rib.generation(), 2);
+
+        loc_rib.remove_routes_learned_from(peer2).await.unwrap();
+        assert_eq!(loc_rib.routes().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn import_and_export_policies_are_applied() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              import-policy=deny,prefix:10.2.0.0/16 \
+                              import-policy=permit,prefix:10.3.0.0/16,\
+                              set-community:no-export \
+                              import-policy=permit,set-local-pref:200"
+            .parse()
+            .unwrap();
+        let other: Config = "64512 10.0.0.2 64515 10.0.0.5 active \
+                             export-policy=permit,prefix:10.1.0.0/16,set-med:50"
+            .parse()
+            .unwrap();
         let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.import_policy = ImportPolicy::new(&config);
+        adj_rib_in.install_from_update(
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(vec![
+                        64513.into()
+                    ])),
+                    PathAttribute::NextHop("10.0.0.3".parse().unwrap()),
+                ]),
+                ["10.1.0.0/24", "10.2.0.0/24", "10.3.0.0/24"]
+                    .iter()
+                    .map(|n| n.parse().unwrap())
+                    .collect(),
+                vec![],
+            ),
+            &config,
+        );
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        let installed: Vec<(IpNetwork, Option<u32>)> = loc_rib
+            .routes()
+            .map(|e| (e.network_address, e.local_pref()))
+            .collect();
+        assert_eq!(
+            installed,
+            vec![
+                ("10.1.0.0/24".parse().unwrap(), Some(200)),
+                ("10.3.0.0/24".parse().unwrap(), Some(100)),
+            ]
+        );
+
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &other,
+            &[AddressFamily::IPV4_UNICAST],
+            &Rib::new(),
+        );
+        let advertised: Vec<(IpNetwork, Option<u32>)> = adj_rib_out
+            .routes()
+            .map(|e| (e.network_address, e.med()))
+            .collect();
+        assert_eq!(
+            advertised,
+            vec![("10.1.0.0/24".parse().unwrap(), Some(50))]
+        );
+
+        // import-policyで付けたNO_EXPORTは、他のASのPeerに広報しない。
+        let external: Config =
+            "64512 10.0.0.2 64516 10.0.0.6 active".parse().unwrap();
         let mut adj_rib_out = AdjRibOut::new();
-        adj_rib_out.install_from_loc_rib(&mut loc_rib, &config);
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &external,
+            &[AddressFamily::IPV4_UNICAST],
+            &Rib::new(),
+        );
+        assert_eq!(adj_rib_out.len(), 1);
+        assert_eq!(adj_rib_out.suppressed.no_export, 1);
+    }
 
-        println!("adj_rib_out is created!");
-        println!("expected_adj_rib_out is creating!");
+    #[tokio::test]
+    async fn default_route_is_originated_while_condition_is_satisfied() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let advertised_networks = |loc_rib: &LocRib, config: &str| {
+            let config: Config = config.parse().unwrap();
+            let mut adj_rib_out = AdjRibOut::new();
+            adj_rib_out.install_from_loc_rib(
+                loc_rib,
+                &config,
+                &[AddressFamily::IPV4_UNICAST],
+                &Rib::new(),
+            );
+            let networks: Vec<IpNetwork> =
+                adj_rib_out.routes().map(|e| e.network_address).collect();
+            networks
+        };
+        let default: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        let conditional = "64512 10.0.0.2 64515 10.0.0.5 active \
+                           default-originate=permit,prefix:10.1.0.0/16";
+        assert_eq!(
+            advertised_networks(
+                &loc_rib,
+                "64512 10.0.0.2 64515 10.0.0.5 active default-originate=on"
+            ),
+            vec![default]
+        );
+        assert_eq!(advertised_networks(&loc_rib, conditional), vec![]);
+
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.install_from_update(
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(vec![
+                        64513.into()
+                    ])),
+                    PathAttribute::NextHop("10.0.0.3".parse().unwrap()),
+                ]),
+                vec!["10.1.0.0/24".parse().unwrap()],
+                vec![],
+            ),
+            &config,
+        );
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        assert_eq!(
+            advertised_networks(&loc_rib, conditional),
+            vec![default, "10.1.0.0/24".parse().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn routes_are_not_advertised_back_to_source_peer() {
+        // route serverのように、自身のAS番号をAS Pathに加えないPeerを想定する。
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.install_from_update(
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(vec![
+                        64600.into()
+                    ])),
+                    PathAttribute::NextHop("10.0.0.3".parse().unwrap()),
+                ]),
+                vec!["10.1.0.0/24".parse().unwrap()],
+                vec![],
+            ),
+            &config,
+        );
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &config,
+            &[AddressFamily::IPV4_UNICAST],
+            &Rib::new(),
+        );
+        assert!(adj_rib_out.is_empty());
+
+        let other: Config =
+            "64512 10.0.0.2 64515 10.0.0.5 active".parse().unwrap();
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &other,
+            &[AddressFamily::IPV4_UNICAST],
+            &Rib::new(),
+        );
+        assert_eq!(adj_rib_out.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn only_best_path_is_advertised_and_withdrawn_from_its_source() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let other: Config =
+            "64512 10.0.0.2 64516 10.0.0.6 active".parse().unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let update = |ases: Vec<u16>, next_hop: &str| {
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(
+                        ases.into_iter().map(|a| a.into()).collect(),
+                    )),
+                    PathAttribute::NextHop(next_hop.parse().unwrap()),
+                ]),
+                vec!["10.1.0.0/24".parse().unwrap()],
+                vec![],
+            )
+        };
+        let as_paths = |adj_rib_out: &AdjRibOut| {
+            adj_rib_out
+                .routes()
+                .map(|e| e.as_path().unwrap().to_string())
+                .collect::<Vec<String>>()
+        };
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.install_from_update(
+            update(vec![64514, 64520, 64521], "10.0.0.4"),
+            &config,
+        );
+        loc_rib
+            .install_from_adj_rib_in("10.0.0.4".parse().unwrap(), &adj_rib_in);
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &config,
+            &config.address_families,
+            &Rib::new(),
+        );
+        assert_eq!(as_paths(&adj_rib_out), vec!["64514 64520 64521"]);
+
+        // configのPeerから受信したルートがbest pathになると、そのPeerには
+        // 他のルートを代わりに広報せず、広報済みのルートをwithdrawする。
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in
+            .install_from_update(update(vec![64513], "10.0.0.3"), &config);
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &config,
+            &config.address_families,
+            &Rib::new(),
+        );
+        assert!(adj_rib_out.is_empty());
+        let withdrawn: Vec<IpNetwork> = adj_rib_out
+            .withdrawn
+            .routes()
+            .map(|e| e.network_address)
+            .collect();
+        assert_eq!(withdrawn, vec!["10.1.0.0/24".parse().unwrap()]);
+
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &other,
+            &other.address_families,
+            &Rib::new(),
+        );
+        assert_eq!(as_paths(&adj_rib_out), vec!["64513"]);
+    }
+
+    #[tokio::test]
+    async fn as_path_is_prepended_only_to_external_peers() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.install_from_update(
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(vec![
+                        64513.into()
+                    ])),
+                    PathAttribute::NextHop("10.0.0.3".parse().unwrap()),
+                ]),
+                vec!["10.1.0.0/24".parse().unwrap()],
+                vec![],
+            ),
+            &config,
+        );
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+
+        let advertised_as_path = |config: &str| {
+            let config: Config = config.parse().unwrap();
+            let mut adj_rib_out = AdjRibOut::new();
+            adj_rib_out.install_from_loc_rib(
+                &loc_rib,
+                &config,
+                &[AddressFamily::IPV4_UNICAST],
+                &Rib::new(),
+            );
+            let routes: Vec<AsPath> = adj_rib_out
+                .routes()
+                .map(|e| e.as_path().unwrap().clone())
+                .collect();
+            routes
+        };
+        assert_eq!(
+            advertised_as_path(
+                "64512 10.0.0.2 64515 10.0.0.5 active as-path-prepend=2 \
+                 export-policy=permit,prepend:1"
+            ),
+            vec![AsPath::from_sequence(vec![
+                64512.into(),
+                64512.into(),
+                64512.into(),
+                64513.into()
+            ])]
+        );
+        assert_eq!(
+            advertised_as_path(
+                "64512 10.0.0.2 64512 10.0.0.4 active as-path-prepend=2 \
+                 export-policy=permit,prepend:1"
+            ),
+            vec![AsPath::from_sequence(vec![64513.into()])]
+        );
+    }
+
+    #[test]
+    fn flapping_route_is_suppressed_and_reused() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              damping=60,750,2000,600"
+            .parse()
+            .unwrap();
+        let network: Ipv4Network = "10.1.0.0/24".parse().unwrap();
+        let announce = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![
+                    64513.into()
+                ])),
+                PathAttribute::NextHop("10.0.0.3".parse().unwrap()),
+            ]),
+            vec![network],
+            vec![],
+        );
+        let withdraw =
+            UpdateMessage::new(Arc::new(vec![]), vec![], vec![network]);
+        let mut adj_rib_in = AdjRibIn::new();
+        for _ in 0..2 {
+            adj_rib_in.install_from_update(announce.clone(), &config);
+            adj_rib_in.install_from_update(withdraw.clone(), &config);
+        }
+        adj_rib_in.install_from_update(announce.clone(), &config);
+        assert_eq!(adj_rib_in.len(), 1);
+        assert!(adj_rib_in.install_from_update(withdraw, &config));
+        assert!(!adj_rib_in.install_from_update(announce, &config));
+        assert!(adj_rib_in.is_empty());
+        assert_eq!(
+            adj_rib_in.damping_stats(),
+            DampingStats {
+                histories: 1,
+                suppressed: 1,
+                flaps: 3,
+            }
+        );
+
+        let damping = config.damping.unwrap();
+        let now = Instant::now();
+        assert!(!adj_rib_in.reuse_damped_routes(&damping, now));
+        assert!(adj_rib_in
+            .reuse_damped_routes(&damping, now + Duration::from_secs(180)));
+        assert_eq!(adj_rib_in.len(), 1);
+        assert_eq!(adj_rib_in.damping_stats().suppressed, 0);
+    }
+
+    #[tokio::test]
+    async fn withdrawn_routes_are_removed_and_withdrawn_from_other_peers() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let other: Config =
+            "64512 10.0.0.2 64515 10.0.0.5 active".parse().unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let update = |as_path: Vec<u16>, networks: &[&str]| {
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(
+                        as_path.into_iter().map(|a| a.into()).collect(),
+                    )),
+                    PathAttribute::NextHop("10.0.0.3".parse().unwrap()),
+                ]),
+                networks.iter().map(|n| n.parse().unwrap()).collect(),
+                vec![],
+            )
+        };
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.install_from_update(
+            update(vec![64513], &["10.1.0.0/24", "10.2.0.0/24"]),
+            &config,
+        );
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &other,
+            &[AddressFamily::IPV4_UNICAST],
+            &Rib::new(),
+        );
+        assert_eq!(adj_rib_out.routes().count(), 2);
+
+        // 10.1.0.0/24は別のAS Pathで広報し直され、古いルートは置き換わる。
+        let withdrawn = adj_rib_in.install_from_update(
+            update(vec![64513, 64514], &["10.1.0.0/24"]),
+            &config,
+        );
+        assert!(!withdrawn);
+        let withdraw = UpdateMessage::new(
+            Arc::new(vec![]),
+            vec![],
+            vec!["10.2.0.0/24".parse().unwrap()],
+        );
+        assert!(adj_rib_in.install_from_update(withdraw, &config));
+        assert_eq!(adj_rib_in.routes().count(), 1);
+
+        let generation = loc_rib.generation();
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        assert!(loc_rib.generation() > generation);
+        assert!(loc_rib.does_contain_withdrawn_route());
+        let routes: Vec<&Arc<RibEntry>> = loc_rib.routes().collect();
+        assert_eq!(routes.len(), 1);
+        assert!(routes[0].does_contain_as(64514.into()));
+
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &other,
+            &[AddressFamily::IPV4_UNICAST],
+            &Rib::new(),
+        );
+        let updates =
+            adj_rib_out.create_update_messages(other.local_ip, other.local_as);
+        assert_eq!(
+            updates[0].withdrawn_routes,
+            vec!["10.2.0.0/24".parse().unwrap()]
+        );
+        assert!(updates[0].network_layer_reachability_information.is_empty());
+        assert_eq!(updates.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn kernel_route_changes_are_taken_without_holding_loc_rib() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let loc_rib =
+            Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        // カーネルでnext hopを解決しないように、確認済みにしておく。
+        loc_rib
+            .lock()
+            .await
+            .checked_next_hops
+            .insert("10.0.0.3".parse().unwrap());
+        let update = |withdrawn: Vec<&str>, networks: Vec<&str>| {
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(vec![
+                        64513.into()
+                    ])),
+                    PathAttribute::NextHop("10.0.0.3".parse().unwrap()),
+                ]),
+                networks.iter().map(|n| n.parse().unwrap()).collect(),
+                withdrawn.iter().map(|n| n.parse().unwrap()).collect(),
+            )
+        };
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.install_from_update(
+            update(vec![], vec!["10.1.0.0/24", "10.2.0.0/24"]),
+            &config,
+        );
+        loc_rib
+            .lock()
+            .await
+            .install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+
+        let changes = loc_rib.lock().await.take_kernel_route_changes().await;
+        // 変更を集めた後は、書き込む前でもLocRibのロックを取れる。
+        assert!(loc_rib.try_lock().is_ok());
+        let installed: Vec<IpNetwork> = changes
+            .installed
+            .iter()
+            .map(|e| e.network_address)
+            .collect();
+        assert_eq!(
+            installed,
+            vec![
+                "10.1.0.0/24".parse().unwrap(),
+                "10.2.0.0/24".parse().unwrap()
+            ]
+        );
+        assert!(changes.withdrawn.is_empty());
+
+        adj_rib_in
+            .install_from_update(update(vec!["10.2.0.0/24"], vec![]), &config);
+        let mut guard = loc_rib.lock().await;
+        guard.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        let changes = guard.take_kernel_route_changes().await;
+        // withdrawnは一度だけ集める。
+        assert!(!guard.does_contain_withdrawn_route());
+        drop(guard);
+        let withdrawn: Vec<IpNetwork> = changes
+            .withdrawn
+            .iter()
+            .map(|e| e.network_address)
+            .collect();
+        assert_eq!(withdrawn, vec!["10.2.0.0/24".parse().unwrap()]);
+        assert_eq!(changes.installed.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn withdrawn_routes_are_removed_from_kernel() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let writer = MockRouteWriter::default();
+        loc_rib.set_route_writer(SharedRouteWriter::new(writer.clone()));
+        let other_peer: IpAddr = "10.0.0.4".parse().unwrap();
+        loc_rib.checked_next_hops.insert(config.remote_ip);
+        loc_rib.checked_next_hops.insert(other_peer);
+        let update = |as_path: Vec<u16>,
+                      next_hop: &str,
+                      withdrawn: Vec<&str>,
+                      networks: Vec<&str>| {
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(
+                        as_path.into_iter().map(|a| a.into()).collect(),
+                    )),
+                    PathAttribute::NextHop(next_hop.parse().unwrap()),
+                ]),
+                networks.iter().map(|n| n.parse().unwrap()).collect(),
+                withdrawn.iter().map(|n| n.parse().unwrap()).collect(),
+            )
+        };
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.install_from_update(
+            update(
+                vec![64513],
+                "10.0.0.3",
+                vec![],
+                vec!["10.1.0.0/24", "10.2.0.0/24"],
+            ),
+            &config,
+        );
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        // 10.2.0.0/24は他のPeerからも、AS Pathの長いルートを受信している。
+        let mut other_adj_rib_in = AdjRibIn::new();
+        other_adj_rib_in.install_from_update(
+            update(
+                vec![64514, 64513],
+                "10.0.0.4",
+                vec![],
+                vec!["10.2.0.0/24"],
+            ),
+            &config,
+        );
+        loc_rib.install_from_adj_rib_in(other_peer, &other_adj_rib_in);
+        loc_rib
+            .take_kernel_route_changes()
+            .await
+            .program()
+            .await
+            .unwrap();
+        assert_eq!(
+            writer.installed(),
+            BTreeMap::from([
+                ("10.1.0.0/24".parse().unwrap(), vec![config.remote_ip]),
+                ("10.2.0.0/24".parse().unwrap(), vec![config.remote_ip]),
+            ])
+        );
+
+        // withdrawされたルートはカーネルから削除する。
+        adj_rib_in.install_from_update(
+            update(vec![64513], "10.0.0.3", vec!["10.1.0.0/24"], vec![]),
+            &config,
+        );
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        loc_rib
+            .take_kernel_route_changes()
+            .await
+            .program()
+            .await
+            .unwrap();
+        assert_eq!(writer.deleted(), vec!["10.1.0.0/24".parse().unwrap()]);
+
+        // Peerとのセッションが切れると、他のPeerのルートに切り替える。
+        loc_rib
+            .remove_routes_learned_from(config.remote_ip)
+            .await
+            .unwrap();
+        assert_eq!(writer.deleted(), vec!["10.1.0.0/24".parse().unwrap()]);
+        assert_eq!(
+            writer.installed(),
+            BTreeMap::from([(
+                "10.2.0.0/24".parse().unwrap(),
+                vec![other_peer]
+            )])
+        );
+
+        // 残ったPeerのセッションも切れると、カーネルから削除する。
+        loc_rib
+            .remove_routes_learned_from(other_peer)
+            .await
+            .unwrap();
+        assert_eq!(
+            writer.deleted(),
+            vec![
+                "10.1.0.0/24".parse().unwrap(),
+                "10.2.0.0/24".parse().unwrap()
+            ]
+        );
+        assert!(writer.installed().is_empty());
+    }
+
+    #[tokio::test]
+    async fn route_with_unreachable_next_hop_is_not_installed() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let writer = MockRouteWriter {
+            reachable: HashSet::from([config.remote_ip]),
+            ..Default::default()
+        };
+        loc_rib.set_route_writer(SharedRouteWriter::new(writer.clone()));
+        let update = |next_hop: &str, network: &str| {
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(vec![
+                        64513.into()
+                    ])),
+                    PathAttribute::NextHop(next_hop.parse().unwrap()),
+                ]),
+                vec![network.parse().unwrap()],
+                vec![],
+            )
+        };
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in
+            .install_from_update(update("10.0.0.3", "10.1.0.0/24"), &config);
+        adj_rib_in
+            .install_from_update(update("10.0.0.9", "10.2.0.0/24"), &config);
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+
+        // 初めて見るnext hopは、書き込む前に到達性を確認する。
+        loc_rib
+            .take_kernel_route_changes()
+            .await
+            .program()
+            .await
+            .unwrap();
+        assert_eq!(
+            writer.installed(),
+            BTreeMap::from([(
+                "10.1.0.0/24".parse().unwrap(),
+                vec![config.remote_ip]
+            )])
+        );
+        // 到達できないnext hopのルートはribから外す。
+        let networks: Vec<IpNetwork> =
+            loc_rib.routes().map(|e| e.network_address).collect();
+        assert_eq!(networks, vec!["10.1.0.0/24".parse().unwrap()]);
+        assert!(loc_rib
+            .unresolved
+            .routes()
+            .any(|e| e.network_address == "10.2.0.0/24".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn reconcile_reinstalls_route_with_different_next_hop() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let writer = MockRouteWriter::default();
+        loc_rib.set_route_writer(SharedRouteWriter::new(writer.clone()));
+        loc_rib.checked_next_hops.insert(config.remote_ip);
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.install_from_update(
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(vec![
+                        64513.into()
+                    ])),
+                    PathAttribute::NextHop("10.0.0.3".parse().unwrap()),
+                ]),
+                vec!["10.1.0.0/24".parse().unwrap()],
+                vec![],
+            ),
+            &config,
+        );
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        loc_rib
+            .take_kernel_route_changes()
+            .await
+            .program()
+            .await
+            .unwrap();
+
+        // 他のプロセスがnext hopを書き換え、LocRibに無いルートを残した。
+        writer.installed.lock().unwrap().extend([
+            (
+                "10.1.0.0/24".parse().unwrap(),
+                vec!["10.0.0.9".parse().unwrap()],
+            ),
+            ("10.9.0.0/24".parse().unwrap(), vec![config.remote_ip]),
+        ]);
+        let loc_rib = Mutex::new(loc_rib);
+        let drift = reconcile::reconcile(&loc_rib).await.unwrap();
+        assert_eq!(
+            drift,
+            FibDriftStats {
+                checks: 1,
+                missing: 1,
+                orphaned: 1,
+            }
+        );
+        assert_eq!(
+            writer.installed(),
+            BTreeMap::from([(
+                "10.1.0.0/24".parse().unwrap(),
+                vec![config.remote_ip]
+            )])
+        );
+
+        let drift = reconcile::reconcile(&loc_rib).await.unwrap();
+        assert_eq!((drift.missing, drift.orphaned), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn learned_routes_are_removed_from_kernel_on_shutdown() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let writer = MockRouteWriter::default();
+        loc_rib.set_route_writer(SharedRouteWriter::new(writer.clone()));
+        loc_rib
+            .checked_next_hops
+            .insert("10.0.0.3".parse().unwrap());
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.install_from_update(
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(vec![
+                        64513.into()
+                    ])),
+                    PathAttribute::NextHop("10.0.0.3".parse().unwrap()),
+                ]),
+                vec![
+                    "10.1.0.0/24".parse().unwrap(),
+                    "10.2.0.0/24".parse().unwrap(),
+                ],
+                vec![],
+            ),
+            &config,
+        );
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+        // 10.2.0.0/24は自身もoriginateする。
+        loc_rib
+            .announce("10.2.0.0/24".parse().unwrap(), None, vec![])
+            .unwrap();
+        loc_rib
+            .take_kernel_route_changes()
+            .await
+            .program()
+            .await
+            .unwrap();
+        assert!(writer
+            .installed()
+            .contains_key(&"10.1.0.0/24".parse().unwrap()));
+
+        loc_rib.remove_learned_routes_from_kernel().await.unwrap();
+        // Peerから受信したルートだけを削除し、originateするネットワークは削除しない。
+        assert_eq!(writer.deleted(), vec!["10.1.0.0/24".parse().unwrap()]);
+        assert!(writer.installed().is_empty());
+    }
+
+    #[tokio::test]
+    async fn routes_from_dead_peer_are_retained_as_llgr_stale() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let update = |as_path: Vec<u16>, network: &str, c: Vec<Community>| {
+            let mut path_attributes = vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(
+                    as_path.into_iter().map(|a| a.into()).collect(),
+                )),
+                PathAttribute::NextHop("10.0.0.3".parse().unwrap()),
+            ];
+            if !c.is_empty() {
+                path_attributes.push(PathAttribute::Communities(c));
+            }
+            UpdateMessage::new(
+                Arc::new(path_attributes),
+                vec![network.parse().unwrap()],
+                vec![],
+            )
+        };
+        let peer1: IpAddr = "10.0.0.3".parse().unwrap();
+        let peer2: IpAddr = "10.0.0.4".parse().unwrap();
+        let network: IpNetwork = "10.1.0.0/24".parse().unwrap();
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.install_from_update(
+            update(vec![64513], "10.1.0.0/24", vec![]),
+            &config,
+        );
+        adj_rib_in.install_from_update(
+            update(vec![64513], "10.2.0.0/24", vec![]),
+            &config,
+        );
+        adj_rib_in.install_from_update(
+            update(vec![64513], "10.3.0.0/24", vec![Community::NO_LLGR]),
+            &config,
+        );
+        loc_rib.install_from_adj_rib_in(peer1, &adj_rib_in);
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.install_from_update(
+            update(vec![64514, 64515], "10.1.0.0/24", vec![]),
+            &config,
+        );
+        loc_rib.install_from_adj_rib_in(peer2, &adj_rib_in);
+        let best = loc_rib.best_path(network).unwrap();
+        assert_eq!(best.reason, BestPathReason::ShorterAsPath);
+
+        // NO_LLGRの10.3.0.0/24は取り除かれ、peer1の10.1.0.0/24は優先度が下がる。
+        let generation = loc_rib.generation();
+        loc_rib.mark_routes_stale(peer1).await.unwrap();
+        assert!(loc_rib.generation() > generation);
+        assert_eq!(loc_rib.routes().count(), 3);
+        let best = loc_rib.best_path(network).unwrap();
+        assert_eq!(best.reason, BestPathReason::NotLlgrStale);
+        assert_eq!(best.entry.as_path().unwrap().path_length(), 2);
+
+        // LLGR Capabilityを広報していないPeerには、best pathがstaleの
+        // 10.2.0.0/24を広報しない。
+        let other: Config =
+            "64512 10.0.0.2 64520 10.0.0.5 active".parse().unwrap();
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &other,
+            &other.address_families,
+            &Rib::new(),
+        );
+        assert_eq!(adj_rib_out.routes().count(), 1);
+        assert_eq!(
+            adj_rib_out.routes().next().unwrap().network_address,
+            network
+        );
+        assert_eq!(adj_rib_out.suppressed.llgr_stale, 1);
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.llgr_supported = true;
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &other,
+            &other.address_families,
+            &Rib::new(),
+        );
+        assert_eq!(adj_rib_out.routes().count(), 2);
+
+        loc_rib.remove_stale_routes_from(peer1).await.unwrap();
+        assert_eq!(loc_rib.routes().count(), 1);
+        assert_eq!(
+            loc_rib.best_path(network).unwrap().reason,
+            BestPathReason::OnlyPath
+        );
+    }
+
+    #[tokio::test]
+    async fn originated_attributes_are_overridden_per_network() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              always-advertise=10.1.0.0/24 \
+                              always-advertise=10.2.0.0/24 \
+                              network-origin=10.1.0.0/24,incomplete \
+                              network-med=10.1.0.0/24,100 \
+                              network-community=10.1.0.0/24,64512:10 \
+                              network-community=10.1.0.0/24,no-export"
+            .parse()
+            .unwrap();
+        let loc_rib = LocRib::new(&config).await.unwrap();
+        let path_attributes = |network: &str| {
+            let network: IpNetwork = network.parse().unwrap();
+            loc_rib
+                .routes()
+                .find(|e| e.network_address == network)
+                .map(|e| Arc::clone(&e.path_attributes))
+                .unwrap()
+        };
+        assert_eq!(
+            *path_attributes("10.1.0.0/24"),
+            vec![
+                PathAttribute::Origin(Origin::Incomplete),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![])),
+                PathAttribute::NextHop("10.0.0.2".parse().unwrap()),
+                PathAttribute::LocalPref(100),
+                PathAttribute::MultiExitDisc(100),
+                PathAttribute::Communities(vec![
+                    Community(64512 << 16 | 10),
+                    Community::NO_EXPORT,
+                ]),
+            ]
+        );
+        assert_eq!(
+            path_attributes("10.2.0.0/24")[0],
+            PathAttribute::Origin(Origin::Igp)
+        );
+    }
+
+    #[tokio::test]
+    async fn origination_outside_allowed_prefixes_is_rejected() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              always-advertise=203.0.113.0/25 \
+                              always-advertise=198.51.100.0/24 \
+                              allowed-origination=203.0.113.0/24"
+            .parse()
+            .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let networks: Vec<IpNetwork> =
+            loc_rib.routes().map(|e| e.network_address).collect();
+        assert_eq!(networks, vec!["203.0.113.0/25".parse().unwrap()]);
+
+        assert!(loc_rib
+            .announce("192.0.2.0/24".parse().unwrap(), None, vec![])
+            .is_err());
+        assert!(loc_rib
+            .announce("203.0.113.128/25".parse().unwrap(), None, vec![])
+            .is_ok());
+        assert_eq!(loc_rib.routes().count(), 2);
+    }
+
+    #[test]
+    fn rib_keeps_candidate_paths_per_network() {
+        let entry = |network: &str, next_hop: &str| {
+            Arc::new(RibEntry {
+                network_address: network.parse().unwrap(),
+                labels: vec![],
+                path_attributes: Arc::new(vec![PathAttribute::NextHop(
+                    next_hop.parse().unwrap(),
+                )]),
+            })
+        };
+        let network: IpNetwork = "10.1.0.0/24".parse().unwrap();
         let mut rib = Rib::new();
-        rib.insert(Arc::new(RibEntry {
+        rib.insert(entry("10.2.0.0/24", "10.0.0.3"));
+        rib.insert(entry("10.1.0.0/24", "10.0.0.4"));
+        rib.insert(entry("10.1.0.0/24", "10.0.0.3"));
+        rib.insert(entry("10.1.0.0/24", "10.0.0.3"));
+        assert_eq!(rib.len(), 3);
+        assert_eq!(
+            rib.paths_of(&network).cloned().collect::<Vec<_>>(),
+            vec![
+                entry("10.1.0.0/24", "10.0.0.3"),
+                entry("10.1.0.0/24", "10.0.0.4")
+            ]
+        );
+        // 全てのエントリはネットワークの順に並ぶ。
+        assert_eq!(
+            rib.routes().map(|e| e.network_address).collect::<Vec<_>>(),
+            vec![network, network, "10.2.0.0/24".parse().unwrap()]
+        );
+
+        // 同じネットワークの別のpathで置き換えると、pathは1つだけ残る。
+        rib.replace(entry("10.1.0.0/24", "10.0.0.5"));
+        assert_eq!(
+            rib.paths_of(&network).cloned().collect::<Vec<_>>(),
+            vec![entry("10.1.0.0/24", "10.0.0.5")]
+        );
+        assert!(rib.remove(&entry("10.1.0.0/24", "10.0.0.5")));
+        assert!(!rib.remove(&entry("10.1.0.0/24", "10.0.0.5")));
+        assert_eq!(rib.paths_of(&network).count(), 0);
+        assert_eq!(rib.len(), 1);
+    }
+
+    #[test]
+    fn own_prefixes_from_ebgp_peer_are_checked() {
+        let update = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![
+                    64513.into()
+                ])),
+                PathAttribute::NextHop("10.0.0.3".parse().unwrap()),
+            ]),
+            vec![
+                "203.0.113.0/24".parse().unwrap(),
+                "198.51.100.0/24".parse().unwrap(),
+            ],
+            vec![],
+        );
+        let received = |config: &str| {
+            let config: Config = config.parse().unwrap();
+            let mut adj_rib_in = AdjRibIn::new();
+            adj_rib_in.install_from_update(update.clone(), &config);
+            adj_rib_in.routes().count()
+        };
+        let ebgp = "64512 10.0.0.2 64513 10.0.0.3 active \
+                    allowed-origination=203.0.113.0/24";
+        assert_eq!(received(ebgp), 2);
+        assert_eq!(received(&format!("{} own-prefix-check=warn", ebgp)), 2);
+        assert_eq!(received(&format!("{} own-prefix-check=reject", ebgp)), 1);
+        assert_eq!(
+            received(
+                "64512 10.0.0.2 64512 10.0.0.3 active \
+                 allowed-origination=203.0.113.0/24 own-prefix-check=reject"
+            ),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn local_pref_is_preferred_and_not_advertised_to_other_as() {
+        let external: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                                local-pref=200"
+            .parse()
+            .unwrap();
+        let internal: Config =
+            "64512 10.0.0.2 64512 10.0.0.4 active".parse().unwrap();
+        assert_eq!(external.local_pref, 200);
+        let mut loc_rib = LocRib::new(&external).await.unwrap();
+        let update = |ases: Vec<u16>, local_pref: u32, next_hop: &str| {
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(
+                        ases.into_iter().map(|a| a.into()).collect(),
+                    )),
+                    PathAttribute::NextHop(next_hop.parse().unwrap()),
+                    PathAttribute::LocalPref(local_pref),
+                ]),
+                vec!["10.1.0.0/24".parse().unwrap()],
+                vec![],
+            )
+        };
+        // eBGPのPeerが付けたLOCAL_PREFは自身の値で置き換え、iBGPのPeerの値は残す。
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.install_from_update(
+            update(vec![64513], 500, "10.0.0.3"),
+            &external,
+        );
+        let local_prefs: Vec<Option<u32>> =
+            adj_rib_in.routes().map(|e| e.local_pref()).collect();
+        assert_eq!(local_prefs, vec![Some(200)]);
+        loc_rib.install_from_adj_rib_in(external.remote_ip, &adj_rib_in);
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.internal_peer =
+            InternalPeer::new(&internal, "10.0.0.4".parse().unwrap());
+        adj_rib_in.install_from_update(
+            update(vec![64514, 64515], 300, "10.0.0.4"),
+            &internal,
+        );
+        loc_rib.install_from_adj_rib_in(internal.remote_ip, &adj_rib_in);
+        let best_path =
+            loc_rib.best_path("10.1.0.0/24".parse().unwrap()).unwrap();
+        assert_eq!(best_path.reason, BestPathReason::HigherLocalPref);
+        assert_eq!(best_path.entry.local_pref(), Some(300));
+
+        let advertised = |config: &Config| {
+            let mut adj_rib_out = AdjRibOut::new();
+            adj_rib_out.internal = config.is_ibgp();
+            adj_rib_out.install_from_loc_rib(
+                &loc_rib,
+                config,
+                &config.address_families,
+                &Rib::new(),
+            );
+            adj_rib_out
+                .create_update_messages(config.local_ip, config.local_as)
+                .iter()
+                .flat_map(|u| u.path_attributes.iter())
+                .filter_map(|p| match p {
+                    PathAttribute::LocalPref(local_pref) => Some(*local_pref),
+                    _ => None,
+                })
+                .collect::<Vec<u32>>()
+        };
+        let other: Config =
+            "64512 10.0.0.2 64516 10.0.0.6 active".parse().unwrap();
+        assert!(advertised(&other).is_empty());
+        // best pathはinternalから受信したルートなので、internalには
+        // LOCAL_PREFの低いexternalのルートも広報しない。
+        assert!(advertised(&internal).is_empty());
+    }
+
+    #[tokio::test]
+    async fn equal_cost_paths_are_installed_up_to_maximum_paths() {
+        let update = |ases: Vec<u16>, next_hop: &str| {
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(
+                        ases.into_iter().map(|a| a.into()).collect(),
+                    )),
+                    PathAttribute::NextHop(next_hop.parse().unwrap()),
+                ]),
+                vec!["10.1.0.0/24".parse().unwrap()],
+                vec![],
+            )
+        };
+        let installed = |config: String| async move {
+            let config: Config = config.parse().unwrap();
+            let mut loc_rib = LocRib::new(&config).await.unwrap();
+            for (ases, next_hop) in [
+                (vec![64513, 64520], "10.0.0.3"),
+                (vec![64514, 64521], "10.0.0.4"),
+                (vec![64515, 64522, 64523], "10.0.0.5"),
+            ] {
+                let mut adj_rib_in = AdjRibIn::new();
+                adj_rib_in
+                    .install_from_update(update(ases, next_hop), &config);
+                loc_rib.install_from_adj_rib_in(
+                    next_hop.parse().unwrap(),
+                    &adj_rib_in,
+                );
+            }
+            loc_rib
+                .paths_to_install()
+                .iter()
+                .map(|e| {
+                    format!("{} {}", e.network_address, e.next_hop().unwrap())
+                })
+                .collect::<Vec<String>>()
+        };
+        let config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                      always-advertise=10.2.0.0/24";
+        assert_eq!(
+            installed(config.to_string()).await,
+            vec!["10.1.0.0/24 10.0.0.3"]
+        );
+        assert_eq!(
+            installed(format!("{} maximum-paths=4", config)).await,
+            vec!["10.1.0.0/24 10.0.0.3", "10.1.0.0/24 10.0.0.4"]
+        );
+    }
+
+    #[tokio::test]
+    async fn adj_rib_out_has_one_path_per_prefix() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let entry = |network: &str, ases: Vec<u16>, next_hop: &str| {
+            Arc::new(RibEntry {
+                network_address: network.parse().unwrap(),
+                labels: vec![],
+                path_attributes: Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(
+                        ases.into_iter().map(|a| a.into()).collect(),
+                    )),
+                    PathAttribute::NextHop(next_hop.parse().unwrap()),
+                ]),
+            })
+        };
+        for (network, ases, next_hop) in [
+            ("10.1.0.0/24", vec![64514, 64520], "10.0.0.4"),
+            ("10.1.0.0/24", vec![64515], "10.0.0.5"),
+            ("10.1.0.0/24", vec![64516, 64521, 64522], "10.0.0.6"),
+            ("10.2.0.0/24", vec![64514, 64520], "10.0.0.4"),
+            ("10.2.0.0/24", vec![64515, 64521], "10.0.0.5"),
+        ] {
+            loc_rib.insert(entry(network, ases, next_hop));
+        }
+        assert_eq!(loc_rib.routes().count(), 5);
+
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &config,
+            &config.address_families,
+            &Rib::new(),
+        );
+        let advertised: Vec<Arc<RibEntry>> =
+            adj_rib_out.routes().cloned().collect();
+        let best_paths: Vec<Arc<RibEntry>> = ["10.1.0.0/24", "10.2.0.0/24"]
+            .iter()
+            .map(|n| loc_rib.best_path(n.parse().unwrap()).unwrap().entry)
+            .collect();
+        assert_eq!(advertised, best_paths);
+    }
+
+    #[test]
+    fn rib_can_lookup_longest_match_and_covered_routes() {
+        let entry = |network: &str, next_hop: &str| {
+            Arc::new(RibEntry {
+                network_address: network.parse().unwrap(),
+                labels: vec![],
+                path_attributes: Arc::new(vec![PathAttribute::NextHop(
+                    next_hop.parse().unwrap(),
+                )]),
+            })
+        };
+        let mut rib = Rib::new();
+        for (network, next_hop) in [
+            ("10.0.0.0/8", "10.0.0.3"),
+            ("10.1.0.0/16", "10.0.0.3"),
+            ("10.1.2.0/24", "10.0.0.3"),
+            ("10.1.2.0/24", "10.0.0.4"),
+            ("10.2.0.0/16", "10.0.0.3"),
+            ("11.0.0.0/8", "10.0.0.3"),
+        ] {
+            rib.insert(entry(network, next_hop));
+        }
+        let networks = |entries: Vec<Arc<RibEntry>>| {
+            entries
+                .iter()
+                .map(|e| e.network_address.to_string())
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(
+            networks(rib.lookup_longest_match("10.1.2.3".parse().unwrap())),
+            vec!["10.1.2.0/24", "10.1.2.0/24"]
+        );
+        assert_eq!(
+            networks(rib.lookup_longest_match("10.1.3.1".parse().unwrap())),
+            vec!["10.1.0.0/16"]
+        );
+        assert_eq!(
+            networks(rib.lookup_longest_match("10.3.0.1".parse().unwrap())),
+            vec!["10.0.0.0/8"]
+        );
+        assert!(rib
+            .lookup_longest_match("192.0.2.1".parse().unwrap())
+            .is_empty());
+
+        assert_eq!(
+            networks(rib.covered_routes("10.1.0.0/16".parse().unwrap())),
+            vec!["10.1.0.0/16", "10.1.2.0/24", "10.1.2.0/24"]
+        );
+        assert_eq!(
+            networks(rib.covered_routes("10.0.0.0/8".parse().unwrap())),
+            vec![
+                "10.0.0.0/8",
+                "10.1.0.0/16",
+                "10.1.2.0/24",
+                "10.1.2.0/24",
+                "10.2.0.0/16"
+            ]
+        );
+        assert!(rib
+            .covered_routes("192.0.2.0/24".parse().unwrap())
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn received_next_hop_is_kept_without_next_hop_self() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              always-advertise=10.2.0.0/24"
+            .parse()
+            .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.install_from_update(
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(vec![
+                        64513.into()
+                    ])),
+                    PathAttribute::NextHop("10.0.0.3".parse().unwrap()),
+                ]),
+                vec!["10.1.0.0/24".parse().unwrap()],
+                vec![],
+            ),
+            &config,
+        );
+        loc_rib.install_from_adj_rib_in(config.remote_ip, &adj_rib_in);
+
+        let advertised = |config: &str| {
+            let config: Config = config.parse().unwrap();
+            let mut adj_rib_out = AdjRibOut::new();
+            adj_rib_out.next_hop_self = config.next_hop_self;
+            adj_rib_out.install_from_loc_rib(
+                &loc_rib,
+                &config,
+                &config.address_families,
+                &Rib::new(),
+            );
+            let mut next_hops = adj_rib_out
+                .create_update_messages(config.local_ip, config.local_as)
+                .iter()
+                .flat_map(|u| u.path_attributes.iter())
+                .filter_map(|p| match p {
+                    PathAttribute::NextHop(n) => Some(n.to_string()),
+                    _ => None,
+                })
+                .collect::<Vec<String>>();
+            next_hops.sort();
+            next_hops
+        };
+        let peer = "64512 10.0.0.2 64514 10.0.0.4 active";
+        assert_eq!(advertised(peer), vec!["10.0.0.2", "10.0.0.2"]);
+        assert_eq!(
+            advertised(&format!("{} next-hop-self=off", peer)),
+            vec!["10.0.0.2", "10.0.0.3"]
+        );
+    }
+
+    #[tokio::test]
+    async fn best_path_is_annotated_with_reason() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let network: IpNetwork = "10.1.0.0/24".parse().unwrap();
+        let entry = |origin, ases: Vec<u16>, next_hop: &str| {
+            Arc::new(RibEntry {
+                network_address: network,
+                labels: vec![],
+                path_attributes: Arc::new(vec![
+                    PathAttribute::Origin(origin),
+                    PathAttribute::AsPath(AsPath::from_sequence(
+                        ases.into_iter().map(|a| a.into()).collect(),
+                    )),
+                    PathAttribute::NextHop(next_hop.parse().unwrap()),
+                ]),
+            })
+        };
+        let best = entry(Origin::Egp, vec![64513], "10.0.0.4");
+        loc_rib.insert(Arc::clone(&best));
+        assert_eq!(
+            loc_rib.best_path(network).unwrap().reason,
+            BestPathReason::OnlyPath
+        );
+
+        loc_rib.insert(entry(Origin::Igp, vec![64513, 64514], "10.0.0.3"));
+        let best_path = loc_rib.best_path(network).unwrap();
+        assert_eq!(best_path.entry, best);
+        assert_eq!(best_path.reason, BestPathReason::ShorterAsPath);
+
+        loc_rib.insert(entry(Origin::Igp, vec![64515], "10.0.0.5"));
+        let best_path = loc_rib.best_path(network).unwrap();
+        assert_eq!(best_path.reason, BestPathReason::LowerOrigin);
+        assert_eq!(
+            best_path.to_string(),
+            "10.1.0.0/24 next-hop 10.0.0.5 as-path [64515] origin igp \
+             best: lower origin (3 paths)"
+        );
+        assert!(loc_rib.best_path("10.2.0.0/24".parse().unwrap()).is_none());
+
+        // 他が同じなら、BGP Identifierの小さいPeerから受信したルートを選ぶ。
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        for (next_hop, bgp_identifier) in
+            [("10.0.0.3", "192.168.0.9"), ("10.0.0.4", "192.168.0.1")]
+        {
+            let mut adj_rib_in = AdjRibIn::new();
+            adj_rib_in.bgp_identifier = Some(bgp_identifier.parse().unwrap());
+            adj_rib_in.insert(entry(Origin::Igp, vec![64513], next_hop));
+            loc_rib.install_from_adj_rib_in(
+                next_hop.parse().unwrap(),
+                &adj_rib_in,
+            );
+        }
+        let best_path = loc_rib.best_path(network).unwrap();
+        assert_eq!(best_path.reason, BestPathReason::LowerRouterId);
+        assert_eq!(
+            best_path.entry.next_hop(),
+            Some("10.0.0.4".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn med_is_compared_and_not_advertised_to_other_as() {
+        let config: Config =
+            "64512 10.0.0.2 64513 10.0.0.3 active".parse().unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let entry = |network: &str, ases: Vec<u16>, med, next_hop: &str| {
+            Arc::new(RibEntry {
+                network_address: network.parse().unwrap(),
+                labels: vec![],
+                path_attributes: Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::from_sequence(
+                        ases.into_iter().map(|a| a.into()).collect(),
+                    )),
+                    PathAttribute::NextHop(next_hop.parse().unwrap()),
+                    PathAttribute::MultiExitDisc(med),
+                ]),
+            })
+        };
+        let network: IpNetwork = "10.1.0.0/24".parse().unwrap();
+        loc_rib.insert(entry(
+            "10.1.0.0/24",
+            vec![64514, 64515],
+            100,
+            "10.0.0.4",
+        ));
+        loc_rib.insert(entry(
+            "10.1.0.0/24",
+            vec![64514, 64516],
+            50,
+            "10.0.0.5",
+        ));
+        let best_path = loc_rib.best_path(network).unwrap();
+        assert_eq!(best_path.reason, BestPathReason::LowerMed);
+        assert_eq!(
+            best_path.to_string(),
+            "10.1.0.0/24 next-hop 10.0.0.5 as-path [64514 64516] origin igp \
+             med 50 best: lower med (2 paths)"
+        );
+        // MEDの大きいルートは他のPeerに広報しない。
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &config,
+            &config.address_families,
+            &Rib::new(),
+        );
+        let advertised: Vec<(Option<IpAddr>, Option<u32>)> = adj_rib_out
+            .routes()
+            .map(|e| (e.next_hop(), e.med()))
+            .collect();
+        assert_eq!(
+            advertised,
+            vec![(Some("10.0.0.5".parse().unwrap()), Some(50))]
+        );
+
+        // 隣のASが異なるルートの間ではMEDを比べない。
+        let network: IpNetwork = "10.2.0.0/24".parse().unwrap();
+        loc_rib.insert(entry("10.2.0.0/24", vec![64514], 100, "10.0.0.4"));
+        loc_rib.insert(entry("10.2.0.0/24", vec![64515], 50, "10.0.0.5"));
+        let best_path = loc_rib.best_path(network).unwrap();
+        assert_eq!(best_path.reason, BestPathReason::LowerNextHop);
+        assert_eq!(best_path.entry.med(), Some(100));
+
+        // 自AS内で生成したルートのMEDだけを他のASに広報する。
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        loc_rib.insert(entry("10.1.0.0/24", vec![64514], 100, "10.0.0.4"));
+        loc_rib.insert(entry("10.3.0.0/24", vec![], 10, "10.0.0.2"));
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &config,
+            &config.address_families,
+            &Rib::new(),
+        );
+        let updates = adj_rib_out
+            .create_update_messages(config.local_ip, config.local_as);
+        let med = |network: &str| {
+            let network = network.parse().unwrap();
+            updates
+                .iter()
+                .find(|u| {
+                    u.network_layer_reachability_information.contains(&network)
+                })
+                .unwrap()
+                .path_attributes
+                .iter()
+                .find_map(|p| match p {
+                    PathAttribute::MultiExitDisc(med) => Some(*med),
+                    _ => None,
+                })
+        };
+        assert_eq!(med("10.1.0.0/24"), None);
+        assert_eq!(med("10.3.0.0/24"), Some(10));
+    }
+
+    #[tokio::test]
+    async fn labeled_route_from_peer_is_advertised_with_implicit_null() {
+        let config: Config = "64512 10.0.0.2 64513 10.0.0.3 active \
+                              address-family=ipv4-labeled-unicast"
+            .parse()
+            .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let entry = RibEntry {
             network_address: "10.100.220.0/24".parse().unwrap(),
+            labels: vec![MplsLabel::new(100).unwrap()],
             path_attributes: Arc::new(vec![
                 PathAttribute::Origin(Origin::Igp),
-                PathAttribute::AsPath(AsPath::AsSequence(vec![])),
-                PathAttribute::NextHop("10.200.100.3".parse().unwrap()),
+                PathAttribute::AsPath(AsPath::from_sequence(vec![
+                    64514.into()
+                ])),
+                PathAttribute::MpReachNlri(MpReachNlri::new(
+                    AddressFamily::IPV4_LABELED_UNICAST,
+                    "10.0.0.4".parse().unwrap(),
+                    MpNlri::LabeledUnicast(vec![]),
+                )),
             ]),
-        }));
-        let expected_adj_rib_out = AdjRibOut(rib);
+        };
+        loc_rib.insert(Arc::new(entry.clone()));
 
-        assert_eq!(adj_rib_out, expected_adj_rib_out);
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(
+            &loc_rib,
+            &config,
+            &config.address_families,
+            &Rib::new(),
+        );
+
+        let expected = RibEntry {
+            labels: vec![MplsLabel::IMPLICIT_NULL],
+            ..entry
+        };
+        assert_eq!(
+            adj_rib_out.routes().collect::<Vec<_>>(),
+            vec![&Arc::new(expected)]
+        );
     }
 }
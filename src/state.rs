@@ -2,6 +2,8 @@
 pub enum State {
     Idle,
     Connect,
+    // TCPの接続に失敗し、ConnectRetryTimerの満了を待っている状態。
+    Active,
     OpenSent,
     OpenConfirm,
     Established,
@@ -0,0 +1,119 @@
+/// LocRibのルートの数とメモリ使用量を定期的に確認するモジュールです。
+/// configで設定した閾値を超えたときと、閾値を下回ったときにログを出す。
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::routing::{LocRibs, RibStats};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 値が閾値を超えているかどうかを覚えておき、超えた/下回ったときだけ知らせる。
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct Threshold {
+    limit: u64,
+    exceeded: bool,
+}
+
+impl Threshold {
+    fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            exceeded: false,
+        }
+    }
+
+    /// 閾値を超えた場合はSome(true)、下回った場合はSome(false)を返す。
+    fn check(&mut self, value: u64) -> Option<bool> {
+        let exceeded = value > self.limit;
+        if exceeded == self.exceeded {
+            return None;
+        }
+        self.exceeded = exceeded;
+        Some(exceeded)
+    }
+}
+
+/// configの`route-count-warning`, `memory-warning`を閾値として、
+/// 全てのLocRibのルートの数の合計とメモリ使用量を監視し続ける。
+/// どちらも設定されていなければ何もしない。
+pub async fn monitor(loc_ribs: Arc<LocRibs>, config: &Config) {
+    let mut routes = config.route_count_warning.map(Threshold::new);
+    let mut memory = config.memory_warning.map(Threshold::new);
+    if routes.is_none() && memory.is_none() {
+        return;
+    }
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let mut stats = RibStats::default();
+        for loc_rib in loc_ribs.values() {
+            stats += loc_rib.lock().await.stats();
+        }
+        if let Some(threshold) = routes.as_mut() {
+            match threshold.check(stats.routes() as u64) {
+                Some(true) => warn!(
+                    "route count exceeds {}: {}.",
+                    threshold.limit, stats
+                ),
+                Some(false) => info!(
+                    "route count is back under {}: {}.",
+                    threshold.limit, stats
+                ),
+                None => {}
+            }
+        }
+        // RSSが取れない環境ではLocRibの見積もりで代用する。
+        let memory_kb = resident_memory_kb()
+            .unwrap_or(stats.estimated_memory as u64 / 1024);
+        if let Some(threshold) = memory.as_mut() {
+            match threshold.check(memory_kb) {
+                Some(true) => warn!(
+                    "memory usage {}kB exceeds {}kB: {}.",
+                    memory_kb, threshold.limit, stats
+                ),
+                Some(false) => info!(
+                    "memory usage {}kB is back under {}kB.",
+                    memory_kb, threshold.limit
+                ),
+                None => {}
+            }
+        }
+    }
+}
+
+/// 自身のプロセスの物理メモリ使用量(kB)を返す。
+#[cfg(target_os = "linux")]
+pub fn resident_memory_kb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|l| l.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn resident_memory_kb() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threshold_is_reported_only_when_crossed() {
+        let mut threshold = Threshold::new(100);
+        assert_eq!(threshold.check(50), None);
+        assert_eq!(threshold.check(101), Some(true));
+        assert_eq!(threshold.check(200), None);
+        assert_eq!(threshold.check(100), Some(false));
+        assert_eq!(threshold.check(10), None);
+    }
+}
@@ -0,0 +1,248 @@
+/// Peerのタスクを監視するモジュールです。
+/// Peerのタスクがpanicした場合は、そのPeerから受信したルートをLocRibから取り除き、
+/// backoffを挟んでPeerを作り直す。
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{mpsc, watch, Mutex};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::bfd::BfdState;
+use crate::config::Config;
+use crate::dump::DumpRequest;
+use crate::flowspec;
+use crate::listener::BgpListener;
+use crate::logging::PEER_SPAN;
+use crate::message_log::MessageLog;
+use crate::peer::{Peer, PeerStatus, ResetKind};
+use crate::routing::LocRib;
+use crate::task_pool::{self, PeerTaskPool};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// この時間より長く動いていたPeerが落ちた場合は、backoffを初期値に戻す。
+const STABLE_PERIOD: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+pub struct PeerSupervisor {
+    config: Config,
+    loc_rib: Arc<Mutex<LocRib>>,
+    listener: Arc<BgpListener>,
+    // bfd=onの場合の、PeerとのBFDのセッションの状態。
+    bfd: Option<watch::Receiver<BfdState>>,
+    // startup-waitの場合の、起動直後の収束を待ったかどうか。
+    converged: Option<watch::Receiver<bool>>,
+    // peer-task-poolの場合の、Peerを動かすタスクの集まり。
+    // Noneの場合はPeer毎に1つのタスクで動かす。
+    pool: Option<PeerTaskPool>,
+    // Peerを作り直してもcontrol socketからの指示が届くように、
+    // 指示はここで受けて、その時点のPeerに転送する。
+    admin_events: mpsc::UnboundedReceiver<ResetKind>,
+    admin_sender: mpsc::UnboundedSender<ResetKind>,
+    dump_requests: mpsc::UnboundedReceiver<DumpRequest>,
+    dump_sender: mpsc::UnboundedSender<DumpRequest>,
+    // maintenance modeの指示。Peerを作り直しても維持するように、
+    // 受けた指示をwatchに保持してPeerに監視させる。
+    maintenance_events: mpsc::UnboundedReceiver<bool>,
+    maintenance_sender: mpsc::UnboundedSender<bool>,
+    maintenance: watch::Sender<bool>,
+    maintenance_receiver: watch::Receiver<bool>,
+    // Peerがpanicした前後のメッセージも確認できるように、Peerの間で引き継ぐ。
+    message_log: MessageLog,
+    // 同様に、その時点のPeerの状態をここに転送する。
+    status: watch::Sender<PeerStatus>,
+    status_receiver: watch::Receiver<PeerStatus>,
+}
+
+impl PeerSupervisor {
+    pub fn new(
+        config: Config,
+        loc_rib: Arc<Mutex<LocRib>>,
+        listener: Arc<BgpListener>,
+        bfd: Option<watch::Receiver<BfdState>>,
+    ) -> Self {
+        let (admin_sender, admin_events) = mpsc::unbounded_channel();
+        let (dump_sender, dump_requests) = mpsc::unbounded_channel();
+        let (maintenance_sender, maintenance_events) =
+            mpsc::unbounded_channel();
+        let (maintenance, maintenance_receiver) = watch::channel(false);
+        let (status, status_receiver) = watch::channel(PeerStatus::default());
+        let message_log = MessageLog::new(config.message_log_size);
+        Self {
+            config,
+            loc_rib,
+            listener,
+            bfd,
+            converged: None,
+            pool: None,
+            admin_events,
+            admin_sender,
+            dump_requests,
+            dump_sender,
+            maintenance_events,
+            maintenance_sender,
+            maintenance,
+            maintenance_receiver,
+            message_log,
+            status,
+            status_receiver,
+        }
+    }
+
+    pub fn remote_ip(&self) -> IpAddr {
+        self.config.remote_ip
+    }
+
+    /// Peerのセッションのリセットを指示するためのsenderを返す。
+    pub fn admin_sender(&self) -> mpsc::UnboundedSender<ResetKind> {
+        self.admin_sender.clone()
+    }
+
+    /// PeerのAdj-RIBのダンプを求めるためのsenderを返す。
+    pub fn dump_sender(&self) -> mpsc::UnboundedSender<DumpRequest> {
+        self.dump_sender.clone()
+    }
+
+    /// maintenance modeの開始(true), 終了(false)を指示するためのsenderを返す。
+    pub fn maintenance_sender(&self) -> mpsc::UnboundedSender<bool> {
+        self.maintenance_sender.clone()
+    }
+
+    /// Peerが直近に送受信したメッセージを返す。
+    pub fn message_log(&self) -> MessageLog {
+        self.message_log.clone()
+    }
+
+    /// 起動直後の収束を待ち、convergedがtrueになるまでPeerにルートを広報させない。
+    pub fn wait_for_convergence(&mut self, converged: watch::Receiver<bool>) {
+        self.converged = Some(converged);
+    }
+
+    /// Peerをpoolのタスクで動かす。
+    pub fn run_on_pool(&mut self, pool: PeerTaskPool) {
+        self.pool = Some(pool);
+    }
+
+    /// Peerの状態を受け取るためのreceiverを返す。
+    pub fn status(&self) -> watch::Receiver<PeerStatus> {
+        self.status_receiver.clone()
+    }
+
+    /// Peerを起動し、落ちる度に作り直し続ける。
+    /// attemptedには最初のPeerが接続を試みた後に通知する。
+    pub async fn run(self, attempted: mpsc::Sender<()>) {
+        // Peer毎にログのレベルを変えられるように、Peerのspanの中で動かす。
+        let span = info_span!(PEER_SPAN, remote_ip = %self.config.remote_ip);
+        self.supervise(attempted).instrument(span).await
+    }
+
+    async fn supervise(mut self, attempted: mpsc::Sender<()>) {
+        let mut attempted = Some(attempted);
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let mut peer = Peer::with_listener(
+                self.config.clone(),
+                Arc::clone(&self.loc_rib),
+                Arc::clone(&self.listener),
+            );
+            if let Some(bfd) = &self.bfd {
+                peer.watch_bfd(bfd.clone());
+            }
+            if let Some(converged) = &self.converged {
+                peer.wait_for_convergence(converged.clone());
+            }
+            peer.record_messages(self.message_log.clone());
+            peer.watch_maintenance(self.maintenance_receiver.clone());
+            let peer_admin_sender = peer.admin_sender();
+            let peer_dump_sender = peer.dump_sender();
+            let mut peer_status = peer.status();
+            let flowspec_enforcer = peer.flowspec_enforcer();
+            peer.start();
+            let attempted = attempted.take();
+            let started = Instant::now();
+            let peer_task = async move {
+                peer.next().await;
+                if let Some(attempted) = attempted {
+                    let _ = attempted.send(()).await;
+                }
+                loop {
+                    peer.next().await;
+                    // next()は受信するメッセージが無くても待たずに戻るので、
+                    // BFDのセッションなど同じruntimeの他のタスクが止まらないように譲る。
+                    tokio::task::yield_now().await;
+                }
+            }
+            .in_current_span();
+            let mut task = match &self.pool {
+                Some(pool) => pool.spawn(peer_task),
+                None => task_pool::spawn(peer_task),
+            };
+
+            let result = loop {
+                tokio::select! {
+                    result = &mut task => break result,
+                    Some(kind) = self.admin_events.recv() => {
+                        let _ = peer_admin_sender.send(kind);
+                    }
+                    Some(reply) = self.dump_requests.recv() => {
+                        let _ = peer_dump_sender.send(reply);
+                    }
+                    Some(maintenance) = self.maintenance_events.recv() => {
+                        let _ = self.maintenance.send(maintenance);
+                    }
+                    Ok(()) = peer_status.changed() => {
+                        let status = peer_status.borrow().clone();
+                        let _ = self.status.send(status);
+                    }
+                }
+            };
+            match result {
+                Err(e) if e.is_panic() => error!(
+                    "peer {} is panicked after running {:?}: {:?}.",
+                    self.config.remote_ip,
+                    started.elapsed(),
+                    e
+                ),
+                Err(e) => error!(
+                    "peer {} is stopped: {:?}.",
+                    self.config.remote_ip, e
+                ),
+                Ok(()) => {
+                    warn!("peer {} is stopped.", self.config.remote_ip)
+                }
+            }
+
+            let _ = self.status.send(PeerStatus::default());
+            let mut loc_rib = self.loc_rib.lock().await;
+            if let Err(e) = loc_rib
+                .remove_routes_learned_from(self.config.remote_ip)
+                .await
+            {
+                warn!("cannot remove routes learned from peer: {:?}.", e);
+            }
+            // LLGRのstale timeを管理するPeerが居なくなるので、保持していたルートも取り除く。
+            if let Err(e) = loc_rib
+                .remove_stale_routes_from(self.config.remote_ip)
+                .await
+            {
+                warn!("cannot remove stale routes of peer: {:?}.", e);
+            }
+            drop(loc_rib);
+            // Peerのタスクが反映したFlowSpecのルールも取り除く。
+            if let Some(enforcer) = &flowspec_enforcer {
+                flowspec::synchronize(enforcer, vec![]).await;
+            }
+
+            if started.elapsed() > STABLE_PERIOD {
+                backoff = INITIAL_BACKOFF;
+            }
+            info!(
+                "peer {} will be restarted in {:?}.",
+                self.config.remote_ip, backoff
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
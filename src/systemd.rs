@@ -0,0 +1,99 @@
+/// systemdのservice(Type=notify)として動作させるためのsd_notifyの実装です。
+/// libsystemdには依存せず、`NOTIFY_SOCKET`環境変数のUnix domain socketに
+/// 直接状態を書き込む。systemd以外から起動された場合は何もしない。
+///
+/// ```text
+/// [Service]
+/// Type=notify
+/// WatchdogSec=30
+/// ExecStart=/usr/local/bin/mrbgpdv2 64512 10.200.100.2 64513 10.200.100.3 passive
+/// ```
+use std::env;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tracing::warn;
+
+/// 起動が完了したことを通知する。
+pub fn notify_ready() {
+    notify("READY=1");
+}
+
+/// 動作し続けていることを通知する。watchdog_intervalの間隔で呼ぶ。
+pub fn notify_watchdog() {
+    notify("WATCHDOG=1");
+}
+
+/// WatchdogSecが設定されている場合、WATCHDOG=1を送るべき間隔を返す。
+/// systemdの推奨に従い、タイムアウトの半分の間隔にする。
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // WATCHDOG_PIDが自身以外を指している場合は自身宛ての設定ではない。
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+fn notify(state: &str) {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    if let Err(e) = send(&path.to_string_lossy(), state) {
+        warn!("cannot notify {} to systemd: {:?}.", state, e);
+    }
+}
+
+fn send(path: &str, state: &str) -> Result<()> {
+    // '@'から始まる場合はabstract namespaceのsocket。
+    if let Some(name) = path.strip_prefix('@') {
+        return send_to_abstract(name, state);
+    }
+    let socket = UnixDatagram::unbound()?;
+    socket
+        .send_to(state.as_bytes(), path)
+        .context(format!("cannot send to {}", path))?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn send_to_abstract(name: &str, state: &str) -> Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    let addr = SocketAddr::from_abstract_name(name.as_bytes())?;
+    let socket = UnixDatagram::unbound()?;
+    socket
+        .send_to_addr(state.as_bytes(), &addr)
+        .context(format!("cannot send to @{}", name))?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_to_abstract(name: &str, _state: &str) -> Result<()> {
+    anyhow::bail!("abstract socket @{} is only supported on linux", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn send_state_to_notify_socket() {
+        let path = env::temp_dir()
+            .join(format!("mrbgpdv2-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        send(&path.to_string_lossy(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,147 @@
+/// 複数のPeerを少数のタスクで動かすためのモジュールです。
+/// 既定ではPeer毎に1つのタスクで動かすが、Peerが数百あるとタスクの切り替えが
+/// 増えるので、`peer-task-pool`を指定した場合は決まった数のタスクに
+/// Peerを順に割り当て、1つのタスクの中で複数のPeerを動かす。
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{FuturesUnordered, StreamExt};
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+
+/// タスクで動かしていたPeerが終了した理由です。
+#[derive(Error, Debug)]
+pub enum PeerTaskError {
+    #[error("peer task is panicked: {0}")]
+    Panicked(String),
+    #[error("peer task is cancelled")]
+    Cancelled,
+}
+
+impl PeerTaskError {
+    pub fn is_panic(&self) -> bool {
+        matches!(self, PeerTaskError::Panicked(_))
+    }
+}
+
+/// Peerを動かすタスクの終了を待つfutureです。
+pub type PeerTask =
+    Pin<Box<dyn Future<Output = Result<(), PeerTaskError>> + Send>>;
+
+/// Peerを動かすタスクの集まりです。複製しても同じタスクを共有する。
+#[derive(Debug, Clone)]
+pub struct PeerTaskPool {
+    workers: Arc<Vec<mpsc::UnboundedSender<BoxFuture<'static, ()>>>>,
+    next: Arc<AtomicUsize>,
+}
+
+impl PeerTaskPool {
+    /// size個のタスクを起動する。
+    pub fn new(size: usize) -> Self {
+        let workers = (0..size.max(1))
+            .map(|_| {
+                let (sender, receiver) = mpsc::unbounded_channel();
+                tokio::spawn(run_worker(receiver));
+                sender
+            })
+            .collect();
+        Self {
+            workers: Arc::new(workers),
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// futureを順番に次のタスクに割り当てて動かす。
+    /// 1つのPeerがpanicしても、同じタスクの他のPeerは動かし続ける。
+    pub fn spawn<F>(&self, future: F) -> PeerTask
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let future = async move {
+            let result = AssertUnwindSafe(future)
+                .catch_unwind()
+                .await
+                .map_err(|e| PeerTaskError::Panicked(panic_message(e)));
+            let _ = sender.send(result);
+        };
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.workers.len();
+        let _ = self.workers[i].send(future.boxed());
+        Box::pin(async move {
+            receiver.await.unwrap_or(Err(PeerTaskError::Cancelled))
+        })
+    }
+}
+
+/// futureをPeer毎のタスクで動かす。
+pub fn spawn<F>(future: F) -> PeerTask
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let handle = tokio::spawn(future);
+    Box::pin(async move {
+        handle.await.map_err(|e| match e.try_into_panic() {
+            Ok(panic) => PeerTaskError::Panicked(panic_message(panic)),
+            Err(_) => PeerTaskError::Cancelled,
+        })
+    })
+}
+
+async fn run_worker(
+    mut receiver: mpsc::UnboundedReceiver<BoxFuture<'static, ()>>,
+) {
+    let mut running = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            future = receiver.recv() => match future {
+                Some(future) => running.push(future),
+                None => break,
+            },
+            Some(()) = running.next(), if !running.is_empty() => {}
+        }
+    }
+    // 新しいPeerが割り当てられなくなっても、動いているPeerは最後まで動かす。
+    while running.next().await.is_some() {}
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn panic_of_a_peer_does_not_stop_other_peers_in_pool() {
+        let pool = PeerTaskPool::new(1);
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let running = pool.spawn(async move {
+            loop {
+                let _ = sender.send(());
+                tokio::task::yield_now().await;
+            }
+        });
+        let panicked = pool.spawn(async { panic!("peer is broken") });
+        match panicked.await {
+            Err(PeerTaskError::Panicked(message)) => {
+                assert_eq!(message, "peer is broken")
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        while receiver.try_recv().is_ok() {}
+        receiver.recv().await.unwrap();
+        drop(running);
+    }
+}
@@ -0,0 +1,712 @@
+/// BGP/MPLS IP VPN (RFC4364)のVPNv4ルートに関するデータ型と、
+/// VRF毎のルーティングテーブルを定義するモジュールです。
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Context;
+use bytes::{BufMut, BytesMut};
+
+use crate::bgp_type::MplsLabel;
+use crate::error::{ConfigParseError, ConvertBytesToBgpMessageError};
+use crate::path_attribute::{MpReachNlri, PathAttribute};
+use crate::routing::{IpNetwork, Ipv4Network, Rib, RibEntry, RibKey};
+
+/// Route Distinguisher, Route Targetに共通する
+/// Administrator SubfieldとAssigned Number Subfieldの組です。
+/// (RFC4364 Section 4.2, RFC4360 Section 3)
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum AdministratorValue {
+    // Type 0: 2 octetsのAS番号 + 4 octetsの値
+    As2 { asn: u16, value: u32 },
+    // Type 1: IPv4アドレス + 2 octetsの値
+    Ipv4 { addr: Ipv4Addr, value: u16 },
+    // Type 2: 4 octetsのAS番号 + 2 octetsの値
+    As4 { asn: u32, value: u16 },
+}
+
+impl AdministratorValue {
+    fn type_(&self) -> u8 {
+        match self {
+            AdministratorValue::As2 { .. } => 0,
+            AdministratorValue::Ipv4 { .. } => 1,
+            AdministratorValue::As4 { .. } => 2,
+        }
+    }
+
+    /// typeの後ろに続く6 octetsのbytes表現を返す。
+    fn value_bytes(&self) -> [u8; 6] {
+        let mut b = [0u8; 6];
+        match self {
+            AdministratorValue::As2 { asn, value } => {
+                b[0..2].copy_from_slice(&asn.to_be_bytes());
+                b[2..6].copy_from_slice(&value.to_be_bytes());
+            }
+            AdministratorValue::Ipv4 { addr, value } => {
+                b[0..4].copy_from_slice(&addr.octets());
+                b[4..6].copy_from_slice(&value.to_be_bytes());
+            }
+            AdministratorValue::As4 { asn, value } => {
+                b[0..4].copy_from_slice(&asn.to_be_bytes());
+                b[4..6].copy_from_slice(&value.to_be_bytes());
+            }
+        }
+        b
+    }
+
+    fn from_type_and_value(
+        type_: u8,
+        b: &[u8],
+    ) -> Result<Self, ConvertBytesToBgpMessageError> {
+        match type_ {
+            0 => Ok(AdministratorValue::As2 {
+                asn: u16::from_be_bytes([b[0], b[1]]),
+                value: u32::from_be_bytes([b[2], b[3], b[4], b[5]]),
+            }),
+            1 => Ok(AdministratorValue::Ipv4 {
+                addr: Ipv4Addr::new(b[0], b[1], b[2], b[3]),
+                value: u16::from_be_bytes([b[4], b[5]]),
+            }),
+            2 => Ok(AdministratorValue::As4 {
+                asn: u32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+                value: u16::from_be_bytes([b[4], b[5]]),
+            }),
+            _ => Err(ConvertBytesToBgpMessageError::from(anyhow::anyhow!(
+                "type {}のRoute Distinguisher/Route Targetには対応していません。",
+                type_
+            ))),
+        }
+    }
+}
+
+impl FromStr for AdministratorValue {
+    type Err = ConfigParseError;
+
+    /// `64512:100`, `10.0.0.1:100`, `4200000000:100`の形式からparseする。
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (admin, value) = s.rsplit_once(':').context(format!(
+            "cannot parse `{}` as route distinguisher/route target",
+            s
+        ))?;
+        if let Ok(addr) = admin.parse::<Ipv4Addr>() {
+            return Ok(AdministratorValue::Ipv4 {
+                addr,
+                value: value.parse().context(format!(
+                    "cannot parse assigned number of `{}`",
+                    s
+                ))?,
+            });
+        }
+        let asn: u32 = admin
+            .parse()
+            .context(format!("cannot parse administrator of `{}`", s))?;
+        if let Ok(asn) = u16::try_from(asn) {
+            Ok(AdministratorValue::As2 {
+                asn,
+                value: value.parse().context(format!(
+                    "cannot parse assigned number of `{}`",
+                    s
+                ))?,
+            })
+        } else {
+            Ok(AdministratorValue::As4 {
+                asn,
+                value: value.parse().context(format!(
+                    "cannot parse assigned number of `{}`",
+                    s
+                ))?,
+            })
+        }
+    }
+}
+
+impl fmt::Display for AdministratorValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdministratorValue::As2 { asn, value } => {
+                write!(f, "{}:{}", asn, value)
+            }
+            AdministratorValue::Ipv4 { addr, value } => {
+                write!(f, "{}:{}", addr, value)
+            }
+            AdministratorValue::As4 { asn, value } => {
+                write!(f, "{}:{}", asn, value)
+            }
+        }
+    }
+}
+
+/// Route Distinguisher (RFC4364 Section 4.2)
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct RouteDistinguisher(pub AdministratorValue);
+
+impl RouteDistinguisher {
+    /// MP_REACH_NLRIのnext hopの前に付けるRoute Distinguisher(すべて0)
+    pub const ZERO: RouteDistinguisher =
+        RouteDistinguisher(AdministratorValue::As2 { asn: 0, value: 0 });
+}
+
+impl From<&RouteDistinguisher> for BytesMut {
+    fn from(rd: &RouteDistinguisher) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        bytes.put_u16(rd.0.type_() as u16);
+        bytes.put(&rd.0.value_bytes()[..]);
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for RouteDistinguisher {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() < 8 {
+            return Err(Self::Error::from(anyhow::anyhow!(
+                "Route Distinguisherの長さが足りません。"
+            )));
+        }
+        let type_ = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let type_ = u8::try_from(type_).context(format!(
+            "type {}のRoute Distinguisherには対応していません。",
+            type_
+        ))?;
+        Ok(Self(AdministratorValue::from_type_and_value(
+            type_,
+            &bytes[2..8],
+        )?))
+    }
+}
+
+impl FromStr for RouteDistinguisher {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.parse()?))
+    }
+}
+
+impl fmt::Display for RouteDistinguisher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Route Target (RFC4360 Section 4)。Extended Communityとして運ばれる。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct RouteTarget(pub AdministratorValue);
+
+impl RouteTarget {
+    /// Extended Community(8 octets)のbytes表現に変換する。
+    pub fn to_extended_community_bytes(self) -> [u8; 8] {
+        let mut b = [0u8; 8];
+        b[0] = self.0.type_();
+        b[1] = 0x02; // Sub-Type: Route Target
+        b[2..8].copy_from_slice(&self.0.value_bytes());
+        b
+    }
+
+    /// Extended Community(8 octets)のbytes表現がRoute Targetであれば変換する。
+    pub fn from_extended_community_bytes(b: &[u8; 8]) -> Option<Self> {
+        if b[1] != 0x02 {
+            return None;
+        }
+        AdministratorValue::from_type_and_value(b[0], &b[2..8])
+            .ok()
+            .map(Self)
+    }
+}
+
+impl FromStr for RouteTarget {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.parse()?))
+    }
+}
+
+impl fmt::Display for RouteTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Route Origin (RFC4360 Section 5)。ルートを生成したサイトを表し、
+/// Site of Originとして同じサイトにルートが戻るのを防ぐのに使われる。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct RouteOrigin(pub AdministratorValue);
+
+impl RouteOrigin {
+    /// Extended Community(8 octets)のbytes表現に変換する。
+    pub fn to_extended_community_bytes(self) -> [u8; 8] {
+        let mut b = [0u8; 8];
+        b[0] = self.0.type_();
+        b[1] = 0x03; // Sub-Type: Route Origin
+        b[2..8].copy_from_slice(&self.0.value_bytes());
+        b
+    }
+
+    /// Extended Community(8 octets)のbytes表現がRoute Originであれば変換する。
+    pub fn from_extended_community_bytes(b: &[u8; 8]) -> Option<Self> {
+        if b[1] != 0x03 {
+            return None;
+        }
+        AdministratorValue::from_type_and_value(b[0], &b[2..8])
+            .ok()
+            .map(Self)
+    }
+}
+
+impl FromStr for RouteOrigin {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.parse()?))
+    }
+}
+
+impl fmt::Display for RouteOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// VPNv4のNLRI(RFC4364 Section 4.3.4, RFC8277)。
+/// ラベルスタック + Route Distinguisher + IPv4 prefixで構成される。
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub struct Vpnv4Prefix {
+    pub labels: Vec<MplsLabel>,
+    pub route_distinguisher: RouteDistinguisher,
+    pub prefix: Ipv4Network,
+}
+
+impl Vpnv4Prefix {
+    /// Route DistinguisherとIPv4 prefixが同じ、つまり同じルートを表すか。
+    /// ラベルは広報し直すと変わり得るので比べない。
+    pub fn is_same_route(&self, other: &Vpnv4Prefix) -> bool {
+        self.route_distinguisher == other.route_distinguisher
+            && self.prefix == other.prefix
+    }
+
+    pub fn bytes_len(&self) -> usize {
+        // Length(1) + ラベル(3 octets * ラベル数) + RD(8) + prefix
+        1 + 3 * self.labels.len() + 8 + (self.prefix.bytes_len() - 1)
+    }
+
+    pub fn from_u8_slice(
+        bytes: &[u8],
+    ) -> Result<Vec<Self>, ConvertBytesToBgpMessageError> {
+        let mut prefixes = vec![];
+        let mut i = 0;
+        while bytes.len() > i {
+            let mut length_bits = bytes[i] as usize;
+            i += 1;
+            let mut labels = vec![];
+            loop {
+                if bytes.len() < i + 3 || length_bits < 24 {
+                    return Err(ConvertBytesToBgpMessageError::from(
+                        anyhow::anyhow!("VPNv4 NLRIのラベルが不正です。"),
+                    ));
+                }
+                let (label, bottom_of_stack) = MplsLabel::from_nlri_bytes([
+                    bytes[i],
+                    bytes[i + 1],
+                    bytes[i + 2],
+                ]);
+                labels.push(label);
+                i += 3;
+                length_bits -= 24;
+                if bottom_of_stack || label == MplsLabel::WITHDRAWN {
+                    break;
+                }
+            }
+            if bytes.len() < i + 8 || length_bits < 64 {
+                return Err(ConvertBytesToBgpMessageError::from(
+                    anyhow::anyhow!(
+                        "VPNv4 NLRIのRoute Distinguisherが不正です。"
+                    ),
+                ));
+            }
+            let route_distinguisher =
+                RouteDistinguisher::try_from(&bytes[i..i + 8])?;
+            i += 8;
+            length_bits -= 64;
+
+            let prefix_length = length_bits as u8;
+            let prefix_bytes_length = length_bits.div_ceil(8);
+            if bytes.len() < i + prefix_bytes_length || prefix_length > 32 {
+                return Err(ConvertBytesToBgpMessageError::from(
+                    anyhow::anyhow!("VPNv4 NLRIのprefixが不正です。"),
+                ));
+            }
+            let mut octets = [0u8; 4];
+            octets[..prefix_bytes_length]
+                .copy_from_slice(&bytes[i..i + prefix_bytes_length]);
+            let prefix =
+                Ipv4Network::new(Ipv4Addr::from(octets), prefix_length)
+                    .context("bytes -> Ipv4に変換出来ませんでした。")?;
+            i += prefix_bytes_length;
+
+            prefixes.push(Self {
+                labels,
+                route_distinguisher,
+                prefix,
+            });
+        }
+        Ok(prefixes)
+    }
+}
+
+impl From<&Vpnv4Prefix> for BytesMut {
+    fn from(p: &Vpnv4Prefix) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        let length_bits =
+            24 * p.labels.len() + 64 + p.prefix.prefix() as usize;
+        bytes.put_u8(length_bits as u8);
+        for (i, label) in p.labels.iter().enumerate() {
+            let bottom_of_stack =
+                i == p.labels.len() - 1 && *label != MplsLabel::WITHDRAWN;
+            bytes.put(&label.to_nlri_bytes(bottom_of_stack)[..]);
+        }
+        bytes.put::<BytesMut>((&p.route_distinguisher).into());
+        let prefix_bytes: BytesMut = (&p.prefix).into();
+        // 先頭のprefix長を表すoctetは上で書き込んだLengthに含まれている。
+        bytes.put(&prefix_bytes[1..]);
+        bytes
+    }
+}
+
+impl fmt::Display for Vpnv4Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.route_distinguisher, *self.prefix)
+    }
+}
+
+/// VPNv4ルートを保持するRibのエントリです。
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+pub struct VpnRibEntry {
+    pub prefix: Vpnv4Prefix,
+    pub path_attributes: Arc<Vec<PathAttribute>>,
+}
+
+impl RibKey for VpnRibEntry {
+    type Key = Vpnv4Prefix;
+
+    fn key(&self) -> &Vpnv4Prefix {
+        &self.prefix
+    }
+}
+
+impl VpnRibEntry {
+    /// VRFにインストールするためのIPv4ルートに変換する。
+    /// MP_REACH_NLRIのnext hopはNEXT_HOPとして保持する。
+    pub fn to_ipv4_rib_entry(&self) -> RibEntry {
+        let path_attributes = self
+            .path_attributes
+            .iter()
+            .map(|p| match p {
+                PathAttribute::MpReachNlri(MpReachNlri {
+                    next_hop: IpAddr::V4(n),
+                    ..
+                }) => PathAttribute::NextHop(*n),
+                p => p.clone(),
+            })
+            .collect();
+        RibEntry {
+            network_address: IpNetwork::V4(self.prefix.prefix),
+            labels: vec![],
+            path_attributes: Arc::new(path_attributes),
+        }
+    }
+
+    pub fn route_targets(&self) -> Vec<RouteTarget> {
+        self.path_attributes
+            .iter()
+            .filter_map(|p| match p {
+                PathAttribute::ExtendedCommunities(c) => Some(c),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|c| c.route_target())
+            .collect()
+    }
+}
+
+/// Route Target Constraint (RFC4684)のRoute Target Membership NLRIです。
+/// Peerはこれで受信したいVPNルートのRoute Targetを広報する。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum RouteTargetMembership {
+    // prefix長0のdefault。すべてのVPNルートを受信する。
+    Default,
+    Member {
+        origin_as: u32,
+        // route_targetのうち先頭何bitが有効か(0-64)
+        route_target_length: u8,
+        route_target: [u8; 8],
+    },
+}
+
+impl RouteTargetMembership {
+    pub fn new(origin_as: u32, route_target: RouteTarget) -> Self {
+        RouteTargetMembership::Member {
+            origin_as,
+            route_target_length: 64,
+            route_target: route_target.to_extended_community_bytes(),
+        }
+    }
+
+    fn prefix_length(&self) -> u8 {
+        match self {
+            RouteTargetMembership::Default => 0,
+            RouteTargetMembership::Member {
+                route_target_length,
+                ..
+            } => 32 + route_target_length,
+        }
+    }
+
+    pub fn bytes_len(&self) -> usize {
+        1 + (self.prefix_length() as usize).div_ceil(8)
+    }
+
+    /// Route Targetのいずれかがこのmembershipのprefixに含まれるか。
+    pub fn does_match(&self, route_targets: &[RouteTarget]) -> bool {
+        let (length, member) = match self {
+            RouteTargetMembership::Default => return true,
+            RouteTargetMembership::Member {
+                route_target_length,
+                route_target,
+                ..
+            } => (
+                *route_target_length as u32,
+                u64::from_be_bytes(*route_target),
+            ),
+        };
+        let mask = u64::MAX.checked_shl(64 - length).unwrap_or(0);
+        route_targets.iter().any(|rt| {
+            u64::from_be_bytes(rt.to_extended_community_bytes()) & mask
+                == member & mask
+        })
+    }
+
+    pub fn from_u8_slice(
+        bytes: &[u8],
+    ) -> Result<Vec<Self>, ConvertBytesToBgpMessageError> {
+        let mut memberships = vec![];
+        let mut i = 0;
+        while bytes.len() > i {
+            let prefix_length = bytes[i];
+            if prefix_length == 0 {
+                memberships.push(RouteTargetMembership::Default);
+                i += 1;
+                continue;
+            }
+            if !(32..=96).contains(&prefix_length) {
+                return Err(ConvertBytesToBgpMessageError::from(
+                    anyhow::anyhow!(
+                        "Route Target Membership NLRIのprefix長{}が不正です。",
+                        prefix_length
+                    ),
+                ));
+            }
+            let octets = (prefix_length as usize).div_ceil(8);
+            let value = bytes.get(i + 1..i + 1 + octets).ok_or_else(|| {
+                ConvertBytesToBgpMessageError::from(anyhow::anyhow!(
+                    "Route Target Membership NLRIの長さが足りません。"
+                ))
+            })?;
+            let mut route_target = [0u8; 8];
+            route_target[..octets - 4].copy_from_slice(&value[4..]);
+            memberships.push(RouteTargetMembership::Member {
+                origin_as: u32::from_be_bytes(
+                    value[..4].try_into().context("origin asの変換に失敗")?,
+                ),
+                route_target_length: prefix_length - 32,
+                route_target,
+            });
+            i += 1 + octets;
+        }
+        Ok(memberships)
+    }
+}
+
+impl From<&RouteTargetMembership> for BytesMut {
+    fn from(m: &RouteTargetMembership) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(m.prefix_length());
+        if let RouteTargetMembership::Member {
+            origin_as,
+            route_target,
+            ..
+        } = m
+        {
+            bytes.put_u32(*origin_as);
+            bytes.put(&route_target[..m.bytes_len() - 1 - 4]);
+        }
+        bytes
+    }
+}
+
+impl fmt::Display for RouteTargetMembership {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteTargetMembership::Default => write!(f, "default"),
+            RouteTargetMembership::Member {
+                origin_as,
+                route_target_length,
+                route_target,
+            } => {
+                match RouteTarget::from_extended_community_bytes(route_target)
+                {
+                    Some(rt) if *route_target_length == 64 => {
+                        write!(f, "{}:{}", origin_as, rt)
+                    }
+                    _ => write!(
+                        f,
+                        "{}:{:016x}/{}",
+                        origin_as,
+                        u64::from_be_bytes(*route_target),
+                        route_target_length
+                    ),
+                }
+            }
+        }
+    }
+}
+
+/// Route Target Membershipを保持するRibのエントリです。
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+pub struct RtcRibEntry {
+    pub membership: RouteTargetMembership,
+    pub path_attributes: Arc<Vec<PathAttribute>>,
+}
+
+impl RibKey for RtcRibEntry {
+    type Key = RouteTargetMembership;
+
+    fn key(&self) -> &RouteTargetMembership {
+        &self.membership
+    }
+}
+
+/// VRFの設定です。Peer毎のConfigの`vrf-*`から作成されます。
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub struct VrfConfig {
+    pub name: String,
+    pub route_distinguisher: RouteDistinguisher,
+    pub import_route_targets: Vec<RouteTarget>,
+    pub export_route_targets: Vec<RouteTarget>,
+    // 指定されていない場合はVrf作成時に割り当てる。
+    pub label: Option<MplsLabel>,
+    // VRF内で自身がoriginateするネットワーク
+    pub networks: Vec<Ipv4Network>,
+}
+
+impl VrfConfig {
+    pub fn new(name: &str, route_distinguisher: RouteDistinguisher) -> Self {
+        Self {
+            name: name.to_owned(),
+            route_distinguisher,
+            import_route_targets: vec![],
+            export_route_targets: vec![],
+            label: None,
+            networks: vec![],
+        }
+    }
+}
+
+/// VRF毎のルーティングテーブルです。
+/// import route targetに一致するVPNv4ルートがRouteDistinguisherを
+/// 取り除いたIPv4ルートとしてインストールされます。
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Vrf {
+    pub config: VrfConfig,
+    pub label: MplsLabel,
+    pub rib: Rib,
+}
+
+impl Vrf {
+    /// ラベルが設定されていない場合は、予約済みの0-15を避けて
+    /// 16からVRFの順番に割り当てる。
+    pub fn new(config: VrfConfig, index: usize) -> Self {
+        let label = config.label.unwrap_or_else(|| {
+            MplsLabel::new(16 + index as u32)
+                .expect("VRFの数がラベルの範囲を超えています。")
+        });
+        Self {
+            config,
+            label,
+            rib: Rib::new(),
+        }
+    }
+
+    pub fn does_import(&self, route_targets: &[RouteTarget]) -> bool {
+        route_targets
+            .iter()
+            .any(|rt| self.config.import_route_targets.contains(rt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_bytes_to_vpnv4_prefix_and_vpnv4_prefix_to_bytes() {
+        let prefix = Vpnv4Prefix {
+            labels: vec![MplsLabel::new(100).unwrap()],
+            route_distinguisher: "64512:1".parse().unwrap(),
+            prefix: "10.1.0.0/16".parse().unwrap(),
+        };
+        let bytes: BytesMut = (&prefix).into();
+        assert_eq!(bytes.len(), prefix.bytes_len());
+        assert_eq!(bytes[0], 24 + 64 + 16);
+
+        let prefixes = Vpnv4Prefix::from_u8_slice(&bytes[..]).unwrap();
+        assert_eq!(prefixes, vec![prefix]);
+    }
+
+    #[test]
+    fn parse_route_distinguisher() {
+        let rd: RouteDistinguisher = "10.0.0.1:5".parse().unwrap();
+        let bytes: BytesMut = (&rd).into();
+        assert_eq!(bytes[..], [0, 1, 10, 0, 0, 1, 0, 5]);
+        assert_eq!(RouteDistinguisher::try_from(&bytes[..]).unwrap(), rd);
+        assert_eq!(rd.to_string(), "10.0.0.1:5");
+    }
+
+    #[test]
+    fn convert_bytes_to_route_target_membership_and_route_target_membership_to_bytes(
+    ) {
+        let memberships = vec![
+            RouteTargetMembership::new(64512, "64512:100".parse().unwrap()),
+            RouteTargetMembership::Default,
+        ];
+        let mut bytes = BytesMut::new();
+        memberships
+            .iter()
+            .for_each(|m| bytes.put::<BytesMut>(m.into()));
+        assert_eq!(bytes.len(), 13 + 1);
+        assert_eq!(
+            RouteTargetMembership::from_u8_slice(&bytes[..]).unwrap(),
+            memberships
+        );
+    }
+
+    #[test]
+    fn route_target_membership_matches_route_targets() {
+        let rt: RouteTarget = "64512:100".parse().unwrap();
+        let other: RouteTarget = "64512:200".parse().unwrap();
+        let member = RouteTargetMembership::new(64512, rt);
+        assert!(member.does_match(&[other, rt]));
+        assert!(!member.does_match(&[other]));
+        assert!(RouteTargetMembership::Default.does_match(&[]));
+
+        // Type, Sub-Type, AS番号まで(32 bits)のprefixは同じASのRoute Targetすべてに一致する。
+        let prefix = RouteTargetMembership::Member {
+            origin_as: 64512,
+            route_target_length: 32,
+            route_target: rt.to_extended_community_bytes(),
+        };
+        assert!(prefix.does_match(&[other]));
+    }
+}
@@ -0,0 +1,467 @@
+/// BGP Messageのbytes表現と相互に変換するための公開APIです。
+/// mrbgpdv2のデーモン以外(mrbgp-decodeなど)から利用することを想定しています。
+/// MRT (RFC6396)形式のダンプに含まれるBGP Messageの変換にも対応しています。
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use bytes::BytesMut;
+
+pub use crate::bgp_type::{AddressFamily, Afi, AutonomousSystemNumber, Safi};
+pub use crate::error::ConvertBytesToBgpMessageError;
+pub use crate::packets::capability::Capability;
+pub use crate::packets::message::Message;
+pub use crate::packets::notification::NotificationMessage;
+pub use crate::packets::open::OpenMessage;
+pub use crate::packets::route_refresh::RouteRefreshMessage;
+pub use crate::packets::update::UpdateMessage;
+pub use crate::path_attribute::PathAttribute;
+use crate::routing::{IpNetwork, Ipv4Network, Ipv6Network};
+
+// BGP Messageのheaderの長さ。Marker(16) + Length(2) + Type(1)
+const HEADER_LENGTH: usize = 19;
+
+fn error(message: String) -> ConvertBytesToBgpMessageError {
+    ConvertBytesToBgpMessageError::from(anyhow::anyhow!(message))
+}
+
+/// bytesに連続して含まれるBGP Messageを先頭から順に変換する。
+/// 変換できないMessageがあった場合は、そのMessageのエラーを返し、
+/// 続くMessageの変換を続ける。Messageの区切りが分からない場合はそこで終わる。
+pub fn decode_messages(
+    bytes: &[u8],
+) -> Vec<Result<Message, ConvertBytesToBgpMessageError>> {
+    let mut messages = vec![];
+    let mut i = 0;
+    while bytes.len() > i {
+        if bytes.len() < i + HEADER_LENGTH {
+            messages.push(Err(error(format!(
+                "offset {}: BGP Messageのheaderの長さが足りません。",
+                i
+            ))));
+            break;
+        }
+        let length =
+            u16::from_be_bytes([bytes[i + 16], bytes[i + 17]]) as usize;
+        if length < HEADER_LENGTH || bytes.len() < i + length {
+            messages.push(Err(error(format!(
+                "offset {}: BGP Messageの長さ{}が不正です。",
+                i, length
+            ))));
+            break;
+        }
+        messages
+            .push(Message::try_from(BytesMut::from(&bytes[i..i + length])));
+        i += length;
+    }
+    messages
+}
+
+/// MRTのレコードです。
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct MrtRecord {
+    pub timestamp: u32,
+    pub body: MrtBody,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum MrtBody {
+    // BGP4MP(_ET)のBGP4MP_MESSAGE, BGP4MP_MESSAGE_AS4 (RFC6396 Section 4.4)
+    Bgp4mpMessage {
+        peer: MrtPeer,
+        message: Message,
+    },
+    // BGP4MP(_ET)のBGP4MP_STATE_CHANGE(_AS4)
+    Bgp4mpStateChange {
+        peer: MrtPeer,
+        old_state: u16,
+        new_state: u16,
+    },
+    // TABLE_DUMP_V2のPEER_INDEX_TABLE (RFC6396 Section 4.3.1)
+    PeerIndexTable {
+        collector_bgp_id: Ipv4Addr,
+        view_name: String,
+        peers: Vec<(Ipv4Addr, IpAddr, u32)>,
+    },
+    // TABLE_DUMP_V2のRIB_IPV4_UNICAST, RIB_IPV6_UNICAST (RFC6396 Section 4.3.2)
+    RibEntries {
+        sequence: u32,
+        prefix: IpNetwork,
+        entries: Vec<MrtRibEntry>,
+    },
+    // 対応していないレコード用
+    Unknown {
+        type_: u16,
+        subtype: u16,
+        length: usize,
+    },
+}
+
+/// BGP4MPのレコードに含まれる、Messageを送受信したPeerの情報です。
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct MrtPeer {
+    pub peer_as: u32,
+    pub local_as: u32,
+    pub peer_ip: IpAddr,
+    pub local_ip: IpAddr,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct MrtRibEntry {
+    pub peer_index: u16,
+    pub originated_time: u32,
+    pub path_attributes: Vec<PathAttribute>,
+}
+
+/// MRT形式のbytesに含まれるレコードを先頭から順に変換する。
+/// 変換できないレコードがあった場合は、そのレコードのエラーを返し、
+/// 続くレコードの変換を続ける。
+pub fn decode_mrt(
+    bytes: &[u8],
+) -> Vec<Result<MrtRecord, ConvertBytesToBgpMessageError>> {
+    let mut records = vec![];
+    let mut i = 0;
+    while bytes.len() > i {
+        // Timestamp(4) + Type(2) + Subtype(2) + Length(4)
+        if bytes.len() < i + 12 {
+            records.push(Err(error(format!(
+                "offset {}: MRTのheaderの長さが足りません。",
+                i
+            ))));
+            break;
+        }
+        let timestamp = read_u32(&bytes[i..]);
+        let type_ = u16::from_be_bytes([bytes[i + 4], bytes[i + 5]]);
+        let subtype = u16::from_be_bytes([bytes[i + 6], bytes[i + 7]]);
+        let length = read_u32(&bytes[i + 8..]) as usize;
+        let mut start = i + 12;
+        let end = start + length;
+        if bytes.len() < end {
+            records.push(Err(error(format!(
+                "offset {}: MRTのレコードの長さ{}が不正です。",
+                i, length
+            ))));
+            break;
+        }
+        // BGP4MP_ETはheaderの後にMicrosecond Timestamp(4)を持つ。
+        if type_ == 17 {
+            start = (start + 4).min(end);
+        }
+        records.push(
+            MrtBody::from_u8_slice(type_, subtype, &bytes[start..end])
+                .map(|body| MrtRecord { timestamp, body }),
+        );
+        i = end;
+    }
+    records
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+/// bytesを先頭から読み進めるための補助です。
+struct Reader<'a> {
+    bytes: &'a [u8],
+    i: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(
+        &mut self,
+        n: usize,
+    ) -> Result<&'a [u8], ConvertBytesToBgpMessageError> {
+        let b = self.bytes.get(self.i..self.i + n).ok_or_else(|| {
+            error("MRTのレコードの長さが足りません。".to_string())
+        })?;
+        self.i += n;
+        Ok(b)
+    }
+
+    fn u16(&mut self) -> Result<u16, ConvertBytesToBgpMessageError> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, ConvertBytesToBgpMessageError> {
+        Ok(read_u32(self.take(4)?))
+    }
+
+    fn ip(
+        &mut self,
+        afi: u16,
+    ) -> Result<IpAddr, ConvertBytesToBgpMessageError> {
+        match afi {
+            1 => {
+                let b = self.take(4)?;
+                Ok(IpAddr::V4(Ipv4Addr::new(b[0], b[1], b[2], b[3])))
+            }
+            2 => {
+                let b: [u8; 16] =
+                    self.take(16)?.try_into().expect("16 octets");
+                Ok(IpAddr::V6(Ipv6Addr::from(b)))
+            }
+            _ => Err(error(format!("AFI {}には対応していません。", afi))),
+        }
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let b = &self.bytes[self.i.min(self.bytes.len())..];
+        self.i = self.bytes.len();
+        b
+    }
+}
+
+impl MrtBody {
+    fn from_u8_slice(
+        type_: u16,
+        subtype: u16,
+        bytes: &[u8],
+    ) -> Result<Self, ConvertBytesToBgpMessageError> {
+        let mut r = Reader { bytes, i: 0 };
+        Ok(match (type_, subtype) {
+            // BGP4MP, BGP4MP_ET
+            (16 | 17, 0 | 1 | 4 | 5 | 6 | 7) => {
+                let is_as4 = matches!(subtype, 4 | 5 | 7);
+                let (peer_as, local_as) = if is_as4 {
+                    (r.u32()?, r.u32()?)
+                } else {
+                    (r.u16()? as u32, r.u16()? as u32)
+                };
+                let _interface_index = r.u16()?;
+                let afi = r.u16()?;
+                let peer = MrtPeer {
+                    peer_as,
+                    local_as,
+                    peer_ip: r.ip(afi)?,
+                    local_ip: r.ip(afi)?,
+                };
+                if matches!(subtype, 0 | 5) {
+                    MrtBody::Bgp4mpStateChange {
+                        peer,
+                        old_state: r.u16()?,
+                        new_state: r.u16()?,
+                    }
+                } else {
+                    let message = Message::try_from(BytesMut::from(r.rest()))?;
+                    MrtBody::Bgp4mpMessage { peer, message }
+                }
+            }
+            // TABLE_DUMP_V2 PEER_INDEX_TABLE
+            (13, 1) => {
+                let id = r.take(4)?;
+                let collector_bgp_id =
+                    Ipv4Addr::new(id[0], id[1], id[2], id[3]);
+                let view_name_length = r.u16()? as usize;
+                let view_name =
+                    String::from_utf8_lossy(r.take(view_name_length)?)
+                        .into_owned();
+                let peer_count = r.u16()?;
+                let mut peers = vec![];
+                for _ in 0..peer_count {
+                    let peer_type = r.take(1)?[0];
+                    let id = r.take(4)?;
+                    let bgp_id = Ipv4Addr::new(id[0], id[1], id[2], id[3]);
+                    let ip =
+                        r.ip(if peer_type & 0x01 == 0 { 1 } else { 2 })?;
+                    let asn = if peer_type & 0x02 == 0 {
+                        r.u16()? as u32
+                    } else {
+                        r.u32()?
+                    };
+                    peers.push((bgp_id, ip, asn));
+                }
+                MrtBody::PeerIndexTable {
+                    collector_bgp_id,
+                    view_name,
+                    peers,
+                }
+            }
+            // TABLE_DUMP_V2 RIB_IPV4_UNICAST, RIB_IPV6_UNICAST
+            (13, 2 | 4) => {
+                let address_family = if subtype == 2 {
+                    AddressFamily::IPV4_UNICAST
+                } else {
+                    AddressFamily::IPV6_UNICAST
+                };
+                let sequence = r.u32()?;
+                let prefix_length = r.take(1)?[0];
+                let prefix_bytes =
+                    r.take((prefix_length as usize).div_ceil(8))?;
+                let mut prefix = vec![prefix_length];
+                prefix.extend_from_slice(prefix_bytes);
+                let prefix = if subtype == 2 {
+                    IpNetwork::from(
+                        Ipv4Network::from_u8_slice(&prefix)?.remove(0),
+                    )
+                } else {
+                    IpNetwork::from(
+                        Ipv6Network::from_u8_slice(&prefix)?.remove(0),
+                    )
+                };
+                let entry_count = r.u16()?;
+                let mut entries = vec![];
+                for _ in 0..entry_count {
+                    let peer_index = r.u16()?;
+                    let originated_time = r.u32()?;
+                    let attribute_length = r.u16()? as usize;
+                    let attributes = expand_mp_reach_nlri(
+                        address_family,
+                        r.take(attribute_length)?,
+                    );
+                    entries.push(MrtRibEntry {
+                        peer_index,
+                        originated_time,
+                        path_attributes: PathAttribute::from_u8_slice(
+                            &attributes,
+                        )?,
+                    });
+                }
+                MrtBody::RibEntries {
+                    sequence,
+                    prefix,
+                    entries,
+                }
+            }
+            _ => MrtBody::Unknown {
+                type_,
+                subtype,
+                length: bytes.len(),
+            },
+        })
+    }
+}
+
+/// TABLE_DUMP_V2のRIB Entryに含まれるMP_REACH_NLRIは、
+/// Next Hop Length + Next Hopだけに省略されている。(RFC6396 Section 4.3.4)
+/// PathAttributeとして変換できるように、AFI/SAFIと空のNLRIを補う。
+fn expand_mp_reach_nlri(
+    address_family: AddressFamily,
+    bytes: &[u8],
+) -> Vec<u8> {
+    let mut expanded = vec![];
+    let mut i = 0;
+    while bytes.len() > i + 2 {
+        let flag = bytes[i];
+        let type_code = bytes[i + 1];
+        let (length, header_length) = if flag & 0b00010000 == 0 {
+            (bytes[i + 2] as usize, 3)
+        } else if bytes.len() > i + 3 {
+            (u16::from_be_bytes([bytes[i + 2], bytes[i + 3]]) as usize, 4)
+        } else {
+            break;
+        };
+        let end = (i + header_length + length).min(bytes.len());
+        let value = &bytes[i + header_length..end];
+        if type_code == 14
+            && value.first().map(|l| *l as usize + 1) == Some(value.len())
+        {
+            let mut v = vec![];
+            v.extend_from_slice(&u16::from(address_family.afi).to_be_bytes());
+            v.push(u8::from(address_family.safi));
+            v.extend_from_slice(value);
+            // Reserved
+            v.push(0);
+            if v.len() > 255 {
+                expanded.extend_from_slice(&[flag | 0b00010000, type_code]);
+                expanded.extend_from_slice(&(v.len() as u16).to_be_bytes());
+            } else {
+                expanded.extend_from_slice(&[
+                    flag & !0b00010000,
+                    type_code,
+                    v.len() as u8,
+                ]);
+            }
+            expanded.extend_from_slice(&v);
+        } else {
+            expanded.extend_from_slice(&bytes[i..end]);
+        }
+        i = end;
+    }
+    expanded
+}
+
+/// 16進数の文字列をbytesに変換する。空白、`:`, `-`と先頭の`0x`は無視する。
+pub fn parse_hex(s: &str) -> Result<Vec<u8>, ConvertBytesToBgpMessageError> {
+    let s = s.trim();
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    let digits: Vec<u8> = s
+        .bytes()
+        .filter(|c| !c.is_ascii_whitespace() && *c != b':' && *c != b'-')
+        .collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(error("16進数の桁数が奇数です。".to_string()));
+    }
+    digits
+        .chunks(2)
+        .map(|c| {
+            std::str::from_utf8(c)
+                .ok()
+                .and_then(|c| u8::from_str_radix(c, 16).ok())
+                .ok_or_else(|| {
+                    error(format!(
+                        "{}を16進数として解釈できません。",
+                        String::from_utf8_lossy(c)
+                    ))
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_consecutive_messages() {
+        let keepalive: BytesMut = Message::new_keepalive().into();
+        let mut bytes = keepalive.to_vec();
+        bytes.extend_from_slice(&keepalive);
+        // 途中で切れているMessage
+        bytes.extend_from_slice(&keepalive[..10]);
+
+        let messages = decode_messages(&bytes);
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].as_ref().unwrap(), &Message::new_keepalive());
+        assert!(messages[1].is_ok());
+        assert!(messages[2].is_err());
+    }
+
+    #[test]
+    fn decode_mrt_bgp4mp_message() {
+        let keepalive: BytesMut = Message::new_keepalive().into();
+        let mut body = vec![];
+        body.extend_from_slice(&64513u32.to_be_bytes());
+        body.extend_from_slice(&64512u32.to_be_bytes());
+        body.extend_from_slice(&[0, 0, 0, 1]);
+        body.extend_from_slice(&[10, 0, 0, 3, 10, 0, 0, 2]);
+        body.extend_from_slice(&keepalive);
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&1_600_000_000u32.to_be_bytes());
+        bytes.extend_from_slice(&[0, 16, 0, 4]);
+        bytes.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&body);
+
+        let records = decode_mrt(&bytes);
+        assert_eq!(
+            records[0].as_ref().unwrap(),
+            &MrtRecord {
+                timestamp: 1_600_000_000,
+                body: MrtBody::Bgp4mpMessage {
+                    peer: MrtPeer {
+                        peer_as: 64513,
+                        local_as: 64512,
+                        peer_ip: "10.0.0.3".parse().unwrap(),
+                        local_ip: "10.0.0.2".parse().unwrap(),
+                    },
+                    message: Message::new_keepalive(),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn parse_hex_string() {
+        assert_eq!(parse_hex("0xff 01:0a").unwrap(), vec![0xff, 0x01, 0x0a]);
+        assert!(parse_hex("f").is_err());
+        assert!(parse_hex("zz").is_err());
+    }
+}